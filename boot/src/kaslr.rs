@@ -0,0 +1,171 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2016-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Moving the kernel to a random address before it starts.
+//!
+//! A kernel built with `make kernel-kaslr` has a table, between
+//! `__kaslr_relocs_start` and `__kaslr_relocs_end`, of each place the
+//! linker wrote an absolute address into it. We pick a random slide, copy
+//! the whole image, `__kernel_start` to `__kernel_end`, up by that much,
+//! add the slide at each of those places in the copy, and carry on booting
+//! from [`_start_moved`](../fn._start_moved.html) in the copy. The boot
+//! page tables identity map the first 1 GiB, so the copy runs where it is.
+//!
+//! We only ever move up, past the end of the image as it is, so the copy
+//! never overwrites this code while it runs. A kernel without a table,
+//! or with nowhere free to go, stays where it was linked.
+use core::{ptr, slice};
+
+/// Slides are multiples of this, so that the kernel's 2 MiB pages stay
+/// aligned. The kernel's `arch::kaslr` has the same.
+const SLIDE_ALIGN: usize = 2 * 1024 * 1024;
+
+/// Slides are less than this.
+const SLIDE_MAX: usize = 128 * 1024 * 1024;
+
+/// How many random slides to try before giving up and staying put.
+const SLIDE_TRIES: usize = 64;
+
+/// A relocation table entry with this bit set is for a 64-bit address;
+/// without it, for a 32-bit one.
+const RELOC_64: u32 = 1 << 31;
+
+/// The multiboot 2 tags we read: the end of the list, a boot module, and
+/// the memory map, and the memory map's type for available RAM.
+const TAG_END: u32 = 0;
+const TAG_MODULE: u32 = 3;
+const TAG_MMAP: u32 = 6;
+const MMAP_AVAILABLE: u32 = 1;
+
+extern "C" {
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+    static __kaslr_relocs_start: u32;
+    static __kaslr_relocs_end: u32;
+    /// The kernel's `arch::kaslr::KASLR_SLIDE`.
+    static mut KASLR_SLIDE: u64;
+}
+
+/// Move the kernel to a random address, if it was built to be moved, and
+/// jump to where `_start_moved` ended up, with the multiboot info pointer
+/// `mbi` in `edi`.
+#[cold]
+#[no_mangle]
+pub unsafe extern "C" fn kaslr_relocate(mbi: usize) -> ! {
+    let start = &__kernel_start as *const u8 as usize;
+    let end = &__kernel_end as *const u8 as usize;
+    let relocs_start = &__kaslr_relocs_start as *const u32;
+    let relocs_end = &__kaslr_relocs_end as *const u32;
+    let relocs = slice::from_raw_parts( relocs_start
+                                      , (relocs_end as usize
+                                         - relocs_start as usize) / 4);
+
+    let slide = if relocs.is_empty() { 0 }
+                else { choose_slide(mbi, start, end) };
+    if slide != 0 {
+        // not `ptr::copy`: that's a call to `memcpy`, and the only global
+        // one by the time we're linked is the 64-bit kernel's.
+        let (_len, _src, _dst): (usize, usize, usize);
+        asm!("rep movsb"
+            : "={ecx}"(_len), "={esi}"(_src), "={edi}"(_dst)
+            : "0"(end - start), "1"(start), "2"(start + slide)
+            : "memory" : "volatile");
+        for &reloc in relocs {
+            let site = start + slide + (reloc & !RELOC_64) as usize;
+            if reloc & RELOC_64 != 0 {
+                let site = site as *mut u64;
+                ptr::write_unaligned(site, ptr::read_unaligned(site)
+                                           + slide as u64);
+            } else {
+                let site = site as *mut u32;
+                ptr::write_unaligned(site, ptr::read_unaligned(site)
+                                           .wrapping_add(slide as u32));
+            }
+        }
+        let moved_slide = &mut KASLR_SLIDE as *mut u64 as usize + slide;
+        *(moved_slide as *mut u64) = slide as u64;
+    }
+
+    let moved = ::_start_moved as usize + slide;
+    asm!("jmp *$0" :: "r"(moved), "{edi}"(mbi) :: "volatile");
+    loop { }
+}
+
+/// Returns a random slide that puts the kernel, from `start` to `end`,
+/// in free memory above where it is now, or 0 if we can't find one.
+unsafe fn choose_slide(mbi: usize, start: usize, end: usize) -> usize {
+    for _ in 0 .. SLIDE_TRIES {
+        // the same as the kernel's `kaslr::compute_slide`.
+        let slide = entropy() as usize & (SLIDE_MAX - 1) & !(SLIDE_ALIGN - 1);
+        if slide >= end - start
+        && is_free(mbi, (start + slide) as u64, (end + slide) as u64) {
+            return slide
+        }
+    }
+    0
+}
+
+/// Returns true if the memory from `start` to `end` is available RAM, by
+/// the multiboot info at `mbi`, and the bootloader hasn't put the info, or
+/// any modules, there.
+unsafe fn is_free(mbi: usize, start: u64, end: u64) -> bool {
+    let overlaps = |base: u64, top: u64| start < top && base < end;
+    let mbi_end = mbi + read_u32(mbi) as usize;
+    if overlaps(mbi as u64, mbi_end as u64) { return false }
+
+    let mut available = false;
+    // tags start after the total size and a reserved word, 8-byte aligned.
+    let mut tag = mbi + 8;
+    while tag + 8 <= mbi_end {
+        let (ty, size) = (read_u32(tag), read_u32(tag + 4) as usize);
+        if ty == TAG_END || size < 8 { break }
+        if ty == TAG_MODULE
+        && overlaps(read_u32(tag + 8) as u64, read_u32(tag + 12) as u64) {
+            return false
+        }
+        if ty == TAG_MMAP {
+            // entries of `entry_size`, after it and the entry version.
+            let entry_size = read_u32(tag + 8) as usize;
+            let mut entry = tag + 16;
+            while entry_size > 0 && entry + entry_size <= tag + size {
+                let base = ptr::read_unaligned(entry as *const u64);
+                let len = ptr::read_unaligned((entry + 8) as *const u64);
+                if read_u32(entry + 16) == MMAP_AVAILABLE
+                && base <= start && end <= base + len {
+                    available = true;
+                }
+                entry += entry_size;
+            }
+        }
+        tag += (size + 7) & !7;
+    }
+    available
+}
+
+#[inline(always)]
+unsafe fn read_u32(addr: usize) -> u32 { *(addr as *const u32) }
+
+/// Returns a random number, from `rdrand` if this CPU has it, or mixed up
+/// from the TSC if it doesn't.
+unsafe fn entropy() -> u32 {
+    let (_eax, ecx): (u32, u32);
+    asm!("cpuid" : "={eax}"(_eax), "={ecx}"(ecx) : "0"(1u32) : "ebx", "edx");
+    if ecx & (1 << 30) != 0 {
+        for _ in 0 .. 10 {
+            let (value, ok): (u32, u8);
+            asm!("rdrand $0; setc $1" : "=r"(value), "=q"(ok)
+                                      ::: "volatile");
+            if ok != 0 { return value }
+        }
+    }
+    // only the bits from 21 up go into a slide, and the low bits of the
+    // TSC are the ones that change between tries, so spread them upwards.
+    let (low, high): (u32, u32);
+    asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile");
+    (low ^ high).wrapping_mul(0x9e37_79b9)
+}
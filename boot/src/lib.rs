@@ -42,6 +42,8 @@
 
 extern crate rlibc;
 
+mod kaslr;
+
 const TABLE_LENGTH: usize = 512;
 /// The size of a "huge" page
 const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024; // 2 MiB
@@ -245,13 +247,31 @@ pub unsafe extern "C" fn _start() {
     }
     boot_write(b"2");
 
-    // 3. if everything is okay, create the page tables and start long mode
+    // 3. move the kernel somewhere random, if it was built to be moved, and
+    //    carry on from `_start_moved` wherever it ended up.
+    asm!("movl $$stack_top, %esp
+          pushl %edi
+          call kaslr_relocate" :::: "volatile");
+}
+
+/// The rest of [`_start`](fn._start.html), in the copy of the kernel that
+/// [`kaslr_relocate`](kaslr/fn.kaslr_relocate.html) jumped to, with the
+/// Multiboot info pointer still in `edi`.
+#[cold]
+#[no_mangle]
+#[naked]
+pub unsafe extern "C" fn _start_moved() {
+    // the copy's own stack, now that the addresses in it have been moved.
+    asm!("movl $$stack_top, %esp" :::: "volatile");
+    boot_write(b"3");
+
+    // 4. if everything is okay, create the page tables and start long mode
     create_page_tables();
     set_long_mode();
 
-    // 4. load the 64-bit GDT
+    // 5. load the 64-bit GDT
     GdtPointer::from(&GDT).load();
-    boot_write(b"4");
+    boot_write(b"5");
 
     // 6. jump to the 64-bit boot subroutine.
     asm!("ljmpl $$8, $$long_mode_init");
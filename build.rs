@@ -1,4 +1,42 @@
 use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::process::Command;
+
+/// Where `linker.ld` puts the kernel. Symbols are stored as 32-bit offsets
+/// from here.
+const KERNEL_BASE: u64 = 0x10_0000;
+
+/// The symbol types (as `nm` prints them) that go in the kallsyms table:
+/// text, weak, read-only data, data, and BSS.
+const KALLSYMS_TYPES: &'static str = "tTwWrRdDbB";
+
+/// The most the kallsyms table should add to the kernel.
+const KALLSYMS_MAX_SIZE: usize = 2 << 20;
+
+/// A KASLR relocation table entry with this bit set is for a 64-bit
+/// address; without it, for a 32-bit one.
+const KASLR_RELOC_64: u32 = 1 << 31;
+
+/// ELF section types and flags, and the one special section index, that
+/// `read_relocs` needs.
+const SHT_RELA: u32 = 4;
+const SHT_REL: u32 = 9;
+const SHF_ALLOC: u64 = 0x2;
+const SHN_ABS: u16 = 0xfff1;
+
+/// The relocation types that are absolute addresses, which have to move
+/// with the kernel: `R_X86_64_64`, `R_X86_64_32` and `R_X86_64_32S`.
+const R_X86_64_64: u32 = 1;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+
+/// The relocation types that don't have to change when the whole kernel
+/// moves: `R_X86_64_NONE`, the PC-relative `PC32`, `PLT32`, `PC16`, `PC8`
+/// and `PC64`, and the thread-local offsets `DTPOFF64`, `TPOFF64`,
+/// `DTPOFF32` and `TPOFF32`.
+const RELOCS_UNMOVED: &'static [u32] = &[0, 2, 4, 13, 15, 24, 17, 18, 21, 23];
 
 fn main() {
     let profile = env::var("PROFILE").unwrap();
@@ -21,5 +59,294 @@ fn main() {
         println!("cargo:rustc-link-lib=static=boot");
     }
 
+    if env::var("CARGO_FEATURE_KALLSYMS").is_ok() {
+        kallsyms();
+    }
+    if env::var("CARGO_FEATURE_KASLR").is_ok() {
+        kaslr();
+    }
+}
+
+/// Generate `$OUT_DIR/kallsyms.rs`, the symbol table for `module::kallsyms`.
+///
+/// The table is part of the image it describes, so it's made from the
+/// image the last build linked, which `$SOS_KALLSYMS` names; without one,
+/// the table is empty. Building with an empty table, then with the table
+/// from that, then once more, gives an image whose table describes it: the
+/// second table has as many entries, with names as long, as the third, so
+/// nothing moves between the second link and the third. `make
+/// kernel-kallsyms` does this.
+fn kallsyms() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOS_KALLSYMS");
+    println!("cargo:rerun-if-env-changed=NM");
+    let mut symbols = match env::var("SOS_KALLSYMS") {
+        Ok(image) => {
+            println!("cargo:rerun-if-changed={}", image);
+            read_symbols(&image)
+        }
+      , Err(_) => Vec::new()
+    };
+    symbols.sort();
+
+    let mut names: Vec<Vec<u8>>
+        = symbols.iter().map(|&(_, ref name)| name.clone()).collect();
+    let tokens = learn_tokens(&mut names);
+
+    let mut table = Vec::with_capacity(symbols.len());
+    let mut packed = Vec::new();
+    for (&(offset, _), name) in symbols.iter().zip(names.iter()) {
+        table.push((offset, packed.len() as u32));
+        // a length of 128 tokens or more takes two bytes, the first with
+        // its top bit set.
+        assert!(name.len() < 1 << 15, "a symbol name is too long to encode");
+        if name.len() < 0x80 {
+            packed.push(name.len() as u8);
+        } else {
+            packed.push(0x80 | (name.len() & 0x7f) as u8);
+            packed.push((name.len() >> 7) as u8);
+        }
+        packed.extend_from_slice(name);
+    }
+
+    let token_bytes: usize = tokens.iter().map(|token| token.len()).sum();
+    let size = table.len() * 8 + packed.len() + token_bytes + 257 * 2;
+    if size > KALLSYMS_MAX_SIZE {
+        println!( "cargo:warning=the kallsyms table is {} KiB, over {} KiB"
+                , size >> 10, KALLSYMS_MAX_SIZE >> 10);
+    }
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("kallsyms.rs");
+    write_kallsyms(&out, &table, &packed, &tokens)
+        .expect("couldn't write the kallsyms table");
+}
+
+/// Returns the offset from `KERNEL_BASE` and the name of each symbol in
+/// `image` that goes in the kallsyms table.
+fn read_symbols(image: &str) -> Vec<(u32, Vec<u8>)> {
+    let nm = env::var("NM").unwrap_or_else(|_| String::from("nm"));
+    let output = Command::new(&nm)
+        .args(&["--format=posix", "--defined-only", image])
+        .output()
+        .unwrap_or_else(|e| panic!("couldn't run {}: {}", nm, e));
+    if !output.status.success() {
+        panic!( "{} {} failed: {}", nm, image
+              , String::from_utf8_lossy(&output.stderr));
+    }
+    // each line is `name type value [size]`, with the value in hex.
+    String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 || !KALLSYMS_TYPES.contains(fields[1]) {
+                return None
+            }
+            match u64::from_str_radix(fields[2], 16) {
+                Ok(value) if value >= KERNEL_BASE
+                          && value - KERNEL_BASE <= u32::max_value() as u64 =>
+                    Some(( (value - KERNEL_BASE) as u32
+                         , fields[0].as_bytes().to_vec()))
+              , _ => None
+            }
+        })
+        .collect()
+}
+
+/// Compress `names` in place, and return the tokens they're made of.
+///
+/// As in Linux's `scripts/kallsyms`, each byte value that appears in a name
+/// starts out as a token for itself. Then, while there are byte values
+/// left over, the pair of tokens that's next to each other most often
+/// becomes a token of its own, and each place it appears is rewritten as
+/// that one byte.
+fn learn_tokens(names: &mut Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let mut tokens = vec![Vec::new(); 256];
+    for name in names.iter() {
+        for &byte in name { tokens[byte as usize] = vec![byte]; }
+    }
+    let mut counts = vec![0u32; 1 << 16];
+    while let Some(free) = tokens.iter().position(|token| token.is_empty()) {
+        for count in counts.iter_mut() { *count = 0 }
+        for name in names.iter() {
+            for pair in name.windows(2) {
+                counts[(pair[0] as usize) << 8 | pair[1] as usize] += 1;
+            }
+        }
+        let (best, &count) = counts.iter().enumerate()
+            .max_by_key(|&(_, count)| *count)
+            .unwrap();
+        // a pair that only appears once saves nothing.
+        if count < 2 { break }
+        let (first, second) = ((best >> 8) as u8, best as u8);
+        let mut token = tokens[first as usize].clone();
+        token.extend_from_slice(&tokens[second as usize]);
+        tokens[free] = token;
+        for name in names.iter_mut() {
+            let mut i = 0;
+            while i + 1 < name.len() {
+                if name[i] == first && name[i + 1] == second {
+                    name[i] = free as u8;
+                    name.remove(i + 1);
+                }
+                i += 1;
+            }
+        }
+    }
+    tokens
+}
+
+/// Write the generated table to `path`.
+fn write_kallsyms( path: &Path, table: &[(u32, u32)], names: &[u8]
+                 , tokens: &[Vec<u8>]) -> ::std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "// generated by build.rs from `nm`'s view of the \
+                   last build")?;
+    writeln!( out, "/// Every symbol in the kernel image, as its offset from \
+                    `KERNEL_BASE`\n\
+                    /// and where its name starts in `NAMES`, sorted by \
+                    offset.")?;
+    writeln!(out, "pub static SYMBOLS: &'static [(u32, u32)] = &[")?;
+    for &(offset, name) in table {
+        writeln!(out, "    (0x{:x}, {}),", offset, name)?;
+    }
+    writeln!(out, "];")?;
+    writeln!(out, "static NAMES: &'static [u8] = {};", byte_string(names))?;
+    let mut index = vec![0usize];
+    for token in tokens {
+        let end = index[index.len() - 1] + token.len();
+        index.push(end);
+    }
+    let all: Vec<u8> = tokens.iter().flat_map(|t| t.clone()).collect();
+    writeln!(out, "static TOKENS: &'static [u8] = {};", byte_string(&all))?;
+    writeln!(out, "static TOKEN_INDEX: [u32; 257] = {:?};", index)?;
+    Ok(())
+}
+
+/// Generate `$OUT_DIR/kaslr_relocs.rs`, the table of places the boot stub
+/// adds the KASLR slide to.
+///
+/// Like the kallsyms table, it's made from the image the last build
+/// linked, which `$SOS_KASLR` names, and is empty without one. It has to
+/// be linked with `--emit-relocs`, so that its relocations are still there
+/// to read. `linker.ld` puts the table after everything else, so it can't
+/// move anything it lists, and linking again with the first image's table
+/// gives an image it describes. `make kernel-kaslr` does this.
+fn kaslr() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOS_KASLR");
+    let relocs = match env::var("SOS_KASLR") {
+        Ok(image) => {
+            println!("cargo:rerun-if-changed={}", image);
+            read_relocs(&image)
+        }
+      , Err(_) => Vec::new()
+    };
 
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("kaslr_relocs.rs");
+    write_kaslr(&out, &relocs)
+        .expect("couldn't write the KASLR relocation table");
+}
+
+/// Returns the place, as an offset from `KERNEL_BASE`, of each absolute
+/// address the linker wrote into the allocated sections of `image`, with
+/// `KASLR_RELOC_64` set for the 64-bit ones, sorted.
+fn read_relocs(image: &str) -> Vec<u32> {
+    let mut elf = Vec::new();
+    File::open(image)
+        .and_then(|mut file| file.read_to_end(&mut elf))
+        .unwrap_or_else(|e| panic!("couldn't read {}: {}", image, e));
+    if elf.len() < 64 || &elf[..4] != b"\x7fELF" || elf[4] != 2 {
+        panic!("{} isn't a 64-bit ELF image", image);
+    }
+    // the section headers are `e_shentsize` apart, from `e_shoff`; in each,
+    // `sh_type` is at 4, `sh_flags` at 8, `sh_addr` at 16, `sh_offset` at
+    // 24, `sh_size` at 32, `sh_link` at 40 and `sh_info` at 44.
+    let (shoff, shentsize, shnum)
+        = (read_u64(&elf, 0x28) as usize, read_u16(&elf, 0x3a) as usize
+          , read_u16(&elf, 0x3c) as usize);
+    let section = |i: usize| shoff + i * shentsize;
+
+    let mut relocs = Vec::new();
+    for rel in (0 .. shnum).map(&section) {
+        // the boot stub's relocations are `Elf64_Rel`s, without addends,
+        // since `objcopy` made its object from a 32-bit one.
+        let entry_size = match read_u32(&elf, rel + 4) {
+            SHT_RELA => 24
+          , SHT_REL => 16
+          , _ => continue
+        };
+        let target = section(read_u32(&elf, rel + 44) as usize);
+        if read_u64(&elf, target + 8) & SHF_ALLOC == 0 { continue }
+        let symtab = section(read_u32(&elf, rel + 40) as usize);
+        let symbols = read_u64(&elf, symtab + 24) as usize;
+
+        let (start, size)
+            = ( read_u64(&elf, rel + 24) as usize
+              , read_u64(&elf, rel + 32) as usize);
+        // each entry starts with the address, then the symbol and type.
+        for entry in (0 .. size / entry_size).map(|i| start + i * entry_size)
+        {
+            let (addr, info)
+                = (read_u64(&elf, entry), read_u64(&elf, entry + 8));
+            let (symbol, ty) = ((info >> 32) as usize, info as u32);
+            if RELOCS_UNMOVED.contains(&ty) { continue }
+            let flag = match ty {
+                R_X86_64_64 => KASLR_RELOC_64
+              , R_X86_64_32 | R_X86_64_32S => 0
+              , _ => panic!( "{}: can't move relocation type {} at {:#x}"
+                           , image, ty, addr)
+            };
+            // an absolute symbol, or an undefined weak one, stays put.
+            let shndx = read_u16(&elf, symbols + symbol * 24 + 6);
+            if symbol == 0 || shndx == 0 || shndx == SHN_ABS { continue }
+            if addr < KERNEL_BASE
+            || addr - KERNEL_BASE >= KASLR_RELOC_64 as u64 {
+                panic!( "{}: relocation at {:#x} is outside the kernel"
+                      , image, addr);
+            }
+            relocs.push((addr - KERNEL_BASE) as u32 | flag);
+        }
+    }
+    relocs.sort();
+    relocs.dedup();
+    relocs
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    bytes[at] as u16 | (bytes[at + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    read_u16(bytes, at) as u32 | (read_u16(bytes, at + 2) as u32) << 16
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    read_u32(bytes, at) as u64 | (read_u32(bytes, at + 4) as u64) << 32
+}
+
+/// Write the generated table to `path`.
+fn write_kaslr(path: &Path, relocs: &[u32]) -> ::std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "// generated by build.rs from the relocations in the \
+                   last build")?;
+    writeln!( out, "/// Where the boot stub adds the slide, as offsets from \
+                    where the kernel\n\
+                    /// was linked; the 64-bit ones have the top bit set.")?;
+    writeln!(out, "#[no_mangle]")?;
+    writeln!(out, "#[link_section = \".kaslr_relocs\"]")?;
+    writeln!(out, "pub static KASLR_RELOCS: [u32; {}] = [", relocs.len())?;
+    for reloc in relocs {
+        writeln!(out, "    0x{:08x},", reloc)?;
+    }
+    writeln!(out, "];")?;
+    Ok(())
+}
+
+/// Returns `bytes` as a byte string literal.
+fn byte_string(bytes: &[u8]) -> String {
+    let mut literal = String::from("b\"");
+    for &byte in bytes {
+        literal.push_str(&format!("\\x{:02x}", byte));
+    }
+    literal.push('"');
+    literal
 }
@@ -44,46 +44,111 @@ use core::marker::PhantomData;
 #[cfg(target_arch = "armv7")] mod armv7;
 #[cfg(target_arch = "armv7")] pub use self::x86::*;
 
-/// A CPU I/O port.
+/// A value that can be read from an I/O port.
+pub trait PortRead {
+    /// Read a value from the port numbered `port`.
+    unsafe fn read_from_port(port: u16) -> Self;
+}
+
+/// A value that can be written to an I/O port.
+pub trait PortWrite {
+    /// Write `value` to the port numbered `port`.
+    unsafe fn write_to_port(port: u16, value: Self);
+}
+
+macro_rules! port_types {
+    ( $( $t:ty, $read:ident, $out:ident ),+ ) => {
+        $(
+            impl PortRead for $t {
+                #[inline]
+                unsafe fn read_from_port(port: u16) -> Self {
+                    UnsafePort::new(port).$read()
+                }
+            }
+
+            impl PortWrite for $t {
+                #[inline]
+                unsafe fn write_to_port(port: u16, value: Self) {
+                    UnsafePort::new(port).$out(value)
+                }
+            }
+        )+
+    }
+}
+
+port_types! { u8, in8, out8
+            , u16, in16, out16
+            , u32, in32, out32
+            }
+
+/// A CPU I/O port, which is read and written as `T`s.
 ///
 /// This is a typed wrapper around an [`UnsafePort`](struct.UnsafePort.html).
-/// Unlike an `UnsafePort`, this
-pub struct Port<T> { raw_port: UnsafePort
+/// Unlike an `UnsafePort`, this can only be used with the width of value
+/// it was made for.
+pub struct Port<T> { number: u16
                    , typ: PhantomData<T>
                    }
 
-macro_rules! make_ports {
-    ( $( $t:ty, $read:ident, $out:ident ),+ ) => {
+/// An I/O port that may only be read.
+pub struct PortReadOnly<T> { number: u16
+                           , typ: PhantomData<T>
+                           }
+
+/// An I/O port that may only be written.
+pub struct PortWriteOnly<T> { number: u16
+                            , typ: PhantomData<T>
+                            }
+
+macro_rules! port_new {
+    ( $( $port:ident ),+ ) => {
         $(
-            impl Port<$t> {
+            impl<T> $port<T> {
+                /// Returns the port numbered `number`.
                 #[inline]
                 pub const fn new(number: u16) -> Self {
                     // TODO: can we check if the port number is valid
-                    unsafe {
-                        Port { raw_port: UnsafePort::new(number)
-                             , typ: PhantomData::<$t>
-                             }
-                    }
-                }
-
-                #[inline]
-                pub fn read(&self) -> $t {
-                    unsafe { self.raw_port.$read() }
+                    $port { number: number, typ: PhantomData }
                 }
 
+                /// Returns this port's number.
                 #[inline]
-                pub fn write(&self, data: $t) {
-                    unsafe { self.raw_port.$out(data) }
-                }
+                pub const fn number(&self) -> u16 { self.number }
             }
         )+
     }
 }
 
-make_ports! { u8, in8, out8
-            , u16, in16, out16
-            , u32, in32, out32
-            }
+port_new! { Port, PortReadOnly, PortWriteOnly }
 
-#[cfg(arch="x86_64")]
-make_ports! { u64, in64, out64 }
+impl<T: PortRead> Port<T> {
+    /// Read a `T` from this port.
+    #[inline]
+    pub fn read(&self) -> T {
+        unsafe { T::read_from_port(self.number) }
+    }
+}
+
+impl<T: PortWrite> Port<T> {
+    /// Write a `T` to this port.
+    #[inline]
+    pub fn write(&self, data: T) {
+        unsafe { T::write_to_port(self.number, data) }
+    }
+}
+
+impl<T: PortRead> PortReadOnly<T> {
+    /// Read a `T` from this port.
+    #[inline]
+    pub fn read(&self) -> T {
+        unsafe { T::read_from_port(self.number) }
+    }
+}
+
+impl<T: PortWrite> PortWriteOnly<T> {
+    /// Write a `T` to this port.
+    #[inline]
+    pub fn write(&self, data: T) {
+        unsafe { T::write_to_port(self.number, data) }
+    }
+}
@@ -0,0 +1,156 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The debug registers, for hardware breakpoints.
+//!
+//! `%dr0` to `%dr3` hold the addresses of up to four breakpoints, `%dr7`
+//! says which of them are enabled and what sort of access each one breaks
+//! on, and `%dr6` says which of them caused the last debug exception (`#DB`).
+//!
+//! Like every other register, these are per-CPU: a breakpoint set on one CPU
+//! does nothing on the others.
+#![warn(missing_docs)]
+use memory::VAddr;
+
+/// The number of breakpoints the debug registers can hold.
+pub const NUM_BREAKPOINTS: u8 = 4;
+
+/// `%dr6`: the bits saying which breakpoints fired, one per slot.
+pub const DR6_BREAKPOINTS: u64 = 0b1111;
+
+/// `%dr7`: the shift of the condition field of slot 0; each slot's
+/// condition and length fields take four bits.
+const DR7_FIELDS_SHIFT: u64 = 16;
+
+/// What kind of access a breakpoint fires on.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum BreakCondition { /// Executing the instruction at the address
+                          Execute = 0b00
+                        , /// Writing to the address
+                          Write = 0b01
+                        , /// Reading from or writing to the address
+                          ReadWrite = 0b11
+                        }
+
+/// How many bytes a breakpoint covers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum BreakLen { /// One byte
+                    Byte = 0b00
+                  , /// Two bytes
+                    Word = 0b01
+                  , /// Eight bytes
+                    Qword = 0b10
+                  , /// Four bytes
+                    Dword = 0b11
+                  }
+
+impl BreakLen {
+    /// Returns the number of bytes covered.
+    #[inline]
+    pub fn bytes(&self) -> usize {
+        match *self {
+            BreakLen::Byte => 1
+          , BreakLen::Word => 2
+          , BreakLen::Dword => 4
+          , BreakLen::Qword => 8
+        }
+    }
+}
+
+macro_rules! debug_reg {
+    ($doc:expr, $name:ident, $reg:tt) => {
+        #[doc=$doc]
+        pub mod $name {
+            /// Read the register.
+            ///
+            /// # Safety
+            /// + This must be done in kernel mode.
+            #[inline]
+            pub unsafe fn read() -> u64 {
+                let value: u64;
+                asm!( concat!("mov $0, ", $reg)
+                    : "=r"(value)
+                    ::: "intel", "volatile");
+                value
+            }
+
+            /// Write `value` to the register.
+            ///
+            /// # Safety
+            /// + This must be done in kernel mode.
+            #[inline]
+            pub unsafe fn write(value: u64) {
+                asm!( concat!("mov ", $reg, ", $0")
+                    :: "r"(value)
+                    :: "intel", "volatile");
+            }
+        }
+    }
+}
+
+debug_reg!("`%dr0`: the address of breakpoint 0.", dr0, "dr0");
+debug_reg!("`%dr1`: the address of breakpoint 1.", dr1, "dr1");
+debug_reg!("`%dr2`: the address of breakpoint 2.", dr2, "dr2");
+debug_reg!("`%dr3`: the address of breakpoint 3.", dr3, "dr3");
+debug_reg!("`%dr6`: debug status.", dr6, "dr6");
+debug_reg!("`%dr7`: debug control.", dr7, "dr7");
+
+/// The current CPU's debug registers.
+pub struct DebugRegs { _private: () }
+
+impl DebugRegs {
+    /// Returns the current CPU's debug registers.
+    ///
+    /// # Safety
+    /// + This must be done in kernel mode, and nothing else may be using
+    ///   the debug registers.
+    pub unsafe fn new() -> Self { DebugRegs { _private: () } }
+
+    /// Break on `condition` accesses to the `len` bytes at `addr`, using
+    /// breakpoint `slot`.
+    ///
+    /// Execute breakpoints must be a `Byte` long, and data breakpoints must
+    /// be aligned to their length.
+    pub fn set_breakpoint( &mut self, slot: u8, addr: VAddr
+                         , condition: BreakCondition, len: BreakLen)
+                         -> Result<(), &'static str> {
+        if slot >= NUM_BREAKPOINTS { return Err("no such breakpoint slot") }
+        if condition == BreakCondition::Execute && len != BreakLen::Byte {
+            return Err("execute breakpoints must be a byte long")
+        }
+        if addr.as_usize() % len.bytes() != 0 {
+            return Err("breakpoint address isn't aligned to its length")
+        }
+        let addr = addr.as_usize() as u64;
+        let shift = DR7_FIELDS_SHIFT + 4 * slot as u64;
+        let fields = (condition as u64) | (len as u64) << 2;
+        unsafe {
+            match slot {
+                0 => dr0::write(addr)
+              , 1 => dr1::write(addr)
+              , 2 => dr2::write(addr)
+              , _ => dr3::write(addr)
+            }
+            let control = dr7::read() & !(0b1111 << shift);
+            dr7::write(control | fields << shift | local_enable(slot));
+        }
+        Ok(())
+    }
+
+    /// Disable breakpoint `slot`.
+    pub fn clear_breakpoint(&mut self, slot: u8) {
+        if slot >= NUM_BREAKPOINTS { return }
+        unsafe { dr7::write(dr7::read() & !local_enable(slot)) }
+    }
+}
+
+/// `%dr7`: the local enable bit for breakpoint `slot`.
+#[inline]
+fn local_enable(slot: u8) -> u64 { 1 << (2 * slot as u64) }
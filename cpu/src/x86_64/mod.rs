@@ -15,6 +15,7 @@
 #[path = "../x86_all/mod.rs"] mod cpu_all;
 
 pub mod context;
+pub mod debug_regs;
 pub mod task;
 pub mod msr;
 
@@ -8,8 +8,54 @@
 //
 //! Code for interacting with the Model-Specific Registers (MSRs).
 
+/// Local APIC base address and enable bit
+pub const IA32_APIC_BASE: u32 = 0x1b;
+/// General-purpose performance counter 0. Counter `n` is at `+ n`.
+pub const IA32_PMC0: u32 = 0xc1;
+/// Event select for performance counter 0. Counter `n`'s is at `+ n`.
+pub const IA32_PERFEVTSEL0: u32 = 0x186;
+/// How many variable-range MTRRs there are, and which MTRR features
+pub const IA32_MTRRCAP: u32 = 0xfe;
+/// Base and type of variable-range MTRR 0. MTRR `n`'s is at `+ 2n`.
+pub const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+/// Mask and valid bit of variable-range MTRR 0. MTRR `n`'s is at `+ 2n`.
+pub const IA32_MTRR_PHYSMASK0: u32 = 0x201;
+/// Types of the eight 64 KiB ranges from 0
+pub const IA32_MTRR_FIX64K_00000: u32 = 0x250;
+/// Types of the eight 16 KiB ranges from `0x80000`
+pub const IA32_MTRR_FIX16K_80000: u32 = 0x258;
+/// Types of the eight 16 KiB ranges from `0xa0000`
+pub const IA32_MTRR_FIX16K_A0000: u32 = 0x259;
+/// Types of the eight 4 KiB ranges from `0xc0000`. Each following MSR, up
+/// to `0x26f`, covers the next 32 KiB.
+pub const IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+/// The page attribute table
+pub const IA32_PAT: u32 = 0x277;
+/// The default memory type, and the MTRR enable bits
+pub const IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
+/// Which performance counters have overflowed
+pub const IA32_PERF_GLOBAL_STATUS: u32 = 0x38e;
+/// Enable bits for each performance counter
+pub const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+/// Clears bits in `IA32_PERF_GLOBAL_STATUS`
+pub const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
 /// Extended Feature Enable Register (EFER) on IA-32
 pub const IA32_EFER: u32 = 0xc0000080;
+/// Segment selectors loaded by `syscall` and `sysret`
+pub const IA32_STAR: u32 = 0xc0000081;
+/// Target `%rip` for `syscall` in long mode
+pub const IA32_LSTAR: u32 = 0xc0000082;
+/// `%rflags` bits cleared by `syscall`
+pub const IA32_FMASK: u32 = 0xc0000084;
+/// Base address of the `%fs` segment
+pub const IA32_FS_BASE: u32 = 0xc0000100;
+/// Base address of the `%gs` segment
+pub const IA32_GS_BASE: u32 = 0xc0000101;
+/// Value swapped into `IA32_GS_BASE` by the `swapgs` instruction
+pub const IA32_KERNEL_GS_BASE: u32 = 0xc0000102;
+/// Physical address of the KVM paravirtual clock's time info, or'd with 1
+/// to enable it
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b564d01;
 
 /// Write `value` to the specified `msr`
 ///
@@ -46,3 +92,11 @@ pub unsafe fn enable_nxe() {
     let efer = read(IA32_EFER) | nxe_bit;
     write(IA32_EFER, efer);
 }
+
+/// Enable the `syscall` and `sysret` instructions, by setting the SCE
+/// (System Call Extensions) bit in the IA-32 EFER register.
+pub unsafe fn enable_syscall() {
+    let sce_bit = 1 << 0;
+    let efer = read(IA32_EFER) | sce_bit;
+    write(IA32_EFER, efer);
+}
@@ -25,7 +25,7 @@ pub struct StateSegment {
   , /// 64-bit values of the stack pointers (`%rsp`) for privilege rings 0-2
     //  TODO: should this be an array or just three u64s?
     pub rsp: [VAddr; 3]
-  , _reserved_2: u32
+  , _reserved_2: u64
   , /// 64-bit values of the interrupt stack table registers
     pub ist: [VAddr; 7]
   , _reserved_3: u64
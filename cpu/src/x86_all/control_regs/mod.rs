@@ -96,18 +96,48 @@ pub mod cr3 {
     // #[cfg(target_arch = "x86_64")]
     // use paging::table::{Table, PML4Level};
 
+    /// The bits of `$cr3` holding the address of the page table.
+    #[cfg(target_arch = "x86_64")]
+    const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+    /// The bits of `$cr3` holding the process-context identifier.
+    #[cfg(target_arch = "x86_64")]
+    const PCID_MASK: u64 = 0xfff;
+    /// Set when writing `$cr3` to keep the new PCID's TLB entries.
+    #[cfg(target_arch = "x86_64")]
+    const NOFLUSH: u64 = 1 << 63;
+
     /// Read the current value from `$cr3`.
     ///
     /// # Safety
     /// + Reading from control registers while not in kernel mode will cause
     ///   a general protection fault.
+    ///
+    /// Only the address of the page table is returned: the PCID (or cache
+    /// control bits) in the low bits are masked off.
     #[cfg(target_arch = "x86_64")]
     pub unsafe fn read() -> PAddr {
         let result: u64;
         asm!(   "mov $0, cr3"
             :   "=r"(result)
             ::: "intel" );
-        PAddr::from(result)
+        PAddr::from(result & ADDR_MASK)
+    }
+
+    /// Read the current process-context identifier from `$cr3`.
+    ///
+    /// If PCIDs are not enabled, this is the page-level cache control bits
+    /// instead.
+    ///
+    /// # Safety
+    /// + Reading from control registers while not in kernel mode will cause
+    ///   a general protection fault.
+    #[cfg(target_arch = "x86_64")]
+    pub unsafe fn read_pcid() -> u16 {
+        let result: u64;
+        asm!(   "mov $0, cr3"
+            :   "=r"(result)
+            ::: "intel" );
+        (result & PCID_MASK) as u16
     }
 
     /// Read the current value from `$cr3`.
@@ -138,6 +168,29 @@ pub mod cr3 {
             :  "intel");
     }
 
+    /// Write the page table address `addr` and the process-context
+    /// identifier `pcid` to `$cr3`.
+    ///
+    /// If `noflush` is true, TLB entries tagged with `pcid` are kept;
+    /// otherwise they are invalidated, as with a plain [`write`].
+    ///
+    /// [`write`]: fn.write.html
+    ///
+    /// # Safety
+    /// + Control registers should generally not be modified during normal
+    ///   operation.
+    /// + `CR4.PCIDE` must be set if `pcid` is nonzero or `noflush` is true.
+    #[cfg(target_arch = "x86_64")]
+    pub unsafe fn write_with_pcid(addr: PAddr, pcid: u16, noflush: bool) {
+        let addr: u64 = addr.into();
+        let mut value = (addr & ADDR_MASK) | (pcid as u64 & PCID_MASK);
+        if noflush { value |= NOFLUSH }
+        asm!(  "mov cr3, $0"
+            :: "r"(value)
+            :  "memory"
+            :  "intel");
+    }
+
     /// Write a value to `$cr3`.
     ///
     /// # Safety
@@ -0,0 +1,88 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The `CPUID` instruction.
+
+/// Leaf 1: processor info and feature bits.
+pub const LEAF_FEATURES: u32 = 0x1;
+/// Leaf 7: structured extended feature flags.
+pub const LEAF_EXT_FEATURES: u32 = 0x7;
+/// Leaf `0xA`: architectural performance monitoring.
+pub const LEAF_PERFMON: u32 = 0xa;
+/// Leaf `0xB`: extended topology enumeration, one subleaf per level.
+pub const LEAF_TOPOLOGY: u32 = 0xb;
+/// Leaf `0xD`: processor extended state enumeration.
+pub const LEAF_XSTATE: u32 = 0xd;
+/// Leaf `0x4000_0000`: the hypervisor's signature, in `%ebx:%ecx:%edx`.
+pub const LEAF_HYPERVISOR: u32 = 0x4000_0000;
+/// Leaf `0x4000_0001`: KVM paravirtual features, in `%eax`.
+pub const LEAF_KVM_FEATURES: u32 = 0x4000_0001;
+
+/// Leaf 1, `%ecx`: SSE4.2, including the `crc32` instruction, is supported.
+pub const ECX_SSE4_2: u32 = 1 << 20;
+/// Leaf 1, `%ecx`: process-context identifiers are supported.
+pub const ECX_PCID: u32 = 1 << 17;
+/// Leaf 1, `%ecx`: the `XSAVE` family of instructions is supported.
+pub const ECX_XSAVE: u32 = 1 << 26;
+/// Leaf 1, `%ecx`: the OS has enabled `XSAVE` (`CR4.OSXSAVE` is set).
+pub const ECX_OSXSAVE: u32 = 1 << 27;
+/// Leaf 1, `%ecx`: AVX is supported.
+pub const ECX_AVX: u32 = 1 << 28;
+/// Leaf 1, `%ecx`: the `rdrand` instruction is supported.
+pub const ECX_RDRAND: u32 = 1 << 30;
+/// Leaf 1, `%ecx`: we're running under a hypervisor.
+pub const ECX_HYPERVISOR: u32 = 1 << 31;
+/// Leaf 1, `%edx`: the CPU has a local APIC.
+pub const EDX_APIC: u32 = 1 << 9;
+/// Leaf 1, `%edx`: memory type range registers are supported.
+pub const EDX_MTRR: u32 = 1 << 12;
+/// Leaf 1, `%edx`: the page attribute table is supported.
+pub const EDX_PAT: u32 = 1 << 16;
+/// Leaf 1, `%edx`: `FXSAVE` and `FXRSTOR` are supported.
+pub const EDX_FXSR: u32 = 1 << 24;
+/// Leaf 1, `%edx`: `%ebx` bits 16-23 hold the number of logical processor
+/// IDs per package.
+pub const EDX_HTT: u32 = 1 << 28;
+/// Leaf 7, `%ebx`: AVX2 is supported.
+pub const EBX_AVX2: u32 = 1 << 5;
+/// Leaf 7, `%ebx`: supervisor mode execution prevention is supported.
+pub const EBX_SMEP: u32 = 1 << 7;
+/// Leaf 7, `%ebx`: the `invpcid` instruction is supported.
+pub const EBX_INVPCID: u32 = 1 << 10;
+/// Leaf 7, `%ebx`: the `rdseed` instruction is supported.
+pub const EBX_RDSEED: u32 = 1 << 18;
+/// Leaf 7, `%ebx`: supervisor mode access prevention is supported.
+pub const EBX_SMAP: u32 = 1 << 20;
+/// Leaf 7, `%ecx`: the `rdpid` instruction is supported.
+pub const ECX_RDPID: u32 = 1 << 22;
+/// KVM features, `%eax`: `MSR_KVM_SYSTEM_TIME_NEW` is supported.
+pub const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+
+/// The hypervisor signature KVM reports in leaf `0x4000_0000`.
+pub const KVM_SIGNATURE: &'static [u8; 12] = b"KVMKVMKVM\0\0\0";
+
+/// The registers returned by `CPUID`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuidResult { pub eax: u32
+                       , pub ebx: u32
+                       , pub ecx: u32
+                       , pub edx: u32
+                       }
+
+/// Execute `CPUID` with `%eax = leaf` and `%ecx = subleaf`.
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!(  "cpuid"
+            :  "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+            :  "{eax}"(leaf), "{ecx}"(subleaf)
+            :: "volatile" );
+    }
+    CpuidResult { eax: eax, ebx: ebx, ecx: ecx, edx: edx }
+}
@@ -49,8 +49,9 @@ pub struct Gate<H = InterruptHandler>
       pub offset_lower: u16
     , /// code segment selector (GDT or LDT)
       pub selector: segment::Selector
-    , /// always zero
-      _zero: u8
+    , /// the interrupt stack table entry to switch to (1 - 7), or zero to
+      /// stay on the current stack
+      ist: u8
     , /// indicates the gate's type and attributes.
       /// the second half indicates the type:
       ///   + `0b1100`: Call gate
@@ -78,7 +79,7 @@ impl<H> Gate<H> {
     pub const fn absent() -> Self {
        Gate { offset_lower: 0
             , selector: segment::Selector::from_raw(0)
-            , _zero: 0
+            , ist: 0
             , flags: GateFlags { bits:  0b1000_1110 }
             , offset_mid: 0
             , offset_upper: 0
@@ -102,6 +103,18 @@ impl<H> Gate<H> {
         self
     }
 
+    /// Have the CPU switch to entry `ist` (1 - 7) of the TSS's interrupt
+    /// stack table before calling the handler.
+    ///
+    /// # Panics
+    /// + If `ist` isn't between 1 and 7.
+    #[inline]
+    pub fn set_stack_index(&mut self, ist: u8) -> &mut Self {
+        assert!(ist >= 1 && ist <= 7, "no interrupt stack {}!", ist);
+        self.ist = ist;
+        self
+    }
+
 }
 
 
@@ -109,7 +122,7 @@ impl<H> Default for Gate<H> {
     fn default() -> Self {
         Gate { offset_lower: 0
              , selector: segment::Selector::from_raw(0)
-             , _zero: 0
+             , ist: 0
              , flags: GateFlags { bits: 0b1000_1110 }
              , offset_mid: 0
              , offset_upper: 0
@@ -142,7 +155,7 @@ impl convert::From<InterruptHandler> for Gate<InterruptHandler> {
             Gate { offset_lower: low
                  , offset_mid: mid
                  , offset_upper: high
-                 , selector: segment::Selector::from_cs()
+                 , selector: segment::KERNEL_CODE_SELECTOR
                  , flags: GateFlags::new_interrupt()
                  , ..Default::default()
                  }
@@ -163,7 +176,7 @@ impl convert::From<ErrorCodeHandler> for Gate<ErrorCodeHandler> {
             Gate { offset_lower: low
                  , offset_mid: mid
                  , offset_upper: high
-                 , selector: segment::Selector::from_cs()
+                 , selector: segment::KERNEL_CODE_SELECTOR
                  , flags: GateFlags::new_interrupt()
                  , ..Default::default()
                  }
@@ -189,7 +202,7 @@ impl convert::From<*const u8> for Gate {
             Gate { offset_lower: low
                  , offset_mid: mid
                  , offset_upper: high
-                 , selector: segment::Selector::from_cs()
+                 , selector: segment::KERNEL_CODE_SELECTOR
                  , flags: GateFlags::new_interrupt()
                  , ..Default::default()
                  }
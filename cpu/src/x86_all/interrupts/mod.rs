@@ -19,6 +19,7 @@ use vga::{CONSOLE, Color};
 
 use core::fmt;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 
 use context::InterruptFrame;
 
@@ -85,8 +86,39 @@ impl fmt::Display for PageFaultErrorCode {
    }
 }
 
+/// Number of hardware IRQ lines on the PICs.
+pub const NUM_IRQS: usize = 16;
+
+/// How many times each hardware IRQ has fired.
+static IRQ_COUNTS: [AtomicUsize; NUM_IRQS]
+    = [ ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT ];
+
+/// Record that hardware IRQ `irq` has fired.
+///
+/// IRQ handlers should call this before doing anything else.
+#[inline]
+pub fn count_irq(irq: usize) {
+    if let Some(count) = IRQ_COUNTS.get(irq) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of times hardware IRQ `irq` has fired.
+#[inline]
+pub fn irq_count(irq: usize) -> usize {
+    IRQ_COUNTS.get(irq)
+              .map(|count| count.load(Ordering::Relaxed))
+              .unwrap_or(0)
+}
+
 /// Handler for the system timer interrupt
 pub extern "x86-interrupt" fn timer(_frame: &InterruptFrame) {
+    count_irq(0);
     // do nothing, just signal the pics to end the IRQ
     // println!("timer!");
     unsafe { pics::end_pic_interrupt(0x20); }
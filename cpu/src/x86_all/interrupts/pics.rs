@@ -21,16 +21,13 @@
 //! PIC1 starts at 32 and PIC2 at 40.
 
 use Port;
+use ports::{PIC1_CMD, PIC1_DATA, PIC2_CMD, PIC2_DATA};
 use spin::Mutex;
 
 use core::mem::transmute;
 
 /// Starting offset for PIC1
 const OFFSET: u8 = 0x20;
-/// Command port for the follower PIC (PIC2)
-const FOLLOWER_CMD_PORT: u16 = 0xA0;
-/// Command port for the leader PIC (PIC1)
-const LEADER_CMD_PORT: u16 = OFFSET as u16;
 
 /// Commands to send to the PIC
 #[repr(u8)]
@@ -70,6 +67,12 @@ pub enum IRQ { /// System timer IRQ
                LPT1         = 7 + OFFSET
              , /// CMOS clock
                RTCTimer     = 8 + OFFSET
+             , /// Free for peripherals (such as PCI devices)
+               Peripheral9  = 9 + OFFSET
+             , /// Free for peripherals (such as PCI devices)
+               Peripheral10 = 10 + OFFSET
+             , /// Free for peripherals (such as PCI devices)
+               Peripheral11 = 11 + OFFSET
              , /// PS/2 mouse controller
                PS2Mouse     = 12 + OFFSET
              , /// Floating-point Coprocessor
@@ -96,16 +99,16 @@ impl PIC {
     /// Construct a new leader PIC
     pub const fn leader() -> PIC {
         PIC { offset: OFFSET
-            , command_port: Port::<u8>::new(LEADER_CMD_PORT)
-            , data_port: Port::<u8>::new(LEADER_CMD_PORT + 1)
+            , command_port: PIC1_CMD
+            , data_port: PIC1_DATA
             }
     }
 
     /// Construct a new follower PIC
     pub const fn follower() -> PIC {
         PIC { offset: OFFSET + 8
-            , command_port: Port::<u8>::new(FOLLOWER_CMD_PORT)
-            , data_port: Port::<u8>::new(FOLLOWER_CMD_PORT + 1)
+            , command_port: PIC2_CMD
+            , data_port: PIC2_DATA
             }
     }
 
@@ -255,6 +258,24 @@ pub unsafe fn initialize() {
 
 }
 
+/// Unmask IRQ line `irq` (numbered 0 to 15), so that the PICs will deliver
+/// it.
+///
+/// Lines on the follower PIC also need the leader's cascade line unmasked.
+pub fn unmask(irq: u8) {
+    let pics = PICS.lock();
+    let unmask_line = |pic: &PIC, line: u8| {
+        let mask = pic.data_port.read();
+        pic.send_data(mask & !(1 << line));
+    };
+    if irq < 8 {
+        unmask_line(&pics.0, irq);
+    } else {
+        unmask_line(&pics.1, irq - 8);
+        unmask_line(&pics.0, IRQ::Cascade as u8 - OFFSET);
+    }
+}
+
 /// If an interrupt is being handled by the PICs, end that interrupt.
 ///
 /// This is called by the interrupt handler at the end of all interrupts.
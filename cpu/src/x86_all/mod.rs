@@ -39,10 +39,13 @@ macro_rules! cpu_flag {
 }
 
 pub mod control_regs;
+pub mod cpuid;
 pub mod segment;
+pub mod ports;
 pub mod dtable;
 pub mod flags;
 pub mod timer;
+pub mod tsc;
 pub mod interrupts;
 
 /// Represents an x86 privilege level.
@@ -0,0 +1,50 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The fixed I/O ports of the legacy PC devices we talk to.
+//!
+//! Devices whose ports are found at runtime (the serial ports, from the
+//! BIOS data area, or virtio devices, from their PCI BARs) don't belong
+//! here.
+use {Port, PortWriteOnly};
+
+/// The first of COM1's eight UART registers, if the BIOS data area
+/// doesn't say otherwise.
+pub const COM1_BASE: u16 = 0x3f8;
+
+/// The leader PIC's command port.
+pub const PIC1_CMD: Port<u8> = Port::new(0x20);
+/// The leader PIC's data port.
+pub const PIC1_DATA: Port<u8> = Port::new(0x21);
+/// The follower PIC's command port.
+pub const PIC2_CMD: Port<u8> = Port::new(0xa0);
+/// The follower PIC's data port.
+pub const PIC2_DATA: Port<u8> = Port::new(0xa1);
+
+/// PIT channel 0, which is wired to IRQ 0.
+pub const PIT_CH0: Port<u8> = Port::new(0x40);
+/// PIT channel 2, which is wired to the PC speaker.
+pub const PIT_CH2: Port<u8> = Port::new(0x42);
+/// The PIT's mode/command register.
+pub const PIT_CMD: PortWriteOnly<u8> = PortWriteOnly::new(0x43);
+/// The PC speaker and PIT channel 2 gate control port.
+pub const PIT_GATE: Port<u8> = Port::new(0x61);
+
+/// The PS/2 controller's data port.
+pub const PS2_DATA: Port<u8> = Port::new(0x60);
+/// The PS/2 controller's command port. Reading this port reads the
+/// controller's status register instead.
+pub const PS2_CMD: PortWriteOnly<u8> = PortWriteOnly::new(0x64);
+
+/// PCI configuration mechanism #1's address port.
+pub const PCI_CONFIG_ADDRESS: Port<u32> = Port::new(0xcf8);
+/// PCI configuration mechanism #1's data port.
+pub const PCI_CONFIG_DATA: Port<u32> = Port::new(0xcfc);
+
+/// QEMU's `isa-debug-exit` device, when it's been given one.
+pub const QEMU_DEBUG_EXIT: PortWriteOnly<u32> = PortWriteOnly::new(0xf4);
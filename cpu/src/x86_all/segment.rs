@@ -106,20 +106,65 @@ bitflags! {
                                         | RPL_RING_3.bits
 
                             , /// If the Table Indicator (TI) is 0, use the GDT
-                              const TI_GDT = 0 << 3
+                              const TI_GDT = 0 << 2
 
                             , /// If the TI is 1, use the LDT
-                              const TI_LDT = 1 << 3
+                              const TI_LDT = 1 << 2
                             }
 }
 
+/// Which descriptor table a segment selector indexes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u16)]
+pub enum TableIndicator { /// The Global Descriptor Table
+                          Gdt = 0
+                        , /// The Local Descriptor Table
+                          Ldt = 1
+                        }
+
+/// The kernel code segment, `gdt64.code` in `boot.asm`.
+#[cfg(target_arch = "x86_64")]
+pub const KERNEL_CODE_SELECTOR: Selector
+    = Selector::new(1, PrivilegeLevel::KernelMode, TableIndicator::Gdt);
+
+/// The kernel data segment, `gdt64.data` in `boot.asm`.
+///
+/// The kernel stack segment must be the descriptor after the kernel code
+/// segment, for `syscall`.
+#[cfg(target_arch = "x86_64")]
+pub const KERNEL_DATA_SELECTOR: Selector
+    = Selector::new(2, PrivilegeLevel::KernelMode, TableIndicator::Gdt);
+
+/// The user data segment, `gdt64.user_data` in `boot.asm`.
+///
+/// `sysret` expects the user stack segment to come right before the user
+/// code segment.
+#[cfg(target_arch = "x86_64")]
+pub const USER_DATA_SELECTOR: Selector
+    = Selector::new(3, PrivilegeLevel::UserMode, TableIndicator::Gdt);
+
+/// The user code segment, `gdt64.user_code` in `boot.asm`.
+#[cfg(target_arch = "x86_64")]
+pub const USER_CODE_SELECTOR: Selector
+    = Selector::new(4, PrivilegeLevel::UserMode, TableIndicator::Gdt);
+
+/// The TSS descriptor, which comes after the user segments.
+///
+/// `gdt64` in `boot.asm` doesn't have one; each CPU's own GDT does.
+#[cfg(target_arch = "x86_64")]
+pub const TSS_SELECTOR: Selector
+    = Selector::new(5, PrivilegeLevel::KernelMode, TableIndicator::Gdt);
+
 impl Selector {
     /// Create a new `Selector`
     ///
     /// # Arguments
     ///   - `index`: the index in the GDT or LDT
-    pub const fn new(index: u16) -> Self {
-        Selector { bits: index << 3 }
+    ///   - `rpl`: the requested privilege level
+    ///   - `ti`: which table `index` is in
+    pub const fn new(index: u16, rpl: PrivilegeLevel, ti: TableIndicator)
+                    -> Self {
+        Selector { bits: index << 3 | (ti as u16) << 2 | rpl as u16 }
     }
 
     /// Create a new `Selector` from raw bits
@@ -155,7 +200,7 @@ impl Selector {
     ///
     /// If the segment is already an LDT segment, this will quietly do nothing.
     #[inline] pub fn set_local(&mut self) -> &mut Self {
-        self.insert(TI_GDT);
+        self.insert(TI_LDT);
         self
     }
 
@@ -163,15 +208,21 @@ impl Selector {
     ///
     /// The RPL must be in the range between 0 and 3.
     #[inline] pub fn set_rpl(&mut self, rpl: PrivilegeLevel) -> &mut Self {
-        self.bits &= rpl as u16;
+        self.remove(RPL);
+        self.bits |= rpl as u16;
         self
     }
 
     /// Checks the segment's privelige.
-    #[inline] pub fn get_rpl(&self) -> PrivilegeLevel {
+    #[inline] pub fn rpl(&self) -> PrivilegeLevel {
         unsafe { mem::transmute(*self & RPL) }
     }
 
+    /// Returns true if this selector indexes the LDT rather than the GDT.
+    #[inline] pub fn is_ldt(&self) -> bool {
+        self.contains(TI_LDT)
+    }
+
 
     /// Load this selector into the stack segment register (`ss`).
     pub unsafe fn load_ss(&self) {
@@ -246,8 +297,8 @@ impl fmt::Display for Selector {
                    else if self.contains(RPL_RING_1) { "1" }
                    else if self.contains(RPL_RING_0) { "0" }
                    else { unreachable!() };
-        let table = if self.contains(TI_GDT) { "GDT" }
-                    else { "LDT" };
+        let table = if self.is_ldt() { "LDT" }
+                    else { "GDT" };
         write!(f, "{}[{}], Ring {}", table, self.index(), ring)
     }
 }
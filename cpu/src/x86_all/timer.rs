@@ -10,8 +10,6 @@
 #![warn(missing_docs)]
 pub mod timestamp {
     //! x86 Timestamp register
-    use core::mem;
-
 
     /// Read the current value of the timestamp counter.
     ///
@@ -20,9 +18,11 @@ pub mod timestamp {
     ///   `%cr4` is set and the CPL is greater than 0.
     pub unsafe fn rtdsc() -> u64 {
         let (high, low): (u32, u32);
+        // without `volatile`, two reads could be merged into one.
         asm!( "rdtsc"
-            : "={eax}" (low), "={edx}" (high));
-        mem::transmute((high, low))
+            : "={eax}" (low), "={edx}" (high)
+            ::: "volatile");
+        (high as u64) << 32 | low as u64
     }
 
     /// Read the current timestamp, after other instructions have been executed.
@@ -35,7 +35,7 @@ pub mod timestamp {
         asm!( "rdtscp"
             : "={eax}" (low), "={edx}" (high)
             ::: "volatile");
-        mem::transmute((high, low))
+        (high as u64) << 32 | low as u64
     }
 
     /// Returns true if timestamps are currently available.
@@ -0,0 +1,111 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Wall-clock time derived from the timestamp counter.
+//!
+//! The TSC ticks at a fixed but unknown rate, so before it can be turned
+//! into nanoseconds it has to be calibrated against a timer with a known
+//! frequency. We use channel 2 of the legacy PIT for this, since it can be
+//! polled without taking any interrupts.
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::timer::timestamp;
+use super::ports::{PIT_CH2, PIT_CMD, PIT_GATE};
+
+/// TSC frequency in kHz (i.e. ticks per millisecond).
+///
+/// This is 0 until [`calibrate`](fn.calibrate.html) has been called.
+static TSC_KHZ: AtomicUsize = AtomicUsize::new(0);
+
+/// PIT input clock frequency, in Hz.
+const PIT_HZ: u64 = 1_193_182;
+/// Length of the calibration window, in milliseconds.
+const CALIBRATE_MS: u64 = 10;
+
+/// Calibrate the TSC against PIT channel 2.
+///
+/// Returns the measured TSC frequency in kHz.
+///
+/// # Safety
+/// + This reprograms PIT channel 2 and the PC speaker gate, so nothing else
+///   may be using them while this runs.
+pub unsafe fn calibrate() -> u64 {
+    let latch = PIT_HZ * CALIBRATE_MS / 1000;
+
+    // enable the channel 2 gate, but keep the speaker output disabled
+    PIT_GATE.write((PIT_GATE.read() & !0x02) | 0x01);
+    // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+    PIT_CMD.write(0b1011_0000);
+    PIT_CH2.write(latch as u8);
+    PIT_CH2.write((latch >> 8) as u8);
+
+    let start = timestamp::rtdsc();
+    // bit 5 of the gate port goes high when channel 2 reaches terminal count
+    while PIT_GATE.read() & 0x20 == 0 { }
+    let end = timestamp::rtdsc();
+
+    let khz = (end - start) / CALIBRATE_MS;
+    TSC_KHZ.store(khz as usize, Ordering::Relaxed);
+    khz
+}
+
+/// Returns the calibrated TSC frequency in kHz, or `None` if the TSC has
+/// not yet been calibrated.
+#[inline]
+pub fn khz() -> Option<u64> {
+    match TSC_KHZ.load(Ordering::Relaxed) {
+        0 => None
+      , khz => Some(khz as u64)
+    }
+}
+
+/// Convert a count of TSC ticks to nanoseconds.
+///
+/// Returns 0 if the TSC has not yet been calibrated.
+#[inline]
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    match khz() {
+        None => 0
+        // split the division so that `ticks * 1_000_000` can't overflow
+      , Some(khz) => (ticks / khz) * 1_000_000
+                   + (ticks % khz) * 1_000_000 / khz
+    }
+}
+
+/// A function returning nanoseconds since boot, used instead of the TSC.
+pub type ClockSource = fn() -> u64;
+
+/// The clock set by [`set_clock_source`](fn.set_clock_source.html), as a
+/// `usize`, or 0 to use the TSC.
+static CLOCK_SOURCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Tell [`current_ns`](fn.current_ns.html) to read `clock` rather than the
+/// TSC, such as when the hypervisor provides a better one.
+///
+/// This should be done before anything has read the time: the two clocks
+/// count from different starting points.
+pub fn set_clock_source(clock: ClockSource) {
+    CLOCK_SOURCE.store(clock as usize, Ordering::SeqCst);
+}
+
+/// Returns the number of nanoseconds since the TSC was reset, or since
+/// boot if a clock source has been set.
+///
+/// Before [`calibrate`](fn.calibrate.html) has been called, and without a
+/// clock source, this always returns 0.
+#[inline]
+pub fn current_ns() -> u64 {
+    match CLOCK_SOURCE.load(Ordering::Relaxed) {
+        0 => ticks_to_ns(unsafe { timestamp::rtdsc() })
+      , clock => {
+            let clock: ClockSource = unsafe { mem::transmute(clock) };
+            clock()
+        }
+    }
+}
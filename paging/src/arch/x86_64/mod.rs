@@ -27,6 +27,7 @@ pub mod table;
 pub mod tlb;
 pub mod temp;
 pub mod cr3;
+pub mod space;
 #[derive(Debug)]
 pub struct ActivePageTable { pml4: ActivePML4 }
 
@@ -227,41 +228,45 @@ impl Mapper for ActivePML4 {
         self.map(page, frame, flags, alloc)
     }
 
+    /// Map the 2 MiB page starting at `page` to 512 contiguous frames,
+    /// aligned to a 2 MiB boundary.
+    fn map_to_huge<A>( &mut self
+                     , page: VirtualPage
+                     , flags: EntryFlags
+                     , alloc: &mut A)
+                     -> MapResult<()>
+    where A: FrameAllocator {
+        if page.number % N_ENTRIES != 0 {
+            return Err(MapErr::Other { message: "map to huge"
+                                     , page: page
+                                     , cause: "page isn't 2 MiB aligned"
+                                     })
+        }
+        let pd = self.pml4_mut()
+                     .create_next(page, alloc)
+                     .and_then(|pdpt| pdpt.create_next(page, alloc))?;
+        if !pd[page].is_unused() {
+            return Err(MapErr::Other { message: "map to huge"
+                                     , page: page
+                                     , cause: "already mapped"
+                                     })
+        }
+        let frames = unsafe {
+            alloc.allocate_aligned(HUGE_PAGE_ORDER, HUGE_PAGE_ORDER)
+        }.map_err(|err| MapErr::Alloc { message: "map to huge"
+                                      , page: page
+                                      , cause: err
+                                      })?;
+        pd[page].set(frames.start, flags | PRESENT | HUGE_PAGE);
+        Ok(())
+    }
+
     /// Unmap the given `VirtualPage`.
     ///
     /// All freed frames are returned to the given `FrameAllocator`.
     fn unmap<A>(&mut self, page: VirtualPage, alloc: &mut A) -> MapResult<()>
     where A: FrameAllocator {
-        use self::tlb::Flush;
-
-        // get the page table entry corresponding to the page.
-        let page_table = self.pml4_mut()
-                             .next_table_mut(page)
-                             .and_then(|pdpt| pdpt.next_table_mut(page))
-                             .and_then(|pd| pd.next_table_mut(page))
-                             .ok_or(MapErr::Other {
-                                message: "unmap"
-                              , page: page
-                              , cause: "huge pages not supported"
-                            })?;
-        // index the entry from the table
-        let entry = &mut page_table[page];
-        trace!("got page table entry for {:?}", page);
-        // get the pointed frame for the page table entry.
-        let frame = entry.get_frame()
-                         .ok_or(MapErr::Other {
-                           message: "unmap"
-                         , page: page
-                         , cause: "it was not mapped"
-                       })?;
-        trace!("page table entry for {:?} points to {:?}", page, frame);
-        // mark the page table entry as unused
-        entry.set_unused();
-        trace!("set page table entry for {:?} as unused", page);
-        // deallocate the frame and flush the translation lookaside buffer
-        // this is safe because we're in kernel mode
-        unsafe { page.invlpg() };
-        trace!("flushed TLB");
+        let frame = self.unmap_no_free(page)?;
         unsafe {
             // this is hopefully safe because nobody else should be using an
             // allocated page frame
@@ -289,12 +294,95 @@ impl ActivePML4 {
         unsafe { self.0.as_mut() }
     }
 
+    /// Returns the page table entry for `page`, or `None` if the tables
+    /// leading to it don't exist.
+    #[inline]
+    pub fn entry_mut(&mut self, page: VirtualPage) -> Option<&mut Entry> {
+        self.pml4_mut().page_table_mut_for(page).map(|table| &mut table[page])
+    }
+
     /// Returns true if the given page is mapped.
     #[inline]
     pub fn is_mapped(&self, page: &VirtualPage) -> bool {
          self.translate_page(*page).is_some()
     }
 
+    /// Returns the effective flags for the given page, or `None` if it is
+    /// not mapped.
+    ///
+    /// The permissions at every level of the page table hierarchy are taken
+    /// into account, so e.g. a page is only reported as `USER_ACCESSIBLE`
+    /// if every table on the way to it is, too.
+    pub fn flags_of(&self, page: VirtualPage) -> Option<EntryFlags> {
+        #[inline]
+        fn combine(upper: EntryFlags, lower: EntryFlags) -> EntryFlags {
+            let and_bits = PRESENT | WRITABLE | USER_ACCESSIBLE;
+            (lower - and_bits) | (lower & upper & and_bits)
+                | (upper & NO_EXECUTE)
+        }
+
+        let pml4 = self.pml4();
+        let flags = pml4[page].flags();
+
+        let pdpt = pml4.next_table(page)?;
+        let entry = &pdpt[page];
+        let flags = combine(flags, entry.flags());
+        if entry.is_huge() {
+            return if flags.is_present() { Some(flags) } else { None }
+        }
+
+        let pd = pdpt.next_table(page)?;
+        let entry = &pd[page];
+        let flags = combine(flags, entry.flags());
+        if entry.is_huge() {
+            return if flags.is_present() { Some(flags) } else { None }
+        }
+
+        let pt = pd.next_table(page)?;
+        let flags = combine(flags, pt[page].flags());
+        if flags.is_present() { Some(flags) } else { None }
+    }
+
+    /// Unmap the given `VirtualPage`, returning the frame it pointed to
+    /// without freeing it.
+    ///
+    /// Only this CPU's TLB is flushed. If other CPUs may have the page
+    /// cached, the frame must not be handed back to the allocator until
+    /// they've flushed it too, so freeing it is left to the caller.
+    pub fn unmap_no_free(&mut self, page: VirtualPage)
+                        -> MapResult<PhysicalPage> {
+        use self::tlb::Flush;
+
+        // get the page table entry corresponding to the page.
+        let page_table = self.pml4_mut()
+                             .next_table_mut(page)
+                             .and_then(|pdpt| pdpt.next_table_mut(page))
+                             .and_then(|pd| pd.next_table_mut(page))
+                             .ok_or(MapErr::Other {
+                                message: "unmap"
+                              , page: page
+                              , cause: "huge pages not supported"
+                            })?;
+        // index the entry from the table
+        let entry = &mut page_table[page];
+        trace!("got page table entry for {:?}", page);
+        // get the pointed frame for the page table entry.
+        let frame = entry.get_frame()
+                         .ok_or(MapErr::Other {
+                           message: "unmap"
+                         , page: page
+                         , cause: "it was not mapped"
+                       })?;
+        trace!("page table entry for {:?} points to {:?}", page, frame);
+        // mark the page table entry as unused
+        entry.set_unused();
+        trace!("set page table entry for {:?} as unused", page);
+        // flush the translation lookaside buffer
+        // this is safe because we're in kernel mode
+        unsafe { page.invlpg() };
+        trace!("flushed TLB");
+        Ok(frame)
+    }
 
 }
 
@@ -323,6 +411,12 @@ impl InactivePageTable {
 
         Ok(InactivePageTable { pml4_frame: frame })
     }
+
+    /// Returns the frame containing this page table's PML4.
+    #[inline]
+    pub fn frame(&self) -> PhysicalPage {
+        self.pml4_frame
+    }
 }
 
 pub fn test_paging<A>(alloc: &mut A) -> MapResult<()>
@@ -403,12 +497,16 @@ where A: FrameAllocator {
         kinfoln!(dots: " . . ", "Remapping kernel ELF sections.");
 
         for section in sections { // remap ELF sections
+            // the sections say where the kernel was linked, not where the
+            // boot stub moved it to.
+            let start = section.address() + params.kernel_slide;
+            let end = section.end_address() + params.kernel_slide;
             attempt!(
-                if section.address().is_page_aligned() {
+                if start.is_page_aligned() {
                     let flags = EntryFlags::from(section);
 
-                    let start_frame = PhysicalPage::from(section.address());
-                    let end_frame = PhysicalPage::from(section.end_address());
+                    let start_frame = PhysicalPage::from(start);
+                    let end_frame = PhysicalPage::from(end);
 
                     for frame in start_frame .. end_frame {
                         let _ = pml4.identity_map(frame, flags, alloc)?;
@@ -439,6 +537,21 @@ where A: FrameAllocator {
             let _ = pml4.identity_map(frame, PRESENT, alloc)?;
                 // .expect("couldn't identity map Multiboot {:?}", frame);
         }
+
+        // map all usable physical memory at `PHYS_OFFSET`, so that the
+        // kernel can get at any frame (such as another address space's
+        // page tables) without remapping anything.
+        kinfoln!( dots: " . . ", "Mapping physical memory at {:#x}"
+                , space::PHYS_OFFSET );
+        for area in params.mem_map().filter(|a| a.is_usable) {
+            let start_frame = PhysicalPage::containing(area.start_addr);
+            let end_frame = PhysicalPage::containing(area.end_addr);
+            for frame in start_frame .. end_frame {
+                let page = VirtualPage::containing(
+                    space::phys_to_virt(frame.base_addr()));
+                let _ = pml4.map(page, frame, WRITABLE | NO_EXECUTE, alloc)?;
+            }
+        }
         Ok(())
     })?;
 
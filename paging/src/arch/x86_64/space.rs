@@ -0,0 +1,234 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Creating and copying address spaces.
+//!
+//! The recursive mapping only lets us edit the *active* page table, which
+//! is no good for building a new task's page tables. Instead, everything in
+//! here goes through the direct map of physical memory at `PHYS_OFFSET`,
+//! which is set up by `kernel_remap`.
+//!
+//! Every address space shares the kernel's PML4 entries (the identity mapped
+//! kernel in entry 0, and everything in the upper half), so kernel mappings
+//! are visible no matter which task is running. User space is PML4 entries
+//! `USER_PML4_START` up to (but not including) `USER_PML4_END`.
+use alloc::FrameAllocator;
+use memory::{PAddr, PhysicalPage, VAddr};
+use ::{MapResult, MapErr};
+
+use super::InactivePageTable;
+use super::table::*;
+
+/// Base of the direct map of all physical memory.
+pub const PHYS_OFFSET: usize = 0xffff_8000_0000_0000;
+
+/// The first PML4 entry belonging to user space.
+pub const USER_PML4_START: usize = 1;
+/// One past the last PML4 entry belonging to user space.
+pub const USER_PML4_END: usize = 256;
+
+/// Returns the virtual address at which `addr` is mapped in the direct map.
+#[inline]
+pub fn phys_to_virt(addr: PAddr) -> VAddr {
+    VAddr::from(PHYS_OFFSET + *addr as usize)
+}
+
+/// Returns the physical address mapped at `addr` in the direct map.
+///
+/// # Panics
+/// + If `addr` isn't in the direct map.
+#[inline]
+pub fn virt_to_phys(addr: VAddr) -> PAddr {
+    let addr = addr.as_usize();
+    assert!(addr >= PHYS_OFFSET, "{:#x} is not in the direct map!", addr);
+    PAddr::from((addr - PHYS_OFFSET) as u64)
+}
+
+/// Access the page table stored in `frame` through the direct map.
+///
+/// # Safety
+/// + `frame` must actually contain a page table of level `L`, and the
+///   caller must not create aliasing mutable references to it.
+#[inline]
+pub unsafe fn table_at<L>(frame: PhysicalPage) -> &'static mut Table<L>
+where L: TableLevel {
+    &mut *phys_to_virt(frame.base_addr()).as_mut_ptr::<Table<L>>()
+}
+
+/// Allocate and zero a frame for a new page table.
+fn new_table<L, A>(alloc: &mut A, message: &'static str)
+                  -> MapResult<(PhysicalPage, &'static mut Table<L>)>
+where L: TableLevel
+    , A: FrameAllocator {
+    let frame = unsafe { alloc.allocate() }
+        .map_err(|_| MapErr::NoPage { message: message
+                                    , cause: "out of frames" })?;
+    let table = unsafe { table_at::<L>(frame) };
+    table.zero();
+    Ok((frame, table))
+}
+
+/// Create a new address space, with the kernel mapped but an empty user
+/// half.
+pub fn new_address_space<A>(alloc: &mut A) -> MapResult<InactivePageTable>
+where A: FrameAllocator {
+    let (frame, pml4) = new_table::<PML4Level, A>(alloc, "new address space")?;
+    let active = unsafe { &*PML4_PTR };
+    pml4[0] = active[0].clone();
+    for i in USER_PML4_END .. N_ENTRIES - 1 {
+        pml4[i] = active[i].clone();
+    }
+    // the recursive entry must point at the new table itself
+    pml4[N_ENTRIES - 1].set(frame, PRESENT | WRITABLE);
+    Ok(InactivePageTable { pml4_frame: frame })
+}
+
+/// Share every present user page in `src` with `dst`, copy-on-write.
+///
+/// New frames are allocated for every intermediate table, but the pages
+/// themselves are shared, and `share` is called with each of their frames so
+/// that the new reference can be counted. Pages that are swapped out are
+/// shared too, and `share_swap` is called with each of their swap slots.
+/// Writable pages are made read-only and marked `COPY_ON_WRITE` in both
+/// address spaces, so that the first write to one from either side faults,
+/// and can be given its own copy.
+/// `dst` should have an empty user half (as returned by
+/// [`new_address_space`](fn.new_address_space.html)).
+///
+/// If `src` is the active address space, the TLB must be flushed afterwards,
+/// or its pages may stay writable.
+//  TODO: frames allocated before a failure are leaked.
+pub fn clone_user_address_space<A, F, G>( src: &mut Table<PML4Level>
+                                        , dst: &mut Table<PML4Level>
+                                        , alloc: &mut A
+                                        , share: &mut F
+                                        , share_swap: &mut G)
+                                        -> MapResult<()>
+where A: FrameAllocator
+    , F: FnMut(PhysicalPage)
+    , G: FnMut(u64) {
+    for i in USER_PML4_START .. USER_PML4_END {
+        if let Some(src_frame) = src[i].get_frame() {
+            let (frame, pdpt) = new_table::<PDPTLevel, A>(alloc, "clone PDPT")?;
+            clone_pdpt( unsafe { table_at(src_frame) }, pdpt, alloc, share
+                      , share_swap )?;
+            dst[i].set(frame, src[i].flags());
+        }
+    }
+    Ok(())
+}
+
+fn clone_pdpt<A, F, G>( src: &mut Table<PDPTLevel>
+                      , dst: &mut Table<PDPTLevel>
+                      , alloc: &mut A, share: &mut F, share_swap: &mut G)
+                      -> MapResult<()>
+where A: FrameAllocator
+    , F: FnMut(PhysicalPage)
+    , G: FnMut(u64) {
+    for i in 0 .. N_ENTRIES {
+        if let Some(src_frame) = src[i].get_frame() {
+            if src[i].is_huge() { return Err(huge_page_err()) }
+            let (frame, pd) = new_table::<PDLevel, A>(alloc, "clone PD")?;
+            clone_pd( unsafe { table_at(src_frame) }, pd, alloc, share
+                    , share_swap )?;
+            dst[i].set(frame, src[i].flags());
+        }
+    }
+    Ok(())
+}
+
+fn clone_pd<A, F, G>( src: &mut Table<PDLevel>, dst: &mut Table<PDLevel>
+                    , alloc: &mut A, share: &mut F, share_swap: &mut G)
+                    -> MapResult<()>
+where A: FrameAllocator
+    , F: FnMut(PhysicalPage)
+    , G: FnMut(u64) {
+    for i in 0 .. N_ENTRIES {
+        if let Some(src_frame) = src[i].get_frame() {
+            if src[i].is_huge() { return Err(huge_page_err()) }
+            let (frame, pt) = new_table::<PTLevel, A>(alloc, "clone PT")?;
+            clone_pt(unsafe { table_at(src_frame) }, pt, share, share_swap);
+            dst[i].set(frame, src[i].flags());
+        }
+    }
+    Ok(())
+}
+
+fn clone_pt<F, G>( src: &mut Table<PTLevel>, dst: &mut Table<PTLevel>
+                 , share: &mut F, share_swap: &mut G)
+where F: FnMut(PhysicalPage)
+    , G: FnMut(u64) {
+    for i in 0 .. N_ENTRIES {
+        if let Some(slot) = src[i].swap_slot() {
+            share_swap(slot);
+            dst[i].set_swapped(slot);
+        } else if let Some(frame) = src[i].get_frame() {
+            let mut flags = src[i].flags();
+            if flags.contains(WRITABLE) {
+                flags.remove(WRITABLE);
+                flags.insert(COPY_ON_WRITE);
+                src[i].set(frame, flags);
+            }
+            share(frame);
+            dst[i].set(frame, flags);
+        }
+    }
+}
+
+#[inline]
+fn huge_page_err() -> MapErr {
+    MapErr::NoPage { message: "clone user address space"
+                   , cause: "huge pages not supported in user space" }
+}
+
+/// Free an address space: every frame mapped in its user half, the page
+/// tables that map them, and finally the PML4 itself. `free_swap` is called
+/// with the swap slot of every page that's swapped out.
+///
+/// # Safety
+/// + The address space must not be active, and nothing else may be using
+///   it.
+pub unsafe fn free_address_space<A, F>( pml4_frame: PhysicalPage
+                                      , alloc: &mut A
+                                      , free_swap: &mut F)
+where A: FrameAllocator
+    , F: FnMut(u64) {
+    let pml4 = table_at::<PML4Level>(pml4_frame);
+    for i in USER_PML4_START .. USER_PML4_END {
+        let pdpt_frame = match pml4[i].get_frame() {
+            Some(frame) => frame
+          , None => continue
+        };
+        let pdpt = table_at::<PDPTLevel>(pdpt_frame);
+        for pdpt_entry in pdpt.entries() {
+            let pd_frame = match pdpt_entry.get_frame() {
+                Some(frame) if !pdpt_entry.is_huge() => frame
+              , _ => continue
+            };
+            let pd = table_at::<PDLevel>(pd_frame);
+            for pd_entry in pd.entries() {
+                let pt_frame = match pd_entry.get_frame() {
+                    Some(frame) if !pd_entry.is_huge() => frame
+                  , _ => continue
+                };
+                let pt = table_at::<PTLevel>(pt_frame);
+                for entry in pt.entries() {
+                    if let Some(slot) = entry.swap_slot() {
+                        free_swap(slot)
+                    } else if let Some(frame) = entry.get_frame() {
+                        alloc.deallocate(frame)
+                    }
+                }
+                alloc.deallocate(pt_frame);
+            }
+            alloc.deallocate(pd_frame);
+        }
+        alloc.deallocate(pdpt_frame);
+    }
+    alloc.deallocate(pml4_frame);
+}
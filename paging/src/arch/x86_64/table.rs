@@ -18,6 +18,8 @@ use ::{ MapResult, MapErr};
 
 /// The number of entries in a page table.
 pub const N_ENTRIES: usize = 512;
+/// A 2 MiB huge page is `2^HUGE_PAGE_ORDER` frames.
+pub const HUGE_PAGE_ORDER: usize = 9;
 /// Size of a page table (in bytes)
 pub const PAGE_TABLE_SIZE: usize = N_ENTRIES * PAGE_SIZE as usize;
 
@@ -31,7 +33,7 @@ pub const PML4_PTR: *mut Table<PML4Level> = PML4_VADDR as *mut _;
 pub const ENTRY_FLAGS_MASK: u64 = (PAGE_SIZE as u64 - 1) as u64;
 
 /// A page table
-#[repr(C)]
+#[repr(C, align(4096))]
 pub struct Table<L>
 where L: TableLevel { /// The entries in the page table.
                       entries: [Entry; N_ENTRIES]
@@ -179,6 +181,12 @@ impl<L: TableLevel> Table<L>  {
         self
     }
 
+    /// Returns all of this table's entries.
+    #[inline]
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
     /// Return the start physical address of this `Table`
     #[inline]
     pub fn start_paddr(&self) -> PAddr {
@@ -249,7 +257,10 @@ impl<L: Sublevel> Table<L> {
               })?;
             //println!("done.");
 
-            self[i].set(frame, PRESENT | WRITABLE);
+            // the CPU checks permissions at every level, so intermediate
+            // tables are as permissive as possible and access is controlled
+            // by the bottom-level entries.
+            self[i].set(frame, PRESENT | WRITABLE | USER_ACCESSIBLE);
             //println!("setted.");
             self.next_table_mut(i).map(Table::zero)
         } else {
@@ -275,12 +286,24 @@ bitflags! {
         /// by this entry
         const WRITABLE =        1 << 1
       , const USER_ACCESSIBLE = 1 << 2
+        /// Write-through flag (PWT). With `NO_CACHE`, this picks the
+        /// page's entry in the page attribute table, which is combined
+        /// with the MTRRs' type for the page: with the PAT as it is at
+        /// reset, `NO_CACHE` alone is UC-, which MTRR write-combining
+        /// overrides, and both together are UC, which nothing does.
       , const WRITE_THROUGH =   1 << 3
+        /// Cache disable flag (PCD). See `WRITE_THROUGH`.
       , const NO_CACHE =        1 << 4
       , const ACCESSED =        1 << 5
       , const DIRTY =           1 << 6
       , const HUGE_PAGE =       1 << 7
       , const GLOBAL =          1 << 8
+        /// Ignored by the CPU: the page is shared copy-on-write, and is
+        /// read-only until it's been copied.
+      , const COPY_ON_WRITE =   1 << 9
+        /// Ignored by the CPU: the page isn't present, but has been written
+        /// out to swap, and bits 12 to 63 are its swap slot.
+      , const SWAPPED =         1 << 10
       , const NO_EXECUTE =      1 << 63
     }
 }
@@ -320,7 +343,7 @@ impl EntryFlags {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Entry(u64);
 
 impl Entry {
@@ -372,7 +395,8 @@ impl Entry {
     /// Returns the physical address pointed to by this page table entry
     #[inline]
     pub fn get_addr(&self) -> PAddr {
-        PAddr::from(self.0 & PML4_VADDR)
+        // bits 12-51 hold the address; the high bits are flags (e.g. NX)
+        PAddr::from(self.0 & 0x000fffff_fffff000)
     }
 
     /// Returns the frame in memory pointed to by this page table entry.
@@ -391,6 +415,23 @@ impl Entry {
         self.0 = addr | flags.bits();
     }
 
+    /// Returns the swap slot of a page that's been swapped out, or `None`
+    /// if it hasn't been.
+    #[inline]
+    pub fn swap_slot(&self) -> Option<u64> {
+        if self.0 & (PRESENT | SWAPPED).bits() == SWAPPED.bits() {
+            Some(self.0 >> 12)
+        } else {
+            None
+        }
+    }
+
+    /// Mark this entry's page as swapped out to `slot`, and not present.
+    pub fn set_swapped(&mut self, slot: u64) {
+        assert!(slot < 1 << 52, "swap slot doesn't fit in an entry!");
+        self.0 = slot << 12 | SWAPPED.bits();
+    }
+
 }
 
 impl<'a> convert::From<&'a elf::Section<u64>> for EntryFlags {
@@ -3,11 +3,14 @@ use super::{Page, VirtualPage};
 
 /// Invalidate the TLB completely by reloading the CR3 register.
 ///
+/// With PCIDs enabled, only the entries tagged with the current PCID are
+/// invalidated.
+///
 /// # Safety
 /// + Causes a general protection fault if not executed in kernel mode.
 pub unsafe fn flush_all() {
     use cpu::control_regs::cr3;
-    cr3::write(cr3::read());
+    cr3::write_with_pcid(cr3::read(), cr3::read_pcid(), false);
 }
 
 /// Something which may be flushed from the TLB
@@ -14,6 +14,7 @@
 #![feature(unique)]
 #![feature(associated_consts, const_fn)]
 #![feature(core_intrinsics)]
+#![feature(repr_align, attr_literals)]
 #![no_std]
 
 #[macro_use] extern crate bitflags;
@@ -106,6 +107,20 @@ pub trait Mapper {
                     -> MapResult<()>
     where A: FrameAllocator;
 
+    /// Map the huge page starting at `page` to any free, suitably aligned,
+    /// physically contiguous frames.
+    ///
+    /// # Arguments
+    /// + `page`: the first `VirtualPage` of the huge page, which must be
+    ///           aligned to the size of a huge page
+    /// + `flags`: the page table entry flags.
+    /// + `alloc`: a memory allocator
+    fn map_to_huge<A>( &mut self, page: VirtualPage
+                     , flags: Self::Flags
+                     , alloc: &mut A)
+                     -> MapResult<()>
+    where A: FrameAllocator;
+
     /// Unmap the given `VirtualPage`.
     ///
     /// All freed frames are returned to the given `FrameAllocator`.
@@ -47,6 +47,9 @@ pub struct InitParams {
     pub kernel_base: PAddr
   , /// The top of the kernel memory range
     pub kernel_top: PAddr
+  , /// How far the kernel was moved from where it was linked, which is
+    /// how far each of `elf_sections` is from where it really is
+    pub kernel_slide: u64
   , /// The base of the memory range for the kernel heap
     pub heap_base: PAddr
   , /// The top of the memory range to use for the kernel heap
@@ -65,6 +68,12 @@ pub struct InitParams {
     /// N.B. that this is currently never `None`, as we only support multiboot.
     /// However, this may change at a later date.
     pub multiboot_end: Option<PAddr>
+  , /// The start address of the initial ramdisk, if the bootloader loaded
+    /// one.
+    pub initrd_start: Option<PAddr>
+  , /// The end address of the initial ramdisk, if the bootloader loaded
+    /// one.
+    pub initrd_end: Option<PAddr>
   , /// Map of memory areas
     pub mem_map: ArrayVec<[mem::Area; MAX_MEM_AREAS]>
     , /// Map of elf sections
@@ -82,12 +91,15 @@ impl Default for InitParams {
                      //       fns that make params.
                      // TODO: should this be an Option instead?
                    , kernel_top: PAddr::from(0x0)
+                   , kernel_slide: 0
                    , heap_base:  PAddr::from(0x0)
                    , heap_top: PAddr::from(0x0)
                    , stack_base: PAddr::from(0x0)
                    , stack_top: PAddr::from(0x0)
                    , multiboot_start: None
                    , multiboot_end: None
+                   , initrd_start: None
+                   , initrd_end: None
                    , mem_map: ArrayVec::<[mem::Area; MAX_MEM_AREAS]>::new()
                    , elf_sections: None
                    }
@@ -147,6 +159,18 @@ impl InitParams {
         PhysicalPage::containing(self.kernel_top).add_one()
     }
 
+    /// Returns the range of frames containing the initial ramdisk, if there
+    /// is one.
+    #[inline]
+    pub fn initrd_frames(&self) -> Option<FrameRange> {
+        match (self.initrd_start, self.initrd_end) {
+            (Some(start), Some(end)) if end > start =>
+                Some(PhysicalPage::containing(start) ..
+                     PhysicalPage::containing(end - 1).add_one())
+          , _ => None
+        }
+    }
+
     /// Returns the range of frames containing the kernel heap
     ///
     /// The heap _should_ start on the first address in the frame range,
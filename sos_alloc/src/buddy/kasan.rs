@@ -0,0 +1,206 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A kernel address sanitizer for the system heap.
+//!
+//! With the `kasan` feature, every byte of the heap is either accessible or
+//! poisoned, as recorded in a shadow map with one byte for each 8-byte
+//! granule of the heap. A shadow byte of 0 means the whole granule is
+//! accessible, 1 to 7 means only that many bytes at the start of it are,
+//! and anything else is poisoned, with a value that says why.
+//!
+//! Each allocation is padded with a red zone on either side, which stays
+//! poisoned, and its block starts with a header recording where it was
+//! allocated from:
+//!
+//! ```text
+//! | call site | red zone |   allocation   | red zone |
+//! |  8 bytes  | 8 bytes  | size, rounded  | 8 bytes  |
+//! ```
+//!
+//! Freed allocations are poisoned again, so [`check_access`] catches
+//! overflows into a red zone and uses after free. Nothing inserts checks
+//! automatically: code calls [`check_access`] on memory it's about to
+//! touch.
+//!
+//! The shadow map is allocated from the heap itself when it's set up.
+//!
+//! [`check_access`]: fn.check_access.html
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use ::{Allocator, Layout};
+use super::Heap;
+
+/// The number of heap bytes described by one shadow byte.
+pub const GRANULE: usize = 8;
+/// The size of the red zones on either side of an allocation.
+const REDZONE: usize = 8;
+/// The size of the header recording an allocation's call site.
+const HEADER: usize = 8;
+
+/// Shadow value for a red zone.
+pub const POISON_REDZONE: u8 = 0xfa;
+/// Shadow value for memory that has been freed.
+pub const POISON_FREED: u8 = 0xfd;
+/// Shadow value for memory that has never been allocated.
+pub const POISON_UNALLOCATED: u8 = 0xfc;
+
+/// The address of the shadow map, or 0 if there isn't one.
+static SHADOW: AtomicUsize = AtomicUsize::new(0);
+/// The first address of the heap.
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+/// One past the last address of the heap.
+static HEAP_END: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+fn round_up(n: usize, to: usize) -> usize { (n + to - 1) & !(to - 1) }
+
+/// Set up the shadow map for `heap`, which runs from `start` for `size`
+/// bytes, and poison all of it.
+///
+/// # Safety
+/// + This must be called once, before anything has been allocated.
+pub unsafe fn init(heap: &mut Heap, start: *mut u8, size: usize) {
+    let shadow = heap.alloc(Layout::from_size_align(size / GRANULE, GRANULE))
+                     .expect("no room in the heap for the KASAN shadow!");
+    SHADOW.store(shadow as usize, Ordering::Relaxed);
+    HEAP_START.store(start as usize, Ordering::Relaxed);
+    HEAP_END.store(start as usize + size, Ordering::Release);
+    poison(start as usize, size, POISON_UNALLOCATED);
+}
+
+/// Returns a pointer to the shadow byte for `addr`, if it's in the heap.
+#[inline]
+fn shadow_of(addr: usize) -> Option<*mut u8> {
+    let shadow = SHADOW.load(Ordering::Acquire);
+    let start = HEAP_START.load(Ordering::Relaxed);
+    let end = HEAP_END.load(Ordering::Relaxed);
+    if shadow == 0 || addr < start || addr >= end {
+        None
+    } else {
+        Some((shadow + (addr - start) / GRANULE) as *mut u8)
+    }
+}
+
+/// Set the shadow of the `len` bytes at `addr` to `value`. Both must be
+/// multiples of `GRANULE`.
+unsafe fn poison(addr: usize, len: usize, value: u8) {
+    for granule in 0 .. len / GRANULE {
+        if let Some(shadow) = shadow_of(addr + granule * GRANULE) {
+            *shadow = value
+        }
+    }
+}
+
+/// Mark the `len` bytes at `addr`, a multiple of `GRANULE`, accessible.
+unsafe fn unpoison(addr: usize, len: usize) {
+    for granule in 0 .. round_up(len, GRANULE) / GRANULE {
+        let offset = granule * GRANULE;
+        if let Some(shadow) = shadow_of(addr + offset) {
+            // a whole granule is 0, not 8.
+            *shadow = (cmp::min(len - offset, GRANULE) % GRANULE) as u8;
+        }
+    }
+}
+
+/// Returns the layout of the block needed to hold an allocation described
+/// by `layout`, and the offset of the allocation in the block.
+#[inline]
+pub fn padded(layout: &Layout) -> (Layout, usize) {
+    let offset = cmp::max(HEADER + REDZONE, layout.align());
+    let size = offset + round_up(layout.size(), GRANULE) + REDZONE;
+    (Layout::from_size_align(size, layout.align()), offset)
+}
+
+/// Set up a newly allocated `block`, for an allocation described by
+/// `layout` that was made from `call_site`, and return the allocation.
+///
+/// # Safety
+/// + `block` must have been allocated with the layout `padded(layout)`.
+pub unsafe fn on_alloc(block: *mut u8, layout: &Layout, call_site: usize)
+                      -> *mut u8 {
+    let (padded, offset) = padded(layout);
+    let addr = block as usize + offset;
+    *((addr - HEADER - REDZONE) as *mut usize) = call_site;
+    poison(block as usize, padded.size(), POISON_REDZONE);
+    unpoison(addr, layout.size());
+    addr as *mut u8
+}
+
+/// Poison the allocation at `ptr`, described by `layout`, and return the
+/// block it was in.
+///
+/// # Safety
+/// + `ptr` must have come from `on_alloc` with the same layout.
+pub unsafe fn on_dealloc(ptr: *mut u8, layout: &Layout) -> *mut u8 {
+    let (padded, offset) = padded(layout);
+    let block = ptr as usize - offset;
+    poison(block, padded.size(), POISON_FREED);
+    block as *mut u8
+}
+
+/// Returns the address that the function this is inlined into will return
+/// to, which is where the allocation is being made from.
+///
+/// This relies on the kernel being built with frame pointers.
+#[inline(always)]
+pub fn call_site() -> usize {
+    let rbp: usize;
+    unsafe { asm!("mov $0, rbp" : "=r"(rbp) ::: "intel") }
+    if rbp == 0 { 0 } else { unsafe { *((rbp + 8) as *const usize) } }
+}
+
+/// Returns the first poisoned byte in the `size` bytes at `addr`, if any.
+///
+/// Memory outside the heap is never poisoned.
+pub fn find_poison(addr: usize, size: usize) -> Option<usize> {
+    let end = addr.saturating_add(size);
+    let mut granule = addr & !(GRANULE - 1);
+    while granule < end {
+        if let Some(shadow) = shadow_of(granule) {
+            let valid = match unsafe { *shadow } {
+                0 => GRANULE
+              , n if (n as usize) < GRANULE => n as usize
+              , _ => 0
+            };
+            // the last byte touched in this granule.
+            let last = cmp::min(end, granule + GRANULE) - 1;
+            if last - granule >= valid {
+                return Some(cmp::max(addr, granule + valid))
+            }
+        }
+        granule += GRANULE;
+    }
+    None
+}
+
+/// Check that the `size` bytes at `addr` may be read (or written, if
+/// `is_write`), reporting a bug if they may not.
+#[inline]
+pub fn check_access(addr: usize, size: usize, is_write: bool) {
+    if let Some(bad) = find_poison(addr, size) {
+        report(addr, size, is_write, bad)
+    }
+}
+
+/// Report an access to poisoned memory, and stop.
+#[cold]
+pub fn report(addr: usize, size: usize, is_write: bool, bad: usize) -> ! {
+    let shadow = shadow_of(bad).map_or(0, |shadow| unsafe { *shadow });
+    let why = match shadow {
+        POISON_REDZONE => "heap out of bounds"
+      , POISON_FREED => "use after free"
+      , POISON_UNALLOCATED => "wild access"
+      , _ => "out of bounds"
+    };
+    error!( "KASAN: {} {} of {} bytes at {:#x}: {:#x} has shadow {:#x}"
+          , why, if is_write { "write" } else { "read" }, size, addr
+          , bad, shadow);
+    panic!("KASAN: {} at {:#x}", why, bad)
+}
@@ -14,6 +14,8 @@ mod math;
 pub mod system;
 #[cfg(feature = "buddy_as_system")]
 pub use self::system::BuddyFrameAllocator;
+#[cfg(feature = "kasan")]
+pub mod kasan;
 
 use super::{Allocator, Layout, Address, AllocErr};
 use self::math::PowersOf2;
@@ -24,13 +26,14 @@ use core::ptr::Unique;
 
 use intrusive::list::{List, Node};
 use intrusive::rawlink::RawLink;
+use intrusive::NonNullOwned;
 use memory::PAGE_SIZE;
 
 #[cfg(test)]
 mod test;
 
 /// A `FreeList` is a list of unique free blocks
-pub type FreeList = List<Unique<FreeBlock>, FreeBlock>;
+pub type FreeList = List<NonNullOwned<FreeBlock>, FreeBlock>;
 
 /// A free block header stores a pointer to the next and previous free blocks.
 pub struct FreeBlock { next: RawLink<FreeBlock>
@@ -166,6 +169,118 @@ impl<'a> Heap<'a> {
         self.push_block(block, order);
     }
 
+    /// Add the memory from `start` to `start + size` to the heap, as the
+    /// largest blocks that fit in it.
+    ///
+    /// The region needn't be part of the heap. Each block is aligned to its
+    /// size relative to the heap's start address, so a block from the
+    /// region is only ever merged with its neighbours from the same region.
+    /// Whatever is left over that's too small for a block isn't added.
+    ///
+    /// # Returns
+    /// + The number of bytes added to the heap
+    ///
+    /// # Safety
+    /// + This function has no way to guarantee that the region is not
+    ///   already in use, or that it doesn't overlap the heap.
+    pub unsafe fn add_region(&mut self, start: Address, size: usize)
+                            -> usize {
+        let base = self.start_addr.as_ptr() as usize;
+        let end = start as usize + size;
+        let mut addr = start as usize;
+        // round the start up to the first minimum-size block boundary.
+        let misalign = addr.wrapping_sub(base) & (self.min_block_size - 1);
+        if misalign != 0 {
+            addr += self.min_block_size - misalign;
+        }
+        let mut added = 0;
+        while addr < end && end - addr >= self.min_block_size {
+            let pos = addr.wrapping_sub(base);
+            let mut order = self.free_lists.len() - 1;
+            while order > 0 {
+                let block_size = self.order_alloc_size(order);
+                if pos & (block_size - 1) == 0 && block_size <= end - addr {
+                    break
+                }
+                order -= 1;
+            }
+            self.push_block(addr as Address, order);
+            addr += self.order_alloc_size(order);
+            added += self.order_alloc_size(order);
+        }
+        added
+    }
+
+    /// Remove the free blocks of whole pages that end at `end`, going down
+    /// as far as `floor`, from the heap.
+    ///
+    /// This is the converse of `add_region`: once the blocks at the top of
+    /// a region are free, the memory they were in can be given back.
+    ///
+    /// # Returns
+    /// + The new end of the region. Everything from there to `end` was
+    ///   free, and isn't part of the heap any more.
+    pub fn remove_top(&mut self, floor: Address, end: Address) -> Address {
+        let base = self.start_addr.as_ptr() as usize;
+        let floor = floor as usize;
+        let mut end = end as usize;
+        'blocks: while end > floor {
+            for order in (0..self.free_lists.len()).rev() {
+                let block_size = self.order_alloc_size(order);
+                if block_size < PAGE_SIZE as usize { break }
+                if end - floor < block_size { continue }
+                let block = end - block_size;
+                if block.wrapping_sub(base) & (block_size - 1) == 0
+                    && self.remove_block(order, block as Address) {
+                    end = block;
+                    continue 'blocks
+                }
+            }
+            break
+        }
+        end as Address
+    }
+
+    /// Merge free blocks whose buddies are also free, returning how many
+    /// pairs were merged.
+    ///
+    /// `dealloc` only merges a block with its buddy if it can find it,
+    /// which it sometimes can't once the block has been merged upward
+    /// already, so blocks freed out of order can be left as free buddies.
+    /// Each free list is walked, smallest blocks first, so that merged
+    /// blocks can be merged again. Blocks that are next to each other but
+    /// aren't buddies can't be merged, since the result wouldn't be
+    /// aligned to its size.
+    pub fn compact(&mut self) -> usize {
+        let mut merged = 0;
+        for order in 0 .. self.free_lists.len() - 1 {
+            // blocks without a free buddy go to the back of the list, so
+            // the ones at the front are the ones left to look at. a buddy
+            // is always one of those: if it had been looked at already,
+            // this block would have been merged with it then.
+            let mut remaining = self.free_lists[order].len();
+            while remaining > 0 {
+                let block = match unsafe { self.pop_block(order) } {
+                    Some(block) => block
+                  , None => break
+                };
+                remaining -= 1;
+                match unsafe { self.get_buddy(order, block) } {
+                    Some(buddy) if self.remove_block(order, buddy) => {
+                        remaining = remaining.saturating_sub(1);
+                        merged += 1;
+                        unsafe { self.push_block(min(block, buddy), order + 1) }
+                    }
+                  , _ => unsafe {
+                        self.free_lists[order].push_back(
+                            NonNullOwned::from_raw(block as *mut FreeBlock))
+                    }
+                }
+            }
+        }
+        merged
+    }
+
     /// Computes the size of an allocation request.
     ///
     /// # Arguments
@@ -236,14 +351,14 @@ impl<'a> Heap<'a> {
     #[inline]
     unsafe fn push_block(&mut self, ptr: *mut u8, order: usize) {
         self.free_lists[order]
-            .push_front(Unique::new(ptr as *mut FreeBlock))
+            .push_front(NonNullOwned::from_raw(ptr as *mut FreeBlock))
     }
 
     #[inline]
     unsafe fn pop_block(&mut self, order: usize) -> Option<*mut u8>{
         self.free_lists[order]
             .pop_front()
-            .map(|block| block.as_ref().as_ptr())
+            .map(|block| block.get().as_ptr())
     }
 
 
@@ -317,13 +432,15 @@ impl<'a> Heap<'a> {
                 "Start address of a (supposedly) valid heap was null. Something\
                  has gone horribly, horribly wrong!");
 
-            // Determine the block's position in the heap.
-            let block_pos = (block as usize) - (start_addr as usize);
+            // Determine the block's position in the heap. Blocks added with
+            // `add_region` may be below the start of the heap, so this
+            // wraps rather than overflowing.
+            let block_pos = (block as usize).wrapping_sub(start_addr as usize);
 
             // Calculate the block's buddy by XORing the block's position
             // in the heap with its size.
-            let block_offset = (block_pos ^ block_size) as isize;
-            Some(start_addr.offset(block_offset))
+            let block_offset = block_pos ^ block_size;
+            Some((start_addr as usize).wrapping_add(block_offset) as Address)
         } else {
             // If the block is the size of the entire heap, it (obviously)
             // cannot have a buddy block.
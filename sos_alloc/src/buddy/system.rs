@@ -1,15 +1,430 @@
 //! This module integrates the buddy heap allocator into the Rust runtime.
+//!
+//! The heap also keeps count of how much is allocated, and in what sizes, so
+//! that the kernel can report it. Sizes are counted as requested, not as
+//! rounded up to a block.
+//!
+//! When the heap runs out of memory, it grows into the region given to
+//! `set_heap_pager`, if there is one, like `sbrk`. Failing that, the
+//! handler registered with `set_oom_handler` gets a chance to free some
+//! before the allocation fails for good.
+//!
+//! Under memory pressure, `compact` merges free blocks that weren't merged
+//! when they were freed, and gives the free top of the heap back.
 use spin::Mutex;
-use core::ptr;
+use core::{cmp, mem, ptr};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use memory::{PAGE_SIZE, VAddr};
 
-use ::{Allocator, Layout};
+use ::{Address, AllocErr, Allocator, Layout};
 use super::{Heap, FreeList};
+#[cfg(feature = "kasan")]
+use super::kasan::{self, call_site};
 
 /// The number of free lists for the kernel heap
 pub const NUM_FREE_LISTS: usize = 19;
 
-static ALLOC: Mutex<Option<Heap<'static>>>
-    = Mutex::new(None);
+/// The number of buckets in the allocation size histogram. Bucket `n`
+/// counts allocations of more than `2^(n-1)` and at most `2^n` bytes, and
+/// the last one counts everything bigger, too.
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
+/// The kernel heap, and its statistics.
+pub struct KernelHeap { heap: Mutex<Option<Heap<'static>>>
+                      , /// Bytes currently allocated
+                        allocated_bytes: AtomicUsize
+                      , /// Bytes not currently allocated
+                        free_bytes: AtomicUsize
+                      , /// Successful allocations, ever
+                        alloc_calls: AtomicU64
+                      , /// Deallocations, ever
+                        free_calls: AtomicU64
+                      , /// The most bytes ever allocated at once
+                        peak_allocated: AtomicUsize
+                      , /// Allocations by power-of-two size
+                        alloc_histogram: [AtomicU64; HISTOGRAM_BUCKETS]
+                      , growth: Mutex<Growth>
+                      }
+
+/// Maps and unmaps the memory the kernel heap grows into.
+#[derive(Copy, Clone)]
+pub struct HeapPager { /// Map `len` bytes of memory at `start`, returning
+                       /// false if it couldn't all be mapped. Both are
+                       /// page-aligned.
+                       pub map: fn(start: VAddr, len: usize) -> bool
+                     , /// Unmap `len` bytes at `start`, which `map` mapped,
+                       /// and free the frames
+                       pub unmap: fn(start: VAddr, len: usize)
+                     , /// One past the highest address the heap may grow to
+                       pub limit: VAddr
+                     }
+
+/// Where the kernel heap has grown to.
+struct Growth { pager: Option<HeapPager>
+              , /// The start of the region the heap grows into
+                floor: VAddr
+              , /// The high-water mark: everything from `floor` to here is
+                /// mapped and part of the heap
+                heap_end: VAddr
+              }
+
+/// A snapshot of the kernel heap's statistics.
+#[derive(Copy, Clone, Debug)]
+pub struct HeapStats { /// Bytes currently allocated
+                       pub allocated_bytes: usize
+                     , /// Bytes not currently allocated
+                       pub free_bytes: usize
+                     , /// Successful allocations since boot
+                       pub alloc_calls: u64
+                     , /// Deallocations since boot
+                       pub free_calls: u64
+                     , /// The most bytes ever allocated at once
+                       pub peak_allocated: usize
+                     , /// Allocations by power-of-two size, bucketed as
+                       /// described for `HISTOGRAM_BUCKETS`
+                       pub alloc_histogram: [u64; HISTOGRAM_BUCKETS]
+                     }
+
+/// What compacting the kernel heap did.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Compaction { /// Pairs of free buddy blocks merged
+                        pub merged: usize
+                      , /// Bytes unmapped from the top of the heap
+                        pub released: usize
+                      }
+
+/// Returns the histogram bucket for an allocation of `size` bytes.
+#[inline]
+fn bucket(size: usize) -> usize {
+    let log2 = size.checked_next_power_of_two()
+                   .map(|size| size.trailing_zeros() as usize)
+                   .unwrap_or(HISTOGRAM_BUCKETS);
+    cmp::min(log2, HISTOGRAM_BUCKETS - 1)
+}
+
+impl KernelHeap {
+    const fn new() -> Self {
+        KernelHeap { heap: Mutex::new(None)
+                   , allocated_bytes: AtomicUsize::new(0)
+                   , free_bytes: AtomicUsize::new(0)
+                   , alloc_calls: AtomicU64::new(0)
+                   , free_calls: AtomicU64::new(0)
+                   , peak_allocated: AtomicUsize::new(0)
+                   , alloc_histogram:
+                       [ AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       , AtomicU64::new(0), AtomicU64::new(0)
+                       ]
+                   , growth: Mutex::new(Growth {
+                         pager: None
+                       , floor: VAddr::from_usize(0)
+                       , heap_end: VAddr::from_usize(0)
+                       })
+                   }
+    }
+
+    /// Count an allocation of `size` bytes.
+    fn count_alloc(&self, size: usize) {
+        self.alloc_calls.fetch_add(1, Ordering::Relaxed);
+        self.alloc_histogram[bucket(size)].fetch_add(1, Ordering::Relaxed);
+        self.free_bytes.fetch_sub(size, Ordering::Relaxed);
+        let allocated
+            = self.allocated_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        let mut peak = self.peak_allocated.load(Ordering::Relaxed);
+        while allocated > peak {
+            match self.peak_allocated.compare_exchange_weak(
+                    peak, allocated, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break
+              , Err(current) => peak = current
+            }
+        }
+    }
+
+    /// Count a deallocation of `size` bytes.
+    fn count_free(&self, size: usize) {
+        self.free_calls.fetch_add(1, Ordering::Relaxed);
+        self.allocated_bytes.fetch_sub(size, Ordering::Relaxed);
+        self.free_bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Call `f` with the heap locked.
+    ///
+    /// # Panics
+    /// + If the heap hasn't been initialized.
+    fn with_heap<F, R>(&self, f: F) -> R
+    where F: FnOnce(&mut Heap<'static>) -> R {
+        let mut heap = self.heap.lock();
+        f(heap.as_mut()
+              .expect("Cannot allocate memory, no system allocator exists!"))
+    }
+
+    /// Grow the heap by enough to allocate a block of `bytes` bytes.
+    ///
+    /// The new memory starts at the heap's high-water mark, and is mapped
+    /// by the heap pager with the heap unlocked, so the pager may allocate.
+    /// The heap doesn't grow while it's already growing, though, so if the
+    /// pager runs out, it fails.
+    ///
+    /// # Returns
+    /// + True if the heap grew, false if there's no pager, the block is
+    ///   bigger than the heap's largest, or the memory couldn't be mapped
+    fn expand(&self, bytes: usize) -> bool {
+        let mut growth = match self.growth.try_lock() {
+            Some(growth) => growth
+          , None => return false
+        };
+        let pager = match growth.pager {
+            Some(pager) => pager
+          , None => return false
+        };
+        let (base, max_block) = match *self.heap.lock() {
+            Some(ref heap) =>
+                (heap.start_addr.as_ptr() as usize, heap.heap_size)
+          , None => return false
+        };
+        let block = match cmp::max(bytes, PAGE_SIZE as usize)
+                              .checked_next_power_of_two() {
+            Some(block) if block <= max_block => block
+          , _ => return false
+        };
+        // blocks are aligned to their size relative to the start of the
+        // heap, so there may be a gap before the new block. it's mapped and
+        // added to the heap along with the block, rather than wasted.
+        let end = growth.heap_end.as_usize();
+        let pos = end.wrapping_sub(base).wrapping_add(block - 1) & !(block - 1);
+        let new_end = match base.wrapping_add(pos).checked_add(block) {
+            Some(new_end) if new_end <= pager.limit.as_usize() => new_end
+          , _ => return false
+        };
+        let len = new_end - end;
+        if !(pager.map)(growth.heap_end, len) { return false }
+
+        let added = self.with_heap(|heap| unsafe {
+            heap.add_region(end as Address, len)
+        });
+        self.free_bytes.fetch_add(added, Ordering::Relaxed);
+        growth.heap_end = VAddr::from_usize(new_end);
+        trace!( target: "alloc", "expanded the heap by {} bytes, to {:#x}"
+              , len, new_end);
+        true
+    }
+
+    /// Grow the heap by enough to allocate `layout`.
+    fn expand_for(&self, layout: &Layout) -> bool {
+        match self.with_heap(|heap| heap.alloc_size(&heap_layout(layout))) {
+            Ok(bytes) => self.expand(bytes)
+          , Err(_) => false
+        }
+    }
+
+    /// Give the free memory at the top of the heap back to the heap pager,
+    /// down as far as where the heap started growing.
+    ///
+    /// # Returns
+    /// + The number of bytes unmapped
+    fn shrink(&self) -> usize { self.shrink_from(&mut self.growth.lock()) }
+
+    /// Shrink the heap, like `shrink`, with `growth` already locked.
+    fn shrink_from(&self, growth: &mut Growth) -> usize {
+        let pager = match growth.pager {
+            Some(pager) => pager
+          , None => return 0
+        };
+        let end = growth.heap_end.as_usize();
+        let new_end = self.with_heap(|heap|
+            heap.remove_top( growth.floor.as_usize() as Address
+                           , end as Address)) as usize;
+        let len = end - new_end;
+        if len > 0 {
+            self.free_bytes.fetch_sub(len, Ordering::Relaxed);
+            growth.heap_end = VAddr::from_usize(new_end);
+            (pager.unmap)(growth.heap_end, len);
+            trace!( target: "alloc", "shrank the heap by {} bytes, to {:#x}"
+                  , len, new_end);
+        }
+        len
+    }
+
+    /// Merge free buddy blocks, and then give the free top of the heap
+    /// back, like `shrink`.
+    ///
+    /// This may be called by the out-of-memory handler while the heap is
+    /// growing, so if it is, the top isn't given back.
+    fn compact(&self) -> Compaction {
+        let merged = self.with_heap(|heap| heap.compact());
+        let released = match self.growth.try_lock() {
+            Some(mut growth) => self.shrink_from(&mut growth)
+          , None => 0
+        };
+        if merged > 0 || released > 0 {
+            trace!( target: "alloc"
+                  , "compacted the heap: merged {} blocks, released {} bytes"
+                  , merged, released);
+        }
+        Compaction { merged: merged, released: released }
+    }
+
+    /// Call `f` with the heap locked again, once the out-of-memory handler
+    /// has run.
+    ///
+    /// The handler may have given frames back, rather than heap memory (by
+    /// compacting the heap, say), so if `f` fails again, the heap is
+    /// expanded for `layout` and `f` is tried once more.
+    fn retry<F, R>(&self, layout: &Layout, f: F) -> Result<R, AllocErr>
+    where F: Fn(&mut Heap<'static>) -> Result<R, AllocErr> {
+        match self.with_heap(&f) {
+            Err(_) if self.expand_for(layout) => self.with_heap(f)
+          , result => result
+        }
+    }
+
+    /// Allocate memory for `layout`, recording `call_site` as its origin.
+    ///
+    /// If the heap is out of memory, it's expanded, or if it can't be, the
+    /// out-of-memory handler is called with the heap unlocked, and the
+    /// allocation is retried if it asks.
+    unsafe fn alloc(&self, layout: Layout, call_site: usize)
+                   -> Result<Address, AllocErr> {
+        let result = self.with_heap(|heap|
+            heap_alloc(heap, layout.clone(), call_site));
+        match result {
+            Err(_) if self.expand_for(&layout) =>
+                self.with_heap(|heap| heap_alloc(heap, layout, call_site))
+          , Err(_) if out_of_memory() =>
+                self.retry(&layout, |heap|
+                    heap_alloc(heap, layout.clone(), call_site))
+          , result => result
+        }
+    }
+
+    /// Move the memory at `ptr` to a block big enough for `new_layout`,
+    /// expanding the heap or retrying like `alloc` if it's out of memory.
+    ///
+    /// A failed reallocation leaves the old block where it was.
+    unsafe fn realloc( &self, ptr: Address
+                     , old_layout: Layout, new_layout: Layout
+                     , call_site: usize)
+                     -> Result<Address, AllocErr> {
+        let result = self.with_heap(|heap|
+            heap_realloc( heap, ptr, old_layout.clone(), new_layout.clone()
+                        , call_site));
+        match result {
+            Err(_) if self.expand_for(&new_layout) =>
+                self.with_heap(|heap|
+                    heap_realloc( heap, ptr, old_layout, new_layout
+                                , call_site))
+          , Err(_) if out_of_memory() =>
+                self.retry(&new_layout, |heap|
+                    heap_realloc( heap, ptr, old_layout.clone()
+                                , new_layout.clone(), call_site))
+          , result => result
+        }
+    }
+
+    /// Returns a snapshot of the heap's statistics.
+    pub fn stats(&self) -> HeapStats {
+        let mut histogram = [0; HISTOGRAM_BUCKETS];
+        for (count, bucket) in histogram.iter_mut()
+                                        .zip(self.alloc_histogram.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        HeapStats { allocated_bytes: self.allocated_bytes
+                                         .load(Ordering::Relaxed)
+                  , free_bytes: self.free_bytes.load(Ordering::Relaxed)
+                  , alloc_calls: self.alloc_calls.load(Ordering::Relaxed)
+                  , free_calls: self.free_calls.load(Ordering::Relaxed)
+                  , peak_allocated: self.peak_allocated
+                                        .load(Ordering::Relaxed)
+                  , alloc_histogram: histogram
+                  }
+    }
+}
+
+static ALLOC: KernelHeap = KernelHeap::new();
+
+/// The out-of-memory handler, as a `fn() -> bool`, or 0 if there isn't one.
+static OOM_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the function called when the heap can't satisfy an allocation.
+///
+/// The handler returns true if it may have made memory available, in which
+/// case the allocation is tried once more. It runs without the heap locked,
+/// so it may allocate, but then it must expect to be called again from
+/// within itself.
+pub fn set_oom_handler(handler: fn() -> bool) {
+    OOM_HANDLER.store(handler as usize, Ordering::SeqCst);
+}
+
+/// Call the out-of-memory handler, returning true if the failed allocation
+/// should be tried again.
+fn out_of_memory() -> bool {
+    match OOM_HANDLER.load(Ordering::SeqCst) {
+        0 => false
+      , handler => unsafe { mem::transmute::<usize, fn() -> bool>(handler)() }
+    }
+}
+
+/// Returns a snapshot of the kernel heap's statistics.
+#[inline]
+pub fn stats() -> HeapStats { ALLOC.stats() }
+
+/// Let the kernel heap grow into the memory from `start` up to
+/// `pager.limit`, which `pager` maps as it's needed.
+///
+/// # Panics
+/// + If `start` isn't page-aligned, or is above `pager.limit`
+pub fn set_heap_pager(pager: HeapPager, start: VAddr) {
+    assert!( start.as_usize() & (PAGE_SIZE as usize - 1) == 0
+           , "the heap must grow from a page boundary!");
+    assert!(start <= pager.limit, "the heap can't grow past its limit!");
+    let mut growth = ALLOC.growth.lock();
+    growth.pager = Some(pager);
+    growth.floor = start;
+    growth.heap_end = start;
+}
+
+/// Unmap the free memory at the top of the kernel heap, if it's grown,
+/// returning the number of bytes given back.
+///
+/// Only whole blocks of at least a page at the high-water mark can go, so
+/// one allocation near the top keeps everything below it mapped.
+#[inline]
+pub fn shrink() -> usize { ALLOC.shrink() }
+
+/// Merge the kernel heap's free buddy blocks that weren't merged when they
+/// were freed, and then unmap its free top, like `shrink`.
+///
+/// This runs with the heap unlocked, so it may be called from the
+/// out-of-memory handler.
+#[inline]
+pub fn compact() -> Compaction { ALLOC.compact() }
+
+/// Allocate `size` bytes aligned to `align`, like `__rust_allocate`, but
+/// return an error rather than panicking if the heap can't satisfy it even
+/// after growing and calling the out-of-memory handler.
+///
+/// The block is freed with `__rust_deallocate`, like any other, so it can
+/// back a `Vec`.
+pub fn try_allocate(size: usize, align: usize) -> Result<Address, AllocErr> {
+    let call_site = call_site();
+    unsafe {
+        ALLOC.alloc(Layout::from_size_align(size, align), call_site)
+             .map(|blck| { ALLOC.count_alloc(size); blck })
+    }
+}
 
 static mut KERNEL_FREE_LISTS: [FreeList; NUM_FREE_LISTS]
     // TODO: I really wish there was a less awful way to do this...
@@ -34,10 +449,102 @@ pub unsafe fn init_heap(start_addr: *mut u8, heap_size: usize ) {
     assert_has_not_been_called!("the kernel heap may not be initialized \
                                  more than once!");
     trace!(target: "alloc", "init_heap() was called.");
-    *(ALLOC.lock())
-        = Some(Heap::new( start_addr
-                                      , &mut KERNEL_FREE_LISTS
-                                      , heap_size));
+    let mut heap = Heap::new(start_addr, &mut KERNEL_FREE_LISTS, heap_size);
+    #[cfg(feature = "kasan")]
+    kasan::init(&mut heap, start_addr, heap_size);
+    *(ALLOC.heap.lock()) = Some(heap);
+    ALLOC.free_bytes.store(heap_size, Ordering::Relaxed);
+}
+
+/// Without KASAN, there's no call site to record.
+#[cfg(not(feature = "kasan"))]
+#[inline(always)]
+fn call_site() -> usize { 0 }
+
+/// Returns the layout of the block `heap_alloc` needs for `layout`.
+#[cfg(not(feature = "kasan"))]
+#[inline]
+fn heap_layout(layout: &Layout) -> Layout { layout.clone() }
+
+/// Returns the layout of the block `heap_alloc` needs for `layout`, with
+/// its red zones.
+#[cfg(feature = "kasan")]
+#[inline]
+fn heap_layout(layout: &Layout) -> Layout { kasan::padded(layout).0 }
+
+/// Allocate memory for `layout` from `heap`.
+#[cfg(not(feature = "kasan"))]
+#[inline]
+unsafe fn heap_alloc(heap: &mut Heap, layout: Layout, _call_site: usize)
+                    -> Result<Address, AllocErr> {
+    heap.alloc(layout)
+}
+
+/// Allocate memory for `layout` from `heap`, between red zones, recording
+/// that it was allocated from `call_site`.
+#[cfg(feature = "kasan")]
+unsafe fn heap_alloc(heap: &mut Heap, layout: Layout, call_site: usize)
+                    -> Result<Address, AllocErr> {
+    let (padded, _) = kasan::padded(&layout);
+    heap.alloc(padded)
+        .map(|block| kasan::on_alloc(block, &layout, call_site))
+}
+
+/// Free memory allocated with `heap_alloc`.
+#[cfg(not(feature = "kasan"))]
+#[inline]
+unsafe fn heap_dealloc(heap: &mut Heap, ptr: Address, layout: Layout) {
+    heap.dealloc(ptr, layout)
+}
+
+/// Poison and free memory allocated with `heap_alloc`.
+#[cfg(feature = "kasan")]
+unsafe fn heap_dealloc(heap: &mut Heap, ptr: Address, layout: Layout) {
+    let (padded, _) = kasan::padded(&layout);
+    heap.dealloc(kasan::on_dealloc(ptr, &layout), padded)
+}
+
+/// Move memory allocated with `heap_alloc` to a block big enough for
+/// `new_layout`.
+#[cfg(not(feature = "kasan"))]
+#[inline]
+unsafe fn heap_realloc( heap: &mut Heap, ptr: Address
+                      , old_layout: Layout, new_layout: Layout
+                      , _call_site: usize)
+                      -> Result<Address, AllocErr> {
+    heap.realloc(ptr, old_layout, new_layout)
+}
+
+/// Move memory allocated with `heap_alloc` to a block big enough for
+/// `new_layout`.
+///
+/// The old memory is always freed, so that it's poisoned, even if the new
+/// allocation would have fit in its block.
+#[cfg(feature = "kasan")]
+unsafe fn heap_realloc( heap: &mut Heap, ptr: Address
+                      , old_layout: Layout, new_layout: Layout
+                      , call_site: usize)
+                      -> Result<Address, AllocErr> {
+    let new_ptr = heap_alloc(heap, new_layout.clone(), call_site)?;
+    let len = cmp::min(old_layout.size(), new_layout.size());
+    ptr::copy_nonoverlapping(ptr, new_ptr, len);
+    heap_dealloc(heap, ptr, old_layout);
+    Ok(new_ptr)
+}
+
+/// Add the memory from `start` to `start + size` to the kernel heap.
+///
+/// # Returns
+/// + The number of bytes added, which is zero if there's no kernel heap yet
+///
+/// # Safety
+/// + The region must not be in use, and must not overlap the heap.
+pub unsafe fn add_region(start: *mut u8, size: usize) -> usize {
+    let added = ALLOC.heap.lock().as_mut()
+                     .map(|heap| heap.add_region(start, size))
+                     .unwrap_or(0);
+    ALLOC.free_bytes.fetch_add(added, Ordering::Relaxed);
+    added
 }
 
 // -- integrate the heap allocator into the Rust runtime ------------------
@@ -45,15 +552,15 @@ pub unsafe fn init_heap(start_addr: *mut u8, heap_size: usize ) {
 #[no_mangle]
 pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
     trace!("__rust_allocate() was called.");
+    let call_site = call_site();
     unsafe {
-        ALLOC.lock().as_mut()
-             .expect("Cannot allocate memory, no system allocator exists!")
-             .alloc(Layout::from_size_align(size, align))
+        ALLOC.alloc(Layout::from_size_align(size, align), call_site)
              .map(|blck| {
                  // TODO: can we use `inspect()` here instead?
                  //       - eliza, 1/23/2017
                  trace!( target: "alloc"
                        , "__rust_allocate: allocated {:?}", blck);
+                 ALLOC.count_alloc(size);
                  blck })
             // TODO: how to handle various error conditions here in
             //       ways the stdlib expects?
@@ -66,10 +573,12 @@ pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
 pub extern "C" fn __rust_deallocate( ptr: *mut u8, old_size: usize
                                    , align: usize ) {
     unsafe {
-        ALLOC.lock().as_mut()
-             .expect("Cannot deallocate memory, no system allocator exists!")
-             .dealloc(ptr, Layout::from_size_align(old_size, align))
+        let mut heap = ALLOC.heap.lock();
+        let heap = heap.as_mut()
+             .expect("Cannot deallocate memory, no system allocator exists!");
+        heap_dealloc(heap, ptr, Layout::from_size_align(old_size, align))
     }
+    ALLOC.count_free(old_size);
 }
 
 #[allow(missing_docs)]
@@ -77,12 +586,18 @@ pub extern "C" fn __rust_deallocate( ptr: *mut u8, old_size: usize
 pub extern "C" fn __rust_reallocate( ptr: *mut u8, old_size: usize
                                    , size: usize, align: usize )
                                    -> *mut u8 {
+    // a reallocation counts as freeing the old block and allocating the new
+    // one.
+    let call_site = call_site();
     unsafe {
-        ALLOC.lock().as_mut()
-             .expect("Cannot reallocate memory, no system allocator exists!")
-             .realloc( ptr
+        ALLOC.realloc( ptr
                      , Layout::from_size_align(old_size, align)
-                     , Layout::from_size_align(size, align))
+                     , Layout::from_size_align(size, align)
+                     , call_site )
+             .map(|blck| {
+                 ALLOC.count_free(old_size);
+                 ALLOC.count_alloc(size);
+                 blck })
              // TODO: how to handle various error conditions here in
              //       ways the stdlib expects?
              //          - eliza, 02/02/2017
@@ -3,6 +3,7 @@ use super::*;
 use ::{Allocator, Layout};
 
 use core::ptr;
+use memory::PAGE_SIZE;
 
 extern "C" {
     /// We need this to allocate aligned memory for our heap.
@@ -179,3 +180,105 @@ fn test_alloc_and_dealloc() {
         free(mem);
     }
 }
+
+#[test]
+fn test_add_region() {
+    unsafe {
+        let mem = memalign(HEAP_ALIGN, HEAP_SIZE);
+        let extra = memalign(HEAP_ALIGN, HEAP_SIZE);
+        let mut free_lists: [FreeList; 5]
+            = [ FreeList::new(), FreeList::new()
+              , FreeList::new(), FreeList::new()
+              , FreeList::new()
+              ];
+        let mut heap = Heap::new( mem, &mut free_lists, HEAP_SIZE );
+
+        // Misaligned regions are trimmed to whole, aligned blocks: 16 bytes
+        // at 16, then 32 at 32 and 64, with 12 bytes left over. The last
+        // block added is the first handed out.
+        assert_eq!(80, heap.add_region(extra.offset(8), 100));
+
+        let whole_heap = heap.alloc(Layout::from_size_align(256, 256));
+        assert_eq!(Ok(mem), whole_heap);
+
+        let block_32 = heap.alloc(Layout::from_size_align(32, 32));
+        assert_eq!(Ok(extra.offset(64)), block_32);
+        let block_16 = heap.alloc(Layout::from_size_align(16, 16));
+        assert_eq!(Ok(extra.offset(16)), block_16);
+
+        heap.dealloc(block_32.unwrap(), Layout::from_size_align(32, 32));
+        heap.dealloc(whole_heap.unwrap(), Layout::from_size_align(256, 256));
+
+        free(extra);
+        free(mem);
+    }
+}
+
+#[test]
+fn test_remove_top() {
+    const PAGE: usize = PAGE_SIZE as usize;
+    unsafe {
+        let mem = memalign(2 * PAGE, 2 * PAGE);
+        let extra = memalign(2 * PAGE, 4 * PAGE);
+        let page = |n: isize| extra.offset(n * PAGE as isize);
+        let mut free_lists: [FreeList; 2] = [FreeList::new(), FreeList::new()];
+        let mut heap = Heap::new( mem, &mut free_lists, 2 * PAGE );
+        assert_eq!(4 * PAGE, heap.add_region(extra, 4 * PAGE));
+
+        // The top block of the region is split, and only its upper half is
+        // free, so that's all that can be removed.
+        let block = heap.alloc(Layout::from_size_align(PAGE, PAGE));
+        assert_eq!(Ok(page(2)), block);
+        assert_eq!(page(3), heap.remove_top(extra, page(4)));
+
+        // Once it's freed, the rest of the region can go too.
+        heap.dealloc(block.unwrap(), Layout::from_size_align(PAGE, PAGE));
+        assert_eq!(extra, heap.remove_top(extra, page(3)));
+
+        // The heap's own memory is still there, and nothing else is.
+        let whole_heap = heap.alloc(Layout::from_size_align(2 * PAGE, PAGE));
+        assert_eq!(Ok(mem), whole_heap);
+        assert!(heap.alloc(Layout::from_size_align(PAGE, PAGE)).is_err());
+
+        heap.dealloc( whole_heap.unwrap()
+                    , Layout::from_size_align(2 * PAGE, PAGE));
+        free(extra);
+        free(mem);
+    }
+}
+
+#[test]
+fn test_compact() {
+    unsafe {
+        let mem = memalign(HEAP_ALIGN, HEAP_SIZE);
+        let mut free_lists: [FreeList; 5]
+            = [ FreeList::new(), FreeList::new()
+              , FreeList::new(), FreeList::new()
+              , FreeList::new()
+              ];
+        let mut heap = Heap::new( mem, &mut free_lists, HEAP_SIZE );
+        let layout = || Layout::from_size_align(16, 16);
+
+        let blocks = [ heap.alloc(layout()), heap.alloc(layout())
+                     , heap.alloc(layout()), heap.alloc(layout()) ];
+        assert_eq!( [ Ok(mem), Ok(mem.offset(16))
+                    , Ok(mem.offset(32)), Ok(mem.offset(48)) ]
+                  , blocks);
+
+        // Freeing the 16 at 16 merges it with the one at 0, but then looks
+        // for the 32-byte buddy at 48 rather than 32, so the two 32-byte
+        // blocks are left unmerged.
+        for &i in [2, 3, 0, 1].iter() {
+            heap.dealloc(blocks[i].unwrap(), layout());
+        }
+        assert!(heap.alloc(Layout::from_size_align(256, 256)).is_err());
+
+        // Merging them lets them merge with the 64 and then the 128.
+        assert_eq!(3, heap.compact());
+        assert_eq!(0, heap.compact());
+        let whole_heap = heap.alloc(Layout::from_size_align(256, 256));
+        assert_eq!(Ok(mem), whole_heap);
+
+        free(mem);
+    }
+}
@@ -31,6 +31,7 @@ pub struct MemMapAllocator<'a> { next_free: Frame
                                , areas: mem::Map<'a>
                                , kernel_frames: FrameRange
                                , mb_frames: FrameRange
+                               , initrd_frames: Option<FrameRange>
                                }
 impl<'a> MemMapAllocator<'a> {
     fn next_area(&mut self) {
@@ -42,7 +43,7 @@ impl<'a> MemMapAllocator<'a> {
                   .min_by_key(|a| a.start_addr)
                   .map(|area| {
                       let start = Frame::containing(area.start_addr);
-                      if self.next_free > start { self.next_free = start };
+                      if self.next_free < start { self.next_free = start };
                       area
                   })
     }
@@ -52,6 +53,8 @@ impl<'a> MemMapAllocator<'a> {
 impl<'a> From<&'a InitParams> for MemMapAllocator<'a> {
     fn from(params: &'a InitParams) -> Self {
         let mut new_allocator = MemMapAllocator {
+              // frames below this are never handed out, so that low memory
+              // can be used for things like the SMP trampoline.
               next_free: Frame::containing(PAddr::new(0x12000))
             , current_area: None
             , areas: params.mem_map()
@@ -59,10 +62,12 @@ impl<'a> From<&'a InitParams> for MemMapAllocator<'a> {
             // TODO: handle non-multiboot case
             , mb_frames: Frame::containing(params.multiboot_start()) ..
                          Frame::containing(params.multiboot_end()).add_one()
+            , initrd_frames: params.initrd_frames()
             };
         trace!("creating mem map allocator");
         trace!("kernel frames: {:?}", new_allocator.kernel_frames);
         trace!("multiboot frames: {:?}", new_allocator.mb_frames);
+        trace!("initrd frames: {:?}", new_allocator.initrd_frames);
         new_allocator.next_area();
         new_allocator
     }
@@ -98,6 +103,13 @@ impl<'a> Allocator for MemMapAllocator<'a> {
                     self.next_free = self.mb_frames.end.add_one();
                     // println!("...and returning None");
                 }
+              , // this frame is part of the initial ramdisk.
+                f if self.initrd_frames.as_ref()
+                         .map_or(false, |r| f >= r.start && f < r.end) => {
+                    // skip ahead to the end of the ramdisk.
+                    self.next_free = self.initrd_frames.as_ref().unwrap()
+                                         .end;
+                }
               , // this frame is free.
                 frame => {
                     // advance the next free frame and return this frame.
@@ -122,9 +134,21 @@ impl<'a> Allocator for MemMapAllocator<'a> {
         // just leak it
     }
 
-    /// Allocate a range of frames
-    unsafe fn allocate_range(&mut self, _num: usize) -> AllocResult<FrameRange> {
-        unimplemented!()
+    /// Allocate a range of physically contiguous frames
+    ///
+    /// Frames are handed out in increasing order, so we just keep allocating
+    /// until we have `num` in a row. Any frames skipped because they weren't
+    /// contiguous with the rest (such as at the end of a memory area) are
+    /// leaked, like everything else.
+    unsafe fn allocate_range(&mut self, num: usize) -> AllocResult<FrameRange> {
+        let mut start = self.allocate()?;
+        let mut end = start.add_one();
+        while end.number() - start.number() < num {
+            let frame = self.allocate()?;
+            if frame != end { start = frame; }
+            end = frame.add_one();
+        }
+        Ok(start .. end)
     }
     /// Deallocate a range of frames
     unsafe fn deallocate_range(&mut self, _range: FrameRange) {
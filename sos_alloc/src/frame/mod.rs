@@ -9,7 +9,7 @@
 //! Frame allocation
 #![warn(missing_docs)]
 use memory::{FrameRange, PhysicalPage as Frame};
-use super::AllocResult;
+use super::{AllocErr, AllocResult};
 use core::ops;
 use spin::Mutex;
 
@@ -28,6 +28,15 @@ pub trait Allocator: Sized  {
     /// Deallocate a range of frames
     unsafe fn deallocate_range(&mut self, range: FrameRange);
 
+    /// Allocate `2^order` contiguous frames, starting at a multiple of
+    /// `2^align_order` frames.
+    ///
+    /// By default, this isn't supported.
+    unsafe fn allocate_aligned(&mut self, _order: usize, _align_order: usize)
+                              -> AllocResult<FrameRange> {
+        Err(AllocErr::invalid_input("aligned allocation is not supported"))
+    }
+
 }
 
 /// An allocator capable of lending [borrowed frame]s
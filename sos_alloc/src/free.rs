@@ -1,11 +1,11 @@
-use core::ptr::Unique;
 use core::mem;
 
 use intrusive::list::{List as IList, Node};
 use intrusive::rawlink::RawLink;
+use intrusive::NonNullOwned;
 
 /// A `FreeList` is a list of unique free blocks
-pub type List = IList<Unique<Block>, Block>;
+pub type List = IList<NonNullOwned<Block>, Block>;
 
 /// A free block header stores a pointer to the next and previous free blocks.
 ///
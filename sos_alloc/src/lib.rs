@@ -55,6 +55,8 @@
 #![feature(unique)]
 #![feature(core_intrinsics)]
 #![feature(step_trait)]
+#![cfg_attr(feature = "buddy_as_system", feature(integer_atomics))]
+#![cfg_attr(feature = "kasan", feature(asm))]
 
 #![cfg_attr(all(test, feature = "bench"), feature(test))]
 #![cfg_attr(test, feature(collections))]
@@ -0,0 +1,252 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! An intrusive AVL tree implementation using `RawLink`s.
+//!
+//! Like the intrusive list, the tree's elements hold the links to the other
+//! elements, so it never allocates. The tree is kept balanced by rotations
+//! after every change, so inserting, finding, and removing the smallest
+//! element all take O(log n) time, where a sorted list takes O(n).
+//!
+//! A tree node reuses its list node's `prev` link for its left child, so
+//! it only needs one more link, for the right child, and its height. An
+//! element can't be on a list and in a tree at the same time.
+use ::RawLink;
+use ::list::{Node, OwnedRef};
+
+use core::cmp::{self, Ordering};
+use core::marker::PhantomData;
+#[cfg(test)] mod test;
+
+/// This trait defines a node in an intrusive AVL tree.
+///
+/// On top of a list `Node`, a tree node must be able to provide references
+/// to its right child, and keep track of the height of its subtree.
+pub trait AvlNode: Node {
+    fn right(&self) -> &RawLink<Self>;
+    fn right_mut(&mut self) -> &mut RawLink<Self>;
+
+    /// Returns the height of the subtree rooted at this node.
+    fn height(&self) -> usize;
+    fn set_height(&mut self, height: usize);
+
+    /// The left child is kept in the list node's `prev` link.
+    #[inline] fn left(&self) -> &RawLink<Self> { self.prev() }
+    #[inline] fn left_mut(&mut self) -> &mut RawLink<Self> { self.prev_mut() }
+}
+
+/// The `AvlTree` struct is our way of interacting with an intrusive AVL tree.
+///
+/// It stores a pointer to the root of the tree, the number of elements in
+/// the tree, and a `PhantomData` marker for the tree's `OwnedRef` type.
+pub struct AvlTree<T, N>
+where T: OwnedRef<N>
+    , N: AvlNode {
+    root: RawLink<N>
+  , _ty_marker: PhantomData<T>
+  , length: usize
+}
+
+/// Returns a copy of `link`.
+///
+/// `RawLink<N>` is only `Copy` if `N` is.
+#[inline]
+fn copy<N>(link: &RawLink<N>) -> RawLink<N> {
+    unsafe { RawLink::from_raw(link.as_raw()) }
+}
+
+/// Returns the height of the subtree `link` points to.
+#[inline]
+unsafe fn height<N: AvlNode>(link: &RawLink<N>) -> usize {
+    link.resolve().map_or(0, |node| node.height())
+}
+
+/// Recompute `node`'s height from its children's.
+#[inline]
+unsafe fn update<N: AvlNode>(node: &mut N) {
+    let height = cmp::max(height(node.left()), height(node.right())) + 1;
+    node.set_height(height);
+}
+
+/// Rotate the subtree rooted at `node` right, returning its new root (the
+/// old root's left child).
+unsafe fn rotate_right<'a, N: AvlNode>(node: &'a mut N) -> &'a mut N {
+    let pivot = node.left_mut().take().resolve_mut()
+                    .expect("can't rotate right without a left child!");
+    *node.left_mut() = pivot.right_mut().take();
+    update(node);
+    *pivot.right_mut() = RawLink::some(node);
+    update(pivot);
+    pivot
+}
+
+/// Rotate the subtree rooted at `node` left, returning its new root (the
+/// old root's right child).
+unsafe fn rotate_left<'a, N: AvlNode>(node: &'a mut N) -> &'a mut N {
+    let pivot = node.right_mut().take().resolve_mut()
+                    .expect("can't rotate left without a right child!");
+    *node.right_mut() = pivot.left_mut().take();
+    update(node);
+    *pivot.left_mut() = RawLink::some(node);
+    update(pivot);
+    pivot
+}
+
+/// Restore the AVL property at `node`, whose subtrees are balanced and
+/// differ in height by at most two, returning the subtree's new root.
+unsafe fn rebalance<'a, N: AvlNode>(node: &'a mut N) -> &'a mut N {
+    update(node);
+    let (left, right) = (height(node.left()), height(node.right()));
+    if left > right + 1 {
+        let child = node.left().resolve_mut().expect("left is taller!");
+        if height(child.right()) > height(child.left()) {
+            *node.left_mut() = RawLink::some(rotate_left(child));
+        }
+        rotate_right(node)
+    } else if right > left + 1 {
+        let child = node.right().resolve_mut().expect("right is taller!");
+        if height(child.left()) > height(child.right()) {
+            *node.right_mut() = RawLink::some(rotate_right(child));
+        }
+        rotate_left(node)
+    } else {
+        node
+    }
+}
+
+/// Insert `item` into the subtree `link` points to, returning the
+/// subtree's new root.
+unsafe fn insert<'a, N>(link: RawLink<N>, item: &'a mut N) -> &'a mut N
+where N: AvlNode + Ord {
+    match link.resolve_mut() {
+        None => item
+      , Some(node) => {
+            // equal elements go to the right, so they come out in the order
+            // they went in.
+            if *item < *node {
+                let left = insert(copy(node.left()), item);
+                *node.left_mut() = RawLink::some(left);
+            } else {
+                let right = insert(copy(node.right()), item);
+                *node.right_mut() = RawLink::some(right);
+            }
+            rebalance(node)
+        }
+    }
+}
+
+/// Unlink the smallest element of the subtree rooted at `node`, returning
+/// the subtree's new root and the element.
+unsafe fn remove_min<'a, N>(node: &'a mut N) -> (RawLink<N>, &'a mut N)
+where N: AvlNode {
+    let left = copy(node.left());
+    match left.resolve_mut() {
+        None => {
+            let right = node.right_mut().take();
+            (right, node)
+        }
+      , Some(left) => {
+            let (new_left, min) = remove_min(left);
+            *node.left_mut() = new_left;
+            (RawLink::some(rebalance(node)), min)
+        }
+    }
+}
+
+impl<T, N> AvlTree<T, N>
+where T: OwnedRef<N>
+    , N: AvlNode {
+
+    /// Construct a new `AvlTree<T, N>` with zero elements
+    pub const fn new() -> Self {
+        AvlTree { root: RawLink::none()
+                , _ty_marker: PhantomData
+                , length: 0 }
+    }
+
+    /// Returns the number of elements in the tree
+    #[inline] pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns true if the tree is empty.
+    #[inline] pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Insert an element into the tree.
+    pub fn insert(&mut self, mut item: T)
+    where N: Ord {
+        unsafe {
+            {
+                let node = item.get_mut();
+                *node.left_mut() = RawLink::none();
+                *node.right_mut() = RawLink::none();
+                node.set_height(1);
+            }
+            self.root = RawLink::some(insert(copy(&self.root), item.get_mut()));
+            item.take();
+            self.length += 1;
+        }
+    }
+
+    /// Removes and returns the smallest element in the tree.
+    ///
+    /// # Returns
+    ///   - `Some(T)` containing the smallest element if the tree is not
+    ///     empty
+    ///   - `None` if the tree is empty
+    pub fn remove_min(&mut self) -> Option<T> {
+        unsafe {
+            self.root.resolve_mut()
+                .map(|root| {
+                    let (new_root, min) = remove_min(root);
+                    self.root = new_root;
+                    self.length -= 1;
+                    *min.right_mut() = RawLink::none();
+                    T::from_raw(min)
+                })
+        }
+    }
+
+    /// Borrows the smallest element in the tree as an `Option`
+    ///
+    /// # Returns
+    ///   - `Some(&N)` if the tree has elements
+    ///   - `None` if the tree is empty.
+    pub fn min(&self) -> Option<&N> {
+        let mut node = unsafe { self.root.resolve() }?;
+        while let Some(left) = unsafe { node.left().resolve() } {
+            node = left;
+        }
+        Some(node)
+    }
+
+    /// Search the tree for an element.
+    ///
+    /// `predicate` is called on the elements along a path from the root, and
+    /// says where the element being searched for is relative to each one:
+    /// `Less` if it's further left, `Greater` if it's further right, and
+    /// `Equal` if this is it.
+    ///
+    /// # Returns
+    ///   - `Some(&N)` borrowing the element found
+    ///   - `None` if no element matched
+    pub fn find<P>(&self, mut predicate: P) -> Option<&N>
+    where P: FnMut(&N) -> Ordering {
+        let mut link = copy(&self.root);
+        while let Some(node) = unsafe { link.resolve() } {
+            link = match predicate(node) {
+                Ordering::Less => copy(node.left())
+              , Ordering::Greater => copy(node.right())
+              , Ordering::Equal => return Some(node)
+            };
+        }
+        None
+    }
+}
@@ -0,0 +1,172 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+
+use std::boxed::Box;
+use std::cmp::Ordering;
+use std::vec::Vec;
+
+use avl::{AvlNode, AvlTree};
+use list::Node;
+use rawlink::RawLink;
+
+#[derive(Debug)]
+pub struct NumberedNode {
+    pub number: usize,
+    left: RawLink<NumberedNode>,
+    next: RawLink<NumberedNode>,
+    right: RawLink<NumberedNode>,
+    height: usize,
+}
+
+impl NumberedNode {
+    pub fn new(number: usize) -> Self {
+        NumberedNode {
+            number: number,
+            left: RawLink::none(),
+            next: RawLink::none(),
+            right: RawLink::none(),
+            height: 0,
+        }
+    }
+}
+
+impl Node for NumberedNode {
+    fn prev(&self) -> &RawLink<Self> {
+        &self.left
+    }
+
+    fn next(&self) -> &RawLink<Self> {
+        &self.next
+    }
+
+    fn prev_mut(&mut self) -> &mut RawLink<Self> {
+        &mut self.left
+    }
+
+    fn next_mut(&mut self) -> &mut RawLink<Self> {
+        &mut self.next
+    }
+}
+
+impl AvlNode for NumberedNode {
+    fn right(&self) -> &RawLink<Self> {
+        &self.right
+    }
+
+    fn right_mut(&mut self) -> &mut RawLink<Self> {
+        &mut self.right
+    }
+
+    fn height(&self) -> usize { self.height }
+
+    fn set_height(&mut self, height: usize) { self.height = height }
+}
+
+impl PartialEq for NumberedNode {
+    fn eq(&self, rhs: &Self) -> bool { self.number == rhs.number }
+}
+
+impl Eq for NumberedNode {}
+
+impl PartialOrd for NumberedNode {
+    fn partial_cmp(&self, rhs: &Self) -> Option<Ordering> {
+        Some(self.cmp(rhs))
+    }
+}
+
+impl Ord for NumberedNode {
+    fn cmp(&self, rhs: &Self) -> Ordering { self.number.cmp(&rhs.number) }
+}
+
+type TestTree = AvlTree<Box<NumberedNode>, NumberedNode>;
+
+/// The numbers 0 to 99, out of order.
+fn shuffled() -> Vec<usize> {
+    (0..100).map(|i| (i * 37) % 100).collect()
+}
+
+#[test]
+fn empty_tree() {
+    let mut tree = TestTree::new();
+
+    assert!(tree.is_empty());
+    assert_eq!(tree.len(), 0);
+    assert_eq!(tree.min(), None);
+    assert!(tree.remove_min().is_none());
+}
+
+#[test]
+fn not_empty_after_insert() {
+    let mut tree = TestTree::new();
+
+    tree.insert(box NumberedNode::new(1));
+
+    assert!(!tree.is_empty());
+    assert_eq!(tree.len(), 1);
+    assert_eq!(tree.min().unwrap().number, 1);
+}
+
+#[test]
+fn remove_min_in_order() {
+    let mut tree = TestTree::new();
+
+    for n in shuffled() {
+        tree.insert(box NumberedNode::new(n));
+    }
+    assert_eq!(tree.len(), 100);
+
+    for n in 0..100 {
+        assert_eq!(tree.min().unwrap().number, n);
+        assert_eq!(tree.remove_min().unwrap().number, n);
+    }
+    assert!(tree.is_empty());
+    assert_eq!(tree.len(), 0);
+}
+
+#[test]
+fn stays_balanced() {
+    let mut tree = TestTree::new();
+
+    // inserting in order is the worst case for an unbalanced tree.
+    for n in 0..1000 {
+        tree.insert(box NumberedNode::new(n));
+    }
+    // an AVL tree of 1000 elements is at most 1.44 * log2(1000) high.
+    assert!(unsafe { tree.root.resolve() }.unwrap().height() <= 14);
+
+    for _ in 0..500 {
+        tree.remove_min();
+    }
+    assert!(unsafe { tree.root.resolve() }.unwrap().height() <= 13);
+}
+
+#[test]
+fn find() {
+    let mut tree = TestTree::new();
+
+    for n in shuffled() {
+        tree.insert(box NumberedNode::new(n * 2));
+    }
+
+    for n in 0..100 {
+        let found = tree.find(|node| (n * 2).cmp(&node.number));
+        assert_eq!(found.unwrap().number, n * 2);
+        assert!(tree.find(|node| (n * 2 + 1).cmp(&node.number)).is_none());
+    }
+
+    // the smallest element at least 51.
+    let mut best = None;
+    tree.find(|node| if node.number >= 51 {
+        best = Some(node.number);
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    });
+    assert_eq!(best, Some(52));
+}
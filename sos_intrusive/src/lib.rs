@@ -20,26 +20,40 @@
 //! kernel subsystems which require structures such as lists prior to
 //! the initialization of the kernel heap.
 //!
-//! This crate currently provides an intrusive linked-list implementation.
+//! This crate currently provides an intrusive doubly-linked list, an
+//! intrusive stack, a lock-free version of the stack, and an intrusive AVL
+//! tree.
 //!
 //! # Features
 //! + `use-std`: use the Rust standard library (`std`), rather than `core`.
+//! + `bench`: build the benchmarks along with the tests (needs nightly's
+//!   `test` crate).
 #![crate_name = "sos_intrusive"]
 #![crate_type = "lib"]
 #![feature( const_fn
           , const_ptr_null_mut )]
-#![feature(ptr_internals)]
-#![feature(unique )]
 #![cfg_attr(not(feature = "use-std"), no_std )]
 #![cfg_attr(feature = "clippy", feature(plugin))]
 #![cfg_attr(feature = "clippy", plugin(clippy))]
 
 #![cfg_attr(test, feature(box_syntax))]
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
+#[cfg(all(test, feature = "bench"))] extern crate test;
 
 pub mod rawlink;
 pub use rawlink::RawLink;
 pub mod list;
 pub use list::List;
+pub mod owned;
+pub use owned::NonNullOwned;
+pub mod stack;
+pub use stack::{Stack, TreiberStack};
+pub use stack::Node as SinglyNode;
+pub mod avl;
+pub use avl::{AvlNode, AvlTree};
 
 #[cfg(test)]
 extern crate std;
+// with `use-std`, `core` isn't linked implicitly.
+#[cfg(feature = "use-std")]
+extern crate core;
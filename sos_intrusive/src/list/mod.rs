@@ -18,8 +18,9 @@
 use super::rawlink::RawLink;
 
 use core::marker::PhantomData;
-use core::ptr::Unique;
 use core::iter;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
 #[cfg(test)] mod test;
 
 pub unsafe trait OwnedRef<T> {
@@ -124,6 +125,16 @@ where T: OwnedRef<N>
         self.head.is_none()
     }
 
+    /// Returns true if the head, tail, and length agree about whether the
+    /// list is empty.
+    ///
+    /// This can only be false if the list's links were changed behind its
+    /// back, so it's for tests and debug assertions.
+    pub fn check_invariants(&self) -> bool {
+        let empty = self.length == 0;
+        self.head.is_none() == empty && self.tail.is_none() == empty
+    }
+
     /// Push an element to the front of the list.
     // TODO: should this really be called "prepend"?
     pub fn push_front(&mut self, mut item: T) {
@@ -206,6 +217,9 @@ where T: OwnedRef<N>
                             self.head = RawLink::some(next);
                         }
                     }
+                    debug_assert!( self.length > 0
+                                 , "pop on empty list with non-null head: \
+                                    invariant violated");
                     self.length -= 1;
                     T::from_raw(head)
                 })
@@ -229,6 +243,9 @@ where T: OwnedRef<N>
                             self.tail = RawLink::some(prev);
                         }
                     }
+                    debug_assert!( self.length > 0
+                                 , "pop on empty list with non-null tail: \
+                                    invariant violated");
                     self.length -= 1;
                     T::from_raw(tail)
                 })
@@ -251,6 +268,24 @@ where T: OwnedRef<N>
                       , current: RawLink::none() }
     }
 
+    /// Returns an iterator over the list's elements, front to back.
+    pub fn iter<'a>(&'a self) -> Iter<'a, N> {
+        Iter { next: unsafe { RawLink::from_raw(self.head.as_raw()) }
+             , _ty_marker: PhantomData }
+    }
+
+    /// Returns an iterator over the list's elements, front to back, which
+    /// prefetches each element's successor as it's yielded.
+    ///
+    /// The next element's address is only known once the current one has
+    /// been loaded, so a plain traversal of a long list misses the cache on
+    /// every step. This lets the load of the next element overlap whatever
+    /// the caller does with the current one. For short lists, or when the
+    /// caller does next to nothing with each element, use `iter`.
+    pub fn iter_prefetch<'a>(&'a self) -> PrefetchIter<'a, N> {
+        PrefetchIter(self.iter())
+    }
+
 }
 
 impl<T, N> iter::FromIterator<T> for List<T, N>
@@ -263,6 +298,72 @@ where T: OwnedRef<N>
         }
 }
 
+/// Hint to the CPU that `node`'s successor will be read soon, so that it's
+/// pulled into the L1 cache while `node` is still being worked on.
+///
+/// This is only a hint: it never faults, even if the successor isn't
+/// mapped, and it does nothing if `node` is the last element or on
+/// targets other than x86_64.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+pub fn prefetch_next<N: Node>(node: &N) {
+    let next = unsafe { node.next().as_raw() };
+    if !next.is_null() {
+        // `prefetcht0`: into every level of the cache.
+        unsafe { _mm_prefetch(next as *const i8, _MM_HINT_T0) }
+    }
+}
+
+/// Hint to the CPU that `node`'s successor will be read soon.
+///
+/// This target has no prefetch hint we use, so this does nothing.
+#[cfg(not(target_arch = "x86_64"))]
+#[inline]
+pub fn prefetch_next<N: Node>(_node: &N) { }
+
+/// An iterator over the elements of a `List`.
+pub struct Iter<'a, N>
+where N: Node
+    , N: 'a {
+    next: RawLink<N>
+  , _ty_marker: PhantomData<&'a N>
+}
+
+impl<'a, N> Iterator for Iter<'a, N>
+where N: Node
+    , N: 'a {
+    type Item = &'a N;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a N> {
+        unsafe {
+            self.next.take().resolve().map(|node: &'a N| {
+                self.next = RawLink::from_raw(node.next().as_raw());
+                node
+            })
+        }
+    }
+}
+
+/// An iterator over the elements of a `List` that prefetches each
+/// element's successor as it's yielded.
+///
+/// See [`List::iter_prefetch`](struct.List.html#method.iter_prefetch).
+pub struct PrefetchIter<'a, N>(Iter<'a, N>)
+where N: Node
+    , N: 'a;
+
+impl<'a, N> Iterator for PrefetchIter<'a, N>
+where N: Node
+    , N: 'a {
+    type Item = &'a N;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a N> {
+        self.0.next().map(|node| { prefetch_next(node); node })
+    }
+}
+
 pub trait Cursor {
     type Item;
 
@@ -480,26 +581,6 @@ where T: OwnedRef<N>
 // }
 //
 
-unsafe impl<T> OwnedRef<T> for Unique<T>  {
-    #[inline]
-    fn get(&self) -> &T {
-        unsafe { self.as_ref() }
-    }
-
-    #[inline] fn get_mut(&mut self) -> &mut T {
-        unsafe { self.as_mut() }
-    }
-
-    #[inline]
-    unsafe fn take(self) {}
-
-    unsafe fn from_raw(ptr: *mut T) -> Self {
-        Unique::new(ptr)
-            // TODO: probably don't panic here.
-            .expect("null pointer passed to OwnedRef::from_raw!")
-    }
-}
-
 #[cfg(any(test, feature = "use-std"))]
 unsafe impl<T> OwnedRef<T> for ::std::boxed::Box<T> {
 
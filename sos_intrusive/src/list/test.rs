@@ -54,6 +54,8 @@ mod boxed {
 
     use list::List;
     use super::*;
+    #[cfg(feature = "bench")] use std::vec::Vec;
+    #[cfg(feature = "bench")] use test::{self, Bencher};
 
     type TestList = List<Box<NumberedNode>, NumberedNode>;
 
@@ -150,6 +152,7 @@ mod boxed {
         assert_eq!(list.back().unwrap().number, 4);
 
         assert!(!list.is_empty());
+        assert!(list.check_invariants());
 
         assert_eq!(list.pop_front().unwrap().number, 0);
         assert_eq!(list.pop_front().unwrap().number, 1);
@@ -158,7 +161,9 @@ mod boxed {
         assert_eq!(list.pop_front().unwrap().number, 4);
 
         assert!(list.is_empty());
+        assert!(list.check_invariants());
         assert_eq!(list.pop_front(), None);
+        assert!(list.check_invariants());
     }
 
     #[test]
@@ -187,6 +192,7 @@ mod boxed {
         assert_eq!(list.back().unwrap().number, 4);
 
         assert!(!list.is_empty());
+        assert!(list.check_invariants());
 
         assert_eq!(list.pop_back().unwrap().number, 4);
         assert_eq!(list.pop_back().unwrap().number, 3);
@@ -195,10 +201,63 @@ mod boxed {
         assert_eq!(list.pop_back().unwrap().number, 0);
 
         assert!(list.is_empty());
+        assert!(list.check_invariants());
         assert_eq!(list.pop_back(), None);
+        assert!(list.check_invariants());
     }
 
+    #[test]
+    fn iter_front_to_back() {
+        let mut list = TestList::new();
+
+        assert_eq!(list.iter().next(), None);
+        assert_eq!(list.iter_prefetch().next(), None);
+
+        for i in 0..5 {
+            list.push_back(Box::new(NumberedNode::new(i)));
+        }
+
+        let numbers = list.iter().map(|node| node.number);
+        assert!(numbers.eq(0..5));
+        let numbers = list.iter_prefetch().map(|node| node.number);
+        assert!(numbers.eq(0..5));
+        assert_eq!(list.len(), 5);
+    }
+
+    #[cfg(feature = "bench")]
+    const BENCH_NODES: usize = 10_000;
 
+    /// A list of `BENCH_NODES` nodes, spread out in memory as they would be
+    /// after a while in the kernel heap, rather than allocated back to back.
+    #[cfg(feature = "bench")]
+    fn bench_list() -> (TestList, Vec<Box<[u8; 4096]>>) {
+        let mut list = TestList::new();
+        let mut spacers = Vec::with_capacity(BENCH_NODES);
+        for i in 0..BENCH_NODES {
+            list.push_back(Box::new(NumberedNode::new(i)));
+            spacers.push(Box::new([0u8; 4096]));
+        }
+        (list, spacers)
+    }
+
+    #[cfg(feature = "bench")]
+    #[bench]
+    fn traverse_10k(b: &mut Bencher) {
+        let (list, _spacers) = bench_list();
+        b.iter(|| {
+            list.iter().fold(0, |sum, node| sum + test::black_box(node.number))
+        })
+    }
+
+    #[cfg(feature = "bench")]
+    #[bench]
+    fn traverse_10k_prefetch(b: &mut Bencher) {
+        let (list, _spacers) = bench_list();
+        b.iter(|| {
+            list.iter_prefetch()
+                .fold(0, |sum, node| sum + test::black_box(node.number))
+        })
+    }
 }
 
 // mod mut_ptr {
@@ -0,0 +1,70 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A non-null owning pointer, for collections whose nodes aren't boxed.
+//!
+//! This stands in for `core::ptr::Unique`, which is unstable, and whose
+//! API keeps changing under us.
+use core::fmt;
+use core::ptr::NonNull;
+
+use list::OwnedRef;
+
+/// An owning pointer to a `T` that isn't null.
+///
+/// Like a `Box`, but nothing is freed when it's dropped: whoever made the
+/// pointer is responsible for the memory it points to.
+pub struct NonNullOwned<T>(NonNull<T>);
+
+// a `NonNullOwned` owns its `T`, so it can be sent or shared whenever the
+// `T` could be.
+unsafe impl<T: Send> Send for NonNullOwned<T> {}
+unsafe impl<T: Sync> Sync for NonNullOwned<T> {}
+
+impl<T> NonNullOwned<T> {
+    /// Take ownership of the `T` at `ptr`.
+    ///
+    /// # Safety
+    /// + `ptr` must point to a valid `T`, which nothing else owns.
+    ///
+    /// # Panics
+    /// + If `ptr` is null, in debug builds.
+    #[inline]
+    pub unsafe fn from_raw(ptr: *mut T) -> Self {
+        debug_assert!(!ptr.is_null(), "null pointer passed to from_raw!");
+        NonNullOwned(NonNull::new_unchecked(ptr))
+    }
+
+    /// Returns a reference to the `T`.
+    #[inline] pub fn get(&self) -> &T { unsafe { self.0.as_ref() } }
+
+    /// Returns a mutable reference to the `T`.
+    #[inline] pub fn get_mut(&mut self) -> &mut T { unsafe { self.0.as_mut() } }
+
+    /// Returns the pointer to the `T`.
+    #[inline] pub fn as_ptr(&self) -> *mut T { self.0.as_ptr() }
+}
+
+unsafe impl<T> OwnedRef<T> for NonNullOwned<T> {
+    #[inline] fn get(&self) -> &T { NonNullOwned::get(self) }
+
+    #[inline] fn get_mut(&mut self) -> &mut T { NonNullOwned::get_mut(self) }
+
+    /// The collection has taken over the `T`, so there's nothing to do.
+    #[inline] unsafe fn take(self) {}
+
+    #[inline] unsafe fn from_raw(ptr: *mut T) -> Self {
+        NonNullOwned::from_raw(ptr)
+    }
+}
+
+impl<T> fmt::Debug for NonNullOwned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NonNullOwned({:p})", self.as_ptr())
+    }
+}
@@ -15,12 +15,16 @@
 //! use intrusive lists in code that runs without the kernel memory allocator,
 //! like the allocator implementation itself, since each list element manages
 //! its own memory.
-use ::{RawLink, OwnedRef};
+use ::RawLink;
+use ::list::OwnedRef;
 
 use core::marker::PhantomData;
 use core::iter;
 #[cfg(test)] mod test;
 
+mod treiber;
+pub use self::treiber::TreiberStack;
+
 
 /// This trait defines a node in an intrusive list.
 ///
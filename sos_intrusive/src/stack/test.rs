@@ -123,3 +123,90 @@ mod boxed {
 
 
 }
+
+mod treiber {
+    use std::boxed::Box;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    use super::super::TreiberStack;
+    use super::*;
+
+    type TestStack = TreiberStack<Box<NumberedNode>, NumberedNode>;
+
+    #[test]
+    fn pop_empty() {
+        let stack = TestStack::new();
+        assert!(stack.is_empty());
+        assert_eq!(unsafe { stack.pop() }, None);
+    }
+
+    #[test]
+    fn pops_in_reverse_order() {
+        let stack = TestStack::new();
+        for i in 0..5 { stack.push(Box::new(NumberedNode::new(i))); }
+        assert!(!stack.is_empty());
+
+        for i in (0..5).rev() {
+            assert_eq!(unsafe { stack.pop() }.unwrap().number, i);
+        }
+        assert!(stack.is_empty());
+        assert_eq!(unsafe { stack.pop() }, None);
+    }
+
+    #[test]
+    fn concurrent_pushes() {
+        const THREADS: usize = 4;
+        const PER_THREAD: usize = 1000;
+        let stack = Arc::new(TestStack::new());
+
+        let threads: Vec<_> = (0..THREADS).map(|t| {
+            let stack = stack.clone();
+            thread::spawn(move || {
+                for i in 0..PER_THREAD {
+                    stack.push(Box::new(NumberedNode::new(t * PER_THREAD + i)));
+                }
+            })
+        }).collect();
+        for thread in threads { thread.join().unwrap(); }
+
+        let mut seen: Vec<bool>
+            = (0..THREADS * PER_THREAD).map(|_| false).collect();
+        while let Some(node) = unsafe { stack.pop() } {
+            assert!(!seen[node.number], "{} popped twice", node.number);
+            seen[node.number] = true;
+        }
+        assert!(seen.iter().all(|&seen| seen));
+    }
+
+    #[test]
+    fn concurrent_pops_and_pushes() {
+        const THREADS: usize = 4;
+        const NODES: usize = 16;
+        const ROUNDS: usize = 10_000;
+        let stack = Arc::new(TestStack::new());
+        for i in 0..NODES { stack.push(Box::new(NumberedNode::new(i))); }
+
+        // each thread keeps popping a node and pushing it straight back, so
+        // the same few nodes are at the head over and over.
+        let threads: Vec<_> = (0..THREADS).map(|_| {
+            let stack = stack.clone();
+            thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    if let Some(node) = unsafe { stack.pop() } {
+                        stack.push(node);
+                    }
+                }
+            })
+        }).collect();
+        for thread in threads { thread.join().unwrap(); }
+
+        let mut seen: Vec<bool> = (0..NODES).map(|_| false).collect();
+        while let Some(node) = unsafe { stack.pop() } {
+            assert!(!seen[node.number], "{} popped twice", node.number);
+            seen[node.number] = true;
+        }
+        assert!(seen.iter().all(|&seen| seen));
+    }
+}
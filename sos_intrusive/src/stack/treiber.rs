@@ -0,0 +1,149 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A lock-free intrusive stack.
+//!
+//! A [`TreiberStack`](struct.TreiberStack.html) is a [`Stack`] that can be
+//! pushed to and popped from through a shared reference, from any number of
+//! threads at once, without taking a lock. Both operations retry a
+//! compare-and-swap on the head of the stack until nobody else has changed
+//! it in the meantime.
+//!
+//! A bare head pointer isn't enough for that: if a pop reads the head node
+//! `A` and its link to `B`, and is then interrupted while someone else pops
+//! `A` and `B` and pushes `A` back, the head is `A` again and the first
+//! pop's compare-and-swap succeeds, putting `B` back on the stack even
+//! though it's been handed out (the ABA problem). So the head is tagged
+//! with a generation count that every successful push and pop bumps, and
+//! the compare-and-swap only succeeds if the count hasn't changed either.
+//!
+//! x86_64 virtual addresses are 48 bits wide, sign-extended to 64, so the
+//! tag goes in the top 16 bits of the head, and the pointer and tag are
+//! swapped together with an ordinary 64-bit compare-and-swap. For the ABA
+//! problem to come back, a pop would have to be stalled across exactly a
+//! multiple of 65,536 other pushes and pops, and find the same node at the
+//! head when it resumed.
+//!
+//! [`Stack`]: ../struct.Stack.html
+use ::RawLink;
+use ::list::OwnedRef;
+use super::Node;
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of bits of the head that hold the pointer to the top node.
+const PTR_BITS: usize = 48;
+/// The bits of the head that hold the pointer to the top node.
+const PTR_MASK: usize = (1 << PTR_BITS) - 1;
+
+/// Returns the head that points to `node`, with the generation count `tag`.
+#[inline]
+fn pack<N>(node: *mut N, tag: usize) -> usize {
+    (node as usize & PTR_MASK) | (tag << PTR_BITS)
+}
+
+/// Returns the node `head` points to.
+#[inline]
+fn node_of<N>(head: usize) -> *mut N {
+    // sign-extend the address back out to 64 bits.
+    let shift = 64 - PTR_BITS;
+    (((head << shift) as isize) >> shift) as usize as *mut N
+}
+
+/// Returns the generation count of `head`.
+#[inline]
+fn tag_of(head: usize) -> usize { head >> PTR_BITS }
+
+/// A lock-free intrusive stack.
+pub struct TreiberStack<T, N>
+where T: OwnedRef<N>
+    , N: Node {
+    /// The top node, packed with a generation count.
+    head: AtomicUsize
+  , _ty_marker: PhantomData<(T, *mut N)>
+ }
+
+unsafe impl<T, N> Send for TreiberStack<T, N>
+where T: OwnedRef<N> + Send
+    , N: Node {}
+
+unsafe impl<T, N> Sync for TreiberStack<T, N>
+where T: OwnedRef<N> + Send
+    , N: Node {}
+
+impl<T, N> TreiberStack<T, N>
+where T: OwnedRef<N>
+    , N: Node {
+
+    /// Construct a new `TreiberStack<T, N>` with zero elements
+    pub const fn new() -> Self {
+        TreiberStack { head: AtomicUsize::new(0)
+                     , _ty_marker: PhantomData }
+    }
+
+    /// Returns true if the stack was empty when it was looked at.
+    ///
+    /// Someone else may have pushed to it since.
+    #[inline] pub fn is_empty(&self) -> bool {
+        node_of::<N>(self.head.load(Ordering::Relaxed)).is_null()
+    }
+
+    /// Push an element to the front of the stack
+    pub fn push(&self, mut item: T) {
+        let node: *mut N = item.get_mut();
+        unsafe { item.take(); };
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // the node isn't on the stack yet, so it's still ours to write.
+            unsafe { *(*node).next_mut() = RawLink::from_raw(node_of(head)); }
+            let new = pack(node, tag_of(head).wrapping_add(1));
+            // `Release`, so that whoever pops the node sees its link.
+            match self.head.compare_exchange_weak( head, new
+                                                 , Ordering::Release
+                                                 , Ordering::Relaxed ) {
+                Ok(_) => return
+              , Err(current) => head = current
+            }
+        }
+    }
+
+    /// Removes and returns the element at the front of the stack.
+    ///
+    /// # Returns
+    ///   - `Some(T)` containing the element at the front of the stack if
+    ///     the stack is not empty
+    ///   - `None` if the stack is empty
+    ///
+    /// # Safety
+    ///   - A node's memory must stay readable after it's popped, since a
+    ///     pop racing with this one may still read its link.
+    pub unsafe fn pop(&self) -> Option<T> {
+        // `Acquire`, so that the link read from the head node is the one
+        // written by whoever pushed it.
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let node: *mut N = node_of(head);
+            if node.is_null() { return None }
+            // if `node` has been popped since `head` was read, this may be
+            // a stale link, but then the generation count has moved on and
+            // the compare-and-swap below fails.
+            let next = (*node).next().as_raw();
+            let new = pack(next, tag_of(head).wrapping_add(1));
+            match self.head.compare_exchange_weak( head, new
+                                                 , Ordering::AcqRel
+                                                 , Ordering::Acquire ) {
+                Ok(_) => {
+                    *(*node).next_mut() = RawLink::none();
+                    return Some(T::from_raw(node))
+                }
+              , Err(current) => head = current
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! # SOS Intrusive Derive
+//!
+//! `#[derive(Node)]`, for implementing `sos_intrusive`'s list `Node` trait
+//! without writing out all four of its methods.
+//!
+//! Mark the link to the next node with `#[intrusive_list_link]`, and the
+//! link to the previous node with `#[prev_link]`:
+//!
+//! ```ignore
+//! #[macro_use] extern crate sos_intrusive_derive;
+//! extern crate sos_intrusive;
+//!
+//! use sos_intrusive::RawLink;
+//!
+//! #[derive(Node)]
+//! struct Block { size: usize
+//!              , #[intrusive_list_link] link: RawLink<Block>
+//!              , #[prev_link] prev_link: RawLink<Block>
+//!              }
+//! ```
+//!
+//! Each attribute must be on exactly one field; anything else is a
+//! compile error.
+#![crate_name = "sos_intrusive_derive"]
+#![crate_type = "proc-macro"]
+
+extern crate proc_macro;
+extern crate syn;
+#[macro_use] extern crate quote;
+
+use proc_macro::TokenStream;
+use syn::{Body, DeriveInput, Field, Ident, VariantData};
+
+/// The attribute marking the link to the next node.
+const NEXT_ATTR: &'static str = "intrusive_list_link";
+/// The attribute marking the link to the previous node.
+const PREV_ATTR: &'static str = "prev_link";
+
+#[proc_macro_derive(Node, attributes(intrusive_list_link, prev_link))]
+pub fn derive_node(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string())
+        .expect("#[derive(Node)]: couldn't parse the item");
+    let tokens = match impl_node(&ast) {
+        Ok(tokens) => tokens
+      , Err(message) => quote! { compile_error!(#message); }
+    };
+    tokens.parse().expect("#[derive(Node)]: generated invalid code")
+}
+
+/// Returns the one field of `fields` marked with `#[attr]`.
+fn marked<'a>(fields: &'a [Field], attr: &str) -> Result<&'a Ident, String> {
+    let mut found
+        = fields.iter()
+                .filter(|field| field.attrs.iter().any(|a| a.name() == attr));
+    match (found.next(), found.next()) {
+        (Some(field), None) =>
+            Ok(field.ident.as_ref()
+                    .expect("named fields always have an identifier"))
+      , (None, _) =>
+            Err(format!( "#[derive(Node)] needs a field marked #[{}]"
+                       , attr))
+      , (Some(_), Some(_)) =>
+            Err(format!( "#[derive(Node)] needs exactly one field marked \
+                          #[{}], not several", attr))
+    }
+}
+
+/// Generate the `Node` impl for `ast`, or the message for a compile error.
+fn impl_node(ast: &DeriveInput) -> Result<quote::Tokens, String> {
+    let fields = match ast.body {
+        Body::Struct(VariantData::Struct(ref fields)) => fields
+      , _ => return Err(String::from( "#[derive(Node)] only works on structs \
+                                       with named fields"))
+    };
+    let next = marked(fields, NEXT_ATTR)?;
+    let prev = marked(fields, PREV_ATTR)?;
+    if next == prev {
+        return Err(String::from( "#[derive(Node)] needs different fields for \
+                                  the next and previous links"))
+    }
+
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause)
+        = ast.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::sos_intrusive::list::Node
+        for #name #ty_generics #where_clause {
+            #[inline]
+            fn next(&self) -> &::sos_intrusive::RawLink<Self> {
+                &self.#next
+            }
+            #[inline]
+            fn prev(&self) -> &::sos_intrusive::RawLink<Self> {
+                &self.#prev
+            }
+            #[inline]
+            fn next_mut(&mut self) -> &mut ::sos_intrusive::RawLink<Self> {
+                &mut self.#next
+            }
+            #[inline]
+            fn prev_mut(&mut self) -> &mut ::sos_intrusive::RawLink<Self> {
+                &mut self.#prev
+            }
+        }
+    })
+}
@@ -0,0 +1,58 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+#[macro_use] extern crate sos_intrusive_derive;
+extern crate sos_intrusive;
+
+use sos_intrusive::{List, RawLink};
+use sos_intrusive::list::Node;
+
+#[derive(Debug, Node)]
+struct NumberedNode { number: usize
+                    , #[prev_link] prev_link: RawLink<NumberedNode>
+                    , #[intrusive_list_link] link: RawLink<NumberedNode>
+                    }
+
+impl NumberedNode {
+    fn new(number: usize) -> Box<Self> {
+        Box::new(NumberedNode { number: number
+                              , prev_link: RawLink::none()
+                              , link: RawLink::none()
+                              })
+    }
+}
+
+type TestList = List<Box<NumberedNode>, NumberedNode>;
+
+#[test]
+fn links_start_empty() {
+    let node = NumberedNode::new(0);
+    assert!(node.next().is_none());
+    assert!(node.prev().is_none());
+}
+
+#[test]
+fn push_and_pop() {
+    let mut list = TestList::new();
+    list.push_back(NumberedNode::new(1));
+    list.push_back(NumberedNode::new(2));
+    list.push_front(NumberedNode::new(0));
+    assert_eq!(list.len(), 3);
+
+    assert_eq!(list.front().unwrap().number, 0);
+    assert_eq!(list.back().unwrap().number, 2);
+    // the derived methods link the nodes both ways.
+    assert!(list.front().unwrap().next().is_some());
+    assert!(list.front().unwrap().prev().is_none());
+    assert!(list.back().unwrap().prev().is_some());
+
+    assert_eq!(list.pop_front().unwrap().number, 0);
+    assert_eq!(list.pop_back().unwrap().number, 2);
+    assert_eq!(list.pop_front().unwrap().number, 1);
+    assert!(list.is_empty());
+}
@@ -23,7 +23,7 @@
 //! mode) modules are currently much less complete.
 
 // 64-bit x86_64 (long mode)
-#[cfg(target_arch="x86_64")] mod x86_64;
+#[cfg(target_arch="x86_64")] #[macro_use] mod x86_64;
 #[cfg(target_arch="x86_64")] pub use self::x86_64::*;
 
 // 32-bit x86 (protected mode)
@@ -0,0 +1,461 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Just enough ACPI Machine Language to read `\_S5_` and call `\_PIC`.
+//!
+//! This is nowhere near a real AML interpreter. It understands integer
+//! constants, packages, `Name`s, and methods whose bodies only `Store` and
+//! `Return` values, or call other such methods. Anything else is rejected
+//! by returning `None`: every DSDT is different, and one we can't read
+//! shouldn't bring the kernel down.
+//!
+//! There's no namespace, either. An object is found by looking through the
+//! DSDT's bytecode for a `DefName` or `DefMethod` whose name ends with the
+//! same segments as the path asked for, so scopes are ignored. The objects
+//! the kernel wants are all defined once, so that's good enough for them.
+use alloc::vec::Vec;
+
+/// `NameOp`: the start of a `DefName`.
+const NAME_OP: u8 = 0x08;
+/// `PackageOp`: the start of a `DefPackage`.
+const PACKAGE_OP: u8 = 0x12;
+/// `MethodOp`: the start of a `DefMethod`.
+const METHOD_OP: u8 = 0x14;
+/// `StoreOp`: the start of a `DefStore`.
+const STORE_OP: u8 = 0x70;
+/// `ReturnOp`: the start of a `DefReturn`.
+const RETURN_OP: u8 = 0xa4;
+
+/// The root prefix, `\`, that may come before a name.
+const ROOT_CHAR: u8 = b'\\';
+/// The parent prefix, `^`, that may come before a name.
+const PARENT_CHAR: u8 = b'^';
+/// `DualNamePrefix`: a name with two segments follows.
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+/// `MultiNamePrefix`: a segment count, and that many segments, follow.
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+/// `NullName`: a name with no segments.
+const NULL_NAME: u8 = 0x00;
+
+/// `ZeroOp`: the constant 0.
+const ZERO_OP: u8 = 0x00;
+/// `OneOp`: the constant 1.
+const ONE_OP: u8 = 0x01;
+/// `OnesOp`: the constant with every bit set.
+const ONES_OP: u8 = 0xff;
+/// `BytePrefix`: an 8-bit constant follows.
+const BYTE_PREFIX: u8 = 0x0a;
+/// `WordPrefix`: a 16-bit constant follows.
+const WORD_PREFIX: u8 = 0x0b;
+/// `DWordPrefix`: a 32-bit constant follows.
+const DWORD_PREFIX: u8 = 0x0c;
+/// `QWordPrefix`: a 64-bit constant follows.
+const QWORD_PREFIX: u8 = 0x0e;
+
+/// `Local0Op`; `Local1Op` to `Local7Op` follow it.
+const LOCAL0_OP: u8 = 0x60;
+/// `Arg0Op`; `Arg1Op` to `Arg6Op` follow it.
+const ARG0_OP: u8 = 0x68;
+/// The number of locals a method has.
+const NUM_LOCALS: usize = 8;
+/// The most arguments a method can take.
+const MAX_ARGS: usize = 7;
+
+/// How deeply methods may call each other, or `Name`s refer to each other,
+/// before we give up on them.
+const MAX_CALL_DEPTH: usize = 8;
+
+/// A four-character segment of a name.
+type NameSeg = [u8; 4];
+
+/// A value computed by AML.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AmlValue { Integer(u64)
+                  , Package(Vec<AmlValue>)
+                  }
+
+impl AmlValue {
+    /// Returns this value, if it's an integer.
+    #[inline]
+    pub fn as_integer(&self) -> Option<u64> {
+        match *self {
+            AmlValue::Integer(value) => Some(value)
+          , _ => None
+        }
+    }
+}
+
+/// A name, as written in AML.
+struct NameString { /// True if the name starts at the root
+                    root: bool
+                  , segs: Vec<NameSeg>
+                  }
+
+impl NameString {
+    /// Returns true if this name could refer to the object at `path`.
+    fn matches(&self, path: &[NameSeg]) -> bool {
+        if self.segs.is_empty() || self.segs.len() > path.len() {
+            return false
+        }
+        if self.root && self.segs.len() != path.len() { return false }
+        path.ends_with(&self.segs[..])
+    }
+}
+
+/// Where an object was defined in the DSDT.
+#[derive(Copy, Clone, Debug)]
+enum Definition {
+    /// A `DefName`, whose value starts at this offset.
+    Name(usize)
+  , /// A `DefMethod`.
+    Method { args: usize, body: usize, end: usize }
+}
+
+/// What to do after a term in a method body.
+enum Flow { Next, Return(AmlValue) }
+
+/// The arguments and locals of a running method.
+struct Frame { args: Vec<AmlValue>
+             , locals: Vec<Option<AmlValue>>
+             }
+
+/// Evaluates objects in a DSDT.
+pub struct Interpreter<'a> { dsdt: &'a [u8]
+                           , /// Values stored to `Name`s, by the offset of
+                             /// their definition, since the DSDT itself is
+                             /// left alone
+                             stores: Vec<(usize, AmlValue)>
+                           }
+
+impl<'a> Interpreter<'a> {
+    /// Returns an interpreter for the AML in `dsdt`, which doesn't include
+    /// the table's header.
+    pub fn new(dsdt: &'a [u8]) -> Self {
+        Interpreter { dsdt: dsdt, stores: Vec::new() }
+    }
+
+    /// Returns the value of the object at `path`, calling it with no
+    /// arguments if it's a method.
+    #[inline]
+    pub fn evaluate(&mut self, path: &str) -> Option<AmlValue> {
+        self.invoke(path, &[])
+    }
+
+    /// Call the method at `path` with `args`, and return its result.
+    ///
+    /// A method that finishes without a `Return` results in zero. If `path`
+    /// is a `Name` rather than a method, `args` must be empty, and its value
+    /// is returned.
+    pub fn invoke(&mut self, path: &str, args: &[AmlValue])
+                 -> Option<AmlValue> {
+        let path = parse_path(path)?;
+        match self.find(&path)? {
+            Definition::Name(_) if !args.is_empty() => None
+          , Definition::Method { args: n, .. } if n != args.len() => None
+          , def => self.value_of(def, args.to_vec(), 0)
+        }
+    }
+
+    /// Find the definition of the object at `path`.
+    ///
+    /// Methods are looked for first, since a method's body is less likely to
+    /// contain a stray `NameOp` than the other way round.
+    fn find(&self, path: &[NameSeg]) -> Option<Definition> {
+        let aml = self.dsdt;
+        let method = (0 .. aml.len())
+            .filter(|&i| aml[i] == METHOD_OP)
+            .filter_map(|i| {
+                let mut j = i + 1;
+                let end = i + 1 + parse_pkg_length(aml, &mut j)?;
+                let name = parse_name(aml, &mut j)?;
+                let flags = *aml.get(j)?;
+                if end > aml.len() || !name.matches(path) { return None }
+                Some(Definition::Method { args: (flags & 0b111) as usize
+                                        , body: j + 1
+                                        , end: end
+                                        })
+            })
+            .next();
+        method.or_else(|| (0 .. aml.len())
+            .filter(|&i| aml[i] == NAME_OP)
+            .filter_map(|i| {
+                let mut j = i + 1;
+                if parse_name(aml, &mut j)?.matches(path) {
+                    Some(Definition::Name(j))
+                } else {
+                    None
+                }
+            })
+            .next())
+    }
+
+    /// Returns the value of the object defined at `def`, calling it with
+    /// `args` if it's a method.
+    fn value_of(&mut self, def: Definition, args: Vec<AmlValue>, depth: usize)
+               -> Option<AmlValue> {
+        // a `Name` may be a package that refers back to itself.
+        if depth >= MAX_CALL_DEPTH { return None }
+        match def {
+            Definition::Name(at) => {
+                if let Some(&(_, ref value)) =
+                        self.stores.iter().find(|&&(def, _)| def == at) {
+                    return Some(value.clone())
+                }
+                let mut i = at;
+                self.data(&mut i, depth + 1)
+            }
+          , Definition::Method { body, end, .. } => {
+                let mut frame = Frame { args: args
+                                      , locals: vec![None; NUM_LOCALS]
+                                      };
+                let mut i = body;
+                while i < end {
+                    match self.term(&mut frame, &mut i, depth + 1)? {
+                        Flow::Next => {}
+                      , Flow::Return(value) => return Some(value)
+                    }
+                }
+                Some(AmlValue::Integer(0))
+            }
+        }
+    }
+
+    /// Run the term at `dsdt[*i]` in a method body, advancing `i` past it.
+    fn term(&mut self, frame: &mut Frame, i: &mut usize, depth: usize)
+           -> Option<Flow> {
+        let aml = self.dsdt;
+        match *aml.get(*i)? {
+            STORE_OP => {
+                *i += 1;
+                let value = self.term_arg(frame, i, depth)?;
+                self.store(frame, i, value)?;
+                Some(Flow::Next)
+            }
+          , RETURN_OP => {
+                *i += 1;
+                self.term_arg(frame, i, depth).map(Flow::Return)
+            }
+            // a method call, whose result is thrown away.
+          , op if is_name_start(op) =>
+                self.term_arg(frame, i, depth).map(|_| Flow::Next)
+          , _ => None
+        }
+    }
+
+    /// Evaluate the argument at `dsdt[*i]`, advancing `i` past it.
+    fn term_arg(&mut self, frame: &mut Frame, i: &mut usize, depth: usize)
+               -> Option<AmlValue> {
+        let aml = self.dsdt;
+        let op = *aml.get(*i)?;
+        if op >= ARG0_OP && op < ARG0_OP + MAX_ARGS as u8 {
+            *i += 1;
+            return frame.args.get((op - ARG0_OP) as usize).cloned()
+        }
+        if op >= LOCAL0_OP && op < LOCAL0_OP + NUM_LOCALS as u8 {
+            *i += 1;
+            return frame.locals[(op - LOCAL0_OP) as usize].clone()
+        }
+        if op == NULL_NAME || !is_name_start(op) {
+            return self.data(i, depth)
+        }
+
+        let name = parse_name(aml, i)?;
+        let def = self.find(&name.segs)?;
+        let n = match def {
+            Definition::Method { args, .. } => args
+          , Definition::Name(_) => 0
+        };
+        let mut args = Vec::with_capacity(n);
+        for _ in 0 .. n {
+            args.push(self.term_arg(frame, i, depth)?);
+        }
+        self.value_of(def, args, depth)
+    }
+
+    /// Store `value` in the target at `dsdt[*i]`, advancing `i` past it.
+    ///
+    /// Only locals, arguments, and `Name`s can be stored to.
+    fn store(&mut self, frame: &mut Frame, i: &mut usize, value: AmlValue)
+            -> Option<()> {
+        let aml = self.dsdt;
+        let op = *aml.get(*i)?;
+        if op >= ARG0_OP && op < ARG0_OP + MAX_ARGS as u8 {
+            *i += 1;
+            let arg = frame.args.get_mut((op - ARG0_OP) as usize)?;
+            *arg = value;
+            return Some(())
+        }
+        if op >= LOCAL0_OP && op < LOCAL0_OP + NUM_LOCALS as u8 {
+            *i += 1;
+            frame.locals[(op - LOCAL0_OP) as usize] = Some(value);
+            return Some(())
+        }
+        if op == NULL_NAME || !is_name_start(op) { return None }
+
+        // anything else, like a field in an operation region, would mean
+        // touching the hardware, which is more than we know how to do.
+        let name = parse_name(aml, i)?;
+        match self.find(&name.segs)? {
+            Definition::Name(at) => {
+                self.stores.retain(|&(def, _)| def != at);
+                self.stores.push((at, value));
+                Some(())
+            }
+          , _ => None
+        }
+    }
+
+    /// Decode the integer or package at `dsdt[*i]`, advancing `i` past it.
+    fn data(&mut self, i: &mut usize, depth: usize) -> Option<AmlValue> {
+        let aml = self.dsdt;
+        if aml.get(*i) != Some(&PACKAGE_OP) {
+            return parse_integer(aml, i).map(AmlValue::Integer)
+        }
+        *i += 1;
+        let start = *i;
+        let end = start + parse_pkg_length(aml, i)?;
+        let num_elements = *aml.get(*i)? as usize;
+        *i += 1;
+        if end > aml.len() { return None }
+
+        let mut elements = Vec::with_capacity(num_elements);
+        while *i < end && elements.len() < num_elements {
+            let op = aml[*i];
+            let element = if op != NULL_NAME && is_name_start(op) {
+                // a reference to another object, which should be a `Name`.
+                let name = parse_name(aml, i)?;
+                match self.find(&name.segs)? {
+                    def @ Definition::Name(_) =>
+                        self.value_of(def, Vec::new(), depth)?
+                  , _ => return None
+                }
+            } else {
+                self.data(i, depth)?
+            };
+            elements.push(element);
+        }
+        *i = end;
+        Some(AmlValue::Package(elements))
+    }
+}
+
+/// Returns the value of the integer at `path` in `dsdt`.
+pub fn find_integer(dsdt: &[u8], path: &str) -> Option<u64> {
+    Interpreter::new(dsdt).evaluate(path)?.as_integer()
+}
+
+/// Returns the elements of the package at `path` in `dsdt`.
+pub fn find_package(dsdt: &[u8], path: &str) -> Option<Vec<AmlValue>> {
+    match Interpreter::new(dsdt).evaluate(path)? {
+        AmlValue::Package(elements) => Some(elements)
+      , _ => None
+    }
+}
+
+/// Turn a path like `\_SB.PCI0` into its name segments, padding short
+/// segments with underscores as ASL does.
+fn parse_path(path: &str) -> Option<Vec<NameSeg>> {
+    let path = path.trim_left_matches('\\');
+    if path.is_empty() { return None }
+    path.split('.')
+        .map(|seg| {
+            let bytes = seg.as_bytes();
+            if bytes.is_empty() || bytes.len() > 4 { return None }
+            let mut name = [b'_'; 4];
+            name[..bytes.len()].copy_from_slice(bytes);
+            Some(name)
+        })
+        .collect()
+}
+
+/// Returns true if `byte` can start a name.
+#[inline]
+fn is_name_start(byte: u8) -> bool {
+    match byte {
+        b'A' ... b'Z' | b'_' => true
+      , ROOT_CHAR | PARENT_CHAR | DUAL_NAME_PREFIX | MULTI_NAME_PREFIX
+      | NULL_NAME => true
+      , _ => false
+    }
+}
+
+/// Decode the name at `aml[*i]`, advancing `i` past it.
+///
+/// Parent prefixes are skipped, since we don't keep track of scopes.
+fn parse_name(aml: &[u8], i: &mut usize) -> Option<NameString> {
+    let root = aml.get(*i) == Some(&ROOT_CHAR);
+    if root { *i += 1 }
+    while aml.get(*i) == Some(&PARENT_CHAR) { *i += 1 }
+
+    let count = match *aml.get(*i)? {
+        NULL_NAME => { *i += 1; 0 }
+      , DUAL_NAME_PREFIX => { *i += 1; 2 }
+      , MULTI_NAME_PREFIX => {
+            let count = *aml.get(*i + 1)? as usize;
+            *i += 2;
+            count
+        }
+      , _ => 1
+    };
+    let mut segs = Vec::with_capacity(count);
+    for _ in 0 .. count {
+        let seg = aml.get(*i .. *i + 4)?;
+        let valid = seg.iter().enumerate().all(|(n, &c)| match c {
+            b'A' ... b'Z' | b'_' => true
+          , b'0' ... b'9' => n > 0
+          , _ => false
+        });
+        if !valid { return None }
+        segs.push([seg[0], seg[1], seg[2], seg[3]]);
+        *i += 4;
+    }
+    Some(NameString { root: root, segs: segs })
+}
+
+/// Decode the `PkgLength` at `aml[*i]`, advancing `i` past it.
+///
+/// The length returned counts the `PkgLength` itself.
+fn parse_pkg_length(aml: &[u8], i: &mut usize) -> Option<usize> {
+    let lead = *aml.get(*i)?;
+    // the top two bits of the first byte say how many more bytes there
+    // are; if there are any, the first byte only gives the low nybble.
+    let extra = (lead >> 6) as usize;
+    if extra == 0 {
+        *i += 1;
+        return Some((lead & 0x3f) as usize)
+    }
+    let mut len = (lead & 0x0f) as usize;
+    for n in 0 .. extra {
+        len |= (*aml.get(*i + 1 + n)? as usize) << (4 + 8 * n);
+    }
+    *i += 1 + extra;
+    Some(len)
+}
+
+/// Decode the integer constant at `aml[*i]`, advancing `i` past it.
+fn parse_integer(aml: &[u8], i: &mut usize) -> Option<u64> {
+    let (value, len) = match *aml.get(*i)? {
+        ZERO_OP => (0, 1)
+      , ONE_OP => (1, 1)
+      , ONES_OP => (!0, 1)
+      , BYTE_PREFIX => (read_le(aml, *i + 1, 1)?, 2)
+      , WORD_PREFIX => (read_le(aml, *i + 1, 2)?, 3)
+      , DWORD_PREFIX => (read_le(aml, *i + 1, 4)?, 5)
+      , QWORD_PREFIX => (read_le(aml, *i + 1, 8)?, 9)
+      , _ => return None
+    };
+    *i += len;
+    Some(value)
+}
+
+/// Read the `len`-byte little-endian integer at `aml[at]`.
+#[inline]
+fn read_le(aml: &[u8], at: usize, len: usize) -> Option<u64> {
+    let bytes = aml.get(at .. at + len)?;
+    Some(bytes.iter().rev().fold(0, |value, &b| value << 8 | b as u64))
+}
@@ -0,0 +1,285 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! ACPI: finding the firmware's tables, and turning the machine off.
+//!
+//! We only read as much as it takes to enter the S5 ("soft off") sleep
+//! state: the FADT, for the addresses of the PM1 control registers, and the
+//! `\_S5_` object in the DSDT, for the values to write to them. We also
+//! call `\_PIC` to tell the firmware we route interrupts through the APIC,
+//! not the PIC.
+use core::slice;
+use cpu::Port;
+use memory::PAddr;
+use paging::arch::table::NO_EXECUTE;
+use spin::Once;
+
+use mm::map_physical;
+
+use self::aml::AmlValue;
+
+pub mod aml;
+
+/// The signature at the start of the RSDP.
+const RSDP_SIGNATURE: &'static [u8] = b"RSD PTR ";
+/// The number of bytes covered by the ACPI 1.0 RSDP checksum.
+const RSDP_V1_LEN: usize = 20;
+/// The size of the header at the start of every system description table.
+const SDT_HEADER_LEN: usize = 36;
+
+/// Where the BIOS data area keeps the real-mode segment of the EBDA.
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+/// The part of the EBDA the RSDP may be in.
+const EBDA_SEARCH_LEN: usize = 1024;
+/// The BIOS read-only memory area, which the RSDP may also be in.
+const BIOS_AREA_START: u64 = 0xe0000;
+/// The size of the BIOS area.
+const BIOS_AREA_LEN: usize = 0x20000;
+
+/// Offsets of the fields we use in the RSDP.
+mod rsdp {
+    pub const REVISION: usize = 15;
+    pub const RSDT_ADDRESS: usize = 16;
+    pub const XSDT_ADDRESS: usize = 24;
+}
+
+/// Offsets of the fields we use in the FADT.
+mod fadt {
+    pub const DSDT: usize = 40;
+    pub const SMI_CMD: usize = 48;
+    pub const ACPI_ENABLE: usize = 52;
+    pub const PM1A_CNT_BLK: usize = 64;
+    pub const PM1B_CNT_BLK: usize = 68;
+    pub const X_DSDT: usize = 140;
+}
+
+/// PM1 control register: the SCI is enabled, so ACPI is in control of
+/// power management.
+const SCI_EN: u16 = 1 << 0;
+/// PM1 control register: the shift of the sleep type field.
+const SLP_TYP_SHIFT: u16 = 10;
+/// PM1 control register: enter the sleep state in `SLP_TYP`.
+const SLP_EN: u16 = 1 << 13;
+
+/// The argument to `\_PIC` that selects the APIC interrupt model.
+const PIC_MODE_APIC: u64 = 1;
+
+/// How many times to poll for ACPI mode after asking the firmware for it.
+const ACPI_ENABLE_TRIES: usize = 1_000_000;
+
+/// What it takes to turn the machine off.
+#[derive(Copy, Clone, Debug)]
+struct PowerInfo { pm1a_cnt: u16
+                 , /// Zero if there is no PM1b control register
+                   pm1b_cnt: u16
+                 , slp_typ_a: u8
+                 , slp_typ_b: u8
+                 , /// The port to write `acpi_enable` to, to take power
+                   /// management away from the firmware
+                   smi_cmd: u16
+                 , acpi_enable: u8
+                 }
+
+static POWER: Once<PowerInfo> = Once::new();
+
+/// Read the little-endian `u16` at `offset` in an ACPI table.
+#[inline]
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+/// Read the little-endian `u32` at `offset` in an ACPI table.
+#[inline]
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32
+        | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+/// Read the little-endian `u64` at `offset` in an ACPI table.
+#[inline]
+pub fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64
+        | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+/// Returns true if the bytes in `bytes` add up to zero.
+#[inline]
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Map `len` bytes of firmware memory at `addr`, and return them.
+fn map(addr: u64, len: usize) -> Result<&'static [u8], &'static str> {
+    let base = map_physical(PAddr::from(addr), len, NO_EXECUTE)
+        .map_err(|_| "could not map ACPI tables")?;
+    Ok(unsafe { slice::from_raw_parts(base.as_ptr::<u8>(), len) })
+}
+
+/// The size of an ACPI 2.0 RSDP.
+const RSDP_V2_LEN: usize = 36;
+
+/// The RSDP's address, if the firmware told us where it is.
+static FIRMWARE_RSDP: Once<u64> = Once::new();
+
+/// Use the RSDP at `addr`, which the firmware told us about, rather than
+/// searching for one.
+///
+/// UEFI firmware doesn't have to put the RSDP anywhere it can be found by
+/// searching, so this must be called before `init` when booted by UEFI.
+pub fn set_rsdp(addr: PAddr) {
+    FIRMWARE_RSDP.call_once(|| *addr);
+}
+
+/// Look for the RSDP in the first KiB of the EBDA, and then in the BIOS
+/// area, unless the firmware has told us where it is.
+fn find_rsdp() -> Result<&'static [u8], &'static str> {
+    if let Some(&addr) = FIRMWARE_RSDP.try() {
+        let rsdp = map(addr, RSDP_V2_LEN)?;
+        return if rsdp.starts_with(RSDP_SIGNATURE)
+                  && checksum_ok(&rsdp[..RSDP_V1_LEN]) {
+            Ok(rsdp)
+        } else {
+            Err("bad RSDP from firmware")
+        }
+    }
+    let ebda = read_u16(map(EBDA_SEGMENT_PTR, 2)?, 0) as u64 * 16;
+    let areas = [ (ebda, EBDA_SEARCH_LEN)
+                , (BIOS_AREA_START, BIOS_AREA_LEN)
+                ];
+    for &(start, len) in areas.iter() {
+        if start == 0 { continue }
+        let area = map(start, len)?;
+        // the RSDP is always on a 16-byte boundary
+        let found = (0 .. len / 16)
+            .map(|i| &area[i * 16..])
+            .find(|rsdp| rsdp.len() >= RSDP_V1_LEN
+                      && rsdp.starts_with(RSDP_SIGNATURE)
+                      && checksum_ok(&rsdp[..RSDP_V1_LEN]));
+        if let Some(rsdp) = found { return Ok(rsdp) }
+    }
+    Err("no RSDP")
+}
+
+/// Map the system description table at `addr`, and check its checksum.
+fn table(addr: u64) -> Result<&'static [u8], &'static str> {
+    let len = read_u32(map(addr, SDT_HEADER_LEN)?, 4) as usize;
+    if len < SDT_HEADER_LEN { return Err("ACPI table is too short") }
+    let bytes = map(addr, len)?;
+    if !checksum_ok(bytes) { return Err("bad ACPI table checksum") }
+    Ok(bytes)
+}
+
+/// Find the table with `signature` through the XSDT, or through the RSDT
+/// on ACPI 1.0 systems.
+fn find_table(rsdp: &[u8], signature: &[u8])
+             -> Result<&'static [u8], &'static str> {
+    let xsdt = if rsdp[rsdp::REVISION] >= 2 {
+        read_u64(rsdp, rsdp::XSDT_ADDRESS)
+    } else {
+        0
+    };
+    let (root, entry_len) = if xsdt != 0 {
+        (table(xsdt)?, 8)
+    } else {
+        (table(read_u32(rsdp, rsdp::RSDT_ADDRESS) as u64)?, 4)
+    };
+    let entries = (root.len() - SDT_HEADER_LEN) / entry_len;
+    for i in 0 .. entries {
+        let offset = SDT_HEADER_LEN + i * entry_len;
+        let addr = if entry_len == 8 {
+            read_u64(root, offset)
+        } else {
+            read_u32(root, offset) as u64
+        };
+        let sdt = table(addr)?;
+        if &sdt[..4] == signature { return Ok(sdt) }
+    }
+    Err("ACPI table not found")
+}
+
+/// Find the system description table with `signature`, for the drivers
+/// that need one.
+pub fn find_sdt(signature: &[u8]) -> Result<&'static [u8], &'static str> {
+    find_table(find_rsdp()?, signature)
+}
+
+/// Read what we need from the ACPI tables.
+///
+/// # Safety
+/// + This must be called once, after the kernel has been remapped.
+pub unsafe fn init() -> Result<(), &'static str> {
+    let rsdp = find_rsdp()?;
+    let facp = find_table(rsdp, b"FACP")?;
+    if facp.len() < fadt::PM1B_CNT_BLK + 4 {
+        return Err("FADT is too short")
+    }
+    let x_dsdt = if facp.len() >= fadt::X_DSDT + 8 {
+        read_u64(facp, fadt::X_DSDT)
+    } else {
+        0
+    };
+    let dsdt = if x_dsdt != 0 {
+        table(x_dsdt)?
+    } else {
+        table(read_u32(facp, fadt::DSDT) as u64)?
+    };
+    let code = &dsdt[SDT_HEADER_LEN..];
+    let s5 = aml::find_package(code, "\\_S5_")
+        .ok_or("no \\_S5_ object in the DSDT")?;
+    // sleep type values are only three bits, so only the low byte is kept.
+    let (slp_typ_a, slp_typ_b) =
+        match (s5.get(0).and_then(AmlValue::as_integer)
+              , s5.get(1).and_then(AmlValue::as_integer)) {
+            (Some(a), Some(b)) => (a as u8, b as u8)
+          , _ => return Err("\\_S5_ doesn't hold two sleep types")
+        };
+
+    // `\_PIC` is optional, and older firmware doesn't have it at all.
+    let apic_mode = AmlValue::Integer(PIC_MODE_APIC);
+    match aml::Interpreter::new(code).invoke("\\_PIC", &[apic_mode]) {
+        Some(_) => debug!("told the firmware we're in APIC mode")
+      , None => debug!("couldn't call \\_PIC; routing may assume the PIC")
+    }
+
+    POWER.call_once(|| PowerInfo {
+        pm1a_cnt: read_u32(facp, fadt::PM1A_CNT_BLK) as u16
+      , pm1b_cnt: read_u32(facp, fadt::PM1B_CNT_BLK) as u16
+      , slp_typ_a: slp_typ_a
+      , slp_typ_b: slp_typ_b
+      , smi_cmd: read_u32(facp, fadt::SMI_CMD) as u16
+      , acpi_enable: facp[fadt::ACPI_ENABLE]
+    });
+    Ok(())
+}
+
+/// Turn the machine off, by entering the S5 sleep state.
+///
+/// Returns only if that didn't work, with the reason why.
+pub fn shutdown() -> &'static str {
+    let power = match POWER.try() {
+        Some(power) => power
+      , None => return "ACPI power management is not available"
+    };
+    let pm1a_cnt = Port::<u16>::new(power.pm1a_cnt);
+    if pm1a_cnt.read() & SCI_EN == 0 {
+        if power.smi_cmd == 0 || power.acpi_enable == 0 {
+            return "the firmware won't hand over power management"
+        }
+        Port::<u8>::new(power.smi_cmd).write(power.acpi_enable);
+        let enabled = (0 .. ACPI_ENABLE_TRIES)
+            .any(|_| pm1a_cnt.read() & SCI_EN != 0);
+        if !enabled { return "timed out switching to ACPI mode" }
+    }
+
+    pm1a_cnt.write((power.slp_typ_a as u16) << SLP_TYP_SHIFT | SLP_EN);
+    if power.pm1b_cnt != 0 {
+        Port::<u16>::new(power.pm1b_cnt)
+            .write((power.slp_typ_b as u16) << SLP_TYP_SHIFT | SLP_EN);
+    }
+    "the machine is still on after entering S5"
+}
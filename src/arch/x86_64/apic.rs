@@ -0,0 +1,177 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The local APIC.
+//!
+//! Every CPU has its own local APIC, at the same physical address. We only
+//! use it to send inter-processor interrupts for now; device interrupts
+//! still come through the legacy PICs.
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use cpu::msr;
+use memory::{PAddr, Page, PhysicalPage, VirtualPage, PAGE_SIZE};
+use paging::Mapper;
+use paging::arch::ActivePageTable;
+use paging::arch::space::phys_to_virt;
+use paging::arch::table::{NO_EXECUTE, WRITABLE};
+
+use mm::boot::{BootAllocator, BootFrames};
+use super::mtrr;
+
+/// Offsets of the local APIC registers.
+mod reg {
+    pub const ID: usize = 0x20;
+    pub const EOI: usize = 0xb0;
+    pub const SPURIOUS: usize = 0xf0;
+    pub const ICR_LOW: usize = 0x300;
+    pub const ICR_HIGH: usize = 0x310;
+    pub const LVT_PERF: usize = 0x340;
+}
+
+/// `IA32_APIC_BASE`: the local APIC is enabled.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// `IA32_APIC_BASE`: the bits holding the physical base address.
+const APIC_BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Spurious interrupt vector register: the APIC is software enabled.
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+/// The vector spurious interrupts are delivered on.
+const SPURIOUS_VECTOR: u32 = 0xff;
+
+/// Interrupt command register: fixed delivery mode, on the vector in the
+/// low byte.
+const ICR_FIXED: u32 = 0b000 << 8;
+/// Interrupt command register: NMI delivery mode.
+const ICR_NMI: u32 = 0b100 << 8;
+/// Interrupt command register: INIT delivery mode.
+const ICR_INIT: u32 = 0b101 << 8;
+/// Interrupt command register: start-up delivery mode.
+const ICR_STARTUP: u32 = 0b110 << 8;
+/// Interrupt command register: the last IPI hasn't been sent yet.
+const ICR_PENDING: u32 = 1 << 12;
+/// Interrupt command register: assert (rather than de-assert) the level.
+const ICR_ASSERT: u32 = 1 << 14;
+/// Interrupt command register: send to every CPU except this one.
+const ICR_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+/// The virtual address of the local APIC's registers, or 0 if they haven't
+/// been mapped yet.
+static BASE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[inline]
+fn base() -> usize {
+    let base = BASE.load(Ordering::Relaxed);
+    assert!(base != 0, "local APIC is not mapped!");
+    base
+}
+
+#[inline]
+unsafe fn read(reg: usize) -> u32 {
+    ptr::read_volatile((base() + reg) as *const u32)
+}
+
+#[inline]
+unsafe fn write(reg: usize, value: u32) {
+    ptr::write_volatile((base() + reg) as *mut u32, value)
+}
+
+/// Map the local APIC's registers into the physical memory map, uncached,
+/// taking any page table frames needed from `alloc`.
+///
+/// # Safety
+/// + This must be called once, on the bootstrap processor, before any other
+///   function in this module.
+pub unsafe fn map(alloc: &mut BootAllocator) -> Result<(), &'static str> {
+    let paddr = PAddr::from(msr::read(msr::IA32_APIC_BASE) & APIC_BASE_MASK);
+    let vaddr = phys_to_virt(paddr);
+    let page = VirtualPage::containing(vaddr);
+    let mut table = ActivePageTable::new();
+    if !table.is_mapped(&page) {
+        let flags = mtrr::mmio_flags(paddr, PAGE_SIZE);
+        table.map( page, PhysicalPage::containing(paddr)
+                 , WRITABLE | flags | NO_EXECUTE
+                 , &mut BootFrames(alloc) )
+             .map_err(|_| "could not map the local APIC")?;
+    }
+    BASE.store(vaddr.as_usize(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Enable the current CPU's local APIC.
+///
+/// # Safety
+/// + [`map`](fn.map.html) must have been called.
+pub unsafe fn init() {
+    let apic_base = msr::read(msr::IA32_APIC_BASE);
+    msr::write(msr::IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+    write(reg::SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR);
+}
+
+/// Returns the current CPU's local APIC ID.
+#[inline]
+pub fn id() -> u8 {
+    (unsafe { read(reg::ID) } >> 24) as u8
+}
+
+/// Signal the end of an interrupt delivered by the local APIC.
+#[inline]
+pub fn eoi() {
+    unsafe { write(reg::EOI, 0) }
+}
+
+/// Deliver performance counter overflow interrupts on `vector`.
+///
+/// The APIC masks the interrupt each time one is delivered, so the handler
+/// has to call this again to get the next one.
+#[inline]
+pub fn set_perf_vector(vector: u8) {
+    unsafe { write(reg::LVT_PERF, vector as u32) }
+}
+
+/// Send an IPI described by `command` to the CPUs selected by its
+/// shorthand, and wait until the APIC has sent it.
+unsafe fn send_ipi(command: u32) {
+    write(reg::ICR_HIGH, 0);
+    write(reg::ICR_LOW, command);
+    while read(reg::ICR_LOW) & ICR_PENDING != 0 {}
+}
+
+/// Send an interrupt on `vector` to every other CPU.
+///
+/// # Safety
+/// + [`map`](fn.map.html) must have been called, and every other CPU must
+///   have a handler for `vector`.
+pub unsafe fn send_fixed_all(vector: u8) {
+    send_ipi(ICR_ALL_BUT_SELF | ICR_ASSERT | ICR_FIXED | vector as u32)
+}
+
+/// Send an INIT IPI to every other CPU, resetting them.
+///
+/// # Safety
+/// + Anything the other CPUs were doing is lost.
+pub unsafe fn send_init_all() {
+    send_ipi(ICR_ALL_BUT_SELF | ICR_ASSERT | ICR_INIT)
+}
+
+/// Send a start-up IPI to every other CPU, which starts any that are waiting
+/// after an INIT in real mode at the address `page << 12`.
+///
+/// # Safety
+/// + There must be code for the other CPUs to run at that address.
+pub unsafe fn send_startup_all(page: u8) {
+    send_ipi(ICR_ALL_BUT_SELF | ICR_ASSERT | ICR_STARTUP | page as u32)
+}
+
+/// Send a non-maskable interrupt to every other CPU.
+///
+/// # Safety
+/// + The other CPUs stop whatever they were doing to run the NMI handler,
+///   even with interrupts disabled.
+pub unsafe fn send_nmi_all() {
+    send_ipi(ICR_ALL_BUT_SELF | ICR_ASSERT | ICR_NMI)
+}
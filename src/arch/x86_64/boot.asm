@@ -31,8 +31,9 @@ start:
     ; 0. Move the stack pointer to the top of the stack. ---------------------
     mov     esp, stack_top
 
-    ; 1. Move Multiboot info pointer to edi ----------------------------------
-    mov     edi, ebx
+    ; 1. Move the boot magic to edi, and the boot info pointer to esi -------
+    mov     edi, eax
+    mov     esi, ebx
 
     ; 2. Make sure that the system supports SOS. -----------------------------
     call    is_multiboot ; check that multiboot is supported
@@ -58,9 +59,13 @@ start:
     jmp     gdt64.code:arch_init
 
 ; == Tests whether or not multiboot is enabled ==============================
+; our UEFI stub (magic 0xdeadbeef) comes in this way too.
 is_multiboot:
     cmp     eax, 0x36d76289
+    je      .ok
+    cmp     eax, 0xdeadbeef
     jne     .no_multiboot
+.ok:
     ret
 .no_multiboot:
     mov     al, "0"
@@ -209,6 +214,12 @@ export gdt64
     dq (1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53) ; code segment
 .data: equ $ - gdt64 ; new
     dq (1<<44) | (1<<47) | (1<<41) ; data segment
+; the user segments must be laid out data-then-code, directly after the
+; kernel segments, for `sysret` to find them.
+.user_data: equ $ - gdt64
+    dq (1<<44) | (1<<47) | (1<<41) | (3<<45) ; user data segment
+.user_code: equ $ - gdt64
+    dq (1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53) | (3<<45) ; user code segment
 .ptr:
     dw $ - gdt64 - 1
     dq gdt64
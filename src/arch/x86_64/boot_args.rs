@@ -0,0 +1,298 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! What the bootloader handed us.
+//!
+//! The entry point gets a magic number in `eax` and a pointer in `ebx`,
+//! which `start` passes on to `arch_init`. The magic number says which
+//! bootloader we came from, and so what the pointer points to:
+//!
+//! + `0x36d76289`: a Multiboot 2 loader such as GRUB, and the Multiboot 2
+//!   info structure.
+//! + `0xdeadbeef`: our UEFI stub, which has exited boot services, and a
+//!   [`UefiHandoff`](struct.UefiHandoff.html).
+//! + anything else: a Linux boot protocol loader, if the pointer is to a
+//!   `boot_params` ("zero page") whose setup header has the `HdrS` magic.
+//!
+//! Each kind of boot info has its memory map in a different format, so
+//! [`BootArgs::memory_map`](enum.BootArgs.html#method.memory_map) turns
+//! them all into `params::mem::Area`s.
+use core::{fmt, ptr, slice};
+use memory::{PAddr, PAGE_SIZE};
+use params::mem;
+
+use super::multiboot2::{self, MemAreas};
+
+/// The magic number a Multiboot 2 loader leaves in `eax`.
+pub const MULTIBOOT2_MAGIC: u32 = 0x36d7_6289;
+/// The magic number our UEFI stub leaves in `eax`.
+pub const UEFI_STUB_MAGIC: u32 = 0xdead_beef;
+/// The magic number in a Linux setup header's `header` field: `"HdrS"`.
+pub const LINUX_HEADER_MAGIC: u32 = 0x5372_6448;
+
+/// Where the setup header starts in `boot_params`.
+const SETUP_HEADER_OFFSET: usize = 0x1f1;
+/// Where the number of E820 entries is in `boot_params`.
+const E820_ENTRIES_OFFSET: usize = 0x1e8;
+/// Where the E820 table starts in `boot_params`.
+const E820_TABLE_OFFSET: usize = 0x2d0;
+/// The most entries the E820 table in `boot_params` can hold.
+const E820_MAX_ENTRIES: usize = 128;
+/// The E820 type of usable RAM.
+const E820_RAM: u32 = 1;
+
+/// The header at the start of every UEFI table.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct EfiTableHeader { pub signature: u64
+                          , pub revision: u32
+                          , pub header_size: u32
+                          , pub crc32: u32
+                          , pub reserved: u32
+                          }
+
+/// The start of the UEFI system table.
+///
+/// Only the fields before the (by now useless) console handles are
+/// described; the rest of the table is still there in memory.
+#[repr(C)]
+#[derive(Debug)]
+pub struct EfiSystemTable { pub hdr: EfiTableHeader
+                          , /// A null-terminated UCS-2 string
+                            pub firmware_vendor: *const u16
+                          , pub firmware_revision: u32
+                          }
+
+/// A UEFI memory map entry.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct EfiMemoryDescriptor { pub ty: u32
+                               , _pad: u32
+                               , pub physical_start: u64
+                               , pub virtual_start: u64
+                               , pub number_of_pages: u64
+                               , pub attribute: u64
+                               }
+
+impl EfiMemoryDescriptor {
+    /// Returns true if the memory is free for the kernel to use, now that
+    /// boot services have exited.
+    pub fn is_usable(&self) -> bool {
+        match self.ty {
+            // loader code and data, boot services code and data, and
+            // conventional memory.
+            1 | 2 | 3 | 4 | 7 => true
+          , _ => false
+        }
+    }
+}
+
+/// What our UEFI stub passes to the kernel.
+///
+/// The stub has to get the memory map itself, since it's only available
+/// from boot services, which are gone by the time the kernel runs.
+#[repr(C)]
+#[derive(Debug)]
+pub struct UefiHandoff { pub system_table: *const EfiSystemTable
+                       , /// The memory map, as returned by
+                         /// `GetMemoryMap`
+                         pub memory_map: *const u8
+                       , /// The size of the memory map, in bytes
+                         pub memory_map_size: u64
+                       , /// The size of each descriptor, which may be
+                         /// more than `size_of::<EfiMemoryDescriptor>()`
+                         pub descriptor_size: u64
+                       }
+
+/// The start of the Linux boot protocol's setup header.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct SetupHeader { pub setup_sects: u8
+                       , pub root_flags: u16
+                       , pub syssize: u32
+                       , pub ram_size: u16
+                       , pub vid_mode: u16
+                       , pub root_dev: u16
+                       , pub boot_flag: u16
+                       , pub jump: u16
+                       , /// `LINUX_HEADER_MAGIC`
+                         pub header: u32
+                       , /// The boot protocol version
+                         pub version: u16
+                       }
+
+/// An entry in the E820 table in `boot_params`.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct E820Entry { pub addr: u64
+                     , pub size: u64
+                     , pub ty: u32
+                     }
+
+/// What the bootloader handed us, and how to find its boot info.
+#[derive(Copy, Clone, Debug)]
+pub enum BootArgs { /// Booted by a Multiboot 2 loader
+                    Multiboot2 { magic: u32, info_ptr: *const u8 }
+                  , /// Booted by our UEFI stub
+                    Uefi { system_table: *const EfiSystemTable
+                         , handoff: *const UefiHandoff
+                         }
+                  , /// Booted with the Linux boot protocol
+                    DirectLinuxBoot { setup_header: *const SetupHeader }
+                  }
+
+impl BootArgs {
+    /// Work out which bootloader we came from, from the magic number and
+    /// pointer it passed to the entry point.
+    ///
+    /// Returns `None` if it's none that we know.
+    ///
+    /// # Safety
+    /// + If `magic` isn't a magic number we know, `ptr` is read as a
+    ///   `boot_params`, so it must be null or point to readable memory.
+    pub unsafe fn from_entry(magic: u32, ptr: *const u8) -> Option<Self> {
+        match magic {
+            MULTIBOOT2_MAGIC =>
+                Some(BootArgs::Multiboot2 { magic: magic, info_ptr: ptr })
+          , UEFI_STUB_MAGIC if !ptr.is_null() => {
+                let handoff = ptr as *const UefiHandoff;
+                Some(BootArgs::Uefi { system_table: (*handoff).system_table
+                                    , handoff: handoff
+                                    })
+            }
+          , _ if !ptr.is_null() => {
+                let header
+                    = ptr.offset(SETUP_HEADER_OFFSET as isize)
+                         as *const SetupHeader;
+                if (*header).header == LINUX_HEADER_MAGIC {
+                    Some(BootArgs::DirectLinuxBoot { setup_header: header })
+                } else {
+                    None
+                }
+            }
+          , _ => None
+        }
+    }
+
+    /// Returns the Multiboot 2 info, if we were booted by a Multiboot 2
+    /// loader.
+    pub fn multiboot_info(&self)
+                         -> Result<&'static multiboot2::Info, &'static str> {
+        match *self {
+            BootArgs::Multiboot2 { info_ptr, .. } => unsafe {
+                multiboot2::Info::from(PAddr::from(info_ptr as u64))
+            }
+          , _ => Err("not booted by a Multiboot 2 loader")
+        }
+    }
+
+    /// Returns an iterator over the memory map the bootloader gave us.
+    pub fn memory_map(&self) -> MemoryMapIterator {
+        match *self {
+            BootArgs::Multiboot2 { .. } =>
+                MemoryMapIterator::Multiboot2(
+                    self.multiboot_info().ok()
+                        .and_then(|info| info.mem_map())
+                        .map(|tag| tag.areas()))
+          , BootArgs::Uefi { handoff, .. } => unsafe {
+                let handoff = &*handoff;
+                let map = slice::from_raw_parts( handoff.memory_map
+                                               , handoff.memory_map_size
+                                                   as usize);
+                MemoryMapIterator::Uefi {
+                    descriptors: map
+                  , descriptor_size: handoff.descriptor_size as usize
+                }
+            }
+          , BootArgs::DirectLinuxBoot { setup_header } => unsafe {
+                let boot_params
+                    = (setup_header as *const u8)
+                        .offset(-(SETUP_HEADER_OFFSET as isize));
+                let count = *boot_params.offset(E820_ENTRIES_OFFSET as isize);
+                let table = boot_params.offset(E820_TABLE_OFFSET as isize)
+                            as *const E820Entry;
+                let count = if (count as usize) < E820_MAX_ENTRIES {
+                    count as usize
+                } else {
+                    E820_MAX_ENTRIES
+                };
+                MemoryMapIterator::E820(slice::from_raw_parts(table, count)
+                                             .iter())
+            }
+        }
+    }
+}
+
+impl fmt::Display for BootArgs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BootArgs::Multiboot2 { info_ptr, .. } =>
+                write!(f, "Multiboot 2, info at {:p}", info_ptr)
+          , BootArgs::Uefi { system_table, .. } =>
+                write!(f, "UEFI, system table at {:p}", system_table)
+          , BootArgs::DirectLinuxBoot { setup_header } =>
+                write!(f, "Linux boot protocol, setup header at {:p}"
+                      , setup_header)
+        }
+    }
+}
+
+/// An iterator over a bootloader's memory map, in any of its formats.
+pub enum MemoryMapIterator { /// A Multiboot 2 memory map tag, if there
+                             /// was one
+                             Multiboot2(Option<MemAreas>)
+                           , /// UEFI memory descriptors, each
+                             /// `descriptor_size` bytes long
+                             Uefi { descriptors: &'static [u8]
+                                  , descriptor_size: usize
+                                  }
+                           , /// The E820 table from `boot_params`
+                             E820(slice::Iter<'static, E820Entry>)
+                           }
+
+impl Iterator for MemoryMapIterator {
+    type Item = mem::Area;
+
+    /// Empty areas are skipped.
+    fn next(&mut self) -> Option<mem::Area> {
+        loop {
+            // the start, size and usability of the next area
+            let (start, size, is_usable) = match *self {
+                MemoryMapIterator::Multiboot2(ref mut areas) =>
+                    return areas.as_mut().and_then(|areas| areas.next())
+                                .map(|area| area.into())
+              , MemoryMapIterator::Uefi { ref mut descriptors
+                                        , descriptor_size } => {
+                    let rest: &'static [u8] = *descriptors;
+                    if descriptor_size == 0 || rest.len() < descriptor_size {
+                        return None
+                    }
+                    let desc: EfiMemoryDescriptor = unsafe {
+                        ptr::read_unaligned(rest.as_ptr()
+                                            as *const EfiMemoryDescriptor)
+                    };
+                    *descriptors = &rest[descriptor_size..];
+                    ( desc.physical_start, desc.number_of_pages * PAGE_SIZE
+                    , desc.is_usable())
+                }
+              , MemoryMapIterator::E820(ref mut entries) =>
+                    match entries.next() {
+                        Some(entry) =>
+                            (entry.addr, entry.size, entry.ty == E820_RAM)
+                      , None => return None
+                    }
+            };
+            if size == 0 { continue }
+            let start = PAddr::from(start);
+            return Some(mem::Area { start_addr: start
+                                  , end_addr: start + (size - 1)
+                                  , is_usable: is_usable
+                                  })
+        }
+    }
+}
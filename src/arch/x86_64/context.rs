@@ -0,0 +1,136 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Kernel context switching.
+//!
+//! A task that isn't running is suspended inside `switch_context`, with its
+//! callee-saved registers pushed on its kernel stack. All we need to keep
+//! around to resume it is that stack pointer.
+use core::mem;
+
+use super::syscall::SyscallFrame;
+
+/// The saved kernel context of a task that isn't running.
+#[repr(C)]
+#[derive(Clone, Debug, Default)]
+pub struct Context { /// The task's saved kernel stack pointer
+                     pub rsp: u64
+                   }
+
+/// The registers pushed by `switch_context`, in stack order.
+#[repr(C)]
+#[derive(Clone, Debug, Default)]
+struct SwitchFrame { r15: u64
+                   , r14: u64
+                   , r13: u64
+                   , r12: u64
+                   , rbx: u64
+                   , rbp: u64
+                   , rflags: u64
+                   , /// The address `switch_context` returns to
+                     rip: u64
+                   }
+
+global_asm!("
+    .intel_syntax noprefix
+    .global switch_context
+switch_context:
+    pushfq
+    push    rbp
+    push    rbx
+    push    r12
+    push    r13
+    push    r14
+    push    r15
+    mov     [rdi], rsp
+    mov     rsp, rsi
+    pop     r15
+    pop     r14
+    pop     r13
+    pop     r12
+    pop     rbx
+    pop     rbp
+    popfq
+    ret
+
+    // a forked child starts here the first time it is switched to, with
+    // its copy of the parent's `SyscallFrame` at the top of the stack.
+    .global fork_child_return
+fork_child_return:
+    xor     eax, eax
+    jmp     syscall_return
+
+    // a kernel thread starts here the first time it is switched to, with
+    // its entry point in `r12`. the entry point never returns.
+    .global kernel_thread_start
+kernel_thread_start:
+    call    r12
+    ud2
+    .att_syntax
+");
+
+extern {
+    fn switch_context(prev_rsp: *mut u64, next_rsp: u64);
+    fn fork_child_return();
+    fn kernel_thread_start();
+}
+
+impl Context {
+    /// Build the initial context for a forked child.
+    ///
+    /// A copy of `frame` is placed at the top of the kernel stack ending at
+    /// `stack_top`, so that when the child is first switched to, it returns
+    /// to user mode with the parent's registers and a return value of 0.
+    ///
+    /// # Safety
+    /// + `stack_top` must be the 16-byte aligned top of a kernel stack that
+    ///   nothing else is using.
+    pub unsafe fn fork_child(stack_top: usize, frame: &SyscallFrame) -> Self {
+        let frame_addr = stack_top - mem::size_of::<SyscallFrame>();
+        *(frame_addr as *mut SyscallFrame) = frame.clone();
+
+        let switch_addr = frame_addr - mem::size_of::<SwitchFrame>();
+        *(switch_addr as *mut SwitchFrame)
+            = SwitchFrame { rip: fork_child_return as u64
+                          , ..Default::default()
+                          };
+        Context { rsp: switch_addr as u64 }
+    }
+
+    /// Build the initial context for a kernel thread, which calls `entry`
+    /// on the kernel stack ending at `stack_top` when it is first switched
+    /// to.
+    ///
+    /// # Safety
+    /// + `stack_top` must be the 16-byte aligned top of a kernel stack that
+    ///   nothing else is using.
+    pub unsafe fn kernel_thread( stack_top: usize
+                               , entry: extern "C" fn() -> !) -> Self {
+        let switch_addr = stack_top - mem::size_of::<SwitchFrame>();
+        *(switch_addr as *mut SwitchFrame)
+            = SwitchFrame { r12: entry as u64
+                          , rip: kernel_thread_start as u64
+                          , ..Default::default()
+                          };
+        Context { rsp: switch_addr as u64 }
+    }
+
+    /// Save the current context in `self` and resume `next`.
+    ///
+    /// Returns when something switches back to `self`.
+    ///
+    /// # Safety
+    /// + `next` must have been saved by `switch_to` or built by
+    ///   [`fork_child`](#method.fork_child) or
+    ///   [`kernel_thread`](#method.kernel_thread), and not resumed since.
+    /// + Interrupts should be disabled.
+    #[inline]
+    pub unsafe fn switch_to(&mut self, next: &Context) {
+        switch_context(&mut self.rsp, next.rsp)
+    }
+}
@@ -0,0 +1,179 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Wrappers for single CPU instructions.
+//!
+//! Kernel code that needs one of these calls the wrapper here, rather than
+//! writing its own `asm!`, so that the inline assembly is all in one place.
+//! Code whose assembly is the whole point of it (the boot trampoline,
+//! context switches, saving FPU state, and so on) keeps it where it is.
+//!
+//! `cpuid` and `rdtsc` are already wrapped by the `cpu` crate, so those just
+//! call the `cpu` crate's versions.
+pub use cpu::cpuid::CpuidResult;
+use cpu::flags;
+
+/// Stop the CPU until the next interrupt.
+#[inline(always)]
+pub unsafe fn hlt() {
+    asm!("hlt" :::: "volatile")
+}
+
+/// Enable interrupts and stop the CPU until the next one.
+///
+/// `sti` doesn't take effect until after the instruction following it, so
+/// an interrupt that arrives in between still wakes the `hlt`. Calling
+/// [`sti`] and then [`hlt`] has no such guarantee.
+///
+/// [`sti`]: fn.sti.html
+/// [`hlt`]: fn.hlt.html
+#[inline(always)]
+pub unsafe fn sti_hlt() {
+    asm!("sti; hlt" :::: "volatile")
+}
+
+/// Do nothing for one instruction.
+#[inline(always)]
+pub unsafe fn nop() {
+    asm!("nop" :::: "volatile")
+}
+
+/// Enable interrupts.
+#[inline(always)]
+pub unsafe fn sti() {
+    asm!("sti" :::: "volatile")
+}
+
+/// Disable interrupts.
+#[inline(always)]
+pub unsafe fn cli() {
+    asm!("cli" :::: "volatile")
+}
+
+/// Trigger a breakpoint exception.
+#[inline(always)]
+pub unsafe fn int3() {
+    asm!("int3" :::: "volatile")
+}
+
+/// Returns the current value of the timestamp counter.
+#[inline(always)]
+pub unsafe fn rdtsc() -> u64 {
+    ::cpu::timer::timestamp::rtdsc()
+}
+
+/// Execute `RDRAND`, returning `None` if the CPU had no random number
+/// ready.
+#[inline(always)]
+pub unsafe fn rdrand() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    asm!( "rdrand $0
+           setc $1"
+        : "=r"(value), "=r"(ok)
+        ::: "intel", "volatile");
+    if ok != 0 { Some(value) } else { None }
+}
+
+/// Execute `RDSEED`, returning `None` if the CPU had no seed ready.
+#[inline(always)]
+pub unsafe fn rdseed() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    asm!( "rdseed $0
+           setc $1"
+        : "=r"(value), "=r"(ok)
+        ::: "intel", "volatile");
+    if ok != 0 { Some(value) } else { None }
+}
+
+/// Execute `RDPID`, returning `IA32_TSC_AUX`.
+#[inline(always)]
+pub unsafe fn rdpid() -> u64 {
+    let id: u64;
+    // `rdpid rax`, which the assembler doesn't know yet.
+    asm!(".byte 0xf3, 0x0f, 0xc7, 0xf8" : "={rax}"(id) ::: "volatile");
+    id
+}
+
+/// Tell the CPU it's in a spin loop, so it can back off.
+#[inline(always)]
+pub unsafe fn pause() {
+    asm!("pause" :::: "volatile")
+}
+
+/// Wait for all earlier loads to complete.
+#[inline(always)]
+pub unsafe fn lfence() {
+    asm!("lfence" ::: "memory" : "volatile")
+}
+
+/// Wait for all earlier stores to complete.
+#[inline(always)]
+pub unsafe fn sfence() {
+    asm!("sfence" ::: "memory" : "volatile")
+}
+
+/// Wait for all earlier loads and stores to complete.
+#[inline(always)]
+pub unsafe fn mfence() {
+    asm!("mfence" ::: "memory" : "volatile")
+}
+
+/// Write the cache line containing `addr` back to memory, and invalidate
+/// it, for devices that don't snoop the CPU's caches.
+#[inline(always)]
+pub unsafe fn clflush(addr: *const u8) {
+    asm!("clflush [$0]" :: "r"(addr) : "memory" : "intel", "volatile")
+}
+
+/// Execute `CPUID` with `%eax = leaf` and `%ecx = subleaf`.
+#[inline(always)]
+pub unsafe fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    ::cpu::cpuid::cpuid(leaf, subleaf)
+}
+
+/// Allow supervisor-mode access to user pages, when SMAP is enabled.
+#[inline(always)]
+pub unsafe fn stac() {
+    asm!("stac" :::: "volatile")
+}
+
+/// Forbid supervisor-mode access to user pages, when SMAP is enabled.
+#[inline(always)]
+pub unsafe fn clac() {
+    asm!("clac" :::: "volatile")
+}
+
+/// Returns the current stack pointer.
+#[inline(always)]
+pub unsafe fn read_rsp() -> usize {
+    let rsp: usize;
+    asm!("mov $0, rsp" : "=r"(rsp) ::: "intel");
+    rsp
+}
+
+/// Returns the current frame pointer.
+#[inline(always)]
+pub unsafe fn read_rbp() -> usize {
+    let rbp: usize;
+    asm!("mov $0, rbp" : "=r"(rbp) ::: "intel");
+    rbp
+}
+
+/// Call `f` with interrupts disabled, so that an interrupt handler can't
+/// spin on a lock held by the code it interrupted. Interrupts are enabled
+/// again afterwards if they were enabled before.
+pub fn without_interrupts<F, R>(f: F) -> R
+where F: FnOnce() -> R {
+    let enabled = flags::read().contains(flags::IF);
+    unsafe { cli() }
+    let result = f();
+    if enabled { unsafe { sti() } }
+    result
+}
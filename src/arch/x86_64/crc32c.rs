@@ -0,0 +1,173 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! CRC32C (Castagnoli) checksums.
+//!
+//! CPUs with SSE4.2 have a `crc32` instruction that computes exactly this
+//! checksum, up to eight bytes at a time. Without it, we fall back to the
+//! usual byte-at-a-time table lookup.
+//!
+//! [`crc32c_update`] works on the raw CRC register: a whole checksum starts
+//! it at `!0` and inverts the result, which [`crc32c`] and [`Crc32cHasher`]
+//! do for you.
+//!
+//! [`crc32c_update`]: fn.crc32c_update.html
+//! [`crc32c`]: fn.crc32c.html
+//! [`Crc32cHasher`]: struct.Crc32cHasher.html
+use core::hash::Hasher;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use cpu::cpuid::{self, cpuid};
+
+/// The CRC32C polynomial, bit-reversed.
+const POLYNOMIAL: u32 = 0x82f6_3b78;
+
+static USE_SSE42: AtomicBool = ATOMIC_BOOL_INIT;
+
+lazy_static! {
+    /// The CRC of each byte value, for the software fallback.
+    static ref TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        for (byte, entry) in table.iter_mut().enumerate() {
+            *entry = (0 .. 8).fold(byte as u32, |crc, _| {
+                if crc & 1 != 0 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 }
+            });
+        }
+        table
+    };
+}
+
+/// Use the `crc32` instruction, if the CPU has SSE4.2.
+///
+/// Returns true if it's being used.
+///
+/// # Safety
+/// + This must be called once, on the bootstrap processor.
+pub unsafe fn init() -> bool {
+    let features = cpuid(cpuid::LEAF_FEATURES, 0);
+    let sse42 = features.ecx & cpuid::ECX_SSE4_2 != 0;
+    USE_SSE42.store(sse42, Ordering::Relaxed);
+    sse42
+}
+
+/// Returns true if checksums are computed with the `crc32` instruction.
+#[inline]
+pub fn sse42_enabled() -> bool { USE_SSE42.load(Ordering::Relaxed) }
+
+#[inline]
+unsafe fn crc32_u64(crc: u32, value: u64) -> u32 {
+    let result: u64;
+    asm!( "crc32 $0, $1"
+        : "=r"(result)
+        : "r"(value), "0"(crc as u64)
+        :: "intel" );
+    result as u32
+}
+
+#[inline]
+unsafe fn crc32_u32(crc: u32, value: u32) -> u32 {
+    let result: u32;
+    asm!( "crc32 $0, $1"
+        : "=r"(result)
+        : "r"(value), "0"(crc)
+        :: "intel" );
+    result
+}
+
+#[inline]
+unsafe fn crc32_u16(crc: u32, value: u16) -> u32 {
+    let result: u32;
+    asm!( "crc32 $0, $1"
+        : "=r"(result)
+        : "r"(value), "0"(crc)
+        :: "intel" );
+    result
+}
+
+#[inline]
+unsafe fn crc32_u8(crc: u32, value: u8) -> u32 {
+    let result: u32;
+    asm!( "crc32 $0, $1"
+        : "=r"(result)
+        : "r"(value), "0"(crc)
+        :: "intel" );
+    result
+}
+
+/// Read a `T` from the start of `bytes`, which may not be aligned for it.
+#[inline]
+unsafe fn read<T>(bytes: &[u8]) -> T {
+    ptr::read_unaligned(bytes.as_ptr() as *const T)
+}
+
+/// Feed `data` through the `crc32` instruction: eight bytes at a time, then
+/// four, two and one for whatever is left.
+///
+/// # Safety
+/// + The CPU must have SSE4.2.
+unsafe fn update_sse42(mut crc: u32, data: &[u8]) -> u32 {
+    let (words, mut tail) = data.split_at(data.len() & !7);
+    for word in words.chunks(8) {
+        crc = crc32_u64(crc, read(word));
+    }
+    if tail.len() >= 4 {
+        crc = crc32_u32(crc, read(tail));
+        tail = &tail[4..];
+    }
+    if tail.len() >= 2 {
+        crc = crc32_u16(crc, read(tail));
+        tail = &tail[2..];
+    }
+    if let Some(&byte) = tail.first() {
+        crc = crc32_u8(crc, byte);
+    }
+    crc
+}
+
+/// Feed `data` through the lookup table.
+fn update_table(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Update the CRC register `crc` with `data`.
+#[inline]
+pub fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    if sse42_enabled() { unsafe { update_sse42(crc, data) } }
+    else { update_table(crc, data) }
+}
+
+/// Returns the CRC32C checksum of `data`.
+#[inline]
+pub fn crc32c(data: &[u8]) -> u32 { !crc32c_update(!0, data) }
+
+/// Computes a CRC32C checksum over everything written to it.
+#[derive(Copy, Clone, Debug)]
+pub struct Crc32cHasher(u32);
+
+impl Crc32cHasher {
+    /// Returns a hasher that hasn't seen any data yet.
+    #[inline]
+    pub fn new() -> Self { Crc32cHasher(!0) }
+}
+
+impl Default for Crc32cHasher {
+    #[inline] fn default() -> Self { Self::new() }
+}
+
+impl Hasher for Crc32cHasher {
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        self.0 = crc32c_update(self.0, bytes);
+    }
+
+    /// Returns the checksum of the data written so far, in the low 32 bits.
+    #[inline]
+    fn finish(&self) -> u64 { !self.0 as u64 }
+}
@@ -61,6 +61,11 @@ lazy_static! {
 
     pub static ref COM4: Mutex<Serial>
         = Mutex::new(Serial(bda::ports::com4().map(SerialPort::new)));
+
+    /// `COM1` again, behind a lock of its own, for CPUs that a panic is
+    /// halting. They may have been stopped while holding `COM1`'s lock.
+    pub static ref PANIC_SERIAL: Mutex<Serial>
+        = Mutex::new(Serial(bda::ports::com1().map(SerialPort::new)));
 }
 
 
@@ -72,7 +77,9 @@ pub struct SerialPort { data_port: Port<u8>
 
 impl SerialPort {
 
-    fn new(port: u16) -> SerialPort {
+    /// Initialize the serial port at I/O port `port`, and return a handle
+    /// on it.
+    pub fn new(port: u16) -> SerialPort {
          // Disable all interrupts
         Port::<u8>::new(port + 1).write(0x00);
         // Enable DLAB (set baud rate divisor)
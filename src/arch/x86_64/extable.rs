@@ -0,0 +1,219 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The exception table, and copying to and from user memory.
+//!
+//! Some kernel instructions are expected to fault: the ones that touch user
+//! memory, which may not be mapped however carefully it was checked. Each
+//! such instruction has an entry in the `__ex_table` section giving the
+//! address to resume at instead, and the page fault handler looks the
+//! faulting `%rip` up there before giving up.
+//!
+//! Where the CPU supports them, SMEP and SMAP stop the kernel from running
+//! or touching user memory by accident: with SMAP on, user pages are only
+//! accessible between a `stac` and a `clac`, and any other kernel access
+//! to them is a page fault.
+use core::slice;
+use cpu::control_regs::cr4;
+use cpu::cpuid::{self, cpuid};
+
+use super::{fpu, memops};
+use super::cpu::{clac, stac};
+
+/// An entry in the exception table.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ExceptionTableEntry { /// The first address the entry covers
+                                 pub start: u64
+                               , /// One past the last address it covers
+                                 pub end: u64
+                               , /// Where to resume after a fault in
+                                 /// `[start, end)`
+                                 pub fixup: u64
+                               }
+
+// `copy_user_bytes(dst, src, len)` copies `len` bytes from `src` to `dst`,
+// and returns the number of bytes it *didn't* copy. `rep movsb` leaves the
+// count of bytes remaining in `%rcx` when it faults, so the fixup returns
+// that.
+global_asm!("
+    .intel_syntax noprefix
+    .global copy_user_bytes
+copy_user_bytes:
+    mov     rcx, rdx
+copy_user_bytes_insn:
+    rep movsb
+copy_user_bytes_insn_end:
+    xor     eax, eax
+    ret
+copy_user_bytes_fixup:
+    mov     rax, rcx
+    ret
+
+    .pushsection __ex_table, \"a\"
+    .balign 8
+    .quad   copy_user_bytes_insn, copy_user_bytes_insn_end
+    .quad   copy_user_bytes_fixup
+    .popsection
+    .att_syntax
+");
+
+// `copy_user_avx2` is `copy_user_bytes` with the middle of the copy done
+// 32 bytes at a time through `%ymm0`, which it saves and restores. The head
+// of the copy brings `dst` up to a 32-byte boundary, so no store in the
+// middle can straddle two pages; a fault there leaves the chunk it faulted
+// on, and everything after it, uncopied. Each of the three parts has its
+// own fixup, since they keep the count of bytes remaining differently.
+global_asm!("
+    .intel_syntax noprefix
+    .global copy_user_avx2
+copy_user_avx2:
+    sub     rsp, 32
+    vmovdqu [rsp], ymm0
+    mov     rcx, rdi
+    neg     rcx
+    and     rcx, 31
+    cmp     rcx, rdx
+    cmova   rcx, rdx
+    sub     rdx, rcx
+copy_user_avx2_head:
+    rep movsb
+copy_user_avx2_head_end:
+    mov     rcx, rdx
+    and     rcx, 31
+    and     rdx, -32
+    jz      copy_user_avx2_tail
+copy_user_avx2_chunks:
+    vmovdqu ymm0, [rsi]
+    vmovdqu [rdi], ymm0
+    add     rsi, 32
+    add     rdi, 32
+    sub     rdx, 32
+    jnz     copy_user_avx2_chunks
+copy_user_avx2_chunks_end:
+copy_user_avx2_tail:
+    rep movsb
+copy_user_avx2_tail_end:
+    xor     eax, eax
+copy_user_avx2_out:
+    vmovdqu ymm0, [rsp]
+    add     rsp, 32
+    ret
+copy_user_avx2_head_fixup:
+    lea     rax, [rcx + rdx]
+    jmp     copy_user_avx2_out
+copy_user_avx2_chunks_fixup:
+    lea     rax, [rdx + rcx]
+    jmp     copy_user_avx2_out
+copy_user_avx2_tail_fixup:
+    mov     rax, rcx
+    jmp     copy_user_avx2_out
+
+    .pushsection __ex_table, \"a\"
+    .balign 8
+    .quad   copy_user_avx2_head, copy_user_avx2_head_end
+    .quad   copy_user_avx2_head_fixup
+    .quad   copy_user_avx2_chunks, copy_user_avx2_chunks_end
+    .quad   copy_user_avx2_chunks_fixup
+    .quad   copy_user_avx2_tail, copy_user_avx2_tail_end
+    .quad   copy_user_avx2_tail_fixup
+    .popsection
+    .att_syntax
+");
+
+extern {
+    fn copy_user_avx2(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn copy_user_bytes(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static __ex_table_start: ExceptionTableEntry;
+    static __ex_table_end: ExceptionTableEntry;
+}
+
+/// Returns every entry in the exception table.
+fn entries() -> &'static [ExceptionTableEntry] {
+    unsafe {
+        let start = &__ex_table_start as *const ExceptionTableEntry;
+        let end = &__ex_table_end as *const ExceptionTableEntry;
+        let len = (end as usize - start as usize)
+                / ::core::mem::size_of::<ExceptionTableEntry>();
+        slice::from_raw_parts(start, len)
+    }
+}
+
+/// Returns the address to resume at after a fault at `rip`, if the
+/// instruction there is allowed to fault.
+pub fn search(rip: u64) -> Option<u64> {
+    entries().iter()
+             .find(|entry| rip >= entry.start && rip < entry.end)
+             .map(|entry| entry.fixup)
+}
+
+/// Turn on SMEP and SMAP, if the CPU has them.
+///
+/// Returns the protections that were turned on.
+///
+/// # Safety
+/// + This must be called once, on the bootstrap processor, before any user
+///   task runs, and after system calls have been set up to clear `AC` on
+///   entry.
+pub unsafe fn init() -> cr4::Flags {
+    let max_leaf = cpuid(0, 0).eax;
+    if max_leaf < cpuid::LEAF_EXT_FEATURES { return cr4::Flags::empty() }
+    let features = cpuid(cpuid::LEAF_EXT_FEATURES, 0);
+    let mut enabled = cr4::Flags::empty();
+    if features.ebx & cpuid::EBX_SMEP != 0 { enabled.insert(cr4::SMEP) }
+    if features.ebx & cpuid::EBX_SMAP != 0 { enabled.insert(cr4::SMAP) }
+    cr4::write(cr4::read() | enabled);
+    enabled
+}
+
+/// Run `f` with user pages accessible to the kernel.
+///
+/// This is for code that has to write to user memory directly, and has
+/// already made sure that memory is mapped, such as `exec` laying out a new
+/// program's stack. Anything else should use [`copy_user`].
+///
+/// [`copy_user`]: fn.copy_user.html
+///
+/// # Safety
+/// + `f` must only touch user memory that is mapped, and must not switch
+///   tasks.
+pub unsafe fn with_user_access<F, R>(f: F) -> R
+where F: FnOnce() -> R {
+    let smap = cr4::read().contains(cr4::SMAP);
+    if smap { stac() }
+    let result = f();
+    if smap { clac() }
+    result
+}
+
+/// Copy `len` bytes from `src` to `dst`, where one of them is in user
+/// memory, returning the number of bytes that couldn't be copied because of
+/// a page fault.
+///
+/// # Safety
+/// + The kernel side of the copy must be valid for `len` bytes.
+/// + The user side must be entirely in user space; whether it's mapped
+///   doesn't matter.
+/// + If SMAP is enabled, this is the only way to read or write user memory
+///   (other than [`with_user_access`](fn.with_user_access.html)): user
+///   pages are made accessible with `stac` for the copy alone, and any other
+///   kernel access to them is a page fault.
+///
+/// Large copies go through `%ymm0` where the CPU has AVX2, like
+/// [`memops::fast_memcpy`](../memops/fn.fast_memcpy.html), which can't be
+/// used here itself since it has no fixups.
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    // below two chunks, there's hardly a middle to speed up.
+    if len >= 2 * memops::CHUNK_SIZE && memops::avx2_enabled() {
+        with_user_access(|| {
+            fpu::with_kernel_fpu(|| copy_user_avx2(dst, src, len))
+        })
+    } else {
+        with_user_access(|| copy_user_bytes(dst, src, len))
+    }
+}
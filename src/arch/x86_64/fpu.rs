@@ -0,0 +1,270 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Saving and restoring FPU, SSE and AVX state.
+//!
+//! The kernel itself is built without floating point, so only user tasks
+//! have any FPU state to preserve. Most tasks never touch the FPU, so a task
+//! gets somewhere to keep its state only when it first uses it: `CR0.TS` is
+//! set whenever a task without an [`XsaveArea`](struct.XsaveArea.html) is
+//! running, and its first FPU instruction raises a `#NM` exception, whose
+//! handler gives it one.
+//!
+//! We use `XSAVE` where the CPU has it, and fall back to `FXSAVE` (which
+//! only covers the x87 and SSE state) where it doesn't.
+//!
+//! The one exception is the bulk memory routines in
+//! [`memops`](../memops/index.html), which borrow `%ymm0` through
+//! [`with_kernel_fpu`](fn.with_kernel_fpu.html).
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT
+                        , ATOMIC_USIZE_INIT};
+use cpu::control_regs::{cr0, cr4};
+use cpu::cpuid::{self, cpuid};
+
+use task::{self, Task};
+
+/// The size of an [`XsaveArea`](struct.XsaveArea.html), which is enough for
+/// every state component up to and including AVX-512.
+pub const XSAVE_AREA_SIZE: usize = 4096;
+
+/// Offset of the x87 control word in the legacy region of the save area.
+const FCW_OFFSET: usize = 0;
+/// Offset of `MXCSR` in the legacy region of the save area.
+const MXCSR_OFFSET: usize = 24;
+/// The x87 control word after `FNINIT`.
+const FCW_DEFAULT: u16 = 0x037f;
+/// `MXCSR` at power-on: all SSE exceptions masked.
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+bitflags! {
+    /// State components that may be enabled in `XCR0`.
+    pub flags Xcr0: u64 { const X87 = 1 << 0
+                        , const SSE = 1 << 1
+                        , const AVX = 1 << 2
+                        }
+}
+
+/// True if we're using `XSAVE`, rather than `FXSAVE`.
+static USE_XSAVE: AtomicBool = ATOMIC_BOOL_INIT;
+/// The state components enabled in `XCR0`.
+static XSAVE_MASK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Memory to save a task's FPU state in.
+///
+/// The first 512 bytes are the legacy `FXSAVE` region, and the next 64 are
+/// the `XSAVE` header; the extended components follow.
+#[repr(C, align(64))]
+pub struct XsaveArea { bytes: [u8; XSAVE_AREA_SIZE] }
+
+impl XsaveArea {
+    /// Returns a save area holding the initial FPU state.
+    ///
+    /// The `XSAVE` header is all zeroes, so restoring it puts every state
+    /// component in its initial configuration. `MXCSR` is loaded regardless
+    /// of the header, so it's filled in here.
+    pub fn new() -> Self {
+        let mut area = XsaveArea { bytes: [0; XSAVE_AREA_SIZE] };
+        area.write_u16(FCW_OFFSET, FCW_DEFAULT);
+        area.write_u32(MXCSR_OFFSET, MXCSR_DEFAULT);
+        area
+    }
+
+    #[inline]
+    fn write_u16(&mut self, offset: usize, value: u16) {
+        self.bytes[offset] = value as u8;
+        self.bytes[offset + 1] = (value >> 8) as u8;
+    }
+
+    #[inline]
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.write_u16(offset, value as u16);
+        self.write_u16(offset + 2, (value >> 16) as u16);
+    }
+}
+
+/// Returns the size of the `XSAVE` area needed for the state components
+/// currently enabled in `XCR0`, according to `CPUID` leaf `0xD`.
+#[inline]
+pub fn xsave_area_size() -> usize {
+    cpuid(cpuid::LEAF_XSTATE, 0).ebx as usize
+}
+
+/// Write `value` to extended control register `xcr`.
+#[inline]
+unsafe fn xsetbv(xcr: u32, value: u64) {
+    asm!(  "xsetbv"
+        :: "{ecx}"(xcr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32)
+        :: "volatile" );
+}
+
+/// Save the current FPU state to `area`.
+///
+/// # Safety
+/// + `area` must be valid for writes.
+/// + `CR0.TS` must be clear.
+#[inline]
+pub unsafe fn xsave(area: *mut XsaveArea) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        let mask = XSAVE_MASK.load(Ordering::Relaxed) as u64;
+        asm!(  "xsave64 [$0]"
+            :: "r"(area), "{eax}"(mask as u32), "{edx}"((mask >> 32) as u32)
+            :  "memory"
+            :  "intel", "volatile" );
+    } else {
+        asm!(  "fxsave64 [$0]"
+            :: "r"(area)
+            :  "memory"
+            :  "intel", "volatile" );
+    }
+}
+
+/// Load the FPU state saved in `area`.
+///
+/// # Safety
+/// + `area` must hold a state saved by [`xsave`](fn.xsave.html), or be
+///   fresh from [`XsaveArea::new`](struct.XsaveArea.html#method.new).
+/// + `CR0.TS` must be clear.
+#[inline]
+pub unsafe fn xrstor(area: *const XsaveArea) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        let mask = XSAVE_MASK.load(Ordering::Relaxed) as u64;
+        asm!(  "xrstor64 [$0]"
+            :: "r"(area), "{eax}"(mask as u32), "{edx}"((mask >> 32) as u32)
+            :  "memory"
+            :  "intel", "volatile" );
+    } else {
+        asm!(  "fxrstor64 [$0]"
+            :: "r"(area)
+            :  "memory"
+            :  "intel", "volatile" );
+    }
+}
+
+/// Returns true if the AVX state component is enabled in `XCR0`, so that
+/// AVX instructions can be used.
+#[inline]
+pub fn avx_enabled() -> bool {
+    XSAVE_MASK.load(Ordering::Relaxed) as u64 & AVX.bits() != 0
+}
+
+/// Run `f`, which uses vector registers, in the kernel.
+///
+/// `TS` is cleared while `f` runs, so that it doesn't trap and hand the
+/// current task an FPU state it never asked for.
+///
+/// # Safety
+/// + The registers may hold the current task's state, so `f` must leave
+///   every one it uses as it found it.
+/// + `f` must not switch tasks.
+pub unsafe fn with_kernel_fpu<F, R>(f: F) -> R
+where F: FnOnce() -> R {
+    let ts = cr0::read().contains(cr0::TS);
+    if ts { clts() }
+    let result = f();
+    if ts { stts() }
+    result
+}
+
+/// Clear `CR0.TS`, so that FPU instructions don't trap.
+#[inline]
+unsafe fn clts() {
+    asm!("clts" :::: "volatile");
+}
+
+/// Set `CR0.TS`, so that the next FPU instruction raises `#NM`.
+#[inline]
+unsafe fn stts() {
+    let flags = cr0::read();
+    if !flags.contains(cr0::TS) { cr0::write(flags | cr0::TS) }
+}
+
+/// Enable the FPU and SSE, and `XSAVE` if the CPU supports it.
+///
+/// Returns true if `XSAVE` is being used.
+///
+/// # Safety
+/// + This must be called once, on the bootstrap processor, before any user
+///   task runs.
+pub unsafe fn init() -> bool {
+    let features = cpuid(cpuid::LEAF_FEATURES, 0);
+    assert!( features.edx & cpuid::EDX_FXSR != 0
+           , "CPU doesn't support FXSAVE!");
+
+    // no emulation, and have `WAIT` honour `TS` too.
+    let mut cr0_flags = cr0::read();
+    cr0_flags.remove(cr0::EM);
+    cr0_flags.insert(cr0::MP | cr0::NE);
+    cr0::write(cr0_flags);
+
+    let mut cr4_flags = cr4::read();
+    cr4_flags.insert(cr4::OSFXSR | cr4::OSXMMEXCPT);
+    let xsave = features.ecx & cpuid::ECX_XSAVE != 0;
+    if xsave { cr4_flags.insert(cr4::OSXSAVE) }
+    cr4::write(cr4_flags);
+
+    if xsave {
+        let mut mask = X87 | SSE;
+        if features.ecx & cpuid::ECX_AVX != 0 { mask.insert(AVX) }
+        xsetbv(0, mask.bits());
+        assert!( xsave_area_size() <= XSAVE_AREA_SIZE
+               , "XSAVE area is too big: {} bytes", xsave_area_size());
+        XSAVE_MASK.store(mask.bits() as usize, Ordering::Relaxed);
+        USE_XSAVE.store(true, Ordering::Relaxed);
+    }
+
+    // nothing is using the FPU yet.
+    stts();
+    xsave
+}
+
+/// Save `prev`'s FPU state and load `next`'s, as part of a context switch.
+///
+/// # Safety
+/// + `prev` must be the current task, and interrupts must be disabled.
+pub unsafe fn switch(prev: &mut Task, next: &Task) {
+    if prev.fpu_state.is_none() && next.fpu_state.is_none() {
+        // neither has used the FPU, so `TS` is already set.
+        return
+    }
+    clts();
+    if let Some(ref mut area) = prev.fpu_state {
+        xsave(&mut **area);
+    }
+    match next.fpu_state {
+        Some(ref area) => xrstor(&**area)
+      , None => stts()
+    }
+}
+
+/// Returns a copy of the current task's FPU state, if it has any, for a
+/// forked child.
+pub fn fork_state(task: &Task) -> Option<Box<XsaveArea>> {
+    task.fpu_state.as_ref().map(|_| {
+        let mut area = Box::new(XsaveArea::new());
+        // the task is running, so its state is in the registers, and `TS`
+        // is clear.
+        unsafe { xsave(&mut *area) };
+        area
+    })
+}
+
+/// Handle a `#NM` exception: the current task has used the FPU for the
+/// first time, so give it an initial FPU state.
+pub fn device_not_available() {
+    unsafe {
+        clts();
+        let task = task::current();
+        if task.fpu_state.is_none() {
+            task.fpu_state = Some(Box::new(XsaveArea::new()));
+        }
+        if let Some(ref area) = task.fpu_state {
+            xrstor(&**area);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Per-CPU GDTs and task state segments.
+//!
+//! The TSS holds the stack the CPU switches to when an interrupt or
+//! exception arrives in user mode (`rsp0`), and the interrupt stack table.
+//! Each CPU needs its own TSS, and `ltr` marks the TSS's descriptor busy,
+//! so each CPU needs its own GDT to put the descriptor in, too. The GDT
+//! `boot.asm` builds is read-only, and the one the AP trampoline loads
+//! only has the segments it needs to get into long mode, so every CPU
+//! switches to a GDT of its own, with the same segments as `gdt64`, as its
+//! per-CPU data is installed.
+//!
+//! NMIs can arrive anywhere, including on a stack that's overflowed, so they
+//! run on a stack of their own, from entry `NMI_IST` of the interrupt stack
+//! table.
+use core::mem;
+use cpu::segment::{ KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR
+                  , TSS_SELECTOR};
+use cpu::task::StateSegment;
+use memory::VAddr;
+
+/// The number of 8-byte GDT entries: the null descriptor, four segments,
+/// and the TSS descriptor, which takes two.
+const GDT_ENTRIES: usize = 7;
+
+/// The segments, as `gdt64` in `boot.asm` has them.
+const KERNEL_CODE: u64 = (1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53);
+const KERNEL_DATA: u64 = (1<<44) | (1<<47) | (1<<41);
+const USER_DATA: u64 = KERNEL_DATA | (3<<45);
+const USER_CODE: u64 = KERNEL_CODE | (3<<45);
+
+/// The system segment type of an available 64-bit TSS.
+const TYPE_TSS: u64 = 0x9;
+
+/// The interrupt stack table entry NMIs run on.
+pub const NMI_IST: u8 = 2;
+/// The size of each CPU's NMI stack.
+pub const NMI_STACK_SIZE: usize = 4096;
+
+/// A CPU's GDT and TSS.
+#[repr(C)]
+pub struct CpuTables { gdt: [u64; GDT_ENTRIES]
+                     , /// The TSS, which must not move once it's loaded
+                       pub tss: StateSegment
+                     }
+
+/// The operand of `lgdt`.
+#[repr(C, packed)]
+struct GdtPointer { limit: u16
+                  , base: u64
+                  }
+
+impl CpuTables {
+    /// Returns empty tables, for [`load`](#method.load) to fill in.
+    pub const fn new() -> Self {
+        CpuTables { gdt: [0; GDT_ENTRIES], tss: StateSegment::new() }
+    }
+
+    /// Set the stack the CPU switches to on an interrupt from user mode.
+    #[inline]
+    pub fn set_rsp0(&mut self, rsp: u64) {
+        self.tss.rsp[0] = VAddr::from(rsp as usize);
+    }
+
+    /// Set the top of the stack interrupts whose gates ask for entry `ist`
+    /// (1 - 7) of the interrupt stack table switch to.
+    #[inline]
+    pub fn set_ist(&mut self, ist: u8, top: u64) {
+        self.tss.ist[ist as usize - 1] = VAddr::from(top as usize);
+    }
+
+    /// Build the GDT, and load it and the TSS on this CPU.
+    ///
+    /// # Safety
+    /// + This must be called once on each CPU, with interrupts disabled,
+    ///   on that CPU's own `CpuTables`, which must never move or be freed.
+    pub unsafe fn load(&'static mut self) {
+        let size = mem::size_of::<StateSegment>();
+        // no I/O permission bitmap: the offset points past the TSS's limit.
+        self.tss.iomap_base_offset = size as u16;
+
+        let base = &self.tss as *const StateSegment as u64;
+        let limit = (size - 1) as u64;
+        self.gdt = [ 0
+                   , KERNEL_CODE
+                   , KERNEL_DATA
+                   , USER_DATA
+                   , USER_CODE
+                   , (limit & 0xffff) | (base & 0xff_ffff) << 16
+                         | TYPE_TSS << 40 | 1 << 47
+                         | (limit >> 16 & 0xf) << 48
+                         | (base >> 24 & 0xff) << 56
+                   , base >> 32
+                   ];
+
+        let ptr = GdtPointer { limit: (mem::size_of_val(&self.gdt) - 1) as u16
+                             , base: self.gdt.as_ptr() as u64
+                             };
+        asm!( "lgdt ($0)"
+            :: "r"(&ptr)
+            :  "memory" );
+        // the AP trampoline's GDT numbers its segments differently, so
+        // reload them all from this one. `%fs` and `%gs` are left alone,
+        // since loading them would clear their bases.
+        asm!( "pushq $0
+               leaq 1f(%rip), %rax
+               pushq %rax
+               lretq
+               1:"
+            :: "r"(KERNEL_CODE_SELECTOR.bits() as u64)
+            :  "rax", "memory"
+            :  "volatile" );
+        KERNEL_DATA_SELECTOR.load_ss();
+        KERNEL_DATA_SELECTOR.load_ds();
+        KERNEL_DATA_SELECTOR.load_es();
+        asm!( "ltr $0"
+            :: "r"(TSS_SELECTOR.bits())
+            :: "volatile" );
+    }
+}
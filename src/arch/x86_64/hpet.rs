@@ -0,0 +1,181 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The High Precision Event Timer.
+//!
+//! We only use one of its comparators, to call a function at a fixed
+//! interval. The HPET is put in legacy replacement mode, so comparator 0
+//! interrupts on IRQ 0 in place of the PIT, which nothing else uses. The
+//! comparator runs in one-shot mode, and is moved on by one interval each
+//! time it fires, since not every HPET can do periodic interrupts on it.
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use core::mem;
+use cpu::context::InterruptFrame;
+use cpu::interrupts::pics;
+use memory::PAddr;
+use paging::arch::table::{NO_EXECUTE, WRITABLE};
+use spin::Once;
+
+use mm::map_physical;
+use super::{acpi, mtrr};
+
+/// Offsets of the fields we use in the ACPI HPET table.
+mod table {
+    /// The address space of the base address; 0 is system memory.
+    pub const ADDRESS_SPACE: usize = 40;
+    /// The physical address of the HPET's registers.
+    pub const ADDRESS: usize = 44;
+}
+
+/// The size of the HPET's register block.
+const REGISTERS_LEN: usize = 0x400;
+
+/// General capabilities and ID register.
+const GENERAL_CAPS: usize = 0x000;
+/// General configuration register.
+const GENERAL_CONFIG: usize = 0x010;
+/// Main counter value register.
+const MAIN_COUNTER: usize = 0x0f0;
+/// Comparator 0's configuration and capability register.
+const TIMER0_CONFIG: usize = 0x100;
+/// Comparator 0's comparator value register.
+const TIMER0_COMPARATOR: usize = 0x108;
+
+/// General capabilities: legacy replacement routing is supported.
+const CAPS_LEGACY_ROUTE: u64 = 1 << 15;
+/// General capabilities: the shift of the counter period, in femtoseconds.
+const CAPS_PERIOD_SHIFT: u64 = 32;
+/// General configuration: the main counter is running.
+const CONFIG_ENABLE: u64 = 1 << 0;
+/// General configuration: use legacy replacement routing.
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+/// Timer configuration: interrupts are level triggered.
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+/// Timer configuration: the timer interrupts when it fires.
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+/// Timer configuration: the timer fires periodically.
+const TIMER_PERIODIC: u64 = 1 << 3;
+
+/// The IRQ line comparator 0 interrupts on, in legacy replacement mode.
+const TIMER0_IRQ: u8 = 0;
+
+/// The number of femtoseconds in a nanosecond.
+const FS_PER_NS: u64 = 1_000_000;
+
+/// A function to call from the HPET's interrupt, with the frame of the code
+/// it interrupted.
+pub type HpetCallback = fn(&InterruptFrame);
+
+/// Where the HPET's registers are, and how fast it counts.
+#[derive(Copy, Clone, Debug)]
+struct Hpet { base: usize
+            , /// The length of a tick of the main counter
+              period_fs: u64
+            }
+
+static HPET: Once<Hpet> = Once::new();
+
+/// The function registered by [`every`](fn.every.html), as a `usize`, or 0.
+static CALLBACK: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The number of ticks between calls to the callback.
+static INTERVAL_TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+impl Hpet {
+    #[inline]
+    unsafe fn read(&self, reg: usize) -> u64 {
+        ptr::read_volatile((self.base + reg) as *const u64)
+    }
+
+    #[inline]
+    unsafe fn write(&self, reg: usize, value: u64) {
+        ptr::write_volatile((self.base + reg) as *mut u64, value)
+    }
+}
+
+/// Find the HPET through ACPI, and start its main counter.
+///
+/// # Safety
+/// + This must be called once, after the kernel has been remapped.
+pub unsafe fn init() -> Result<(), &'static str> {
+    let sdt = acpi::find_sdt(b"HPET")?;
+    if sdt.len() < table::ADDRESS + 8 { return Err("HPET table is too short") }
+    if sdt[table::ADDRESS_SPACE] != 0 {
+        return Err("HPET registers aren't in memory")
+    }
+    let addr = ptr::read_unaligned(
+        sdt[table::ADDRESS..].as_ptr() as *const u64);
+    let flags = mtrr::mmio_flags(PAddr::from(addr), REGISTERS_LEN as u64);
+    let base = map_physical( PAddr::from(addr), REGISTERS_LEN
+                           , WRITABLE | flags | NO_EXECUTE )
+        .map_err(|_| "could not map the HPET")?;
+    let hpet = Hpet { base: base.as_usize(), period_fs: 0 };
+
+    let caps = hpet.read(GENERAL_CAPS);
+    if caps & CAPS_LEGACY_ROUTE == 0 {
+        return Err("HPET can't take over IRQ 0")
+    }
+    let period_fs = caps >> CAPS_PERIOD_SHIFT;
+    if period_fs == 0 { return Err("HPET reports a period of zero") }
+
+    hpet.write(GENERAL_CONFIG, 0);
+    hpet.write(MAIN_COUNTER, 0);
+    hpet.write(TIMER0_CONFIG, 0);
+    hpet.write(GENERAL_CONFIG, CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+    HPET.call_once(|| Hpet { period_fs: period_fs, ..hpet });
+    info!("HPET at {:#x}, ticking every {} fs", addr, period_fs);
+    Ok(())
+}
+
+/// Call `callback` from the HPET's interrupt every `interval_ns`
+/// nanoseconds, replacing any callback registered before.
+pub fn every(interval_ns: u64, callback: HpetCallback)
+            -> Result<(), &'static str> {
+    let hpet = HPET.try().ok_or("no HPET")?;
+    let ticks = interval_ns.saturating_mul(FS_PER_NS) / hpet.period_fs;
+    if ticks == 0 { return Err("interval is shorter than an HPET tick") }
+    INTERVAL_TICKS.store(ticks as usize, Ordering::SeqCst);
+    CALLBACK.store(callback as usize, Ordering::SeqCst);
+    unsafe {
+        let config = hpet.read(TIMER0_CONFIG)
+                   & !(TIMER_PERIODIC | TIMER_LEVEL_TRIGGERED);
+        hpet.write( TIMER0_COMPARATOR
+                  , hpet.read(MAIN_COUNTER).wrapping_add(ticks));
+        hpet.write(TIMER0_CONFIG, config | TIMER_INT_ENABLE);
+    }
+    pics::unmask(TIMER0_IRQ);
+    Ok(())
+}
+
+/// Handle an interrupt from comparator 0: move it on to the next interval,
+/// and call the callback.
+///
+/// This is called by the IRQ 0 handler, which sends the end of interrupt.
+pub fn interrupt(frame: &InterruptFrame) {
+    let hpet = match HPET.try() {
+        Some(hpet) => hpet
+      , None => return
+    };
+    let callback = CALLBACK.load(Ordering::SeqCst);
+    if callback == 0 { return }
+    let ticks = INTERVAL_TICKS.load(Ordering::SeqCst) as u64;
+    unsafe {
+        // if we were held up for more than a whole interval, the comparator
+        // would be behind the counter, and not fire again until it wrapped.
+        let now = hpet.read(MAIN_COUNTER);
+        let next = hpet.read(TIMER0_COMPARATOR).wrapping_add(ticks);
+        let next = if (next.wrapping_sub(now) as i64) <= 0 {
+            now.wrapping_add(ticks)
+        } else {
+            next
+        };
+        hpet.write(TIMER0_COMPARATOR, next);
+    }
+    let callback: HpetCallback = unsafe { mem::transmute(callback) };
+    callback(frame);
+}
@@ -7,12 +7,20 @@
 //  directory of this repository for more information.
 //
 
-use cpu::interrupts::pics;
+use cpu::interrupts::{count_irq, pics, InterruptHandler, NUM_IRQS};
 use cpu::interrupts::idt::{Gate, Idt};
 
 use cpu::context::InterruptFrame;
 use cpu::dtable::DTable;
 
+use core::mem;
+use memory::VAddr;
+use mm::fault::{handle_user_fault, segfault, Access, FaultResult};
+use phase::{advance_phase, KernelPhase};
+use trace::{self, trace_event};
+use super::tlb_shootdown::SHOOTDOWN_VECTOR;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
 
 //==--------------------------------------------------------------------------==
 // Top-level interrupt handling
@@ -36,10 +44,23 @@ pub unsafe fn initialize() -> Result<(), ()>{
     // asm!("int $0" :: "N" (0xff));
 
     Idt::enable_interrupts(); // enable interrupts
+    advance_phase(KernelPhase::InterruptInit);
     Ok(())
 
 }
 
+/// Load the IDT on an application processor.
+///
+/// The PICs only interrupt the bootstrap processor, so this is all an AP
+/// needs to take IPIs. Interrupts are left disabled.
+///
+/// # Safety
+/// + This must be called once on each application processor, after
+///   [`initialize`](fn.initialize.html) has run on the bootstrap processor.
+pub unsafe fn init_ap() {
+    IDT.load();
+}
+
 macro_rules! exception_inner {
     ($title:expr, $kind:expr, $source:expr, $f:expr) => {
         use vga::{CONSOLE, Color};
@@ -104,16 +125,11 @@ macro_rules! exceptions {
 exceptions! {
     fault: divide_by_zero, "Divide by Zero Error",
            "DIV or IDIV instruction",
-    fault: nmi, "Non-Maskable Interrupt",
-          "Non-maskable external interrupt",
     trap: overflow, "Overflow", "INTO instruction",
     fault: bound_exceeded, "BOUND range exceeded",
           "BOUND instruction",
     fault: undefined_opcode, "Undefined Opcode",
            "UD2 instruction or reserved opcode",
-    fault: device_not_available, "Device Not Available"
-         , "Floating-point or WAIT/FWAIT instruction \
-            (no math coprocessor)",
     fault (code): double_fault, "Double Fault"
          , "Any instruction that can generate an exception, a NMI, or \
             an INTR",
@@ -137,6 +153,241 @@ exceptions! {
          , "SSE/SSE2/SSE3 floating-point instructions",
 }
 
+/// Page fault error code: the page was present.
+const PF_PRESENT: usize = 1 << 0;
+/// Page fault error code: the access was a write.
+const PF_WRITE: usize = 1 << 1;
+/// Page fault error code: the fault happened in user mode.
+const PF_USER: usize = 1 << 2;
+/// Page fault error code: the fault was an instruction fetch.
+const PF_INSTRUCTION: usize = 1 << 4;
+
+/// Returns what a page fault was for, as far as the error code and the
+/// faulting address tell.
+///
+/// SMAP and SMEP violations don't have error code bits of their own: they
+/// show up as a kernel-mode protection fault on a present user page.
+fn page_fault_source(error_code: usize, addr: usize) -> &'static str {
+    use cpu::control_regs::cr4;
+    use mm::{USER_SPACE_END, USER_SPACE_START};
+    const ANY: &'static str = "Any memory reference";
+    let kernel_protection_fault
+        = error_code & (PF_PRESENT | PF_USER) == PF_PRESENT;
+    let user_addr = addr >= USER_SPACE_START && addr < USER_SPACE_END;
+    if !(kernel_protection_fault && user_addr) { return ANY }
+    let protection = unsafe { cr4::read() };
+    if error_code & PF_INSTRUCTION != 0 {
+        if protection.contains(cr4::SMEP) {
+            return "SMEP violation: the kernel jumped into user memory"
+        }
+    } else if protection.contains(cr4::SMAP) {
+        return "SMAP violation: the kernel touched user memory without \
+                copy_from_user or copy_to_user"
+    }
+    ANY
+}
+
+/// Returns the kind of access a page fault's error code describes.
+fn page_fault_access(error_code: usize) -> Access {
+    if error_code & PF_INSTRUCTION != 0 { Access::Execute }
+    else if error_code & PF_WRITE != 0 { Access::Write }
+    else { Access::Read }
+}
+
+/// Page Fault.
+///
+/// Faults on user memory that's valid but hasn't been touched yet are
+/// handled by mapping the page, and the access is retried; this includes
+/// the kernel's accesses while copying to or from user memory. A user task
+/// that faults any other way is killed.
+///
+/// If the kernel faulted on an instruction listed in the exception table
+/// (while copying to or from user memory), it resumes at the instruction's
+/// fixup. Any other page fault is fatal.
+extern "x86-interrupt" fn page_fault( frame: &InterruptFrame
+                                    , error_code: usize) {
+    let addr = unsafe { ::cpu::control_regs::cr2::read() };
+    trace_event( trace::PAGE_FAULT, addr as u64, error_code as u64
+               , frame.rip as u64);
+    let fixup = if error_code & PF_USER == 0 {
+        super::extable::search(frame.rip as u64)
+    } else {
+        None
+    };
+    if error_code & PF_USER != 0 || fixup.is_some() {
+        let result = handle_user_fault( VAddr::from(addr)
+                                      , page_fault_access(error_code)
+                                      , error_code & PF_PRESENT != 0 );
+        match result {
+            FaultResult::Handled => return
+          , why if error_code & PF_USER != 0 =>
+                segfault(VAddr::from(addr), why)
+          , _ => {}
+        }
+    }
+    if let Some(fixup) = fixup {
+        unsafe {
+            let frame = frame as *const InterruptFrame as *mut InterruptFrame;
+            (*frame).rip = fixup as *const u8;
+        }
+        return
+    }
+    exception_inner!( "Page Fault", "Fault"
+                    , page_fault_source(error_code, addr)
+                    , frame, error_code);
+    loop {}
+}
+
+/// A function called when a hardware breakpoint fires, with the
+/// breakpoint's slot and the `%rip` of the code that hit it.
+pub type BreakpointHandler = fn(u8, VAddr);
+
+/// The handler registered for hardware breakpoints, as a `usize`, or 0.
+static BREAKPOINT_HANDLER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Call `handler` whenever a breakpoint set in the debug registers fires.
+pub fn register_breakpoint_handler(handler: BreakpointHandler) {
+    BREAKPOINT_HANDLER.store(handler as usize, Ordering::SeqCst);
+}
+
+/// Debug Exception: a hardware breakpoint fired.
+///
+/// Data breakpoints are traps, reported after the access. Execute
+/// breakpoints are faults, so the resume flag is set to get past the
+/// instruction rather than hitting the breakpoint again.
+extern "x86-interrupt" fn debug(frame: &InterruptFrame) {
+    use cpu::debug_regs::{dr6, DR6_BREAKPOINTS, NUM_BREAKPOINTS};
+    use cpu::flags::RF;
+    let status = unsafe { dr6::read() };
+    // the processor never clears `%dr6`, so stale bits would show up
+    // next time.
+    unsafe { dr6::write(status & !DR6_BREAKPOINTS) }
+    let rip = VAddr::from(frame.rip as usize);
+    let handler = BREAKPOINT_HANDLER.load(Ordering::SeqCst);
+    for slot in (0 .. NUM_BREAKPOINTS).filter(|&i| status & (1 << i) != 0) {
+        if handler == 0 {
+            warn!("breakpoint {} hit at {:?}", slot, rip);
+        } else {
+            let handler: BreakpointHandler = unsafe {
+                mem::transmute(handler)
+            };
+            handler(slot, rip);
+        }
+    }
+    unsafe {
+        let frame = frame as *const InterruptFrame as *mut InterruptFrame;
+        (*frame).rflags.insert(RF);
+    }
+}
+
+/// Non-Maskable Interrupt.
+///
+/// Once a panic has started halting the other CPUs, an NMI is how it asks
+/// this one to stop. Otherwise, it's from the hardware, and fatal.
+extern "x86-interrupt" fn nmi(frame: &InterruptFrame) {
+    if super::smp::is_halting() {
+        unsafe { super::smp::halt_here(frame) }
+    }
+    exception_inner!( "Non-Maskable Interrupt", "Fault"
+                    , "Non-maskable external interrupt", frame);
+    loop {}
+}
+
+/// IRQ 0, which the HPET's comparator 0 takes over from the PIT.
+extern "x86-interrupt" fn hpet_timer(frame: &InterruptFrame) {
+    count_irq(0);
+    trace_event(trace::IRQ, 0, 0, 0);
+    super::hpet::interrupt(frame);
+    unsafe { pics::end_pic_interrupt(0x20); }
+}
+
+/// The vector the local APIC raises performance monitoring interrupts on.
+pub const PMI_VECTOR: u8 = 0xf0;
+
+/// A performance counter overflowed.
+///
+/// The local APIC masks its performance counter entry when it delivers
+/// this, so it's unmasked again once the counters have been reloaded.
+extern "x86-interrupt" fn perf_counter(frame: &InterruptFrame) {
+    unsafe { ::perf::counter::reload_overflowed(); }
+    ::perf::sample(frame.rip as usize);
+    super::apic::set_perf_vector(PMI_VECTOR);
+    super::apic::eoi();
+}
+
+/// A TLB shootdown IPI from another CPU.
+extern "x86-interrupt" fn tlb_shootdown(_frame: &InterruptFrame) {
+    super::tlb_shootdown::handle_ipi();
+    super::apic::eoi();
+}
+
+/// Device Not Available: a task used the FPU while `CR0.TS` was set.
+extern "x86-interrupt" fn device_not_available(_frame: &InterruptFrame) {
+    super::fpu::device_not_available()
+}
+
+//==--------------------------------------------------------------------------==
+// Device IRQs
+
+/// A handler for a device IRQ, registered by the device's driver.
+pub type IrqHandler = fn();
+
+/// The first IRQ line that drivers may register a handler for. The lines
+/// below it are the timer, the keyboard, and the PIC cascade.
+const FIRST_DEVICE_IRQ: usize = 3;
+
+/// The handler registered for each IRQ line, as a `usize` so that it can be
+/// replaced atomically. Zero means no handler has been registered.
+static IRQ_HANDLERS: [AtomicUsize; NUM_IRQS]
+    = [ ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT ];
+
+/// Register `handler` to be called whenever IRQ line `irq` fires, and
+/// unmask that line.
+///
+/// # Panics
+/// + If `irq` is reserved for the timer, keyboard or PIC cascade, or is not
+///   a valid IRQ line.
+pub fn register_irq(irq: u8, handler: IrqHandler) {
+    let line = irq as usize;
+    assert!( line >= FIRST_DEVICE_IRQ && line < NUM_IRQS
+           , "can't register a handler for IRQ {}", irq);
+    IRQ_HANDLERS[line].store(handler as usize, Ordering::SeqCst);
+    pics::unmask(irq);
+}
+
+/// Call the handler registered for IRQ line `irq`, if there is one.
+fn dispatch_irq(irq: u8) {
+    count_irq(irq as usize);
+    trace_event(trace::IRQ, irq as u64, 0, 0);
+    let handler = IRQ_HANDLERS[irq as usize].load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: IrqHandler = unsafe { mem::transmute(handler) };
+        handler();
+    }
+    unsafe { pics::end_pic_interrupt(0x20 + irq); }
+}
+
+macro_rules! irq_handlers {
+    ($($name:ident => $irq:expr),+) => {
+        $(
+            extern "x86-interrupt" fn $name(_frame: &InterruptFrame) {
+                dispatch_irq($irq)
+            }
+        )+
+        /// Entry points for the device IRQs, starting at `FIRST_DEVICE_IRQ`.
+        static IRQ_ENTRIES: &'static [InterruptHandler] = &[ $($name),+ ];
+    }
+}
+
+irq_handlers! { irq3 => 3, irq4 => 4, irq5 => 5, irq6 => 6, irq7 => 7
+              , irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11
+              , irq12 => 12, irq13 => 13, irq14 => 14, irq15 => 15 }
+
 lazy_static! {
     static ref IDT: Idt = {
         let mut idt = Idt::new();
@@ -146,7 +397,10 @@ lazy_static! {
         //       trace faults occurring during IDT population (if any)
         //          - eliza, 5/22/2017
         idt.divide_by_zero = Gate::from(divide_by_zero as InterruptHandler);
+        idt.debug = Gate::from(self::debug as InterruptHandler);
         idt.nmi = Gate::from(nmi as InterruptHandler);
+        // the stack an NMI arrives on may be the reason for the panic.
+        idt.nmi.set_stack_index(super::gdt::NMI_IST);
         idt.overflow = Gate::from(overflow as InterruptHandler);
         idt.overflow.set_trap();
         idt.bound_exceeded = Gate::from(bound_exceeded as InterruptHandler);
@@ -157,7 +411,7 @@ lazy_static! {
         idt.segment_not_present = Gate::from(segment_not_present as ErrorCodeHandler);
         idt.stack_segment_fault = Gate::from(stack_segment_fault as ErrorCodeHandler);
         idt.general_protection_fault = Gate::from(general_protection_fault as ErrorCodeHandler);
-        idt.page_fault = Gate::from(page_fault as ErrorCodeHandler);
+        idt.page_fault = Gate::from(self::page_fault as ErrorCodeHandler);
 
         idt.floating_point_error = Gate::from(floating_point_error as InterruptHandler);
         idt.alignment_check = Gate::from(alignment_check as ErrorCodeHandler);
@@ -165,10 +419,17 @@ lazy_static! {
         idt.simd_fp_exception = Gate::from(simd_fp_exception as InterruptHandler);
 
         idt.breakpoint = Gate::from(breakpoint as InterruptHandler);
-        idt.page_fault = Gate::from(page_fault as ErrorCodeHandler);
+        idt.page_fault = Gate::from(self::page_fault as ErrorCodeHandler);
 
-        idt.interrupts[0x20 - 32] = Gate::from(timer as InterruptHandler);
+        idt.interrupts[0x20 - 32] = Gate::from(hpet_timer as InterruptHandler);
         idt.interrupts[0x21 - 32] = Gate::from(keyboard as InterruptHandler);
+        for (i, &entry) in IRQ_ENTRIES.iter().enumerate() {
+            idt.interrupts[FIRST_DEVICE_IRQ + i] = Gate::from(entry);
+        }
+        idt.interrupts[PMI_VECTOR as usize - 32]
+            = Gate::from(perf_counter as InterruptHandler);
+        idt.interrupts[SHOOTDOWN_VECTOR as usize - 32]
+            = Gate::from(tlb_shootdown as InterruptHandler);
         idt.interrupts[0xff - 32] = Gate::from(test as InterruptHandler);
 
         kinfoln!( dots: " . . ", target: "Adding interrupt handlers to IDT"
@@ -181,6 +442,8 @@ lazy_static! {
 #[no_mangle] #[inline(never)]
 pub extern "x86-interrupt" fn keyboard(_frame: &InterruptFrame) {
     use io::keyboard;
+    count_irq(1);
+    trace_event(trace::IRQ, 1, 0, 0);
 
     // println!("keyboard happened");
     if let Some(input) = keyboard::read_char() {
@@ -0,0 +1,612 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! DMA remapping with an Intel VT-d IOMMU.
+//!
+//! With remapping on, the addresses a device uses for DMA are translated
+//! through page tables, so a device can only reach memory it's been given.
+//! Each device that may do DMA is put in a [`Domain`], whose second-level
+//! page tables map I/O virtual addresses to physical ones. These are laid
+//! out just like the CPU's page tables, with the present and writable bits
+//! meaning read and write, so they're made of `paging`'s `Table`s and
+//! `Entry`s. The hardware finds a device's domain through the root table,
+//! which has an entry for each bus, and a context table for each bus, which
+//! has an entry for each device and function.
+//!
+//! Every context entry starts out not present, so a device that hasn't been
+//! given a domain faults on any DMA at all, and one that has can only reach
+//! what's been [`map`]ped in it.
+//!
+//! We only drive one remapping unit: the one that covers every PCI device
+//! not listed under another, or failing that the first one in the DMAR.
+//! Its tables are in legacy mode, with four-level (48-bit) second-level
+//! tables. None of the drivers map their buffers yet, so remapping is only
+//! turned on with the `iommu` feature.
+//!
+//! [`Domain`]: struct.Domain.html
+//! [`map`]: struct.Iommu.html#method.map
+use alloc::vec::Vec;
+use core::{fmt, mem};
+use cpu::tsc;
+use memory::{Addr, PAddr, PAGE_SIZE, Page, PhysicalPage};
+use paging::arch::ActivePageTable;
+use paging::arch::space::phys_to_virt;
+use paging::arch::table::{ Entry, EntryFlags, PML4Level, Sublevel, Table
+                         , TableLevel, PRESENT, WRITABLE };
+use sos_alloc::FrameAllocator;
+use spin::{Mutex, Once};
+
+use mm::dma::DmaBox;
+use mm::frame;
+use mm::mmio::MmioRegion;
+use dev::pci::PciDevice;
+use super::acpi::{self, read_u16, read_u64};
+use super::cpu::{clflush, mfence};
+
+/// Where the remapping structures start in the DMAR, after the header, the
+/// host address width, the flags and 10 reserved bytes.
+const DMAR_STRUCTURES: usize = 48;
+/// The type of a DMA Remapping Hardware unit Definition structure.
+const DRHD: u16 = 0;
+
+/// Offsets of the fields we use in a DRHD.
+mod drhd {
+    pub const FLAGS: usize = 4;
+    pub const SEGMENT: usize = 6;
+    pub const REGISTER_BASE: usize = 8;
+    /// The length of a DRHD without any device scopes.
+    pub const LEN: usize = 16;
+}
+
+/// DRHD flags: the unit covers every PCI device not listed under another.
+const DRHD_INCLUDE_PCI_ALL: u8 = 1 << 0;
+
+/// Offsets of the remapping unit's registers.
+mod reg {
+    /// Capabilities.
+    pub const CAP: usize = 0x08;
+    /// Extended capabilities.
+    pub const ECAP: usize = 0x10;
+    /// Global command.
+    pub const GCMD: usize = 0x18;
+    /// Global status.
+    pub const GSTS: usize = 0x1c;
+    /// Root table address.
+    pub const RTADDR: usize = 0x20;
+    /// Context command.
+    pub const CCMD: usize = 0x28;
+    /// The size of the register block, up to where the IOTLB registers may
+    /// start.
+    pub const SIZE: usize = 0x1000;
+    /// The IOTLB invalidate register's offset from the IOTLB registers.
+    pub const IOTLB: usize = 0x08;
+    /// The size of the IOTLB registers.
+    pub const IOTLB_SIZE: usize = 0x10;
+}
+
+/// CAP: the unit supports `1 << (4 + 2 * ND)` domains.
+const CAP_ND_MASK: u64 = 0b111;
+/// CAP: the write buffer must be flushed for the unit to see changes to
+/// the tables.
+const CAP_RWBF: u64 = 1 << 4;
+/// CAP: caching mode, where entries that aren't present may be cached, so
+/// making one present needs an invalidation too.
+const CAP_CM: u64 = 1 << 7;
+/// CAP: four-level (48-bit) second-level tables are supported.
+const CAP_SAGAW_4_LEVEL: u64 = 1 << 10;
+/// ECAP: the unit snoops the CPU's caches when it walks the tables.
+const ECAP_COHERENT: u64 = 1 << 0;
+/// ECAP: where the IOTLB registers are, in 16-byte units.
+const ECAP_IRO_SHIFT: u64 = 8;
+const ECAP_IRO_MASK: u64 = 0x3ff;
+
+/// GCMD and GSTS: translation is enabled.
+const GCMD_TE: u32 = 1 << 31;
+/// GCMD and GSTS: the root table address has been set.
+const GCMD_SRTP: u32 = 1 << 30;
+/// GCMD and GSTS: the write buffer is being flushed.
+const GCMD_WBF: u32 = 1 << 27;
+/// The bits of GSTS that stay in effect; the rest are one-shot commands,
+/// which mustn't be written back to GCMD.
+const GSTS_PERSISTENT: u32 = 0x96ff_ffff;
+
+/// CCMD: invalidate the context cache, and cleared when that's done.
+const CCMD_ICC: u64 = 1 << 63;
+/// CCMD: invalidate every context entry.
+const CCMD_GLOBAL: u64 = 1 << 61;
+
+/// IOTLB: invalidate the IOTLB, and cleared when that's done.
+const IOTLB_IVT: u64 = 1 << 63;
+/// IOTLB: invalidate every entry.
+const IOTLB_GLOBAL: u64 = 1 << 60;
+/// IOTLB: invalidate the entries for the domain in `IOTLB_DID_SHIFT`.
+const IOTLB_DOMAIN: u64 = 2 << 60;
+const IOTLB_DID_SHIFT: u64 = 32;
+/// IOTLB: drain reads and writes before invalidating.
+const IOTLB_DRAIN: u64 = 1 << 49 | 1 << 48;
+
+/// Root and context entries: the entry is present.
+const ENTRY_PRESENT: u64 = 1 << 0;
+/// Context entries: the domain has four-level tables.
+const CONTEXT_AW_4_LEVEL: u64 = 2;
+const CONTEXT_DID_SHIFT: u64 = 8;
+
+/// The number of entries in the root table, one per bus.
+const N_BUSES: usize = 256;
+/// The number of entries in a context table, one per device and function.
+const N_DEVFNS: usize = 256;
+
+/// The end of the I/O virtual addresses four-level tables can map.
+const IOVA_END: u64 = 1 << 48;
+/// How long to wait for the unit to finish a command.
+const TIMEOUT_NS: u64 = 1_000_000_000;
+/// The size of a cache line, for flushing tables out of the cache.
+const CACHE_LINE: usize = 64;
+
+bitflags! {
+    /// What a device may do with memory mapped in its domain.
+    pub flags IoPerms: u8 {
+        const IO_READ =  1 << 0,
+        const IO_WRITE = 1 << 1
+    }
+}
+
+impl IoPerms {
+    /// Returns the second-level entry flags for these permissions.
+    fn entry_flags(&self) -> EntryFlags {
+        let mut flags = EntryFlags::empty();
+        if self.contains(IO_READ) { flags.insert(PRESENT) }
+        if self.contains(IO_WRITE) { flags.insert(WRITABLE) }
+        flags
+    }
+}
+
+/// The number of a domain, which the hardware tags its caches with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct DomainId(pub u16);
+
+/// Errors returned by the IOMMU driver.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IommuError { /// There's no ACPI DMAR table
+                      NoDmar
+                    , /// The DMAR lists no remapping unit we can use
+                      NoUnit
+                    , /// The unit can't do four-level tables
+                      Unsupported
+                    , /// We ran out of memory for registers or tables
+                      NoMemory
+                    , /// The unit took too long to finish a command
+                      Timeout
+                    , /// Every domain the unit supports is in use
+                      NoDomains
+                    , /// The device already has a domain
+                      AlreadyAssigned
+                    , /// There's no domain with this number
+                      NoSuchDomain(DomainId)
+                    , /// The addresses aren't page aligned, or the I/O
+                      /// virtual addresses are out of range
+                      BadRange
+                    , /// The mapping gives no permissions at all
+                      NoPermissions
+                    , /// This I/O virtual address is already mapped
+                      AlreadyMapped(u64)
+                    }
+
+impl fmt::Display for IommuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IommuError::NoDmar => f.write_str("no DMAR table")
+          , IommuError::NoUnit => f.write_str("no remapping unit")
+          , IommuError::Unsupported =>
+                f.write_str("remapping unit can't do 4-level tables")
+          , IommuError::NoMemory => f.write_str("out of memory")
+          , IommuError::Timeout => f.write_str("timed out")
+          , IommuError::NoDomains => f.write_str("out of domains")
+          , IommuError::AlreadyAssigned =>
+                f.write_str("device already has a domain")
+          , IommuError::NoSuchDomain(DomainId(id)) =>
+                write!(f, "no domain {}", id)
+          , IommuError::BadRange => f.write_str("bad address range")
+          , IommuError::NoPermissions => f.write_str("no permissions")
+          , IommuError::AlreadyMapped(iova) =>
+                write!(f, "{:#x} is already mapped", iova)
+        }
+    }
+}
+
+/// An entry in the root table.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct RootEntry { /// The context table's address, and `ENTRY_PRESENT`
+                   lo: u64
+                 , hi: u64
+                 }
+
+/// An entry in a context table.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct ContextEntry { /// The second-level table's address, the
+                      /// translation type (0, for untranslated requests
+                      /// only) and `ENTRY_PRESENT`
+                      lo: u64
+                    , /// The domain and the address width
+                      hi: u64
+                    }
+
+/// Write `len` bytes at `ptr` back to memory, unless the unit snoops the
+/// CPU's caches.
+fn flush(coherent: bool, ptr: *const u8, len: usize) {
+    if coherent { return }
+    let end = ptr as usize + len;
+    let mut line = ptr as usize & !(CACHE_LINE - 1);
+    unsafe {
+        while line < end {
+            clflush(line as *const u8);
+            line += CACHE_LINE;
+        }
+        mfence()
+    }
+}
+
+/// Returns a reference to the table in `frame`.
+unsafe fn table_at<L: TableLevel>(frame: PhysicalPage)
+                                 -> &'static mut Table<L> {
+    &mut *phys_to_virt(frame.base_addr()).as_mut_ptr::<Table<L>>()
+}
+
+/// Returns the index of `iova` in a table whose entries each map
+/// `1 << shift` bytes.
+#[inline]
+fn index_of(iova: u64, shift: u64) -> usize {
+    ((iova >> shift) & 0o777) as usize
+}
+
+/// Spin until `done` returns true, for at most `TIMEOUT_NS`.
+fn wait_for<F: Fn() -> bool>(done: F) -> Result<(), IommuError> {
+    let until = tsc::current_ns() + TIMEOUT_NS;
+    while !done() {
+        if tsc::current_ns() >= until { return Err(IommuError::Timeout) }
+    }
+    Ok(())
+}
+
+/// A set of devices that share a second-level page table, and so can all
+/// reach the same memory.
+pub struct Domain { pub id: DomainId
+                  , /// The top-level table
+                    root: PhysicalPage
+                  , /// Every table in the domain, including the top one
+                    tables: Vec<PhysicalPage>
+                  }
+
+impl Domain {
+    fn new(id: DomainId, coherent: bool) -> Result<Domain, IommuError> {
+        let mut domain = Domain { id: id
+                                , root: PhysicalPage { number: 0 }
+                                , tables: Vec::new()
+                                };
+        domain.root = domain.new_table(coherent)?;
+        Ok(domain)
+    }
+
+    /// Allocate an empty table for this domain.
+    fn new_table(&mut self, coherent: bool)
+                -> Result<PhysicalPage, IommuError> {
+        let frame = unsafe { frame::allocator().allocate() }
+            .map_err(|_| IommuError::NoMemory)?;
+        self.tables.push(frame);
+        let table = unsafe { table_at::<PML4Level>(frame) };
+        table.zero();
+        flush(coherent, table as *const _ as *const u8, PAGE_SIZE as usize);
+        Ok(frame)
+    }
+
+    /// Returns the table that entry `i` of `table` points to, adding it if
+    /// there isn't one.
+    fn next_table<L>(&mut self, table: &mut Table<L>, i: usize
+                    , coherent: bool)
+                    -> Result<&'static mut Table<L::Next>, IommuError>
+    where L: Sublevel {
+        if table[i].is_unused() {
+            let frame = self.new_table(coherent)?;
+            // access is controlled by the bottom-level entries, as it is
+            // for the CPU's tables.
+            table[i].set(frame, PRESENT | WRITABLE);
+            flush( coherent, &table[i] as *const Entry as *const u8
+                 , mem::size_of::<Entry>());
+        }
+        Ok(unsafe { table_at(PhysicalPage::containing(table[i].get_addr())) })
+    }
+
+    /// Map `len` bytes at I/O virtual address `iova` to `phys`.
+    ///
+    /// Pages mapped before an error stay mapped.
+    fn map( &mut self, iova: u64, phys: PAddr, len: usize
+          , flags: EntryFlags, coherent: bool)
+          -> Result<(), IommuError> {
+        let pages = (len as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+        for n in 0 .. pages {
+            let (iova, frame) = ( iova + n * PAGE_SIZE
+                                , PhysicalPage::containing(
+                                      phys + n * PAGE_SIZE) );
+            let pml4 = unsafe { table_at::<PML4Level>(self.root) };
+            let pdpt = self.next_table(pml4, index_of(iova, 39), coherent)?;
+            let pd = self.next_table(pdpt, index_of(iova, 30), coherent)?;
+            let pt = self.next_table(pd, index_of(iova, 21), coherent)?;
+            let entry = &mut pt[index_of(iova, 12)];
+            if !entry.is_unused() {
+                return Err(IommuError::AlreadyMapped(iova))
+            }
+            entry.set(frame, flags);
+            flush( coherent, entry as *const Entry as *const u8
+                 , mem::size_of::<Entry>());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Domain {
+    fn drop(&mut self) {
+        for &frame in &self.tables {
+            unsafe { frame::allocator().deallocate(frame) }
+        }
+    }
+}
+
+impl fmt::Debug for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Domain")
+         .field("id", &self.id)
+         .field("root", &self.root.base_addr())
+         .field("tables", &self.tables.len())
+         .finish()
+    }
+}
+
+/// The root and context tables, and the domains they point to.
+struct Tables { root: DmaBox<[RootEntry; N_BUSES]>
+              , /// The context table for each bus, if it has one
+                contexts: Vec<Option<DmaBox<[ContextEntry; N_DEVFNS]>>>
+              , domains: Vec<Domain>
+              , next_domain: u16
+              }
+
+/// A remapping unit.
+pub struct Iommu { regs: MmioRegion
+                 , cap: u64
+                 , ecap: u64
+                 , tables: Mutex<Tables>
+                 }
+
+impl Iommu {
+    /// Find the remapping unit in the ACPI DMAR table `dmar`, and turn on
+    /// DMA remapping, with no device allowed to do DMA.
+    ///
+    /// # Safety
+    /// + Any DMA already going on is cut off.
+    pub unsafe fn init(dmar: &[u8]) -> Result<Iommu, IommuError> {
+        let base = find_unit(dmar).ok_or(IommuError::NoUnit)?;
+        let map = |size| {
+            MmioRegion::new( base, size
+                           , &mut ActivePageTable::new()
+                           , &mut frame::allocator())
+                .map_err(|_| IommuError::NoMemory)
+        };
+        let regs = map(reg::SIZE)?;
+        let (cap, ecap) = (regs.read_u64(reg::CAP), regs.read_u64(reg::ECAP));
+        if cap & CAP_SAGAW_4_LEVEL == 0 { return Err(IommuError::Unsupported) }
+        // the IOTLB registers may be past the first page.
+        let iotlb_end = ((ecap >> ECAP_IRO_SHIFT) & ECAP_IRO_MASK) as usize
+                      * 16 + reg::IOTLB_SIZE;
+        let regs = if iotlb_end > reg::SIZE { map(iotlb_end)? } else { regs };
+
+        let root = DmaBox::<[RootEntry; N_BUSES]>::zeroed()
+            .map_err(|_| IommuError::NoMemory)?;
+        let iommu = Iommu { regs: regs
+                          , cap: cap
+                          , ecap: ecap
+                          , tables: Mutex::new(Tables {
+                                root: root
+                              , contexts: (0..N_BUSES).map(|_| None).collect()
+                              , domains: Vec::new()
+                                // domain 0 is reserved in caching mode.
+                              , next_domain: 1
+                            })
+                          };
+        iommu.enable()?;
+        Ok(iommu)
+    }
+
+    /// Returns true if the unit snoops the CPU's caches.
+    #[inline]
+    fn coherent(&self) -> bool { self.ecap & ECAP_COHERENT != 0 }
+
+    /// Returns the number of domains the unit supports.
+    #[inline]
+    pub fn max_domains(&self) -> u32 {
+        1 << (4 + 2 * (self.cap & CAP_ND_MASK) as u32)
+    }
+
+    /// Point the unit at the (empty) root table and turn translation on.
+    fn enable(&self) -> Result<(), IommuError> {
+        {
+            let tables = self.tables.lock();
+            flush( self.coherent(), tables.root.as_ptr() as *const u8
+                 , mem::size_of::<[RootEntry; N_BUSES]>());
+            self.regs.write_u64(reg::RTADDR, *tables.root.paddr());
+        }
+        self.command(GCMD_SRTP)?;
+        self.invalidate_contexts()?;
+        self.invalidate_iotlb(IOTLB_GLOBAL)?;
+        self.command(GCMD_TE)
+    }
+
+    /// Set `bit` in the global command register, and wait for the unit to
+    /// set it in the status register.
+    fn command(&self, bit: u32) -> Result<(), IommuError> {
+        let status = self.regs.read_u32(reg::GSTS) & GSTS_PERSISTENT;
+        self.regs.write_u32(reg::GCMD, status | bit);
+        wait_for(|| self.regs.read_u32(reg::GSTS) & bit != 0)
+    }
+
+    /// Make sure the unit sees our writes to the tables, if it needs to be
+    /// told.
+    fn flush_write_buffer(&self) -> Result<(), IommuError> {
+        if self.cap & CAP_RWBF == 0 { return Ok(()) }
+        let status = self.regs.read_u32(reg::GSTS) & GSTS_PERSISTENT;
+        self.regs.write_u32(reg::GCMD, status | GCMD_WBF);
+        wait_for(|| self.regs.read_u32(reg::GSTS) & GCMD_WBF == 0)
+    }
+
+    /// Invalidate every cached context entry.
+    fn invalidate_contexts(&self) -> Result<(), IommuError> {
+        self.regs.write_u64(reg::CCMD, CCMD_ICC | CCMD_GLOBAL);
+        wait_for(|| self.regs.read_u64(reg::CCMD) & CCMD_ICC == 0)
+    }
+
+    /// Invalidate cached translations, with `granularity` saying which.
+    fn invalidate_iotlb(&self, granularity: u64) -> Result<(), IommuError> {
+        let iotlb = ((self.ecap >> ECAP_IRO_SHIFT) & ECAP_IRO_MASK) as usize
+                  * 16 + reg::IOTLB;
+        self.regs.write_u64(iotlb, IOTLB_IVT | IOTLB_DRAIN | granularity);
+        wait_for(|| self.regs.read_u64(iotlb) & IOTLB_IVT == 0)
+    }
+
+    /// Give `dev` a new, empty domain, so that it may do DMA to whatever is
+    /// mapped in it.
+    pub fn create_domain(&self, dev: &PciDevice)
+                        -> Result<DomainId, IommuError> {
+        let coherent = self.coherent();
+        let bus = dev.bus as usize;
+        let devfn = (dev.slot << 3 | dev.function) as usize;
+        let mut guard = self.tables.lock();
+        let tables = &mut *guard;
+        if tables.next_domain as u32 >= self.max_domains() {
+            return Err(IommuError::NoDomains)
+        }
+        if tables.contexts[bus].is_none() {
+            let context = unsafe {
+                DmaBox::<[ContextEntry; N_DEVFNS]>::zeroed()
+            }.map_err(|_| IommuError::NoMemory)?;
+            flush( coherent, context.as_ptr() as *const u8
+                 , mem::size_of::<[ContextEntry; N_DEVFNS]>());
+            tables.root[bus].lo = *context.paddr() | ENTRY_PRESENT;
+            flush( coherent, &tables.root[bus] as *const _ as *const u8
+                 , mem::size_of::<RootEntry>());
+            tables.contexts[bus] = Some(context);
+        }
+        let context = tables.contexts[bus].as_mut()
+                            .expect("context table was just added!");
+        if context[devfn].lo & ENTRY_PRESENT != 0 {
+            return Err(IommuError::AlreadyAssigned)
+        }
+
+        let id = DomainId(tables.next_domain);
+        let domain = Domain::new(id, coherent)?;
+        context[devfn].hi = CONTEXT_AW_4_LEVEL
+                          | (id.0 as u64) << CONTEXT_DID_SHIFT;
+        context[devfn].lo = *domain.root.base_addr() | ENTRY_PRESENT;
+        flush( coherent, &context[devfn] as *const _ as *const u8
+             , mem::size_of::<ContextEntry>());
+        tables.domains.push(domain);
+        tables.next_domain += 1;
+
+        // in caching mode, the entry may have been cached as not present.
+        self.flush_write_buffer()?;
+        self.invalidate_contexts()?;
+        self.invalidate_iotlb( IOTLB_DOMAIN
+                             | (id.0 as u64) << IOTLB_DID_SHIFT)?;
+        debug!("iommu: {:?} is in domain {}", dev, id.0);
+        Ok(id)
+    }
+
+    /// Let the devices in `domain` access the `len` bytes at `phys`, at I/O
+    /// virtual address `iova`, with permissions `perms`.
+    ///
+    /// `iova` and `phys` must be page aligned; `len` is rounded up to a
+    /// whole number of pages.
+    pub fn map( &self, domain: DomainId, iova: u64, phys: PAddr, len: usize
+              , perms: IoPerms)
+              -> Result<(), IommuError> {
+        if perms.is_empty() { return Err(IommuError::NoPermissions) }
+        let in_range = match iova.checked_add(len as u64) {
+            Some(end) => end <= IOVA_END
+          , None => false
+        };
+        if len == 0 || !in_range || iova % PAGE_SIZE != 0
+                    || !phys.is_page_aligned() {
+            return Err(IommuError::BadRange)
+        }
+        {
+            let mut tables = self.tables.lock();
+            tables.domains.iter_mut()
+                  .find(|d| d.id == domain)
+                  .ok_or(IommuError::NoSuchDomain(domain))?
+                  .map(iova, phys, len, perms.entry_flags(), self.coherent())?;
+        }
+        self.flush_write_buffer()?;
+        if self.cap & CAP_CM != 0 {
+            self.invalidate_iotlb( IOTLB_DOMAIN
+                                 | (domain.0 as u64) << IOTLB_DID_SHIFT)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Iommu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "Iommu {{ regs: {:?}, cap: {:#x}, ecap: {:#x} }}"
+              , self.regs, self.cap, self.ecap )
+    }
+}
+
+/// Returns the register base of the remapping unit in `dmar` for PCI
+/// segment 0 that covers every device not listed under another unit, or
+/// failing that, the first one.
+fn find_unit(dmar: &[u8]) -> Option<PAddr> {
+    let mut first = None;
+    let mut offset = DMAR_STRUCTURES;
+    while offset + 4 <= dmar.len() {
+        let kind = read_u16(dmar, offset);
+        let len = read_u16(dmar, offset + 2) as usize;
+        // a zero length would have us looking at this structure forever.
+        if len < 4 || offset + len > dmar.len() { break }
+        let unit = &dmar[offset .. offset + len];
+        if kind == DRHD && len >= drhd::LEN
+                        && read_u16(unit, drhd::SEGMENT) == 0 {
+            let base = PAddr::from(read_u64(unit, drhd::REGISTER_BASE));
+            if unit[drhd::FLAGS] & DRHD_INCLUDE_PCI_ALL != 0 {
+                return Some(base)
+            }
+            first = first.or(Some(base));
+        }
+        offset += len;
+    }
+    first
+}
+
+static IOMMU: Once<Iommu> = Once::new();
+
+/// Find the remapping unit through the ACPI DMAR table, and turn on DMA
+/// remapping.
+///
+/// # Safety
+/// + This must be called once, after the kernel has been remapped.
+/// + Any DMA already going on is cut off.
+pub unsafe fn init() -> Result<(), IommuError> {
+    let dmar = acpi::find_sdt(b"DMAR").map_err(|_| IommuError::NoDmar)?;
+    let iommu = Iommu::init(dmar)?;
+    info!( "iommu: remapping on, {} domains, {}coherent"
+         , iommu.max_domains(), if iommu.coherent() { "" } else { "not " });
+    IOMMU.call_once(|| iommu);
+    Ok(())
+}
+
+/// Returns the remapping unit, if DMA remapping is on.
+#[inline]
+pub fn iommu() -> Option<&'static Iommu> { IOMMU.try() }
@@ -0,0 +1,100 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Kernel address space layout randomization (KASLR) slides.
+//!
+//! A slide is how far the kernel is moved from the address it was linked
+//! at: a multiple of 2 MiB, so that the kernel's huge-page mappings still
+//! line up, below 128 MiB.
+//!
+//! The kernel is linked at 1 MiB and runs identity mapped, so moving it
+//! means moving it in physical memory too. With the `kaslr` feature,
+//! `build.rs` makes a table of every place the linker wrote an absolute
+//! address into the image, from the relocations `--emit-relocs` leaves in
+//! the last image linked, which `$SOS_KASLR` names; `make kernel-kaslr`
+//! links twice to get one. The boot stub copies the image up by a random
+//! slide, to somewhere the memory map says is free, adds the slide at each
+//! place in the table, writes it to [`KASLR_SLIDE`](static.KASLR_SLIDE.html)
+//! in the copy, and carries on booting there. Without a table (or without
+//! anywhere free to go), the kernel stays where it was linked, and the
+//! slide is 0.
+//!
+//! ELF section addresses from the bootloader are still link-time ones, so
+//! `arch_init` adds the slide to them, as `kernel_remap` does for each
+//! section it maps.
+//!
+//! Symbol tables hold link-time addresses, so with the `kallsyms` feature,
+//! [`sym_lookup_addr`](fn.sym_lookup_addr.html) takes the slide off an
+//! address before looking it up.
+use core::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "kallsyms")] use memory::VAddr;
+#[cfg(feature = "kallsyms")] use module::kallsyms::{self, SymbolName};
+
+use super::rng;
+
+/// Slides are multiples of this, so that 2 MiB pages stay aligned.
+pub const SLIDE_ALIGN: u64 = 2 * 1024 * 1024;
+
+/// Slides are less than this.
+pub const SLIDE_MAX: u64 = 128 * 1024 * 1024;
+
+/// How far the running kernel has been moved from where it was linked.
+///
+/// The boot stub writes this, in the copy of the kernel it jumps to.
+#[no_mangle]
+pub static KASLR_SLIDE: AtomicU64 = AtomicU64::new(0);
+
+// `KASLR_RELOCS`, the places the boot stub adds the slide to.
+#[cfg(feature = "kaslr")]
+include!(concat!(env!("OUT_DIR"), "/kaslr_relocs.rs"));
+
+/// Returns how far the running kernel has been moved from where it was
+/// linked.
+#[inline]
+pub fn slide() -> u64 { KASLR_SLIDE.load(Ordering::Relaxed) }
+
+/// Returns a slide chosen by `entropy`: a multiple of `SLIDE_ALIGN` in
+/// `[0, SLIDE_MAX)`.
+///
+/// Only the bits of `entropy` between the two are used, so it should be
+/// random in those bits (which the low bits of the TSC aren't).
+#[inline]
+pub fn compute_slide(entropy: u64) -> u64 {
+    entropy & (SLIDE_MAX - 1) & !(SLIDE_ALIGN - 1)
+}
+
+/// Returns a random slide.
+#[inline]
+pub fn random_slide() -> u64 { compute_slide(rng::next_u64()) }
+
+/// Returns where the link-time address `addr` ends up once the kernel has
+/// been moved by `slide`.
+#[inline]
+pub fn apply_slide(addr: u64, slide: u64) -> u64 { addr + slide }
+
+/// Returns the link-time address of `addr` in a kernel moved by `slide`,
+/// which is what the addresses in a symbol table are.
+#[inline]
+pub fn remove_slide(addr: u64, slide: u64) -> u64 { addr - slide }
+
+/// Returns the kernel symbol that `addr`, an address in the running kernel,
+/// is in.
+///
+/// The symbol's `addr` is where it is in the running kernel, too.
+#[cfg(feature = "kallsyms")]
+pub fn sym_lookup_addr(addr: VAddr) -> Option<SymbolName> {
+    let slide = slide();
+    let addr = addr.as_usize() as u64;
+    if addr < slide { return None }
+    kallsyms::name_of(VAddr::from(remove_slide(addr, slide) as usize))
+        .map(|mut symbol| {
+            let linked = symbol.addr.as_usize() as u64;
+            symbol.addr = VAddr::from(apply_slide(linked, slide) as usize);
+            symbol
+        })
+}
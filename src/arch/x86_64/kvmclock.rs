@@ -0,0 +1,140 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The KVM paravirtual clock.
+//!
+//! Under KVM, the TSC we calibrated against the PIT is only as good as the
+//! PIT emulation, and may not tick steadily if the host migrates us. KVM
+//! can instead tell us how to turn the TSC into nanoseconds itself: we give
+//! it the physical address of a [`KvmClockData`], and it keeps the TSC
+//! scale and the time at the last update there.
+//!
+//! The host may rewrite the structure at any time, so it's read with a
+//! seqlock: the version is odd while an update is in progress, and changes
+//! whenever one has happened.
+//!
+//! Only the bootstrap processor registers a structure. The others read
+//! its copy, which is fine as long as the host keeps the TSCs in sync.
+//!
+//! [`KvmClockData`]: struct.KvmClockData.html
+use core::{mem, ptr};
+use core::sync::atomic::{fence, Ordering};
+use cpu::cpuid::{self, cpuid};
+use cpu::msr;
+use cpu::timer::timestamp;
+use cpu::tsc;
+use memory::PAGE_SIZE;
+
+/// The time information KVM shares with the guest, laid out as in the KVM
+/// ABI (`struct pvclock_vcpu_time_info`).
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct KvmClockData { /// Odd while the host is updating the rest
+                          pub version: u32
+                        , _pad0: u32
+                        , /// The TSC when `system_time` was taken
+                          pub tsc_timestamp: u64
+                        , /// Nanoseconds since boot, at `tsc_timestamp`
+                          pub system_time: u64
+                        , /// TSC ticks to nanoseconds, as a 32.32 fixed
+                          /// point multiplier, after the shift
+                          pub tsc_to_system_mul: u32
+                        , /// Shift TSC ticks left by this (right, if it's
+                          /// negative) before multiplying
+                          pub tsc_shift: i8
+                        , pub flags: u8
+                        , _pad: [u8; 2]
+                        }
+
+/// Bit 0 of `MSR_KVM_SYSTEM_TIME_NEW` turns the clock on.
+const SYSTEM_TIME_ENABLE: u64 = 1;
+
+/// The structure registered with KVM.
+///
+/// The kernel image is identity mapped, so its address is also its
+/// physical address.
+static mut CLOCK: KvmClockData = KvmClockData { version: 0
+                                              , _pad0: 0
+                                              , tsc_timestamp: 0
+                                              , system_time: 0
+                                              , tsc_to_system_mul: 0
+                                              , tsc_shift: 0
+                                              , flags: 0
+                                              , _pad: [0; 2]
+                                              };
+
+/// Returns true if we're running under KVM, and it has the new clock MSR.
+fn kvm_has_clock() -> bool {
+    if cpuid(cpuid::LEAF_FEATURES, 0).ecx & cpuid::ECX_HYPERVISOR == 0 {
+        return false
+    }
+    let id = cpuid(cpuid::LEAF_HYPERVISOR, 0);
+    let mut signature = [0u8; 12];
+    for (i, reg) in [id.ebx, id.ecx, id.edx].iter().enumerate() {
+        for byte in 0 .. 4 {
+            signature[i * 4 + byte] = (reg >> (byte * 8)) as u8;
+        }
+    }
+    &signature == cpuid::KVM_SIGNATURE
+        && cpuid(cpuid::LEAF_KVM_FEATURES, 0).eax
+           & cpuid::KVM_FEATURE_CLOCKSOURCE2 != 0
+}
+
+/// Use the KVM clock for timekeeping, if we're running under KVM.
+///
+/// Returns true if it's being used.
+///
+/// # Safety
+/// + This must be called once, on the bootstrap processor, before anything
+///   has read the time.
+pub unsafe fn init() -> bool {
+    if !kvm_has_clock() { return false }
+    let addr = &CLOCK as *const KvmClockData as u64;
+    // KVM won't accept a structure that crosses a page boundary.
+    let size = mem::size_of::<KvmClockData>() as u64;
+    if addr / PAGE_SIZE != (addr + size - 1) / PAGE_SIZE {
+        warn!("kvmclock: time info at {:#x} crosses a page", addr);
+        return false
+    }
+    msr::write(msr::MSR_KVM_SYSTEM_TIME_NEW, addr | SYSTEM_TIME_ENABLE);
+    tsc::set_clock_source(kvm_clock_ns);
+    true
+}
+
+/// Read a consistent copy of the time info.
+fn snapshot() -> (KvmClockData, u64) {
+    loop {
+        unsafe {
+            let version = ptr::read_volatile(&CLOCK.version);
+            // the host is partway through an update.
+            if version & 1 != 0 { continue }
+            fence(Ordering::Acquire);
+            let data = ptr::read_volatile(&CLOCK);
+            let now = timestamp::rtdsc();
+            fence(Ordering::Acquire);
+            if ptr::read_volatile(&CLOCK.version) == version {
+                return (data, now)
+            }
+        }
+    }
+}
+
+/// Returns the number of nanoseconds since boot, according to KVM.
+pub fn kvm_clock_ns() -> u64 {
+    let (data, now) = snapshot();
+    let mut delta = now.wrapping_sub(data.tsc_timestamp);
+    if data.tsc_shift < 0 {
+        delta >>= -data.tsc_shift as u32;
+    } else {
+        delta <<= data.tsc_shift as u32;
+    }
+    // `(delta * mul) >> 32`, without a 128-bit product.
+    let mul = data.tsc_to_system_mul as u64;
+    let scaled = (delta >> 32) * mul + ((delta & 0xffff_ffff) * mul >> 32);
+    data.system_time.wrapping_add(scaled)
+}
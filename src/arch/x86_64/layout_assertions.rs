@@ -0,0 +1,79 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Checks on the layout of structs whose layout is defined by the CPU.
+//!
+//! Adding a field to one of these, or changing a field's type, quietly
+//! breaks everything that hands it to the hardware, so the layouts are
+//! pinned down here.
+//!
+//! Sizes are checked at compile time, with `mem::transmute`, which won't
+//! compile unless both of its types are the same size. The compiler can't
+//! evaluate alignments, field offsets or bitflags in a constant yet, so
+//! those are checked by [`check`](fn.check.html) at boot instead.
+//!
+//! `KernelTls` is here too: its layout is up to us, but assembly relies on
+//! its field offsets.
+use core::mem;
+use cpu::interrupts::idt::Gate;
+use cpu::task::StateSegment;
+use paging::arch::table::{ Entry, PML4Level, Table, COPY_ON_WRITE, HUGE_PAGE
+                         , PRESENT, SWAPPED };
+
+use super::tls::{offsets, KernelTls};
+
+/// Fail to compile unless `$ty` is `$size` bytes.
+macro_rules! assert_size {
+    ($ty:ty, $size:expr) => {
+        let _ = |value: $ty| -> [u8; $size] {
+            unsafe { mem::transmute(value) }
+        };
+    }
+}
+
+/// Returns the offset of `$field` in `$value`, in bytes.
+macro_rules! offset_of {
+    ($value:expr, $field:ident) => {
+        &$value.$field as *const _ as usize - &$value as *const _ as usize
+    }
+}
+
+/// Never called: this only has to compile.
+#[allow(dead_code)]
+fn sizes() {
+    assert_size!(Entry, 8);
+    assert_size!(Table<PML4Level>, 4096);
+    assert_size!(Gate, 16);
+    assert_size!(StateSegment, 104);
+}
+
+/// Check the parts of the layouts that can't be checked at compile time.
+///
+/// # Panics
+/// + If any of them are wrong.
+pub fn check() {
+    assert_eq!( mem::align_of::<Table<PML4Level>>(), 4096
+              , "page tables must be page aligned!");
+    assert_eq!(PRESENT.bits(), 1 << 0, "wrong bit for PRESENT!");
+    assert_eq!(HUGE_PAGE.bits(), 1 << 7, "wrong bit for HUGE_PAGE!");
+    // bits 9 to 11 are the only ones the CPU leaves to software.
+    assert_eq!(COPY_ON_WRITE.bits(), 1 << 9, "wrong bit for COPY_ON_WRITE!");
+    assert_eq!(SWAPPED.bits(), 1 << 10, "wrong bit for SWAPPED!");
+
+    let tss = StateSegment::new();
+    // `rsp[0]` is `rsp0`, and `ist[0]` is `ist1`.
+    assert_eq!(offset_of!(tss, rsp), 4, "wrong offset for the TSS's rsp0!");
+    assert_eq!(offset_of!(tss, ist), 36, "wrong offset for the TSS's ist1!");
+
+    let tls = KernelTls::new();
+    assert_eq!(offset_of!(tls, errno), offsets::ERRNO);
+    assert_eq!(offset_of!(tls, preempt_count), offsets::PREEMPT_COUNT);
+    assert_eq!(offset_of!(tls, in_irq), offsets::IN_IRQ);
+    assert_eq!(offset_of!(tls, current), offsets::CURRENT);
+    assert_eq!(offset_of!(tls, cpu_id), offsets::CPU_ID);
+}
@@ -0,0 +1,169 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Filling and copying large buffers.
+//!
+//! On CPUs with AVX2, [`fast_memset`] and [`fast_memcpy`] move 32 bytes per
+//! instruction through `%ymm0`. The destination is brought up to a 32-byte
+//! boundary a byte at a time, the aligned middle is done in 32-byte chunks,
+//! and whatever is left over is done a byte at a time again. Without AVX2,
+//! they're plain byte loops.
+//!
+//! The vector registers may hold the current task's state, so the chunk
+//! loops save `%ymm0` on the stack first, and put it back when they're done.
+//!
+//! [`fast_memset`]: fn.fast_memset.html
+//! [`fast_memcpy`]: fn.fast_memcpy.html
+use core::cmp;
+use core::sync::atomic::{ compiler_fence, AtomicBool, Ordering
+                        , ATOMIC_BOOL_INIT };
+use cpu::cpuid::{self, cpuid};
+
+use super::fpu;
+
+/// The number of bytes moved by one AVX2 store.
+pub const CHUNK_SIZE: usize = 32;
+
+static USE_AVX2: AtomicBool = ATOMIC_BOOL_INIT;
+
+// `avx2_set_chunks(dst, pattern, len)` fills `len` bytes at `dst` with the
+// 32 bytes at `pattern`, and `avx2_copy_chunks(dst, src, len)` copies `len`
+// bytes from `src` to `dst`. `len` must be a multiple of 32 in both.
+global_asm!("
+    .intel_syntax noprefix
+    .global avx2_set_chunks
+avx2_set_chunks:
+    sub     rsp, 32
+    vmovdqu [rsp], ymm0
+    vmovdqu ymm0, [rsi]
+    test    rdx, rdx
+    jz      2f
+1:
+    vmovdqu [rdi], ymm0
+    add     rdi, 32
+    sub     rdx, 32
+    jnz     1b
+2:
+    vmovdqu ymm0, [rsp]
+    add     rsp, 32
+    ret
+
+    .global avx2_copy_chunks
+avx2_copy_chunks:
+    sub     rsp, 32
+    vmovdqu [rsp], ymm0
+    test    rdx, rdx
+    jz      2f
+1:
+    vmovdqu ymm0, [rsi]
+    vmovdqu [rdi], ymm0
+    add     rsi, 32
+    add     rdi, 32
+    sub     rdx, 32
+    jnz     1b
+2:
+    vmovdqu ymm0, [rsp]
+    add     rsp, 32
+    ret
+    .att_syntax
+");
+
+extern {
+    fn avx2_set_chunks(dst: *mut u8, pattern: *const u8, len: usize);
+    fn avx2_copy_chunks(dst: *mut u8, src: *const u8, len: usize);
+}
+
+/// Use AVX2 for large fills and copies, if the CPU has it and the FPU has
+/// been set up to save AVX state.
+///
+/// Returns true if AVX2 is being used.
+///
+/// # Safety
+/// + This must be called once, on the bootstrap processor, after
+///   [`fpu::init`](../fpu/fn.init.html).
+pub unsafe fn init() -> bool {
+    let max_leaf = cpuid(0, 0).eax;
+    if max_leaf < cpuid::LEAF_EXT_FEATURES || !fpu::avx_enabled() {
+        return false
+    }
+    let features = cpuid(cpuid::LEAF_EXT_FEATURES, 0);
+    let avx2 = features.ebx & cpuid::EBX_AVX2 != 0;
+    USE_AVX2.store(avx2, Ordering::Relaxed);
+    avx2
+}
+
+/// Returns true if [`fast_memset`] and [`fast_memcpy`] are using AVX2.
+///
+/// [`fast_memset`]: fn.fast_memset.html
+/// [`fast_memcpy`]: fn.fast_memcpy.html
+#[inline]
+pub fn avx2_enabled() -> bool { USE_AVX2.load(Ordering::Relaxed) }
+
+/// Returns the number of bytes from `addr` to the next 32-byte boundary,
+/// but no more than `len`.
+#[inline]
+fn head_len(addr: usize, len: usize) -> usize {
+    cmp::min((CHUNK_SIZE - addr % CHUNK_SIZE) % CHUNK_SIZE, len)
+}
+
+#[inline]
+unsafe fn set_bytes(dst: *mut u8, val: u8, len: usize) {
+    for i in 0 .. len as isize { *dst.offset(i) = val }
+}
+
+#[inline]
+unsafe fn copy_bytes(dst: *mut u8, src: *const u8, len: usize) {
+    for i in 0 .. len as isize { *dst.offset(i) = *src.offset(i) }
+}
+
+/// Fill the `len` bytes at `dst` with `val`.
+///
+/// # Safety
+/// + `dst` must be valid for `len` bytes of writes.
+pub unsafe fn fast_memset(dst: *mut u8, val: u8, len: usize) {
+    if !avx2_enabled() || len < CHUNK_SIZE {
+        return set_bytes(dst, val, len)
+    }
+    let head = head_len(dst as usize, len);
+    let middle = (len - head) & !(CHUNK_SIZE - 1);
+    let pattern = [val; CHUNK_SIZE];
+    set_bytes(dst, val, head);
+    // the chunk loop is out of the compiler's sight, so keep the byte loops
+    // on either side of it from being moved across it.
+    compiler_fence(Ordering::SeqCst);
+    fpu::with_kernel_fpu(|| {
+        avx2_set_chunks(dst.offset(head as isize), pattern.as_ptr(), middle)
+    });
+    compiler_fence(Ordering::SeqCst);
+    set_bytes( dst.offset((head + middle) as isize), val
+             , len - head - middle );
+}
+
+/// Copy `len` bytes from `src` to `dst`.
+///
+/// # Safety
+/// + `src` must be valid for `len` bytes of reads, and `dst` for `len`
+///   bytes of writes.
+/// + The two must not overlap.
+pub unsafe fn fast_memcpy(dst: *mut u8, src: *const u8, len: usize) {
+    if !avx2_enabled() || len < CHUNK_SIZE {
+        return copy_bytes(dst, src, len)
+    }
+    let head = head_len(dst as usize, len);
+    let middle = (len - head) & !(CHUNK_SIZE - 1);
+    copy_bytes(dst, src, head);
+    compiler_fence(Ordering::SeqCst);
+    fpu::with_kernel_fpu(|| {
+        avx2_copy_chunks( dst.offset(head as isize)
+                        , src.offset(head as isize)
+                        , middle )
+    });
+    compiler_fence(Ordering::SeqCst);
+    let done = (head + middle) as isize;
+    copy_bytes(dst.offset(done), src.offset(done), len - head - middle);
+}
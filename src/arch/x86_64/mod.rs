@@ -7,9 +7,35 @@
 //  directory of this repository for more information.
 //
 //! `x86_64` architecture-specific implementation.
-// pub mod cpu;
+pub mod acpi;
+pub mod apic;
+pub mod boot_args;
+pub mod context;
+pub mod cpu;
+pub mod crc32c;
 pub mod drivers;
+pub mod extable;
+pub mod fpu;
+pub mod gdt;
+pub mod hpet;
 pub mod interrupts;
+pub mod iommu;
+pub mod kaslr;
+pub mod kvmclock;
+mod layout_assertions;
+pub mod memops;
+pub mod mtrr;
+pub mod numa;
+pub mod pcid;
+pub mod percpu;
+pub mod reset;
+pub mod rng;
+pub mod smp;
+pub mod syscall;
+pub mod tlb_shootdown;
+pub mod topology;
+#[macro_use] pub mod tls;
+pub mod uefi;
 
 #[path = "../x86_all/bda.rs"] pub mod bda;
 #[path = "../x86_all/multiboot2.rs"] pub mod multiboot2;
@@ -59,33 +85,56 @@ pub unsafe extern "C" fn long_mode_init() {
 
 /// Entry point for architecture-specific kernel init
 ///
-/// This expects to be passed the address of a valid
-/// Multiboot 2 info struct. It's the bootloader's responsibility to ensure
-/// that this is passed in the correct register as expected by the calling
-/// convention (`edi` on x86). If this isn't there, you can expect to have a
-/// bad problem and not go to space today.
+/// This expects to be passed the magic number the bootloader left in `eax`,
+/// and the pointer it left in `ebx`, in `edi` and `esi` as the calling
+/// convention expects; see [`boot_args`](boot_args/index.html) for the
+/// bootloaders we know. If they aren't there, you can expect to have a bad
+/// problem and not go to space today.
+///
+/// Only Multiboot 2 gets all the way through for now: remapping the kernel
+/// needs the ELF sections from its info structure.
 #[no_mangle]
-pub extern "C" fn arch_init(multiboot_addr: PAddr) {
+pub extern "C" fn arch_init(magic: u32, boot_ptr: *const u8) {
     use cpu::{control_regs, msr};
-    use params::{InitParams, mem};
+    use params::InitParams;
+    use self::boot_args::BootArgs;
 
     kinfoln!(dots: " . ", "Beginning `arch_init()` for x86_64");
+    layout_assertions::check();
 
     ::io::term::CONSOLE.lock().clear();
-    ::logger::initialize()
+    // calibrate the TSC first, so that log timestamps are meaningful.
+    let tsc_khz = unsafe { ::cpu::tsc::calibrate() };
+    let kvmclock = unsafe { kvmclock::init() };
+    #[cfg(feature = "logging")]
+    ::logger::KernelLogger::init()
         .expect("Could not initialize logger!");
+    info!("TSC frequency: {} kHz", tsc_khz);
+    if kvmclock { info!("using the KVM clock for timekeeping"); }
 
 
-    // -- Unpack multiboot tag ------------------------------------------------
-    kinfoln!( dots: " . "
-            , "trying to unpack multiboot info at {:?}"
-            , multiboot_addr);
+    // -- Work out who booted us ----------------------------------------------
+    let boot_args = unsafe { BootArgs::from_entry(magic, boot_ptr) }
+        .unwrap_or_else(|| panic!( "Unknown bootloader (magic {:#x})!"
+                                 , magic));
+    kinfoln!(dots: " . ", "Booted by {}", boot_args);
+    if let BootArgs::Uefi { system_table, .. } = boot_args {
+        match unsafe { uefi::SystemTable::from_ptr(system_table) } {
+            Some(table) => if let Some(rsdp) = table.acpi_rsdp() {
+                kinfoln!(dots: " . . ", "ACPI RSDP at {:?}", rsdp);
+                acpi::set_rsdp(rsdp);
+            }
+          , None => warn!("UEFI system table at {:p} is bad", system_table)
+        }
+    }
 
+    // -- Unpack multiboot tag ------------------------------------------------
     // try to interpret the structure at the multiboot address as a multiboot
     // info struct. if it's invalid, fail.
     let boot_info
-        = unsafe { multiboot2::Info::from(multiboot_addr)
-                    .expect("Could not unpack multiboot2 information!") };
+        = boot_args.multiboot_info()
+                   .expect("Could not unpack multiboot2 information!");
+    let multiboot_addr = boot_info.start_addr();
 
     // Extract ELF sections tag from the multiboot info
     let elf_sections_tag
@@ -118,6 +167,14 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
                     \nSomething is deeply wrong.");
 
     kinfoln!( dots: " . ", "Detected {} kernel ELF sections.", n_elf_sections);
+
+    // the sections are where the kernel was linked; the boot stub may have
+    // moved it since.
+    let slide = kaslr::slide();
+    let (kernel_begin, kernel_end) = (kernel_begin + slide, kernel_end + slide);
+    if slide != 0 {
+        kinfoln!(dots: " . . ", "Kernel was moved up by {:#x}.", slide);
+    }
     kinfoln!( dots: " . . ", "Kernel begins at {:#p} and ends at {:#p}."
             , kernel_begin, kernel_end );
 
@@ -128,6 +185,7 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
 
     let mut params = InitParams { kernel_base: kernel_begin
                             , kernel_top: kernel_end
+                            , kernel_slide: slide
                             , multiboot_start: Some(multiboot_addr)
                             , multiboot_end: Some(multiboot_end)
                             , heap_base: unsafe { PAddr::from(HEAP_BASE) }
@@ -138,16 +196,24 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
                             , ..Default::default()
                         };
 
-    // Extract the memory map tag from the multiboot info
-    let mem_map = boot_info.mem_map()
-                           .expect("Memory map tag required!");
+    // the first boot module, if there is one, is the initial ramdisk.
+    if let Some(module) = boot_info.modules().next() {
+        kinfoln!( dots: " . . ", "Initial ramdisk begins at {:#x} and ends at \
+                                  {:#x}."
+                , module.start_addr(), module.end_addr() );
+        params.initrd_start = Some(module.start_addr());
+        params.initrd_end = Some(module.end_addr());
+    }
 
+    // Extract the memory map from the boot info
     kinfoln!(dots: " . ", "Detected memory areas:");
-    for area in mem_map {
-        kinfoln!( dots: " . . ", "{}", area);
-        let a: mem::Area = area.into();
-        if a.is_usable == true { params.mem_map.push(a); }
+    for area in boot_args.memory_map() {
+        kinfoln!( dots: " . . ", "{:#08x} to {:#08x}{}"
+                , area.start_addr, area.end_addr
+                , if area.is_usable { "" } else { " (reserved)" });
+        if area.is_usable { params.mem_map.push(area); }
     }
+    assert!(!params.mem_map.is_empty(), "Memory map required!");
 
      //-- enable flags needed for paging ------------------------------------
      unsafe {
@@ -160,6 +226,48 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
         let efer = msr::read(msr::IA32_EFER);
         trace!("EFER = {:#x}", efer);
         kinfoln!(dots: " . ", "Page no execute bit ENABLED");
+
+        percpu::init_bsp(STACK_TOP as u64);
+        syscall::init();
+        kinfoln!(dots: " . ", "System calls ENABLED");
+
+        if fpu::init() {
+            kinfoln!(dots: " . ", "FPU ENABLED, using XSAVE");
+        } else {
+            kinfoln!(dots: " . ", "FPU ENABLED, using FXSAVE");
+        }
+
+        if memops::init() {
+            kinfoln!(dots: " . ", "AVX2 memset and memcpy ENABLED");
+        }
+
+        if crc32c::init() {
+            kinfoln!(dots: " . ", "SSE4.2 CRC32C ENABLED");
+        }
+
+        rng::init();
+        if rng::has_rdrand() {
+            kinfoln!(dots: " . ", "RDRAND ENABLED");
+        }
+        if rng::has_rdseed() {
+            kinfoln!(dots: " . ", "RDSEED ENABLED");
+        }
+
+        if mtrr::init() {
+            kinfoln!(dots: " . ", "MTRRs READ");
+        }
+
+        if pcid::init() {
+            kinfoln!(dots: " . ", "PCIDs ENABLED");
+        }
+
+        let protection = extable::init();
+        if protection.contains(control_regs::cr4::SMEP) {
+            kinfoln!(dots: " . ", "SMEP ENABLED");
+        }
+        if protection.contains(control_regs::cr4::SMAP) {
+            kinfoln!(dots: " . ", "SMAP ENABLED");
+        }
      }
 
     kinfoln!(dots: " . ", "Transferring to `kernel_init()`.");
@@ -0,0 +1,317 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Memory type range registers (MTRRs).
+//!
+//! The firmware sets the MTRRs to say how each range of physical memory
+//! may be cached: RAM is usually write-back, and device memory
+//! uncacheable. We only read them, to check that device registers are
+//! mapped uncached.
+//!
+//! # The MTRRs and the PAT
+//!
+//! The memory type a page is actually accessed with combines the MTRR type
+//! of its physical address with the type its page table entry picks from
+//! the page attribute table (PAT). The entry's `WRITE_THROUGH` (PWT),
+//! `NO_CACHE` (PCD) and PAT bits make an index into the PAT; we leave the
+//! PAT as it is at reset, where the first four entries are:
+//!
+//! | `NO_CACHE` | `WRITE_THROUGH` | PAT type |
+//! |------------|-----------------|----------|
+//! | 0          | 0               | WB       |
+//! | 0          | 1               | WT       |
+//! | 1          | 0               | UC-      |
+//! | 1          | 1               | UC       |
+//!
+//! UC in the PAT always wins, whatever the MTRRs say. UC- is uncacheable
+//! too, except that it gives way to WC from the MTRRs, which is what a
+//! framebuffer wants, but would let writes to device registers be combined
+//! and reordered. So [`mmio_flags`](fn.mmio_flags.html) only uses
+//! `NO_CACHE` alone where the MTRRs already say UC or WC, and both bits
+//! everywhere else.
+use core::fmt;
+use cpu::cpuid::{self, cpuid};
+use cpu::msr;
+use memory::PAddr;
+use paging::arch::table::{EntryFlags, NO_CACHE, WRITE_THROUGH};
+use spin::Once;
+
+/// The most variable-range MTRRs we keep.
+pub const MAX_VARIABLE: usize = 16;
+
+/// The number of fixed-range MTRRs.
+pub const NUM_FIXED: usize = 11;
+
+/// Fixed-range MTRRs cover the first MiB.
+const FIXED_END: u64 = 0x10_0000;
+
+/// `IA32_MTRRCAP`: the number of variable-range MTRRs.
+const CAP_VCNT_MASK: u64 = 0xff;
+/// `IA32_MTRRCAP`: fixed-range MTRRs are supported.
+const CAP_FIX: u64 = 1 << 8;
+/// `IA32_MTRR_DEF_TYPE`: fixed-range MTRRs are enabled.
+const DEF_TYPE_FE: u64 = 1 << 10;
+/// `IA32_MTRR_DEF_TYPE`: MTRRs are enabled.
+const DEF_TYPE_E: u64 = 1 << 11;
+/// `IA32_MTRR_PHYSMASKn`: the MTRR is in use.
+const PHYSMASK_VALID: u64 = 1 << 11;
+/// The address bits of `IA32_MTRR_PHYSBASEn` and `IA32_MTRR_PHYSMASKn`.
+const ADDR_MASK: u64 = !0xfff;
+
+/// A memory type.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MemType { /// UC: every access goes to memory, in order
+                   Uncacheable = 0
+                 , /// WC: writes are buffered and combined, reads uncached
+                   WriteCombining = 1
+                 , /// WT: reads are cached, writes go straight to memory
+                   WriteThrough = 4
+                 , /// WP: reads are cached, writes aren't
+                   WriteProtect = 5
+                 , /// WB: reads and writes are cached
+                   WriteBack = 6
+                 }
+
+impl MemType {
+    /// Returns the memory type encoded as `bits`, if it's a valid one.
+    pub fn from_bits(bits: u8) -> Option<MemType> {
+        match bits {
+            0 => Some(MemType::Uncacheable)
+          , 1 => Some(MemType::WriteCombining)
+          , 4 => Some(MemType::WriteThrough)
+          , 5 => Some(MemType::WriteProtect)
+          , 6 => Some(MemType::WriteBack)
+          , _ => None
+        }
+    }
+
+    /// Returns true if device registers can be mapped with this type.
+    #[inline]
+    pub fn is_uncached(&self) -> bool {
+        match *self {
+            MemType::Uncacheable | MemType::WriteCombining => true
+          , _ => false
+        }
+    }
+}
+
+/// A variable-range MTRR.
+#[derive(Copy, Clone, Debug)]
+pub struct MtrrEntry { /// The start of the range
+                       pub base: u64
+                     , /// Which address bits must match `base`'s
+                       pub mask: u64
+                     , pub mem_type: MemType
+                     , /// True if the MTRR is in use
+                       pub valid: bool
+                     }
+
+/// An unused variable-range MTRR.
+const NO_ENTRY: MtrrEntry = MtrrEntry { base: 0
+                                      , mask: 0
+                                      , mem_type: MemType::Uncacheable
+                                      , valid: false
+                                      };
+
+impl MtrrEntry {
+    /// Returns the MTRR with the values `base` and `mask` in its
+    /// `IA32_MTRR_PHYSBASEn` and `IA32_MTRR_PHYSMASKn`.
+    ///
+    /// MTRRs with an invalid type are treated as unused.
+    pub fn from_msrs(base: u64, mask: u64) -> MtrrEntry {
+        match MemType::from_bits(base as u8) {
+            Some(mem_type) => MtrrEntry { base: base & ADDR_MASK
+                                        , mask: mask & ADDR_MASK
+                                        , mem_type: mem_type
+                                        , valid: mask & PHYSMASK_VALID != 0
+                                        }
+          , None => NO_ENTRY
+        }
+    }
+
+    /// Returns true if the MTRR is in use and covers `addr`.
+    #[inline]
+    pub fn contains(&self, addr: u64) -> bool {
+        self.valid && addr & self.mask == self.base & self.mask
+    }
+}
+
+/// The memory types the MTRRs give each range of physical memory.
+#[derive(Copy, Clone)]
+pub struct MtrrMap { /// True if the MTRRs are enabled at all
+                     pub enabled: bool
+                   , /// True if the fixed-range MTRRs are enabled
+                     pub fixed_enabled: bool
+                   , /// The type of memory no MTRR covers
+                     pub default: MemType
+                   , /// The fixed-range MTRRs, as read, each holding the
+                     /// types of eight ranges
+                     pub fixed: [u64; NUM_FIXED]
+                   , pub entries: [MtrrEntry; MAX_VARIABLE]
+                   , /// The number of variable-range MTRRs
+                     pub count: usize
+                   }
+
+impl MtrrMap {
+    /// Returns the map described by the values of `IA32_MTRRCAP`,
+    /// `IA32_MTRR_DEF_TYPE`, the fixed-range MTRRs in order, and the
+    /// base and mask of each variable-range MTRR.
+    ///
+    /// Only the first `MAX_VARIABLE` variable-range MTRRs are kept.
+    pub fn from_msrs( cap: u64, def_type: u64, fixed: [u64; NUM_FIXED]
+                    , variable: &[(u64, u64)]) -> MtrrMap {
+        let mut entries = [NO_ENTRY; MAX_VARIABLE];
+        let mut count = 0;
+        for (entry, &(base, mask)) in entries.iter_mut().zip(variable) {
+            *entry = MtrrEntry::from_msrs(base, mask);
+            count += 1;
+        }
+        MtrrMap { enabled: def_type & DEF_TYPE_E != 0
+                , fixed_enabled: cap & CAP_FIX != 0
+                              && def_type & DEF_TYPE_FE != 0
+                , default: MemType::from_bits(def_type as u8)
+                                   .unwrap_or(MemType::Uncacheable)
+                , fixed: fixed
+                , entries: entries
+                , count: count
+                }
+    }
+
+    /// Read the current CPU's MTRRs.
+    ///
+    /// # Safety
+    /// + The CPU must have MTRRs.
+    pub unsafe fn read() -> MtrrMap {
+        let cap = msr::read(msr::IA32_MTRRCAP);
+        let mut fixed = [0; NUM_FIXED];
+        if cap & CAP_FIX != 0 {
+            fixed[0] = msr::read(msr::IA32_MTRR_FIX64K_00000);
+            fixed[1] = msr::read(msr::IA32_MTRR_FIX16K_80000);
+            fixed[2] = msr::read(msr::IA32_MTRR_FIX16K_A0000);
+            for (i, value) in fixed[3..].iter_mut().enumerate() {
+                *value = msr::read(msr::IA32_MTRR_FIX4K_C0000 + i as u32);
+            }
+        }
+        let mut variable = [(0, 0); MAX_VARIABLE];
+        let count = (cap & CAP_VCNT_MASK) as usize;
+        for (i, msrs) in variable.iter_mut().take(count).enumerate() {
+            let n = 2 * i as u32;
+            *msrs = ( msr::read(msr::IA32_MTRR_PHYSBASE0 + n)
+                    , msr::read(msr::IA32_MTRR_PHYSMASK0 + n));
+        }
+        let count = if count < MAX_VARIABLE { count } else { MAX_VARIABLE };
+        MtrrMap::from_msrs( cap, msr::read(msr::IA32_MTRR_DEF_TYPE), fixed
+                          , &variable[..count])
+    }
+
+    /// Returns the type the fixed-range MTRRs give `addr`, which must be in
+    /// the first MiB.
+    fn lookup_fixed(&self, addr: u64) -> MemType {
+        let (index, range) = match addr {
+            0 ... 0x7_ffff => (0, addr >> 16)
+          , 0x8_0000 ... 0xb_ffff =>
+                (1 + ((addr - 0x8_0000) >> 17), (addr >> 14) & 7)
+          , _ => (3 + ((addr - 0xc_0000) >> 15), (addr >> 12) & 7)
+        };
+        let bits = (self.fixed[index as usize] >> (range * 8)) as u8;
+        MemType::from_bits(bits).unwrap_or(MemType::Uncacheable)
+    }
+
+    /// Returns the memory type of the byte at `phys`.
+    ///
+    /// Where variable-range MTRRs overlap, UC wins, then WT over WB, as
+    /// the CPU does; other overlaps are undefined, and are taken to be UC.
+    pub fn lookup(&self, phys: PAddr) -> MemType {
+        let addr = *phys;
+        if !self.enabled { return MemType::Uncacheable }
+        if self.fixed_enabled && addr < FIXED_END {
+            return self.lookup_fixed(addr)
+        }
+        let mut found = None;
+        for entry in self.entries[..self.count].iter()
+                                               .filter(|e| e.contains(addr)) {
+            found = match (found, entry.mem_type) {
+                (None, mem_type) => Some(mem_type)
+              , (Some(a), b) if a == b => Some(a)
+              , (Some(MemType::WriteThrough), MemType::WriteBack)
+              | (Some(MemType::WriteBack), MemType::WriteThrough) =>
+                    Some(MemType::WriteThrough)
+              , _ => return MemType::Uncacheable
+            };
+        }
+        found.unwrap_or(self.default)
+    }
+
+    /// Returns the memory type of the `len` bytes at `start`, if they all
+    /// have the same type.
+    ///
+    /// MTRRs never cover less than a page, so only one address in each
+    /// page is looked up.
+    pub fn lookup_range(&self, start: PAddr, len: u64) -> Option<MemType> {
+        let first = self.lookup(start);
+        let end = *start + len;
+        let mut page = (*start & !0xfff) + 0x1000;
+        while page < end {
+            if self.lookup(PAddr::from(page)) != first { return None }
+            page += 0x1000;
+        }
+        Some(first)
+    }
+}
+
+impl fmt::Debug for MtrrMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MtrrMap")
+         .field("enabled", &self.enabled)
+         .field("fixed_enabled", &self.fixed_enabled)
+         .field("default", &self.default)
+         .field("entries", &&self.entries[..self.count])
+         .finish()
+    }
+}
+
+/// The bootstrap processor's MTRRs, once they've been read.
+static MTRRS: Once<MtrrMap> = Once::new();
+
+/// Read the bootstrap processor's MTRRs, if it has them.
+///
+/// Returns true if it did.
+///
+/// # Safety
+/// + This must be called once, on the bootstrap processor.
+pub unsafe fn init() -> bool {
+    if cpuid(cpuid::LEAF_FEATURES, 0).edx & cpuid::EDX_MTRR == 0 {
+        return false
+    }
+    let map = MTRRS.call_once(|| MtrrMap::read());
+    debug!("{:?}", map);
+    true
+}
+
+/// Returns the MTRRs, if they've been read.
+#[inline]
+pub fn map() -> Option<&'static MtrrMap> { MTRRS.try() }
+
+/// Returns the caching flags to map the `len` bytes of device registers
+/// at `phys` with.
+///
+/// That's just `NO_CACHE` if the MTRRs say the range is UC or WC already,
+/// and otherwise `NO_CACHE | WRITE_THROUGH`, which makes it UC whatever
+/// they say: see the [module docs](index.html).
+pub fn mmio_flags(phys: PAddr, len: u64) -> EntryFlags {
+    match map().map(|mtrrs| mtrrs.lookup_range(phys, len)) {
+        Some(Some(mem_type)) if mem_type.is_uncached() => NO_CACHE
+      , Some(mem_type) => {
+            warn!( "MTRRs give MMIO at {:?} type {:?}; overriding with PAT"
+                 , phys, mem_type);
+            NO_CACHE | WRITE_THROUGH
+        }
+      , None => NO_CACHE | WRITE_THROUGH
+    }
+}
@@ -0,0 +1,161 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! NUMA topology, from the ACPI System Resource Affinity Table (SRAT).
+//!
+//! The SRAT says which proximity domain each CPU and each range of memory
+//! belongs to. We group them into one [`NumaNode`] per domain, so that the
+//! frame allocator can prefer memory close to whoever is going to use it.
+//!
+//! Machines without an SRAT (including QEMU, unless it's given `-numa`
+//! options) have no topology at all, and every frame is as good as any
+//! other.
+//!
+//! [`NumaNode`]: struct.NumaNode.html
+use alloc::vec::Vec;
+use memory::PAddr;
+use spin::Once;
+
+use super::acpi::{self, read_u32, read_u64};
+
+/// Where the entries start in the SRAT, after the header and 12 reserved
+/// bytes.
+const SRAT_ENTRIES: usize = 48;
+
+/// SRAT entry types.
+mod entry {
+    /// Processor Local APIC Affinity.
+    pub const LOCAL_APIC: u8 = 0;
+    /// Memory Affinity.
+    pub const MEMORY: u8 = 1;
+    /// Processor Local x2APIC Affinity.
+    pub const LOCAL_X2APIC: u8 = 2;
+}
+
+/// Every entry type we use has its enabled flag in bit 0.
+const FLAG_ENABLED: u32 = 1 << 0;
+
+/// The CPUs and memory in one proximity domain.
+#[derive(Clone, Debug)]
+pub struct NumaNode { pub domain: u32
+                    , /// Start and end addresses of this node's memory
+                      pub memory_ranges: Vec<(PAddr, PAddr)>
+                    , /// The local APIC IDs of this node's CPUs
+                      pub apic_ids: Vec<u32>
+                    }
+
+impl NumaNode {
+    fn new(domain: u32) -> Self {
+        NumaNode { domain: domain
+                 , memory_ranges: Vec::new()
+                 , apic_ids: Vec::new()
+                 }
+    }
+
+    /// Returns true if the memory from `start` up to `end` is all in one of
+    /// this node's ranges.
+    pub fn contains(&self, start: PAddr, end: PAddr) -> bool {
+        self.memory_ranges.iter()
+            .any(|&(lo, hi)| lo <= start && end <= hi)
+    }
+}
+
+/// The machine's proximity domains.
+#[derive(Clone, Debug)]
+pub struct NumaTopology { pub nodes: Vec<NumaNode> }
+
+impl NumaTopology {
+    /// Returns the node for proximity domain `domain`, if there is one.
+    pub fn node(&self, domain: u32) -> Option<&NumaNode> {
+        self.nodes.iter().find(|node| node.domain == domain)
+    }
+
+    /// Returns the proximity domain of the CPU with local APIC ID
+    /// `apic_id`, if the SRAT mentions it.
+    pub fn domain_of_apic(&self, apic_id: u32) -> Option<u32> {
+        self.nodes.iter()
+            .find(|node| node.apic_ids.contains(&apic_id))
+            .map(|node| node.domain)
+    }
+
+    /// Returns the node for `domain`, adding it if it's new.
+    fn node_mut(&mut self, domain: u32) -> &mut NumaNode {
+        match self.nodes.iter().position(|node| node.domain == domain) {
+            Some(i) => &mut self.nodes[i]
+          , None => {
+                self.nodes.push(NumaNode::new(domain));
+                self.nodes.last_mut().expect("node was just added!")
+            }
+        }
+    }
+}
+
+/// Build a NUMA topology from the SRAT `srat`, ignoring entries that are
+/// disabled or that we don't understand.
+pub fn parse_srat(srat: &[u8]) -> NumaTopology {
+    let mut topology = NumaTopology { nodes: Vec::new() };
+    let mut offset = SRAT_ENTRIES;
+    while offset + 2 <= srat.len() {
+        let (kind, len) = (srat[offset], srat[offset + 1] as usize);
+        // a zero length would have us looking at this entry forever.
+        if len < 2 || offset + len > srat.len() { break }
+        let entry = &srat[offset .. offset + len];
+        match kind {
+            entry::LOCAL_APIC if len >= 16 => {
+                if read_u32(entry, 4) & FLAG_ENABLED != 0 {
+                    // the domain is split: its low byte comes first.
+                    let domain = entry[2] as u32
+                               | (read_u32(entry, 8) & 0xff_ffff) << 8;
+                    let apic_id = entry[3] as u32;
+                    topology.node_mut(domain).apic_ids.push(apic_id);
+                }
+            }
+          , entry::MEMORY if len >= 40 => {
+                let base = read_u64(entry, 8);
+                let length = read_u64(entry, 16);
+                if read_u32(entry, 28) & FLAG_ENABLED != 0 && length > 0 {
+                    let range = ( PAddr::from(base)
+                                , PAddr::from(base.saturating_add(length)) );
+                    topology.node_mut(read_u32(entry, 2))
+                            .memory_ranges.push(range);
+                }
+            }
+          , entry::LOCAL_X2APIC if len >= 24 => {
+                if read_u32(entry, 12) & FLAG_ENABLED != 0 {
+                    let apic_id = read_u32(entry, 8);
+                    topology.node_mut(read_u32(entry, 4))
+                            .apic_ids.push(apic_id);
+                }
+            }
+          , _ => {}
+        }
+        offset += len;
+    }
+    topology.nodes.sort_by_key(|node| node.domain);
+    topology
+}
+
+static TOPOLOGY: Once<NumaTopology> = Once::new();
+
+/// Read the NUMA topology from the SRAT, returning the number of nodes.
+///
+/// # Safety
+/// + This must be called once, after the kernel has been remapped.
+pub unsafe fn init() -> Result<usize, &'static str> {
+    let srat = acpi::find_sdt(b"SRAT")?;
+    let topology = TOPOLOGY.call_once(|| parse_srat(srat));
+    for node in &topology.nodes {
+        debug!( "NUMA node {}: {} CPU(s), memory {:?}"
+              , node.domain, node.apic_ids.len(), node.memory_ranges);
+    }
+    Ok(topology.nodes.len())
+}
+
+/// Returns the NUMA topology, if the machine has one.
+#[inline]
+pub fn topology() -> Option<&'static NumaTopology> { TOPOLOGY.try() }
@@ -0,0 +1,177 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Process-context identifiers.
+//!
+//! With PCIDs enabled, every TLB entry is tagged with the PCID that was in
+//! `%cr3` when it was made, and switching address spaces needn't throw the
+//! whole TLB away: a task whose PCID hasn't been used by anyone else since
+//! it last ran picks up its old entries again.
+//!
+//! Tasks are given a PCID the first time they're switched to, and keep it
+//! until they're reaped or it's taken by a newer task. A PCID is always
+//! flushed when it's given out, so its previous owner's entries can't leak.
+//! PCID 0 is the one the kernel booted with, and is never given out.
+//!
+//! `invlpg` only reaches the current PCID. That's fine for changes a task
+//! makes to its own address space, but a change to the mappings of a task
+//! that isn't running must be followed by [`release`](fn.release.html)-ing
+//! the task's PCID. Kernel mappings aren't global, so every PCID may have
+//! cached them, and unmapping one must be followed by
+//! [`flush_kernel_page`](fn.flush_kernel_page.html).
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use cpu::control_regs::cr4;
+use cpu::cpuid::{self, cpuid};
+use memory::VAddr;
+use paging::arch::cr3;
+use paging::arch::tlb::Flush;
+use spin::Mutex;
+
+use task::{Pid, Task};
+
+/// The number of PCIDs, including the kernel's.
+pub const NUM_PCIDS: usize = 4096;
+
+/// The PCID in use before any task was switched to.
+const BOOT_PCID: u16 = 0;
+
+/// The `invpcid` type that flushes every PCID, global entries included.
+const INVPCID_ALL_CONTEXTS: u64 = 2;
+
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+static HAS_INVPCID: AtomicBool = ATOMIC_BOOL_INIT;
+
+// this is a `const` static rather than a lazy one so that the owner table,
+// which is 32 KiB, is never built on the stack.
+static PCIDS: Mutex<PcidAllocator> = Mutex::new(PcidAllocator::new());
+
+/// Keeps track of which task each PCID belongs to.
+pub struct PcidAllocator { owners: [Option<Pid>; NUM_PCIDS]
+                         , /// Where to start looking for a free PCID
+                           next: usize
+                         }
+
+impl PcidAllocator {
+    /// Returns an allocator with every PCID but the kernel's free.
+    pub const fn new() -> Self {
+        PcidAllocator { owners: [None; NUM_PCIDS]
+                      , next: BOOT_PCID as usize + 1
+                      }
+    }
+
+    /// Returns true if `pcid` belongs to the task `pid`.
+    #[inline]
+    pub fn owns(&self, pid: Pid, pcid: u16) -> bool {
+        self.owners.get(pcid as usize) == Some(&Some(pid))
+    }
+
+    /// Give a PCID to the task `pid`, and return it.
+    ///
+    /// PCIDs are given out in turn, so if none are free, the one after the
+    /// last one given out is the one that has been held longest, and it is
+    /// taken from its owner. The returned PCID must be flushed before use.
+    pub fn allocate(&mut self, pid: Pid) -> u16 {
+        let usable = NUM_PCIDS - 1;
+        let start = self.next - 1;
+        let pcid = (0 .. usable)
+            .map(|i| 1 + (start + i) % usable)
+            .find(|&pcid| self.owners[pcid].is_none())
+            .unwrap_or(self.next);
+        self.owners[pcid] = Some(pid);
+        self.next = if pcid + 1 == NUM_PCIDS { 1 } else { pcid + 1 };
+        pcid as u16
+    }
+
+    /// Take `pcid` back from the task `pid`, if it still has it.
+    #[inline]
+    pub fn free(&mut self, pid: Pid, pcid: u16) {
+        if self.owns(pid, pcid) { self.owners[pcid as usize] = None }
+    }
+}
+
+/// Turn on PCIDs, if the CPU has them.
+///
+/// Returns true if PCIDs are being used.
+///
+/// # Safety
+/// + This must be called once, on the bootstrap processor, before any user
+///   task runs.
+pub unsafe fn init() -> bool {
+    let features = cpuid(cpuid::LEAF_FEATURES, 0);
+    if features.ecx & cpuid::ECX_PCID == 0 { return false }
+    // `CR4.PCIDE` can only be set while the current PCID is 0.
+    cr3::write_with_pcid(cr3::read(), BOOT_PCID, false);
+    let mut cr4_flags = cr4::read();
+    cr4_flags.insert(cr4::PCIDE);
+    cr4::write(cr4_flags);
+    let ext = cpuid(cpuid::LEAF_EXT_FEATURES, 0);
+    HAS_INVPCID.store(ext.ebx & cpuid::EBX_INVPCID != 0, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Flush every PCID's TLB entries on this CPU.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn flush_all_contexts() {
+    if HAS_INVPCID.load(Ordering::Relaxed) {
+        let descriptor: [u64; 2] = [0, 0];
+        asm!( "invpcid $0, [$1]"
+            :: "r"(INVPCID_ALL_CONTEXTS), "r"(&descriptor)
+            :  "memory"
+            :  "intel", "volatile" );
+    } else {
+        // changing `CR4.PGE` flushes everything, for every PCID.
+        let flags = cr4::read();
+        cr4::write(flags ^ cr4::PGE);
+        cr4::write(flags);
+    }
+}
+
+/// Flush the kernel page at `addr` from this CPU's TLB, for every PCID.
+///
+/// There's no way to flush one address from every PCID short of flushing
+/// it from each of them in turn, so with PCIDs on, this flushes them all.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn flush_kernel_page(addr: VAddr) {
+    if ENABLED.load(Ordering::Relaxed) { flush_all_contexts() }
+    else { addr.invlpg() }
+}
+
+/// Load `next`'s page tables, as part of a context switch.
+///
+/// If `next` still has its PCID, its TLB entries are kept; otherwise it's
+/// given a new one, which is flushed.
+///
+/// # Safety
+/// + Interrupts must be disabled.
+pub unsafe fn switch(next: &mut Task) {
+    let addr = next.page_table.base_addr();
+    if !ENABLED.load(Ordering::Relaxed) { return cr3::write(addr) }
+    let mut pcids = PCIDS.lock();
+    match next.pcid {
+        Some(pcid) if pcids.owns(next.pid, pcid) =>
+            cr3::write_with_pcid(addr, pcid, true)
+      , _ => {
+            let pcid = pcids.allocate(next.pid);
+            next.pcid = Some(pcid);
+            cr3::write_with_pcid(addr, pcid, false)
+        }
+    }
+}
+
+/// Take back `task`'s PCID, if it has one, so that the next time it runs
+/// its TLB entries are flushed.
+pub fn release(task: &mut Task) {
+    if let Some(pcid) = task.pcid.take() {
+        PCIDS.lock().free(task.pid, pcid)
+    }
+}
@@ -0,0 +1,176 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Per-CPU data.
+//!
+//! Each CPU has a [`CpuData`](struct.CpuData.html) structure, which is
+//! pointed to by the `%gs` segment base while running in the kernel. While
+//! running user code, the pointer lives in `IA32_KERNEL_GS_BASE` instead, and
+//! the kernel entry points `swapgs` it back into place.
+use alloc::boxed::Box;
+use core::ptr;
+use cpu::PrivilegeLevel;
+use cpu::context::InterruptFrame;
+use cpu::msr;
+
+use mm::frame::PerCpuFrameCache;
+use task::Task;
+use super::gdt::{CpuTables, NMI_IST, NMI_STACK_SIZE};
+use super::smp::CpuPanicState;
+
+/// Offsets of `CpuData` fields, for use from assembly.
+///
+/// These must be kept in sync with the definition of `CpuData`.
+pub mod offsets {
+    pub const SELF_PTR: usize = 0;
+    pub const KERNEL_RSP: usize = 8;
+    pub const USER_RSP: usize = 16;
+    pub const STACK_CANARY_ADDR: usize = 24;
+    pub const STACK_CANARY: usize = 32;
+}
+
+/// Data private to a single CPU.
+#[repr(C)]
+pub struct CpuData { /// Pointer to this structure, so that it can be found
+                     /// with a single `mov` from `%gs:0`.
+                     self_ptr: *mut CpuData
+                   , /// Top of the current task's kernel stack, loaded on
+                     /// entry to the kernel from user mode.
+                     pub kernel_rsp: u64
+                   , /// The user stack pointer, saved on `syscall` entry.
+                     pub user_rsp: u64
+                   , /// Where the current task's stack canary is.
+                     pub stack_canary_addr: u64
+                   , /// The value the current task's stack canary should
+                     /// have.
+                     pub stack_canary: u64
+                   , /// This CPU's number.
+                     pub cpu_id: u32
+                   , /// The task currently running on this CPU, or null.
+                     pub current_task: *mut Task
+                   , /// The task this CPU runs when nothing else is
+                     /// runnable, or null.
+                     pub idle_task: *mut Task
+                   , /// Free frames for this CPU to allocate from.
+                     pub frame_cache: PerCpuFrameCache
+                   , /// When this CPU last switched tasks, in nanoseconds
+                     /// since boot.
+                     pub switch_time: u64
+                   , /// Where this CPU was when a panic on another CPU
+                     /// halted it.
+                     pub panic_state: CpuPanicState
+                   , /// The number of the last TLB shootdown this CPU
+                     /// flushed for.
+                     pub shootdown_gen: usize
+                   , /// Set while this CPU is waiting on a TLB shootdown
+                     /// it started.
+                     pub in_shootdown: bool
+                   , /// This CPU's GDT and TSS.
+                     pub tables: CpuTables
+                   }
+
+impl CpuData {
+    const fn empty() -> Self {
+        CpuData { self_ptr: ptr::null_mut()
+                , kernel_rsp: 0
+                , user_rsp: 0
+                , stack_canary_addr: 0
+                , stack_canary: 0
+                , cpu_id: 0
+                , current_task: ptr::null_mut()
+                , idle_task: ptr::null_mut()
+                , frame_cache: PerCpuFrameCache::new()
+                , switch_time: 0
+                , panic_state: CpuPanicState::empty()
+                , shootdown_gen: 0
+                , in_shootdown: false
+                , tables: CpuTables::new()
+                }
+    }
+}
+
+/// The bootstrap processor's `CpuData`.
+static mut BSP_DATA: CpuData = CpuData::empty();
+/// The bootstrap processor's NMI stack.
+static mut BSP_NMI_STACK: [u8; NMI_STACK_SIZE] = [0; NMI_STACK_SIZE];
+
+/// Initialize the per-CPU data for the bootstrap processor and point
+/// `%gs` at it.
+///
+/// # Safety
+/// + This must only be called once, on the bootstrap processor, with
+///   interrupts disabled.
+pub unsafe fn init_bsp(kernel_rsp: u64) {
+    let nmi_stack = &BSP_NMI_STACK[0] as *const u8 as u64;
+    install( &mut BSP_DATA, 0, kernel_rsp
+           , nmi_stack + NMI_STACK_SIZE as u64)
+}
+
+/// Allocate per-CPU data for an application processor, and point its `%gs`
+/// at it.
+///
+/// # Safety
+/// + This must only be called once on each application processor, with
+///   interrupts disabled.
+pub unsafe fn init_ap(cpu_id: u32, kernel_rsp: u64) {
+    let data = Box::into_raw(Box::new(CpuData::empty()));
+    let nmi_stack = Box::into_raw(vec![0u8; NMI_STACK_SIZE].into_boxed_slice());
+    install( &mut *data, cpu_id, kernel_rsp
+           , (*nmi_stack).as_ptr() as u64 + NMI_STACK_SIZE as u64)
+}
+
+/// Point this CPU's `%gs` base at `data`, and load its GDT and TSS, with
+/// `nmi_stack` as the top of the stack NMIs run on.
+unsafe fn install( data: &'static mut CpuData, cpu_id: u32, kernel_rsp: u64
+                 , nmi_stack: u64) {
+    data.self_ptr = data as *mut CpuData;
+    data.cpu_id = cpu_id;
+    data.kernel_rsp = kernel_rsp;
+    data.tables.set_rsp0(kernel_rsp);
+    data.tables.set_ist(NMI_IST, nmi_stack);
+    let tables = &mut *(&mut data.tables as *mut CpuTables);
+    tables.load();
+    msr::write(msr::IA32_GS_BASE, data.self_ptr as u64);
+    // user mode starts out with a null `%gs` base.
+    msr::write(msr::IA32_KERNEL_GS_BASE, 0);
+}
+
+/// Returns the current CPU's `CpuData`.
+///
+/// # Safety
+/// + This may only be called in kernel mode, after the per-CPU data has been
+///   installed, and with `%gs` not swapped out.
+/// + The caller must ensure that it isn't migrated to another CPU while
+///   holding the returned reference, and that it isn't aliased.
+#[inline]
+pub unsafe fn current() -> &'static mut CpuData {
+    let ptr: *mut CpuData;
+    asm!( "mov $0, gs:[0]"
+        : "=r"(ptr)
+        ::: "intel" );
+    &mut *ptr
+}
+
+/// Returns the current CPU's `CpuData` from an interrupt handler, whether
+/// the interrupt came from kernel or user mode.
+///
+/// If it came from user mode, `%gs` is still the user's, and the pointer is
+/// in `IA32_KERNEL_GS_BASE`.
+///
+/// # Safety
+/// + As for [`current`](fn.current.html), and `frame` must be the frame
+///   of the interrupt being handled.
+/// + The interrupt mustn't have arrived in the few instructions of a
+///   kernel entry point before its `swapgs`.
+pub unsafe fn interrupted(frame: &InterruptFrame) -> &'static mut CpuData {
+    let base = match frame.cs.rpl() {
+        PrivilegeLevel::UserMode => msr::read(msr::IA32_KERNEL_GS_BASE)
+      , _ => msr::read(msr::IA32_GS_BASE)
+    };
+    &mut *(base as *mut CpuData)
+}
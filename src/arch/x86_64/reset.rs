@@ -0,0 +1,41 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Resetting the machine.
+use core::ptr;
+use cpu::dtable::Pointer;
+use cpu::interrupts::idt::Idt;
+use cpu::ports::PS2_CMD;
+
+/// Keyboard controller command: pulse the CPU reset line.
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+/// Reset the machine by pulsing the CPU reset line through the keyboard
+/// controller.
+///
+/// Returns if there's no keyboard controller, or it ignored us.
+pub fn keyboard_controller() {
+    PS2_CMD.write(KBC_PULSE_RESET);
+}
+
+/// Reset the machine by triple faulting: with an empty IDT, the CPU can't
+/// handle any exception, so the first one resets it.
+///
+/// This is the last resort, for when nothing else has worked.
+pub fn triple_fault() -> ! {
+    let idt: Pointer<Idt> = Pointer { limit: 0, base: ptr::null() };
+    unsafe {
+        Idt::disable_interrupts();
+        asm!(  "lidt ($0)
+                int3"
+            :: "r"(&idt)
+            :  "memory"
+            :  "volatile" );
+    }
+    unreachable!("the CPU survived a triple fault!")
+}
@@ -0,0 +1,138 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Random numbers, from the CPU's hardware random number generator.
+//!
+//! `rdrand` returns numbers from a generator that the CPU reseeds from its
+//! entropy source; `rdseed` returns the conditioned entropy itself, which
+//! is slower and runs out sooner. Either can fail when the CPU is busy,
+//! so both are retried a few times.
+//!
+//! Without either, [`next_u64`](fn.next_u64.html) falls back to an LFSR
+//! seeded from the TSC, which is fine for spreading things out but
+//! predictable by anyone who can guess when it was seeded.
+//!
+//! [`fill_bytes`](fn.fill_bytes.html) prefers a virtio entropy device to
+//! any of these, when there is one.
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering, ATOMIC_BOOL_INIT};
+use cpu::cpuid::{self, cpuid};
+
+use dev::virtio;
+use super::cpu;
+
+/// How many times to try `rdrand` or `rdseed` before giving up.
+const RETRIES: usize = 10;
+
+/// The taps of the fallback LFSR, for a maximal-length 64-bit sequence.
+const LFSR_TAPS: u64 = 0xd800_0000_0000_0000;
+
+static HAS_RDRAND: AtomicBool = ATOMIC_BOOL_INIT;
+static HAS_RDSEED: AtomicBool = ATOMIC_BOOL_INIT;
+static HAS_RDPID: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The fallback LFSR's state, or 0 if it hasn't been seeded yet.
+static LFSR: AtomicU64 = AtomicU64::new(0);
+
+/// Find out which random number instructions the CPU has.
+///
+/// Until this is called, everything comes from the fallback LFSR.
+///
+/// # Safety
+/// + This must be called once, on the bootstrap processor.
+pub unsafe fn init() {
+    let features = cpuid(cpuid::LEAF_FEATURES, 0);
+    let ext = cpuid(cpuid::LEAF_EXT_FEATURES, 0);
+    HAS_RDRAND.store( features.ecx & cpuid::ECX_RDRAND != 0
+                    , Ordering::Relaxed);
+    HAS_RDSEED.store(ext.ebx & cpuid::EBX_RDSEED != 0, Ordering::Relaxed);
+    HAS_RDPID.store(ext.ecx & cpuid::ECX_RDPID != 0, Ordering::Relaxed);
+}
+
+/// Returns true if the CPU has `rdrand`.
+#[inline]
+pub fn has_rdrand() -> bool { HAS_RDRAND.load(Ordering::Relaxed) }
+
+/// Returns true if the CPU has `rdseed`.
+#[inline]
+pub fn has_rdseed() -> bool { HAS_RDSEED.load(Ordering::Relaxed) }
+
+/// Returns a random number from `rdrand`, or `None` if the CPU doesn't
+/// have it or it failed `RETRIES` times in a row.
+pub fn rdrand() -> Option<u64> {
+    if !has_rdrand() { return None }
+    (0 .. RETRIES).filter_map(|_| unsafe { cpu::rdrand() }).next()
+}
+
+/// Returns a random seed from `rdseed`, or `None` if the CPU doesn't have
+/// it or it failed `RETRIES` times in a row.
+pub fn rdseed() -> Option<u64> {
+    if !has_rdseed() { return None }
+    for _ in 0 .. RETRIES {
+        if let Some(seed) = unsafe { cpu::rdseed() } { return Some(seed) }
+        // the entropy source needs time to refill.
+        unsafe { cpu::pause() }
+    }
+    None
+}
+
+/// Returns 64 more bits from the fallback LFSR, seeding it first if it
+/// hasn't been.
+///
+/// The seed is the TSC, XORed with the CPU number from `rdpid` if the CPU
+/// has it, so CPUs that seed at the same moment still differ.
+pub fn lfsr() -> u64 {
+    let mut state = LFSR.load(Ordering::Relaxed);
+    loop {
+        let mut next = if state != 0 { state } else {
+            let id = if HAS_RDPID.load(Ordering::Relaxed) {
+                unsafe { cpu::rdpid() }
+            } else {
+                0
+            };
+            // the all-zero state never leaves itself.
+            (unsafe { cpu::rdtsc() } ^ id.rotate_right(16)) | 1
+        };
+        for _ in 0 .. 64 {
+            let bit = next & 1;
+            next >>= 1;
+            if bit != 0 { next ^= LFSR_TAPS }
+        }
+        match LFSR.compare_exchange( state, next
+                                   , Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return next
+          , Err(actual) => state = actual
+        }
+    }
+}
+
+/// Returns a random number, from `rdrand` if possible, then `rdseed`, and
+/// then the fallback LFSR.
+pub fn next_u64() -> u64 {
+    rdrand().or_else(rdseed).unwrap_or_else(lfsr)
+}
+
+/// Fill `buf` with random bytes.
+///
+/// If there's a virtio entropy device, the bytes come from the host
+/// through it first, which blocks; whatever it doesn't fill comes from
+/// [`next_u64`](fn.next_u64.html).
+pub fn fill_bytes(mut buf: &mut [u8]) {
+    if let Some(device) = virtio::rng::device() {
+        while !buf.is_empty() {
+            let filled = device.fill(buf);
+            if filled == 0 { break }
+            buf = &mut {buf}[filled..];
+        }
+    }
+    for chunk in buf.chunks_mut(8) {
+        let bytes: [u8; 8] = unsafe {
+            ::core::mem::transmute(next_u64().to_le())
+        };
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
@@ -0,0 +1,352 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Starting the application processors.
+//!
+//! At boot, only the bootstrap processor (BSP) is running. The application
+//! processors (APs) wait for an INIT IPI followed by two start-up IPIs, and
+//! then start in real mode at the page named by the start-up IPI. We copy a
+//! trampoline there which takes them through protected mode into long mode,
+//! on the BSP's page tables and a stack of their own, and calls
+//! [`ap_startup`](fn.ap_startup.html).
+//!
+//! We don't read the ACPI tables yet, so we don't know how many APs there
+//! are. Instead, the IPIs are broadcast to every other CPU, and each AP
+//! takes the next CPU number as it arrives in the trampoline.
+use core::{mem, ptr};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use cpu::context::InterruptFrame;
+use cpu::cpuid::{self, cpuid};
+use cpu::tsc;
+use memory::{PAddr, Page, PhysicalPage, VAddr, VirtualPage, PAGE_SIZE};
+use paging::Mapper;
+use paging::arch::ActivePageTable;
+use paging::arch::cr3;
+use paging::arch::space::phys_to_virt;
+use paging::arch::table::WRITABLE;
+
+use mm::frame;
+use task::{sched, KernelStack};
+use super::{apic, interrupts, percpu, tlb_shootdown, topology};
+use super::cpu::hlt;
+use super::drivers::serial::PANIC_SERIAL;
+
+/// The most CPUs we'll start, including the BSP.
+///
+/// This must match the size of `ap_stacks` in the trampoline.
+pub const MAX_CPUS: usize = 16;
+
+/// The physical address the trampoline is copied to. It has to be page
+/// aligned and below 1 MiB, so that real mode can reach it.
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// How long to wait for the APs to arrive, in nanoseconds.
+const AP_WAIT_NS: u64 = 100_000_000;
+
+/// How long a panicking CPU waits for the others to halt, in nanoseconds.
+const HALT_WAIT_NS: u64 = 100_000_000;
+
+/// The number of CPUs that are running, including the BSP.
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Set once a CPU has started halting the others.
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Returns the number of CPUs that are running.
+#[inline]
+pub fn cpus_online() -> usize {
+    CPUS_ONLINE.load(Ordering::SeqCst)
+}
+
+// The trampoline is assembled along with the rest of the kernel, but only
+// ever runs from its copy at `TRAMPOLINE_ADDR`, so every absolute address
+// in it is computed relative to that.
+global_asm!("
+    .intel_syntax noprefix
+    .set    AP_BASE, 0x8000
+    .global ap_trampoline_start
+    .global ap_trampoline_end
+    .global ap_next_id
+    .global ap_cr3
+    .global ap_entry
+    .global ap_stacks
+
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    xor     ax, ax
+    mov     ds, ax
+    lgdt    [AP_BASE + (ap_gdt_ptr - ap_trampoline_start)]
+    mov     eax, cr0
+    or      eax, 1
+    mov     cr0, eax
+    // jmp 0x08:ap_trampoline_32
+    .byte   0x66, 0xea
+    .long   AP_BASE + (ap_trampoline_32 - ap_trampoline_start)
+    .word   0x08
+
+    .code32
+ap_trampoline_32:
+    mov     ax, 0x10
+    mov     ds, ax
+    mov     es, ax
+    mov     ss, ax
+    // enable PAE, and load the BSP's PML4
+    mov     eax, cr4
+    or      eax, 1 << 5
+    mov     cr4, eax
+    mov     eax, [AP_BASE + (ap_cr3 - ap_trampoline_start)]
+    mov     cr3, eax
+    // set EFER.LME, and EFER.NXE since the page tables use the NX bit
+    mov     ecx, 0xc0000080
+    rdmsr
+    or      eax, (1 << 8) | (1 << 11)
+    wrmsr
+    mov     eax, cr0
+    or      eax, 1 << 31
+    mov     cr0, eax
+    // jmp 0x18:ap_trampoline_64
+    .byte   0xea
+    .long   AP_BASE + (ap_trampoline_64 - ap_trampoline_start)
+    .word   0x18
+
+    .code64
+ap_trampoline_64:
+    mov     ax, 0x10
+    mov     ds, ax
+    mov     es, ax
+    mov     ss, ax
+    // take a CPU number, and the stack that goes with it
+    mov     eax, 1
+    lock xadd dword ptr [AP_BASE + (ap_next_id - ap_trampoline_start)], eax
+    cmp     eax, 16
+    jae     ap_halt
+    mov     edi, eax
+    mov     rsp, [AP_BASE + (ap_stacks - ap_trampoline_start) + rax * 8]
+    mov     rax, [AP_BASE + (ap_entry - ap_trampoline_start)]
+    call    rax
+ap_halt:
+    cli
+    hlt
+    jmp     ap_halt
+
+    .align  8
+ap_gdt:
+    .quad   0
+    .quad   0x00cf9a000000ffff  // 0x08: 32-bit code
+    .quad   0x00cf92000000ffff  // 0x10: data
+    .quad   0x00209a0000000000  // 0x18: 64-bit code
+ap_gdt_end:
+ap_gdt_ptr:
+    .word   ap_gdt_end - ap_gdt - 1
+    .long   AP_BASE + (ap_gdt - ap_trampoline_start)
+
+    .align  8
+ap_next_id:
+    .long   1
+ap_cr3:
+    .long   0
+ap_entry:
+    .quad   0
+ap_stacks:
+    .fill   16, 8, 0
+ap_trampoline_end:
+    .att_syntax
+");
+
+extern {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_next_id: u32;
+    static ap_cr3: u32;
+    static ap_entry: u64;
+    static ap_stacks: [u64; MAX_CPUS];
+}
+
+/// Returns a pointer to `symbol` in the copy of the trampoline at `dst`.
+#[inline]
+unsafe fn relocate<T>(dst: *mut u8, symbol: &T) -> *mut T {
+    let start = &ap_trampoline_start as *const u8 as usize;
+    let offset = symbol as *const T as usize - start;
+    dst.offset(offset as isize) as *mut T
+}
+
+/// Where a CPU was when it was halted by [`halt_all_cpus`].
+///
+/// [`halt_all_cpus`]: fn.halt_all_cpus.html
+#[derive(Copy, Clone, Debug)]
+pub struct CpuPanicState { pub rip: u64
+                         , pub cs: u64
+                         , pub rflags: u64
+                         , pub rsp: u64
+                         , pub ss: u64
+                         , pub cr3: u64
+                         }
+
+impl CpuPanicState {
+    /// Returns a state that's all 0, for a CPU that hasn't been halted.
+    pub const fn empty() -> Self {
+        CpuPanicState { rip: 0, cs: 0, rflags: 0, rsp: 0, ss: 0, cr3: 0 }
+    }
+
+    fn save(frame: &InterruptFrame) -> Self {
+        CpuPanicState { rip: frame.rip as u64
+                      , cs: frame.cs.bits() as u64
+                      , rflags: frame.rflags.bits() as u64
+                      , rsp: frame.rsp as u64
+                      , ss: frame.ss.bits() as u64
+                      , cr3: *cr3::current_pagetable_frame().base_addr()
+                      }
+    }
+}
+
+/// Returns true if a CPU has started halting the others, in which case any
+/// NMI is a request to halt.
+#[inline]
+pub fn is_halting() -> bool {
+    HALTING.load(Ordering::SeqCst)
+}
+
+/// Stop every other CPU, so that they don't print over a panic message.
+///
+/// Each of them is sent an NMI, which gets through even with interrupts
+/// disabled, and the NMI handler calls [`halt_here`]. There's no way to
+/// tell when they've all got there, so this just waits long enough that
+/// they should have.
+///
+/// [`halt_here`]: fn.halt_here.html
+pub fn halt_all_cpus() {
+    if HALTING.swap(true, Ordering::SeqCst) {
+        // another CPU got here first, and will send this one an NMI.
+        loop { unsafe { hlt() } }
+    }
+    if cpus_online() < 2 {
+        return
+    }
+    unsafe { apic::send_nmi_all() }
+    delay_ns(HALT_WAIT_NS)
+}
+
+/// Save where this CPU was in its `CpuPanicState`, report it on the
+/// serial port, and halt forever.
+///
+/// This is called from the NMI handler once [`halt_all_cpus`] has started.
+/// NMIs are blocked until the handler returns, which it never does, so
+/// nothing wakes the CPU up again.
+///
+/// # Safety
+/// + `frame` must be the frame of the NMI being handled.
+///
+/// It runs on the CPU's NMI stack, from IST2, so a CPU whose own stack has
+/// gone bad still halts cleanly.
+///
+/// [`halt_all_cpus`]: fn.halt_all_cpus.html
+pub unsafe fn halt_here(frame: &InterruptFrame) -> ! {
+    let data = percpu::interrupted(frame);
+    data.panic_state = CpuPanicState::save(frame);
+    let _ = write!( PANIC_SERIAL.lock()
+                  , "CPU {} halted at RIP={:#x}\n"
+                  , data.cpu_id, data.panic_state.rip);
+    loop { hlt() }
+}
+
+/// Spin for `ns` nanoseconds.
+fn delay_ns(ns: u64) {
+    let until = tsc::current_ns() + ns;
+    while tsc::current_ns() < until {}
+}
+
+/// Start the application processors, returning the number of CPUs that are
+/// running.
+///
+/// # Safety
+/// + This must be called once, on the BSP, after the heap and the per-CPU
+///   data have been set up.
+pub unsafe fn init() -> usize {
+    if cpuid(cpuid::LEAF_FEATURES, 0).edx & cpuid::EDX_APIC == 0 {
+        warn!("no local APIC, so no SMP");
+        return cpus_online()
+    }
+    if tsc::khz().is_none() {
+        warn!("TSC not calibrated, can't time the AP start-up sequence");
+        return cpus_online()
+    }
+    if let Err(why) = apic::map(&mut frame::allocator()) {
+        warn!("{}", why);
+        return cpus_online()
+    }
+    apic::init();
+
+    // the APs turn paging on while running the trampoline, so it must be
+    // identity mapped as well as being in the physical memory map.
+    let trampoline = PAddr::from(TRAMPOLINE_ADDR);
+    let page = VirtualPage::containing(VAddr::from(TRAMPOLINE_ADDR as usize));
+    let mut table = ActivePageTable::new();
+    if !table.is_mapped(&page) {
+        let frame = PhysicalPage::containing(trampoline);
+        if table.identity_map(frame, WRITABLE, &mut frame::allocator())
+                .is_err() {
+            warn!("could not identity map the SMP trampoline");
+            return cpus_online()
+        }
+    }
+
+    let start = &ap_trampoline_start as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    assert!(len <= PAGE_SIZE as usize, "SMP trampoline is too big!");
+    let dst = phys_to_virt(trampoline).as_mut_ptr::<u8>();
+    ptr::copy_nonoverlapping(start, dst, len);
+
+    let pml4 = *cr3::current_pagetable_frame().base_addr();
+    assert!( pml4 < 1 << 32
+           , "the APs can't load a PML4 above 4 GiB in protected mode");
+    *relocate(dst, &ap_next_id) = 1;
+    *relocate(dst, &ap_cr3) = pml4 as u32;
+    *relocate(dst, &ap_entry) = ap_startup as u64;
+    let stacks = relocate(dst, &ap_stacks);
+    for id in 1 .. MAX_CPUS {
+        // we don't know how many APs will turn up, so every possible one
+        // gets a stack, and they all live forever.
+        let stack = KernelStack::new();
+        (*stacks)[id] = stack.top() as u64;
+        mem::forget(stack);
+    }
+
+    apic::send_init_all();
+    delay_ns(10_000_000);
+    for _ in 0 .. 2 {
+        apic::send_startup_all((TRAMPOLINE_ADDR >> 12) as u8);
+        delay_ns(200_000);
+    }
+    delay_ns(AP_WAIT_NS);
+    if cpus_online() > 1 {
+        // the NMI handler mustn't be the first to touch the port.
+        ::lazy_static::initialize(&PANIC_SERIAL);
+        ::vga::panic::set_stop_hook(halt_all_cpus);
+    }
+    cpus_online()
+}
+
+/// Where the APs end up after the trampoline, running on their own stacks
+/// with interrupts disabled.
+extern "C" fn ap_startup(cpu_id: u8) -> ! {
+    unsafe {
+        // the kernel stack pointer is filled in when a task is switched
+        // to.
+        percpu::init_ap(cpu_id as u32, 0);
+        apic::init();
+        interrupts::init_ap();
+        tlb_shootdown::init_ap();
+    }
+    topology::register_cpu(cpu_id);
+    CPUS_ONLINE.fetch_add(1, Ordering::SeqCst);
+    info!("CPU {} is up, with local APIC ID {}", cpu_id, apic::id());
+    sched::idle()
+}
@@ -0,0 +1,172 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! `syscall`/`sysret` support.
+//!
+//! User code enters the kernel with the `syscall` instruction, using the
+//! same register conventions as Linux: the system call number is in `%rax`,
+//! the arguments are in `%rdi`, `%rsi`, `%rdx`, `%r10`, `%r8` and `%r9`,
+//! and the return value is placed in `%rax`. Every other register except
+//! `%rcx` and `%r11` (which are clobbered by the instruction itself) is
+//! preserved.
+use cpu::msr;
+use cpu::segment::{KERNEL_CODE_SELECTOR, USER_DATA_SELECTOR};
+use core::mem;
+
+use super::cpu::cli;
+use super::percpu;
+use task;
+
+/// The registers saved on the kernel stack by `syscall_entry`.
+///
+/// This is always at the very top of the task's kernel stack, so it can be
+/// found (and modified, by e.g. `execve`) from anywhere in a system call.
+#[repr(C)]
+#[derive(Clone, Debug, Default)]
+pub struct SyscallFrame { pub r15: u64
+                        , pub r14: u64
+                        , pub r13: u64
+                        , pub r12: u64
+                        , pub rbx: u64
+                        , pub rbp: u64
+                        , pub r9: u64
+                        , pub r8: u64
+                        , pub r10: u64
+                        , pub rdx: u64
+                        , pub rsi: u64
+                        , pub rdi: u64
+                        , /// User `%rflags`, saved in `%r11` by `syscall`
+                          pub rflags: u64
+                        , /// User `%rip`, saved in `%rcx` by `syscall`
+                          pub rip: u64
+                        , /// User stack pointer
+                          pub rsp: u64
+                        }
+
+/// Returns the saved user registers for the current system call.
+///
+/// # Safety
+/// + This may only be called while handling a system call.
+#[inline]
+pub unsafe fn current_frame() -> &'static mut SyscallFrame {
+    let top = percpu::current().kernel_rsp as usize;
+    &mut *((top - mem::size_of::<SyscallFrame>()) as *mut SyscallFrame)
+}
+
+// Offsets into `CpuData` used below are those in `percpu::offsets`:
+// `gs:[8]` is `kernel_rsp`, `gs:[16]` is `user_rsp`, `gs:[24]` is
+// `stack_canary_addr` and `gs:[32]` is `stack_canary`.
+global_asm!("
+    .intel_syntax noprefix
+    .global syscall_entry
+syscall_entry:
+    swapgs
+    mov     gs:[16], rsp
+    mov     rsp, gs:[8]
+
+    // build a `SyscallFrame` on the kernel stack
+    push    qword ptr gs:[16]
+    push    rcx
+    push    r11
+    push    rdi
+    push    rsi
+    push    rdx
+    push    r10
+    push    r8
+    push    r9
+    push    rbp
+    push    rbx
+    push    r12
+    push    r13
+    push    r14
+    push    r15
+
+    // if the task has overflowed its kernel stack, the canary at the
+    // bottom won't match. `%rcx` and `%r11` are saved in the frame.
+    mov     r11, gs:[24]
+    mov     r11, [r11]
+    cmp     r11, gs:[32]
+    jne     stack_smash_handler
+
+    // shuffle the Linux syscall registers into the SysV calling convention;
+    // the sixth argument goes on the stack (keeping it 16-byte aligned)
+    push    r9
+    mov     r9, r8
+    mov     r8, r10
+    mov     rcx, rdx
+    mov     rdx, rsi
+    mov     rsi, rdi
+    mov     rdi, rax
+    call    dispatch_syscall
+    add     rsp, 8
+
+    // return to user mode with `%rax` as the result and the `SyscallFrame`
+    // on top of the stack. forked children also enter here.
+    .global syscall_return
+syscall_return:
+    // handlers may have enabled interrupts; we can't take one between
+    // `swapgs` and `sysret`.
+    cli
+    pop     r15
+    pop     r14
+    pop     r13
+    pop     r12
+    pop     rbx
+    pop     rbp
+    pop     r9
+    pop     r8
+    pop     r10
+    pop     rdx
+    pop     rsi
+    pop     rdi
+    pop     r11
+    pop     rcx
+    swapgs
+    pop     rsp
+    sysretq
+    .att_syntax
+");
+
+extern {
+    fn syscall_entry();
+}
+
+/// Called from `syscall_entry`, in place of `dispatch_syscall`, if the
+/// current task's stack canary has been overwritten.
+///
+/// The frame is at the top of the stack, well away from the damage, so
+/// the user `%rip` it was called from can still be reported.
+#[no_mangle]
+pub extern "C" fn stack_smash_handler() -> ! {
+    unsafe {
+        cli();
+        let rip = current_frame().rip;
+        let task = task::current();
+        panic!( "kernel stack overflow in task {} ({}), from RIP={:#x}"
+              , task.pid, task.name, rip)
+    }
+}
+
+/// Configure the `syscall` MSRs for this CPU.
+///
+/// # Safety
+/// + The per-CPU data must already be installed, and the GDT must contain
+///   the user segments.
+pub unsafe fn init() {
+    // `sysret` loads `%cs` from STAR[63:48] + 16 and `%ss` from
+    // STAR[63:48] + 8, so the user base is the kernel data segment.
+    let star = ((USER_DATA_SELECTOR.bits() as u64 - 8) << 48)
+             | ((KERNEL_CODE_SELECTOR.bits() as u64) << 32);
+    msr::write(msr::IA32_STAR, star);
+    msr::write(msr::IA32_LSTAR, syscall_entry as u64);
+    // clear IF (and DF, and TF) on entry, and AC, so that user code can't
+    // turn SMAP off for the kernel.
+    msr::write( msr::IA32_FMASK
+              , (1 << 9) | (1 << 10) | (1 << 8) | (1 << 18));
+    msr::enable_syscall();
+}
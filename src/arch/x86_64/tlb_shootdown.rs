@@ -0,0 +1,117 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! TLB shootdowns.
+//!
+//! `invlpg` only flushes the TLB of the CPU that runs it, so when a mapping
+//! that other CPUs may have cached is removed, they have to be told to
+//! flush it too, or they may keep using the stale translation, and the
+//! frame it pointed to, after it has been freed.
+//!
+//! [`broadcast`](fn.broadcast.html) does this with an IPI on
+//! `SHOOTDOWN_VECTOR`, and waits for every other CPU that's online to say
+//! it has flushed, by counting `SHOOTDOWN_ACK` down to zero. There's one
+//! shootdown at a time; each is numbered, and each CPU remembers the last
+//! one it flushed for, so a CPU that's waiting to start its own shootdown
+//! can answer the one in progress without answering it twice when the IPI
+//! finally gets through.
+use core::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering
+                        , ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use memory::VAddr;
+
+use super::{apic, pcid, percpu, smp};
+use super::cpu::{pause, without_interrupts};
+use super::percpu::CpuData;
+
+/// The vector shootdown IPIs are sent on.
+pub const SHOOTDOWN_VECTOR: u8 = 0xf1;
+
+/// The address being shot down, or 0 between shootdowns.
+static SHOOTDOWN_ADDR: AtomicU64 = AtomicU64::new(0);
+/// The number of CPUs that have yet to flush `SHOOTDOWN_ADDR`.
+static SHOOTDOWN_ACK: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The number of the latest shootdown.
+static SHOOTDOWN_GEN: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Held by the CPU whose shootdown is in progress.
+static SHOOTDOWN_LOCK: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Flush `SHOOTDOWN_ADDR` from this CPU's TLB and acknowledge it, unless
+/// this CPU has already done so for the shootdown in progress.
+///
+/// The address is flushed for every PCID, not just the one that's loaded:
+/// a task that comes back to this CPU with its PCID kept would otherwise
+/// get the stale entry back.
+fn answer(cpu: &mut CpuData) {
+    let gen = SHOOTDOWN_GEN.load(Ordering::Acquire);
+    if gen == cpu.shootdown_gen { return }
+    let addr = SHOOTDOWN_ADDR.load(Ordering::Relaxed);
+    unsafe { pcid::flush_kernel_page(VAddr::from(addr as usize)) }
+    cpu.shootdown_gen = gen;
+    SHOOTDOWN_ACK.fetch_sub(1, Ordering::Release);
+}
+
+/// Called by the `SHOOTDOWN_VECTOR` IPI handler.
+pub fn handle_ipi() {
+    answer(unsafe { percpu::current() })
+}
+
+/// Get this CPU ready to take shootdowns.
+///
+/// Shootdowns that finished before this CPU started are nothing to do with
+/// it.
+///
+/// # Safety
+/// + This must be called once on each application processor, before it
+///   counts itself as online.
+pub unsafe fn init_ap() {
+    percpu::current().shootdown_gen = SHOOTDOWN_GEN.load(Ordering::Acquire);
+}
+
+/// Flush `addr` from the TLB of every CPU, for every PCID, returning once
+/// they all have.
+///
+/// # Panics
+/// + If this CPU is already in the middle of a shootdown, as it can only
+///   be from an exception or NMI handler, and the shootdown would wait on
+///   the one it interrupted forever.
+pub fn broadcast(addr: VAddr) {
+    unsafe { pcid::flush_kernel_page(addr) }
+    if smp::cpus_online() <= 1 { return }
+    // with interrupts on, an interrupt handler on this CPU could start a
+    // shootdown of its own while we wait.
+    without_interrupts(|| unsafe { shoot_down(addr) })
+}
+
+/// Have every other CPU flush `addr`, and wait for them to.
+///
+/// # Safety
+/// + Interrupts must be disabled.
+unsafe fn shoot_down(addr: VAddr) {
+    let cpu = percpu::current();
+    assert!(!cpu.in_shootdown, "recursive TLB shootdown of {:?}!", addr);
+    cpu.in_shootdown = true;
+    // the CPU holding the lock may be waiting for us, and our interrupts
+    // are off, so answer it by hand.
+    while SHOOTDOWN_LOCK.compare_and_swap(false, true, Ordering::Acquire) {
+        answer(cpu);
+        pause();
+    }
+    SHOOTDOWN_ADDR.store(addr.as_usize() as u64, Ordering::Relaxed);
+    SHOOTDOWN_ACK.store(smp::cpus_online() - 1, Ordering::Relaxed);
+    // publishes the address and count along with the new number.
+    let gen = SHOOTDOWN_GEN.fetch_add(1, Ordering::Release) + 1;
+    // we flushed before we got here.
+    cpu.shootdown_gen = gen;
+    apic::send_fixed_all(SHOOTDOWN_VECTOR);
+    while SHOOTDOWN_ACK.load(Ordering::Acquire) != 0 {
+        pause();
+    }
+    SHOOTDOWN_ADDR.store(0, Ordering::Relaxed);
+    SHOOTDOWN_LOCK.store(false, Ordering::Release);
+    cpu.in_shootdown = false;
+}
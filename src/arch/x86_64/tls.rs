@@ -0,0 +1,127 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Thread-local storage for kernel threads.
+//!
+//! Each kernel thread has a [`KernelTls`](struct.KernelTls.html), which the
+//! `%fs` segment base points to while the thread is running, so that its
+//! fields can be read and written with a single `mov` by the
+//! [`kthread_tls_read!`] and [`kthread_tls_write!`] macros.
+//!
+//! `%fs` belongs to user code while a user task is running, even in the
+//! kernel, so tasks with a user address space don't get one; per-CPU data,
+//! which works in any task, is reached through `%gs` instead (see
+//! [`percpu`](../percpu/index.html)).
+//!
+//! [`kthread_tls_read!`]: ../../macro.kthread_tls_read.html
+//! [`kthread_tls_write!`]: ../../macro.kthread_tls_write.html
+use core::ptr;
+use cpu::msr;
+
+use task::Task;
+
+/// Offsets of `KernelTls` fields, for use from assembly and the
+/// `kthread_tls_*` macros.
+///
+/// These must be kept in sync with the definition of `KernelTls`; they're
+/// checked at boot by `layout_assertions`.
+pub mod offsets {
+    pub const ERRNO: usize = 0;
+    pub const PREEMPT_COUNT: usize = 4;
+    pub const IN_IRQ: usize = 8;
+    pub const CURRENT: usize = 16;
+    pub const CPU_ID: usize = 24;
+}
+
+/// Data private to a single kernel thread.
+#[repr(C)]
+#[derive(Debug)]
+pub struct KernelTls { /// The error from the last call that failed
+                       pub errno: i32
+                     , /// How many times preemption has been disabled
+                       pub preempt_count: u32
+                     , /// Whether the thread is handling an interrupt
+                       pub in_irq: bool
+                     , /// The thread's own task
+                       pub current: *mut Task
+                     , /// The CPU the thread is running on
+                       pub cpu_id: u8
+                     }
+
+impl KernelTls {
+    /// Returns a `KernelTls` for a thread that hasn't run yet.
+    ///
+    /// `current` and `cpu_id` are filled in each time the thread is
+    /// switched to.
+    pub const fn new() -> Self {
+        KernelTls { errno: 0
+                  , preempt_count: 0
+                  , in_irq: false
+                  , current: ptr::null_mut()
+                  , cpu_id: 0
+                  }
+    }
+}
+
+/// Point `%fs` at `tls`, or at nothing if `tls` is `None`.
+///
+/// # Safety
+/// + `tls` must live until `%fs` is pointed somewhere else.
+/// + This must not be called while a user task's `%fs` base is in use.
+pub unsafe fn install(tls: Option<&mut KernelTls>) {
+    let base = tls.map_or(0, |tls| tls as *mut KernelTls as u64);
+    msr::write(msr::IA32_FS_BASE, base);
+}
+
+/// Read the field `$field` of the running kernel thread's `KernelTls`.
+///
+/// # Safety
+/// + This must be used in an `unsafe` block, and only by a kernel thread:
+///   other tasks have no `KernelTls` for `%fs` to point to.
+#[macro_export]
+macro_rules! kthread_tls_read {
+    (errno) => { kthread_tls_read!(@ i32, ERRNO) };
+    (preempt_count) => { kthread_tls_read!(@ u32, PREEMPT_COUNT) };
+    (in_irq) => { kthread_tls_read!(@ bool, IN_IRQ) };
+    (current) => { kthread_tls_read!(@ *mut $crate::task::Task, CURRENT) };
+    (cpu_id) => { kthread_tls_read!(@ u8, CPU_ID) };
+    (@ $ty:ty, $offset:ident) => {{
+        let value: $ty;
+        asm!( "mov $0, fs:[$1]"
+            : "=r"(value)
+            : "i"($crate::arch::tls::offsets::$offset)
+            :: "intel" );
+        value
+    }};
+}
+
+/// Set the field `$field` of the running kernel thread's `KernelTls` to
+/// `$value`.
+///
+/// # Safety
+/// + This must be used in an `unsafe` block, and only by a kernel thread:
+///   other tasks have no `KernelTls` for `%fs` to point to.
+#[macro_export]
+macro_rules! kthread_tls_write {
+    (errno, $value:expr) => { kthread_tls_write!(@ i32, ERRNO, $value) };
+    (preempt_count, $value:expr) => {
+        kthread_tls_write!(@ u32, PREEMPT_COUNT, $value)
+    };
+    (in_irq, $value:expr) => { kthread_tls_write!(@ bool, IN_IRQ, $value) };
+    (current, $value:expr) => {
+        kthread_tls_write!(@ *mut $crate::task::Task, CURRENT, $value)
+    };
+    (cpu_id, $value:expr) => { kthread_tls_write!(@ u8, CPU_ID, $value) };
+    (@ $ty:ty, $offset:ident, $value:expr) => {{
+        let value: $ty = $value;
+        asm!( "mov fs:[$0], $1"
+            :: "i"($crate::arch::tls::offsets::$offset), "r"(value)
+            : "memory"
+            : "intel" );
+    }};
+}
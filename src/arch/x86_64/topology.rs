@@ -0,0 +1,224 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! CPU topology: which CPUs are hardware threads of the same core, and
+//! which cores share a package.
+//!
+//! CPUID leaf `0xB` describes the topology a level at a time, from the
+//! bottom: subleaf 0 is the SMT level, whose threads share a core, and
+//! subleaf 1 is the core level, whose cores share a package. Each level
+//! says how many bits to shift off an x2APIC ID to get the ID of the level
+//! above, so two CPUs are SMT siblings if their APIC IDs agree once the SMT
+//! level's bits are shifted off.
+//!
+//! CPUs without leaf `0xB` only say, in leaf 1, how many logical
+//! processors a package has. We count each of those as a core of its own.
+//!
+//! SMT siblings share their core's caches and execution units, which can
+//! leak what one of them is doing to the other, so the
+//! [scheduler](../../../task/sched/index.html) asks for a CPU's siblings
+//! before picking a task to run on it.
+use alloc::vec::Vec;
+use core::{cmp, fmt};
+use cpu::cpuid::{self, cpuid, CpuidResult};
+use spin::{Mutex, Once};
+
+use super::smp::MAX_CPUS;
+
+/// The level type of a leaf `0xB` subleaf describing SMT threads.
+const LEVEL_SMT: u32 = 1;
+/// The level type of a leaf `0xB` subleaf describing cores.
+const LEVEL_CORE: u32 = 2;
+
+/// How the CPUs are arranged into cores and packages.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CpuTopology { /// Hardware threads per core
+                         pub smt_per_core: u8
+                       , /// Cores per package
+                         pub cores_per_package: u8
+                       , /// Packages with a CPU that has started
+                         pub num_packages: u8
+                       }
+
+impl fmt::Display for CpuTopology {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "{} package(s) of {} core(s), {} thread(s) per core"
+              , self.num_packages, self.cores_per_package, self.smt_per_core)
+    }
+}
+
+/// What CPUID says about the SMT and core levels of the topology.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Levels { /// Bits of an x2APIC ID below the core ID
+                    pub smt_shift: u32
+                  , pub smt_per_core: u32
+                  , /// Bits of an x2APIC ID below the package ID
+                    pub core_shift: u32
+                  , /// Logical processors per package
+                    pub per_package: u32
+                  }
+
+/// Returns the level type of a leaf `0xB` subleaf.
+#[inline]
+fn level_type(leaf: &CpuidResult) -> u32 { (leaf.ecx >> 8) & 0xff }
+
+/// Returns the number of logical processors at a leaf `0xB` level.
+#[inline]
+fn level_count(leaf: &CpuidResult) -> u32 { leaf.ebx & 0xffff }
+
+/// Returns true if the CPU has leaf `0xB`.
+fn has_leaf_0b() -> bool {
+    cpuid(0, 0).eax >= cpuid::LEAF_TOPOLOGY
+        && level_count(&cpuid(cpuid::LEAF_TOPOLOGY, 0)) != 0
+}
+
+impl Levels {
+    /// Decode subleaves 0 and 1 of leaf `0xB`, or return `None` if they
+    /// aren't the SMT and core levels.
+    pub fn from_leaves(smt: CpuidResult, core: CpuidResult) -> Option<Self> {
+        if level_type(&smt) != LEVEL_SMT || level_type(&core) != LEVEL_CORE
+            || level_count(&smt) == 0 || level_count(&core) == 0 {
+            return None
+        }
+        Some(Levels { smt_shift: smt.eax & 0x1f
+                    , smt_per_core: level_count(&smt)
+                    , core_shift: core.eax & 0x1f
+                    , per_package: level_count(&core)
+                    })
+    }
+
+    /// Decode leaf 1, for CPUs without leaf `0xB`. Each logical processor
+    /// is taken to be a core.
+    pub fn from_leaf_1(features: CpuidResult) -> Self {
+        let per_package = if features.edx & cpuid::EDX_HTT != 0 {
+            cmp::max((features.ebx >> 16) & 0xff, 1)
+        } else {
+            1
+        };
+        // enough bits to number every logical processor in the package.
+        let core_shift = 32 - (per_package - 1).leading_zeros();
+        Levels { smt_shift: 0
+               , smt_per_core: 1
+               , core_shift: core_shift
+               , per_package: per_package
+               }
+    }
+
+    /// Read the levels from CPUID on this CPU.
+    pub fn read() -> Self {
+        if has_leaf_0b() {
+            let smt = cpuid(cpuid::LEAF_TOPOLOGY, 0);
+            let core = cpuid(cpuid::LEAF_TOPOLOGY, 1);
+            if let Some(levels) = Levels::from_leaves(smt, core) {
+                return levels
+            }
+        }
+        Levels::from_leaf_1(cpuid(cpuid::LEAF_FEATURES, 0))
+    }
+
+    /// Returns the ID of the core the CPU with `apic_id` is part of.
+    #[inline] pub fn core_id(&self, apic_id: u32) -> u32 {
+        apic_id >> self.smt_shift
+    }
+
+    /// Returns the ID of the package the CPU with `apic_id` is part of.
+    #[inline] pub fn package_id(&self, apic_id: u32) -> u32 {
+        apic_id >> self.core_shift
+    }
+
+    /// Returns the topology of the CPUs with the APIC IDs in `apic_ids`.
+    pub fn topology(&self, apic_ids: &[Option<u32>]) -> CpuTopology {
+        let mut packages: Vec<u32> = apic_ids.iter()
+            .filter_map(|&id| id)
+            .map(|id| self.package_id(id))
+            .collect();
+        packages.sort();
+        packages.dedup();
+        let cores = cmp::max(self.per_package / self.smt_per_core, 1);
+        CpuTopology { smt_per_core: saturate(self.smt_per_core as usize)
+                    , cores_per_package: saturate(cores as usize)
+                    , num_packages: saturate(cmp::max(packages.len(), 1))
+                    }
+    }
+
+    /// Returns the SMT siblings of each CPU in `apic_ids`, by CPU number,
+    /// not counting the CPU itself.
+    pub fn siblings(&self, apic_ids: &[Option<u32>]) -> Vec<Vec<u8>> {
+        let core_of = |id: &Option<u32>| id.map(|id| self.core_id(id));
+        apic_ids.iter().enumerate().map(|(cpu, id)| match core_of(id) {
+            None => Vec::new()
+          , core => apic_ids.iter().enumerate()
+                        .filter(|&(other, other_id)|
+                                other != cpu && core_of(other_id) == core)
+                        .map(|(other, _)| other as u8)
+                        .collect()
+        }).collect()
+    }
+}
+
+#[inline]
+fn saturate(n: usize) -> u8 { cmp::min(n, u8::max_value() as usize) as u8 }
+
+/// The APIC ID of each CPU that has started, by CPU number.
+static APIC_IDS: Mutex<[Option<u32>; MAX_CPUS]>
+    = Mutex::new([None; MAX_CPUS]);
+
+static TOPOLOGY: Once<CpuTopology> = Once::new();
+static SIBLINGS: Once<Vec<Vec<u8>>> = Once::new();
+
+/// Returns the x2APIC ID of this CPU, or its initial APIC ID if it doesn't
+/// have leaf `0xB`.
+fn apic_id() -> u32 {
+    if has_leaf_0b() { cpuid(cpuid::LEAF_TOPOLOGY, 0).edx }
+    else { cpuid(cpuid::LEAF_FEATURES, 0).ebx >> 24 }
+}
+
+/// Record the APIC ID of the CPU this is running on, which is CPU number
+/// `cpu_id`.
+///
+/// Each CPU calls this as it starts.
+pub fn register_cpu(cpu_id: u8) {
+    if let Some(slot) = APIC_IDS.lock().get_mut(cpu_id as usize) {
+        *slot = Some(apic_id());
+    }
+}
+
+/// Describe the topology of the CPUs that have started so far, from
+/// CPUID and their APIC IDs.
+pub fn build_from_cpuid() -> CpuTopology {
+    Levels::read().topology(&*APIC_IDS.lock())
+}
+
+/// Work out the topology of the CPUs that have started, and which of them
+/// are SMT siblings.
+///
+/// # Safety
+/// + This must be called once, on the BSP, after the APs have started.
+pub unsafe fn init() -> &'static CpuTopology {
+    // the BSP didn't come through the trampoline, so it hasn't registered.
+    register_cpu(0);
+    let levels = Levels::read();
+    let apic_ids = *APIC_IDS.lock();
+    SIBLINGS.call_once(|| levels.siblings(&apic_ids));
+    TOPOLOGY.call_once(|| levels.topology(&apic_ids))
+}
+
+/// Returns the topology, once it has been worked out.
+#[inline]
+pub fn topology() -> Option<&'static CpuTopology> { TOPOLOGY.try() }
+
+/// Returns the CPUs that share a physical core with CPU `cpu_id`, not
+/// counting `cpu_id` itself.
+///
+/// This is empty until the topology has been worked out.
+pub fn sibling_cpus(cpu_id: u8) -> &'static [u8] {
+    match SIBLINGS.try().and_then(|siblings| siblings.get(cpu_id as usize)) {
+        Some(siblings) => siblings
+      , None => &[]
+    }
+}
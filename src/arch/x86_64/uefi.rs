@@ -0,0 +1,280 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The UEFI system table.
+//!
+//! When the kernel is started by UEFI firmware (directly, as a UEFI
+//! application, or through our stub), it's handed the system table, which
+//! leads to the boot and runtime services and to the firmware's
+//! configuration tables, among them the ACPI RSDP.
+//!
+//! Firmware functions use the Microsoft x64 calling convention, so they're
+//! `extern "win64"`.
+//!
+//! See chapter 4 of the [UEFI specification] for the tables' layouts.
+//!
+//! [UEFI specification]: http://www.uefi.org/sites/default/files/resources/UEFI%20Spec%202_7_A%20Sept%206.pdf
+use core::{fmt, mem, slice};
+use memory::PAddr;
+
+use super::boot_args::{EfiSystemTable, EfiTableHeader, MemoryMapIterator};
+
+/// The system table's signature: `"IBI SYST"`.
+pub const SYSTEM_TABLE_SIGNATURE: u64 = 0x5453_5953_2049_4249;
+/// The boot services table's signature: `"BOOTSERV"`.
+pub const BOOT_SERVICES_SIGNATURE: u64 = 0x5652_4553_544f_4f42;
+/// The runtime services table's signature: `"RUNTSERV"`.
+pub const RUNTIME_SERVICES_SIGNATURE: u64 = 0x5652_4553_544e_5552;
+
+/// Where the CRC32 is in a table header.
+const CRC32_OFFSET: usize = 16;
+
+/// A GUID, as UEFI lays them out.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Guid { pub data1: u32
+                , pub data2: u16
+                , pub data3: u16
+                , pub data4: [u8; 8]
+                }
+
+/// The configuration table entry for an ACPI 2.0 (or later) RSDP.
+pub const EFI_ACPI_20_TABLE_GUID: Guid
+    = Guid { data1: 0x8868_e871, data2: 0xe4f1, data3: 0x11d3
+           , data4: [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81] };
+
+/// The configuration table entry for an ACPI 1.0 RSDP.
+pub const EFI_ACPI_TABLE_GUID: Guid
+    = Guid { data1: 0xeb9d_2d30, data2: 0x2d88, data3: 0x11d3
+           , data4: [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d] };
+
+/// The status a firmware function returns.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct EfiStatus(pub usize);
+
+/// The high bit of a status is set for errors.
+const ERROR_BIT: usize = 1 << 63;
+
+impl EfiStatus {
+    /// `EFI_SUCCESS`.
+    pub const fn success() -> Self { EfiStatus(0) }
+
+    /// The buffer was too small; the size it needed to be was returned.
+    pub const fn buffer_too_small() -> Self { EfiStatus(ERROR_BIT | 5) }
+
+    /// Returns true if the status is an error, rather than success or a
+    /// warning.
+    #[inline] pub fn is_error(&self) -> bool { self.0 & ERROR_BIT != 0 }
+}
+
+impl fmt::Debug for EfiStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_error() {
+            write!(f, "EfiStatus(error {})", self.0 & !ERROR_BIT)
+        } else {
+            write!(f, "EfiStatus({})", self.0)
+        }
+    }
+}
+
+/// An entry in the configuration table array.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ConfigurationTable { pub vendor_guid: Guid
+                              , pub vendor_table: *const u8
+                              }
+
+/// The UEFI system table.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SystemTable { pub hdr: EfiTableHeader
+                       , /// A null-terminated UCS-2 string
+                         pub firmware_vendor: *const u16
+                       , pub firmware_revision: u32
+                       , pub console_in_handle: *const u8
+                       , pub con_in: *const u8
+                       , pub console_out_handle: *const u8
+                       , pub con_out: *const u8
+                       , pub standard_error_handle: *const u8
+                       , pub std_err: *const u8
+                       , pub runtime_services: *const RuntimeServices
+                       , /// Null once boot services have exited
+                         pub boot_services: *const BootServices
+                       , pub number_of_table_entries: usize
+                       , pub configuration_table: *const ConfigurationTable
+                       }
+
+/// `GetMemoryMap`.
+pub type GetMemoryMap
+    = extern "win64" fn( memory_map_size: *mut usize
+                       , memory_map: *mut u8
+                       , map_key: *mut usize
+                       , descriptor_size: *mut usize
+                       , descriptor_version: *mut u32
+                       ) -> EfiStatus;
+
+/// The start of the boot services table.
+///
+/// Only the functions up to `GetMemoryMap` are described; the rest of the
+/// table is still there in memory.
+#[repr(C)]
+pub struct BootServices { pub hdr: EfiTableHeader
+                        , pub raise_tpl: usize
+                        , pub restore_tpl: usize
+                        , pub allocate_pages: usize
+                        , pub free_pages: usize
+                        , pub get_memory_map: GetMemoryMap
+                        }
+
+/// The start of the runtime services table.
+///
+/// None of its functions are called yet, so only the header is described.
+#[repr(C)]
+#[derive(Debug)]
+pub struct RuntimeServices { pub hdr: EfiTableHeader }
+
+/// A memory map returned by `GetMemoryMap`.
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryMap { /// Passed to `ExitBootServices`, to show the
+                       /// caller has the current map
+                       pub map_key: usize
+                     , pub descriptor_size: usize
+                     , descriptors: &'static [u8]
+                     }
+
+impl MemoryMap {
+    /// Returns an iterator over the memory map, for the frame allocator.
+    pub fn iter(&self) -> MemoryMapIterator {
+        MemoryMapIterator::Uefi { descriptors: self.descriptors
+                                , descriptor_size: self.descriptor_size
+                                }
+    }
+}
+
+/// Returns the CRC32 (the IEEE one, not CRC32C) of the `size`-byte table
+/// at `table`, as it would be with the header's CRC32 field zeroed.
+///
+/// # Safety
+/// + `table` must point to `size` readable bytes.
+pub unsafe fn table_crc32(table: *const u8, size: usize) -> u32 {
+    let bytes = slice::from_raw_parts(table, size);
+    !bytes.iter().enumerate().fold(!0u32, |crc, (i, &byte)| {
+        let byte = if i >= CRC32_OFFSET && i < CRC32_OFFSET + 4 { 0 }
+                   else { byte };
+        (0 .. 8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1))
+        })
+    })
+}
+
+/// Returns true if the table with header `hdr` has the signature
+/// `signature`, is at least `min_size` bytes long, and has a good CRC32.
+unsafe fn table_ok(hdr: &EfiTableHeader, signature: u64, min_size: usize)
+                   -> bool {
+    hdr.signature == signature
+        && hdr.header_size as usize >= min_size
+        && table_crc32( hdr as *const _ as *const u8
+                      , hdr.header_size as usize) == hdr.crc32
+}
+
+impl SystemTable {
+    /// Returns the system table at `ptr`, if its signature and CRC32 are
+    /// good.
+    ///
+    /// # Safety
+    /// + `ptr` must be null or point to readable memory, which stays
+    ///   mapped.
+    pub unsafe fn from_ptr(ptr: *const EfiSystemTable)
+                           -> Option<&'static SystemTable> {
+        match (ptr as *const SystemTable).as_ref() {
+            Some(table) if table_ok( &table.hdr, SYSTEM_TABLE_SIGNATURE
+                                   , mem::size_of::<SystemTable>()) =>
+                Some(table)
+          , _ => None
+        }
+    }
+
+    /// Returns the boot services, unless they've exited or their table is
+    /// bad.
+    pub fn boot_services(&self) -> Option<&'static BootServices> {
+        unsafe {
+            match self.boot_services.as_ref() {
+                Some(services) if table_ok( &services.hdr
+                                          , BOOT_SERVICES_SIGNATURE
+                                          , mem::size_of::<BootServices>()) =>
+                    Some(services)
+              , _ => None
+            }
+        }
+    }
+
+    /// Returns the runtime services, unless their table is bad.
+    pub fn runtime_services(&self) -> Option<&'static RuntimeServices> {
+        unsafe {
+            match self.runtime_services.as_ref() {
+                Some(services) if table_ok( &services.hdr
+                                          , RUNTIME_SERVICES_SIGNATURE
+                                          , mem::size_of::<RuntimeServices>())
+                    => Some(services)
+              , _ => None
+            }
+        }
+    }
+
+    /// Returns the configuration tables.
+    pub fn configuration_tables(&self) -> &'static [ConfigurationTable] {
+        if self.configuration_table.is_null() { return &[] }
+        unsafe {
+            slice::from_raw_parts( self.configuration_table
+                                 , self.number_of_table_entries)
+        }
+    }
+
+    /// Returns the configuration table identified by `guid`, if there is
+    /// one.
+    pub fn find_table(&self, guid: &Guid) -> Option<*const u8> {
+        self.configuration_tables().iter()
+            .find(|entry| entry.vendor_guid == *guid)
+            .map(|entry| entry.vendor_table)
+    }
+
+    /// Returns the address of the ACPI RSDP, preferring the ACPI 2.0 one,
+    /// which leads to the XSDT.
+    pub fn acpi_rsdp(&self) -> Option<PAddr> {
+        self.find_table(&EFI_ACPI_20_TABLE_GUID)
+            .or_else(|| self.find_table(&EFI_ACPI_TABLE_GUID))
+            .map(|ptr| PAddr::from(ptr as u64))
+    }
+}
+
+impl BootServices {
+    /// Get the memory map into `buf`.
+    ///
+    /// If `buf` is too small, this fails with `EFI_BUFFER_TOO_SMALL`, and
+    /// the size it needs to be is logged.
+    pub fn get_memory_map(&self, buf: &'static mut [u8])
+                          -> Result<MemoryMap, EfiStatus> {
+        let mut size = buf.len();
+        let mut map_key = 0;
+        let mut descriptor_size = 0;
+        let mut descriptor_version = 0;
+        let status = (self.get_memory_map)( &mut size, buf.as_mut_ptr()
+                                          , &mut map_key
+                                          , &mut descriptor_size
+                                          , &mut descriptor_version);
+        if status == EfiStatus::buffer_too_small() {
+            warn!("UEFI memory map needs {} bytes, have {}", size, buf.len());
+        }
+        if status.is_error() { return Err(status) }
+        let buf: &'static [u8] = buf;
+        Ok(MemoryMap { map_key: map_key
+                     , descriptor_size: descriptor_size
+                     , descriptors: &buf[..size]
+                     })
+    }
+}
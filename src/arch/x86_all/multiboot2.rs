@@ -16,7 +16,7 @@ use params::mem;
 
 use core::convert::Into;
 use core::iter::IntoIterator;
-use core::fmt;
+use core::{fmt, slice};
 
 const END_TAG_LEN: u32 = 8;
 
@@ -123,6 +123,24 @@ impl Info {
             })
     }
 
+    /// Finds the framebuffer info tag.
+    ///
+    ///  # Returns
+    ///  - `Some(FramebufferTag)` if the bootloader set up a framebuffer
+    ///  - `None` if no tag of the given type could be found.
+    #[inline]
+    pub fn framebuffer(&'static self) -> Option<&'static FramebufferTag> {
+        self.get_tag(TagType::FramebufferInfo)
+            .map(|tag| unsafe {
+                &*((tag as *const Tag) as *const FramebufferTag)
+            })
+    }
+
+    /// Returns an iterator over the boot modules, in the order the
+    /// bootloader loaded them.
+    #[inline]
+    pub fn modules(&'static self) -> Modules { Modules(self.tags()) }
+
     /// Returns an iterator over all Multiboot tags.
     #[inline]
     fn tags(&'static self) -> Tags { Tags(&self.tag_start as *const Tag) }
@@ -291,16 +309,61 @@ pub struct CommandLineTag { tag: Tag
                           }
 
 
+/// A tag describing a boot module loaded by the bootloader.
+///
+/// There's one of these for every module.
 #[repr(C)]
 pub struct ModulesTag { tag: Tag
-                      , /// The address at which the module begins.
-                        pub mod_begin: PAddr
-                      , /// The address at which the module ends.
-                        pub mod_end: PAddr
-                      , /// A string (typically a command line)
-                        pub string: [u8]
+                      , /// The physical address at which the module begins.
+                        pub mod_start: u32
+                      , /// The physical address at which the module ends.
+                        pub mod_end: u32
+                      , /// The first byte of the module's string
+                        string_start: u8
                       }
 
+impl ModulesTag {
+    /// Returns the physical address of the start of the module.
+    #[inline] pub fn start_addr(&self) -> PAddr {
+        PAddr::from(self.mod_start as u64)
+    }
+
+    /// Returns the physical address of the end of the module.
+    #[inline] pub fn end_addr(&self) -> PAddr {
+        PAddr::from(self.mod_end as u64)
+    }
+
+    /// Returns the string (typically a command line) the module was loaded
+    /// with, without its NUL terminator.
+    pub fn string(&self) -> &[u8] {
+        let offset = ::core::mem::size_of::<Tag>()
+                   + 2 * ::core::mem::size_of::<u32>();
+        let len = (self.tag.length as usize).saturating_sub(offset);
+        let bytes = unsafe {
+            slice::from_raw_parts(&self.string_start as *const u8, len)
+        };
+        match bytes.iter().position(|&b| b == 0) {
+            Some(nul) => &bytes[..nul]
+          , None => bytes
+        }
+    }
+}
+
+/// An iterator over the Multiboot 2 module tags.
+pub struct Modules(Tags);
+
+impl Iterator for Modules {
+    type Item = &'static ModulesTag;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.find(|t| t.ty == TagType::Modules)
+              .map(|tag| unsafe {
+                  &*((tag as *const Tag) as *const ModulesTag)
+              })
+    }
+}
+
 #[repr(u32)]
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum MemAreaType { Available = 1
@@ -378,6 +441,60 @@ impl Iterator for MemAreas {
     }
 }
 
+/// Framebuffer type: indexed color, with a palette.
+pub const FRAMEBUFFER_TYPE_INDEXED: u8 = 0;
+/// Framebuffer type: direct RGB color.
+pub const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+/// Framebuffer type: EGA text mode.
+pub const FRAMEBUFFER_TYPE_EGA_TEXT: u8 = 2;
+
+/// A Multiboot 2 framebuffer info tag
+#[repr(C)]
+#[derive(Debug)]
+pub struct FramebufferTag { tag: Tag
+                          , /// the physical address of the framebuffer
+                            pub addr: u64
+                          , /// the number of bytes in each row of pixels
+                            pub pitch: u32
+                          , /// the width of the framebuffer, in pixels
+                            pub width: u32
+                          , /// the height of the framebuffer, in pixels
+                            pub height: u32
+                          , /// the number of bits in each pixel
+                            pub bpp: u8
+                          , /// one of the `FRAMEBUFFER_TYPE_*` constants
+                            pub fb_type: u8
+                          , _reserved: u16
+                          , /// the position and size of each color
+                            /// channel, for RGB framebuffers only
+                            color_info: [u8; 6]
+                          }
+
+/// The position and size of one channel of an RGB pixel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ColorField { /// the bit offset of the channel within a pixel
+                        pub position: u8
+                      , /// the number of bits in the channel
+                        pub size: u8
+                      }
+
+impl FramebufferTag {
+    /// Returns true if this is a direct color framebuffer.
+    #[inline] pub fn is_rgb(&self) -> bool {
+        self.fb_type == FRAMEBUFFER_TYPE_RGB
+    }
+
+    /// Returns the red, green, and blue channels of an RGB framebuffer, or
+    /// `None` if the framebuffer isn't RGB.
+    pub fn rgb_fields(&self) -> Option<(ColorField, ColorField, ColorField)> {
+        if !self.is_rgb() { return None }
+        let field = |i: usize| ColorField { position: self.color_info[i * 2]
+                                          , size: self.color_info[i * 2 + 1]
+                                          };
+        Some((field(0), field(1), field(2)))
+    }
+}
+
 #[cfg(target_pointer_width = "32")]
 pub type Word = u32;
 #[cfg(target_pointer_width = "64")]
@@ -0,0 +1,621 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! AHCI driver, for SATA disks.
+//!
+//! The host bus adapter's registers are in its sixth BAR: some global ones,
+//! followed by a block for each of up to 32 ports. Each port with a disk on
+//! it has a command list in memory, whose entries point to command tables
+//! holding the ATA command and a list of the memory to transfer to or from
+//! (the PRDT).
+//!
+//! Like the other block drivers, we only use one command slot per port, and
+//! copy data through a bounce buffer in DMA memory.
+//!
+//! See the [AHCI specification] and the
+//! [OS Dev wiki](http://wiki.osdev.org/AHCI) for more information.
+//!
+//! [AHCI specification]: https://www.intel.com/content/www/us/en/io/serial-ata/serial-ata-ahci-spec-rev1-3-1.html
+use alloc::arc::Arc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{fence, Ordering};
+use cpu::tsc;
+use memory::{PAddr, PAGE_SIZE};
+use paging::arch::ActivePageTable;
+use spin::{Mutex, Once};
+
+use arch::interrupts;
+use dev::block::{self, BlockDevice, BlockError};
+use dev::pci::{Bar, PciDevice};
+use mm::dma::DmaBox;
+use mm::frame;
+use mm::mmio::MmioRegion;
+use task::wait::Semaphore;
+
+/// The PCI class of mass storage controllers.
+pub const CLASS_STORAGE: u8 = 0x01;
+/// The PCI subclass of SATA controllers.
+pub const SUBCLASS_SATA: u8 = 0x06;
+
+/// The size of a sector, in bytes.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Offsets of the global HBA registers.
+mod reg {
+    pub const CAP: usize = 0x00;
+    pub const GHC: usize = 0x04;
+    pub const IS: usize = 0x08;
+    pub const PI: usize = 0x0c;
+    /// The first port's registers start here
+    pub const PORTS: usize = 0x100;
+    /// The size of each port's registers
+    pub const PORT_SIZE: usize = 0x80;
+}
+
+/// Offsets of each port's registers, from the start of its block.
+mod port_reg {
+    pub const CLB: usize = 0x00;
+    pub const CLBU: usize = 0x04;
+    pub const FB: usize = 0x08;
+    pub const FBU: usize = 0x0c;
+    pub const IS: usize = 0x10;
+    pub const IE: usize = 0x14;
+    pub const CMD: usize = 0x18;
+    pub const TFD: usize = 0x20;
+    pub const SIG: usize = 0x24;
+    pub const SSTS: usize = 0x28;
+    pub const SERR: usize = 0x30;
+    pub const CI: usize = 0x38;
+}
+
+/// `CAP.S64A`: the HBA can address 64 bits of memory.
+const CAP_S64A: u32 = 1 << 31;
+
+/// `GHC.HR`: reset the HBA.
+const GHC_HR: u32 = 1 << 0;
+/// `GHC.IE`: interrupts are enabled.
+const GHC_IE: u32 = 1 << 1;
+/// `GHC.AE`: AHCI mode, rather than legacy IDE emulation.
+const GHC_AE: u32 = 1 << 31;
+
+/// `PxCMD` bits.
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+/// `PxIS` and `PxIE` bits: a D2H register FIS arrived, and a task file
+/// error.
+const IS_DHRS: u32 = 1 << 0;
+const IS_TFES: u32 = 1 << 30;
+
+/// `PxTFD` status bits.
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+/// `PxSSTS`: a device is present and communicating, and the link is
+/// active.
+const SSTS_DET_PRESENT: u32 = 3;
+const SSTS_IPM_ACTIVE: u32 = 1;
+
+/// `PxSIG` of a SATA disk (rather than, say, an ATAPI drive).
+const SIG_ATA: u32 = 0x0000_0101;
+
+/// The type of a host-to-device register FIS.
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+/// ATA commands.
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_IDENTIFY: u8 = 0xec;
+
+/// How long to wait for the HBA or a port to respond, in nanoseconds.
+const TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// The size of the bounce buffer, which is the most a command transfers.
+const MAX_TRANSFER: usize = PAGE_SIZE as usize;
+/// The most sectors transferred by a single command.
+const MAX_SECTORS: usize = MAX_TRANSFER / SECTOR_SIZE;
+
+/// An entry in a port's command list.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct CommandHeader { /// FIS length in dwords, the write bit, and more
+                       flags: u16
+                     , /// The number of PRDT entries
+                       prdtl: u16
+                     , /// Bytes transferred, filled in by the HBA
+                       prdbc: u32
+                     , /// The physical address of the command table
+                       ctba: u64
+                     , reserved: [u32; 4]
+                     }
+
+/// An entry in a command table's PRDT.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct PrdtEntry { /// The physical address of the data
+                   dba: u64
+                 , reserved: u32
+                 , /// The byte count, less one
+                   dbc: u32
+                 }
+
+/// A command table, with a single PRDT entry.
+#[repr(C)]
+struct CommandTable { /// The command FIS
+                      cfis: [u8; 64]
+                    , /// The ATAPI command, which we don't use
+                      acmd: [u8; 16]
+                    , reserved: [u8; 48]
+                    , prdt: PrdtEntry
+                    }
+
+/// The DMA memory for a port.
+///
+/// This starts at the beginning of a frame, which lines up everything the
+/// way the HBA wants it: the command list is 1 KiB aligned, the received
+/// FIS area 256 bytes aligned, and the command table 128 bytes aligned.
+#[repr(C)]
+struct PortMemory { command_list: [CommandHeader; 32]
+                  , received_fis: [u8; 256]
+                  , table: CommandTable
+                  , data: [u8; MAX_TRANSFER]
+                  }
+
+/// Errors returned by the AHCI driver.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AhciError { /// The device has no memory BAR for the HBA registers
+                     NoMemoryBar
+                   , /// We ran out of memory for registers or buffers
+                     NoMemory
+                   , /// The HBA or the disk took too long to respond
+                     Timeout
+                   , /// The disk reported an error, with this status
+                     Device(u8)
+                   , /// The HBA has no disks attached
+                     NoDisks
+                   , /// The request goes past the end of the disk
+                     OutOfRange
+                   , /// The buffer isn't as long as the request
+                     BadLength
+                   }
+
+impl fmt::Display for AhciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AhciError::NoMemoryBar => f.write_str("device has no AHCI BAR")
+          , AhciError::NoMemory => f.write_str("out of memory")
+          , AhciError::Timeout => f.write_str("timed out")
+          , AhciError::Device(status) =>
+                write!(f, "disk error, status {:#x}", status)
+          , AhciError::NoDisks => f.write_str("no disks attached")
+          , AhciError::OutOfRange => f.write_str("request out of range")
+          , AhciError::BadLength =>
+                f.write_str("buffer doesn't match request")
+        }
+    }
+}
+
+impl From<AhciError> for BlockError {
+    fn from(err: AhciError) -> Self {
+        match err {
+            AhciError::OutOfRange => BlockError::OutOfRange
+          , _ => BlockError::Io
+        }
+    }
+}
+
+/// Spin until `done` returns true, for at most `TIMEOUT_NS`.
+fn wait_for<F: Fn() -> bool>(done: F) -> Result<(), AhciError> {
+    let until = tsc::current_ns() + TIMEOUT_NS;
+    while !done() {
+        if tsc::current_ns() >= until { return Err(AhciError::Timeout) }
+    }
+    Ok(())
+}
+
+/// Returns the ATA string `words[..]`, whose bytes are swapped in pairs.
+fn ata_string(words: &[u16]) -> String {
+    let mut string = String::new();
+    for &word in words {
+        string.push((word >> 8) as u8 as char);
+        string.push(word as u8 as char);
+    }
+    String::from(string.trim())
+}
+
+/// A port with a SATA disk on it.
+pub struct AhciPort { /// The port's number on the HBA
+                      number: u8
+                    , regs: MmioRegion
+                    , /// The disk's model number
+                      model: String
+                    , /// Size of the disk, in sectors
+                      capacity: u64
+                    , memory: Mutex<DmaBox<PortMemory>>
+                    , /// Held by the task with a command in flight
+                      busy: Semaphore
+                    , /// Signalled by the interrupt handler when the port
+                      /// has finished a command
+                      complete: Semaphore
+                    }
+
+impl AhciPort {
+    /// Set up port `number`, whose registers are at `phys`, if there's a
+    /// disk on it.
+    fn probe(number: u8, phys: PAddr) -> Result<Option<AhciPort>, AhciError> {
+        let regs = MmioRegion::new( phys, reg::PORT_SIZE
+                                  , &mut unsafe { ActivePageTable::new() }
+                                  , &mut frame::allocator())
+            .map_err(|_| AhciError::NoMemory)?;
+        let ssts = regs.read_u32(port_reg::SSTS);
+        if ssts & 0xf != SSTS_DET_PRESENT
+            || (ssts >> 8) & 0xf != SSTS_IPM_ACTIVE
+            || regs.read_u32(port_reg::SIG) != SIG_ATA {
+            return Ok(None)
+        }
+        let memory = unsafe { DmaBox::<PortMemory>::zeroed() }
+            .map_err(|_| AhciError::NoMemory)?;
+        let mut port = AhciPort { number: number
+                                , regs: regs
+                                , model: String::new()
+                                , capacity: 0
+                                , memory: Mutex::new(memory)
+                                , busy: Semaphore::new(1)
+                                , complete: Semaphore::new(0)
+                                };
+        port.stop()?;
+        {
+            let memory = port.memory.lock();
+            let list = *memory.paddr_of(&memory.command_list);
+            let fis = *memory.paddr_of(&memory.received_fis);
+            port.regs.write_u32(port_reg::CLB, list as u32);
+            port.regs.write_u32(port_reg::CLBU, (list >> 32) as u32);
+            port.regs.write_u32(port_reg::FB, fis as u32);
+            port.regs.write_u32(port_reg::FBU, (fis >> 32) as u32);
+        }
+        port.start()?;
+        port.regs.write_u32(port_reg::IE, IS_DHRS | IS_TFES);
+
+        port.prepare(ATA_IDENTIFY, 0, 0);
+        port.issue_polled()?;
+        let words: Vec<u16> = {
+            let memory = port.memory.lock();
+            memory.data[..SECTOR_SIZE].chunks(2)
+                  .map(|pair| pair[0] as u16 | (pair[1] as u16) << 8)
+                  .collect()
+        };
+        port.model = ata_string(&words[27..47]);
+        // words 100-103 hold the 48-bit sector count, if the disk supports
+        // 48-bit addressing, and words 60-61 the 28-bit one.
+        port.capacity = if words[83] & (1 << 10) != 0 {
+            words[100..104].iter().rev()
+                           .fold(0, |count, &word| count << 16 | word as u64)
+        } else {
+            words[60] as u64 | (words[61] as u64) << 16
+        };
+        Ok(Some(port))
+    }
+
+    /// Stop the port's command engine, and wait for it to stop.
+    fn stop(&self) -> Result<(), AhciError> {
+        let cmd = self.regs.read_u32(port_reg::CMD);
+        self.regs.write_u32(port_reg::CMD, cmd & !CMD_ST);
+        wait_for(|| self.regs.read_u32(port_reg::CMD) & CMD_CR == 0)?;
+        let cmd = self.regs.read_u32(port_reg::CMD);
+        self.regs.write_u32(port_reg::CMD, cmd & !CMD_FRE);
+        wait_for(|| self.regs.read_u32(port_reg::CMD) & CMD_FR == 0)
+    }
+
+    /// Clear the port's errors, and start its command engine.
+    fn start(&self) -> Result<(), AhciError> {
+        self.regs.write_u32(port_reg::SERR, !0);
+        self.regs.write_u32(port_reg::IS, !0);
+        let cmd = self.regs.read_u32(port_reg::CMD);
+        self.regs.write_u32(port_reg::CMD, cmd | CMD_FRE);
+        wait_for(|| self.regs.read_u32(port_reg::TFD)
+                         & (TFD_BSY | TFD_DRQ) == 0)?;
+        let cmd = self.regs.read_u32(port_reg::CMD);
+        self.regs.write_u32(port_reg::CMD, cmd | CMD_ST);
+        Ok(())
+    }
+
+    /// Returns the size of the disk, in sectors.
+    #[inline] pub fn capacity(&self) -> u64 { self.capacity }
+
+    /// Fill in command slot 0 for the ATA command `command`, for `count`
+    /// sectors at `lba`, using the bounce buffer.
+    fn prepare(&self, command: u8, lba: u64, count: usize) {
+        let mut memory = self.memory.lock();
+        let memory = &mut *memory;
+        let table = memory.paddr_of(&memory.table);
+        let data = memory.paddr_of(&memory.data);
+        let len = if command == ATA_IDENTIFY { SECTOR_SIZE }
+                  else { count * SECTOR_SIZE };
+        let fis_dwords = 5;
+        let write = if command == ATA_WRITE_DMA_EXT { 1 << 6 } else { 0 };
+        memory.command_list[0] = CommandHeader { flags: fis_dwords | write
+                                               , prdtl: 1
+                                               , prdbc: 0
+                                               , ctba: *table
+                                               , reserved: [0; 4]
+                                               };
+        {
+            let fis = &mut memory.table.cfis;
+            for byte in fis.iter_mut() { *byte = 0 }
+            fis[0] = FIS_TYPE_REG_H2D;
+            fis[1] = 1 << 7; // this is a command, not a control update
+            fis[2] = command;
+            for i in 0..3 {
+                fis[4 + i] = (lba >> (8 * i)) as u8;
+                fis[8 + i] = (lba >> (8 * (i + 3))) as u8;
+            }
+            fis[7] = 1 << 6; // LBA addressing
+            fis[12] = count as u8;
+            fis[13] = (count >> 8) as u8;
+        }
+        memory.table.prdt = PrdtEntry { dba: *data
+                                      , reserved: 0
+                                      , dbc: len as u32 - 1
+                                      };
+    }
+
+    /// Tell the HBA to run command slot 0.
+    fn issue(&self) -> Result<(), AhciError> {
+        wait_for(|| self.regs.read_u32(port_reg::TFD)
+                         & (TFD_BSY | TFD_DRQ) == 0)?;
+        // the command must be in memory before the HBA looks for it.
+        fence(Ordering::SeqCst);
+        self.regs.write_u32(port_reg::CI, 1);
+        Ok(())
+    }
+
+    /// Returns `Ok(true)` once command slot 0 has finished, `Ok(false)` if
+    /// it's still running, or the error if it failed.
+    ///
+    /// A failed command stops the port, so this restarts it.
+    fn finished(&self) -> Result<bool, AhciError> {
+        let tfd = self.regs.read_u32(port_reg::TFD);
+        if tfd & TFD_ERR != 0 {
+            self.stop()?;
+            self.start()?;
+            return Err(AhciError::Device(tfd as u8))
+        }
+        Ok(self.regs.read_u32(port_reg::CI) & 1 == 0)
+    }
+
+    /// Run command slot 0, and spin until it finishes.
+    ///
+    /// This is for commands sent before the interrupt handler is
+    /// registered.
+    fn issue_polled(&self) -> Result<(), AhciError> {
+        self.issue()?;
+        let until = tsc::current_ns() + TIMEOUT_NS;
+        while !self.finished()? {
+            if tsc::current_ns() >= until { return Err(AhciError::Timeout) }
+        }
+        Ok(())
+    }
+
+    /// Run command slot 0, and wait for the interrupt saying it's finished.
+    ///
+    /// The caller must hold `busy`.
+    fn issue_and_wait(&self) -> Result<(), AhciError> {
+        self.issue()?;
+        // the interrupt may arrive before we get around to waiting for it,
+        // or the semaphore be left over from an earlier one, so check the
+        // port rather than trusting the semaphore.
+        while !self.finished()? {
+            self.complete.down();
+        }
+        Ok(())
+    }
+
+    /// Check that a request for `count` sectors at `lba`, with a `len`-byte
+    /// buffer, makes sense.
+    fn check(&self, lba: u64, count: u16, len: usize) -> Result<(), AhciError> {
+        if len != count as usize * SECTOR_SIZE {
+            return Err(AhciError::BadLength)
+        }
+        match lba.checked_add(count as u64) {
+            Some(end) if end <= self.capacity => Ok(())
+          , _ => Err(AhciError::OutOfRange)
+        }
+    }
+
+    /// Read `count` sectors starting at sector `lba` into `buf`, which must
+    /// be exactly `count` sectors long.
+    ///
+    /// This blocks until the disk has finished.
+    pub fn read_sectors(&self, lba: u64, count: u16, buf: &mut [u8])
+                       -> Result<(), AhciError> {
+        self.check(lba, count, buf.len())?;
+        self.busy.down();
+        let mut result = Ok(());
+        for (i, chunk) in buf.chunks_mut(MAX_TRANSFER).enumerate() {
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            self.prepare(ATA_READ_DMA_EXT, lba, chunk.len() / SECTOR_SIZE);
+            result = self.issue_and_wait();
+            if result.is_err() { break }
+            chunk.copy_from_slice(&self.memory.lock().data[..chunk.len()]);
+        }
+        self.busy.up();
+        result
+    }
+
+    /// Write `buf`, which must be exactly `count` sectors long, to the
+    /// `count` sectors starting at sector `lba`.
+    ///
+    /// This blocks until the disk has finished.
+    pub fn write_sectors(&self, lba: u64, count: u16, buf: &[u8])
+                        -> Result<(), AhciError> {
+        self.check(lba, count, buf.len())?;
+        self.busy.down();
+        let mut result = Ok(());
+        for (i, chunk) in buf.chunks(MAX_TRANSFER).enumerate() {
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            self.memory.lock().data[..chunk.len()].copy_from_slice(chunk);
+            self.prepare(ATA_WRITE_DMA_EXT, lba, chunk.len() / SECTOR_SIZE);
+            result = self.issue_and_wait();
+            if result.is_err() { break }
+        }
+        self.busy.up();
+        result
+    }
+
+    /// Acknowledge the port's interrupt, returning true if it had one.
+    fn ack_interrupt(&self) -> bool {
+        let status = self.regs.read_u32(port_reg::IS);
+        self.regs.write_u32(port_reg::IS, status);
+        status != 0
+    }
+}
+
+impl BlockDevice for AhciPort {
+    #[inline] fn block_size(&self) -> usize { SECTOR_SIZE }
+
+    #[inline] fn block_count(&self) -> u64 { self.capacity }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        assert_eq!(buf.len(), SECTOR_SIZE, "buffer must be one block long");
+        self.read_sectors(lba, 1, buf).map_err(BlockError::from)
+    }
+
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        assert_eq!(buf.len(), SECTOR_SIZE, "buffer must be one block long");
+        self.write_sectors(lba, 1, buf).map_err(BlockError::from)
+    }
+}
+
+impl fmt::Debug for AhciPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "AhciPort({}: {}, {} MiB)", self.number, self.model
+              , self.capacity * SECTOR_SIZE as u64 / (1024 * 1024))
+    }
+}
+
+/// An AHCI host bus adapter.
+pub struct AhciController { regs: MmioRegion
+                          , /// The legacy IRQ line the HBA interrupts on
+                            irq: u8
+                          , /// The ports with disks on them
+                            ports: Vec<Arc<AhciPort>>
+                          }
+
+/// The HBA, once it has been found.
+static AHCI: Once<AhciController> = Once::new();
+
+impl AhciController {
+    /// Reset `dev`, if it is an AHCI HBA, and set up the disks attached to
+    /// it.
+    ///
+    /// Its interrupts aren't enabled, so its disks can't complete any
+    /// requests until it has been passed to [`init`](fn.init.html).
+    pub fn probe(dev: &PciDevice) -> Option<AhciController> {
+        if dev.class != CLASS_STORAGE || dev.subclass != SUBCLASS_SATA {
+            return None
+        }
+        match AhciController::setup(dev) {
+            Ok(ahci) => Some(ahci)
+          , Err(why) => {
+                warn!("ahci {:?}: {}", dev, why);
+                None
+            }
+        }
+    }
+
+    fn setup(dev: &PciDevice) -> Result<AhciController, AhciError> {
+        let base = match dev.bar(5) {
+            Some(Bar::Memory(addr)) => addr
+          , _ => return Err(AhciError::NoMemoryBar)
+        };
+        dev.enable_bus_master();
+        let regs = MmioRegion::new( base, reg::PORTS
+                                  , &mut unsafe { ActivePageTable::new() }
+                                  , &mut frame::allocator())
+            .map_err(|_| AhciError::NoMemory)?;
+
+        // some HBAs only reset in AHCI mode, and the reset takes them out
+        // of it again.
+        regs.write_u32(reg::GHC, GHC_AE);
+        regs.write_u32(reg::GHC, GHC_AE | GHC_HR);
+        wait_for(|| regs.read_u32(reg::GHC) & GHC_HR == 0)?;
+        regs.write_u32(reg::GHC, GHC_AE);
+
+        if regs.read_u32(reg::CAP) & CAP_S64A == 0 {
+            // TODO: keep port memory below 4 GiB for HBAs like this.
+            warn!("ahci {:?}: HBA can only address 32 bits", dev);
+        }
+        let implemented = regs.read_u32(reg::PI);
+        let mut ports = Vec::new();
+        for number in (0..32).filter(|&n| implemented & (1 << n) != 0) {
+            let phys = base + (reg::PORTS + number * reg::PORT_SIZE) as u64;
+            match AhciPort::probe(number as u8, phys) {
+                Ok(Some(port)) => ports.push(Arc::new(port))
+              , Ok(None) => {}
+              , Err(why) => warn!("ahci {:?}: port {}: {}", dev, number, why)
+            }
+        }
+        if ports.is_empty() { return Err(AhciError::NoDisks) }
+        Ok(AhciController { regs: regs
+                          , irq: dev.interrupt_line()
+                          , ports: ports
+                          })
+    }
+
+    /// Returns the ports with disks on them.
+    #[inline] pub fn ports(&self) -> &[Arc<AhciPort>] { &self.ports }
+}
+
+impl fmt::Debug for AhciController {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.ports.iter()).finish()
+    }
+}
+
+/// Handler for the HBA's IRQ.
+fn handle_irq() {
+    if let Some(ahci) = AHCI.try() {
+        let pending = ahci.regs.read_u32(reg::IS);
+        for port in &ahci.ports {
+            if pending & (1 << port.number) != 0 && port.ack_interrupt() {
+                port.complete.up();
+            }
+        }
+        // the ports' interrupts have to be cleared first, or the HBA's
+        // will just be raised again.
+        ahci.regs.write_u32(reg::IS, pending);
+    }
+}
+
+/// Set up `dev`, if it is an AHCI HBA and we don't already have one, and
+/// register each of its disks as a block device.
+///
+/// Returns true if the HBA was set up.
+pub fn init(dev: &PciDevice) -> bool {
+    if AHCI.try().is_some() { return false }
+    match AhciController::probe(dev) {
+        Some(ahci) => {
+            let irq = ahci.irq;
+            info!("ahci {:?}: {:?}, IRQ {}", dev, ahci, irq);
+            let ahci = AHCI.call_once(|| ahci);
+            interrupts::register_irq(irq, handle_irq);
+            let ghc = ahci.regs.read_u32(reg::GHC);
+            ahci.regs.write_u32(reg::GHC, ghc | GHC_IE);
+            for port in &ahci.ports {
+                block::register(port.clone());
+            }
+            true
+        }
+      , None => false
+    }
+}
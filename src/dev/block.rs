@@ -0,0 +1,154 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Block devices.
+//!
+//! Anything that stores data in fixed-size blocks (disks, mostly) is a
+//! [`BlockDevice`](trait.BlockDevice.html). Drivers register the devices
+//! they find, so that file systems can be mounted from them.
+//!
+//! A [`Ramdisk`](struct.Ramdisk.html) is a block device backed by memory,
+//! for exercising file systems without any hardware.
+use alloc::arc::Arc;
+use alloc::vec::Vec;
+use core::{cmp, fmt};
+use spin::{Mutex, RwLock};
+
+/// Errors returned by block devices.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockError { /// The block is past the end of the device
+                      OutOfRange
+                    , /// The device failed to carry out the request
+                      Io
+                    , /// The device can't be written to
+                      ReadOnly
+                    }
+
+/// A device addressed in fixed-size blocks.
+///
+/// All methods take `&self`, so devices are responsible for their own
+/// locking, just like inodes.
+pub trait BlockDevice: Send + Sync {
+    /// Returns the size of a block, in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Returns the number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    /// Read block number `lba` into `buf`.
+    ///
+    /// # Panics
+    /// + If `buf` isn't exactly `block_size()` bytes long.
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf` to block number `lba`.
+    ///
+    /// # Panics
+    /// + If `buf` isn't exactly `block_size()` bytes long.
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Read `buf.len()` bytes from `dev`, starting at byte `offset`, whether
+/// or not they line up with its blocks.
+pub fn read_bytes(dev: &BlockDevice, offset: u64, buf: &mut [u8])
+                 -> Result<(), BlockError> {
+    let block_size = dev.block_size();
+    let mut block = vec![0u8; block_size];
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let lba = pos / block_size as u64;
+        let within = (pos % block_size as u64) as usize;
+        let n = cmp::min(block_size - within, buf.len() - done);
+        dev.read_block(lba, &mut block)?;
+        buf[done .. done + n].copy_from_slice(&block[within .. within + n]);
+        done += n;
+    }
+    Ok(())
+}
+
+lazy_static! {
+    /// Every block device that has been found, in the order they were found.
+    static ref DEVICES: RwLock<Vec<Arc<BlockDevice>>> = RwLock::new(Vec::new());
+}
+
+/// Make `device` available to the rest of the kernel, returning its index.
+pub fn register(device: Arc<BlockDevice>) -> usize {
+    let mut devices = DEVICES.write();
+    devices.push(device);
+    devices.len() - 1
+}
+
+/// Returns the block device with index `index`, if there is one.
+pub fn get(index: usize) -> Option<Arc<BlockDevice>> {
+    DEVICES.read().get(index).cloned()
+}
+
+/// A block device backed by memory.
+pub struct Ramdisk { data: Mutex<&'static mut [u8]>
+                   , block_size: usize
+                   }
+
+impl Ramdisk {
+    /// Returns a ramdisk with `block_size`-byte blocks, stored in `data`.
+    ///
+    /// If `data` isn't a whole number of blocks long, the partial block at
+    /// the end is ignored.
+    ///
+    /// # Panics
+    /// + If `block_size` is zero.
+    pub fn from_static_slice(data: &'static mut [u8], block_size: usize)
+                            -> Self {
+        assert!(block_size > 0, "block size must not be zero");
+        Ramdisk { data: Mutex::new(data), block_size: block_size }
+    }
+
+    /// Returns the byte offset of block `lba`, if it's on the disk.
+    fn offset(&self, lba: u64) -> Result<usize, BlockError> {
+        if lba < self.block_count() {
+            Ok(lba as usize * self.block_size)
+        } else {
+            Err(BlockError::OutOfRange)
+        }
+    }
+}
+
+impl BlockDevice for Ramdisk {
+    #[inline] fn block_size(&self) -> usize { self.block_size }
+
+    #[inline] fn block_count(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        assert_eq!( buf.len(), self.block_size
+                  , "buffer must be one block long");
+        let offset = self.offset(lba)?;
+        let data = self.data.lock();
+        buf.copy_from_slice(&data[offset .. offset + self.block_size]);
+        Ok(())
+    }
+
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        assert_eq!( buf.len(), self.block_size
+                  , "buffer must be one block long");
+        let offset = self.offset(lba)?;
+        let mut data = self.data.lock();
+        data[offset .. offset + self.block_size].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Ramdisk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Ramdisk")
+         .field("block_size", &self.block_size)
+         .field("block_count", &self.block_count())
+         .finish()
+    }
+}
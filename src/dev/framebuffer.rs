@@ -0,0 +1,267 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Linear framebuffer graphics.
+//!
+//! If the bootloader switched to a graphics mode, it leaves a description
+//! of the framebuffer in the Multiboot 2 info. We map the framebuffer into
+//! the physical memory map, uncached, and draw on it directly. Once it's
+//! mapped, it belongs to the [text console](../../io/console/index.html).
+//!
+//! Colors are always given as `0x00RRGGBB`, and converted to the
+//! framebuffer's own pixel layout as they are drawn.
+use core::{fmt, ptr};
+use memory::{PAddr, Page, PhysicalPage, VirtualPage};
+use paging::Mapper;
+use paging::arch::ActivePageTable;
+use paging::arch::space::phys_to_virt;
+use paging::arch::table::{ NO_CACHE, NO_EXECUTE, WRITABLE
+                         , WRITE_THROUGH };
+use params::InitParams;
+
+use arch::multiboot2::{self, ColorField, FramebufferTag};
+use io::console;
+use mm::frame;
+
+/// A linear framebuffer with 24 or 32 bits per pixel.
+pub struct Framebuffer { /// The first byte of the framebuffer
+                         base: *mut u8
+                       , width: u32
+                       , height: u32
+                       , /// The number of bytes in each row of pixels
+                         pitch: u32
+                       , /// Bits per pixel: either 24 or 32
+                         bpp: u8
+                       , red: ColorField
+                       , green: ColorField
+                       , blue: ColorField
+                       }
+
+// the framebuffer is mapped for the lifetime of the kernel.
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// Map the framebuffer described by `tag`.
+    ///
+    /// Only direct color framebuffers with 24 or 32 bits per pixel are
+    /// supported.
+    pub fn from_multiboot2(tag: &FramebufferTag)
+                          -> Result<Framebuffer, &'static str> {
+        let (red, green, blue) = tag.rgb_fields()
+            .ok_or("framebuffer is not in an RGB mode")?;
+        if tag.bpp != 24 && tag.bpp != 32 {
+            return Err("framebuffer must have 24 or 32 bits per pixel")
+        }
+        let size = tag.pitch as u64 * tag.height as u64;
+        if size == 0 {
+            return Err("framebuffer is empty")
+        }
+
+        let start = PAddr::from(tag.addr);
+        let base = phys_to_virt(start);
+        let first_frame = PhysicalPage::containing(start);
+        let last_frame = PhysicalPage::containing(start + (size - 1));
+        let first_page = VirtualPage::containing(base);
+
+        let mut table = unsafe { ActivePageTable::new() };
+        let mut frames = frame::allocator();
+        for i in 0 .. last_frame.number - first_frame.number + 1 {
+            let page = VirtualPage { number: first_page.number + i as usize };
+            // the framebuffer usually isn't in RAM, so it won't be in the
+            // physical memory map yet, but it might be if it's shared
+            // memory from the integrated graphics.
+            if table.is_mapped(&page) { continue }
+            let frame = PhysicalPage { number: first_frame.number + i };
+            table.map( page, frame
+                     , WRITABLE | WRITE_THROUGH | NO_CACHE | NO_EXECUTE
+                     , &mut frames )
+                 .map_err(|_| "could not map framebuffer")?;
+        }
+
+        Ok(Framebuffer { base: base.as_mut_ptr::<u8>()
+                       , width: tag.width
+                       , height: tag.height
+                       , pitch: tag.pitch
+                       , bpp: tag.bpp
+                       , red: red
+                       , green: green
+                       , blue: blue
+                       })
+    }
+
+    /// Returns the width of the framebuffer, in pixels.
+    #[inline] pub fn width(&self) -> u32 { self.width }
+
+    /// Returns the height of the framebuffer, in pixels.
+    #[inline] pub fn height(&self) -> u32 { self.height }
+
+    /// Returns the number of bytes in each row of pixels.
+    #[inline] pub fn pitch(&self) -> u32 { self.pitch }
+
+    /// Returns the number of bits in each pixel.
+    #[inline] pub fn bpp(&self) -> u8 { self.bpp }
+
+    /// Convert an `0x00RRGGBB` color to the framebuffer's pixel layout.
+    #[inline]
+    fn encode(&self, color: u32) -> u32 {
+        #[inline]
+        fn channel(value: u32, field: ColorField) -> u32 {
+            let value = value & 0xff;
+            let scaled = if field.size <= 8 { value >> (8 - field.size) }
+                         else { value << (field.size - 8) };
+            scaled << field.position
+        }
+        channel(color >> 16, self.red)
+            | channel(color >> 8, self.green)
+            | channel(color, self.blue)
+    }
+
+    /// Returns a pointer to the pixel at `(x, y)`, which must be on screen.
+    #[inline]
+    fn pixel_ptr(&self, x: u32, y: u32) -> *mut u8 {
+        debug_assert!(x < self.width && y < self.height);
+        let offset = y as usize * self.pitch as usize
+                   + x as usize * (self.bpp as usize / 8);
+        unsafe { self.base.offset(offset as isize) }
+    }
+
+    /// Write an already encoded pixel to `dst`.
+    #[inline]
+    unsafe fn put(&self, dst: *mut u8, pixel: u32) {
+        if self.bpp == 32 {
+            ptr::write_volatile(dst as *mut u32, pixel);
+        } else {
+            ptr::write_volatile(dst, pixel as u8);
+            ptr::write_volatile(dst.offset(1), (pixel >> 8) as u8);
+            ptr::write_volatile(dst.offset(2), (pixel >> 16) as u8);
+        }
+    }
+
+    /// Set the pixel at `(x, y)` to `color`.
+    ///
+    /// Pixels off the edge of the screen are ignored.
+    #[inline]
+    pub fn write_pixel(&mut self, x: u32, y: u32, color: u32) {
+        if x >= self.width || y >= self.height { return }
+        let pixel = self.encode(color);
+        unsafe { self.put(self.pixel_ptr(x, y), pixel) }
+    }
+
+    /// Clip the `w` by `h` rectangle at `(x, y)` to the screen, returning
+    /// its new width and height.
+    #[inline]
+    fn clip(&self, x: u32, y: u32, w: u32, h: u32) -> (u32, u32) {
+        if x >= self.width || y >= self.height { return (0, 0) }
+        (w.min(self.width - x), h.min(self.height - y))
+    }
+
+    /// Fill the `w` by `h` rectangle whose top left corner is `(x, y)` with
+    /// `color`.
+    ///
+    /// Any part of the rectangle off the edge of the screen is ignored.
+    pub fn fill_rect(&mut self, x: u32, y: u32, w: u32, h: u32, color: u32) {
+        let (w, h) = self.clip(x, y, w, h);
+        if w == 0 || h == 0 { return }
+        let pixel = self.encode(color);
+        let step = self.bpp as isize / 8;
+        for row in y .. y + h {
+            let mut dst = self.pixel_ptr(x, row);
+            for _ in 0 .. w {
+                unsafe {
+                    self.put(dst, pixel);
+                    dst = dst.offset(step);
+                }
+            }
+        }
+    }
+
+    /// Copy the `w` by `h` image in `src`, stored row by row as
+    /// `0x00RRGGBB` colors, to the screen with its top left corner at
+    /// `(dx, dy)`.
+    ///
+    /// Any part of the image off the edge of the screen is ignored.
+    ///
+    /// # Panics
+    /// + If `src` has fewer than `w * h` pixels.
+    pub fn blit_from( &mut self, src: &[u32]
+                    , dx: u32, dy: u32, w: u32, h: u32) {
+        assert!( src.len() >= w as usize * h as usize
+               , "source image is too small");
+        let (clipped_w, clipped_h) = self.clip(dx, dy, w, h);
+        if clipped_w == 0 || clipped_h == 0 { return }
+        let step = self.bpp as isize / 8;
+        for row in 0 .. clipped_h {
+            let start = row as usize * w as usize;
+            let line = &src[start .. start + clipped_w as usize];
+            let mut dst = self.pixel_ptr(dx, dy + row);
+            for &color in line {
+                unsafe {
+                    self.put(dst, self.encode(color));
+                    dst = dst.offset(step);
+                }
+            }
+        }
+    }
+
+    /// Move everything on screen up by `lines` rows of pixels, filling the
+    /// rows uncovered at the bottom with `color`.
+    pub fn scroll_up(&mut self, lines: u32, color: u32) {
+        if lines == 0 { return }
+        if lines >= self.height {
+            let (width, height) = (self.width, self.height);
+            return self.fill_rect(0, 0, width, height, color)
+        }
+        let pitch = self.pitch as usize;
+        unsafe {
+            let src = self.base.offset((lines as usize * pitch) as isize);
+            ptr::copy(src, self.base, (self.height - lines) as usize * pitch);
+        }
+        let (width, height) = (self.width, self.height);
+        self.fill_rect(0, height - lines, width, lines, color);
+    }
+}
+
+impl fmt::Debug for Framebuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "Framebuffer({}x{}x{} at {:p})"
+              , self.width, self.height, self.bpp, self.base)
+    }
+}
+
+/// Map the framebuffer described by the Multiboot 2 info, if there is one,
+/// and start the text console on it.
+///
+/// This must be called after the kernel has been remapped, since the
+/// framebuffer is accessed through the physical memory map.
+pub fn init(params: &InitParams) {
+    let info = match unsafe {
+        multiboot2::Info::from(params.multiboot_start())
+    } {
+        Ok(info) => info
+      , Err(why) => {
+            warn!("could not read Multiboot info: {}", why);
+            return
+        }
+    };
+    let tag = match info.framebuffer() {
+        Some(tag) => tag
+      , None => {
+            kinfoln!(dots: " . . ", "No framebuffer.");
+            return
+        }
+    };
+    match Framebuffer::from_multiboot2(tag) {
+        Ok(fb) => {
+            kinfoln!(dots: " . . ", "Found {:?}", fb);
+            if let Err(why) = console::init(fb) {
+                kinfoln!(dots: " . . ", "No text console: {}", why);
+            }
+        }
+      , Err(why) => kinfoln!(dots: " . . ", "Framebuffer unusable: {}", why)
+    }
+}
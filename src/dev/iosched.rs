@@ -0,0 +1,149 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A credit-based I/O scheduler, for sharing a disk fairly between tasks.
+//!
+//! Each task with requests queued has a credit balance, in bytes. The next
+//! request dispatched is the oldest one from whichever task has the most
+//! credit, and dispatching it costs that task the request's length. Every
+//! `QUANTUM_NS`, every task earns `CREDIT_RATE` bytes back, up to
+//! `MAX_CREDIT`, so a task doing lots of I/O gives way to one doing a
+//! little.
+//!
+//! Credit never falls below `MIN_CREDIT`, however much a task has queued,
+//! so even a task that has just issued a huge request is served again once
+//! a bounded number of quanta have passed.
+use alloc::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use alloc::vec_deque::VecDeque;
+use core::{cmp, fmt, i32};
+
+use task::Pid;
+
+/// The credit, in bytes, that every task earns each quantum.
+pub const CREDIT_RATE: i32 = 256 * 1024;
+/// The length of a scheduler quantum.
+pub const QUANTUM_NS: u64 = 10_000_000;
+/// The most credit a task can save up.
+pub const MAX_CREDIT: i32 = 4 * CREDIT_RATE;
+/// The least credit a task can have.
+pub const MIN_CREDIT: i32 = -4 * CREDIT_RATE;
+
+/// A request to transfer `len` bytes starting at block `lba`.
+#[derive(Copy, Clone)]
+pub struct IoRequest { pub lba: u64
+                     , pub len: usize
+                     , /// True to write to the disk, false to read
+                       pub write: bool
+                     , /// Called once the transfer has finished
+                       pub completion: fn()
+                     }
+
+impl IoRequest {
+    /// Report that the request has been carried out.
+    #[inline] pub fn complete(&self) { (self.completion)() }
+
+    /// What dispatching the request costs, in credit.
+    #[inline] fn cost(&self) -> i32 {
+        cmp::min(self.len, i32::MAX as usize) as i32
+    }
+}
+
+impl fmt::Debug for IoRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "IoRequest({} {} bytes at block {})"
+              , if self.write { "write" } else { "read" }, self.len, self.lba)
+    }
+}
+
+/// A task's queued requests, and its credit.
+#[derive(Debug)]
+struct TaskQueue { credit: i32
+                 , requests: VecDeque<IoRequest>
+                 }
+
+/// Requests waiting for a disk.
+#[derive(Debug)]
+pub struct IoScheduler { queues: BTreeMap<Pid, TaskQueue>
+                       , /// When credit was last handed out
+                         last_refill_ns: u64
+                       }
+
+impl IoScheduler {
+    /// Returns a scheduler with no requests queued, whose first quantum
+    /// starts at `now_ns`.
+    pub fn new(now_ns: u64) -> Self {
+        IoScheduler { queues: BTreeMap::new(), last_refill_ns: now_ns }
+    }
+
+    /// Queue `request` on behalf of `task`.
+    ///
+    /// A task that hasn't done any I/O lately starts with full credit.
+    pub fn submit(&mut self, request: IoRequest, task: Pid) {
+        self.queues.entry(task)
+            .or_insert_with(|| TaskQueue { credit: MAX_CREDIT
+                                         , requests: VecDeque::new() })
+            .requests.push_back(request)
+    }
+
+    /// Returns the number of requests queued.
+    pub fn len(&self) -> usize {
+        self.queues.values().map(|queue| queue.requests.len()).sum()
+    }
+
+    /// Returns true if no requests are queued.
+    pub fn is_empty(&self) -> bool {
+        self.queues.values().all(|queue| queue.requests.is_empty())
+    }
+
+    /// Returns `task`'s credit, if it has done any I/O lately.
+    pub fn credit(&self, task: Pid) -> Option<i32> {
+        self.queues.get(&task).map(|queue| queue.credit)
+    }
+
+    /// Take the next request to send to the disk, and the task it's for.
+    pub fn dispatch(&mut self, now_ns: u64) -> Option<(Pid, IoRequest)> {
+        self.refill(now_ns);
+        // ties go to the lowest PID.
+        let (pid, _) = self.queues.iter()
+            .filter(|&(_, queue)| !queue.requests.is_empty())
+            .fold(None, |best, (&pid, queue)| match best {
+                Some((_, credit)) if credit >= queue.credit => best
+              , _ => Some((pid, queue.credit))
+            })?;
+        let queue = self.queues.get_mut(&pid)?;
+        let request = queue.requests.pop_front()?;
+        queue.credit = cmp::max( queue.credit.saturating_sub(request.cost())
+                               , MIN_CREDIT);
+        Some((pid, request))
+    }
+
+    /// Hand out the credit earned by every quantum since the last refill.
+    fn refill(&mut self, now_ns: u64) {
+        let quanta = now_ns.saturating_sub(self.last_refill_ns) / QUANTUM_NS;
+        if quanta == 0 { return }
+        self.last_refill_ns += quanta * QUANTUM_NS;
+        // enough quanta take anyone from the floor to the cap.
+        let most = ((MAX_CREDIT - MIN_CREDIT) / CREDIT_RATE) as u64;
+        let earned = cmp::min(quanta, most) as i32 * CREDIT_RATE;
+        for queue in self.queues.values_mut() {
+            queue.credit = cmp::min( queue.credit.saturating_add(earned)
+                                   , MAX_CREDIT);
+        }
+        // an idle task with full credit is no different from one that has
+        // never done any I/O, so there's no need to remember it.
+        let idle: Vec<Pid> = self.queues.iter()
+            .filter(|&(_, queue)| queue.requests.is_empty()
+                               && queue.credit == MAX_CREDIT)
+            .map(|(&pid, _)| pid)
+            .collect();
+        for pid in idle {
+            self.queues.remove(&pid);
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Device drivers.
+pub mod ahci;
+pub mod block;
+pub mod framebuffer;
+pub mod iosched;
+pub mod nvme;
+pub mod pci;
+pub mod virtio;
+
+/// Enumerate the PCI bus, and set up every device we have a driver for.
+///
+/// This must be called after the heap and the physical memory map have been
+/// set up.
+pub fn init() {
+    for device in pci::enumerate() {
+        kinfoln!(dots: " . . ", "PCI {:?}", device);
+        virtio::net::init(&device);
+        virtio::blk::init(&device);
+        virtio::p9::init(&device);
+        virtio::rng::init(&device);
+        nvme::init(&device);
+        ahci::init(&device);
+    }
+}
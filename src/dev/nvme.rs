@@ -0,0 +1,657 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! NVMe driver, for SSDs attached over PCIe.
+//!
+//! The controller's registers are in its first memory BAR. Commands go on
+//! submission queues in memory, and the controller posts an entry on a
+//! completion queue as it finishes each one. There's a pair of queues for
+//! admin commands, which we only use while setting the controller up, and
+//! a pair for I/O.
+//!
+//! Like the virtio block driver, we only have one command in flight at a
+//! time, and copy data through a one-page bounce buffer, so that a command
+//! never needs more than one PRP (physical region page) entry.
+//!
+//! See the [NVM Express specification] for more information.
+//!
+//! [NVM Express specification]: https://nvmexpress.org/specifications/
+use alloc::arc::Arc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{cmp, fmt, ptr, str};
+use core::sync::atomic::{fence, Ordering};
+use cpu::tsc;
+use memory::PAGE_SIZE;
+use paging::arch::ActivePageTable;
+use spin::{Mutex, Once};
+
+use arch::interrupts;
+use dev::block::{self, BlockDevice, BlockError};
+use dev::pci::{Bar, PciDevice};
+use mm::dma::DmaBox;
+use mm::frame;
+use mm::mmio::MmioRegion;
+use task::wait::Semaphore;
+
+/// The PCI class of mass storage controllers.
+pub const CLASS_STORAGE: u8 = 0x01;
+/// The PCI subclass of NVMe controllers.
+pub const SUBCLASS_NVME: u8 = 0x08;
+
+/// Offsets of the controller registers, from the start of BAR 0.
+mod reg {
+    pub const CAP: usize = 0x00;
+    pub const INTMS: usize = 0x0c;
+    pub const INTMC: usize = 0x10;
+    pub const CC: usize = 0x14;
+    pub const CSTS: usize = 0x1c;
+    pub const AQA: usize = 0x24;
+    pub const ASQ: usize = 0x28;
+    pub const ACQ: usize = 0x30;
+    /// The doorbells start here
+    pub const DOORBELLS: usize = 0x1000;
+}
+
+/// `CAP.CSS` bit: the controller supports the NVM command set.
+const CAP_CSS_NVM: u64 = 1 << 37;
+
+/// `CC.EN`: the controller is enabled.
+const CC_EN: u32 = 1 << 0;
+/// `CC.IOSQES`: I/O submission queue entries are 2^6 bytes.
+const CC_IOSQES: u32 = 6 << 16;
+/// `CC.IOCQES`: I/O completion queue entries are 2^4 bytes.
+const CC_IOCQES: u32 = 4 << 20;
+
+/// `CSTS.RDY`: the controller is ready.
+const CSTS_RDY: u32 = 1 << 0;
+/// `CSTS.CFS`: the controller has had a fatal error.
+const CSTS_CFS: u32 = 1 << 1;
+
+/// Admin command opcodes.
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+
+/// I/O command opcodes.
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+/// What an `Identify` command identifies.
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+
+/// The number of entries in each queue.
+const QUEUE_SIZE: u16 = 16;
+/// The admin queues' ID.
+const ADMIN_QUEUE: u16 = 0;
+/// The I/O queues' ID.
+const IO_QUEUE: u16 = 1;
+
+/// The most namespaces we look for.
+const MAX_NAMESPACES: u32 = 16;
+/// The size of the bounce buffer, which is the most a command transfers.
+const MAX_TRANSFER: usize = PAGE_SIZE as usize;
+
+/// A submission queue entry.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct Command { opcode: u8
+               , flags: u8
+               , /// Command identifier, echoed in the completion
+                 cid: u16
+               , nsid: u32
+               , reserved: u64
+               , mptr: u64
+               , prp1: u64
+               , prp2: u64
+               , cdw10: u32
+               , cdw11: u32
+               , cdw12: u32
+               , cdw13: u32
+               , cdw14: u32
+               , cdw15: u32
+               }
+
+/// A completion queue entry.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct Completion { /// Command-specific result
+                    result: u32
+                  , reserved: u32
+                  , sq_head: u16
+                  , sq_id: u16
+                  , cid: u16
+                  , /// Bit 0 is the phase tag; the rest is the status
+                    status: u16
+                  }
+
+/// Errors returned by the NVMe driver.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NvmeError { /// The device has no memory BAR for its registers
+                     NoMemoryBar
+                   , /// The controller can't do something we need
+                     Unsupported(&'static str)
+                   , /// We ran out of memory for registers, queues or
+                     /// buffers
+                     NoMemory
+                   , /// The controller took too long to respond
+                     Timeout
+                   , /// The controller has had a fatal error
+                     Fatal
+                   , /// A command failed with this status
+                     Command(u16)
+                   , /// The controller has no usable namespaces
+                     NoNamespaces
+                   , /// There's no namespace with this ID
+                     NoSuchNamespace(u32)
+                   , /// The request goes past the end of the namespace
+                     OutOfRange
+                   , /// The buffer isn't as long as the request
+                     BadLength
+                   }
+
+impl fmt::Display for NvmeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NvmeError::NoMemoryBar => f.write_str("device has no memory BAR")
+          , NvmeError::Unsupported(what) => write!(f, "unsupported: {}", what)
+          , NvmeError::NoMemory => f.write_str("out of memory")
+          , NvmeError::Timeout => f.write_str("controller timed out")
+          , NvmeError::Fatal => f.write_str("controller fatal status")
+          , NvmeError::Command(status) =>
+                write!(f, "command failed with status {:#x}", status)
+          , NvmeError::NoNamespaces => f.write_str("no usable namespaces")
+          , NvmeError::NoSuchNamespace(nsid) =>
+                write!(f, "no namespace {}", nsid)
+          , NvmeError::OutOfRange => f.write_str("request out of range")
+          , NvmeError::BadLength =>
+                f.write_str("buffer doesn't match request")
+        }
+    }
+}
+
+impl From<NvmeError> for BlockError {
+    fn from(err: NvmeError) -> Self {
+        match err {
+            NvmeError::OutOfRange => BlockError::OutOfRange
+          , _ => BlockError::Io
+        }
+    }
+}
+
+/// Read the little-endian `u32` at `offset` in `bytes`.
+#[inline]
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset] as u32
+        | (bytes[offset + 1] as u32) << 8
+        | (bytes[offset + 2] as u32) << 16
+        | (bytes[offset + 3] as u32) << 24
+}
+
+/// Read the little-endian `u64` at `offset` in `bytes`.
+#[inline]
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+/// The controller's registers.
+struct Registers { mmio: MmioRegion
+                 , /// The distance between doorbells, in bytes
+                   doorbell_stride: usize
+                 }
+
+impl Registers {
+    /// Write `value` to the submission queue tail doorbell of queue `id`,
+    /// or to its completion queue head doorbell if `completion` is true.
+    fn ring(&self, id: u16, completion: bool, value: u16) {
+        let index = 2 * id as usize + completion as usize;
+        self.mmio.write_u32( reg::DOORBELLS + index * self.doorbell_stride
+                           , value as u32)
+    }
+
+    /// Wait for `CSTS.RDY` to become `ready`, for at most `timeout_ns`.
+    fn wait_ready(&self, ready: bool, timeout_ns: u64)
+                 -> Result<(), NvmeError> {
+        let until = tsc::current_ns() + timeout_ns;
+        loop {
+            let csts = self.mmio.read_u32(reg::CSTS);
+            if csts & CSTS_CFS != 0 && ready { return Err(NvmeError::Fatal) }
+            if (csts & CSTS_RDY != 0) == ready { return Ok(()) }
+            if tsc::current_ns() >= until { return Err(NvmeError::Timeout) }
+        }
+    }
+}
+
+/// A submission queue and the completion queue it posts to.
+struct QueuePair { id: u16
+                 , sq: DmaBox<[Command]>
+                 , cq: DmaBox<[Completion]>
+                 , sq_tail: u16
+                 , cq_head: u16
+                 , /// The phase tag of entries the controller has posted
+                   /// since the completion queue last wrapped around
+                   phase: bool
+                 , next_cid: u16
+                 }
+
+impl QueuePair {
+    fn new(id: u16) -> Result<QueuePair, NvmeError> {
+        let sq = DmaBox::new_slice(QUEUE_SIZE as usize)
+            .map_err(|_| NvmeError::NoMemory)?;
+        let cq = DmaBox::new_slice(QUEUE_SIZE as usize)
+            .map_err(|_| NvmeError::NoMemory)?;
+        Ok(QueuePair { id: id
+                     , sq: sq
+                     , cq: cq
+                     , sq_tail: 0
+                     , cq_head: 0
+                     , phase: true
+                     , next_cid: 0
+                     })
+    }
+
+    /// Put `command` on the submission queue, and tell the controller.
+    ///
+    /// Returns the command's identifier.
+    fn submit(&mut self, regs: &Registers, mut command: Command) -> u16 {
+        command.cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+        unsafe {
+            ptr::write_volatile(&mut self.sq[self.sq_tail as usize], command)
+        }
+        self.sq_tail = (self.sq_tail + 1) % QUEUE_SIZE;
+        // the entry must be in memory before the controller looks for it.
+        fence(Ordering::SeqCst);
+        regs.ring(self.id, false, self.sq_tail);
+        command.cid
+    }
+
+    /// Take the next entry off the completion queue, if the controller has
+    /// posted one.
+    fn poll(&mut self, regs: &Registers) -> Option<Completion> {
+        let entry
+            = unsafe { ptr::read_volatile(&self.cq[self.cq_head as usize]) };
+        if (entry.status & 1 != 0) != self.phase { return None }
+        fence(Ordering::SeqCst);
+        self.cq_head += 1;
+        if self.cq_head == QUEUE_SIZE {
+            self.cq_head = 0;
+            self.phase = !self.phase;
+        }
+        regs.ring(self.id, true, self.cq_head);
+        Some(entry)
+    }
+
+    /// Submit `command`, and spin until it completes or `timeout_ns`
+    /// passes.
+    ///
+    /// This is for admin commands while the controller is being set up,
+    /// before its interrupt handler is registered.
+    fn run_polled( &mut self, regs: &Registers, command: Command
+                 , timeout_ns: u64)
+                 -> Result<Completion, NvmeError> {
+        let cid = self.submit(regs, command);
+        let until = tsc::current_ns() + timeout_ns;
+        loop {
+            match self.poll(regs) {
+                Some(entry) if entry.cid == cid => return check(entry)
+              , Some(_) => {}
+              , None if tsc::current_ns() >= until =>
+                    return Err(NvmeError::Timeout)
+              , None => {}
+            }
+        }
+    }
+}
+
+/// Returns `entry` if its command succeeded, or the status if it didn't.
+#[inline]
+fn check(entry: Completion) -> Result<Completion, NvmeError> {
+    match entry.status >> 1 {
+        0 => Ok(entry)
+      , status => Err(NvmeError::Command(status))
+    }
+}
+
+/// A namespace: a range of blocks on the controller.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Namespace { pub id: u32
+                     , /// The size of a block, in bytes
+                       pub block_size: usize
+                     , /// The number of blocks in the namespace
+                       pub block_count: u64
+                     }
+
+/// The parts of the controller that change with each command.
+struct Inner { admin: QueuePair
+             , io: QueuePair
+             , buffer: DmaBox<[u8; MAX_TRANSFER]>
+             }
+
+/// An NVMe controller.
+pub struct NvmeController { regs: Registers
+                          , /// The legacy IRQ line the controller
+                            /// interrupts on
+                            irq: u8
+                          , /// The model number, from `Identify Controller`
+                            model: String
+                          , /// The namespaces we can use, in ID order
+                            namespaces: Vec<Namespace>
+                          , inner: Mutex<Inner>
+                          , /// Held by the task with a command in flight
+                            busy: Semaphore
+                          , /// Signalled by the interrupt handler when the
+                            /// controller has posted a completion
+                            complete: Semaphore
+                          }
+
+/// The controller, once it has been found.
+static NVME: Once<Arc<NvmeController>> = Once::new();
+
+impl NvmeController {
+    /// Initialize `dev`, if it is an NVMe controller.
+    ///
+    /// Its interrupt handler isn't registered, so it can't complete any
+    /// I/O until it has been passed to [`init`](fn.init.html).
+    pub fn probe(dev: &PciDevice) -> Option<NvmeController> {
+        if dev.class != CLASS_STORAGE || dev.subclass != SUBCLASS_NVME {
+            return None
+        }
+        match NvmeController::setup(dev) {
+            Ok(controller) => Some(controller)
+          , Err(why) => {
+                warn!("nvme {:?}: {}", dev, why);
+                None
+            }
+        }
+    }
+
+    fn setup(dev: &PciDevice) -> Result<NvmeController, NvmeError> {
+        let base = match dev.bar(0) {
+            Some(Bar::Memory(addr)) => addr
+          , _ => return Err(NvmeError::NoMemoryBar)
+        };
+        dev.enable_bus_master();
+
+        // the doorbell stride is in `CAP`, so map the fixed registers first
+        // to find out how much more there is.
+        let mut table = unsafe { ActivePageTable::new() };
+        let mut frames = frame::allocator();
+        let cap = MmioRegion::new(base, reg::DOORBELLS, &mut table, &mut frames)
+            .map_err(|_| NvmeError::NoMemory)?
+            .read_u64(reg::CAP);
+        let doorbell_stride = 4 << ((cap >> 32) & 0xf);
+        let mmio = MmioRegion::new( base, reg::DOORBELLS + 4 * doorbell_stride
+                                  , &mut table, &mut frames)
+            .map_err(|_| NvmeError::NoMemory)?;
+        let regs = Registers { mmio: mmio, doorbell_stride: doorbell_stride };
+
+        if cap & CAP_CSS_NVM == 0 {
+            return Err(NvmeError::Unsupported("NVM command set"))
+        }
+        if (cap >> 48) & 0xf != 0 {
+            return Err(NvmeError::Unsupported("4 KiB memory pages"))
+        }
+        if (cap & 0xffff) + 1 < QUEUE_SIZE as u64 {
+            return Err(NvmeError::Unsupported("queue size"))
+        }
+        // `CAP.TO` is in units of 500 ms.
+        let timeout_ns = cmp::max((cap >> 24) & 0xff, 1) * 500_000_000;
+
+        regs.mmio.write_u32(reg::CC, 0);
+        regs.wait_ready(false, timeout_ns)?;
+        // interrupts stay masked until the handler has been registered.
+        regs.mmio.write_u32(reg::INTMS, 1);
+
+        let mut admin = QueuePair::new(ADMIN_QUEUE)?;
+        let io = QueuePair::new(IO_QUEUE)?;
+        let mut buffer = unsafe { DmaBox::<[u8; MAX_TRANSFER]>::zeroed() }
+            .map_err(|_| NvmeError::NoMemory)?;
+        let entries = (QUEUE_SIZE - 1) as u32;
+        regs.mmio.write_u32(reg::AQA, entries << 16 | entries);
+        regs.mmio.write_u64(reg::ASQ, *admin.sq.paddr());
+        regs.mmio.write_u64(reg::ACQ, *admin.cq.paddr());
+        regs.mmio.write_u32(reg::CC, CC_EN | CC_IOSQES | CC_IOCQES);
+        regs.wait_ready(true, timeout_ns)?;
+
+        let buffer_addr = *buffer.paddr();
+        let identify = |nsid, cns| Command { opcode: ADMIN_IDENTIFY
+                                           , nsid: nsid
+                                           , prp1: buffer_addr
+                                           , cdw10: cns
+                                           , ..Default::default()
+                                           };
+        admin.run_polled(&regs, identify(0, CNS_CONTROLLER), timeout_ns)?;
+        let model = str::from_utf8(&buffer[24..64]).unwrap_or("?")
+                        .trim();
+        let model = String::from(model);
+        let count = read_u32(&*buffer, 516);
+
+        let mut namespaces = Vec::new();
+        for nsid in 1 .. cmp::min(count, MAX_NAMESPACES) + 1 {
+            for byte in buffer.iter_mut() { *byte = 0 }
+            admin.run_polled( &regs, identify(nsid, CNS_NAMESPACE)
+                            , timeout_ns)?;
+            let block_count = read_u64(&*buffer, 0);
+            let format = (buffer[26] & 0xf) as usize;
+            let block_size = 1usize << buffer[128 + 4 * format + 2];
+            // namespaces that aren't attached are all zeroes.
+            if block_count == 0 { continue }
+            if block_size > MAX_TRANSFER {
+                warn!("nvme {:?}: namespace {} has {}-byte blocks, skipping"
+                     , dev, nsid, block_size);
+                continue
+            }
+            namespaces.push(Namespace { id: nsid
+                                      , block_size: block_size
+                                      , block_count: block_count
+                                      });
+        }
+        if namespaces.is_empty() { return Err(NvmeError::NoNamespaces) }
+
+        let create_cq = Command { opcode: ADMIN_CREATE_CQ
+                                , prp1: *io.cq.paddr()
+                                , cdw10: entries << 16 | IO_QUEUE as u32
+                                  // physically contiguous, with interrupts
+                                , cdw11: 1 | 1 << 1
+                                , ..Default::default()
+                                };
+        admin.run_polled(&regs, create_cq, timeout_ns)?;
+        let create_sq = Command { opcode: ADMIN_CREATE_SQ
+                                , prp1: *io.sq.paddr()
+                                , cdw10: entries << 16 | IO_QUEUE as u32
+                                  // physically contiguous, completing to
+                                  // the I/O completion queue
+                                , cdw11: 1 | (IO_QUEUE as u32) << 16
+                                , ..Default::default()
+                                };
+        admin.run_polled(&regs, create_sq, timeout_ns)?;
+
+        Ok(NvmeController { regs: regs
+                          , irq: dev.interrupt_line()
+                          , model: model
+                          , namespaces: namespaces
+                          , inner: Mutex::new(Inner { admin: admin
+                                                    , io: io
+                                                    , buffer: buffer })
+                          , busy: Semaphore::new(1)
+                          , complete: Semaphore::new(0)
+                          })
+    }
+
+    /// Returns the namespaces we can use.
+    #[inline] pub fn namespaces(&self) -> &[Namespace] { &self.namespaces }
+
+    /// Returns the legacy IRQ line the controller interrupts on.
+    #[inline] pub fn irq(&self) -> u8 { self.irq }
+
+    /// Check that a request for `count` blocks of namespace `nsid` at
+    /// `lba`, with a `len`-byte buffer, makes sense.
+    fn check(&self, nsid: u32, lba: u64, count: u16, len: usize)
+            -> Result<Namespace, NvmeError> {
+        let ns = *self.namespaces.iter().find(|ns| ns.id == nsid)
+                      .ok_or(NvmeError::NoSuchNamespace(nsid))?;
+        if len != count as usize * ns.block_size {
+            return Err(NvmeError::BadLength)
+        }
+        match lba.checked_add(count as u64) {
+            Some(end) if end <= ns.block_count => Ok(ns)
+          , _ => Err(NvmeError::OutOfRange)
+        }
+    }
+
+    /// Read `count` blocks of namespace `nsid`, starting at block `lba`,
+    /// into `buf`, which must be exactly `count` blocks long.
+    ///
+    /// This blocks until the controller has finished.
+    pub fn read_sectors( &self, nsid: u32, lba: u64, count: u16
+                       , buf: &mut [u8])
+                       -> Result<(), NvmeError> {
+        let ns = self.check(nsid, lba, count, buf.len())?;
+        let per_command = MAX_TRANSFER / ns.block_size;
+        self.busy.down();
+        let mut result = Ok(());
+        for (i, chunk) in buf.chunks_mut(per_command * ns.block_size)
+                             .enumerate() {
+            let lba = lba + (i * per_command) as u64;
+            let blocks = chunk.len() / ns.block_size;
+            result = self.submit(IO_READ, nsid, lba, blocks);
+            if result.is_err() { break }
+            let inner = self.inner.lock();
+            chunk.copy_from_slice(&inner.buffer[..chunk.len()]);
+        }
+        self.busy.up();
+        result
+    }
+
+    /// Write `buf`, which must be exactly `count` blocks long, to the
+    /// `count` blocks of namespace `nsid` starting at block `lba`.
+    ///
+    /// This blocks until the controller has finished.
+    pub fn write_sectors( &self, nsid: u32, lba: u64, count: u16
+                        , buf: &[u8])
+                        -> Result<(), NvmeError> {
+        let ns = self.check(nsid, lba, count, buf.len())?;
+        let per_command = MAX_TRANSFER / ns.block_size;
+        self.busy.down();
+        let mut result = Ok(());
+        for (i, chunk) in buf.chunks(per_command * ns.block_size)
+                             .enumerate() {
+            let lba = lba + (i * per_command) as u64;
+            let blocks = chunk.len() / ns.block_size;
+            self.inner.lock().buffer[..chunk.len()].copy_from_slice(chunk);
+            result = self.submit(IO_WRITE, nsid, lba, blocks);
+            if result.is_err() { break }
+        }
+        self.busy.up();
+        result
+    }
+
+    /// Send an I/O command `opcode` for `blocks` blocks at `lba` in
+    /// namespace `nsid`, using the bounce buffer, and wait for it to
+    /// complete.
+    ///
+    /// The caller must hold `busy`.
+    fn submit(&self, opcode: u8, nsid: u32, lba: u64, blocks: usize)
+             -> Result<(), NvmeError> {
+        debug_assert!(blocks > 0);
+        let cid = {
+            let mut inner = self.inner.lock();
+            let inner = &mut *inner;
+            let command = Command { opcode: opcode
+                                  , nsid: nsid
+                                  , prp1: *inner.buffer.paddr()
+                                  , cdw10: lba as u32
+                                  , cdw11: (lba >> 32) as u32
+                                  , // the block count is 0-based
+                                    cdw12: (blocks - 1) as u32
+                                  , ..Default::default()
+                                  };
+            inner.io.submit(&self.regs, command)
+        };
+        // the completion may be posted before we get around to waiting for
+        // it, or the semaphore be left over from a spurious interrupt, so
+        // check the queue rather than trusting the semaphore.
+        loop {
+            match self.inner.lock().io.poll(&self.regs) {
+                Some(entry) if entry.cid == cid =>
+                    return check(entry).map(|_| ())
+              , Some(_) => continue
+              , None => {}
+            }
+            // the interrupt handler masks the interrupt, which may be
+            // level-triggered, until we're ready for another.
+            self.regs.mmio.write_u32(reg::INTMC, 1);
+            self.complete.down();
+        }
+    }
+}
+
+impl BlockDevice for NvmeController {
+    /// The block size of the first namespace.
+    #[inline] fn block_size(&self) -> usize { self.namespaces[0].block_size }
+
+    /// The size of the first namespace.
+    #[inline] fn block_count(&self) -> u64 { self.namespaces[0].block_count }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        assert_eq!( buf.len(), self.block_size()
+                  , "buffer must be one block long");
+        self.read_sectors(self.namespaces[0].id, lba, 1, buf)
+            .map_err(BlockError::from)
+    }
+
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        assert_eq!( buf.len(), self.block_size()
+                  , "buffer must be one block long");
+        self.write_sectors(self.namespaces[0].id, lba, 1, buf)
+            .map_err(BlockError::from)
+    }
+}
+
+impl fmt::Debug for NvmeController {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NvmeController({}", self.model)?;
+        for ns in &self.namespaces {
+            write!( f, ", namespace {}: {} MiB", ns.id
+                  , ns.block_count * ns.block_size as u64 / (1024 * 1024))?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// Handler for the controller's IRQ.
+///
+/// There's no way to tell whether the controller raised the interrupt, so
+/// this always wakes the waiting task, which checks the completion queue.
+fn handle_irq() {
+    if let Some(nvme) = NVME.try() {
+        nvme.regs.mmio.write_u32(reg::INTMS, 1);
+        nvme.complete.up();
+    }
+}
+
+/// Set up `dev` as a block device, if it is an NVMe controller and we
+/// don't already have one.
+///
+/// Returns true if the controller was set up.
+pub fn init(dev: &PciDevice) -> bool {
+    if NVME.try().is_some() { return false }
+    match NvmeController::probe(dev) {
+        Some(nvme) => {
+            let irq = nvme.irq;
+            info!("nvme {:?}: {:?}, IRQ {}", dev, nvme, irq);
+            let nvme = NVME.call_once(|| Arc::new(nvme));
+            interrupts::register_irq(irq, handle_irq);
+            block::register(nvme.clone());
+            true
+        }
+      , None => false
+    }
+}
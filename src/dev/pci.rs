@@ -0,0 +1,227 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! PCI bus enumeration and configuration space access.
+//!
+//! Configuration space is accessed through the legacy I/O ports (PCI
+//! configuration mechanism #1), which every PC-compatible machine and
+//! hypervisor provides.
+//!
+//! See [the OS Dev wiki](http://wiki.osdev.org/PCI) for more information.
+use alloc::vec::Vec;
+use core::fmt;
+use cpu::Port;
+use cpu::ports::{PCI_CONFIG_ADDRESS, PCI_CONFIG_DATA};
+use memory::PAddr;
+use spin::Mutex;
+
+/// The address and data ports for configuration mechanism #1.
+static CONFIG_PORTS: Mutex<(Port<u32>, Port<u32>)>
+    = Mutex::new((PCI_CONFIG_ADDRESS, PCI_CONFIG_DATA));
+
+/// The vendor ID read back when no device is present.
+const NO_DEVICE: u16 = 0xffff;
+
+/// Offsets of registers in the configuration space header.
+pub mod config {
+    pub const VENDOR_ID: u8 = 0x00;
+    pub const DEVICE_ID: u8 = 0x02;
+    pub const COMMAND: u8 = 0x04;
+    pub const SUBCLASS: u8 = 0x0a;
+    pub const CLASS: u8 = 0x0b;
+    pub const HEADER_TYPE: u8 = 0x0e;
+    pub const BAR0: u8 = 0x10;
+    pub const SUBSYSTEM_ID: u8 = 0x2e;
+    pub const INTERRUPT_LINE: u8 = 0x3c;
+}
+
+bitflags! {
+    /// Bits in the configuration space command register.
+    pub flags Command: u16 { const IO_SPACE     = 1 << 0
+                           , const MEMORY_SPACE = 1 << 1
+                           , const BUS_MASTER   = 1 << 2
+                           , const INTX_DISABLE = 1 << 10
+                           }
+}
+
+/// A function on the PCI bus.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct PciDevice { pub bus: u8
+                     , pub slot: u8
+                     , pub function: u8
+                     , pub vendor_id: u16
+                     , pub device_id: u16
+                     , pub class: u8
+                     , pub subclass: u8
+                     }
+
+/// A base address register, describing where one of a device's register
+/// blocks is mapped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bar { /// Registers in I/O port space, starting at this port
+               Io(u16)
+             , /// Registers in physical memory, starting at this address
+               Memory(PAddr)
+             }
+
+/// Returns the configuration address of `offset` in a function's header.
+#[inline]
+fn config_addr(bus: u8, slot: u8, function: u8, offset: u8) -> u32 {
+    0x8000_0000 | (bus as u32) << 16 | (slot as u32) << 11
+                | (function as u32) << 8 | (offset as u32 & 0xfc)
+}
+
+/// Read the 32-bit configuration register containing `offset`.
+fn read_config(bus: u8, slot: u8, function: u8, offset: u8) -> u32 {
+    let ports = CONFIG_PORTS.lock();
+    ports.0.write(config_addr(bus, slot, function, offset));
+    ports.1.read()
+}
+
+impl PciDevice {
+    /// Look for a function at the given location, returning it if present.
+    pub fn probe(bus: u8, slot: u8, function: u8) -> Option<PciDevice> {
+        let id = read_config(bus, slot, function, config::VENDOR_ID);
+        if id as u16 == NO_DEVICE { return None }
+        let class = read_config(bus, slot, function, config::SUBCLASS & !3);
+        Some(PciDevice { bus: bus
+                       , slot: slot
+                       , function: function
+                       , vendor_id: id as u16
+                       , device_id: (id >> 16) as u16
+                       , class: (class >> 24) as u8
+                       , subclass: (class >> 16) as u8
+                       })
+    }
+
+    /// Read the 32-bit configuration register at `offset`, which must be
+    /// aligned to 4 bytes.
+    #[inline]
+    pub fn read_config(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.slot, self.function, offset)
+    }
+
+    /// Read the 16-bit configuration register at `offset`, which must be
+    /// aligned to 2 bytes.
+    #[inline]
+    pub fn read_config_u16(&self, offset: u8) -> u16 {
+        (self.read_config(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    /// Read the 8-bit configuration register at `offset`.
+    #[inline]
+    pub fn read_config_u8(&self, offset: u8) -> u8 {
+        (self.read_config(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Write the 32-bit configuration register at `offset`, which must be
+    /// aligned to 4 bytes.
+    pub fn write_config(&self, offset: u8, value: u32) {
+        let ports = CONFIG_PORTS.lock();
+        ports.0.write(config_addr(self.bus, self.slot, self.function, offset));
+        ports.1.write(value);
+    }
+
+    /// Write the 16-bit configuration register at `offset`, which must be
+    /// aligned to 2 bytes.
+    pub fn write_config_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_config(offset & !3);
+        let new = old & !(0xffff << shift) | (value as u32) << shift;
+        self.write_config(offset & !3, new);
+    }
+
+    /// Returns true if this function is part of a multi-function device.
+    #[inline]
+    pub fn is_multifunction(&self) -> bool {
+        self.read_config_u8(config::HEADER_TYPE) & 0x80 != 0
+    }
+
+    /// Returns the subsystem ID, which for some devices (such as legacy
+    /// virtio devices) identifies what kind of device this is.
+    #[inline]
+    pub fn subsystem_id(&self) -> u16 {
+        self.read_config_u16(config::SUBSYSTEM_ID)
+    }
+
+    /// Returns the legacy interrupt line that this device's interrupts are
+    /// routed to.
+    #[inline]
+    pub fn interrupt_line(&self) -> u8 {
+        self.read_config_u8(config::INTERRUPT_LINE)
+    }
+
+    /// Returns the `n`th base address register, or `None` if it is unused.
+    pub fn bar(&self, n: u8) -> Option<Bar> {
+        assert!(n < 6, "PCI devices only have 6 BARs");
+        let offset = config::BAR0 + n * 4;
+        let raw = self.read_config(offset);
+        if raw & 1 == 1 {
+            let port = (raw & !3) as u16;
+            if port == 0 { None } else { Some(Bar::Io(port)) }
+        } else {
+            let mut addr = (raw & !0xf) as u64;
+            // a 64-bit BAR keeps the upper half of its address in the next
+            // register
+            if (raw >> 1) & 3 == 2 && n < 5 {
+                addr |= (self.read_config(offset + 4) as u64) << 32;
+            }
+            if addr == 0 { None } else { Some(Bar::Memory(PAddr::from(addr))) }
+        }
+    }
+
+    /// Returns the contents of the command register.
+    #[inline]
+    pub fn command(&self) -> Command {
+        Command::from_bits_truncate(self.read_config_u16(config::COMMAND))
+    }
+
+    /// Turn on `flags` in the command register.
+    pub fn enable(&self, flags: Command) {
+        let command = self.command() | flags;
+        self.write_config_u16(config::COMMAND, command.bits());
+    }
+
+    /// Allow the device to respond to I/O and memory accesses, and to
+    /// perform DMA.
+    #[inline]
+    pub fn enable_bus_master(&self) {
+        self.enable(IO_SPACE | MEMORY_SPACE | BUS_MASTER);
+    }
+}
+
+impl fmt::Debug for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "{:02x}:{:02x}.{} [{:04x}:{:04x}] class {:02x}{:02x}"
+              , self.bus, self.slot, self.function
+              , self.vendor_id, self.device_id
+              , self.class, self.subclass)
+    }
+}
+
+/// Returns every function on the PCI bus.
+///
+/// This checks every possible slot on every bus, rather than following
+/// bridges, which is slow but simple.
+pub fn enumerate() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0 .. 256 {
+        for slot in 0 .. 32 {
+            let first = match PciDevice::probe(bus as u8, slot, 0) {
+                Some(device) => device
+              , None => continue
+            };
+            devices.push(first);
+            if first.is_multifunction() {
+                devices.extend((1 .. 8).filter_map(|function|
+                    PciDevice::probe(bus as u8, slot, function)));
+            }
+        }
+    }
+    devices
+}
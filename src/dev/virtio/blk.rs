@@ -0,0 +1,321 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Virtio block device driver.
+//!
+//! Each request is a chain of three buffers: a header saying what to do and
+//! where, the data to read or write, and a status byte for the device to
+//! fill in. We only have one request in flight at a time, and copy the data
+//! through a bounce buffer in DMA memory, which keeps things simple at the
+//! expense of speed.
+use alloc::arc::Arc;
+use core::{fmt, mem};
+use spin::{Mutex, Once};
+
+use arch::interrupts;
+use dev::block::{self, BlockDevice, BlockError};
+use dev::pci::PciDevice;
+use mm::dma::DmaBox;
+use task::wait::Semaphore;
+use super::{VirtioError, VirtioPci, VIRTIO_VENDOR_ID, DRIVER_OK};
+use super::queue::{Buffer, Virtqueue};
+
+/// The PCI device ID of a (transitional) virtio block device.
+pub const VIRTIO_BLK_DEVICE_ID: u16 = 0x1001;
+
+/// Feature bit: the device is read-only.
+pub const VIRTIO_BLK_F_RO: u32 = 1 << 5;
+
+/// The size of a sector, in bytes. Requests are always addressed in
+/// sectors, whatever the underlying device's block size.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The most sectors transferred by a single request.
+const MAX_SECTORS: usize = 8;
+/// The size of the bounce buffer.
+const MAX_TRANSFER: usize = MAX_SECTORS * SECTOR_SIZE;
+
+/// Request types.
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+/// Request statuses.
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The header at the start of every request.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct BlkReqHeader { type_: u32
+                    , reserved: u32
+                    , sector: u64
+                    }
+
+/// The DMA memory for a request.
+#[repr(C)]
+struct Request { header: BlkReqHeader
+               , data: [u8; MAX_TRANSFER]
+               , status: u8
+               }
+
+/// Errors returned by the virtio block driver.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlkError { /// The request goes past the end of the device
+                    OutOfRange
+                  , /// The buffer isn't as long as the request
+                    BadLength
+                  , /// The device is read-only
+                    ReadOnly
+                  , /// The device reported an I/O error
+                    Io
+                  , /// The device doesn't support the request
+                    Unsupported
+                  }
+
+impl From<BlkError> for BlockError {
+    fn from(err: BlkError) -> Self {
+        match err {
+            BlkError::OutOfRange => BlockError::OutOfRange
+          , BlkError::ReadOnly => BlockError::ReadOnly
+          , _ => BlockError::Io
+        }
+    }
+}
+
+/// The parts of the device that change with each request.
+struct Inner { queue: Virtqueue
+             , request: DmaBox<Request>
+             }
+
+/// A virtio block device.
+pub struct VirtioBlk { transport: VirtioPci
+                     , /// Size of the device, in sectors
+                       capacity: u64
+                     , read_only: bool
+                     , inner: Mutex<Inner>
+                     , /// Held by the task with a request in flight
+                       busy: Semaphore
+                     , /// Signalled by the interrupt handler when the
+                       /// device has finished with a request
+                       complete: Semaphore
+                     }
+
+/// The block device, once it has been found.
+static BLK: Once<Arc<VirtioBlk>> = Once::new();
+
+impl VirtioBlk {
+    /// Initialize `dev`, if it is a virtio block device.
+    ///
+    /// Its interrupt handler isn't registered, so it can't complete any
+    /// requests until it has been passed to [`init`](fn.init.html).
+    pub fn probe(dev: &PciDevice) -> Option<VirtioBlk> {
+        if dev.vendor_id != VIRTIO_VENDOR_ID
+            || dev.device_id != VIRTIO_BLK_DEVICE_ID {
+            return None
+        }
+        let transport = match VirtioPci::new(dev) {
+            Ok(transport) => transport
+          , Err(why) => {
+                warn!("virtio-blk {:?}: {}", dev, why);
+                return None
+            }
+        };
+        match VirtioBlk::setup(transport) {
+            Ok(blk) => Some(blk)
+          , Err(why) => {
+                warn!("virtio-blk {:?}: {}", dev, why);
+                transport.fail();
+                None
+            }
+        }
+    }
+
+    fn setup(transport: VirtioPci) -> Result<VirtioBlk, VirtioError> {
+        let features = transport.negotiate(VIRTIO_BLK_F_RO);
+        // the capacity is a 64-bit field, but the legacy registers are at
+        // most 32 bits wide
+        let capacity = transport.config_u32(0) as u64
+                     | (transport.config_u32(4) as u64) << 32;
+        let queue = transport.setup_queue(0)?;
+        let request = unsafe { DmaBox::<Request>::zeroed() }
+            .map_err(|_| VirtioError::NoMemory)?;
+        transport.add_status(DRIVER_OK);
+        Ok(VirtioBlk { transport: transport
+                     , capacity: capacity
+                     , read_only: features & VIRTIO_BLK_F_RO != 0
+                     , inner: Mutex::new(Inner { queue: queue
+                                               , request: request })
+                     , busy: Semaphore::new(1)
+                     , complete: Semaphore::new(0)
+                     })
+    }
+
+    /// Returns the size of the device, in sectors.
+    #[inline] pub fn capacity(&self) -> u64 { self.capacity }
+
+    /// Returns true if the device can't be written to.
+    #[inline] pub fn is_read_only(&self) -> bool { self.read_only }
+
+    /// Check that a request for `count` sectors at `lba`, with a `len`-byte
+    /// buffer, makes sense.
+    fn check(&self, lba: u64, count: u32, len: usize) -> Result<(), BlkError> {
+        if len != count as usize * SECTOR_SIZE {
+            return Err(BlkError::BadLength)
+        }
+        match lba.checked_add(count as u64) {
+            Some(end) if end <= self.capacity => Ok(())
+          , _ => Err(BlkError::OutOfRange)
+        }
+    }
+
+    /// Read `count` sectors starting at sector `lba` into `buf`, which must
+    /// be exactly `count` sectors long.
+    ///
+    /// This blocks until the device has finished.
+    pub fn read_sectors(&self, lba: u64, count: u32, buf: &mut [u8])
+                       -> Result<(), BlkError> {
+        self.check(lba, count, buf.len())?;
+        self.busy.down();
+        let mut result = Ok(());
+        for (i, chunk) in buf.chunks_mut(MAX_TRANSFER).enumerate() {
+            let sector = lba + (i * MAX_SECTORS) as u64;
+            result = self.submit(VIRTIO_BLK_T_IN, sector, chunk.len());
+            if result.is_err() { break }
+            let inner = self.inner.lock();
+            chunk.copy_from_slice(&inner.request.data[..chunk.len()]);
+        }
+        self.busy.up();
+        result
+    }
+
+    /// Write `buf`, which must be exactly `count` sectors long, to the
+    /// `count` sectors starting at sector `lba`.
+    ///
+    /// This blocks until the device has finished.
+    pub fn write_sectors(&self, lba: u64, count: u32, buf: &[u8])
+                        -> Result<(), BlkError> {
+        if self.read_only { return Err(BlkError::ReadOnly) }
+        self.check(lba, count, buf.len())?;
+        self.busy.down();
+        let mut result = Ok(());
+        for (i, chunk) in buf.chunks(MAX_TRANSFER).enumerate() {
+            let sector = lba + (i * MAX_SECTORS) as u64;
+            self.inner.lock().request.data[..chunk.len()]
+                .copy_from_slice(chunk);
+            result = self.submit(VIRTIO_BLK_T_OUT, sector, chunk.len());
+            if result.is_err() { break }
+        }
+        self.busy.up();
+        result
+    }
+
+    /// Send a request of type `kind` for the `len` bytes at `sector`, using
+    /// the bounce buffer, and wait for it to complete.
+    ///
+    /// The caller must hold `busy`.
+    fn submit(&self, kind: u32, sector: u64, len: usize)
+             -> Result<(), BlkError> {
+        debug_assert!(len <= MAX_TRANSFER);
+        {
+            let mut inner = self.inner.lock();
+            let inner = &mut *inner;
+            inner.request.header = BlkReqHeader { type_: kind
+                                                , reserved: 0
+                                                , sector: sector
+                                                };
+            // something the device will never write, in case it doesn't
+            inner.request.status = 0xff;
+            let request = &inner.request;
+            let chain =
+                [ Buffer { addr: request.paddr_of(&request.header)
+                         , len: mem::size_of::<BlkReqHeader>() as u32
+                         , writable: false
+                         }
+                , Buffer { addr: request.paddr_of(&request.data)
+                         , len: len as u32
+                         , writable: kind == VIRTIO_BLK_T_IN
+                         }
+                , Buffer { addr: request.paddr_of(&request.status)
+                         , len: 1
+                         , writable: true
+                         }
+                ];
+            // we only ever have one request in flight, so the queue can't
+            // be full.
+            inner.queue.push(&chain).expect("virtio-blk queue full!");
+            self.transport.notify(&inner.queue);
+        }
+        // the completion may be signalled before we get around to waiting
+        // for it, or be left over from a spurious interrupt, so check the
+        // queue rather than trusting the semaphore.
+        while self.inner.lock().queue.pop_used().is_none() {
+            self.complete.down();
+        }
+        match self.inner.lock().request.status {
+            VIRTIO_BLK_S_OK => Ok(())
+          , VIRTIO_BLK_S_IOERR => Err(BlkError::Io)
+          , VIRTIO_BLK_S_UNSUPP => Err(BlkError::Unsupported)
+          , // the device didn't fill in the status at all
+            _ => Err(BlkError::Io)
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    #[inline] fn block_size(&self) -> usize { SECTOR_SIZE }
+
+    #[inline] fn block_count(&self) -> u64 { self.capacity }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        assert_eq!(buf.len(), SECTOR_SIZE, "buffer must be one block long");
+        self.read_sectors(lba, 1, buf).map_err(BlockError::from)
+    }
+
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        assert_eq!(buf.len(), SECTOR_SIZE, "buffer must be one block long");
+        self.write_sectors(lba, 1, buf).map_err(BlockError::from)
+    }
+}
+
+impl fmt::Debug for VirtioBlk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "VirtioBlk({} MiB{})"
+              , self.capacity * SECTOR_SIZE as u64 / (1024 * 1024)
+              , if self.read_only { ", read-only" } else { "" })
+    }
+}
+
+/// Handler for the block device's IRQ.
+fn handle_irq() {
+    if let Some(blk) = BLK.try() {
+        // bit 0 of the ISR status means a queue was updated
+        if blk.transport.ack_interrupt() & 1 != 0 {
+            blk.complete.up();
+        }
+    }
+}
+
+/// Set up `dev` as a block device, if it is a virtio block device and we
+/// don't already have one.
+///
+/// Returns true if the device was set up.
+pub fn init(dev: &PciDevice) -> bool {
+    if BLK.try().is_some() { return false }
+    match VirtioBlk::probe(dev) {
+        Some(blk) => {
+            let irq = blk.transport.irq();
+            info!("virtio-blk {:?}: {:?}, IRQ {}", dev, blk, irq);
+            let blk = BLK.call_once(|| Arc::new(blk));
+            interrupts::register_irq(irq, handle_irq);
+            block::register(blk.clone());
+            true
+        }
+      , None => false
+    }
+}
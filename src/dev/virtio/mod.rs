@@ -0,0 +1,207 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Virtio paravirtualized devices.
+//!
+//! Devices are driven through the legacy PCI interface (virtio 0.9.5), in
+//! which the common registers live in the device's first I/O BAR. Every
+//! hypervisor we care about (QEMU, KVM, VirtualBox) offers it.
+//!
+//! See the [virtio specification] for more information.
+//!
+//! [virtio specification]: http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html
+use core::fmt;
+use cpu::Port;
+use memory::PAGE_SHIFT;
+
+use dev::pci::{Bar, PciDevice};
+use self::queue::{Virtqueue, QUEUE_SIZE};
+
+pub mod blk;
+pub mod net;
+pub mod p9;
+pub mod queue;
+pub mod rng;
+
+/// The PCI vendor ID of all virtio devices.
+pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+/// Offsets of the legacy virtio registers, from the start of BAR 0.
+mod reg {
+    pub const DEVICE_FEATURES: u16 = 0x00;
+    pub const GUEST_FEATURES: u16 = 0x04;
+    pub const QUEUE_ADDRESS: u16 = 0x08;
+    pub const QUEUE_SIZE: u16 = 0x0c;
+    pub const QUEUE_SELECT: u16 = 0x0e;
+    pub const QUEUE_NOTIFY: u16 = 0x10;
+    pub const DEVICE_STATUS: u16 = 0x12;
+    pub const ISR_STATUS: u16 = 0x13;
+    /// Device-specific configuration starts here (when MSI-X is disabled)
+    pub const DEVICE_CONFIG: u16 = 0x14;
+}
+
+bitflags! {
+    /// Bits in the device status register.
+    pub flags Status: u8 { const ACKNOWLEDGE = 1
+                         , const DRIVER      = 2
+                         , const DRIVER_OK   = 4
+                         , const FEATURES_OK = 8
+                         , const FAILED      = 0x80
+                         }
+}
+
+/// Errors that can occur while setting up a virtio device.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VirtioError { /// The device has no I/O BAR for the legacy interface
+                       NoIoBar
+                     , /// The device doesn't have the requested queue
+                       NoQueue(u16)
+                     , /// The queue's size isn't `QUEUE_SIZE`
+                       QueueSize { queue: u16, size: u16 }
+                     , /// We ran out of memory for queues or buffers
+                       NoMemory
+                     }
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VirtioError::NoIoBar => f.write_str("device has no I/O BAR")
+          , VirtioError::NoQueue(queue) =>
+                write!(f, "device has no queue {}", queue)
+          , VirtioError::QueueSize { queue, size } =>
+                write!( f, "queue {} has {} entries, but only {} are supported"
+                      , queue, size, QUEUE_SIZE)
+          , VirtioError::NoMemory => f.write_str("out of memory")
+        }
+    }
+}
+
+/// The legacy PCI interface to a virtio device.
+#[derive(Copy, Clone, Debug)]
+pub struct VirtioPci { /// The first port of the device's registers
+                       base: u16
+                     , /// The legacy IRQ line the device interrupts on
+                       irq: u8
+                     }
+
+impl VirtioPci {
+    /// Reset the virtio device `dev`, and tell it that a driver has found
+    /// it.
+    pub fn new(dev: &PciDevice) -> Result<Self, VirtioError> {
+        let base = match dev.bar(0) {
+            Some(Bar::Io(port)) => port
+          , _ => return Err(VirtioError::NoIoBar)
+        };
+        dev.enable_bus_master();
+        let transport = VirtioPci { base: base, irq: dev.interrupt_line() };
+        transport.set_status(Status::empty());
+        transport.add_status(ACKNOWLEDGE | DRIVER);
+        Ok(transport)
+    }
+
+    #[inline] fn port8(&self, reg: u16) -> Port<u8> {
+        Port::<u8>::new(self.base + reg)
+    }
+
+    #[inline] fn port16(&self, reg: u16) -> Port<u16> {
+        Port::<u16>::new(self.base + reg)
+    }
+
+    #[inline] fn port32(&self, reg: u16) -> Port<u32> {
+        Port::<u32>::new(self.base + reg)
+    }
+
+    /// Returns the legacy IRQ line the device interrupts on.
+    #[inline] pub fn irq(&self) -> u8 { self.irq }
+
+    /// Returns the device status register.
+    #[inline]
+    pub fn status(&self) -> Status {
+        Status::from_bits_truncate(self.port8(reg::DEVICE_STATUS).read())
+    }
+
+    /// Overwrite the device status register. Writing zero resets the device.
+    #[inline]
+    pub fn set_status(&self, status: Status) {
+        self.port8(reg::DEVICE_STATUS).write(status.bits())
+    }
+
+    /// Set `status` in the device status register, keeping the bits that
+    /// are already set.
+    #[inline]
+    pub fn add_status(&self, status: Status) {
+        let current = self.status();
+        self.set_status(current | status)
+    }
+
+    /// Tell the device that the driver has given up on it.
+    #[inline]
+    pub fn fail(&self) { self.add_status(FAILED) }
+
+    /// Accept the features in `supported` that the device offers, returning
+    /// the features that were accepted.
+    pub fn negotiate(&self, supported: u32) -> u32 {
+        let features = self.port32(reg::DEVICE_FEATURES).read() & supported;
+        self.port32(reg::GUEST_FEATURES).write(features);
+        features
+    }
+
+    /// Set up the device's queue number `index`.
+    ///
+    /// Legacy devices choose the size of their queues, so this fails if the
+    /// queue doesn't have `QUEUE_SIZE` entries.
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        self.port16(reg::QUEUE_SELECT).write(index);
+        let size = self.port16(reg::QUEUE_SIZE).read();
+        if size == 0 {
+            return Err(VirtioError::NoQueue(index))
+        }
+        if size as usize != QUEUE_SIZE {
+            return Err(VirtioError::QueueSize { queue: index, size: size })
+        }
+        let queue = Virtqueue::new(index)
+            .map_err(|_| VirtioError::NoMemory)?;
+        let pfn = *queue.paddr() >> PAGE_SHIFT;
+        self.port32(reg::QUEUE_ADDRESS).write(pfn as u32);
+        Ok(queue)
+    }
+
+    /// Tell the device that there are new buffers in `queue`.
+    #[inline]
+    pub fn notify(&self, queue: &Virtqueue) {
+        self.port16(reg::QUEUE_NOTIFY).write(queue.index())
+    }
+
+    /// Acknowledge an interrupt, returning the ISR status.
+    ///
+    /// Reading the ISR status deasserts the device's interrupt line. Bit 0
+    /// is set if a queue was updated, and bit 1 if the device
+    /// configuration changed.
+    #[inline]
+    pub fn ack_interrupt(&self) -> u8 {
+        self.port8(reg::ISR_STATUS).read()
+    }
+
+    /// Read a byte from the device-specific configuration.
+    #[inline]
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        self.port8(reg::DEVICE_CONFIG + offset).read()
+    }
+
+    /// Read a 16-bit value from the device-specific configuration.
+    #[inline]
+    pub fn config_u16(&self, offset: u16) -> u16 {
+        self.port16(reg::DEVICE_CONFIG + offset).read()
+    }
+
+    /// Read a 32-bit value from the device-specific configuration.
+    #[inline]
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        self.port32(reg::DEVICE_CONFIG + offset).read()
+    }
+}
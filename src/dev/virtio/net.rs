@@ -0,0 +1,378 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Virtio network card driver.
+//!
+//! The device has two queues: the driver keeps the receive queue stocked
+//! with empty buffers for the device to fill, and puts outgoing frames on
+//! the transmit queue. Every frame in either direction is preceded by a
+//! [`NetHeader`](struct.NetHeader.html), which we always leave zeroed,
+//! since we don't negotiate any offloads.
+//!
+//! Received frames are copied out of the DMA buffers into a ring of
+//! [`Packet`](struct.Packet.html)s by the interrupt handler, so that the
+//! buffers can go straight back to the device.
+use alloc::boxed::Box;
+use alloc::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use core::{cmp, fmt, mem, ptr};
+use spin::{Mutex, Once};
+use util::ring::RingBuffer;
+
+use arch::interrupts;
+use dev::pci::PciDevice;
+use heap;
+use mm::dma::DmaBox;
+use task::wait::Semaphore;
+use super::{VirtioError, VirtioPci, VIRTIO_VENDOR_ID, DRIVER_OK};
+use super::queue::{Buffer, Virtqueue};
+
+/// The PCI device ID of a (transitional) virtio network card.
+pub const VIRTIO_NET_DEVICE_ID: u16 = 0x1000;
+
+/// Feature bit: the device has a MAC address in its configuration.
+pub const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+
+/// The largest Ethernet frame we send or receive: a 1500-byte payload plus
+/// the 14-byte header (the device handles the checksum).
+pub const MAX_FRAME_SIZE: usize = 1514;
+/// The number of received packets that may wait to be read before more are
+/// dropped.
+pub const RX_RING_SIZE: usize = 64;
+
+/// The ring of frames received but not yet read.
+type RxRing = RingBuffer<[Packet; RX_RING_SIZE]>;
+
+/// The number of buffers kept on the receive queue.
+const RX_BUFFERS: usize = 16;
+/// The number of frames that may be in flight on the transmit queue.
+const TX_BUFFERS: usize = 16;
+
+/// The receive queue's index.
+const RX_QUEUE: u16 = 0;
+/// The transmit queue's index.
+const TX_QUEUE: u16 = 1;
+
+/// The header preceding every frame.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NetHeader { pub flags: u8
+                     , pub gso_type: u8
+                     , pub hdr_len: u16
+                     , pub gso_size: u16
+                     , pub csum_start: u16
+                     , pub csum_offset: u16
+                     }
+
+/// A DMA buffer holding one frame and its header.
+#[repr(C)]
+struct FrameBuffer { header: NetHeader
+                   , data: [u8; MAX_FRAME_SIZE]
+                   }
+
+impl FrameBuffer {
+    /// Returns the buffer as a descriptor chain: the header, followed by
+    /// the first `len` bytes of the frame.
+    fn chain(this: &DmaBox<FrameBuffer>, len: usize, writable: bool)
+            -> [Buffer; 2] {
+        [ Buffer { addr: this.paddr_of(&this.header)
+                 , len: mem::size_of::<NetHeader>() as u32
+                 , writable: writable
+                 }
+        , Buffer { addr: this.paddr_of(&this.data)
+                 , len: len as u32
+                 , writable: writable
+                 }
+        ]
+    }
+}
+
+/// A received Ethernet frame.
+#[derive(Copy, Clone)]
+pub struct Packet { len: usize
+                  , data: [u8; MAX_FRAME_SIZE]
+                  }
+
+impl Packet {
+    /// Returns the frame's bytes.
+    #[inline] pub fn as_bytes(&self) -> &[u8] { &self.data[..self.len] }
+
+    /// Returns the length of the frame, in bytes.
+    #[inline] pub fn len(&self) -> usize { self.len }
+}
+
+impl fmt::Debug for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Packet({} bytes)", self.len)
+    }
+}
+
+/// Errors returned by [`VirtioNet::send`](struct.VirtioNet.html#method.send).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SendError { /// The frame is larger than `MAX_FRAME_SIZE`
+                     TooLarge
+                   , /// Every transmit buffer is in flight
+                     QueueFull
+                   }
+
+/// A virtio network card.
+pub struct VirtioNet { transport: VirtioPci
+                     , mac: [u8; 6]
+                     , rx: Virtqueue
+                     , tx: Virtqueue
+                     , rx_buffers: Vec<DmaBox<FrameBuffer>>
+                     , /// The receive buffer in each chain on the receive
+                       /// queue, by the chain's head descriptor
+                       rx_pending: BTreeMap<u16, usize>
+                     , tx_buffers: Vec<DmaBox<FrameBuffer>>
+                     , /// Transmit buffers that aren't in flight
+                       tx_free: Vec<usize>
+                     , /// The transmit buffer in each chain on the
+                       /// transmit queue, by the chain's head descriptor
+                       tx_pending: BTreeMap<u16, usize>
+                     , /// Frames received but not yet read
+                       received: Box<RxRing>
+                     , /// Frames dropped because `received` was full
+                       dropped: usize
+                     }
+
+/// The network card, once it has been found.
+pub static NET: Mutex<Option<VirtioNet>> = Mutex::new(None);
+
+/// The network card's transport, so that the interrupt handler can
+/// acknowledge interrupts without taking the lock on `NET`.
+static TRANSPORT: Once<VirtioPci> = Once::new();
+
+lazy_static! {
+    /// Signalled by the interrupt handler whenever frames may have arrived,
+    /// so that a task can wait for them.
+    pub static ref RECEIVED: Semaphore = Semaphore::new(0);
+}
+
+/// Returns a new, empty `RxRing`, allocated straight on the heap.
+///
+/// The ring is about 97 KiB, so building it with `Box::new` would put a
+/// temporary copy on the stack first, and the boot stack is only 16 KiB.
+fn new_rx_ring() -> Result<Box<RxRing>, VirtioError> {
+    let size = mem::size_of::<RxRing>();
+    let ring = heap::try_allocate(size, mem::align_of::<RxRing>())
+                   .map_err(|_| VirtioError::NoMemory)?;
+    unsafe {
+        // all zeroes is an empty ring: its head and length are 0, and no
+        // packet in it is read before it's been written.
+        ptr::write_bytes(ring, 0, size);
+        Ok(Box::from_raw(ring as *mut RxRing))
+    }
+}
+
+impl VirtioNet {
+    /// Initialize `dev`, if it is a virtio network card.
+    ///
+    /// The card is ready to send and receive once this returns, but its
+    /// interrupt handler isn't registered; see [`init`](fn.init.html).
+    pub fn probe(dev: &PciDevice) -> Option<VirtioNet> {
+        if dev.vendor_id != VIRTIO_VENDOR_ID
+            || dev.device_id != VIRTIO_NET_DEVICE_ID {
+            return None
+        }
+        let transport = match VirtioPci::new(dev) {
+            Ok(transport) => transport
+          , Err(why) => {
+                warn!("virtio-net {:?}: {}", dev, why);
+                return None
+            }
+        };
+        match VirtioNet::setup(transport) {
+            Ok(net) => Some(net)
+          , Err(why) => {
+                warn!("virtio-net {:?}: {}", dev, why);
+                transport.fail();
+                None
+            }
+        }
+    }
+
+    fn setup(transport: VirtioPci) -> Result<VirtioNet, VirtioError> {
+        let features = transport.negotiate(VIRTIO_NET_F_MAC);
+        let mut mac = [0; 6];
+        if features & VIRTIO_NET_F_MAC != 0 {
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = transport.config_u8(i as u16);
+            }
+        }
+
+        let rx = transport.setup_queue(RX_QUEUE)?;
+        let tx = transport.setup_queue(TX_QUEUE)?;
+        let new_buffer = |_| unsafe { DmaBox::<FrameBuffer>::zeroed() };
+        let rx_buffers = (0 .. RX_BUFFERS).map(&new_buffer)
+                                          .collect::<Result<Vec<_>, _>>()
+                                          .map_err(|_| VirtioError::NoMemory)?;
+        let tx_buffers = (0 .. TX_BUFFERS).map(&new_buffer)
+                                          .collect::<Result<Vec<_>, _>>()
+                                          .map_err(|_| VirtioError::NoMemory)?;
+
+        let mut net = VirtioNet { transport: transport
+                                , mac: mac
+                                , rx: rx
+                                , tx: tx
+                                , rx_buffers: rx_buffers
+                                , rx_pending: BTreeMap::new()
+                                , tx_buffers: tx_buffers
+                                , tx_free: (0 .. TX_BUFFERS).collect()
+                                , tx_pending: BTreeMap::new()
+                                , received: new_rx_ring()?
+                                , dropped: 0
+                                };
+        for slot in 0 .. RX_BUFFERS {
+            net.post_rx(slot);
+        }
+        transport.add_status(DRIVER_OK);
+        transport.notify(&net.rx);
+        Ok(net)
+    }
+
+    /// Returns the card's MAC address, or all zeroes if it doesn't have
+    /// one.
+    #[inline] pub fn mac(&self) -> [u8; 6] { self.mac }
+
+    /// Returns the number of received frames dropped because they weren't
+    /// read quickly enough.
+    #[inline] pub fn dropped(&self) -> usize { self.dropped }
+
+    /// Put receive buffer `slot` on the receive queue.
+    fn post_rx(&mut self, slot: usize) {
+        let chain = FrameBuffer::chain( &self.rx_buffers[slot]
+                                      , MAX_FRAME_SIZE, true);
+        // there are more descriptors than receive buffers, so this can't
+        // fail.
+        let head = self.rx.push(&chain)
+                       .expect("virtio-net receive queue full!");
+        self.rx_pending.insert(head, slot);
+    }
+
+    /// Return transmit buffers the device has finished with to the free
+    /// list.
+    fn reclaim_tx(&mut self) {
+        while let Some((head, _)) = self.tx.pop_used() {
+            if let Some(slot) = self.tx_pending.remove(&head) {
+                self.tx_free.push(slot);
+            }
+        }
+    }
+
+    /// Send the Ethernet frame in `buf`.
+    ///
+    /// The frame is copied into a transmit buffer, so `buf` may be reused
+    /// as soon as this returns.
+    pub fn send(&mut self, buf: &[u8]) -> Result<(), SendError> {
+        if buf.len() > MAX_FRAME_SIZE { return Err(SendError::TooLarge) }
+        self.reclaim_tx();
+        let slot = self.tx_free.pop().ok_or(SendError::QueueFull)?;
+        let head = {
+            let frame = &mut self.tx_buffers[slot];
+            frame.header = NetHeader::default();
+            frame.data[..buf.len()].copy_from_slice(buf);
+            let chain = FrameBuffer::chain(frame, buf.len(), false);
+            self.tx.push(&chain)
+        };
+        match head {
+            Some(head) => {
+                self.tx_pending.insert(head, slot);
+                self.transport.notify(&self.tx);
+                Ok(())
+            }
+          , None => {
+                self.tx_free.push(slot);
+                Err(SendError::QueueFull)
+            }
+        }
+    }
+
+    /// Copy every frame the device has received into the packet ring, and
+    /// give the buffers back to the device.
+    ///
+    /// Returns the number of frames received.
+    pub fn handle_interrupt(&mut self) -> usize {
+        let mut received = 0;
+        while let Some((head, written)) = self.rx.pop_used() {
+            let slot = match self.rx_pending.remove(&head) {
+                Some(slot) => slot
+              , None => continue
+            };
+            {
+                let frame = &self.rx_buffers[slot];
+                // the device's count includes the header
+                let len = (written as usize)
+                    .saturating_sub(mem::size_of::<NetHeader>());
+                let len = cmp::min(len, MAX_FRAME_SIZE);
+                let mut packet = Packet { len: len
+                                        , data: [0; MAX_FRAME_SIZE] };
+                packet.data[..len].copy_from_slice(&frame.data[..len]);
+                if self.received.push(packet).is_err() {
+                    self.dropped += 1;
+                }
+            }
+            self.post_rx(slot);
+            received += 1;
+        }
+        if received > 0 { self.transport.notify(&self.rx); }
+        received
+    }
+
+    /// Take the oldest received frame, if there is one.
+    pub fn receive(&mut self) -> Option<Packet> {
+        // pick up anything that arrived while the interrupt handler
+        // couldn't get the lock
+        self.handle_interrupt();
+        self.received.pop()
+    }
+}
+
+impl fmt::Debug for VirtioNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mac = self.mac;
+        write!( f, "VirtioNet({:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x})"
+              , mac[0], mac[1], mac[2], mac[3], mac[4], mac[5])
+    }
+}
+
+/// Handler for the network card's IRQ.
+fn handle_irq() {
+    if let Some(transport) = TRANSPORT.try() {
+        // bit 0 of the ISR status means a queue was updated
+        if transport.ack_interrupt() & 1 != 0 {
+            RECEIVED.up();
+        }
+    }
+    // if the driver is in use on this CPU, whatever arrived will be picked
+    // up by the next `receive()`.
+    if let Some(mut net) = NET.try_lock() {
+        if let Some(ref mut net) = *net {
+            net.handle_interrupt();
+        }
+    }
+}
+
+/// Set up `dev` as the network card, if it is a virtio network card and we
+/// don't already have one.
+///
+/// Returns true if the card was set up.
+pub fn init(dev: &PciDevice) -> bool {
+    if TRANSPORT.try().is_some() { return false }
+    match VirtioNet::probe(dev) {
+        Some(net) => {
+            let transport = net.transport;
+            info!("virtio-net {:?}: {:?}, IRQ {}", dev, net, transport.irq());
+            *NET.lock() = Some(net);
+            TRANSPORT.call_once(|| transport);
+            interrupts::register_irq(transport.irq(), handle_irq);
+            true
+        }
+      , None => false
+    }
+}
@@ -0,0 +1,218 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Virtio 9P transport driver.
+//!
+//! QEMU's `-virtfs` option shares a directory on the host through this
+//! device, which carries 9P messages: the driver puts a request on the
+//! device's only queue, followed by a buffer for the device to write the
+//! reply into. The 9P protocol itself lives in [`fs::p9`], which mounts the
+//! share.
+//!
+//! Like the block driver, we only have one request in flight at a time,
+//! through a pair of buffers in DMA memory.
+//!
+//! [`fs::p9`]: ../../../fs/p9/index.html
+use alloc::arc::Arc;
+use core::{cmp, fmt};
+use spin::{Mutex, Once};
+
+use arch::interrupts;
+use dev::pci::PciDevice;
+use fs;
+use mm::dma::DmaBox;
+use task::wait::Semaphore;
+use super::{VirtioError, VirtioPci, VIRTIO_VENDOR_ID, DRIVER_OK};
+use super::queue::{Buffer, Virtqueue};
+
+/// The PCI device ID of a (transitional) virtio 9P device.
+pub const VIRTIO_9P_DEVICE_ID: u16 = 0x1009;
+
+/// Feature bit: the device has a mount tag in its configuration.
+pub const VIRTIO_9P_F_MOUNT_TAG: u32 = 1 << 0;
+
+/// The largest message sent or received, in bytes. This is what we ask for
+/// in `Tversion`; the server may choose less.
+pub const MSIZE: usize = 8192;
+
+/// The longest mount tag we keep.
+pub const MAX_TAG: usize = 64;
+
+/// The DMA memory for a request and its reply.
+#[repr(C)]
+struct Message { request: [u8; MSIZE]
+               , reply: [u8; MSIZE]
+               }
+
+/// The parts of the device that change with each request.
+struct Inner { queue: Virtqueue
+             , message: DmaBox<Message>
+             }
+
+/// A virtio 9P device.
+pub struct Virtio9p { transport: VirtioPci
+                    , /// The name QEMU was given for the share
+                      tag: [u8; MAX_TAG]
+                    , tag_len: usize
+                    , inner: Mutex<Inner>
+                    , /// Held by the task with a request in flight
+                      busy: Semaphore
+                    , /// Signalled by the interrupt handler when the
+                      /// device has replied
+                      complete: Semaphore
+                    }
+
+/// The 9P device, once it has been found.
+static P9: Once<Arc<Virtio9p>> = Once::new();
+
+impl Virtio9p {
+    /// Initialize `dev`, if it is a virtio 9P device.
+    ///
+    /// Its interrupt handler isn't registered, so it can't complete any
+    /// requests until it has been passed to [`init`](fn.init.html).
+    pub fn probe(dev: &PciDevice) -> Option<Virtio9p> {
+        if dev.vendor_id != VIRTIO_VENDOR_ID
+            || dev.device_id != VIRTIO_9P_DEVICE_ID {
+            return None
+        }
+        let transport = match VirtioPci::new(dev) {
+            Ok(transport) => transport
+          , Err(why) => {
+                warn!("virtio-9p {:?}: {}", dev, why);
+                return None
+            }
+        };
+        match Virtio9p::setup(transport) {
+            Ok(p9) => Some(p9)
+          , Err(why) => {
+                warn!("virtio-9p {:?}: {}", dev, why);
+                transport.fail();
+                None
+            }
+        }
+    }
+
+    fn setup(transport: VirtioPci) -> Result<Virtio9p, VirtioError> {
+        let features = transport.negotiate(VIRTIO_9P_F_MOUNT_TAG);
+        let mut tag = [0; MAX_TAG];
+        let mut tag_len = 0;
+        if features & VIRTIO_9P_F_MOUNT_TAG != 0 {
+            tag_len = cmp::min(transport.config_u16(0) as usize, MAX_TAG);
+            for (i, byte) in tag[..tag_len].iter_mut().enumerate() {
+                *byte = transport.config_u8(2 + i as u16);
+            }
+        }
+        let queue = transport.setup_queue(0)?;
+        let message = unsafe { DmaBox::<Message>::zeroed() }
+            .map_err(|_| VirtioError::NoMemory)?;
+        transport.add_status(DRIVER_OK);
+        Ok(Virtio9p { transport: transport
+                    , tag: tag
+                    , tag_len: tag_len
+                    , inner: Mutex::new(Inner { queue: queue
+                                              , message: message })
+                    , busy: Semaphore::new(1)
+                    , complete: Semaphore::new(0)
+                    })
+    }
+
+    /// Returns the share's mount tag, or an empty slice if the device
+    /// doesn't have one.
+    #[inline] pub fn tag(&self) -> &[u8] { &self.tag[..self.tag_len] }
+
+    /// Send a request and wait for the reply.
+    ///
+    /// `build` writes the request into the buffer it's given, and returns
+    /// its length. `parse` is then called with the reply.
+    ///
+    /// This blocks until the device has replied.
+    pub fn rpc<B, P, R>(&self, build: B, parse: P) -> R
+    where B: FnOnce(&mut [u8]) -> usize
+        , P: FnOnce(&[u8]) -> R {
+        self.busy.down();
+        {
+            let mut inner = self.inner.lock();
+            let inner = &mut *inner;
+            let len = build(&mut inner.message.request);
+            assert!(len <= MSIZE, "9P request is too long!");
+            let message = &inner.message;
+            let chain =
+                [ Buffer { addr: message.paddr_of(&message.request)
+                         , len: len as u32
+                         , writable: false
+                         }
+                , Buffer { addr: message.paddr_of(&message.reply)
+                         , len: MSIZE as u32
+                         , writable: true
+                         }
+                ];
+            // we only ever have one request in flight, so the queue can't
+            // be full.
+            inner.queue.push(&chain).expect("virtio-9p queue full!");
+            self.transport.notify(&inner.queue);
+        }
+        // as in the block driver, the queue is what says we're done.
+        let len;
+        loop {
+            if let Some((_, used)) = self.inner.lock().queue.pop_used() {
+                len = cmp::min(used as usize, MSIZE);
+                break
+            }
+            self.complete.down();
+        }
+        let result = parse(&self.inner.lock().message.reply[..len]);
+        self.busy.up();
+        result
+    }
+}
+
+impl fmt::Debug for Virtio9p {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Virtio9p(")?;
+        for &b in self.tag() {
+            write!(f, "{}", b as char)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Handler for the 9P device's IRQ.
+fn handle_irq() {
+    if let Some(p9) = P9.try() {
+        // bit 0 of the ISR status means a queue was updated
+        if p9.transport.ack_interrupt() & 1 != 0 {
+            p9.complete.up();
+        }
+    }
+}
+
+/// Set up `dev` and mount its share, if it is a virtio 9P device and we
+/// don't already have one.
+///
+/// The share is mounted in the root directory, under its mount tag: QEMU's
+/// `-virtfs local,path=...,mount_tag=host,...` is mounted at `/host`.
+///
+/// Returns true if the device was set up.
+pub fn init(dev: &PciDevice) -> bool {
+    if P9.try().is_some() { return false }
+    match Virtio9p::probe(dev) {
+        Some(p9) => {
+            let irq = p9.transport.irq();
+            info!("virtio-9p {:?}: {:?}, IRQ {}", dev, p9, irq);
+            let p9 = P9.call_once(|| Arc::new(p9));
+            interrupts::register_irq(irq, handle_irq);
+            let mount_point = if p9.tag().is_empty() { &b"host"[..] }
+                              else { p9.tag() };
+            if let Err(why) = fs::p9::mount(p9.clone(), mount_point) {
+                warn!("virtio-9p {:?}: could not mount: {:?}", dev, why);
+            }
+            true
+        }
+      , None => false
+    }
+}
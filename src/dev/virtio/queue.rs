@@ -0,0 +1,262 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Virtqueues.
+//!
+//! A virtqueue is how buffers are passed between a driver and a virtio
+//! device. It has three parts, all in memory shared with the device:
+//!
+//! + the descriptor table, describing each buffer,
+//! + the available ring, where the driver puts chains of descriptors for
+//!   the device to process, and
+//! + the used ring, where the device returns them when it's done.
+//!
+//! The legacy interface requires the three to be laid out back-to-back in
+//! physically contiguous memory, with the used ring starting on a new page.
+use core::{fmt, ptr};
+use core::sync::atomic::{fence, Ordering};
+use memory::PAddr;
+use sos_alloc::AllocResult;
+
+use mm::dma::DmaBox;
+
+/// The number of descriptors in a queue.
+///
+/// Legacy devices decide the size of their queues, and QEMU's are this
+/// size.
+pub const QUEUE_SIZE: usize = 256;
+/// The alignment of the used ring required by the legacy interface.
+pub const QUEUE_ALIGN: usize = 4096;
+
+bitflags! {
+    /// Flags on a descriptor.
+    pub flags DescFlags: u16 { /// The buffer continues in `next`
+                               const NEXT     = 1
+                             , /// The device writes to (rather than reads
+                               /// from) the buffer
+                               const WRITE    = 2
+                             , /// The buffer is a table of descriptors
+                               const INDIRECT = 4
+                             }
+}
+
+/// A descriptor, pointing at one buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Descriptor { /// Physical address of the buffer
+                        pub addr: u64
+                      , /// Length of the buffer, in bytes
+                        pub len: u32
+                      , /// The `DescFlags` bits
+                        pub flags: u16
+                      , /// The next descriptor in the chain, if `NEXT` is
+                        /// set
+                        pub next: u16
+                      }
+
+/// The descriptor table.
+#[repr(C)]
+pub struct VirtqueueDescTable { pub descs: [Descriptor; QUEUE_SIZE] }
+
+/// The available ring, written by the driver.
+#[repr(C)]
+pub struct AvailableRing { pub flags: u16
+                         , /// Where the driver will put the next entry
+                           /// (modulo the queue size)
+                           pub idx: u16
+                         , /// Heads of descriptor chains
+                           pub ring: [u16; QUEUE_SIZE]
+                         , pub used_event: u16
+                         }
+
+/// An entry in the used ring.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct UsedElem { /// Head of the descriptor chain that was used
+                      pub id: u32
+                    , /// Number of bytes the device wrote to the chain
+                      pub len: u32
+                    }
+
+/// The used ring, written by the device.
+#[repr(C)]
+pub struct UsedRing { pub flags: u16
+                    , /// Where the device will put the next entry
+                      /// (modulo the queue size)
+                      pub idx: u16
+                    , pub ring: [UsedElem; QUEUE_SIZE]
+                    , pub avail_event: u16
+                    }
+
+/// Padding between the available ring and the used ring. The descriptor
+/// table is a whole number of pages, so only the available ring needs to be
+/// padded out.
+const PADDING: usize = QUEUE_ALIGN - (2 + 2 + 2 * QUEUE_SIZE + 2);
+
+/// The memory shared with the device, in the layout the legacy interface
+/// expects.
+#[repr(C)]
+struct QueueMemory { desc: VirtqueueDescTable
+                   , avail: AvailableRing
+                   , _pad: [u8; PADDING]
+                   , used: UsedRing
+                   }
+
+/// A buffer to add to a virtqueue.
+#[derive(Copy, Clone, Debug)]
+pub struct Buffer { /// Physical address of the buffer
+                    pub addr: PAddr
+                  , /// Length of the buffer, in bytes
+                    pub len: u32
+                  , /// True if the device should write to the buffer
+                    pub writable: bool
+                  }
+
+/// A virtqueue.
+pub struct Virtqueue { /// The queue's number on its device
+                       index: u16
+                     , mem: DmaBox<QueueMemory>
+                     , /// Head of the list of unused descriptors
+                       free_head: u16
+                     , /// Number of unused descriptors
+                       num_free: usize
+                     , /// The used ring index we've processed up to
+                       last_used: u16
+                     }
+
+impl Virtqueue {
+    /// Allocate a new, empty queue to be the device's queue `index`.
+    pub fn new(index: u16) -> AllocResult<Self> {
+        // an all-zero queue is empty, and has no flags set.
+        let mut mem = unsafe { DmaBox::<QueueMemory>::zeroed()? };
+        // chain every descriptor into the free list
+        for (i, desc) in mem.desc.descs.iter_mut().enumerate() {
+            desc.next = (i + 1) as u16;
+        }
+        Ok(Virtqueue { index: index
+                     , mem: mem
+                     , free_head: 0
+                     , num_free: QUEUE_SIZE
+                     , last_used: 0
+                     })
+    }
+
+    /// Returns the queue's number on its device.
+    #[inline] pub fn index(&self) -> u16 { self.index }
+
+    /// Returns the physical address of the queue, for the device.
+    #[inline] pub fn paddr(&self) -> PAddr { self.mem.paddr() }
+
+    /// Returns the number of unused descriptors.
+    #[inline] pub fn num_free(&self) -> usize { self.num_free }
+
+    /// Make a chain of `buffers` available to the device.
+    ///
+    /// Returns the descriptor at the head of the chain, which identifies
+    /// the chain when the device returns it, or `None` if there aren't
+    /// enough free descriptors. The device isn't notified.
+    pub fn push(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free {
+            return None
+        }
+        // the free list is linked through `next`, so taking descriptors off
+        // the front of it leaves them chained together already.
+        let head = self.free_head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let index = self.free_head as usize;
+            let desc = &mut self.mem.desc.descs[index];
+            desc.addr = *buffer.addr;
+            desc.len = buffer.len;
+            desc.flags = if buffer.writable { WRITE.bits() } else { 0 };
+            if i + 1 < buffers.len() { desc.flags |= NEXT.bits(); }
+            self.free_head = desc.next;
+        }
+        self.num_free -= buffers.len();
+
+        let avail_idx = self.mem.avail.idx;
+        self.mem.avail.ring[avail_idx as usize % QUEUE_SIZE] = head;
+        // the device must see the descriptors before the new index
+        fence(Ordering::SeqCst);
+        unsafe {
+            ptr::write_volatile( &mut self.mem.avail.idx
+                               , avail_idx.wrapping_add(1));
+        }
+        Some(head)
+    }
+
+    /// Take the next descriptor chain the device has finished with.
+    ///
+    /// Returns the head of the chain and the number of bytes the device
+    /// wrote to it, and returns the chain's descriptors to the free list.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { ptr::read_volatile(&self.mem.used.idx) };
+        if used_idx == self.last_used { return None }
+        // don't read the entry before we've seen the index
+        fence(Ordering::SeqCst);
+        let elem = self.mem.used.ring[self.last_used as usize % QUEUE_SIZE];
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = elem.id as u16;
+        let mut tail = head;
+        loop {
+            self.num_free += 1;
+            let desc = &self.mem.desc.descs[tail as usize];
+            if desc.flags & NEXT.bits() == 0 { break }
+            tail = desc.next;
+        }
+        self.mem.desc.descs[tail as usize].next = self.free_head;
+        self.free_head = head;
+        Some((head, elem.len))
+    }
+
+    /// Returns descriptor number `index`.
+    #[inline]
+    pub fn descriptor(&self, index: u16) -> Descriptor {
+        self.mem.desc.descs[index as usize % QUEUE_SIZE]
+    }
+
+    /// Take the next chain from the available ring, as the device would,
+    /// returning its head. `next_avail` is the device's place in the ring.
+    ///
+    /// Drivers never need this; it's for standing in for a device in
+    /// tests.
+    pub fn device_pop_avail(&self, next_avail: &mut u16) -> Option<u16> {
+        let avail_idx = unsafe { ptr::read_volatile(&self.mem.avail.idx) };
+        if avail_idx == *next_avail { return None }
+        fence(Ordering::SeqCst);
+        let head = self.mem.avail.ring[*next_avail as usize % QUEUE_SIZE];
+        *next_avail = next_avail.wrapping_add(1);
+        Some(head)
+    }
+
+    /// Return the chain at `head` to the driver through the used ring,
+    /// having written `len` bytes to it, as the device would.
+    ///
+    /// Like [`device_pop_avail`](#method.device_pop_avail), this is only
+    /// for tests.
+    pub fn device_push_used(&mut self, head: u16, len: u32) {
+        let used_idx = self.mem.used.idx;
+        self.mem.used.ring[used_idx as usize % QUEUE_SIZE]
+            = UsedElem { id: head as u32, len: len };
+        fence(Ordering::SeqCst);
+        unsafe {
+            ptr::write_volatile( &mut self.mem.used.idx
+                               , used_idx.wrapping_add(1));
+        }
+    }
+}
+
+impl fmt::Debug for Virtqueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Virtqueue")
+         .field("index", &self.index)
+         .field("paddr", &self.paddr())
+         .field("num_free", &self.num_free)
+         .finish()
+    }
+}
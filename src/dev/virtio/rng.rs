@@ -0,0 +1,196 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Virtio entropy device driver.
+//!
+//! QEMU's `-device virtio-rng-pci` passes random bytes from the host's
+//! `/dev/urandom` (or a hardware RNG) through to the guest. The device has
+//! a single queue: the driver puts a writable buffer on it, and the device
+//! hands it back with as many random bytes as it had to give.
+//!
+//! When there's one, [`arch::rng::fill_bytes`] prefers it to `rdrand`,
+//! which a guest may not have, or may not want to trust.
+//!
+//! Like the other virtio drivers, we only have one request in flight at a
+//! time, through a single buffer in DMA memory.
+//!
+//! [`arch::rng::fill_bytes`]: ../../../arch/rng/fn.fill_bytes.html
+use core::{cmp, fmt};
+use spin::{Mutex, Once};
+
+use arch::interrupts;
+use dev::pci::PciDevice;
+use mm::dma::DmaBox;
+use task::wait::Semaphore;
+use super::{VirtioError, VirtioPci, VIRTIO_VENDOR_ID, DRIVER_OK};
+use super::queue::{Buffer, Virtqueue};
+
+/// The PCI device ID of a (transitional) virtio entropy device.
+pub const VIRTIO_RNG_DEVICE_ID: u16 = 0x1005;
+
+/// The most bytes asked for in one request.
+pub const RNG_BUF: usize = 64;
+
+/// The DMA buffer the device writes random bytes into.
+pub type RngBuffer = DmaBox<[u8; RNG_BUF]>;
+
+/// The parts of the device that change with each request.
+struct Inner { queue: Virtqueue
+             , buf: RngBuffer
+             }
+
+/// A virtio entropy device.
+pub struct VirtioRng { transport: VirtioPci
+                     , inner: Mutex<Inner>
+                     , /// Held by the task with a request in flight
+                       busy: Semaphore
+                     , /// Signalled by the interrupt handler when the
+                       /// device has filled the buffer
+                       complete: Semaphore
+                     }
+
+/// The entropy device, once it has been found.
+static RNG: Once<VirtioRng> = Once::new();
+
+/// Put `buf` on `queue` for the device to write `len` random bytes to.
+///
+/// The device isn't notified. Returns the head of the chain, or `None` if
+/// the queue is full.
+pub fn submit(queue: &mut Virtqueue, buf: &RngBuffer, len: usize)
+              -> Option<u16> {
+    let chain = [ Buffer { addr: buf.paddr()
+                         , len: cmp::min(len, RNG_BUF) as u32
+                         , writable: true
+                         }
+                ];
+    queue.push(&chain)
+}
+
+/// Copy the bytes the device wrote to `buf` into `out`, if the device has
+/// handed it back, returning how many there were.
+pub fn collect(queue: &mut Virtqueue, buf: &RngBuffer, out: &mut [u8])
+               -> Option<usize> {
+    queue.pop_used().map(|(_, written)| {
+        let len = cmp::min(cmp::min(written as usize, RNG_BUF), out.len());
+        out[..len].copy_from_slice(&buf[..len]);
+        len
+    })
+}
+
+impl VirtioRng {
+    /// Initialize `dev`, if it is a virtio entropy device.
+    ///
+    /// Its interrupt handler isn't registered, so it can't complete any
+    /// requests until it has been passed to [`init`](fn.init.html).
+    pub fn probe(dev: &PciDevice) -> Option<VirtioRng> {
+        if dev.vendor_id != VIRTIO_VENDOR_ID
+            || dev.device_id != VIRTIO_RNG_DEVICE_ID {
+            return None
+        }
+        let transport = match VirtioPci::new(dev) {
+            Ok(transport) => transport
+          , Err(why) => {
+                warn!("virtio-rng {:?}: {}", dev, why);
+                return None
+            }
+        };
+        match VirtioRng::setup(transport) {
+            Ok(rng) => Some(rng)
+          , Err(why) => {
+                warn!("virtio-rng {:?}: {}", dev, why);
+                transport.fail();
+                None
+            }
+        }
+    }
+
+    fn setup(transport: VirtioPci) -> Result<VirtioRng, VirtioError> {
+        // the entropy device has no features.
+        transport.negotiate(0);
+        let queue = transport.setup_queue(0)?;
+        let buf = unsafe { RngBuffer::zeroed() }
+            .map_err(|_| VirtioError::NoMemory)?;
+        transport.add_status(DRIVER_OK);
+        Ok(VirtioRng { transport: transport
+                     , inner: Mutex::new(Inner { queue: queue, buf: buf })
+                     , busy: Semaphore::new(1)
+                     , complete: Semaphore::new(0)
+                     })
+    }
+
+    /// Fill as much of `buf` as the device will in one request, returning
+    /// the number of bytes filled.
+    ///
+    /// At most `RNG_BUF` bytes are filled at once, and the device may give
+    /// fewer. This blocks until the device has replied.
+    pub fn fill(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() { return 0 }
+        self.busy.down();
+        {
+            let mut inner = self.inner.lock();
+            let inner = &mut *inner;
+            // we only ever have one request in flight, so the queue can't
+            // be full.
+            submit(&mut inner.queue, &inner.buf, buf.len())
+                .expect("virtio-rng queue full!");
+            self.transport.notify(&inner.queue);
+        }
+        let len;
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                let inner = &mut *inner;
+                if let Some(n) = collect(&mut inner.queue, &inner.buf, buf) {
+                    len = n;
+                    break
+                }
+            }
+            self.complete.down();
+        }
+        self.busy.up();
+        len
+    }
+}
+
+impl fmt::Debug for VirtioRng {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VirtioRng(IRQ {})", self.transport.irq())
+    }
+}
+
+/// Returns the entropy device, if there is one.
+#[inline]
+pub fn device() -> Option<&'static VirtioRng> { RNG.try() }
+
+/// Handler for the entropy device's IRQ.
+fn handle_irq() {
+    if let Some(rng) = RNG.try() {
+        // bit 0 of the ISR status means a queue was updated
+        if rng.transport.ack_interrupt() & 1 != 0 {
+            rng.complete.up();
+        }
+    }
+}
+
+/// Set up `dev`, if it is a virtio entropy device and we don't already
+/// have one.
+///
+/// Returns true if the device was set up.
+pub fn init(dev: &PciDevice) -> bool {
+    if RNG.try().is_some() { return false }
+    match VirtioRng::probe(dev) {
+        Some(rng) => {
+            let irq = rng.transport.irq();
+            info!("virtio-rng {:?}: {:?}", dev, rng);
+            RNG.call_once(|| rng);
+            interrupts::register_irq(irq, handle_irq);
+            true
+        }
+      , None => false
+    }
+}
@@ -0,0 +1,358 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! CPIO archives, and a read-only file system backed by one.
+//!
+//! The initial ramdisk is a CPIO archive in the "new ASCII" (`newc`)
+//! format, as made by `find . | cpio -o -H newc`, and loaded by the
+//! bootloader as a Multiboot module. Each entry is a 110-byte header of
+//! hexadecimal ASCII fields, followed by the entry's NUL-terminated name and
+//! then its contents, each padded to a multiple of four bytes. The archive
+//! ends with an entry called `TRAILER!!!`.
+//!
+//! The archive is never copied: [`CpioFs`](struct.CpioFs.html) files read
+//! straight out of the module's memory.
+use alloc::arc::Arc;
+use alloc::btree_map::BTreeMap;
+use core::{cmp, slice, str};
+use memory::PAddr;
+use paging::arch::table::NO_EXECUTE;
+use params::InitParams;
+
+use mm::map_physical;
+use super::{DirEntry, FileName, Inode, InodeStat, IoError, mode};
+
+/// The magic number at the start of every `newc` header.
+const NEWC_MAGIC: &'static [u8] = b"070701";
+/// The size of a `newc` header, in bytes.
+const NEWC_HEADER_LEN: usize = 110;
+/// The name of the entry marking the end of the archive.
+const TRAILER: &'static str = "TRAILER!!!";
+
+/// Offsets of the header fields used here. Every field is eight hex digits.
+mod field {
+    pub const MODE: usize = 14;
+    pub const FILESIZE: usize = 54;
+    pub const NAMESIZE: usize = 94;
+}
+
+/// Round `n` up to a multiple of four.
+#[inline]
+fn align4(n: usize) -> usize { (n + 3) & !3 }
+
+/// Parse the eight-digit hex field at `offset` in `header`.
+fn hex_field(header: &[u8], offset: usize) -> Option<u32> {
+    str::from_utf8(&header[offset .. offset + 8]).ok()
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+}
+
+/// A CPIO archive in the `newc` format.
+#[derive(Copy, Clone, Debug)]
+pub struct CpioArchive<'a> { data: &'a [u8] }
+
+impl<'a> CpioArchive<'a> {
+    /// Returns the archive held in `data`.
+    ///
+    /// Nothing is checked until the archive is iterated over: iteration
+    /// stops at the first malformed entry, as if the archive ended there.
+    #[inline]
+    pub fn from_slice(data: &'a [u8]) -> CpioArchive<'a> {
+        CpioArchive { data: data }
+    }
+
+    /// Returns an iterator over the entries in the archive, not including
+    /// the trailer.
+    #[inline]
+    pub fn iter(&self) -> CpioEntries<'a> {
+        CpioEntries { rest: self.data }
+    }
+}
+
+impl<'a> IntoIterator for &'a CpioArchive<'a> {
+    type Item = CpioEntry<'a>;
+    type IntoIter = CpioEntries<'a>;
+    #[inline] fn into_iter(self) -> Self::IntoIter { self.iter() }
+}
+
+/// A file, directory or other object in a CPIO archive.
+#[derive(Copy, Clone, Debug)]
+pub struct CpioEntry<'a> { /// The entry's path, relative to the root of
+                           /// the archive
+                           pub name: &'a str
+                         , /// The entry's contents
+                           pub data: &'a [u8]
+                         , /// The entry's type and permission bits, in the
+                           /// same format as `InodeStat::mode`
+                           pub mode: u32
+                         }
+
+/// An iterator over the entries in a [`CpioArchive`].
+///
+/// [`CpioArchive`]: struct.CpioArchive.html
+#[derive(Clone, Debug)]
+pub struct CpioEntries<'a> { rest: &'a [u8] }
+
+impl<'a> CpioEntries<'a> {
+    /// Parse the entry at the start of `self.rest`, and advance past it.
+    fn parse_next(&mut self) -> Option<CpioEntry<'a>> {
+        let data = self.rest;
+        if data.len() < NEWC_HEADER_LEN
+            || &data[..NEWC_MAGIC.len()] != NEWC_MAGIC {
+            return None
+        }
+        let header = &data[..NEWC_HEADER_LEN];
+        let (mode, file_size, name_size)
+            = match ( hex_field(header, field::MODE)
+                    , hex_field(header, field::FILESIZE)
+                    , hex_field(header, field::NAMESIZE) ) {
+                (Some(mode), Some(file_size), Some(name_size)) =>
+                    (mode, file_size as usize, name_size as usize)
+              , _ => return None
+            };
+
+        // the name's length includes its NUL terminator
+        let name_end = NEWC_HEADER_LEN + name_size;
+        if name_size == 0 || name_end > data.len() { return None }
+        let name = &data[NEWC_HEADER_LEN .. name_end - 1];
+        let name = match str::from_utf8(name) {
+            Ok(name) => name
+          , Err(_) => return None
+        };
+
+        let data_start = align4(name_end);
+        let data_end = data_start + file_size;
+        if data_end > data.len() { return None }
+        let contents = &data[data_start .. data_end];
+
+        let next = cmp::min(align4(data_end), data.len());
+        self.rest = &data[next..];
+        Some(CpioEntry { name: name, data: contents, mode: mode })
+    }
+}
+
+impl<'a> Iterator for CpioEntries<'a> {
+    type Item = CpioEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.parse_next() {
+            Some(CpioEntry { name, .. }) if name == TRAILER => {
+                self.rest = &[];
+                None
+            }
+          , Some(entry) => Some(entry)
+          , None => {
+                self.rest = &[];
+                None
+            }
+        }
+    }
+}
+
+/// A read-only file system backed by a CPIO archive.
+pub struct CpioFs;
+
+impl CpioFs {
+    /// Returns the root directory of a file system holding the contents of
+    /// `archive`.
+    ///
+    /// Directories that aren't in the archive themselves, but have entries
+    /// in it, are made up. Entries with names that can't be used in a path
+    /// are skipped.
+    pub fn mount(archive: CpioArchive<'static>) -> Arc<Inode> {
+        let mut root = DirBuilder::new(mode::S_IFDIR | 0o755);
+        for entry in archive.iter() {
+            root.insert(entry);
+        }
+        root.build()
+    }
+}
+
+/// A directory in a CPIO file system that's still being filled in.
+struct DirBuilder { mode: u32
+                  , children: BTreeMap<FileName, Node>
+                  }
+
+/// An entry in a `DirBuilder`.
+enum Node { File(CpioEntry<'static>)
+          , Dir(DirBuilder)
+          }
+
+impl DirBuilder {
+    fn new(mode: u32) -> Self {
+        DirBuilder { mode: mode, children: BTreeMap::new() }
+    }
+
+    /// Add `entry` to the tree below this directory.
+    fn insert(&mut self, entry: CpioEntry<'static>) {
+        let mut path = entry.name.split('/')
+                            .filter(|&name| !name.is_empty() && name != ".");
+        let mut name = match path.next() {
+            Some(name) => name
+          , None => return
+        };
+        let mut dir = self;
+        for next in path {
+            let child = match FileName::new(name.as_bytes()) {
+                Some(child) => child
+              , None => return
+            };
+            let parent = dir;
+            let node = parent.children.entry(child).or_insert_with(||
+                Node::Dir(DirBuilder::new(mode::S_IFDIR | 0o755)));
+            dir = match *node {
+                Node::Dir(ref mut subdir) => subdir
+              , Node::File(_) => return
+            };
+            name = next;
+        }
+        let name = match FileName::new(name.as_bytes()) {
+            Some(name) => name
+          , None => return
+        };
+        if entry.mode & mode::S_IFMT == mode::S_IFDIR {
+            // the directory may already have been made up for an earlier
+            // entry inside it.
+            match *dir.children.entry(name).or_insert_with(||
+                    Node::Dir(DirBuilder::new(entry.mode))) {
+                Node::Dir(ref mut subdir) => subdir.mode = entry.mode
+              , Node::File(_) => {}
+            }
+        } else {
+            dir.children.insert(name, Node::File(entry));
+        }
+    }
+
+    fn build(self) -> Arc<Inode> {
+        let children = self.children.into_iter()
+            .map(|(name, node)| (name, match node {
+                Node::File(entry) => Arc::new(CpioFile(entry)) as Arc<Inode>
+              , Node::Dir(dir) => dir.build()
+            }))
+            .collect();
+        Arc::new(CpioDir { mode: self.mode, children: children })
+    }
+}
+
+/// A directory in a CPIO file system.
+struct CpioDir { mode: u32
+               , children: BTreeMap<FileName, Arc<Inode>>
+               }
+
+impl Inode for CpioDir {
+    #[inline]
+    fn read_at(&self, _offset: u64, _buf: &mut [u8])
+              -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline]
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline]
+    fn stat(&self) -> InodeStat {
+        InodeStat { size: self.children.len() as u64
+                  , mode: self.mode
+                  , ..Default::default()
+                  }
+    }
+
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError> {
+        Ok(self.children.iter().nth(offset as usize)
+               .map(|(name, inode)|
+                    DirEntry { name: *name
+                             , kind: inode.stat().mode & mode::S_IFMT
+                             }))
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        let name = FileName::new(name).ok_or(IoError::InvalidArgument)?;
+        self.children.get(&name)
+            .cloned()
+            .ok_or(IoError::NotFound)
+    }
+}
+
+/// A file in a CPIO file system.
+struct CpioFile(CpioEntry<'static>);
+
+impl Inode for CpioFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, IoError> {
+        let data = self.0.data;
+        let offset = cmp::min(offset, data.len() as u64) as usize;
+        let n = cmp::min(buf.len(), data.len() - offset);
+        buf[..n].copy_from_slice(&data[offset .. offset + n]);
+        Ok(n)
+    }
+
+    #[inline]
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, IoError> {
+        Err(IoError::Unsupported)
+    }
+
+    #[inline]
+    fn stat(&self) -> InodeStat {
+        let size = self.0.data.len() as u64;
+        InodeStat { size: size
+                  , blocks: (size + 511) / 512
+                  , mode: self.0.mode
+                  , ..Default::default()
+                  }
+    }
+
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::Unsupported)
+    }
+
+    #[inline]
+    fn readdir(&self, _offset: u64) -> Result<Option<DirEntry>, IoError> {
+        Err(IoError::NotADirectory)
+    }
+}
+
+/// Map the initial ramdisk into the physical memory map, read-only, and
+/// return its contents.
+fn map_initrd(start: PAddr, end: PAddr) -> Result<&'static [u8], IoError> {
+    if end <= start { return Ok(&[]) }
+    let len = (*end - *start) as usize;
+    let base = map_physical(start, len, NO_EXECUTE)
+        .map_err(|_| IoError::NoSpace)?;
+    Ok(unsafe { slice::from_raw_parts(base.as_ptr::<u8>(), len) })
+}
+
+/// Mount the initial ramdisk the bootloader loaded, if there is one, by
+/// linking each of its top-level entries into the root directory. Entries
+/// that would hide something already there (such as `/proc`) are skipped.
+///
+/// Returns the number of entries in the archive.
+pub fn mount_initrd(params: &InitParams) -> Result<usize, IoError> {
+    let (start, end) = match (params.initrd_start, params.initrd_end) {
+        (Some(start), Some(end)) => (start, end)
+      , _ => return Ok(0)
+    };
+    let archive = CpioArchive::from_slice(map_initrd(start, end)?);
+    let root = CpioFs::mount(archive);
+    let mut offset = 0;
+    while let Some(entry) = root.readdir(offset)? {
+        let name = entry.name.as_bytes();
+        match super::root_dir().link(name, root.lookup(name)?) {
+            Err(IoError::AlreadyExists) =>
+                warn!("initrd: /{:?} is already mounted", entry.name)
+          , result => result?
+        }
+        offset += 1;
+    }
+    Ok(archive.iter().count())
+}
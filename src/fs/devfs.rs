@@ -0,0 +1,162 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A pseudo-file system of device files, mounted at `/dev`.
+//!
+//! ```text
+//! /dev
+//! └── kmsg    the kernel log (see `syslog`)
+//! ```
+use alloc::arc::Arc;
+use core::str;
+use core::fmt::Write;
+use spin::Mutex;
+use util::bufwriter::BufWriter;
+
+use syslog::{self, DEFAULT_LEVEL};
+use task;
+use super::{DirEntry, FileName, Inode, InodeStat, IoError, mode};
+
+/// The devfs file system.
+pub struct Devfs;
+
+impl Devfs {
+    /// Returns the root directory of a devfs.
+    pub fn mount() -> Arc<Inode> {
+        Arc::new(DevRoot)
+    }
+
+    /// Returns a new reader and writer for the kernel log, which starts
+    /// reading from the oldest entry still kept.
+    pub fn kmsg() -> Arc<Inode> {
+        Arc::new(Kmsg { next_seq: Mutex::new(0) })
+    }
+}
+
+/// The files in the devfs root directory.
+const DEV_FILES: [&'static [u8]; 1] = [b"kmsg"];
+
+/// The devfs root directory.
+struct DevRoot;
+
+impl Inode for DevRoot {
+    #[inline]
+    fn read_at(&self, _offset: u64, _buf: &mut [u8])
+              -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline]
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline]
+    fn stat(&self) -> InodeStat {
+        InodeStat { mode: mode::S_IFDIR | 0o555, ..Default::default() }
+    }
+
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError> {
+        Ok(DEV_FILES.get(offset as usize)
+                    .and_then(|name| FileName::new(name))
+                    .map(|name| DirEntry { name: name
+                                         , kind: mode::S_IFCHR }))
+    }
+
+    /// Each lookup of `kmsg` is a new inode, so that each open file
+    /// description keeps its own place in the log, as on Linux.
+    fn lookup(&self, name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        if name == b"kmsg" { Ok(Devfs::kmsg()) }
+        else { Err(IoError::NotFound) }
+    }
+}
+
+/// `/dev/kmsg`.
+///
+/// Each read returns one entry, as `level,seq,timestamp_ns;message\n`,
+/// blocking until there is one. Readers that fall behind skip straight to
+/// the oldest entry still kept.
+///
+/// Each write logs one message, with the writer's PID prepended. A leading
+/// `<level>` sets its level, as on Linux.
+struct Kmsg { /// The sequence number of the next entry to read
+              next_seq: Mutex<u64>
+            }
+
+/// Splits a leading `<level>` off `buf`, returning the level and the rest.
+fn parse_level(buf: &[u8]) -> (u8, &[u8]) {
+    match (buf.get(0), buf.get(1), buf.get(2)) {
+        (Some(&b'<'), Some(&digit), Some(&b'>'))
+            if digit >= b'0' && digit <= b'7' => (digit - b'0', &buf[3..])
+      , _ => (DEFAULT_LEVEL, buf)
+    }
+}
+
+impl Inode for Kmsg {
+    /// Read the next entry, failing with `InvalidArgument` if it doesn't
+    /// fit in `buf`.
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, IoError> {
+        // not locked while waiting, or anyone else reading this file would
+        // spin until there was something to read.
+        let seq = *self.next_seq.lock();
+        let entry = syslog::wait_for(seq);
+        // messages are truncated to a byte count, so they may not be valid
+        // UTF-8 any more, and are copied as bytes.
+        let mut prefix = [0u8; 64];
+        let prefix_len = {
+            let mut w = BufWriter::new(&mut prefix);
+            let _ = write!( w, "{},{},{};"
+                          , entry.level, entry.seq, entry.timestamp_ns);
+            w.len()
+        };
+        let message = entry.message();
+        let len = prefix_len + message.len() + 1;
+        if len > buf.len() { return Err(IoError::InvalidArgument) }
+        buf[..prefix_len].copy_from_slice(&prefix[..prefix_len]);
+        buf[prefix_len..len - 1].copy_from_slice(message);
+        buf[len - 1] = b'\n';
+        *self.next_seq.lock() = entry.seq + 1;
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, IoError> {
+        let (level, message) = parse_level(buf);
+        let message = match message.last() {
+            Some(&b'\n') => &message[..message.len() - 1]
+          , _ => message
+        };
+        let pid = unsafe { task::current() }.pid;
+        match str::from_utf8(message) {
+            Ok(text) => syslog::log_and_wake( level
+                                            , format_args!( "[{}] {}"
+                                                          , pid, text))
+          , Err(_) => return Err(IoError::InvalidArgument)
+        }
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn stat(&self) -> InodeStat {
+        InodeStat { mode: mode::S_IFCHR | 0o644, ..Default::default() }
+    }
+
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::InvalidArgument)
+    }
+
+    #[inline]
+    fn readdir(&self, _offset: u64) -> Result<Option<DirEntry>, IoError> {
+        Err(IoError::NotADirectory)
+    }
+}
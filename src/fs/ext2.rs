@@ -0,0 +1,354 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A read-only ext2 file system.
+//!
+//! ```text
+//! | boot (1K) | superblock (1K) | ... | group 0 | group 1 | ...
+//!                                      └─ descriptors, bitmaps, inodes, data
+//! ```
+//!
+//! The superblock is always 1024 bytes into the device. The block group
+//! descriptors start in the block after it, and say where each group's
+//! inode table is; everything else is found through inodes. An inode's
+//! data is in the blocks listed in its first 12 block pointers, then in
+//! the blocks listed in the singly-indirect block, and then through the
+//! doubly-indirect block. Files big enough to need the triply-indirect
+//! block can't be read.
+//!
+//! Nothing is cached, so every read goes to the device.
+use alloc::arc::Arc;
+use alloc::vec::Vec;
+use core::cmp;
+
+use dev::block::{self, BlockDevice};
+use super::{DirEntry, FileName, Inode, InodeStat, IoError, mode};
+
+/// The byte offset of the superblock.
+const SUPERBLOCK_OFFSET: u64 = 1024;
+/// The length of the superblock.
+const SUPERBLOCK_LEN: usize = 1024;
+/// `s_magic` for ext2 (and ext3 and ext4).
+pub const EXT2_MAGIC: u16 = 0xef53;
+/// The inode number of the root directory.
+pub const ROOT_INO: u32 = 2;
+
+/// The length of a block group descriptor.
+const GROUP_DESC_LEN: usize = 32;
+/// The inode size of revision 0 file systems, which don't say.
+const GOOD_OLD_INODE_SIZE: usize = 128;
+/// The number of direct block pointers in an inode.
+const DIRECT_BLOCKS: u64 = 12;
+/// The index of the singly-indirect block pointer.
+const IND_BLOCK: usize = 12;
+/// The index of the doubly-indirect block pointer.
+const DIND_BLOCK: usize = 13;
+
+/// `s_feature_incompat`: directory entries have a file type.
+const INCOMPAT_FILETYPE: u32 = 0x2;
+/// The incompatible features we can read.
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+
+/// The length of a directory entry's header, before its name.
+const DIR_ENTRY_LEN: usize = 8;
+
+/// Returns the little-endian `u16` at `at` in `buf`.
+#[inline]
+fn get_u16(buf: &[u8], at: usize) -> u16 {
+    buf[at] as u16 | (buf[at + 1] as u16) << 8
+}
+
+/// Returns the little-endian `u32` at `at` in `buf`.
+#[inline]
+fn get_u32(buf: &[u8], at: usize) -> u32 {
+    get_u16(buf, at) as u32 | (get_u16(buf, at + 2) as u32) << 16
+}
+
+/// A mounted ext2 file system.
+pub struct Ext2 { dev: Arc<BlockDevice>
+                , /// The size of a block, in bytes
+                  block_size: usize
+                , /// The size of an on-disk inode, in bytes
+                  inode_size: usize
+                , inodes_per_group: u32
+                , inode_count: u32
+                , /// The first block of each group's inode table
+                  inode_tables: Vec<u32>
+                }
+
+impl Ext2 {
+    /// Mount the ext2 file system on `dev`.
+    ///
+    /// Fails with `IoError::InvalidArgument` if `dev` doesn't hold an ext2
+    /// file system, or `IoError::Unsupported` if it uses features we can't
+    /// read.
+    pub fn mount(dev: Arc<BlockDevice>) -> Result<Arc<Ext2>, IoError> {
+        let mut sb = [0u8; SUPERBLOCK_LEN];
+        read_bytes(&*dev, SUPERBLOCK_OFFSET, &mut sb)?;
+        if get_u16(&sb, 56) != EXT2_MAGIC {
+            return Err(IoError::InvalidArgument)
+        }
+        let log_block_size = get_u32(&sb, 24);
+        if log_block_size > 6 { return Err(IoError::InvalidArgument) }
+        let block_size = 1024 << log_block_size;
+        if block_size % dev.block_size() != 0 {
+            return Err(IoError::Unsupported)
+        }
+        let revision = get_u32(&sb, 76);
+        let (inode_size, incompat) =
+            if revision == 0 { (GOOD_OLD_INODE_SIZE, 0) }
+            else { (get_u16(&sb, 88) as usize, get_u32(&sb, 96)) };
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(IoError::Unsupported)
+        }
+        let inode_count = get_u32(&sb, 0);
+        let block_count = get_u32(&sb, 4);
+        let first_data_block = get_u32(&sb, 20);
+        let blocks_per_group = get_u32(&sb, 32);
+        let inodes_per_group = get_u32(&sb, 40);
+        if inode_size < GOOD_OLD_INODE_SIZE || inode_size > block_size
+            || blocks_per_group == 0 || inodes_per_group == 0
+            || block_count <= first_data_block {
+            return Err(IoError::InvalidArgument)
+        }
+
+        let groups = (block_count - first_data_block + blocks_per_group - 1)
+                   / blocks_per_group;
+        let mut descs = vec![0u8; groups as usize * GROUP_DESC_LEN];
+        let table = (first_data_block as u64 + 1) * block_size as u64;
+        read_bytes(&*dev, table, &mut descs)?;
+        let inode_tables = descs.chunks(GROUP_DESC_LEN)
+                                .map(|desc| get_u32(desc, 8))
+                                .collect();
+        Ok(Arc::new(Ext2 { dev: dev
+                         , block_size: block_size
+                         , inode_size: inode_size
+                         , inodes_per_group: inodes_per_group
+                         , inode_count: inode_count
+                         , inode_tables: inode_tables
+                         }))
+    }
+
+    /// Returns inode number `ino` of `fs`.
+    pub fn inode(fs: &Arc<Ext2>, ino: u32) -> Result<Ext2Inode, IoError> {
+        if ino == 0 || ino > fs.inode_count { return Err(IoError::NotFound) }
+        let group = ((ino - 1) / fs.inodes_per_group) as usize;
+        let index = ((ino - 1) % fs.inodes_per_group) as u64;
+        let table = *fs.inode_tables.get(group).ok_or(IoError::NotFound)?;
+        let offset = table as u64 * fs.block_size as u64
+                   + index * fs.inode_size as u64;
+        let mut raw = [0u8; GOOD_OLD_INODE_SIZE];
+        fs.read_bytes(offset, &mut raw)?;
+
+        let mode = get_u16(&raw, 0) as u32;
+        let mut size = get_u32(&raw, 4) as u64;
+        // `i_dir_acl` is the top half of a regular file's size.
+        if mode & mode::S_IFMT == mode::S_IFREG {
+            size |= (get_u32(&raw, 108) as u64) << 32;
+        }
+        let mut block = [0; 15];
+        for (i, ptr) in block.iter_mut().enumerate() {
+            *ptr = get_u32(&raw, 40 + i * 4);
+        }
+        Ok(Ext2Inode { fs: fs.clone()
+                     , ino: ino
+                     , stat: InodeStat { size: size
+                                       , blocks: get_u32(&raw, 28) as u64
+                                       , mode: mode
+                                       , uid: get_u16(&raw, 2) as u32
+                                       , gid: get_u16(&raw, 24) as u32
+                                       , atime: secs_to_ns(get_u32(&raw, 8))
+                                       , mtime: secs_to_ns(get_u32(&raw, 16))
+                                       }
+                     , block: block
+                     })
+    }
+
+    /// Returns the root directory of `fs`.
+    pub fn root(fs: &Arc<Ext2>) -> Result<Ext2Inode, IoError> {
+        Ext2::inode(fs, ROOT_INO)
+    }
+
+    /// Returns the size of a block, in bytes.
+    #[inline] pub fn block_size(&self) -> usize { self.block_size }
+
+    #[inline]
+    fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), IoError> {
+        read_bytes(&*self.dev, offset, buf)
+    }
+
+    /// Returns entry `index` of the block of pointers `block`.
+    fn indirect(&self, block: u32, index: u64) -> Result<u32, IoError> {
+        // a hole: everything under it is a hole, too.
+        if block == 0 { return Ok(0) }
+        let mut ptr = [0u8; 4];
+        self.read_bytes( block as u64 * self.block_size as u64 + index * 4
+                       , &mut ptr)?;
+        Ok(get_u32(&ptr, 0))
+    }
+}
+
+/// Read `buf.len()` bytes from `dev`, starting at byte `offset`.
+#[inline]
+fn read_bytes(dev: &BlockDevice, offset: u64, buf: &mut [u8])
+             -> Result<(), IoError> {
+    block::read_bytes(dev, offset, buf).map_err(|_| IoError::Device)
+}
+
+/// ext2 keeps times in seconds since the Unix epoch.
+#[inline]
+fn secs_to_ns(secs: u32) -> u64 { secs as u64 * 1_000_000_000 }
+
+/// A file or directory in an ext2 file system.
+pub struct Ext2Inode { fs: Arc<Ext2>
+                     , ino: u32
+                     , stat: InodeStat
+                     , /// `i_block`: the direct, singly-, doubly-, and
+                       /// triply-indirect block pointers
+                       block: [u32; 15]
+                     }
+
+impl Ext2Inode {
+    /// Returns this inode's number.
+    #[inline] pub fn ino(&self) -> u32 { self.ino }
+
+    #[inline] fn is_dir(&self) -> bool { self.stat.is_dir() }
+
+    /// Returns the block holding block `index` of the file's data, or 0
+    /// if it's a hole.
+    fn block_of(&self, index: u64) -> Result<u32, IoError> {
+        let per_block = (self.fs.block_size / 4) as u64;
+        if index < DIRECT_BLOCKS { return Ok(self.block[index as usize]) }
+        let index = index - DIRECT_BLOCKS;
+        if index < per_block {
+            return self.fs.indirect(self.block[IND_BLOCK], index)
+        }
+        let index = index - per_block;
+        if index < per_block * per_block {
+            let ind = self.fs.indirect( self.block[DIND_BLOCK]
+                                      , index / per_block)?;
+            return self.fs.indirect(ind, index % per_block)
+        }
+        Err(IoError::Unsupported)
+    }
+
+    /// Read the file's data, whatever kind of file it is.
+    fn read_data(&self, offset: u64, buf: &mut [u8])
+                -> Result<usize, IoError> {
+        if offset >= self.stat.size { return Ok(0) }
+        let len = cmp::min(buf.len() as u64, self.stat.size - offset) as usize;
+        let block_size = self.fs.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = (pos % block_size) as usize;
+            let n = cmp::min(block_size as usize - within, len - done);
+            let chunk = &mut buf[done .. done + n];
+            match self.block_of(pos / block_size)? {
+                0 => for byte in chunk.iter_mut() { *byte = 0 }
+              , block => self.fs.read_bytes( block as u64 * block_size
+                                             + within as u64
+                                           , chunk)?
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// Call `f` with the inode number, name, and file type of each entry
+    /// in this directory, until it returns `Some`.
+    fn find_entry<F, R>(&self, mut f: F) -> Result<Option<R>, IoError>
+    where F: FnMut(u32, &[u8], u8) -> Option<R> {
+        if !self.is_dir() { return Err(IoError::NotADirectory) }
+        let mut block = vec![0u8; self.fs.block_size];
+        let mut offset = 0;
+        while offset < self.stat.size {
+            let len = self.read_data(offset, &mut block)?;
+            // entries never cross blocks.
+            let mut at = 0;
+            while at + DIR_ENTRY_LEN <= len {
+                let ino = get_u32(&block, at);
+                let rec_len = get_u16(&block, at + 4) as usize;
+                let name_len = block[at + 6] as usize;
+                if rec_len < DIR_ENTRY_LEN || at + rec_len > len
+                    || DIR_ENTRY_LEN + name_len > rec_len {
+                    warn!("ext2: bad entry in directory {}", self.ino);
+                    return Err(IoError::Device)
+                }
+                let name = &block[at + DIR_ENTRY_LEN
+                                  .. at + DIR_ENTRY_LEN + name_len];
+                // an inode number of zero marks an unused entry.
+                if ino != 0 {
+                    if let Some(result) = f(ino, name, block[at + 7]) {
+                        return Ok(Some(result))
+                    }
+                }
+                at += rec_len;
+            }
+            offset += block.len() as u64;
+        }
+        Ok(None)
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.is_dir() { return Err(IoError::IsADirectory) }
+        self.read_data(offset, buf)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, IoError> {
+        Err(IoError::ReadOnly)
+    }
+
+    /// Times are in nanoseconds since the Unix epoch, rather than since
+    /// boot.
+    #[inline] fn stat(&self) -> InodeStat { self.stat }
+
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::ReadOnly)
+    }
+
+    /// `.` and `..` are skipped. The entries' types come from the
+    /// directory if the file system records them there, and from their
+    /// inodes if not.
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError> {
+        let mut index = 0;
+        let found = self.find_entry(|ino, name, file_type| {
+            if name == b"." || name == b".." { return None }
+            let name = FileName::new(name)?;
+            if index != offset {
+                index += 1;
+                return None
+            }
+            Some((ino, name, file_type))
+        })?;
+        let (ino, name, file_type) = match found {
+            Some(entry) => entry
+          , None => return Ok(None)
+        };
+        let kind = match file_type {
+            1 => mode::S_IFREG
+          , 2 => mode::S_IFDIR
+          , 3 => mode::S_IFCHR
+          , 4 => mode::S_IFBLK
+          , 5 => mode::S_IFIFO
+          , 7 => mode::S_IFLNK
+          , _ => Ext2::inode(&self.fs, ino)?.stat.mode & mode::S_IFMT
+        };
+        Ok(Some(DirEntry { name: name, kind: kind }))
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        let ino = self.find_entry(|ino, entry, _| {
+            if entry == name { Some(ino) } else { None }
+        })?;
+        let ino = ino.ok_or(IoError::NotFound)?;
+        Ok(Arc::new(Ext2::inode(&self.fs, ino)?))
+    }
+}
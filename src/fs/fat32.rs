@@ -0,0 +1,495 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A read-only FAT32 file system, for reading the EFI System Partition.
+//!
+//! ```text
+//! | reserved sectors (BPB) | FAT | FAT | data: cluster 2, cluster 3, ...
+//! ```
+//!
+//! The BIOS Parameter Block in the first sector says how big each region
+//! is. A file's data is a chain of clusters: its directory entry holds the
+//! first, and the file allocation table entry for each cluster holds the
+//! next. Directories, including the root, are files of 32-byte entries.
+//!
+//! Long file names are stored in extra entries before a file's 8.3 entry,
+//! and are used when their checksum matches it. Names are compared without
+//! regard to ASCII case, as Windows does.
+use alloc::arc::Arc;
+use core::cmp;
+
+use dev::block::{self, BlockDevice};
+use super::{DirEntry, FileName, Inode, InodeStat, IoError, mode, NAME_MAX};
+
+/// The boot sector signature, at the end of the first sector.
+const BOOT_SIGNATURE: u16 = 0xaa55;
+/// FAT entries are 28 bits; the top four are reserved.
+const ENTRY_MASK: u32 = 0x0fff_ffff;
+/// FAT entries at or above this end a chain.
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+/// The first cluster in the data region.
+const FIRST_CLUSTER: u32 = 2;
+
+/// The length of a directory entry.
+const DIR_ENTRY_LEN: usize = 32;
+/// The first byte of a deleted entry's name.
+const DELETED: u8 = 0xe5;
+/// A first name byte of this stands for `DELETED`, in a name that really
+/// does start with it.
+const KANJI_E5: u8 = 0x05;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// The attributes of a long file name entry.
+const ATTR_LFN: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM
+                   | ATTR_VOLUME_ID;
+
+/// Set on the first long name entry, which has the end of the name.
+const LFN_LAST: u8 = 0x40;
+/// The characters each long name entry holds.
+const LFN_CHARS: usize = 13;
+/// Where in a long name entry each of its characters is.
+const LFN_OFFSETS: [usize; LFN_CHARS]
+    = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// The longest long file name, in UCS-2 characters.
+const LFN_MAX: usize = 255;
+
+/// Windows NT's flags in the 8.3 entry: the base name is in lower case.
+const NT_LOWER_BASE: u8 = 0x08;
+/// Windows NT's flags in the 8.3 entry: the extension is in lower case.
+const NT_LOWER_EXT: u8 = 0x10;
+
+/// Returns the little-endian `u16` at `at` in `buf`.
+#[inline]
+fn get_u16(buf: &[u8], at: usize) -> u16 {
+    buf[at] as u16 | (buf[at + 1] as u16) << 8
+}
+
+/// Returns the little-endian `u32` at `at` in `buf`.
+#[inline]
+fn get_u32(buf: &[u8], at: usize) -> u32 {
+    get_u16(buf, at) as u32 | (get_u16(buf, at + 2) as u32) << 16
+}
+
+/// Returns `b` in lower case, if it's an ASCII letter.
+#[inline]
+fn to_lower(b: u8) -> u8 {
+    if b >= b'A' && b <= b'Z' { b + (b'a' - b'A') } else { b }
+}
+
+/// Returns true if `a` and `b` are the same, ignoring ASCII case.
+fn eq_ignore_case(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(&x, &y)| to_lower(x) == to_lower(y))
+}
+
+/// Returns the checksum of the 11-byte 8.3 name `short_name`, which each
+/// of its long name entries holds.
+pub fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &b| {
+        (sum >> 1 | sum << 7).wrapping_add(b)
+    })
+}
+
+/// A mounted FAT32 file system.
+pub struct Fat32 { dev: Arc<BlockDevice>
+                 , bytes_per_sector: usize
+                 , sectors_per_cluster: usize
+                 , /// The first sector of the first FAT
+                   fat_start: u64
+                 , /// The first sector of cluster 2
+                   data_start: u64
+                 , /// The number of clusters in the data region
+                   cluster_count: u32
+                 , root_cluster: u32
+                 }
+
+impl Fat32 {
+    /// Mount the FAT32 file system on `dev`.
+    ///
+    /// Fails with `IoError::InvalidArgument` if `dev` doesn't hold a FAT32
+    /// file system. FAT12 and FAT16 aren't supported.
+    pub fn mount(dev: Arc<BlockDevice>) -> Result<Arc<Fat32>, IoError> {
+        let mut bpb = [0u8; 512];
+        read_bytes(&*dev, 0, &mut bpb)?;
+        if get_u16(&bpb, 510) != BOOT_SIGNATURE {
+            return Err(IoError::InvalidArgument)
+        }
+        let bytes_per_sector = get_u16(&bpb, 11) as usize;
+        let sectors_per_cluster = bpb[13] as usize;
+        let reserved_sectors = get_u16(&bpb, 14) as u64;
+        let fats = bpb[16] as u64;
+        let root_entries = get_u16(&bpb, 17);
+        let total_sectors = match get_u16(&bpb, 19) {
+            0 => get_u32(&bpb, 32) as u64
+          , sectors => sectors as u64
+        };
+        let fat_size = get_u32(&bpb, 36) as u64;
+        if !bytes_per_sector.is_power_of_two() || bytes_per_sector < 512
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0 || fats == 0 {
+            return Err(IoError::InvalidArgument)
+        }
+        // FAT32 has no fixed root directory, and keeps the size of the FAT
+        // in the extended BPB rather than the 16-bit field.
+        if root_entries != 0 || get_u16(&bpb, 22) != 0 || fat_size == 0 {
+            return Err(IoError::InvalidArgument)
+        }
+        let data_start = reserved_sectors + fats * fat_size;
+        if total_sectors <= data_start { return Err(IoError::InvalidArgument) }
+        let cluster_count =
+            ((total_sectors - data_start) / sectors_per_cluster as u64) as u32;
+        let root_cluster = get_u32(&bpb, 44);
+        Ok(Arc::new(Fat32 { dev: dev
+                          , bytes_per_sector: bytes_per_sector
+                          , sectors_per_cluster: sectors_per_cluster
+                          , fat_start: reserved_sectors
+                          , data_start: data_start
+                          , cluster_count: cluster_count
+                          , root_cluster: root_cluster
+                          }))
+    }
+
+    /// Returns the root directory of `fs`.
+    pub fn root(fs: &Arc<Fat32>) -> Fat32File {
+        Fat32File { fs: fs.clone()
+                  , first_cluster: fs.root_cluster
+                  , size: 0
+                  , attr: ATTR_DIRECTORY
+                  }
+    }
+
+    /// Returns the size of a cluster, in bytes.
+    #[inline] pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    /// Returns an iterator over the chain of clusters that starts with
+    /// `first_cluster`.
+    pub fn follow_chain(&self, first_cluster: u32) -> Chain {
+        Chain { fs: self
+              , next: first_cluster
+              , remaining: self.cluster_count
+              }
+    }
+
+    #[inline] fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER
+            && cluster - FIRST_CLUSTER < self.cluster_count
+    }
+
+    /// Returns the FAT entry for `cluster`.
+    fn fat_entry(&self, cluster: u32) -> Result<u32, IoError> {
+        let mut entry = [0u8; 4];
+        let offset = self.fat_start * self.bytes_per_sector as u64
+                   + cluster as u64 * 4;
+        read_bytes(&*self.dev, offset, &mut entry)?;
+        Ok(get_u32(&entry, 0) & ENTRY_MASK)
+    }
+
+    /// Read `buf.len()` bytes from `cluster`, starting `offset` bytes in.
+    fn read_cluster(&self, cluster: u32, offset: usize, buf: &mut [u8])
+                   -> Result<(), IoError> {
+        let sector = self.data_start + (cluster - FIRST_CLUSTER) as u64
+                                       * self.sectors_per_cluster as u64;
+        read_bytes( &*self.dev
+                  , sector * self.bytes_per_sector as u64 + offset as u64
+                  , buf)
+    }
+}
+
+/// Read `buf.len()` bytes from `dev`, starting at byte `offset`.
+#[inline]
+fn read_bytes(dev: &BlockDevice, offset: u64, buf: &mut [u8])
+             -> Result<(), IoError> {
+    block::read_bytes(dev, offset, buf).map_err(|_| IoError::Device)
+}
+
+/// The clusters of a file, in order. Returned by
+/// [`Fat32::follow_chain`](struct.Fat32.html#method.follow_chain).
+///
+/// A chain that runs into a free or bad cluster, or that's longer than
+/// the file system (so must loop), ends with `IoError::Device`.
+pub struct Chain<'a> { fs: &'a Fat32
+                     , /// The next cluster, or 0 at the end of the chain
+                       next: u32
+                     , /// How many more clusters the chain can have
+                       remaining: u32
+                     }
+
+impl<'a> Iterator for Chain<'a> {
+    type Item = Result<u32, IoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let cluster = self.next;
+        // a zero-length file has no clusters.
+        if cluster == 0 || cluster >= END_OF_CHAIN { return None }
+        if !self.fs.is_cluster(cluster) || self.remaining == 0 {
+            self.next = 0;
+            return Some(Err(IoError::Device))
+        }
+        self.remaining -= 1;
+        self.next = match self.fs.fat_entry(cluster) {
+            Ok(next) => next
+          , Err(why) => { self.next = 0; return Some(Err(why)) }
+        };
+        Some(Ok(cluster))
+    }
+}
+
+/// A directory entry, with its long name if it has one.
+struct Entry { name: FileName
+             , attr: u8
+             , first_cluster: u32
+             , size: u32
+             }
+
+/// Collects the long name entries before an 8.3 entry.
+struct LongName { chars: [u16; LFN_MAX]
+                , len: usize
+                , checksum: u8
+                , /// The sequence number of the next entry we expect, or 0
+                  /// if we don't have a complete name
+                  next: u8
+                }
+
+impl LongName {
+    fn new() -> Self {
+        LongName { chars: [0; LFN_MAX], len: 0, checksum: 0, next: 0 }
+    }
+
+    /// Forget the entries so far.
+    #[inline] fn reset(&mut self) {
+        self.len = 0;
+        self.next = 0;
+    }
+
+    /// Add the long name entry `entry`. They come last part first.
+    fn push(&mut self, entry: &[u8]) {
+        let seq = entry[0] & !LFN_LAST;
+        let last = entry[0] & LFN_LAST != 0;
+        if last {
+            self.len = 0;
+            self.checksum = entry[13];
+        } else if self.len == 0 || seq != self.next
+               || entry[13] != self.checksum {
+            // out of order, or not part of the name we have.
+            return self.reset()
+        }
+        if seq == 0 || (seq as usize - 1) * LFN_CHARS >= LFN_MAX {
+            return self.reset()
+        }
+        let start = (seq as usize - 1) * LFN_CHARS;
+        for (i, &at) in LFN_OFFSETS.iter().enumerate() {
+            let c = get_u16(entry, at);
+            // the name ends with a NUL, and is padded with 0xffff.
+            if c == 0 || c == 0xffff || start + i >= LFN_MAX { break }
+            self.chars[start + i] = c;
+            if last { self.len = start + i + 1 }
+        }
+        self.next = seq - 1;
+    }
+
+    /// Returns the name, as UTF-8, if it's complete and belongs to the 8.3
+    /// entry `short_name`.
+    fn take(&mut self, short_name: &[u8]) -> Option<FileName> {
+        let complete = self.next == 0 && self.len > 0
+                    && self.checksum == lfn_checksum(short_name);
+        let len = self.len;
+        self.reset();
+        if !complete { return None }
+        let mut utf8 = [0u8; NAME_MAX];
+        let mut n = 0;
+        for &c in &self.chars[..len] {
+            // UCS-2, so each character is one code point. Surrogates are
+            // encoded as they come, which isn't valid UTF-8, but does keep
+            // names distinct.
+            let c = c as u32;
+            let (bytes, count) =
+                if c < 0x80 { ([c as u8, 0, 0], 1) }
+                else if c < 0x800 {
+                    ([0xc0 | (c >> 6) as u8, 0x80 | (c & 0x3f) as u8, 0], 2)
+                } else {
+                    ( [ 0xe0 | (c >> 12) as u8
+                      , 0x80 | (c >> 6 & 0x3f) as u8
+                      , 0x80 | (c & 0x3f) as u8 ]
+                    , 3)
+                };
+            if n + count > NAME_MAX { return None }
+            utf8[n .. n + count].copy_from_slice(&bytes[..count]);
+            n += count;
+        }
+        FileName::new(&utf8[..n])
+    }
+}
+
+/// Returns the name in the 8.3 entry `entry`, as `BASE.EXT`.
+fn short_name(entry: &[u8]) -> Option<FileName> {
+    let lower = entry[12];
+    let mut name = [0u8; 12];
+    let mut n = 0;
+    {
+        let mut push = |part: &[u8], lowercase: bool| {
+            let len = part.iter().rposition(|&b| b != b' ')
+                          .map_or(0, |i| i + 1);
+            for &b in &part[..len] {
+                name[n] = if lowercase { to_lower(b) } else { b };
+                n += 1;
+            }
+        };
+        push(&entry[0..8], lower & NT_LOWER_BASE != 0);
+        if entry[8..11].iter().any(|&b| b != b' ') {
+            name[n] = b'.';
+            n += 1;
+            push(&entry[8..11], lower & NT_LOWER_EXT != 0);
+        }
+    }
+    if name[0] == KANJI_E5 { name[0] = DELETED }
+    FileName::new(&name[..n])
+}
+
+/// A file or directory in a FAT32 file system.
+pub struct Fat32File { fs: Arc<Fat32>
+                     , first_cluster: u32
+                     , /// The file's length; 0 for directories, which
+                       /// are as long as their chain of clusters
+                       size: u32
+                     , attr: u8
+                     }
+
+impl Fat32File {
+    #[inline] fn is_dir(&self) -> bool { self.attr & ATTR_DIRECTORY != 0 }
+
+    /// Read the file's data. Directories are read to the end of their
+    /// last cluster.
+    fn read_data(&self, offset: u64, buf: &mut [u8])
+                -> Result<usize, IoError> {
+        let end = if self.is_dir() { u64::max_value() }
+                  else { self.size as u64 };
+        if offset >= end { return Ok(0) }
+        let len = cmp::min(buf.len() as u64, end - offset) as usize;
+        let cluster_size = self.fs.cluster_size() as u64;
+        let skip = (offset / cluster_size) as usize;
+        let mut within = (offset % cluster_size) as usize;
+        let mut done = 0;
+        for cluster in self.fs.follow_chain(self.first_cluster).skip(skip) {
+            if done == len { break }
+            let n = cmp::min(cluster_size as usize - within, len - done);
+            self.fs.read_cluster(cluster?, within, &mut buf[done .. done + n])?;
+            done += n;
+            within = 0;
+        }
+        Ok(done)
+    }
+
+    /// Call `f` with each entry in this directory, until it returns
+    /// `Some`. Volume labels, `.`, and `..` are skipped.
+    fn find_entry<F, R>(&self, mut f: F) -> Result<Option<R>, IoError>
+    where F: FnMut(&Entry) -> Option<R> {
+        if !self.is_dir() { return Err(IoError::NotADirectory) }
+        let mut long_name = LongName::new();
+        let mut raw = [0u8; DIR_ENTRY_LEN];
+        let mut offset = 0;
+        while self.read_data(offset, &mut raw)? == DIR_ENTRY_LEN {
+            offset += DIR_ENTRY_LEN as u64;
+            match raw[0] {
+                // no more entries.
+                0 => break
+              , DELETED => { long_name.reset(); continue }
+              , _ => {}
+            }
+            let attr = raw[11];
+            if attr & ATTR_LFN == ATTR_LFN {
+                long_name.push(&raw);
+                continue
+            }
+            let name = long_name.take(&raw[..11]);
+            if attr & ATTR_VOLUME_ID != 0 { continue }
+            let name = match name.or_else(|| short_name(&raw)) {
+                Some(name) => name
+              , None => continue
+            };
+            if name.as_bytes() == b"." || name.as_bytes() == b".." {
+                continue
+            }
+            let first_cluster = (get_u16(&raw, 20) as u32) << 16
+                              | get_u16(&raw, 26) as u32;
+            let entry = Entry { name: name
+                              , attr: attr
+                              , first_cluster: first_cluster
+                              , size: get_u32(&raw, 28)
+                              };
+            if let Some(result) = f(&entry) { return Ok(Some(result)) }
+        }
+        Ok(None)
+    }
+}
+
+impl Inode for Fat32File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.is_dir() { return Err(IoError::IsADirectory) }
+        self.read_data(offset, buf)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, IoError> {
+        Err(IoError::ReadOnly)
+    }
+
+    /// FAT has no owners or permissions, and keeps times in local time, so
+    /// everything is owned by root, read-only, and has no times.
+    fn stat(&self) -> InodeStat {
+        let cluster_size = self.fs.cluster_size() as u64;
+        let size = self.size as u64;
+        let kind = if self.is_dir() { mode::S_IFDIR | 0o555 }
+                   else { mode::S_IFREG | 0o444 };
+        InodeStat { size: size
+                  , blocks: (size + cluster_size - 1) / cluster_size
+                          * (cluster_size / 512)
+                  , mode: kind
+                  , ..Default::default()
+                  }
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::ReadOnly)
+    }
+
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError> {
+        let mut index = 0;
+        self.find_entry(|entry| {
+            if index != offset {
+                index += 1;
+                return None
+            }
+            let kind = if entry.attr & ATTR_DIRECTORY != 0 { mode::S_IFDIR }
+                       else { mode::S_IFREG };
+            Some(DirEntry { name: entry.name, kind: kind })
+        })
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        let found = self.find_entry(|entry| {
+            if eq_ignore_case(entry.name.as_bytes(), name) {
+                Some(Fat32File { fs: self.fs.clone()
+                               , first_cluster: entry.first_cluster
+                               , size: entry.size
+                               , attr: entry.attr
+                               })
+            } else {
+                None
+            }
+        })?;
+        match found {
+            Some(file) => Ok(Arc::new(file))
+          , None => Err(IoError::NotFound)
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! File descriptor tables.
+//!
+//! Each task has an [`FdTable`](struct.FdTable.html) mapping the small
+//! integers user code passes to system calls to open files.
+use super::Inode;
+
+use alloc::arc::Arc;
+use core::fmt;
+
+/// The maximum number of files a task may have open at once.
+//  TODO: this is limited to 32 because the standard library only implements
+//        `Clone` and `Default` for arrays of up to 32 elements. Make the
+//        table growable once we care.
+pub const OPEN_FILES_MAX: usize = 32;
+
+bitflags! {
+    /// Flags passed to `open(2)`. These have the same values as on Linux.
+    pub flags OpenFlags: u32 {
+        const O_RDONLY =    0o0,
+        const O_WRONLY =    0o1,
+        const O_RDWR =      0o2,
+        const O_CREAT =     0o100,
+        const O_EXCL =      0o200,
+        const O_TRUNC =     0o1000,
+        const O_APPEND =    0o2000,
+        const O_NONBLOCK =  0o4000,
+        const O_DIRECTORY = 0o200000,
+        const O_CLOEXEC =   0o2000000
+    }
+}
+
+impl OpenFlags {
+    /// Returns true if these flags permit reading.
+    #[inline] pub fn is_readable(&self) -> bool {
+        !self.contains(O_WRONLY)
+    }
+
+    /// Returns true if these flags permit writing.
+    #[inline] pub fn is_writable(&self) -> bool {
+        self.intersects(O_WRONLY | O_RDWR)
+    }
+}
+
+/// A file descriptor.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Fd(pub u32);
+
+impl fmt::Display for Fd {
+    #[inline] fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Errors returned by [`FdTable`](struct.FdTable.html) operations.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FdError {
+    /// The file descriptor doesn't refer to an open file.
+    BadFd
+  , /// The task already has `OPEN_FILES_MAX` files open.
+    TooManyFiles
+}
+
+/// An open file.
+#[derive(Clone)]
+pub struct FileDescription { /// The file this description refers to
+                             pub inode: Arc<Inode>
+                           , /// The current offset for `read` and `write`
+                             pub position: u64
+                           , /// The flags the file was opened with
+                             pub flags: OpenFlags
+                           }
+
+/// A task's table of open files.
+///
+/// Cloning an `FdTable` (as `fork` does) shares the underlying inodes
+/// between the two tables, but each gets its own file positions.
+#[derive(Clone, Default)]
+pub struct FdTable {
+    files: [Option<FileDescription>; OPEN_FILES_MAX]
+}
+
+impl FdTable {
+    /// Returns a new, empty `FdTable`.
+    #[inline] pub fn new() -> Self { Self::default() }
+
+    /// Install `desc` in the lowest free slot at or above `min`.
+    fn install(&mut self, min: usize, desc: FileDescription)
+              -> Result<Fd, FdError> {
+        let idx = self.files.iter()
+                      .skip(min)
+                      .position(Option::is_none)
+                      .ok_or(FdError::TooManyFiles)?
+                + min;
+        self.files[idx] = Some(desc);
+        Ok(Fd(idx as u32))
+    }
+
+    /// Open `inode` using the lowest available file descriptor.
+    pub fn open(&mut self, inode: Arc<Inode>, flags: OpenFlags)
+               -> Result<Fd, FdError> {
+        self.install(0, FileDescription { inode: inode
+                                        , position: 0
+                                        , flags: flags
+                                        })
+    }
+
+    /// Close `fd`, dropping its reference to the underlying inode.
+    pub fn close(&mut self, fd: Fd) -> Result<(), FdError> {
+        self.files.get_mut(fd.0 as usize)
+            .and_then(Option::take)
+            .map(|_| ())
+            .ok_or(FdError::BadFd)
+    }
+
+    /// Returns the open file referred to by `fd`, if there is one.
+    #[inline]
+    pub fn get(&self, fd: Fd) -> Option<&FileDescription> {
+        self.files.get(fd.0 as usize)
+            .and_then(Option::as_ref)
+    }
+
+    /// Returns the open file referred to by `fd` mutably, if there is one.
+    #[inline]
+    pub fn get_mut(&mut self, fd: Fd) -> Option<&mut FileDescription> {
+        self.files.get_mut(fd.0 as usize)
+            .and_then(Option::as_mut)
+    }
+
+    /// Duplicate `fd` into the lowest available file descriptor.
+    pub fn dup(&mut self, fd: Fd) -> Result<Fd, FdError> {
+        let desc = self.get(fd).cloned().ok_or(FdError::BadFd)?;
+        self.install(0, desc)
+    }
+
+    /// Close every file opened with `O_CLOEXEC`.
+    pub fn close_on_exec(&mut self) {
+        for slot in self.files.iter_mut() {
+            let cloexec = slot.as_ref()
+                              .map(|desc| desc.flags.contains(O_CLOEXEC))
+                              .unwrap_or(false);
+            if cloexec { *slot = None; }
+        }
+    }
+}
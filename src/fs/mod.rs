@@ -0,0 +1,262 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The virtual file system.
+//!
+//! Every file system the kernel knows about exposes its files and
+//! directories as [`Inode`](trait.Inode.html)s, and the rest of the kernel
+//! only ever deals with them through an `Arc<Inode>`. This means that code
+//! like the system call layer doesn't need to care whether a file lives in
+//! memory, on a disk, or is synthesized on the fly.
+use core::{cmp, fmt};
+use alloc::arc::Arc;
+use spin::Once;
+
+pub mod tmpfs;
+pub mod cpio;
+pub mod devfs;
+pub mod ext2;
+pub mod fat32;
+pub mod fd;
+pub mod p9;
+pub mod pipe;
+pub mod procfs;
+
+use self::devfs::Devfs;
+use self::procfs::Procfs;
+use self::tmpfs::{Tmpfs, TmpfsDir};
+
+/// The maximum length of a single path component, in bytes.
+pub const NAME_MAX: usize = 255;
+/// The maximum length of a path, in bytes, including the terminating NUL.
+pub const PATH_MAX: usize = 4096;
+
+/// The root of the file system tree.
+static ROOT: Once<Arc<TmpfsDir>> = Once::new();
+
+/// Mount a tmpfs as the root file system, with a procfs at `/proc` and a
+/// devfs at `/dev`.
+///
+/// This must be called after the heap has been initialized.
+pub fn init_root() -> Result<(), IoError> {
+    ROOT.call_once(Tmpfs::mount);
+    root_dir().link(b"proc", Procfs::mount())?;
+    root_dir().link(b"dev", Devfs::mount())
+}
+
+/// Returns the root directory of the file system tree.
+///
+/// # Panics
+/// + If the root file system has not yet been mounted.
+#[inline]
+pub fn root() -> Arc<Inode> {
+    root_dir().clone()
+}
+
+/// Returns the root tmpfs directory, so that other file systems can be
+/// attached to it.
+#[inline]
+pub fn root_dir() -> &'static Arc<TmpfsDir> {
+    ROOT.try().expect("root file system not mounted!")
+}
+
+/// Look up the inode at `path`.
+///
+/// There's no working directory yet, so relative paths are resolved from
+/// the root, too.
+pub fn lookup(path: &[u8]) -> Result<Arc<Inode>, IoError> {
+    let mut inode = root();
+    for name in path.split(|&b| b == b'/') {
+        if name.is_empty() || name == b"." { continue }
+        inode = inode.lookup(name)?;
+    }
+    Ok(inode)
+}
+
+/// Errors returned by file system operations.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IoError {
+    /// No file or directory with the requested name exists.
+    NotFound
+  , /// A directory operation was attempted on something that isn't one.
+    NotADirectory
+  , /// A regular file operation was attempted on a directory.
+    IsADirectory
+  , /// A file with the requested name already exists.
+    AlreadyExists
+  , /// An argument (such as an offset or a name) was invalid.
+    InvalidArgument
+  , /// The file system is out of space.
+    NoSpace
+  , /// This operation is not supported by this kind of inode.
+    Unsupported
+  , /// The underlying device reported an error.
+    Device
+  , /// The other end of a pipe has been closed.
+    BrokenPipe
+  , /// The file system can't be written to.
+    ReadOnly
+  , /// The caller isn't allowed to do this to the file.
+    PermissionDenied
+  , /// The file would grow past the largest size the file system allows.
+    FileTooBig
+}
+
+/// Metadata about an inode, as returned by [`Inode::stat`].
+///
+/// [`Inode::stat`]: trait.Inode.html#tymethod.stat
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct InodeStat { /// Size of the file, in bytes
+                       pub size: u64
+                     , /// Number of 512-byte blocks allocated to the file
+                       pub blocks: u64
+                     , /// File type and permission bits (see [`mode`])
+                       ///
+                       /// [`mode`]: mode/index.html
+                       pub mode: u32
+                     , pub uid: u32
+                     , pub gid: u32
+                     , /// Last access time, in nanoseconds since boot
+                       pub atime: u64
+                     , /// Last modification time, in nanoseconds since boot
+                       pub mtime: u64
+                     }
+
+impl InodeStat {
+    /// Returns true if this inode is a directory.
+    #[inline] pub fn is_dir(&self) -> bool {
+        self.mode & mode::S_IFMT == mode::S_IFDIR
+    }
+
+    /// Returns true if this inode is a regular file.
+    #[inline] pub fn is_file(&self) -> bool {
+        self.mode & mode::S_IFMT == mode::S_IFREG
+    }
+}
+
+pub mod mode {
+    //! Values for [`InodeStat::mode`]. These are the same as Linux's.
+    //!
+    //! [`InodeStat::mode`]: ../struct.InodeStat.html#structfield.mode
+
+    /// Mask for the file type bits
+    pub const S_IFMT: u32   = 0o170000;
+    /// Named pipe (FIFO)
+    pub const S_IFIFO: u32  = 0o010000;
+    /// Character device
+    pub const S_IFCHR: u32  = 0o020000;
+    /// Directory
+    pub const S_IFDIR: u32  = 0o040000;
+    /// Block device
+    pub const S_IFBLK: u32  = 0o060000;
+    /// Regular file
+    pub const S_IFREG: u32  = 0o100000;
+    /// Symbolic link
+    pub const S_IFLNK: u32  = 0o120000;
+}
+
+/// A single path component.
+///
+/// This is stored inline, so it can be used without touching the heap.
+#[derive(Copy, Clone)]
+pub struct FileName { len: u8
+                    , bytes: [u8; NAME_MAX]
+                    }
+
+impl FileName {
+    /// Returns a new `FileName`, or `None` if `name` is empty, longer
+    /// than `NAME_MAX`, or contains a `/` or a NUL byte.
+    pub fn new(name: &[u8]) -> Option<Self> {
+        if name.is_empty() || name.len() > NAME_MAX
+            || name.iter().any(|&b| b == b'/' || b == 0) {
+            return None
+        }
+        let mut bytes = [0; NAME_MAX];
+        bytes[..name.len()].copy_from_slice(name);
+        Some(FileName { len: name.len() as u8, bytes: bytes })
+    }
+
+    #[inline] pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl PartialEq for FileName {
+    #[inline] fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+impl Eq for FileName { }
+
+impl PartialOrd for FileName {
+    #[inline] fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FileName {
+    #[inline] fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl fmt::Debug for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"")?;
+        for &b in self.as_bytes() {
+            if b >= 0x20 && b < 0x7f {
+                write!(f, "{}", b as char)?;
+            } else {
+                write!(f, "\\x{:02x}", b)?;
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+/// A single entry returned by [`Inode::readdir`].
+///
+/// [`Inode::readdir`]: trait.Inode.html#tymethod.readdir
+#[derive(Copy, Clone, Debug)]
+pub struct DirEntry { /// The entry's name within its directory
+                      pub name: FileName
+                    , /// The entry's type (the `S_IFMT` bits of its mode)
+                      pub kind: u32
+                    }
+
+/// A file, directory, or other object in the file system.
+///
+/// All methods take `&self`, so inodes are responsible for their own
+/// locking; this lets them be shared between tasks as an `Arc<Inode>`.
+pub trait Inode: Send + Sync {
+    /// Read bytes starting at `offset` into `buf`, returning the number of
+    /// bytes read. Reading at or past the end of the file returns 0.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, IoError>;
+
+    /// Write `buf` starting at `offset`, returning the number of bytes
+    /// written.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, IoError>;
+
+    /// Returns this inode's metadata.
+    fn stat(&self) -> InodeStat;
+
+    /// Set the size of the file to `size` bytes, discarding any data past
+    /// the end or extending it with zeroes.
+    fn truncate(&self, size: u64) -> Result<(), IoError>;
+
+    /// Returns the `offset`th entry in this directory, or `None` if there
+    /// are no more entries.
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError>;
+
+    /// Look up the entry called `name` in this directory.
+    ///
+    /// The default implementation is for inodes that aren't directories.
+    fn lookup(&self, _name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        Err(IoError::NotADirectory)
+    }
+}
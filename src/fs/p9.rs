@@ -0,0 +1,524 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A 9P2000.L client, for mounting directories shared by the host.
+//!
+//! Every message is a little-endian header (its size, its type, and a tag
+//! matching the reply to the request) followed by the type's fields. Files
+//! on the server are named by fids, numbers the client picks: `Tattach`
+//! gives fid 0 the share's root, `Twalk` gives a new fid a file in a
+//! directory, and `Tclunk` forgets one.
+//!
+//! Each [`P9Inode`] owns the fid it was walked to. 9P won't walk from a fid
+//! that's been opened, so reads and writes go through a second fid, which
+//! is cloned from the first and opened the first time it's needed.
+//!
+//! The virtio transport only has one request in flight, so every request
+//! has the same tag.
+//!
+//! [`P9Inode`]: struct.P9Inode.html
+use alloc::arc::Arc;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use dev::virtio::p9::{Virtio9p, MSIZE};
+use syscall::errno;
+use super::{root_dir, DirEntry, FileName, Inode, InodeStat, IoError, mode};
+
+/// The protocol version we speak.
+const VERSION: &'static [u8] = b"9P2000.L";
+
+/// Message types. Each reply's type is one more than its request's.
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+/// The tag `Tversion` must use.
+const NOTAG: u16 = 0xffff;
+/// The tag every other request uses.
+const TAG: u16 = 0;
+/// No fid, for `Tattach`'s authentication fid.
+const NOFID: u32 = 0xffff_ffff;
+/// The fid of the share's root.
+const ROOT_FID: u32 = 0;
+
+/// The size of a message header: `size[4] type[1] tag[2]`.
+const HEADER_LEN: usize = 7;
+
+/// `Tlopen` flags. These are the same as Linux's `open(2)` flags.
+const O_RDONLY: u32 = 0;
+const O_RDWR: u32 = 2;
+/// `Tgetattr`: ask for everything in a `struct stat`.
+const GETATTR_BASIC: u64 = 0x7ff;
+/// `Tsetattr`: set the size.
+const SETATTR_SIZE: u32 = 1 << 3;
+/// Qid type: a directory.
+const QTDIR: u8 = 0x80;
+
+/// The server's unique identity for a file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Qid { pub kind: u8
+               , pub version: u32
+               , pub path: u64
+               }
+
+/// Writes the fields of a request.
+struct Writer<'a> { buf: &'a mut [u8]
+                  , pos: usize
+                  }
+
+impl<'a> Writer<'a> {
+    /// Start a request of type `kind` in `buf`.
+    fn new(buf: &'a mut [u8], kind: u8) -> Self {
+        let tag = if kind == TVERSION { NOTAG } else { TAG };
+        // the size goes in when we know it.
+        let mut w = Writer { buf: buf, pos: 4 };
+        w.u8(kind);
+        w.u16(tag);
+        w
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos .. self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    fn u8(&mut self, value: u8) { self.bytes(&[value]) }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&[value as u8, (value >> 8) as u8])
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.u16(value as u16);
+        self.u16((value >> 16) as u16);
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.u32(value as u32);
+        self.u32((value >> 32) as u32);
+    }
+
+    /// Write a string: its length, as a `u16`, then its bytes.
+    fn string(&mut self, string: &[u8]) {
+        self.u16(string.len() as u16);
+        self.bytes(string);
+    }
+
+    /// Fill in the size, and return it.
+    fn finish(self) -> usize {
+        let len = self.pos;
+        for i in 0 .. 4 { self.buf[i] = (len >> (i * 8)) as u8 }
+        len
+    }
+}
+
+/// Reads the fields of a reply, or the entries of a `Rreaddir`.
+struct Reader<'a> { buf: &'a [u8]
+                  , pos: usize
+                  }
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self { Reader { buf: buf, pos: 0 } }
+
+    #[inline] fn is_empty(&self) -> bool { self.pos >= self.buf.len() }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos .. self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> { self.bytes(1).map(|b| b[0]) }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| b[0] as u16 | (b[1] as u16) << 8)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(self.u16()? as u32 | (self.u16()? as u32) << 16)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    fn qid(&mut self) -> Option<Qid> {
+        Some(Qid { kind: self.u8()?, version: self.u32()?, path: self.u64()? })
+    }
+}
+
+/// Returns the `IoError` for the Linux error number in a `Rlerror`.
+fn from_errno(ecode: u32) -> IoError {
+    match ecode as i64 {
+        errno::ENOENT => IoError::NotFound
+      , errno::ENOTDIR => IoError::NotADirectory
+      , errno::EISDIR => IoError::IsADirectory
+      , errno::EEXIST => IoError::AlreadyExists
+      , errno::EINVAL | errno::ENAMETOOLONG => IoError::InvalidArgument
+      , errno::ENOSPC => IoError::NoSpace
+      , errno::ENOSYS => IoError::Unsupported
+      , _ => IoError::Device
+    }
+}
+
+/// Check the header of `reply` to a request of type `kind`, and return a
+/// reader for the rest of it.
+fn reply(reply: &[u8], kind: u8) -> Result<Reader, IoError> {
+    let mut r = Reader::new(reply);
+    let size = r.u32().ok_or(IoError::Device)? as usize;
+    let rkind = r.u8().ok_or(IoError::Device)?;
+    r.u16().ok_or(IoError::Device)?;
+    if size < HEADER_LEN || size > reply.len() {
+        return Err(IoError::Device)
+    }
+    r.buf = &reply[..size];
+    if rkind == RLERROR {
+        return Err(from_errno(r.u32().ok_or(IoError::Device)?))
+    }
+    if rkind != kind + 1 { return Err(IoError::Device) }
+    Ok(r)
+}
+
+/// A session with a 9P server.
+struct Client { dev: Arc<Virtio9p>
+              , /// The largest message the server accepts
+                msize: usize
+              , /// The next fid to hand out
+                next_fid: AtomicUsize
+              }
+
+impl Client {
+    /// Agree on a protocol version and message size with the server on
+    /// `dev`.
+    fn connect(dev: Arc<Virtio9p>) -> Result<Self, IoError> {
+        let first_fid = ROOT_FID as usize + 1;
+        let mut client = Client { dev: dev
+                                , msize: MSIZE
+                                , next_fid: AtomicUsize::new(first_fid)
+                                };
+        let msize = client.call(TVERSION, |w| {
+            w.u32(MSIZE as u32);
+            w.string(VERSION);
+        }, |r| Some((r.u32()?, r.string()? == VERSION)))?;
+        match msize {
+            (msize, true) if msize as usize > HEADER_LEN + 24 => {
+                client.msize = cmp::min(msize as usize, MSIZE);
+                Ok(client)
+            }
+          , _ => Err(IoError::Unsupported)
+        }
+    }
+
+    /// Send a request of type `kind`, whose fields are written by `build`,
+    /// and parse the fields of the reply with `parse`.
+    fn call<B, P, R>(&self, kind: u8, build: B, parse: P) -> Result<R, IoError>
+    where B: FnOnce(&mut Writer)
+        , P: FnOnce(&mut Reader) -> Option<R> {
+        let msize = self.msize;
+        self.dev.rpc( |buf| {
+                          let mut w = Writer::new(&mut buf[..msize], kind);
+                          build(&mut w);
+                          w.finish()
+                      }
+                    , |buf| {
+                          let mut r = reply(buf, kind)?;
+                          parse(&mut r).ok_or(IoError::Device)
+                      })
+    }
+
+    /// Returns an unused fid.
+    fn fid(&self) -> u32 {
+        self.next_fid.fetch_add(1, Ordering::Relaxed) as u32
+    }
+
+    /// The most data a `Tread` can return, or a `Twrite` can carry.
+    #[inline] fn max_read(&self) -> usize { self.msize - HEADER_LEN - 4 }
+    #[inline] fn max_write(&self) -> usize { self.msize - HEADER_LEN - 16 }
+
+    /// Point `fid` at the root of the share.
+    fn attach(&self, fid: u32) -> Result<Qid, IoError> {
+        self.call(TATTACH, |w| {
+            w.u32(fid);
+            w.u32(NOFID);
+            w.string(b"");
+            w.string(b"");
+            w.u32(0);
+        }, |r| r.qid())
+    }
+
+    /// Point a new fid at `name` in the directory `fid`, or at the same
+    /// file as `fid` if `name` is `None`.
+    fn walk(&self, fid: u32, name: Option<&[u8]>)
+           -> Result<(u32, Qid), IoError> {
+        let newfid = self.fid();
+        let qid = self.call(TWALK, |w| {
+            w.u32(fid);
+            w.u32(newfid);
+            match name {
+                Some(name) => { w.u16(1); w.string(name) }
+              , None => w.u16(0)
+            }
+        }, |r| {
+            let nwqid = r.u16()?;
+            if nwqid == 0 { Some(None) } else { r.qid().map(Some) }
+        })?;
+        match (name, qid) {
+            (Some(_), Some(qid)) => Ok((newfid, qid))
+          , (None, _) => Ok((newfid, Qid { kind: 0, version: 0, path: 0 }))
+          , // the server didn't get as far as the name.
+            (Some(_), None) => Err(IoError::NotFound)
+        }
+    }
+
+    /// Open `fid` with the `open(2)` flags `flags`.
+    fn lopen(&self, fid: u32, flags: u32) -> Result<(), IoError> {
+        self.call(TLOPEN, |w| { w.u32(fid); w.u32(flags) }, |r| r.qid())
+            .map(|_| ())
+    }
+
+    /// Returns the attributes of `fid`.
+    fn getattr(&self, fid: u32) -> Result<InodeStat, IoError> {
+        self.call(TGETATTR, |w| { w.u32(fid); w.u64(GETATTR_BASIC) }, |r| {
+            let _valid = r.u64()?;
+            let _qid = r.qid()?;
+            let mode = r.u32()?;
+            let uid = r.u32()?;
+            let gid = r.u32()?;
+            let _nlink = r.u64()?;
+            let _rdev = r.u64()?;
+            let size = r.u64()?;
+            let _blksize = r.u64()?;
+            let blocks = r.u64()?;
+            let atime = r.u64()? * 1_000_000_000 + r.u64()?;
+            let mtime = r.u64()? * 1_000_000_000 + r.u64()?;
+            Some(InodeStat { size: size
+                           , blocks: blocks
+                           , mode: mode
+                           , uid: uid
+                           , gid: gid
+                           , atime: atime
+                           , mtime: mtime
+                           })
+        })
+    }
+
+    /// Set the size of the file `fid` to `size`.
+    fn set_size(&self, fid: u32, size: u64) -> Result<(), IoError> {
+        self.call(TSETATTR, |w| {
+            w.u32(fid);
+            w.u32(SETATTR_SIZE);
+            // mode, uid, gid
+            w.u32(0); w.u32(0); w.u32(0);
+            w.u64(size);
+            // atime and mtime, in seconds and nanoseconds
+            for _ in 0 .. 4 { w.u64(0) }
+        }, |_| Some(()))
+    }
+
+    /// Read from the open file `fid` at `offset` into `buf`, returning the
+    /// number of bytes read.
+    fn read(&self, fid: u32, offset: u64, buf: &mut [u8])
+           -> Result<usize, IoError> {
+        let count = cmp::min(buf.len(), self.max_read());
+        self.call(TREAD, |w| { w.u32(fid); w.u64(offset); w.u32(count as u32) }
+                 , |r| {
+            let len = r.u32()? as usize;
+            let data = r.bytes(cmp::min(len, count))?;
+            buf[..data.len()].copy_from_slice(data);
+            Some(data.len())
+        })
+    }
+
+    /// Write `buf` to the open file `fid` at `offset`, returning the number
+    /// of bytes written.
+    fn write(&self, fid: u32, offset: u64, buf: &[u8])
+            -> Result<usize, IoError> {
+        let data = &buf[..cmp::min(buf.len(), self.max_write())];
+        self.call(TWRITE, |w| {
+            w.u32(fid);
+            w.u64(offset);
+            w.u32(data.len() as u32);
+            w.bytes(data);
+        }, |r| r.u32().map(|len| cmp::min(len as usize, data.len())))
+    }
+
+    /// Forget `fid`.
+    fn clunk(&self, fid: u32) {
+        if let Err(why) = self.call(TCLUNK, |w| w.u32(fid), |_| Some(())) {
+            warn!("9p: could not clunk fid {}: {:?}", fid, why);
+        }
+    }
+}
+
+/// A file or directory in a 9P share.
+pub struct P9Inode { client: Arc<Client>
+                   , /// Walked to this file, and never opened
+                     fid: u32
+                   , qid: Qid
+                   , /// Opened for reading and writing, or 0 if it hasn't
+                     /// been yet (0 is the root's `fid`, so never this)
+                     io_fid: AtomicUsize
+                   }
+
+impl P9Inode {
+    fn new(client: Arc<Client>, fid: u32, qid: Qid) -> Self {
+        P9Inode { client: client, fid: fid, qid: qid
+                , io_fid: AtomicUsize::new(0) }
+    }
+
+    #[inline] fn is_dir(&self) -> bool { self.qid.kind & QTDIR != 0 }
+
+    /// Returns the open fid for this file, opening one if need be.
+    ///
+    /// Files are opened for writing too, if the server lets us.
+    fn io_fid(&self) -> Result<u32, IoError> {
+        let current = self.io_fid.load(Ordering::Acquire);
+        if current != 0 { return Ok(current as u32) }
+        let (fid, _) = self.client.walk(self.fid, None)?;
+        let opened = if self.is_dir() {
+            self.client.lopen(fid, O_RDONLY)
+        } else {
+            self.client.lopen(fid, O_RDWR)
+                .or_else(|_| self.client.lopen(fid, O_RDONLY))
+        };
+        if let Err(why) = opened {
+            self.client.clunk(fid);
+            return Err(why)
+        }
+        // someone else may have opened one meanwhile.
+        match self.io_fid.compare_and_swap(0, fid as usize, Ordering::AcqRel) {
+            0 => Ok(fid)
+          , theirs => {
+                self.client.clunk(fid);
+                Ok(theirs as u32)
+            }
+        }
+    }
+}
+
+impl Drop for P9Inode {
+    fn drop(&mut self) {
+        let io_fid = self.io_fid.load(Ordering::Acquire);
+        if io_fid != 0 { self.client.clunk(io_fid as u32) }
+        self.client.clunk(self.fid);
+    }
+}
+
+impl Inode for P9Inode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.is_dir() { return Err(IoError::IsADirectory) }
+        let fid = self.io_fid()?;
+        let mut done = 0;
+        while done < buf.len() {
+            let n = self.client.read(fid, offset + done as u64
+                                    , &mut buf[done..])?;
+            if n == 0 { break }
+            done += n;
+        }
+        Ok(done)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, IoError> {
+        if self.is_dir() { return Err(IoError::IsADirectory) }
+        let fid = self.io_fid()?;
+        let mut done = 0;
+        while done < buf.len() {
+            let n = self.client.write(fid, offset + done as u64, &buf[done..])?;
+            if n == 0 { return Err(IoError::NoSpace) }
+            done += n;
+        }
+        Ok(done)
+    }
+
+    /// Times are the host's, in nanoseconds since the Unix epoch, rather
+    /// than since boot.
+    fn stat(&self) -> InodeStat {
+        self.client.getattr(self.fid).unwrap_or_else(|why| {
+            warn!("9p: could not stat fid {}: {:?}", self.fid, why);
+            let kind = if self.is_dir() { mode::S_IFDIR }
+                       else { mode::S_IFREG };
+            InodeStat { mode: kind, ..Default::default() }
+        })
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), IoError> {
+        if self.is_dir() { return Err(IoError::IsADirectory) }
+        self.client.set_size(self.fid, size)
+    }
+
+    /// 9P's directory offsets are cookies rather than indices, so this
+    /// reads the directory from the start each time. `.` and `..` are
+    /// skipped.
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError> {
+        if !self.is_dir() { return Err(IoError::NotADirectory) }
+        let fid = self.io_fid()?;
+        let (mut cookie, mut index) = (0, 0);
+        loop {
+            let start = cookie;
+            let count = self.client.max_read() as u32;
+            let (found, more) = self.client.call(TREADDIR, |w| {
+                w.u32(fid);
+                w.u64(start);
+                w.u32(count);
+            }, |r| {
+                let len = r.u32()? as usize;
+                let mut entries = Reader::new(r.bytes(len)?);
+                while !entries.is_empty() {
+                    let _qid = entries.qid()?;
+                    cookie = entries.u64()?;
+                    // a `d_type`, which is the `S_IFMT` bits shifted down.
+                    let kind = (entries.u8()? as u32) << 12;
+                    let name = entries.string()?;
+                    if name == b"." || name == b".." { continue }
+                    let name = match FileName::new(name) {
+                        Some(name) => name
+                      , None => continue
+                    };
+                    if index == offset {
+                        return Some((Some(DirEntry { name: name, kind: kind })
+                                    , false))
+                    }
+                    index += 1;
+                }
+                Some((None, len > 0))
+            })?;
+            if found.is_some() || !more { return Ok(found) }
+        }
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        if !self.is_dir() { return Err(IoError::NotADirectory) }
+        if name.is_empty() || name == b"." || name == b".."
+            || name.contains(&b'/') {
+            return Err(IoError::InvalidArgument)
+        }
+        let (fid, qid) = self.client.walk(self.fid, Some(name))?;
+        Ok(Arc::new(P9Inode::new(self.client.clone(), fid, qid)))
+    }
+}
+
+/// Mount the 9P share on `dev` in the root directory, as `name`.
+pub fn mount(dev: Arc<Virtio9p>, name: &[u8]) -> Result<(), IoError> {
+    let client = Arc::new(Client::connect(dev)?);
+    let qid = client.attach(ROOT_FID)?;
+    root_dir().link(name, Arc::new(P9Inode::new(client, ROOT_FID, qid)))
+}
@@ -0,0 +1,161 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Anonymous pipes.
+//!
+//! A pipe is a [`PipeInode`](struct.PipeInode.html) holding a fixed-size
+//! buffer, and two ends that refer to it. Each end is a separate inode, so
+//! that the pipe can tell when all the file descriptors for one end have
+//! been closed: the end is dropped along with its last `Arc`.
+use alloc::arc::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use util::ring::RingBuffer;
+
+use task::wait::WaitQueue;
+use super::{DirEntry, Inode, InodeStat, IoError, mode};
+
+/// The capacity of a pipe's buffer, in bytes.
+pub const PIPE_BUF: usize = 4096;
+
+/// The shared state of a pipe.
+pub struct PipeInode { buffer: Mutex<RingBuffer<[u8; PIPE_BUF]>>
+                     , /// The number of open read ends
+                       reader_count: AtomicUsize
+                     , /// The number of open write ends
+                       writer_count: AtomicUsize
+                     , /// Readers waiting for data
+                       read_wq: WaitQueue
+                     , /// Writers waiting for space
+                       write_wq: WaitQueue
+                     }
+
+/// Create a new pipe, returning its read end and write end.
+pub fn new() -> (Arc<Inode>, Arc<Inode>) {
+    let pipe = Arc::new(PipeInode {
+        buffer: Mutex::new(RingBuffer::new())
+      , reader_count: AtomicUsize::new(0)
+      , writer_count: AtomicUsize::new(0)
+      , read_wq: WaitQueue::new()
+      , write_wq: WaitQueue::new()
+    });
+    let reader = PipeEnd::new(pipe.clone(), false);
+    let writer = PipeEnd::new(pipe, true);
+    (Arc::new(reader), Arc::new(writer))
+}
+
+impl Inode for PipeInode {
+    /// Read from the pipe, blocking until there is data to read.
+    ///
+    /// Returns 0 (end of file) once the pipe is empty and every write end
+    /// has been closed.
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, IoError> {
+        if buf.is_empty() { return Ok(0) }
+        loop {
+            let n = self.buffer.lock().read(buf);
+            if n > 0 {
+                self.write_wq.wake_all();
+                return Ok(n)
+            }
+            if self.writer_count.load(Ordering::SeqCst) == 0 {
+                return Ok(0)
+            }
+            self.read_wq.sleep();
+        }
+    }
+
+    /// Write to the pipe, blocking until all of `buf` has been written.
+    ///
+    /// Fails with `BrokenPipe` if every read end has been closed.
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, IoError> {
+        let mut written = 0;
+        while written < buf.len() {
+            if self.reader_count.load(Ordering::SeqCst) == 0 {
+                return Err(IoError::BrokenPipe)
+            }
+            let n = self.buffer.lock().write(&buf[written..]);
+            if n > 0 {
+                written += n;
+                self.read_wq.wake_all();
+            } else {
+                self.write_wq.sleep();
+            }
+        }
+        Ok(written)
+    }
+
+    fn stat(&self) -> InodeStat {
+        InodeStat { size: self.buffer.lock().len() as u64
+                  , mode: mode::S_IFIFO | 0o600
+                  , ..Default::default()
+                  }
+    }
+
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::InvalidArgument)
+    }
+
+    #[inline]
+    fn readdir(&self, _offset: u64) -> Result<Option<DirEntry>, IoError> {
+        Err(IoError::NotADirectory)
+    }
+}
+
+/// One end of a pipe.
+struct PipeEnd { pipe: Arc<PipeInode>
+               , is_writer: bool
+               }
+
+impl PipeEnd {
+    fn new(pipe: Arc<PipeInode>, is_writer: bool) -> Self {
+        if is_writer {
+            pipe.writer_count.fetch_add(1, Ordering::SeqCst);
+        } else {
+            pipe.reader_count.fetch_add(1, Ordering::SeqCst);
+        }
+        PipeEnd { pipe: pipe, is_writer: is_writer }
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        // wake up anyone blocked on the other end, so they notice
+        if self.is_writer {
+            self.pipe.writer_count.fetch_sub(1, Ordering::SeqCst);
+            self.pipe.read_wq.wake_all();
+        } else {
+            self.pipe.reader_count.fetch_sub(1, Ordering::SeqCst);
+            self.pipe.write_wq.wake_all();
+        }
+    }
+}
+
+impl Inode for PipeEnd {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.is_writer { return Err(IoError::Unsupported) }
+        self.pipe.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, IoError> {
+        if !self.is_writer { return Err(IoError::Unsupported) }
+        self.pipe.write_at(offset, buf)
+    }
+
+    #[inline] fn stat(&self) -> InodeStat { self.pipe.stat() }
+
+    #[inline]
+    fn truncate(&self, size: u64) -> Result<(), IoError> {
+        self.pipe.truncate(size)
+    }
+
+    #[inline]
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError> {
+        self.pipe.readdir(offset)
+    }
+}
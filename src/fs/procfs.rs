@@ -0,0 +1,459 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A pseudo-file system exposing kernel state.
+//!
+//! Nothing in procfs is stored anywhere: each file's contents are generated
+//! into a stack buffer every time it is read.
+//!
+//! ```text
+//! /proc
+//! ├── meminfo       physical memory and kernel heap usage
+//! ├── tasks         every task's PID, state and name
+//! ├── interrupts    hardware IRQ counts
+//! ├── uptime        seconds since boot
+//! ├── sys           kernel parameters, one file each (see `sysctl`)
+//! └── <pid>
+//!     ├── maps      the task's virtual memory regions
+//!     └── stat      the task's status and CPU time, as on Linux
+//! ```
+use alloc::arc::Arc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{cmp, str};
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+use cpu::{interrupts, tsc};
+use memory::{PAGE_SIZE, VAddr};
+use util::bufwriter::BufWriter;
+
+use heap;
+use mm::frame;
+use mm::vm::{VM_EXEC, VM_GROWSDOWN, VM_READ, VM_WRITE};
+use perf::PerfEvent;
+use syscall::time::NSEC_PER_SEC;
+use sysctl::{self, GetFn, SetFn, Sysctl};
+use task::{self, sched, Pid, Task, TaskState};
+use super::{DirEntry, FileName, Inode, InodeStat, IoError, mode};
+
+/// The most a single procfs file can hold. Output past this is truncated.
+const PROC_FILE_MAX: usize = 4096;
+
+/// The clock ticks per second that times in `/proc/<pid>/stat` are in.
+const USER_HZ: u64 = 100;
+
+/// The procfs file system.
+pub struct Procfs;
+
+impl Procfs {
+    /// Returns the root directory of a procfs.
+    pub fn mount() -> Arc<Inode> {
+        Arc::new(ProcRoot)
+    }
+}
+
+/// The files in the procfs root directory.
+const ROOT_FILES: [(&'static [u8], ProcFile); 4]
+    = [ (b"meminfo", ProcFile::MemInfo)
+      , (b"tasks", ProcFile::Tasks)
+      , (b"interrupts", ProcFile::Interrupts)
+      , (b"uptime", ProcFile::Uptime)
+      ];
+
+/// The files in each `/proc/<pid>` directory.
+const PID_FILES: [&'static [u8]; 2] = [b"maps", b"stat"];
+
+/// Parse a directory name as a PID.
+fn parse_pid(name: &[u8]) -> Option<Pid> {
+    str::from_utf8(name).ok()
+        .and_then(|name| name.parse().ok())
+        .map(Pid)
+}
+
+/// Copy the part of `contents` starting at `offset` into `buf`, returning
+/// the number of bytes copied.
+fn copy_at(contents: &[u8], offset: u64, buf: &mut [u8]) -> usize {
+    let offset = cmp::min(offset, contents.len() as u64) as usize;
+    let n = cmp::min(buf.len(), contents.len() - offset);
+    buf[..n].copy_from_slice(&contents[offset .. offset + n]);
+    n
+}
+
+/// Stat for a procfs directory.
+#[inline]
+fn dir_stat() -> InodeStat {
+    InodeStat { mode: mode::S_IFDIR | 0o555, ..Default::default() }
+}
+
+/// The procfs root directory.
+struct ProcRoot;
+
+impl Inode for ProcRoot {
+    #[inline]
+    fn read_at(&self, _offset: u64, _buf: &mut [u8])
+              -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline]
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline] fn stat(&self) -> InodeStat { dir_stat() }
+
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    /// The fixed files come first, then `sys`, followed by a directory for
+    /// each task.
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError> {
+        let offset = offset as usize;
+        if let Some(&(name, _)) = ROOT_FILES.get(offset) {
+            return Ok(FileName::new(name)
+                        .map(|name| DirEntry { name: name
+                                             , kind: mode::S_IFREG }))
+        }
+        if offset == ROOT_FILES.len() {
+            return Ok(FileName::new(b"sys")
+                        .map(|name| DirEntry { name: name
+                                             , kind: mode::S_IFDIR }))
+        }
+        let mut pids = Vec::new();
+        task::for_each(|task| pids.push(task.pid));
+        Ok(pids.get(offset - ROOT_FILES.len() - 1).and_then(|pid| {
+            let mut buf = [0u8; 10];
+            let len = {
+                let mut w = BufWriter::new(&mut buf);
+                let _ = write!(w, "{}", pid);
+                w.len()
+            };
+            FileName::new(&buf[..len])
+                .map(|name| DirEntry { name: name, kind: mode::S_IFDIR })
+        }))
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        if let Some(&(_, file)) = ROOT_FILES.iter().find(|&&(n, _)| n == name) {
+            return Ok(Arc::new(file))
+        }
+        if name == b"sys" { return Ok(Arc::new(ProcSysDir(String::new()))) }
+        match parse_pid(name) {
+            Some(pid) if task::with_task(pid, |_| ()).is_some() =>
+                Ok(Arc::new(ProcPidDir(pid)))
+          , _ => Err(IoError::NotFound)
+        }
+    }
+}
+
+/// A `/proc/<pid>` directory.
+struct ProcPidDir(Pid);
+
+impl Inode for ProcPidDir {
+    #[inline]
+    fn read_at(&self, _offset: u64, _buf: &mut [u8])
+              -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline]
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline] fn stat(&self) -> InodeStat { dir_stat() }
+
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError> {
+        Ok(PID_FILES.get(offset as usize)
+                    .and_then(|name| FileName::new(name))
+                    .map(|name| DirEntry { name: name
+                                         , kind: mode::S_IFREG }))
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        if name == b"maps" { Ok(Arc::new(ProcFile::Maps(self.0))) }
+        else if name == b"stat" { Ok(Arc::new(ProcFile::Stat(self.0))) }
+        else { Err(IoError::NotFound) }
+    }
+}
+
+/// A generated procfs file.
+#[derive(Copy, Clone, Debug)]
+enum ProcFile { MemInfo
+              , Tasks
+              , Interrupts
+              , Uptime
+              , Maps(Pid)
+              , Stat(Pid)
+              }
+
+/// Returns the letter for `task`'s state in `/proc`.
+fn state_char(task: &Task) -> char {
+    match task.state {
+        TaskState::Runnable => 'R'
+      , TaskState::Blocked => 'S'
+      , TaskState::Zombie => 'Z'
+    }
+}
+
+/// Write `/proc/meminfo`.
+fn meminfo<W: Write>(w: &mut W) -> fmt::Result {
+    let stats = frame::stats();
+    let kb = |frames: usize| frames * PAGE_SIZE as usize / 1024;
+    write!(w, "MemTotal: {:>10} kB\n", kb(stats.total))?;
+    write!(w, "MemFree:  {:>10} kB\n", kb(stats.free()))?;
+    // there's no page cache (yet)
+    write!(w, "Cached:   {:>10} kB\n", 0)?;
+
+    let heap = heap::stats();
+    let kb = |bytes: usize| bytes / 1024;
+    write!(w, "HeapUsed: {:>10} kB\n", kb(heap.allocated_bytes))?;
+    write!(w, "HeapFree: {:>10} kB\n", kb(heap.free_bytes))?;
+    write!(w, "HeapPeak: {:>10} kB\n", kb(heap.peak_allocated))?;
+    write!(w, "HeapAllocs: {:>8}\n", heap.alloc_calls)?;
+    write!(w, "HeapFrees:  {:>8}\n", heap.free_calls)?;
+    for (bucket, &count) in heap.alloc_histogram.iter().enumerate() {
+        if count == 0 { continue }
+        write!(w, "HeapSize{}: {:>8}\n", 1u64 << bucket, count)?;
+    }
+    Ok(())
+}
+
+/// Write `/proc/tasks`.
+fn tasks<W: Write>(w: &mut W) -> fmt::Result {
+    write!(w, "  PID STATE NAME\n")?;
+    let mut result = Ok(());
+    task::for_each(|task| {
+        result = result.and_then(|_|
+            write!(w, "{:>5} {:>5} {}\n"
+                  , task.pid, state_char(task), task.name));
+    });
+    result
+}
+
+/// Write `/proc/interrupts`.
+fn irq_counts<W: Write>(w: &mut W) -> fmt::Result {
+    for irq in 0 .. interrupts::NUM_IRQS {
+        write!(w, "{:>3}: {:>10}\n", irq, interrupts::irq_count(irq))?;
+    }
+    Ok(())
+}
+
+/// Write `/proc/uptime`.
+fn uptime<W: Write>(w: &mut W) -> fmt::Result {
+    let ns = tsc::current_ns();
+    let secs = ns / 1_000_000_000;
+    let hundredths = (ns / 10_000_000) % 100;
+    // we don't keep track of idle time
+    write!(w, "{}.{:02} 0.00\n", secs, hundredths)
+}
+
+/// Write `/proc/<pid>/maps` for `task`.
+///
+/// The format is Linux's, but every region is anonymous, so the offset,
+/// device and inode are always 0.
+fn maps<W: Write>(task: &Task, w: &mut W) -> fmt::Result {
+    let round_up = |addr: VAddr| {
+        let mask = PAGE_SIZE as usize - 1;
+        (addr.as_usize() + mask) & !mask
+    };
+    let heap = round_up(task.brk_start) .. round_up(task.brk);
+    for region in task.vm.iter() {
+        let flag = |flag, c| if region.flags.contains(flag) { c } else { '-' };
+        let start = region.start.as_usize();
+        let name = if region.flags.contains(VM_GROWSDOWN) {
+            "[stack]"
+        } else if heap.start <= start && start < heap.end {
+            "[heap]"
+        } else {
+            ""
+        };
+        write!(w, "{:012x}-{:012x} {}{}{}p 00000000 00:00 0 {}\n"
+              , start, region.end.as_usize()
+              , flag(VM_READ, 'r'), flag(VM_WRITE, 'w'), flag(VM_EXEC, 'x')
+              , name)?;
+    }
+    Ok(())
+}
+
+/// Write `/proc/<pid>/stat` for `task`.
+///
+/// These are the first 17 fields of Linux's, through `cstime`. The ones we
+/// don't keep track of are 0, and system time isn't told apart from user
+/// time yet. Page faults are only counted with the `task-perf` feature.
+fn stat<W: Write>(task: &Task, w: &mut W) -> fmt::Result {
+    let ticks = |ns: u64| ns / (NSEC_PER_SEC / USER_HZ);
+    let ppid = task.parent.map(|pid| pid.0).unwrap_or(0);
+    let children = task.children_cpu_time_ns.load(Ordering::Relaxed);
+    write!(w, "{} ({}) {} {} 0 0 0 0 0 {} 0 {} 0 {} 0 {} 0\n"
+          , task.pid, task.name, state_char(task), ppid
+          , task.perf.get(PerfEvent::MinorFaults)
+          , task.perf.get(PerfEvent::MajorFaults)
+          , ticks(sched::cpu_time_ns(task))
+          , ticks(children))
+}
+
+impl ProcFile {
+    /// Write this file's current contents to `w`.
+    ///
+    /// Returns `None` if the file is about a task that no longer exists.
+    /// The task list stays locked while a task's file is written, so the
+    /// task can't go away halfway through.
+    fn generate<W: Write>(&self, w: &mut W) -> Option<fmt::Result> {
+        match *self {
+            ProcFile::MemInfo => Some(meminfo(w))
+          , ProcFile::Tasks => Some(tasks(w))
+          , ProcFile::Interrupts => Some(irq_counts(w))
+          , ProcFile::Uptime => Some(uptime(w))
+          , ProcFile::Maps(pid) => task::with_task(pid, |task| maps(task, w))
+          , ProcFile::Stat(pid) => task::with_task(pid, |task| stat(task, w))
+        }
+    }
+}
+
+impl Inode for ProcFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, IoError> {
+        let mut contents = [0u8; PROC_FILE_MAX];
+        let len = {
+            let mut w = BufWriter::new(&mut contents);
+            // `BufWriter` truncates rather than failing
+            match self.generate(&mut w) {
+                Some(_) => w.len()
+              , None => return Err(IoError::NotFound)
+            }
+        };
+        Ok(copy_at(&contents[..len], offset, buf))
+    }
+
+    #[inline]
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, IoError> {
+        Err(IoError::Unsupported)
+    }
+
+    /// Files are generated on demand, so their size is always reported as
+    /// zero, like on Linux.
+    #[inline]
+    fn stat(&self) -> InodeStat {
+        InodeStat { mode: mode::S_IFREG | 0o444, ..Default::default() }
+    }
+
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::Unsupported)
+    }
+
+    #[inline]
+    fn readdir(&self, _offset: u64) -> Result<Option<DirEntry>, IoError> {
+        Err(IoError::NotADirectory)
+    }
+}
+
+/// A directory under `/proc/sys`, for the directory at this path in the
+/// sysctl tree.
+struct ProcSysDir(String);
+
+impl Inode for ProcSysDir {
+    #[inline]
+    fn read_at(&self, _offset: u64, _buf: &mut [u8])
+              -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline]
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline] fn stat(&self) -> InodeStat { dir_stat() }
+
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError> {
+        Ok(sysctl::read_dir(&self.0, offset as usize)
+                  .and_then(|(name, is_dir)| {
+                      FileName::new(name.as_bytes())
+                          .map(|name| DirEntry { name: name
+                                               , kind: if is_dir {
+                                                     mode::S_IFDIR
+                                                 } else {
+                                                     mode::S_IFREG
+                                                 }
+                                               })
+                  }))
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        let name = str::from_utf8(name).map_err(|_| IoError::NotFound)?;
+        let path = if self.0.is_empty() { String::from(name) }
+                   else { format!("{}/{}", self.0, name) };
+        if !sysctl::valid_path(&path) { return Err(IoError::NotFound) }
+        match sysctl::lookup(&path) {
+            Some(Sysctl::Dir) => Ok(Arc::new(ProcSysDir(path)))
+          , Some(Sysctl::Param(get, set)) =>
+                Ok(Arc::new(ProcSysParam { get: get, set: set }))
+          , None => Err(IoError::NotFound)
+        }
+    }
+}
+
+/// A file under `/proc/sys`, for a kernel parameter.
+///
+/// Reading it gets the parameter's value, in decimal, and writing a decimal
+/// number to it sets the parameter, if it can be set.
+struct ProcSysParam { get: GetFn
+                    , set: Option<SetFn>
+                    }
+
+impl Inode for ProcSysParam {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, IoError> {
+        let mut contents = [0u8; 24];
+        let len = {
+            let mut w = BufWriter::new(&mut contents);
+            let _ = write!(w, "{}\n", (self.get)());
+            w.len()
+        };
+        Ok(copy_at(&contents[..len], offset, buf))
+    }
+
+    /// The whole value has to be written at once; `offset` is ignored.
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, IoError> {
+        let set = self.set.ok_or(IoError::PermissionDenied)?;
+        let value = str::from_utf8(buf).ok()
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or(IoError::InvalidArgument)?;
+        set(value);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn stat(&self) -> InodeStat {
+        let perms = if self.set.is_some() { 0o644 } else { 0o444 };
+        InodeStat { mode: mode::S_IFREG | perms, ..Default::default() }
+    }
+
+    /// Opening a parameter for writing truncates it, which does nothing.
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        if self.set.is_some() { Ok(()) } else { Err(IoError::PermissionDenied) }
+    }
+
+    #[inline]
+    fn readdir(&self, _offset: u64) -> Result<Option<DirEntry>, IoError> {
+        Err(IoError::NotADirectory)
+    }
+}
@@ -0,0 +1,236 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! An in-memory file system, backed by the kernel heap.
+//!
+//! This is mounted as the root file system during boot, before we know
+//! about any disks.
+use super::{DirEntry, FileName, Inode, InodeStat, IoError, mode};
+
+use alloc::arc::Arc;
+use alloc::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use core::{cmp, ptr};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+use cpu::tsc;
+use heap;
+
+/// The largest a tmpfs file may grow, in bytes.
+///
+/// Files live on the kernel heap, so without a limit, one `ftruncate` of a
+/// memfd could ask for more memory than the heap could ever have.
+pub const TMPFS_FILE_MAX: u64 = 64 * 1024 * 1024;
+
+/// The tmpfs file system.
+pub struct Tmpfs;
+
+impl Tmpfs {
+    /// Create a new, empty tmpfs and return its root directory.
+    pub fn mount() -> Arc<TmpfsDir> {
+        Arc::new(TmpfsDir::new())
+    }
+
+    /// Create a new, empty regular file that isn't in any directory, as
+    /// `memfd_create(2)` does.
+    ///
+    /// It's freed once the last file descriptor and mapping of it are gone.
+    pub fn unlinked_file() -> Arc<TmpfsFile> {
+        Arc::new(TmpfsFile::new())
+    }
+}
+
+/// A regular file in a tmpfs.
+pub struct TmpfsFile { data: RwLock<Vec<u8>>
+                     , mtime: AtomicUsize
+                     }
+
+impl TmpfsFile {
+    fn new() -> Self {
+        TmpfsFile { data: RwLock::new(Vec::new())
+                  , mtime: AtomicUsize::new(tsc::current_ns() as usize)
+                  }
+    }
+
+    #[inline] fn touch(&self) {
+        self.mtime.store(tsc::current_ns() as usize, Ordering::Relaxed);
+    }
+}
+
+/// Resize `data` to `len` bytes, filling any new space with zeroes.
+///
+/// The buffer is grown with a fallible allocation, so a file that doesn't
+/// fit on the heap fails with `NoSpace`, rather than panicking the kernel.
+fn try_resize(data: &mut Vec<u8>, len: usize) -> Result<(), IoError> {
+    if len > data.capacity() {
+        // at least double the capacity, so appending a little at a time
+        // doesn't copy the whole file every time.
+        let cap = cmp::min( cmp::max(len, data.capacity() * 2)
+                          , TMPFS_FILE_MAX as usize);
+        let buf = heap::try_allocate(cap, 1).map_err(|_| IoError::NoSpace)?;
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len());
+            // dropping the old vector frees its buffer.
+            *data = Vec::from_raw_parts(buf, data.len(), cap);
+        }
+    }
+    data.resize(len, 0);
+    Ok(())
+}
+
+impl Inode for TmpfsFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, IoError> {
+        let data = self.data.read();
+        if offset >= data.len() as u64 {
+            return Ok(0)
+        }
+        let data = &data[offset as usize..];
+        let n = if buf.len() < data.len() { buf.len() } else { data.len() };
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, IoError> {
+        let end = offset.checked_add(buf.len() as u64)
+                        .ok_or(IoError::InvalidArgument)?;
+        if end > TMPFS_FILE_MAX {
+            return Err(IoError::FileTooBig)
+        }
+        let (offset, end) = (offset as usize, end as usize);
+
+        let mut data = self.data.write();
+        if end > data.len() {
+            // writing past the end of the file fills the gap with zeroes
+            try_resize(&mut data, end)?;
+        }
+        data[offset..end].copy_from_slice(buf);
+        self.touch();
+        Ok(buf.len())
+    }
+
+    fn stat(&self) -> InodeStat {
+        let size = self.data.read().len() as u64;
+        let mtime = self.mtime.load(Ordering::Relaxed) as u64;
+        InodeStat { size: size
+                  , blocks: (size + 511) / 512
+                  , mode: mode::S_IFREG | 0o644
+                  , atime: mtime
+                  , mtime: mtime
+                  , ..Default::default()
+                  }
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), IoError> {
+        if size > TMPFS_FILE_MAX {
+            return Err(IoError::FileTooBig)
+        }
+        try_resize(&mut self.data.write(), size as usize)?;
+        self.touch();
+        Ok(())
+    }
+
+    #[inline]
+    fn readdir(&self, _offset: u64) -> Result<Option<DirEntry>, IoError> {
+        Err(IoError::NotADirectory)
+    }
+}
+
+/// A directory in a tmpfs.
+pub struct TmpfsDir { children: Mutex<BTreeMap<FileName, Arc<Inode>>>
+                    , mtime: AtomicUsize
+                    }
+
+impl TmpfsDir {
+    fn new() -> Self {
+        TmpfsDir { children: Mutex::new(BTreeMap::new())
+                 , mtime: AtomicUsize::new(tsc::current_ns() as usize)
+                 }
+    }
+
+    /// Insert `inode` into this directory as `name`.
+    ///
+    /// Fails if `name` is not a valid file name, or if an entry with that
+    /// name already exists.
+    pub fn link(&self, name: &[u8], inode: Arc<Inode>) -> Result<(), IoError> {
+        let name = FileName::new(name).ok_or(IoError::InvalidArgument)?;
+        let mut children = self.children.lock();
+        if children.contains_key(&name) {
+            return Err(IoError::AlreadyExists)
+        }
+        children.insert(name, inode);
+        self.mtime.store(tsc::current_ns() as usize, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Remove the entry called `name` from this directory.
+    pub fn unlink(&self, name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        let name = FileName::new(name).ok_or(IoError::InvalidArgument)?;
+        let removed = self.children.lock().remove(&name)
+                          .ok_or(IoError::NotFound)?;
+        self.mtime.store(tsc::current_ns() as usize, Ordering::Relaxed);
+        Ok(removed)
+    }
+
+    /// Create a new, empty subdirectory called `name`.
+    pub fn mkdir(&self, name: &[u8]) -> Result<Arc<TmpfsDir>, IoError> {
+        let dir = Arc::new(TmpfsDir::new());
+        self.link(name, dir.clone())?;
+        Ok(dir)
+    }
+
+    /// Create a new, empty regular file called `name`.
+    pub fn create(&self, name: &[u8]) -> Result<Arc<TmpfsFile>, IoError> {
+        let file = Arc::new(TmpfsFile::new());
+        self.link(name, file.clone())?;
+        Ok(file)
+    }
+}
+
+impl Inode for TmpfsDir {
+    #[inline]
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    #[inline]
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    fn stat(&self) -> InodeStat {
+        let mtime = self.mtime.load(Ordering::Relaxed) as u64;
+        InodeStat { size: self.children.lock().len() as u64
+                  , mode: mode::S_IFDIR | 0o755
+                  , atime: mtime
+                  , mtime: mtime
+                  , ..Default::default()
+                  }
+    }
+
+    #[inline]
+    fn truncate(&self, _size: u64) -> Result<(), IoError> {
+        Err(IoError::IsADirectory)
+    }
+
+    fn readdir(&self, offset: u64) -> Result<Option<DirEntry>, IoError> {
+        let children = self.children.lock();
+        Ok(children.iter().nth(offset as usize)
+                   .map(|(name, inode)|
+                        DirEntry { name: *name
+                                 , kind: inode.stat().mode & mode::S_IFMT
+                                 }))
+    }
+
+    fn lookup(&self, name: &[u8]) -> Result<Arc<Inode>, IoError> {
+        let name = FileName::new(name).ok_or(IoError::InvalidArgument)?;
+        self.children.lock().get(&name)
+            .cloned()
+            .ok_or(IoError::NotFound)
+    }
+}
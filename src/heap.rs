@@ -6,7 +6,73 @@
 //  Released under the terms of the MIT license. See `LICENSE` in the root
 //  directory of this repository for more information.
 //
+//! The kernel heap.
+//!
+//! The heap starts out in memory reserved in the kernel image. Once that's
+//! used up, it grows into the region from `GROWTH_BASE` to `GROWTH_END`,
+//! mapping frames as it goes.
+use memory::{PAGE_SIZE, Page, VAddr, VirtualPage};
+use paging::Mapper;
+use paging::arch::ActivePageTable;
+use paging::arch::table::WRITABLE;
 use params::InitParams;
+use sos_alloc::FrameAllocator;
+use sos_alloc::buddy::system::{set_heap_pager, set_oom_handler, HeapPager};
+
+use arch::tlb_shootdown;
+use mm::{frame, oom};
+
+pub use sos_alloc::buddy::system::{ compact, shrink, stats, try_allocate
+                                  , Compaction, HeapStats, HISTOGRAM_BUCKETS };
+/// The kernel address sanitizer: code can check heap accesses with
+/// `kasan::check_access`.
+#[cfg(feature = "kasan")]
+pub use sos_alloc::buddy::kasan;
+
+/// The first address the kernel heap grows into.
+pub const GROWTH_BASE: usize = 0x8000_0000;
+/// One past the last address the kernel heap may grow into.
+pub const GROWTH_END: usize = 0xC000_0000;
+
+/// Map `len` bytes of new memory for the heap at `start`.
+///
+/// If there aren't enough frames, whatever was mapped is unmapped again.
+fn map_pages(start: VAddr, len: usize) -> bool {
+    let mut table = unsafe { ActivePageTable::new() };
+    let mut frames = frame::allocator();
+    let first = VirtualPage::containing(start);
+    let pages = len / PAGE_SIZE as usize;
+    for i in 0 .. pages {
+        let page = VirtualPage { number: first.number + i };
+        if table.map_to_any(page, WRITABLE, &mut frames).is_err() {
+            for j in 0 .. i {
+                let page = VirtualPage { number: first.number + j };
+                let _ = table.unmap(page, &mut frames);
+            }
+            return false
+        }
+    }
+    true
+}
+
+/// Unmap the `len` bytes of heap memory at `start`, freeing the frames.
+///
+/// Every CPU shares the heap's mappings, so each page is shot down from
+/// all of their TLBs.
+fn unmap_pages(start: VAddr, len: usize) {
+    let mut table = unsafe { ActivePageTable::new() };
+    let mut frames = frame::allocator();
+    let first = VirtualPage::containing(start);
+    for i in 0 .. len / PAGE_SIZE as usize {
+        let page = VirtualPage { number: first.number + i };
+        // other CPUs may still have the page cached, so the frame can only
+        // be reused once they've all flushed it.
+        if let Ok(frame) = table.unmap_no_free(page) {
+            tlb_shootdown::broadcast(page.base());
+            unsafe { frames.deallocate(frame) };
+        }
+    }
+}
 
 /// Initialise the kernel heap.
 //  TODO: this is the Worst Thing In The Universe. De-stupid-ify it.
@@ -14,5 +80,11 @@ pub unsafe fn initialize<'a>(params: &InitParams) -> Result<&'a str, &'a str> {
     // let heap_base_ptr = params.heap_base.as_mut_ptr();
     let heap_size: u64 = (params.heap_top - params.heap_base).into();
     // buddy::system::init_heap(heap_base_ptr, heap_size as usize);
+    set_heap_pager( HeapPager { map: map_pages
+                              , unmap: unmap_pages
+                              , limit: VAddr::from_usize(GROWTH_END)
+                              }
+                  , VAddr::from_usize(GROWTH_BASE));
+    set_oom_handler(oom::kill_largest_task);
     Ok("[ OKAY ]")
 }
@@ -0,0 +1,133 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A text console drawn on a graphical framebuffer.
+//!
+//! The console is set up as soon as the framebuffer is found, in the font
+//! built into the kernel: the basic Latin glyphs of the public domain
+//! `font8x8`, as an 8x8 PSF2 font.
+use core::fmt;
+use spin::Mutex;
+
+use dev::framebuffer::Framebuffer;
+use super::psf2::Psf2Font;
+
+/// The built-in console font.
+static FONT: &'static [u8] = include_bytes!("font8x8.psf");
+
+/// The console on the framebuffer, if there is one.
+pub static CONSOLE: Mutex<Option<TextConsole<'static>>> = Mutex::new(None);
+
+/// The default foreground color (light grey).
+pub const DEFAULT_FG: u32 = 0x00aa_aaaa;
+/// The default background color (black).
+pub const DEFAULT_BG: u32 = 0x0000_0000;
+
+/// A text console on a framebuffer, in a fixed-width bitmap font.
+pub struct TextConsole<'a> { fb: Framebuffer
+                           , font: Psf2Font<'a>
+                           , /// The cursor's column, in characters
+                             col: u32
+                           , /// The cursor's row, in characters
+                             row: u32
+                           , fg: u32
+                           , bg: u32
+                           }
+
+impl<'a> TextConsole<'a> {
+    /// Returns a console drawing on `fb` in `font`, and clears the screen.
+    ///
+    /// Fails if not even one character fits on the screen.
+    pub fn new(fb: Framebuffer, font: Psf2Font<'a>)
+              -> Result<Self, &'static str> {
+        if font.width() > fb.width() || font.height() > fb.height() {
+            return Err("font is too big for the framebuffer")
+        }
+        let mut console = TextConsole { fb: fb
+                                      , font: font
+                                      , col: 0
+                                      , row: 0
+                                      , fg: DEFAULT_FG
+                                      , bg: DEFAULT_BG
+                                      };
+        console.clear();
+        Ok(console)
+    }
+
+    /// Returns the number of characters in each line.
+    #[inline] pub fn columns(&self) -> u32 {
+        self.fb.width() / self.font.width()
+    }
+
+    /// Returns the number of lines on screen.
+    #[inline] pub fn rows(&self) -> u32 {
+        self.fb.height() / self.font.height()
+    }
+
+    /// Set the colors used for writing subsequent characters.
+    pub fn set_colors(&mut self, fg: u32, bg: u32) -> &mut Self {
+        self.fg = fg;
+        self.bg = bg;
+        self
+    }
+
+    /// Clear the screen, and move the cursor to the top left corner.
+    pub fn clear(&mut self) -> &mut Self {
+        let (width, height) = (self.fb.width(), self.fb.height());
+        self.fb.fill_rect(0, 0, width, height, self.bg);
+        self.col = 0;
+        self.row = 0;
+        self
+    }
+
+    /// Move the cursor to the start of the next line, scrolling if it was
+    /// on the bottom line.
+    fn newline(&mut self) {
+        self.col = 0;
+        self.row += 1;
+        if self.row >= self.rows() {
+            let lines = self.font.height();
+            self.fb.scroll_up(lines, self.bg);
+            self.row = self.rows() - 1;
+        }
+    }
+
+    /// Write the given byte to the console, and advance the cursor.
+    pub fn write_byte(&mut self, byte: u8) -> &mut Self {
+        match byte {
+            b'\n' => self.newline()
+          , b'\r' => self.col = 0
+          , _ => {
+                if self.col >= self.columns() { self.newline() }
+                let glyph = self.font.glyph_index(byte);
+                let x = self.col * self.font.width();
+                let y = self.row * self.font.height();
+                self.font.draw_glyph( &mut self.fb, glyph, x, y
+                                    , self.fg, self.bg);
+                self.col += 1;
+            }
+        }
+        self
+    }
+}
+
+impl<'a> fmt::Write for TextConsole<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Set up the console on `fb`, in the built-in font.
+pub fn init(fb: Framebuffer) -> Result<(), &'static str> {
+    let font = Psf2Font::parse(FONT)?;
+    *CONSOLE.lock() = Some(TextConsole::new(fb, font)?);
+    Ok(())
+}
@@ -8,6 +8,7 @@
 //
 //! PS/2 keyboard driver
 use cpu::Port;
+use cpu::ports::PS2_DATA;
 use spin::Mutex;
 
 use core::default::Default;
@@ -122,7 +123,7 @@ impl Modifiers {
 /// Our global keyboard state, protected by a mutex.
 //  TODO: can this be thread local?
 static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
-    data_port: Port::<u8>::new(0x60)
+    data_port: PS2_DATA
   , state: Modifiers::new()
 });
 
@@ -12,3 +12,5 @@
 //! implementation.
 pub mod term;
 pub mod keyboard;
+pub mod console;
+pub mod psf2;
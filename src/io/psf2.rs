@@ -0,0 +1,153 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! PC Screen Font (version 2) bitmap fonts.
+//!
+//! A PSF2 file is a small header followed by the glyph bitmaps, one after
+//! another. Each row of a glyph is padded to a whole number of bytes, with
+//! the leftmost pixel in the most significant bit. Fonts are meant to be
+//! embedded in the kernel image, like this:
+//!
+//! ```ignore
+//! static FONT: &'static [u8] = include_bytes!("font.psf");
+//! let font = Psf2Font::parse(FONT)?;
+//! ```
+use core::fmt;
+
+use dev::framebuffer::Framebuffer;
+
+/// The magic number at the start of every PSF2 font.
+pub const PSF2_MAGIC: u32 = 0x864a_b572;
+
+/// Header flag: the font has a Unicode translation table.
+pub const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+
+/// The size of the PSF2 header, in bytes.
+const HEADER_LEN: usize = 32;
+
+/// Read the little-endian `u32` at `offset` in `bytes`.
+#[inline]
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset] as u32
+        | (bytes[offset + 1] as u32) << 8
+        | (bytes[offset + 2] as u32) << 16
+        | (bytes[offset + 3] as u32) << 24
+}
+
+/// A PSF2 bitmap font.
+#[derive(Copy, Clone)]
+pub struct Psf2Font<'a> { /// The whole font file
+                          data: &'a [u8]
+                        , version: u32
+                        , /// Offset of the first glyph
+                          header_size: u32
+                        , flags: u32
+                        , num_glyphs: u32
+                        , bytes_per_glyph: u32
+                        , /// Height of each glyph, in pixels
+                          height: u32
+                        , /// Width of each glyph, in pixels
+                          width: u32
+                        }
+
+impl<'a> Psf2Font<'a> {
+    /// Parse the PSF2 font in `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < HEADER_LEN {
+            return Err("font is too short to have a PSF2 header")
+        }
+        if read_u32(data, 0) != PSF2_MAGIC {
+            return Err("font does not start with the PSF2 magic number")
+        }
+        let font = Psf2Font { data: data
+                            , version: read_u32(data, 4)
+                            , header_size: read_u32(data, 8)
+                            , flags: read_u32(data, 12)
+                            , num_glyphs: read_u32(data, 16)
+                            , bytes_per_glyph: read_u32(data, 20)
+                            , height: read_u32(data, 24)
+                            , width: read_u32(data, 28)
+                            };
+        if font.version != 0 {
+            return Err("unknown PSF2 version")
+        }
+        if font.num_glyphs == 0 || font.width == 0 || font.height == 0 {
+            return Err("font has no glyphs")
+        }
+        if font.bytes_per_glyph < font.row_bytes() * font.height {
+            return Err("font's glyphs are too small for their dimensions")
+        }
+        let glyphs_len = font.num_glyphs as u64 * font.bytes_per_glyph as u64;
+        if font.header_size as u64 + glyphs_len > data.len() as u64 {
+            return Err("font is shorter than its header says")
+        }
+        Ok(font)
+    }
+
+    /// Returns the width of each glyph, in pixels.
+    #[inline] pub fn width(&self) -> u32 { self.width }
+
+    /// Returns the height of each glyph, in pixels.
+    #[inline] pub fn height(&self) -> u32 { self.height }
+
+    /// Returns the number of glyphs in the font.
+    #[inline] pub fn num_glyphs(&self) -> u32 { self.num_glyphs }
+
+    /// Returns true if the font has a Unicode translation table.
+    #[inline] pub fn has_unicode_table(&self) -> bool {
+        self.flags & PSF2_HAS_UNICODE_TABLE != 0
+    }
+
+    /// Returns the number of bytes in each row of a glyph.
+    #[inline] fn row_bytes(&self) -> u32 { (self.width + 7) / 8 }
+
+    /// Returns the bitmap of glyph number `index`, or of glyph 0 if the
+    /// font has no such glyph.
+    fn glyph(&self, index: u32) -> &'a [u8] {
+        let index = if index < self.num_glyphs { index } else { 0 };
+        let start = self.header_size as usize
+                  + index as usize * self.bytes_per_glyph as usize;
+        &self.data[start .. start + self.bytes_per_glyph as usize]
+    }
+
+    /// Returns the glyph for the byte `byte`.
+    ///
+    /// The first 256 glyphs of a console font are normally the code page
+    /// the font was made for, which agrees with ASCII, so we don't bother
+    /// with the Unicode table.
+    #[inline]
+    pub fn glyph_index(&self, byte: u8) -> u32 {
+        if (byte as u32) < self.num_glyphs { byte as u32 }
+        else { b'?' as u32 }
+    }
+
+    /// Draw glyph number `glyph_idx` with its top left corner at `(x, y)`,
+    /// in `fg` on `bg`.
+    pub fn draw_glyph( &self, fb: &mut Framebuffer, glyph_idx: u32
+                     , x: u32, y: u32, fg: u32, bg: u32) {
+        let glyph = self.glyph(glyph_idx);
+        let row_bytes = self.row_bytes() as usize;
+        for (dy, row) in glyph.chunks(row_bytes)
+                              .take(self.height as usize)
+                              .enumerate() {
+            for dx in 0 .. self.width {
+                let byte = row[dx as usize / 8];
+                let set = byte & (0x80 >> (dx % 8)) != 0;
+                fb.write_pixel( x + dx, y + dy as u32
+                              , if set { fg } else { bg });
+            }
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Psf2Font<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "Psf2Font({} glyphs, {}x{})"
+              , self.num_glyphs, self.width, self.height)
+    }
+}
@@ -0,0 +1,185 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! LZ4 block compression, for crash dumps.
+//!
+//! This writes the LZ4 block format (without the frame format around it),
+//! so a dump's pages can be unpacked by anything that reads LZ4 blocks. It
+//! makes no attempt to compress well: a page of a crash dump is mostly
+//! zeroes, or not worth compressing at all.
+//!
+//! A block is a series of sequences, each of which is a token, some
+//! literal bytes, and a match: an offset back into the output and a length
+//! to copy from there. The last sequence is literals only, and the last
+//! five bytes of the input are always literals.
+use core::cmp;
+
+/// The shortest match that's encoded.
+const MIN_MATCH: usize = 4;
+/// The number of bytes at the end that must be literals.
+const LAST_LITERALS: usize = 5;
+/// No match may start in this many bytes at the end.
+const MF_LIMIT: usize = 12;
+/// log2 of the number of entries in the hash table.
+const HASH_LOG: usize = 12;
+/// The largest offset a match can have.
+const MAX_OFFSET: usize = 0xffff;
+
+/// The largest input `Compressor::compress` accepts.
+pub const MAX_INPUT: usize = MAX_OFFSET;
+
+/// An LZ4 block compressor.
+///
+/// This holds the hash table of recently seen positions, which is too big
+/// to want on the stack when we're panicking.
+pub struct Compressor { table: [u16; 1 << HASH_LOG] }
+
+#[inline]
+fn read_u32(src: &[u8], i: usize) -> u32 {
+    (src[i] as u32) | (src[i + 1] as u32) << 8
+        | (src[i + 2] as u32) << 16 | (src[i + 3] as u32) << 24
+}
+
+#[inline]
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Writes bytes into a slice, failing once it's full.
+struct Output<'a> { buf: &'a mut [u8]
+                  , pos: usize
+                  }
+
+impl<'a> Output<'a> {
+    #[inline]
+    fn byte(&mut self, byte: u8) -> Option<()> {
+        *self.buf.get_mut(self.pos)? = byte;
+        self.pos += 1;
+        Some(())
+    }
+
+    #[inline]
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.pos.checked_add(bytes.len())?;
+        self.buf.get_mut(self.pos .. end)?.copy_from_slice(bytes);
+        self.pos = end;
+        Some(())
+    }
+
+    /// Write the rest of a length that didn't fit in its token's nibble.
+    fn length(&mut self, mut len: usize) -> Option<()> {
+        while len >= 255 {
+            self.byte(255)?;
+            len -= 255;
+        }
+        self.byte(len as u8)
+    }
+
+    /// Write a sequence: `literals`, then a match of `len` bytes `offset`
+    /// bytes back, if there is one.
+    fn sequence(&mut self, literals: &[u8], matched: Option<(usize, usize)>)
+               -> Option<()> {
+        let lits = literals.len();
+        let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+        let token = cmp::min(lits, 15) << 4 | cmp::min(match_len, 15);
+        self.byte(token as u8)?;
+        if lits >= 15 { self.length(lits - 15)? }
+        self.bytes(literals)?;
+        if let Some((offset, _)) = matched {
+            self.byte(offset as u8)?;
+            self.byte((offset >> 8) as u8)?;
+            if match_len >= 15 { self.length(match_len - 15)? }
+        }
+        Some(())
+    }
+}
+
+impl Compressor {
+    /// Returns a new `Compressor`.
+    pub const fn new() -> Self {
+        Compressor { table: [0; 1 << HASH_LOG] }
+    }
+
+    /// Compress `src` into `dst`, returning the length of the compressed
+    /// block, or `None` if it didn't fit.
+    ///
+    /// # Panics
+    /// + If `src` is longer than `MAX_INPUT`.
+    pub fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> Option<usize> {
+        assert!(src.len() <= MAX_INPUT, "LZ4 input is too long!");
+        // positions are stored plus one, so that 0 is empty.
+        for entry in self.table.iter_mut() { *entry = 0 }
+        let mut out = Output { buf: dst, pos: 0 };
+        let mut anchor = 0;
+        let mut i = 0;
+        if src.len() > MF_LIMIT {
+            let match_limit = src.len() - LAST_LITERALS;
+            while i < src.len() - MF_LIMIT {
+                let sequence = read_u32(src, i);
+                let slot = &mut self.table[hash(sequence)];
+                let candidate = *slot as usize;
+                *slot = (i + 1) as u16;
+                if candidate == 0 || read_u32(src, candidate - 1) != sequence {
+                    i += 1;
+                    continue
+                }
+                let start = candidate - 1;
+                let mut len = MIN_MATCH;
+                while i + len < match_limit && src[start + len] == src[i + len]
+                {
+                    len += 1;
+                }
+                out.sequence(&src[anchor .. i], Some((i - start, len)))?;
+                i += len;
+                anchor = i;
+            }
+        }
+        out.sequence(&src[anchor ..], None)?;
+        Some(out.pos)
+    }
+}
+
+/// Decompress the LZ4 block `src` into `dst`, returning the length of the
+/// decompressed data, or `None` if `src` is malformed or doesn't fit.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let (mut i, mut out) = (0, 0);
+    // reads a length that didn't fit in its token's nibble.
+    let length = |i: &mut usize, mut len: usize| -> Option<usize> {
+        loop {
+            let byte = *src.get(*i)?;
+            *i += 1;
+            len += byte as usize;
+            if byte != 255 { return Some(len) }
+        }
+    };
+    loop {
+        let token = *src.get(i)? as usize;
+        i += 1;
+        let mut lits = token >> 4;
+        if lits == 15 { lits = length(&mut i, lits)? }
+        dst.get_mut(out .. out + lits)?
+           .copy_from_slice(src.get(i .. i + lits)?);
+        i += lits;
+        out += lits;
+        if i == src.len() { return Some(out) }
+
+        let offset = *src.get(i)? as usize | (*src.get(i + 1)? as usize) << 8;
+        i += 2;
+        let mut len = token & 15;
+        if len == 15 { len = length(&mut i, len)? }
+        len += MIN_MATCH;
+        if offset == 0 || offset > out || out + len > dst.len() {
+            return None
+        }
+        // the match may overlap what it's copying, so go a byte at a time.
+        for _ in 0 .. len {
+            dst[out] = dst[out - offset];
+            out += 1;
+        }
+    }
+}
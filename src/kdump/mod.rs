@@ -0,0 +1,312 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Crash dumps.
+//!
+//! At boot, [`init`] reserves a region of physical memory for the dump of
+//! the next panic. When the kernel panics, [`capture`] writes a [`Header`]
+//! to the start of the region (the panic message, some registers, and the
+//! memory map), followed by as many pages of physical memory as fit, each
+//! compressed as an LZ4 block unless that doesn't make it any smaller:
+//!
+//! ```text
+//! | Header | PageRecord | page data | PageRecord | page data | ...
+//! ```
+//!
+//! Memory survives a warm reset, and the frame allocator hands out frames
+//! in address order, so the next boot reserves the same region and finds
+//! the dump still there, if nothing has cleared it since. The magic number
+//! is written last, so a capture that didn't finish doesn't count.
+//!
+//! [`init`]: fn.init.html
+//! [`capture`]: fn.capture.html
+//! [`Header`]: struct.Header.html
+use core::{cmp, mem, ptr, slice};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use cpu::{control_regs, flags};
+use memory::{PAGE_SIZE, PAddr};
+use params::InitParams;
+use paging::arch::space::phys_to_virt;
+use sos_alloc::FrameAllocator;
+use spin::Once;
+use util::bufwriter::BufWriter;
+
+use arch::cpu::{read_rbp, read_rsp};
+use mm::frame;
+use task::timer;
+
+pub mod lz4;
+
+/// The size of the dump region, in bytes.
+pub const DUMP_SIZE: usize = 64 * 1024 * 1024;
+/// Marks a finished dump.
+pub const DUMP_MAGIC: u32 = 0xdead_c0de;
+/// The version of the dump format.
+pub const DUMP_VERSION: u32 = 1;
+
+/// The longest panic message kept, in bytes.
+const MESSAGE_MAX: usize = 512;
+/// The most memory map areas kept.
+const AREAS_MAX: usize = 32;
+/// The size of a page, as a `usize`.
+const PAGE: usize = PAGE_SIZE as usize;
+/// The most an LZ4 block of one page can come to.
+const COMPRESSED_MAX: usize = PAGE + PAGE / 255 + 16;
+
+/// `PageRecord` flag: the page data is an LZ4 block.
+pub const PAGE_COMPRESSED: u32 = 1 << 0;
+
+/// Registers saved in a dump.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CrashRegisters { pub rip: u64
+                          , pub rsp: u64
+                          , pub rbp: u64
+                          , pub rflags: u64
+                          , pub cr0: u64
+                          , pub cr2: u64
+                          , pub cr3: u64
+                          , pub cr4: u64
+                          }
+
+impl CrashRegisters {
+    /// Returns the registers of the function this is inlined into, with the
+    /// address it will return to as `rip`.
+    #[inline(always)]
+    pub fn here() -> Self {
+        let (rsp, rbp) = unsafe { (read_rsp(), read_rbp()) };
+        let rip = if rbp == 0 { 0 }
+                  else { unsafe { *((rbp + 8) as *const usize) } };
+        let crs = control_regs::dump();
+        CrashRegisters { rip: rip as u64
+                       , rsp: rsp as u64
+                       , rbp: rbp as u64
+                       , rflags: flags::read().bits() as u64
+                       , cr0: crs.cr0.bits() as u64
+                       , cr2: crs.cr2 as u64
+                       , cr3: crs.cr3 as u64
+                       , cr4: crs.cr4.bits() as u64
+                       }
+    }
+}
+
+/// An area of the memory map, as saved in a dump.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DumpArea { pub start: u64
+                    , pub end: u64
+                    , pub usable: u64
+                    }
+
+/// The start of a dump.
+#[repr(C)]
+pub struct Header { /// `DUMP_MAGIC`, if the dump is complete
+                    pub magic: u32
+                  , /// `DUMP_VERSION`
+                    pub version: u32
+                  , /// When the kernel panicked, in nanoseconds since boot
+                    pub timestamp_ns: u64
+                  , pub registers: CrashRegisters
+                  , /// The length of `message`
+                    pub message_len: u32
+                  , /// The number of entries in `areas`
+                    pub num_areas: u32
+                  , /// The panic message, and where it came from
+                    pub message: [u8; MESSAGE_MAX]
+                  , /// The memory map
+                    pub areas: [DumpArea; AREAS_MAX]
+                  , /// The number of pages after the header
+                    pub num_pages: u64
+                  , /// The number of bytes of page records after the header
+                    pub data_len: u64
+                  , /// Nonzero if some memory didn't fit in the region
+                    pub truncated: u64
+                  }
+
+impl Header {
+    /// Returns the panic message.
+    pub fn message(&self) -> &str {
+        let len = cmp::min(self.message_len as usize, MESSAGE_MAX);
+        ::core::str::from_utf8(&self.message[..len]).unwrap_or("<invalid>")
+    }
+}
+
+/// Precedes each page of memory in a dump.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct PageRecord { /// The page's physical address
+                        pub paddr: u64
+                      , /// The length of the data that follows
+                        pub len: u32
+                      , /// `PAGE_COMPRESSED`, or 0 if the page is raw
+                        pub flags: u32
+                      }
+
+/// The physical address of the dump region, or 0 if there isn't one.
+static REGION: AtomicUsize = AtomicUsize::new(0);
+/// Set while a dump is being captured, so a panic during a capture doesn't
+/// start another.
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+/// The boot parameters, for the memory map.
+static PARAMS: Once<&'static InitParams> = Once::new();
+
+/// Scratch space for `capture`, which can't use the heap or much stack.
+static mut COMPRESSOR: lz4::Compressor = lz4::Compressor::new();
+static mut COMPRESSED: [u8; COMPRESSED_MAX] = [0; COMPRESSED_MAX];
+
+/// Rounds `n` up to the next multiple of 8.
+#[inline]
+fn align8(n: usize) -> usize { (n + 7) & !7 }
+
+/// Returns the dump at the start of the region at `base`.
+#[inline]
+unsafe fn header_at(base: usize) -> &'static mut Header {
+    &mut *phys_to_virt(PAddr::from(base as u64)).as_mut_ptr::<Header>()
+}
+
+/// Returns the memory map in `params`, as it would be saved in a dump.
+fn areas(params: &InitParams) -> ([DumpArea; AREAS_MAX], usize) {
+    let mut areas = [DumpArea::default(); AREAS_MAX];
+    let mut count = 0;
+    for (slot, area) in areas.iter_mut().zip(params.mem_map()) {
+        *slot = DumpArea { start: *area.start_addr
+                         , end: *area.end_addr
+                         , usable: area.is_usable as u64
+                         };
+        count += 1;
+    }
+    (areas, count)
+}
+
+/// Returns true if the region at `base` holds a complete dump taken on a
+/// machine with the same memory map as this one.
+unsafe fn previous(params: &InitParams, base: usize) -> bool {
+    let header = header_at(base);
+    let (areas, count) = areas(params);
+    header.magic == DUMP_MAGIC
+        && header.version == DUMP_VERSION
+        && header.num_areas as usize == count
+        && header.areas[..count] == areas[..count]
+        && (header.data_len as usize)
+               <= DUMP_SIZE - align8(mem::size_of::<Header>())
+}
+
+/// Reserve the dump region, and capture a dump whenever the kernel panics.
+///
+/// Returns the region's physical address. If the last boot left a dump
+/// there, that's reported.
+///
+/// # Safety
+/// + This must be called once, after the kernel has been remapped, and
+///   before anything else has allocated frames that it didn't allocate
+///   on the last boot.
+pub unsafe fn init(params: &'static InitParams) -> Result<PAddr, &'static str> {
+    let range = frame::allocator().allocate_range(DUMP_SIZE / PAGE)
+                                  .map_err(|_| "not enough memory")?;
+    let base = range.start.base_addr();
+    if previous(params, *base as usize) {
+        let header = header_at(*base as usize);
+        warn!( "previous crash dump available at {:#x}: {} page(s), \
+                panicked at {} ns: {}"
+             , *base, header.num_pages, header.timestamp_ns
+             , header.message());
+    }
+    PARAMS.call_once(|| params);
+    REGION.store(*base as usize, Ordering::Release);
+    ::vga::panic::set_hook(on_panic);
+    Ok(base)
+}
+
+/// The panic hook, which captures a dump of the panic.
+fn on_panic(args: fmt::Arguments, file: &'static str, line: usize) {
+    let registers = CrashRegisters::here();
+    let mut message = [0u8; MESSAGE_MAX];
+    let len = {
+        let mut w = BufWriter::new(&mut message);
+        // `BufWriter` truncates rather than failing.
+        let _ = write!(w, "{}:{}: {}", file, line, args);
+        w.len()
+    };
+    unsafe { capture(&registers, &message[..len]) }
+}
+
+/// Write a dump of all usable physical memory to the dump region, with the
+/// panic message `message` and `registers`.
+///
+/// Does nothing if there's no dump region, or a dump is already being
+/// captured.
+///
+/// # Safety
+/// + Nothing else may run while this does: it's only for when the kernel
+///   has panicked.
+pub unsafe fn capture(registers: &CrashRegisters, message: &[u8]) {
+    let base = REGION.load(Ordering::Acquire);
+    let params = match PARAMS.try() {
+        Some(params) if base != 0 => *params
+      , _ => return
+    };
+    if CAPTURING.swap(true, Ordering::AcqRel) { return }
+
+    let header = header_at(base);
+    // until the magic goes back in, this isn't a dump.
+    ptr::write_volatile(&mut header.magic, 0);
+    header.version = DUMP_VERSION;
+    header.timestamp_ns = timer::now_ns();
+    header.registers = *registers;
+    let len = cmp::min(message.len(), MESSAGE_MAX);
+    header.message[..len].copy_from_slice(&message[..len]);
+    header.message_len = len as u32;
+    let (areas, count) = areas(params);
+    header.areas = areas;
+    header.num_areas = count as u32;
+
+    let data = phys_to_virt(PAddr::from(base as u64)).as_usize()
+             + align8(mem::size_of::<Header>());
+    let end = phys_to_virt(PAddr::from(base as u64)).as_usize() + DUMP_SIZE;
+    let (mut cursor, mut pages, mut truncated) = (data, 0, false);
+    'areas: for area in params.mem_map().filter(|area| area.is_usable) {
+        let first = (*area.start_addr as usize + PAGE - 1) / PAGE;
+        let last = *area.end_addr as usize / PAGE;
+        for number in first .. last {
+            let paddr = number * PAGE;
+            // don't dump the dump.
+            if paddr >= base && paddr < base + DUMP_SIZE { continue }
+            let page = slice::from_raw_parts(
+                phys_to_virt(PAddr::from(paddr as u64)).as_ptr::<u8>(), PAGE);
+            let (bytes, flags) =
+                match COMPRESSOR.compress(page, &mut COMPRESSED) {
+                    Some(len) if len < PAGE =>
+                        (&COMPRESSED[..len], PAGE_COMPRESSED)
+                  , _ => (page, 0)
+                };
+            let size = align8(mem::size_of::<PageRecord>() + bytes.len());
+            if cursor + size > end {
+                truncated = true;
+                break 'areas
+            }
+            *(cursor as *mut PageRecord)
+                = PageRecord { paddr: paddr as u64
+                             , len: bytes.len() as u32
+                             , flags: flags
+                             };
+            let dst = slice::from_raw_parts_mut(
+                (cursor + mem::size_of::<PageRecord>()) as *mut u8
+              , bytes.len());
+            dst.copy_from_slice(bytes);
+            cursor += size;
+            pages += 1;
+        }
+    }
+    header.num_pages = pages;
+    header.data_len = (cursor - data) as u64;
+    header.truncated = truncated as u64;
+    ptr::write_volatile(&mut header.magic, DUMP_MAGIC);
+    CAPTURING.store(false, Ordering::Release);
+}
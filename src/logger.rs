@@ -6,80 +6,176 @@
 //  Released under the terms of the MIT license. See `LICENSE` in the root
 //  directory of this repository for more information.
 //
-use log;
-use log::{LogRecord, LogLevel, LogMetadata, LogLevelFilter};
-use arch::drivers::serial;
+//! Kernel logging.
+//!
+//! When the `logging` feature is enabled, the `log` crate's macros are
+//! backed by [`KernelLogger`](struct.KernelLogger.html). Every record is
+//! written to `COM1` and kept in the [`syslog`](../syslog/index.html) ring;
+//! errors and warnings are also echoed to the VGA console in red and
+//! yellow, respectively, so they can't be missed.
+//!
+//! When `logging` is disabled, the `log` macros compile to nothing.
+#[cfg(feature = "logging")]
+pub use self::imp::*;
 
-use core::fmt::Write;
+#[cfg(feature = "logging")]
+mod imp {
+    use log;
+    use log::{Record, Level, Metadata, LevelFilter};
+    use arch::drivers::serial;
+    use vga::{Color, CONSOLE};
+    use util::bufwriter::BufWriter;
+    use cpu::tsc;
+    use syslog;
 
-struct SerialLogger;
+    use core::fmt::Write;
 
-pub fn initialize() -> Result<(), log::SetLoggerError> {
-    unsafe {
-        log::set_logger_raw(|max_log_level| {
-            max_log_level.set(LogLevelFilter::Trace);
-            &SerialLogger
-        })
-    }
-}
-pub fn shutdown() -> Result<(), log::ShutdownLoggerError> {
-    log::shutdown_logger_raw().map(|_logger| {
-    })
-}
+    /// Size of the stack buffer each log line is formatted into.
+    ///
+    /// Lines longer than this are truncated.
+    const LINE_MAX: usize = 256;
 
+    /// The maximum level of messages that will be logged at boot.
+    ///
+    /// This can be changed later with [`set_level`](fn.set_level.html).
+    #[cfg(debug_assertions)]
+    const MAX_LEVEL: LevelFilter = LevelFilter::Trace;
+    #[cfg(not(debug_assertions))]
+    const MAX_LEVEL: LevelFilter = LevelFilter::Info;
 
-#[cfg(debug_assertions)]
-impl log::Log for SerialLogger {
+    static LOGGER: KernelLogger = KernelLogger;
 
-    #[inline] fn enabled(&self, _metadata: &LogMetadata) -> bool {
-        true // TODO: for now?
-    }
+    /// The kernel's `log::Log` implementation.
+    ///
+    /// Formatting is done into a fixed-size buffer on the stack, so the
+    /// logger never touches the heap and may be used before the heap is
+    /// initialized.
+    pub struct KernelLogger;
 
-    #[inline]
-    fn log(&self, record: &LogRecord) {
-        let meta = record.metadata();
-        match record.level() {
-            LogLevel::Trace if self.enabled(meta) => {
-                let location = record.location();
-                let _ = write!( *serial::COM1.lock()
-                              , "[ TRACE ][ {}:{} ] {}: {}\n"
-                              , location.module_path(), location.line()
-                              , meta.target()
-                              , record.args() );
-            }
-          , LogLevel::Debug if self.enabled(meta) => {
-                let _ = write!( *serial::COM1.lock()
-                              , "[ DEBUG ] {}: {}\n"
-                              , meta.target()
-                              , record.args() );
-            }
-          , level => {
-                let target = meta.target();
-                let args = record.args();
-                let _ = write!( *serial::COM1.lock()
-                              , "[ {} ] {}: {}\n"
-                              , level, target, args );
-                // println!("{}: {}", target, args );
-            }
+    impl KernelLogger {
+        /// Install the `KernelLogger` as the global logger.
+        pub fn init() -> Result<(), log::SetLoggerError> {
+            log::set_logger(&LOGGER)
+                .map(|()| log::set_max_level(MAX_LEVEL))
         }
     }
 
-}
+    /// Returns the maximum level of messages that will be logged, from 0
+    /// (nothing) to 5 (everything, down to `trace`).
+    pub fn level() -> u64 { log::max_level() as usize as u64 }
 
-#[cfg(not(debug_assertions))]
-impl log::Log for SerialLogger {
+    /// Set the maximum level of messages that will be logged, from 0
+    /// (nothing) to 5 (everything, down to `trace`).
+    ///
+    /// Levels above 5 are taken as 5. Levels more verbose than the `log`
+    /// crate was compiled to allow are still filtered out by its macros.
+    pub fn set_level(level: u64) {
+        let filter = match level {
+            0 => LevelFilter::Off
+          , 1 => LevelFilter::Error
+          , 2 => LevelFilter::Warn
+          , 3 => LevelFilter::Info
+          , 4 => LevelFilter::Debug
+          , _ => LevelFilter::Trace
+        };
+        log::set_max_level(filter)
+    }
 
-    #[inline] fn enabled(&self, metadata: &LogMetadata) -> bool {
-        false // TODO: for now
+    /// Returns the syslog priority for `level`.
+    fn priority(level: Level) -> u8 {
+        match level {
+            Level::Error => 3
+          , Level::Warn => 4
+          , Level::Info => 6
+          , Level::Debug | Level::Trace => 7
+        }
     }
 
-    #[inline]
-    fn log(&self, record: &LogRecord) {
-        let meta = record.metadata();
-        if self.enabled(meta) {
-            println!("{}: {}", target, args );
-            // TODO: should we log for com1 also?
+    impl log::Log for KernelLogger {
+
+        #[inline] fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= log::max_level()
+        }
+
+        fn log(&self, record: &Record) {
+            if !self.enabled(record.metadata()) { return }
+
+            let mut buf = [0u8; LINE_MAX];
+            let mut w = BufWriter::new(&mut buf);
+            // `BufWriter` truncates rather than failing, so the result can
+            // safely be ignored.
+            let _ = write!( w, "[{:>16}][ {:<5} ][ {} ] {}"
+                          , tsc::current_ns()
+                          , record.level()
+                          , record.module_path()
+                                  .unwrap_or_else(|| record.target())
+                          , record.args() );
+            let line = w.as_str();
+
+            {
+                // suppress errors because we don't care if there's no serial
+                // port
+                let mut com1 = serial::COM1.lock();
+                let _ = com1.write_str(line);
+                let _ = com1.write_char('\n');
+            }
+            syslog::log( priority(record.level())
+                       , format_args!( "{}: {}"
+                                     , record.module_path()
+                                             .unwrap_or_else(|| record.target())
+                                     , record.args()));
+
+            let color = match record.level() {
+                Level::Error => Color::Red
+              , Level::Warn => Color::Yellow
+              , _ => return
+            };
+            let mut console = CONSOLE.lock();
+            let palette = console.palette();
+            console.set_palette(palette.set_foreground(color));
+            let _ = console.write_str(line);
+            let _ = console.write_char('\n');
+            console.set_palette(palette);
         }
+
+        #[inline] fn flush(&self) { }
+
     }
+}
 
+/// Stand-ins for the `log` crate's macros when the `logging` feature is
+/// disabled.
+///
+/// The arguments are still type-checked, but nothing is ever formatted.
+#[cfg(not(feature = "logging"))]
+#[macro_use]
+mod disabled {
+    macro_rules! log {
+        (target: $target:expr, $lvl:expr, $($arg:tt)+) => ({
+            let _ = $target;
+            let _ = $lvl;
+            if false { let _ = format_args!($($arg)+); }
+        });
+        ($lvl:expr, $($arg:tt)+) => (log!(target: "", $lvl, $($arg)+));
+    }
+    macro_rules! error {
+        (target: $target:expr, $($arg:tt)+) => (log!(target: $target, (), $($arg)+));
+        ($($arg:tt)+) => (log!((), $($arg)+));
+    }
+    macro_rules! warn {
+        (target: $target:expr, $($arg:tt)+) => (log!(target: $target, (), $($arg)+));
+        ($($arg:tt)+) => (log!((), $($arg)+));
+    }
+    macro_rules! info {
+        (target: $target:expr, $($arg:tt)+) => (log!(target: $target, (), $($arg)+));
+        ($($arg:tt)+) => (log!((), $($arg)+));
+    }
+    macro_rules! debug {
+        (target: $target:expr, $($arg:tt)+) => (log!(target: $target, (), $($arg)+));
+        ($($arg:tt)+) => (log!((), $($arg)+));
+    }
+    macro_rules! trace {
+        (target: $target:expr, $($arg:tt)+) => (log!(target: $target, (), $($arg)+));
+        ($($arg:tt)+) => (log!((), $($arg)+));
+    }
 }
@@ -29,8 +29,14 @@
           , slice_patterns
           , associated_consts
           , type_ascription
-          , custom_derive )]
+          , custom_derive
+          , const_ptr_null_mut )]
 #![feature(alloc)]
+#![feature(unique)]
+#![feature(global_asm)]
+#![feature(compiler_fences)]
+#![feature(integer_atomics)]
+#![feature(repr_align, attr_literals)]
 
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
@@ -43,9 +49,10 @@
 // -- non-SOS dependencies --------------------------------------------------
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate bitflags;
+#[cfg(feature = "logging")]
 #[macro_use] extern crate log;
 
-extern crate alloc;
+#[macro_use] extern crate alloc;
 extern crate rlibc;
 extern crate spin;
 
@@ -53,6 +60,7 @@ extern crate spin;
 #[macro_use] extern crate vga;
 
 extern crate sos_alloc;
+extern crate sos_intrusive;
 extern crate cpu;
 extern crate elf;
 extern crate paging;
@@ -60,13 +68,37 @@ extern crate params;
 extern crate memory;
 extern crate util;
 
+// must come first, so that the stand-in `log` macros are in scope for the
+// rest of the crate when the `logging` feature is disabled.
+#[macro_use] pub mod logger;
 #[macro_use] pub mod io;
 
 pub mod heap;
-pub mod arch;
-pub mod logger;
+#[macro_use] pub mod arch;
+pub mod dev;
+pub mod fs;
+pub mod kdump;
+pub mod mm;
+pub mod module;
+pub mod net;
+pub mod perf;
+pub mod phase;
+pub mod shell;
+pub mod syscall;
+pub mod sysctl;
+pub mod syslog;
+pub mod task;
+pub mod trace;
+pub mod watchdog;
+
+#[cfg(feature = "qemu-test")]
+pub mod qemu_runner;
 
 use params::InitParams;
+use spin::Once;
+
+/// The init params passed to `kernel_init()`.
+static INIT_PARAMS: Once<InitParams> = Once::new();
 
 /// SOS version number
 pub const VERSION_STRING: &'static str
@@ -85,7 +117,9 @@ pub fn kernel_main() -> ! {
     // let mut frame_allocator = frame_alloc::FrameAllocator::new();
     // paging::test_paging(&mut frame_allocator);
 
-    loop { }
+    // there's no preemption, so task 0 has to keep giving up the CPU for
+    // anything else (such as the debug shell) to run.
+    loop { task::sched::schedule() }
 }
 
 /// Kernel initialization function called into by architecture-specific init
@@ -112,14 +146,18 @@ pub fn kernel_main() -> ! {
 /// +---------------------------------------------------------------+
 /// ```
 pub fn kernel_init(params: &InitParams) {
-    use sos_alloc::frame::mem_map::MemMapAllocator;
     use ::paging::kernel_remap;
 
     kinfoln!("Hello from the kernel!");
     // kinfoln!("Got init params: {:#?}", params );
 
+    // the frame allocator borrows the memory map for the lifetime of the
+    // kernel, so stash a copy of the params somewhere permanent.
+    let params: &'static InitParams = INIT_PARAMS.call_once(|| params.clone());
+    mm::frame::init(params);
+
     // -- remap the kernel ----------------------------------------------------
-    let mut frame_allocator = MemMapAllocator::from(params);
+    let mut frame_allocator = mm::frame::allocator();
     kinfoln!(dots: " . ", "Remapping the kernel...");
     let page_table = match kernel_remap(&params, &mut frame_allocator) {
         Ok(p) => {
@@ -131,6 +169,16 @@ pub fn kernel_init(params: &InitParams) {
             panic!( "Could not remap kernel: {:?}", why)
         }
     };
+    unsafe { mm::frame::init_refcounts(params) };
+
+    // -- reserve memory for crash dumps -------------------------------------
+    // this has to come before anything else takes frames, so that it gets
+    // the same region on every boot.
+    match unsafe { kdump::init(params) } {
+        Ok(base) => kinfoln!( dots: " . ", "Crash dumps go to {:#x}."
+                            , *base)
+      , Err(why) => kinfoln!(dots: " . ", "No crash dumps: {}", why)
+    }
 
     attempt!(paging::test_paging(&mut frame_allocator) =>
              dots: " . . ", "Testing paging...");
@@ -142,15 +190,107 @@ pub fn kernel_init(params: &InitParams) {
             , "Heap begins at {:#x} and ends at {:#x}"
             , params.heap_base, params.heap_top);
 
-
     // -- initialize interrupts ----------------------------------------------
-    // attempt!( unsafe { arch::interrupts::initialize() } =>
-    //           "Initializing interrupts...", dots: " . " );
+    // page faults have to be handled before any user task runs, and device
+    // drivers and the watchdog need their IRQs.
+    attempt!( unsafe { arch::interrupts::initialize() } =>
+              dots: " . ", "Initializing interrupts...");
+
+    // -- register kernel parameters -----------------------------------------
+    sysctl::init();
+
+    // -- mount the root file system -----------------------------------------
+    attempt!( fs::init_root() =>
+              dots: " . ", "Mounting tmpfs as root file system...");
+
+    // -- unpack the initial ramdisk -----------------------------------------
+    match fs::cpio::mount_initrd(params) {
+        Ok(0) => kinfoln!(dots: " . ", "No initial ramdisk.")
+      , Ok(n) => kinfoln!( dots: " . "
+                         , "Mounted initial ramdisk ({} entries).", n)
+      , Err(why) => warn!("could not mount initial ramdisk: {:?}", why)
+    }
+
+    // -- become task 0 -------------------------------------------------------
+    unsafe { task::init(); }
+    kinfoln!(dots: " . ", "Boot thread is now task 0.");
+    let pid = task::workqueue::init();
+    kinfoln!(dots: " . ", "Work queue running as task {}.", pid);
+    let pid = mm::swap::init();
+    kinfoln!(dots: " . ", "kswapd running as task {}.", pid);
+
+    // -- start the debug shell ---------------------------------------------
+    match shell::spawn() {
+        Some(pid) => kinfoln!( dots: " . "
+                             , "Debug shell running on COM1 as task {}.", pid)
+      , None => kinfoln!(dots: " . ", "No COM1, so no debug shell.")
+    }
+
+    // -- start the other CPUs ------------------------------------------------
+    kinfoln!(dots: " . ", "Starting application processors...");
+    let cpus = unsafe { arch::smp::init() };
+    kinfoln!(dots: " . . ", "{} CPU(s) online.", cpus);
+    let topology = unsafe { arch::topology::init() };
+    kinfoln!(dots: " . . ", "{}.", topology);
+
+    // -- read the ACPI tables ----------------------------------------------
+    kinfoln!(dots: " . ", "Reading ACPI tables...");
+    match unsafe { arch::acpi::init() } {
+        Ok(()) => kinfoln!(dots: " . . ", "ACPI power-off is available.")
+      , Err(why) => kinfoln!(dots: " . . ", "No ACPI power-off: {}", why)
+    }
+    match unsafe { arch::numa::init() } {
+        Ok(nodes) => kinfoln!(dots: " . . ", "{} NUMA node(s).", nodes)
+      , Err(why) => kinfoln!(dots: " . . ", "No NUMA topology: {}", why)
+    }
+    #[cfg(feature = "iommu")]
+    match unsafe { arch::iommu::init() } {
+        Ok(()) => kinfoln!(dots: " . . ", "DMA remapping is on.")
+      , Err(why) => kinfoln!(dots: " . . ", "No DMA remapping: {}", why)
+    }
+
+    // -- start the watchdog -------------------------------------------------
+    kinfoln!(dots: " . ", "Starting the watchdog...");
+    match unsafe { arch::hpet::init() }.and_then(|_| watchdog::init()) {
+        Ok(()) => kinfoln!( dots: " . . ", "Watchdog checks every {} s."
+                          , watchdog::CHECK_INTERVAL_NS / 1_000_000_000)
+      , Err(why) => kinfoln!(dots: " . . ", "No watchdog: {}", why)
+    }
+
+    // -- find the framebuffer ------------------------------------------------
+    kinfoln!(dots: " . ", "Looking for a framebuffer...");
+    dev::framebuffer::init(params);
+
+    // -- find the performance counters --------------------------------------
+    perf::init(params);
+    match perf::counter::detect() {
+        Some(pmu) => kinfoln!( dots: " . "
+                             , "PMU version {}: {} {}-bit counters."
+                             , pmu.version, pmu.counters, pmu.width)
+      , None => kinfoln!(dots: " . ", "No performance counters.")
+    }
+
+    // -- find devices --------------------------------------------------------
+    kinfoln!(dots: " . ", "Probing PCI devices...");
+    dev::init();
+
+    // -- start the network stack ---------------------------------------------
+    match net::init() {
+        Some(pid) => kinfoln!( dots: " . ", "Network up as {}, netd is task {}."
+                             , net::config().map_or(Default::default()
+                                                   , |config| config.ip)
+                             , pid)
+      , None => kinfoln!(dots: " . ", "No network card, so no network.")
+    }
 
     println!("\n{} {}-bit\n", VERSION_STRING, arch::ARCH_BITS);
 
+    // -- run the self-tests, if this is a test build ------------------------
+    #[cfg(feature = "qemu-test")]
+    qemu_runner::run();
+
     // -- call into kernel main loop ------------------------------------------
-    // (currently, this does nothing)
+    phase::advance_phase(phase::KernelPhase::FullyOperational);
     kernel_main()
 }
 
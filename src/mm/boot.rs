@@ -0,0 +1,113 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Frame allocation for boot-time code.
+//!
+//! Early initialization (mapping the local APIC, say) needs a few frames,
+//! but doesn't care which allocator they come from: that depends on how far
+//! boot has got. Such code takes a `&mut BootAllocator`, which any frame
+//! allocator can be passed as, and so can the [`Memblock`], before there is
+//! a frame allocator.
+//!
+//! The paging code still wants a `FrameAllocator`, so [`BootFrames`] wraps a
+//! `BootAllocator` back up as one.
+//!
+//! [`Memblock`]: ../memblock/struct.Memblock.html
+//! [`BootFrames`]: struct.BootFrames.html
+use memory::{FrameRange, MemRange, PAGE_SIZE, Page, PAddr, PhysicalPage};
+use sos_alloc::{AllocErr, AllocResult, FrameAllocator, Layout};
+
+use super::memblock::Memblock;
+
+/// A source of physical frames for boot-time code.
+///
+/// Unlike `FrameAllocator`, this can be used as a trait object, so code
+/// that takes one needn't be generic over where its frames come from.
+pub trait BootAllocator {
+    /// Allocate `count` contiguous frames, returning the address of the
+    /// first, or `None` if there aren't enough.
+    unsafe fn alloc_frames(&mut self, count: usize) -> Option<PAddr>;
+
+    /// Free the `count` frames starting at `base`, which must have come
+    /// from `alloc_frames` on this allocator.
+    unsafe fn free_frames(&mut self, base: PAddr, count: usize);
+}
+
+impl<A> BootAllocator for A
+where A: FrameAllocator {
+    unsafe fn alloc_frames(&mut self, count: usize) -> Option<PAddr> {
+        let frames = if count == 1 {
+            self.allocate().map(|frame| frame.range_of(1))
+        } else {
+            self.allocate_range(count)
+        };
+        frames.ok().map(|range| range.start.base_addr())
+    }
+
+    unsafe fn free_frames(&mut self, base: PAddr, count: usize) {
+        let first = PhysicalPage::containing(base);
+        if count == 1 {
+            self.deallocate(first)
+        } else {
+            self.deallocate_range(first.range_of(count))
+        }
+    }
+}
+
+impl BootAllocator for Memblock {
+    /// Take `count` frames from the early boot buffer.
+    ///
+    /// The buffer is in the identity-mapped kernel image, so its addresses
+    /// are physical addresses.
+    unsafe fn alloc_frames(&mut self, count: usize) -> Option<PAddr> {
+        let size = count.checked_mul(PAGE_SIZE as usize)?;
+        let block = self.alloc(size, PAGE_SIZE as usize);
+        if block.is_null() {
+            None
+        } else {
+            Some(PAddr::from(block))
+        }
+    }
+
+    /// Early boot memory is never freed, so this does nothing.
+    unsafe fn free_frames(&mut self, _base: PAddr, _count: usize) { }
+}
+
+/// A `BootAllocator`, as a `FrameAllocator`.
+pub struct BootFrames<'a>(pub &'a mut BootAllocator);
+
+/// The error for running out of frames.
+fn exhausted(count: usize) -> AllocErr {
+    AllocErr::Exhausted {
+        request: Layout::from_size_align( count * PAGE_SIZE as usize
+                                        , PAGE_SIZE as usize)
+    }
+}
+
+impl<'a> FrameAllocator for BootFrames<'a> {
+    unsafe fn allocate(&mut self) -> AllocResult<PhysicalPage> {
+        self.0.alloc_frames(1)
+            .map(PhysicalPage::containing)
+            .ok_or_else(|| exhausted(1))
+    }
+
+    unsafe fn deallocate(&mut self, frame: PhysicalPage) {
+        self.0.free_frames(frame.base_addr(), 1)
+    }
+
+    unsafe fn allocate_range(&mut self, num: usize)
+                            -> AllocResult<FrameRange> {
+        self.0.alloc_frames(num)
+            .map(|base| PhysicalPage::containing(base).range_of(num))
+            .ok_or_else(|| exhausted(num))
+    }
+
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        self.0.free_frames(range.start.base_addr(), range.length())
+    }
+}
@@ -0,0 +1,150 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Memory for device DMA.
+//!
+//! Devices address memory physically, so anything they read or write must
+//! live in physically contiguous frames whose address we know. A
+//! [`DmaBox`](struct.DmaBox.html) owns such frames, and the kernel accesses
+//! them through the physical memory map.
+//!
+//! The box holds a reference to each of its frames, like anything else
+//! that uses them. A driver whose device may still be using the frames
+//! after the box has been dropped, such as a request it has given up on,
+//! should take a reference of its own with
+//! [`frame_inc_ref`](../frame/fn.frame_inc_ref.html), and free the frame
+//! once the device is done with it. Until then, the frames won't be handed
+//! out again.
+use core::{cmp, fmt, mem, ptr, slice};
+use core::ops::{Deref, DerefMut};
+use memory::{FrameRange, MemRange, PAddr, PAGE_SIZE};
+use paging::arch::space::phys_to_virt;
+use sos_alloc::{AllocErr, AllocResult, FrameAllocator};
+
+use arch::memops;
+use super::frame;
+
+/// An owned `T` in physically contiguous memory.
+///
+/// The `T` always starts at the beginning of a frame.
+pub struct DmaBox<T: ?Sized> { frames: FrameRange
+                             , ptr: *mut T
+                             }
+
+// the frames belong to the box, so it may be sent wherever a `T` may.
+unsafe impl<T: ?Sized + Send> Send for DmaBox<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for DmaBox<T> {}
+
+/// Allocate enough physically contiguous frames for `size` bytes, filled
+/// with zeroes, and return them and a pointer to their start.
+fn allocate_zeroed(size: usize) -> AllocResult<(FrameRange, *mut u8)> {
+    let page_size = PAGE_SIZE as usize;
+    // even an empty box gets a frame, so that it has an address.
+    let num_frames = cmp::max((size + page_size - 1) / page_size, 1);
+    let frames = frame::allocator().allocate_range(num_frames)?;
+    let ptr = phys_to_virt(frames.start.base_addr()).as_mut_ptr::<u8>();
+    unsafe { memops::fast_memset(ptr, 0, num_frames * page_size) }
+    Ok((frames, ptr))
+}
+
+impl<T> DmaBox<T> {
+    /// Allocate physically contiguous memory for a `T`, filled with zeroes.
+    ///
+    /// # Safety
+    /// + All zeroes must be a valid `T`.
+    pub unsafe fn zeroed() -> AllocResult<Self> {
+        let (frames, ptr) = allocate_zeroed(mem::size_of::<T>())?;
+        Ok(DmaBox { frames: frames, ptr: ptr as *mut T })
+    }
+
+    /// Move `value` into physically contiguous memory.
+    pub fn new(value: T) -> AllocResult<Self> {
+        unsafe {
+            let dma = Self::zeroed()?;
+            ptr::write(dma.ptr, value);
+            Ok(dma)
+        }
+    }
+}
+
+impl<T: Default> DmaBox<[T]> {
+    /// Allocate physically contiguous memory for `len` `T`s, each set to
+    /// `T::default()`.
+    ///
+    /// This is the usual way to make a descriptor ring.
+    pub fn new_slice(len: usize) -> AllocResult<Self> {
+        let size = mem::size_of::<T>().checked_mul(len)
+                                      .ok_or(AllocErr::invalid_input(
+                                          "DMA slice is too large"))?;
+        let (frames, ptr) = allocate_zeroed(size)?;
+        let elems = ptr as *mut T;
+        for i in 0 .. len {
+            unsafe { ptr::write(elems.offset(i as isize), T::default()) }
+        }
+        let ptr = unsafe { slice::from_raw_parts_mut(elems, len) };
+        Ok(DmaBox { frames: frames, ptr: ptr as *mut [T] })
+    }
+}
+
+impl<T: ?Sized> DmaBox<T> {
+    /// Returns the physical address of the `T`, for handing to a device.
+    #[inline]
+    pub fn paddr(&self) -> PAddr {
+        self.frames.start.base_addr()
+    }
+
+    /// Returns the physical address of a field or element of the `T`,
+    /// given a reference to it.
+    ///
+    /// # Panics
+    /// + If `part` doesn't point into this box.
+    #[inline]
+    pub fn paddr_of<U>(&self, part: &U) -> PAddr {
+        let offset = (part as *const U as usize)
+            .checked_sub(self.ptr as *const u8 as usize)
+            .expect("reference is not inside this DmaBox!");
+        assert!( offset + mem::size_of::<U>() <= mem::size_of_val(&**self)
+               , "reference is not inside this DmaBox!");
+        self.paddr() + offset as u64
+    }
+
+    /// Returns a raw pointer to the `T`.
+    #[inline] pub fn as_ptr(&self) -> *const T { self.ptr }
+
+    /// Returns a mutable raw pointer to the `T`, for accesses that the
+    /// compiler mustn't reorder or elide, such as volatile ones.
+    #[inline] pub fn as_mut_ptr(&mut self) -> *mut T { self.ptr }
+}
+
+impl<T: ?Sized> Deref for DmaBox<T> {
+    type Target = T;
+    #[inline] fn deref(&self) -> &T { unsafe { &*self.ptr } }
+}
+
+impl<T: ?Sized> DerefMut for DmaBox<T> {
+    #[inline] fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.ptr } }
+}
+
+impl<T: ?Sized> Drop for DmaBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr);
+            let frames = self.frames.start .. self.frames.end;
+            frame::allocator().deallocate_range(frames);
+        }
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for DmaBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DmaBox")
+         .field("paddr", &self.paddr())
+         .field("frames", &self.frames.length())
+         .finish()
+    }
+}
@@ -0,0 +1,247 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Page faults on user memory.
+//!
+//! `mmap` and `brk` only add regions to a task's
+//! [`VmMap`](../vm/struct.VmMap.html); no frames are allocated until a page
+//! is first touched. The page fault that follows comes here, and the page is
+//! given a zeroed frame, mapped with its region's permissions, so the
+//! faulting instruction can be retried.
+//!
+//! A page that [`swap`](../swap/index.html) has written out is read back
+//! into a new frame, and mapped again the same way. So is a page of a
+//! file mapping, from its file, the first time it's touched.
+//!
+//! Pages shared copy-on-write by `fork` are mapped read-only, even in
+//! writable regions. The first write to one faults, and comes here too:
+//! the writer gets a copy of the page, unless nobody else has it mapped any
+//! more, in which case it can just have it back.
+//!
+//! A fault on an address outside every region, or an access its region
+//! doesn't allow, is the task's own fault, and kills it.
+use alloc::arc::Arc;
+use core::slice;
+use memory::{PAGE_SIZE, Page, VAddr, VirtualPage};
+use paging::arch::ActivePageTable;
+use paging::arch::space::phys_to_virt;
+use paging::arch::table::{COPY_ON_WRITE, PRESENT, WRITABLE};
+use paging::arch::tlb::Flush;
+use sos_alloc::FrameAllocator;
+
+use arch::memops;
+use dev::block::BlockDevice;
+use fs::Inode;
+use perf::{self, PerfEvent};
+use task;
+use super::{frame, is_user_range, map_user_page, pte_flags, swap};
+use super::vm::{VmBacking, VmFlags, VM_EXEC, VM_READ, VM_WRITE};
+
+/// The signal number of a segmentation fault.
+pub const SIGSEGV: u8 = 11;
+
+/// The kind of access that faulted.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Access { Read, Write, Execute }
+
+impl Access {
+    /// Returns true if a region with `flags` allows this access.
+    ///
+    /// Pages can't be made writable or executable without also being
+    /// readable, so any region that allows some access allows reads.
+    #[inline]
+    fn allowed_by(&self, flags: VmFlags) -> bool {
+        match *self {
+            Access::Read => flags.intersects(VM_READ | VM_WRITE | VM_EXEC)
+          , Access::Write => flags.contains(VM_WRITE)
+          , Access::Execute => flags.contains(VM_EXEC)
+        }
+    }
+}
+
+/// What became of a page fault on user memory.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultResult {
+    /// The page was mapped, and the access may be retried.
+    Handled
+  , /// The address isn't in any of the task's regions.
+    Unmapped
+  , /// The address is in a region that doesn't allow the access.
+    Denied
+  , /// There was no frame to map the page to.
+    OutOfMemory
+  , /// The page couldn't be read back in from swap.
+    SwapError
+  , /// The page couldn't be read in from the file it maps.
+    ReadError
+}
+
+/// Handle a fault on the user address `addr` in the current task.
+///
+/// `present` is true if the page was already mapped, and the fault was
+/// caused by the page's permissions.
+pub fn handle_user_fault(addr: VAddr, access: Access, present: bool)
+                        -> FaultResult {
+    perf::increment(PerfEvent::PageFaults);
+    if !is_user_range(addr, 1) { return FaultResult::Unmapped }
+    let task = unsafe { task::current() };
+    let region = match task.vm.find(addr) {
+        Some(region) => region
+      , None => return FaultResult::Unmapped
+    };
+    let flags = region.flags;
+    if !access.allowed_by(flags) { return FaultResult::Denied }
+    let page = VirtualPage::containing(addr);
+    if present {
+        // other than copy-on-write pages, a present page already has all
+        // the permissions its region gives it.
+        let result = if access == Access::Write { break_cow(page) }
+                     else { FaultResult::Denied };
+        if result == FaultResult::Handled {
+            perf::increment(PerfEvent::MinorFaults);
+        }
+        return result
+    }
+
+    if let VmBacking::Swap { ref device, block_offset } = region.backing {
+        let slot = unsafe { ActivePageTable::new() }
+            .entry_mut(page)
+            .and_then(|entry| entry.swap_slot());
+        if let Some(slot) = slot {
+            return swap_in(page, slot, device, block_offset, flags)
+        }
+    }
+
+    if let VmBacking::File { ref inode, offset } = region.backing {
+        let offset = offset + (page.base().as_usize()
+                               - region.start.as_usize()) as u64;
+        return file_in(page, &**inode, offset, flags)
+    }
+
+    match map_user_page(page, flags) {
+        Ok(frame) => {
+            trace!( "task {}: demand paged {:?} at {:?} to {:?}"
+                  , task.pid, access, addr, frame);
+            perf::increment(PerfEvent::MinorFaults);
+            FaultResult::Handled
+        }
+      , Err(err) => {
+            trace!( "task {}: couldn't demand page {:?}: {:?}"
+                  , task.pid, addr, err);
+            FaultResult::OutOfMemory
+        }
+    }
+}
+
+/// Read `page` back in from swap slot `slot`, in the swap area at
+/// `block_offset` on `device`, and map it with its region's `flags`.
+fn swap_in( page: VirtualPage, slot: u64, device: &Arc<BlockDevice>
+          , block_offset: u64, flags: VmFlags)
+          -> FaultResult {
+    let offset = match swap::slot_offset(device, block_offset, slot) {
+        Some(offset) => offset
+      , None => return FaultResult::SwapError
+    };
+    let mut frames = frame::allocator();
+    let frame = match unsafe { frames.allocate() } {
+        Ok(frame) => frame
+      , Err(_) => return FaultResult::OutOfMemory
+    };
+    if let Err(err) = swap::read_page(&**device, offset, frame) {
+        trace!("{:?}: couldn't read swap slot {}: {:?}", page, slot, err);
+        unsafe { frames.deallocate(frame) }
+        return FaultResult::SwapError
+    }
+    // nothing else changes our page tables while we wait for the disk, so
+    // the entry is still the swapped one.
+    let mut table = unsafe { ActivePageTable::new() };
+    match table.entry_mut(page) {
+        Some(entry) => entry.set(frame, pte_flags(flags) | PRESENT)
+      , None => unreachable!("page table vanished during swap in!")
+    }
+    swap::free_slot(slot);
+    trace!("{:?}: swapped in from slot {} to {:?}", page, slot, frame);
+    perf::increment(PerfEvent::MajorFaults);
+    FaultResult::Handled
+}
+
+/// Map `page` to a new frame holding the page of `inode` at `offset`,
+/// with its region's `flags`.
+///
+/// Whatever of the page lies past the end of the file is zeroed.
+fn file_in(page: VirtualPage, inode: &Inode, offset: u64, flags: VmFlags)
+          -> FaultResult {
+    let frame = match map_user_page(page, flags) {
+        Ok(frame) => frame
+      , Err(_) => return FaultResult::OutOfMemory
+    };
+    let buf = unsafe {
+        slice::from_raw_parts_mut( phys_to_virt(frame.base_addr()).as_mut_ptr()
+                                 , PAGE_SIZE as usize)
+    };
+    // tmpfs reads as much as there is in one go; a short read is the end
+    // of the file, and the rest of the frame is already zero.
+    if let Err(err) = inode.read_at(offset, buf) {
+        trace!( "{:?}: couldn't read file offset {:#x}: {:?}"
+              , page, offset, err);
+        return FaultResult::ReadError
+    }
+    trace!("{:?}: read in file offset {:#x} to {:?}", page, offset, frame);
+    perf::increment(PerfEvent::MajorFaults);
+    FaultResult::Handled
+}
+
+/// Give the current task its own, writable copy of the copy-on-write
+/// `page`.
+fn break_cow(page: VirtualPage) -> FaultResult {
+    let mut table = unsafe { ActivePageTable::new() };
+    let entry = match table.entry_mut(page) {
+        Some(entry) => entry
+      , None => return FaultResult::Denied
+    };
+    let flags = entry.flags();
+    let old = match entry.get_frame() {
+        Some(frame) if flags.contains(COPY_ON_WRITE) => frame
+      , _ => return FaultResult::Denied
+    };
+    let flags = (flags - COPY_ON_WRITE) | WRITABLE;
+
+    if frame::frame_refcount(old) <= 1 {
+        // everyone else has copied it or unmapped it already.
+        trace!("{:?}: last reference to copy-on-write {:?}", page, old);
+        entry.set(old, flags);
+    } else {
+        let mut frames = frame::allocator();
+        let new = match unsafe { frames.allocate() } {
+            Ok(frame) => frame
+          , Err(_) => return FaultResult::OutOfMemory
+        };
+        unsafe {
+            memops::fast_memcpy( phys_to_virt(new.base_addr()).as_mut_ptr()
+                               , phys_to_virt(old.base_addr()).as_ptr()
+                               , PAGE_SIZE as usize );
+        }
+        trace!("{:?}: copied copy-on-write {:?} to {:?}", page, old, new);
+        entry.set(new, flags);
+        // this only drops our reference, since somebody else has one.
+        unsafe { frames.deallocate(old) }
+    }
+    unsafe { page.invlpg() }
+    FaultResult::Handled
+}
+
+/// Kill the current task for touching `addr`, as `SIGSEGV` would.
+///
+/// Signals are only delivered on the way out of system calls, so faults
+/// can't be caught: the task just exits with the status a shell would
+/// report for a task killed by one.
+pub fn segfault(addr: VAddr, why: FaultResult) -> ! {
+    let pid = unsafe { task::current().pid };
+    trace!("task {}: segmentation fault at {:?} ({:?})", pid, addr, why);
+    task::exit(128 + SIGSEGV)
+}
@@ -0,0 +1,473 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The kernel's global frame allocator.
+//!
+//! Code that needs physical frames after boot (such as the page fault
+//! handler, or `munmap`) gets them through [`GlobalFrames`], a zero-sized
+//! handle on the real allocator.
+//!
+//! The boot memory map allocator can't take frames back, so freed frames go
+//! on a lock-free free list instead, and single frames are taken from there
+//! first. Each free frame holds the link to the next one, so the list costs
+//! no memory. Ranges of frames still come from the memory map allocator,
+//! under its lock, since the free list isn't sorted.
+//!
+//! In front of both, each CPU keeps a [`PerCpuFrameCache`] of free frames,
+//! so most single-frame allocations and frees touch nothing shared at all.
+//! Interrupts are disabled while a CPU's cache is in use, so that an
+//! interrupt handler that allocates or frees a frame can't find it half
+//! updated.
+//!
+//! A frame may be mapped by more than one address space, after `fork`
+//! shares it copy-on-write, so every frame has a reference count. Freeing a
+//! frame only drops a reference; it's only really freed once the last one
+//! is gone.
+//!
+//! [`GlobalFrames`]: struct.GlobalFrames.html
+//! [`PerCpuFrameCache`]: struct.PerCpuFrameCache.html
+use alloc::vec::Vec;
+use core::{cmp, mem, ptr, slice};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use memory::{FrameRange, MemRange, PAGE_SIZE, Page, PhysicalPage, VAddr};
+use paging::arch::space::{phys_to_virt, virt_to_phys};
+use params::InitParams;
+use sos_alloc::{AllocResult, FrameAllocator};
+use sos_alloc::frame::mem_map::MemMapAllocator;
+use sos_intrusive::{NonNullOwned, RawLink, SinglyNode, TreiberStack};
+use spin::Mutex;
+
+use arch::{memops, numa, percpu};
+use arch::cpu::without_interrupts;
+use phase::{advance_phase, require_phase, KernelPhase};
+use trace::{self, trace_event};
+
+/// The most frames a [`PerCpuFrameCache`] holds.
+///
+/// [`PerCpuFrameCache`]: struct.PerCpuFrameCache.html
+pub const FRAME_CACHE_SIZE: usize = 64;
+
+/// How many frames move between a CPU's frame cache and the global
+/// allocator at once.
+const FRAME_CACHE_BATCH: usize = 32;
+
+/// How many ranges [`allocate_on_node`] tries before settling for one on
+/// the wrong node.
+///
+/// [`allocate_on_node`]: fn.allocate_on_node.html
+const NODE_ALLOC_ATTEMPTS: usize = 16;
+
+static FRAME_ALLOCATOR: Mutex<Option<MemMapAllocator<'static>>>
+    = Mutex::new(None);
+
+macro_rules! with_allocator {
+    (|$a:ident| $body:expr) => {{
+        let mut lock = FRAME_ALLOCATOR.lock();
+        let $a = lock.as_mut()
+                     .expect("frame allocator not initialized!");
+        $body
+    }}
+}
+
+/// A frame on the free list, seen through the direct map.
+struct FreeFrame { next: RawLink<FreeFrame> }
+
+impl SinglyNode for FreeFrame {
+    #[inline] fn next(&self) -> &RawLink<Self> { &self.next }
+    #[inline] fn next_mut(&mut self) -> &mut RawLink<Self> { &mut self.next }
+}
+
+/// Frames that have been freed, and can be handed out again.
+///
+/// Every frame stays mapped in the direct map after it's popped, so a pop
+/// racing with another one can always read its link.
+static FREE_FRAMES: TreiberStack<NonNullOwned<FreeFrame>, FreeFrame>
+    = TreiberStack::new();
+
+/// Put `frame` on the free list.
+unsafe fn push_free(frame: PhysicalPage) {
+    let node = phys_to_virt(frame.base_addr()).as_mut_ptr::<FreeFrame>();
+    ptr::write(node, FreeFrame { next: RawLink::none() });
+    FREE_FRAMES.push(NonNullOwned::from_raw(node));
+}
+
+/// Take a frame off the free list, if there is one.
+unsafe fn pop_free() -> Option<PhysicalPage> {
+    FREE_FRAMES.pop().map(|node| {
+        let addr = VAddr::from(node.as_ptr() as usize);
+        PhysicalPage::containing(virt_to_phys(addr))
+    })
+}
+
+/// A CPU's private stash of free frames.
+///
+/// An empty cache is refilled with a batch of frames from the free list,
+/// topped up from the memory map allocator in a single critical section;
+/// a full one gives a batch back to the free list.
+///
+/// Frames in a cache count as free in [`stats`](fn.stats.html).
+pub struct PerCpuFrameCache {
+    frames: [Option<PhysicalPage>; FRAME_CACHE_SIZE]
+  , count: usize
+}
+
+impl PerCpuFrameCache {
+    /// Returns an empty frame cache.
+    pub const fn new() -> Self {
+        PerCpuFrameCache { frames: [None; FRAME_CACHE_SIZE], count: 0 }
+    }
+
+    /// Returns the number of frames in the cache.
+    #[inline] pub fn len(&self) -> usize { self.count }
+
+    #[inline]
+    fn pop(&mut self) -> Option<PhysicalPage> {
+        if self.count == 0 { return None }
+        self.count -= 1;
+        self.frames[self.count].take()
+    }
+
+    #[inline]
+    fn push(&mut self, frame: PhysicalPage) {
+        assert!(self.count < FRAME_CACHE_SIZE, "frame cache overflowed!");
+        self.frames[self.count] = Some(frame);
+        self.count += 1;
+    }
+
+    /// Fill the cache up to a batch of frames from the global allocator.
+    ///
+    /// Fails only if not a single frame could be found.
+    unsafe fn refill(&mut self) -> AllocResult<()> {
+        while self.count < FRAME_CACHE_BATCH {
+            match pop_free() {
+                Some(frame) => self.push(frame)
+              , None => break
+            }
+        }
+        if self.count < FRAME_CACHE_BATCH {
+            with_allocator!(|a| {
+                while self.count < FRAME_CACHE_BATCH {
+                    match a.allocate() {
+                        Ok(frame) => self.push(frame)
+                      , Err(_) if self.count > 0 => break
+                      , Err(err) => return Err(err)
+                    }
+                }
+            })
+        }
+        Ok(())
+    }
+
+    /// Give a batch of frames back to the free list.
+    unsafe fn drain(&mut self) {
+        for _ in 0 .. FRAME_CACHE_BATCH {
+            match self.pop() {
+                Some(frame) => push_free(frame)
+              , None => return
+            }
+        }
+    }
+}
+
+/// The number of usable frames in the memory map.
+static TOTAL_FRAMES: AtomicUsize = AtomicUsize::new(0);
+/// The number of frames currently in use, including the kernel image.
+static USED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Initialize the global frame allocator from the boot memory map.
+pub fn init(params: &'static InitParams) {
+    let page_size = PAGE_SIZE as usize;
+    let total = params.mem_map()
+                      .map(|area| (*area.end_addr - *area.start_addr) as usize
+                                  / page_size)
+                      .sum();
+    TOTAL_FRAMES.store(total, Ordering::Relaxed);
+    USED_FRAMES.store(params.kernel_frames().length(), Ordering::Relaxed);
+    *FRAME_ALLOCATOR.lock() = Some(MemMapAllocator::from(params));
+    advance_phase(KernelPhase::MemoryInit);
+}
+
+/// A snapshot of physical memory usage.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameStats { /// The number of usable frames
+                        pub total: usize
+                      , /// The number of frames in use
+                        pub used: usize
+                      }
+
+impl FrameStats {
+    /// Returns the number of frames that aren't in use.
+    #[inline] pub fn free(&self) -> usize {
+        self.total.saturating_sub(self.used)
+    }
+}
+
+/// Returns the current physical memory usage.
+pub fn stats() -> FrameStats {
+    FrameStats { total: TOTAL_FRAMES.load(Ordering::Relaxed)
+               , used: USED_FRAMES.load(Ordering::Relaxed)
+               }
+}
+
+/// The number of references to each frame, indexed by frame number.
+///
+/// Frames are handed out with a count of one, and free frames have a count
+/// of zero. Frames handed out before the table existed have a count of
+/// zero too, which is taken to mean one. The table is empty until
+/// [`init_refcounts`](fn.init_refcounts.html) is called, and never changes
+/// afterwards.
+static mut FRAME_REFCOUNTS: &'static [AtomicU32] = &[];
+
+/// Set up the frame reference counts.
+///
+/// The counts are kept in frames taken from the allocator, and accessed
+/// through the physical memory map, so this must be called after the kernel
+/// has been remapped.
+///
+/// # Safety
+/// + This must be called once, before any task runs.
+pub unsafe fn init_refcounts(params: &InitParams) {
+    let page_size = PAGE_SIZE as usize;
+    let num_frames = params.mem_map()
+                           .map(|area| *area.end_addr as usize / page_size)
+                           .max()
+                           .unwrap_or(0);
+    let size = num_frames * mem::size_of::<AtomicU32>();
+    let range = allocator().allocate_range((size + page_size - 1) / page_size)
+                           .expect("no memory for frame reference counts!");
+    let counts = phys_to_virt(range.start.base_addr()).as_mut_ptr::<u8>();
+    memops::fast_memset(counts, 0, size);
+    FRAME_REFCOUNTS
+        = slice::from_raw_parts(counts as *const AtomicU32, num_frames);
+}
+
+/// Returns `frame`'s reference count, if it has one.
+#[inline]
+fn refcount(frame: PhysicalPage) -> Option<&'static AtomicU32> {
+    unsafe { FRAME_REFCOUNTS.get(frame.number as usize) }
+}
+
+/// Give the newly allocated `frame` its first reference.
+#[inline]
+fn set_allocated(frame: PhysicalPage) {
+    if let Some(count) = refcount(frame) { count.store(1, Ordering::Release) }
+}
+
+/// Returns the number of references to `frame`.
+pub fn frame_refcount(frame: PhysicalPage) -> u32 {
+    refcount(frame)
+        .map_or(1, |count| cmp::max(count.load(Ordering::Acquire), 1))
+}
+
+/// Count a new reference to `frame`.
+pub fn frame_inc_ref(frame: PhysicalPage) {
+    if let Some(count) = refcount(frame) {
+        let mut current = count.load(Ordering::Relaxed);
+        loop {
+            // a count of zero is a single reference already.
+            let new = cmp::max(current, 1) + 1;
+            match count.compare_exchange_weak( current, new
+                                             , Ordering::AcqRel
+                                             , Ordering::Relaxed ) {
+                Ok(_) => return
+              , Err(actual) => current = actual
+            }
+        }
+    }
+}
+
+/// Drop a reference to `frame`, and return the number left.
+///
+/// The caller should free the frame if this returns zero. Freeing a frame
+/// through [`GlobalFrames`](struct.GlobalFrames.html) drops a reference
+/// itself, and only frees it if that was the last one.
+pub fn frame_dec_ref(frame: PhysicalPage) -> u32 {
+    let count = match refcount(frame) {
+        Some(count) => count
+      , None => return 0
+    };
+    let mut current = count.load(Ordering::Relaxed);
+    loop {
+        let new = current.saturating_sub(1);
+        match count.compare_exchange_weak( current, new
+                                         , Ordering::AcqRel
+                                         , Ordering::Relaxed ) {
+            Ok(_) => return new
+          , Err(actual) => current = actual
+        }
+    }
+}
+
+/// A handle on the global frame allocator.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GlobalFrames;
+
+/// Returns a handle on the global frame allocator.
+#[inline] pub fn allocator() -> GlobalFrames { GlobalFrames }
+
+impl FrameAllocator for GlobalFrames {
+    #[inline]
+    unsafe fn allocate(&mut self) -> AllocResult<PhysicalPage> {
+        require_phase(KernelPhase::MemoryInit);
+        let frame = without_interrupts(|| -> AllocResult<PhysicalPage> {
+            let cache = &mut percpu::current().frame_cache;
+            if cache.len() == 0 { cache.refill()? }
+            Ok(cache.pop().expect("frame cache is empty after refill!"))
+        })?;
+        set_allocated(frame);
+        USED_FRAMES.fetch_add(1, Ordering::Relaxed);
+        trace_event(trace::ALLOC_FRAME, *frame.base_addr(), 1, 0);
+        Ok(frame)
+    }
+
+    #[inline]
+    unsafe fn deallocate(&mut self, frame: PhysicalPage) {
+        // someone else still has the frame mapped.
+        if frame_dec_ref(frame) > 0 { return }
+        without_interrupts(|| {
+            let cache = &mut percpu::current().frame_cache;
+            if cache.len() == FRAME_CACHE_SIZE { cache.drain() }
+            cache.push(frame);
+        });
+        USED_FRAMES.fetch_sub(1, Ordering::Relaxed);
+        trace_event(trace::FREE_FRAME, *frame.base_addr(), 1, 0);
+    }
+
+    #[inline]
+    unsafe fn allocate_range(&mut self, num: usize)
+                            -> AllocResult<FrameRange> {
+        require_phase(KernelPhase::MemoryInit);
+        let range = with_allocator!(|a| a.allocate_range(num))?;
+        for number in range.start.number .. range.end.number {
+            set_allocated(PhysicalPage { number: number });
+        }
+        USED_FRAMES.fetch_add(num, Ordering::Relaxed);
+        trace_event( trace::ALLOC_FRAME, *range.start.base_addr(), num as u64
+                   , 0);
+        Ok(range)
+    }
+
+    /// Free every frame in `range` that nobody else has a reference to.
+    #[inline]
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        let mut freed = 0;
+        for number in range.start.number .. range.end.number {
+            let frame = PhysicalPage { number: number };
+            if frame_dec_ref(frame) > 0 { continue }
+            push_free(frame);
+            freed += 1;
+        }
+        USED_FRAMES.fetch_sub(freed, Ordering::Relaxed);
+        trace_event( trace::FREE_FRAME, *range.start.base_addr()
+                   , freed as u64, 0);
+    }
+
+    /// Allocate `2^order` contiguous frames, starting at a multiple of
+    /// `2^align_order` frames.
+    ///
+    /// The memory map allocator hands frames out in address order, so this
+    /// takes them one at a time until it has a long enough run starting on
+    /// the boundary. The frames passed over on the way go on the free list,
+    /// rather than being leaked like those skipped by `allocate_range`.
+    ///
+    /// # Panics
+    /// + If `align_order` is less than `order`.
+    unsafe fn allocate_aligned(&mut self, order: usize, align_order: usize)
+                              -> AllocResult<FrameRange> {
+        assert!( align_order >= order
+               , "alignment must be at least as large as the allocation");
+        require_phase(KernelPhase::MemoryInit);
+        let (num, align) = (1u64 << order, 1u64 << align_order);
+        let range = with_allocator!(|a| {
+            // the aligned run of frames we're building up, if any.
+            let mut run: Option<(u64, u64)> = None;
+            let result = loop {
+                let frame = match a.allocate() {
+                    Ok(frame) => frame
+                  , Err(err) => break Err(err)
+                };
+                run = match run {
+                    Some((start, end)) if frame.number == end =>
+                        Some((start, end + 1))
+                  , broken => {
+                        if let Some((start, end)) = broken {
+                            for number in start .. end {
+                                push_free(PhysicalPage { number: number });
+                            }
+                        }
+                        if frame.number % align == 0 {
+                            Some((frame.number, frame.number + 1))
+                        } else {
+                            push_free(frame);
+                            None
+                        }
+                    }
+                };
+                match run {
+                    Some((start, end)) if end - start == num =>
+                        break Ok(PhysicalPage { number: start }
+                                 .. PhysicalPage { number: end })
+                  , _ => {}
+                }
+            };
+            if let (&Err(_), Some((start, end))) = (&result, run) {
+                for number in start .. end {
+                    push_free(PhysicalPage { number: number });
+                }
+            }
+            result
+        })?;
+        for number in range.start.number .. range.end.number {
+            set_allocated(PhysicalPage { number: number });
+        }
+        USED_FRAMES.fetch_add(num as usize, Ordering::Relaxed);
+        trace_event(trace::ALLOC_FRAME, *range.start.base_addr(), num, 0);
+        Ok(range)
+    }
+}
+
+/// Allocate `2^order` contiguous frames, preferring ones on the NUMA node
+/// for proximity domain `node`.
+///
+/// Frames are handed out in address order, so this takes ranges until one
+/// lands on the node, giving back the ones that don't. If none of the
+/// first few do, or the machine has no such node, any frames will do.
+pub unsafe fn allocate_on_node(order: usize, node: u32)
+                              -> AllocResult<FrameRange> {
+    let num = 1 << order;
+    let mut frames = allocator();
+    let node = match numa::topology().and_then(|t| t.node(node)) {
+        Some(node) => node
+      , None => return frames.allocate_range(num)
+    };
+    let mut misses = Vec::new();
+    let mut result = None;
+    for _ in 0 .. NODE_ALLOC_ATTEMPTS {
+        match frames.allocate_range(num) {
+            Ok(range) => if node.contains( range.start.base_addr()
+                                         , range.end.base_addr()) {
+                result = Some(Ok(range));
+                break
+            } else {
+                misses.push(range)
+            }
+          , Err(err) => { result = Some(Err(err)); break }
+        }
+    }
+    // settle for the first miss, if we have to.
+    let mut misses = misses.into_iter();
+    let result = match result {
+        Some(Ok(range)) => Ok(range)
+      , Some(Err(err)) => misses.next().ok_or(err)
+      , None => Ok(misses.next().expect("no ranges allocated or failed!"))
+    };
+    for range in misses {
+        frames.deallocate_range(range);
+    }
+    result
+}
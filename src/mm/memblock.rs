@@ -0,0 +1,124 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Early boot memory.
+//!
+//! Until the frame allocator and the heap are up, there's nowhere to get
+//! memory from, but setting them up can need some. The [`Memblock`] is a
+//! bump allocator over a fixed buffer in `.bss`, for those allocations.
+//! Nothing it hands out is ever freed.
+//!
+//! Once the heap is ready, [`reclaim_to_heap`] gives it whatever part of
+//! the buffer was never used.
+//!
+//! [`Memblock`]: struct.Memblock.html
+//! [`reclaim_to_heap`]: fn.reclaim_to_heap.html
+use core::ptr;
+use spin::Mutex;
+use sos_alloc::buddy::system as heap;
+
+/// The size of the early boot memory buffer.
+pub const MEMBLOCK_SIZE: usize = 64 * 1024;
+
+/// The buffer the early allocator hands out.
+#[repr(align(4096))]
+struct Buffer([u8; MEMBLOCK_SIZE]);
+
+static mut BUFFER: Buffer = Buffer([0; MEMBLOCK_SIZE]);
+
+/// A bump allocator over a fixed buffer.
+pub struct Memblock { buf: *mut u8
+                    , size: usize
+                    , /// Bytes handed out, including alignment padding
+                      used: usize
+                    }
+
+// the buffer is only reached through the allocator's lock.
+unsafe impl Send for Memblock {}
+
+impl Memblock {
+    /// Returns an allocator over the `size` bytes at `buf`.
+    ///
+    /// # Safety
+    /// + Nothing else may use the buffer.
+    pub const unsafe fn new(buf: *mut u8, size: usize) -> Self {
+        Memblock { buf: buf, size: size, used: 0 }
+    }
+
+    /// Allocate `size` bytes, aligned to `align`.
+    ///
+    /// # Returns
+    /// + A pointer to the memory, or a null pointer if there isn't enough
+    ///   left
+    ///
+    /// # Panics
+    /// + If `align` isn't a power of two
+    pub fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        assert!(align.is_power_of_two(), "alignment must be a power of 2!");
+        let base = self.buf as usize;
+        let start = (base + self.used + align - 1) & !(align - 1);
+        match (start - base).checked_add(size) {
+            Some(end) if end <= self.size => {
+                self.used = end;
+                start as *mut u8
+            }
+          , _ => ptr::null_mut()
+        }
+    }
+
+    /// Returns the number of bytes that haven't been handed out.
+    #[inline] pub fn remaining(&self) -> usize { self.size - self.used }
+
+    /// Returns the number of bytes that have been handed out.
+    #[inline] pub fn used(&self) -> usize { self.used }
+
+    /// Add the part of the buffer that was never handed out to the kernel
+    /// heap, as free memory. The allocator is empty afterwards.
+    ///
+    /// Returns the number of bytes the heap took, which may be a little
+    /// less than what was left, since the heap only takes whole blocks.
+    ///
+    /// # Safety
+    /// + The heap must not overlap the buffer.
+    pub unsafe fn reclaim_to_heap(&mut self) -> usize {
+        let start = self.buf.offset(self.used as isize);
+        let size = self.remaining();
+        self.used = self.size;
+        heap::add_region(start, size)
+    }
+}
+
+lazy_static! {
+    /// The kernel's early boot allocator.
+    static ref MEMBLOCK: Mutex<Memblock>
+        = Mutex::new(unsafe {
+            Memblock::new(BUFFER.0.as_mut_ptr(), MEMBLOCK_SIZE)
+        });
+}
+
+/// Allocate `size` bytes of early boot memory, aligned to `align`.
+///
+/// Returns a null pointer if there isn't enough left.
+#[inline]
+pub fn alloc(size: usize, align: usize) -> *mut u8 {
+    MEMBLOCK.lock().alloc(size, align)
+}
+
+/// Returns the number of bytes of early boot memory left.
+#[inline]
+pub fn remaining() -> usize { MEMBLOCK.lock().remaining() }
+
+/// Give the early boot memory that was never used to the kernel heap,
+/// returning the number of bytes reclaimed.
+///
+/// Memory that was handed out is still in use, so it stays where it is.
+/// Any allocations after this fail.
+pub fn reclaim_to_heap() -> usize {
+    // the buffer is in the kernel image, and the heap isn't.
+    unsafe { MEMBLOCK.lock().reclaim_to_heap() }
+}
@@ -0,0 +1,178 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Memory-mapped device registers.
+//!
+//! An [`MmioRegion`](struct.MmioRegion.html) is a device's register window,
+//! mapped uncached into the physical memory map. Every access to it is
+//! volatile, and bounds checked against the size of the window.
+//!
+//! Registers with fixed offsets can be named with an
+//! [`MmioField`](struct.MmioField.html):
+//!
+//! ```ignore
+//! const VERSION: MmioField<u32> = MmioField::new(0x30);
+//! let version = VERSION.read(&region);
+//! ```
+use core::{fmt, mem, ptr};
+use core::marker::PhantomData;
+use memory::{PAddr, Page, PhysicalPage, VAddr, VirtualPage};
+use paging::{MapResult, Mapper};
+use paging::arch::space::phys_to_virt;
+use paging::arch::table::{ EntryFlags, NO_CACHE, NO_EXECUTE, WRITABLE
+                         , WRITE_THROUGH };
+use sos_alloc::FrameAllocator;
+
+/// A window of device registers.
+pub struct MmioRegion { virt_base: VAddr
+                      , size: usize
+                      }
+
+// the registers belong to the device, not to any particular CPU.
+unsafe impl Send for MmioRegion {}
+unsafe impl Sync for MmioRegion {}
+
+impl MmioRegion {
+    /// Map the `size` bytes of device memory at `phys` into the physical
+    /// memory map, uncached, using `mapper` and `alloc`.
+    ///
+    /// Pages that are already mapped are left as they are.
+    pub fn new<M, A>(phys: PAddr, size: usize, mapper: &mut M, alloc: &mut A)
+                    -> MapResult<MmioRegion>
+    where M: Mapper<Flags = EntryFlags>
+        , A: FrameAllocator {
+        let virt_base = phys_to_virt(phys);
+        if size > 0 {
+            let first_frame = PhysicalPage::containing(phys);
+            let last_frame
+                = PhysicalPage::containing(phys + (size as u64 - 1));
+            let first_page = VirtualPage::containing(virt_base);
+            for i in 0 .. last_frame.number - first_frame.number + 1 {
+                let page
+                    = VirtualPage { number: first_page.number + i as usize };
+                if mapper.translate_page(page).is_some() { continue }
+                let frame = PhysicalPage { number: first_frame.number + i };
+                mapper.map( page, frame
+                          , WRITABLE | NO_CACHE | WRITE_THROUGH | NO_EXECUTE
+                          , alloc )?;
+            }
+        }
+        Ok(MmioRegion { virt_base: virt_base, size: size })
+    }
+
+    /// Returns the virtual address of the start of the region.
+    #[inline] pub fn virt_base(&self) -> VAddr { self.virt_base }
+
+    /// Returns the size of the region, in bytes.
+    #[inline] pub fn size(&self) -> usize { self.size }
+
+    /// Returns a pointer to the `T` at `offset`.
+    ///
+    /// # Panics
+    /// + If the `T` isn't entirely inside the region.
+    #[inline]
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!( offset.checked_add(mem::size_of::<T>())
+                       .map_or(false, |end| end <= self.size)
+               , "MMIO access at {:#x} is outside a region of {:#x} bytes"
+               , offset, self.size );
+        (self.virt_base.as_usize() + offset) as *mut T
+    }
+
+    /// Read the `T` at `offset`.
+    ///
+    /// # Panics
+    /// + If the `T` isn't entirely inside the region.
+    #[inline]
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.ptr(offset)) }
+    }
+
+    /// Write `value` at `offset`.
+    ///
+    /// # Panics
+    /// + If the `T` isn't entirely inside the region.
+    #[inline]
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.ptr(offset), value) }
+    }
+
+    #[inline] pub fn read_u8(&self, offset: usize) -> u8 { self.read(offset) }
+    #[inline] pub fn read_u32(&self, offset: usize) -> u32 {
+        self.read(offset)
+    }
+    #[inline] pub fn read_u64(&self, offset: usize) -> u64 {
+        self.read(offset)
+    }
+
+    #[inline] pub fn write_u8(&self, offset: usize, value: u8) {
+        self.write(offset, value)
+    }
+    #[inline] pub fn write_u32(&self, offset: usize, value: u32) {
+        self.write(offset, value)
+    }
+    #[inline] pub fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value)
+    }
+}
+
+impl fmt::Debug for MmioRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "MmioRegion {{ {:#x} .. {:#x} }}"
+              , self.virt_base.as_usize()
+              , self.virt_base.as_usize() + self.size )
+    }
+}
+
+/// A register of type `T` at a fixed offset in an
+/// [`MmioRegion`](struct.MmioRegion.html).
+pub struct MmioField<T> { offset: usize
+                        , ty: PhantomData<T>
+                        }
+
+impl<T> MmioField<T> {
+    /// Returns the register at `offset`.
+    #[inline]
+    pub const fn new(offset: usize) -> Self {
+        MmioField { offset: offset, ty: PhantomData }
+    }
+
+    /// Returns the offset of the register.
+    #[inline] pub fn offset(&self) -> usize { self.offset }
+}
+
+impl<T: Copy> MmioField<T> {
+    /// Read this register in `region`.
+    ///
+    /// # Panics
+    /// + If the register isn't entirely inside `region`.
+    #[inline]
+    pub fn read(&self, region: &MmioRegion) -> T {
+        region.read(self.offset)
+    }
+
+    /// Write `value` to this register in `region`.
+    ///
+    /// # Panics
+    /// + If the register isn't entirely inside `region`.
+    #[inline]
+    pub fn write(&self, region: &MmioRegion, value: T) {
+        region.write(self.offset, value)
+    }
+}
+
+impl<T> Clone for MmioField<T> {
+    #[inline] fn clone(&self) -> Self { MmioField::new(self.offset) }
+}
+impl<T> Copy for MmioField<T> {}
+
+impl<T> fmt::Debug for MmioField<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MmioField({:#x})", self.offset)
+    }
+}
@@ -0,0 +1,151 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Architecture-independent virtual memory management.
+//!
+//! User space occupies PML4 entries 1 through 255. Entry 0 holds the
+//! identity-mapped kernel, and the upper half of the address space belongs
+//! to the kernel.
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use memory::{PAGE_SIZE, PAddr, Page, PhysicalPage, VAddr, VirtualPage};
+use paging::{MapErr, MapResult, Mapper};
+use paging::arch::ActivePageTable;
+use paging::arch::space::phys_to_virt;
+use paging::arch::table::{EntryFlags, NO_EXECUTE, USER_ACCESSIBLE, WRITABLE};
+use sos_alloc::FrameAllocator;
+
+use arch::memops;
+use self::vm::{VmFlags, VM_EXEC, VM_WRITE};
+
+pub mod boot;
+pub mod dma;
+pub mod fault;
+pub mod mmio;
+pub mod frame;
+pub mod memblock;
+pub mod oom;
+pub mod swap;
+pub mod vm;
+pub mod user;
+
+/// The lowest address that may be mapped in user space.
+pub const USER_SPACE_START: usize = 0x0000_0080_0000_0000;
+/// One past the highest address that may be mapped in user space.
+pub const USER_SPACE_END: usize = 0x0000_8000_0000_0000;
+
+/// The highest swappiness.
+pub const SWAPPINESS_MAX: usize = 100;
+
+/// How eagerly to swap pages out, from 0 to `SWAPPINESS_MAX`, as on Linux.
+///
+/// This sets how short free frames must be before `kswapd` starts swapping
+/// (see [`swap`](swap/index.html)). It can be set through `vm/swappiness`.
+static SWAPPINESS: AtomicUsize = AtomicUsize::new(60);
+
+/// Returns how eagerly to swap pages out.
+#[inline]
+pub fn swappiness() -> usize { SWAPPINESS.load(Ordering::Relaxed) }
+
+/// Set how eagerly to swap pages out, up to `SWAPPINESS_MAX`.
+pub fn set_swappiness(swappiness: usize) {
+    SWAPPINESS.store(cmp::min(swappiness, SWAPPINESS_MAX), Ordering::Relaxed)
+}
+
+/// Returns true if the `len` bytes starting at `addr` lie entirely within
+/// user space.
+#[inline]
+pub fn is_user_range(addr: VAddr, len: usize) -> bool {
+    let start = addr.as_usize();
+    match start.checked_add(len) {
+        Some(end) => start >= USER_SPACE_START && end <= USER_SPACE_END
+      , None => false
+    }
+}
+
+/// Unmap every present page in `[start, end)` from the current address
+/// space, returning the frames to the global frame allocator.
+///
+/// Pages in the range that were never faulted in are skipped, and pages that
+/// were swapped out give up their swap slots. Frames that are still mapped
+/// somewhere else, after a `fork`, only lose a reference, and are freed
+/// once the last address space unmaps them.
+pub fn unmap_user_pages(start: VAddr, end: VAddr) {
+    debug_assert!(is_user_range(start, end.as_usize() - start.as_usize()));
+    let mut table = unsafe { ActivePageTable::new() };
+    let mut frames = frame::allocator();
+    let first = VirtualPage::containing(start);
+    let last = VirtualPage::containing(VAddr::from(end.as_usize() - 1));
+    for number in first.number .. last.number + 1 {
+        let page = VirtualPage { number: number };
+        if table.is_mapped(&page) {
+            // we just checked that the page is mapped, so this can't fail.
+            let _ = table.unmap(page, &mut frames);
+        } else if let Some(entry) = table.entry_mut(page) {
+            if let Some(slot) = entry.swap_slot() {
+                swap::free_slot(slot);
+                entry.set_unused();
+            }
+        }
+    }
+}
+
+/// Returns the page table flags for a user page in a region with `flags`.
+pub fn pte_flags(flags: VmFlags) -> EntryFlags {
+    let mut pte = USER_ACCESSIBLE;
+    if flags.contains(VM_WRITE) { pte.insert(WRITABLE); }
+    if !flags.contains(VM_EXEC) { pte.insert(NO_EXECUTE); }
+    pte
+}
+
+/// Map `page` in the current address space to a new, zeroed frame.
+///
+/// Returns the frame, so that the caller can fill it in through the
+/// physical memory map regardless of the page's permissions.
+pub fn map_user_page(page: VirtualPage, flags: VmFlags)
+                    -> MapResult<PhysicalPage> {
+    let mut frames = frame::allocator();
+    let frame = unsafe { frames.allocate() }
+        .map_err(|err| MapErr::Alloc { message: "map user page"
+                                     , page: page
+                                     , cause: err })?;
+    unsafe {
+        memops::fast_memset( phys_to_virt(frame.base_addr()).as_mut_ptr()
+                           , 0, PAGE_SIZE as usize);
+    }
+    let mut table = unsafe { ActivePageTable::new() };
+    if let Err(err) = table.map(page, frame, pte_flags(flags), &mut frames) {
+        unsafe { frames.deallocate(frame); }
+        return Err(err)
+    }
+    Ok(frame)
+}
+
+/// Map the `len` bytes of physical memory starting at `start` into the
+/// physical memory map with `flags`, and return their virtual address.
+///
+/// Pages that are already mapped are left alone, so this is safe to call on
+/// memory that's already in the physical memory map, such as RAM.
+pub fn map_physical(start: PAddr, len: usize, flags: EntryFlags)
+                   -> MapResult<VAddr> {
+    let base = phys_to_virt(start);
+    if len == 0 { return Ok(base) }
+    let first_frame = PhysicalPage::containing(start);
+    let last_frame = PhysicalPage::containing(start + (len as u64 - 1));
+    let first_page = VirtualPage::containing(base);
+
+    let mut table = unsafe { ActivePageTable::new() };
+    let mut frames = frame::allocator();
+    for i in 0 .. last_frame.number - first_frame.number + 1 {
+        let page = VirtualPage { number: first_page.number + i as usize };
+        if table.is_mapped(&page) { continue }
+        let frame = PhysicalPage { number: first_frame.number + i };
+        table.map(page, frame, flags, &mut frames)?;
+    }
+    Ok(base)
+}
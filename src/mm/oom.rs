@@ -0,0 +1,111 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The out-of-memory killer.
+//!
+//! When the kernel heap can't satisfy an allocation, it calls
+//! [`kill_largest_task`]. That first compacts the heap, and if that didn't
+//! help, sends `SIGKILL` to the user task with the most memory mapped, and
+//! has the allocation tried again.
+//!
+//! A task's badness is the number of bytes it has mapped, plus its
+//! `oom_score_adj` thousandths of physical memory, as on Linux. A task
+//! with an `oom_score_adj` of `OOM_SCORE_ADJ_MIN` is never killed, so that
+//! interactive processes can be protected.
+//!
+//! The victim only dies, and gives its memory back, the next time it
+//! returns from a system call, so the retry succeeds only if memory was
+//! freed in the meantime. The kill is logged, and recorded in the trace
+//! ring.
+//!
+//! [`kill_largest_task`]: fn.kill_largest_task.html
+use core::sync::atomic::{AtomicBool, Ordering};
+use memory::PAGE_SIZE;
+
+use heap;
+use task::{self, Pid, Task, TaskState};
+use task::signal::{self, SIGKILL};
+use trace::{self, trace_event};
+use super::frame;
+
+/// The lowest `oom_score_adj`: the task is never killed.
+pub const OOM_SCORE_ADJ_MIN: i32 = -1000;
+/// The highest `oom_score_adj`: the task is killed first.
+pub const OOM_SCORE_ADJ_MAX: i32 = 1000;
+
+/// Set while the OOM killer runs, so that an allocation that fails inside
+/// it doesn't start it again.
+static KILLING: AtomicBool = AtomicBool::new(false);
+
+/// Returns `task`'s badness, or `None` if it may not be killed.
+///
+/// `total` is the number of bytes of physical memory, which
+/// `oom_score_adj` is a fraction of.
+fn badness(task: &Task, total: i64) -> Option<i64> {
+    let mapped = task.vm.total_mapped_bytes();
+    let dying = task.pending_signals.load(Ordering::SeqCst)
+              & (1 << (SIGKILL - 1)) != 0;
+    // kernel threads have no user address space, and task 0 is the kernel
+    // itself.
+    if task.pid == Pid(0) || mapped == 0 || dying
+        || task.state == TaskState::Zombie
+        || task.oom_score_adj <= OOM_SCORE_ADJ_MIN {
+        return None
+    }
+    let adj = task.oom_score_adj as i64 * total / 1000;
+    Some(mapped as i64 + adj)
+}
+
+/// Kill the user task with the highest badness, unless compacting the
+/// kernel heap freed something.
+///
+/// Returns true if the heap was compacted or a task was sent `SIGKILL`, so
+/// the failed allocation should be retried.
+pub fn kill_largest_task() -> bool {
+    if KILLING.swap(true, Ordering::SeqCst) { return false }
+    let compaction = heap::compact();
+    if compaction.merged > 0 || compaction.released > 0 {
+        warn!( "out of memory: compacted the heap ({} blocks merged, {} \
+                bytes released)", compaction.merged, compaction.released);
+        KILLING.store(false, Ordering::SeqCst);
+        return true
+    }
+    let total = (frame::stats().total as u64 * PAGE_SIZE) as i64;
+    let mut victim: Option<(Pid, i64, usize, i32)> = None;
+    // if the allocation that failed was made with the task table locked,
+    // waiting for it would deadlock.
+    let locked = !task::try_for_each(|task| {
+        if let Some(points) = badness(task, total) {
+            if victim.map_or(true, |(_, most, _, _)| points > most) {
+                victim = Some(( task.pid, points
+                              , task.vm.total_mapped_bytes()
+                              , task.oom_score_adj ));
+            }
+        }
+    });
+
+    let killed = match victim {
+        Some((pid, _, mapped, adj)) if signal::task_kill(pid) => {
+            error!( "out of memory: killed task {} ({} bytes mapped, \
+                     oom_score_adj {})", pid, mapped, adj);
+            trace_event(trace::OOM_KILL, pid.0 as u64, mapped as u64
+                       , adj as i64 as u64);
+            true
+        }
+      , _ if locked => {
+            error!("out of memory, and the task table is locked");
+            false
+        }
+      , _ => {
+            error!("out of memory, and there's no task to kill");
+            false
+        }
+    };
+    KILLING.store(false, Ordering::SeqCst);
+    killed
+}
@@ -0,0 +1,362 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Swapping user pages out to a block device.
+//!
+//! A region backed by swap ([`VmBacking::Swap`]) names a block device, and
+//! the block its swap area starts at. Its pages start out like anonymous
+//! ones, and are zero-filled when they're first touched. When free frames
+//! run short, `kswapd` walks the swap-backed regions of blocked tasks: a
+//! page whose accessed bit is set has it cleared, and one whose accessed
+//! bit is still clear from last time is written to a free slot in its
+//! region's swap area. Its entry is marked `SWAPPED`, with the slot in
+//! place of the frame, and the frame is freed. Touching the page again
+//! faults, and [`fault`](../fault/index.html) reads it back in.
+//!
+//! Slots are numbered across every swap area, so that an entry alone says
+//! where its page is: each area takes the next range of slot numbers the
+//! first time it's used. After a `fork`, both address spaces point at the
+//! same slots, so each slot counts the entries that refer to it.
+//!
+//! Frames shared copy-on-write are never swapped out, since only one of
+//! the address spaces mapping them would be updated.
+//!
+//! [`VmBacking::Swap`]: ../vm/enum.VmBacking.html
+use alloc::arc::Arc;
+use alloc::vec::Vec;
+use core::{cmp, slice};
+use memory::{PAGE_SIZE, Page, PhysicalPage, VirtualPage};
+use paging::arch::space::{phys_to_virt, table_at};
+use paging::arch::table::{ Entry, PDLevel, PDPTLevel, PML4Level, PTLevel
+                         , ACCESSED };
+use sos_alloc::FrameAllocator;
+use spin::Mutex;
+
+use arch::pcid;
+use dev::block::{BlockDevice, BlockError};
+use heap;
+use task::{self, Pid, Task, TaskState};
+use task::timer::{now_ns, sleep_until};
+use super::{frame, swappiness, SWAPPINESS_MAX};
+use super::vm::VmBacking;
+
+/// How often `kswapd` checks whether frames are short.
+pub const KSWAPD_INTERVAL_NS: u64 = 1_000_000_000;
+
+/// The most slots a swap area may have, which bounds the size of its
+/// reference counts.
+const MAX_AREA_SLOTS: u64 = 1 << 18;
+
+/// The part of a block device from `block_offset` on, holding swapped out
+/// pages.
+struct SwapArea { device: Arc<BlockDevice>
+                , block_offset: u64
+                , /// The number of the area's first slot
+                  first_slot: u64
+                , /// The number of entries referring to each slot
+                  counts: Vec<u32>
+                }
+
+impl SwapArea {
+    /// Returns the swap area at `block_offset` on `device`, with slots
+    /// numbered from `first_slot`, or `None` if there's no room for a page
+    /// or the device's blocks don't fit evenly in one.
+    fn new(device: &Arc<BlockDevice>, block_offset: u64, first_slot: u64)
+          -> Option<SwapArea> {
+        let block_size = device.block_size() as u64;
+        if block_size == 0 || PAGE_SIZE % block_size != 0 { return None }
+        let blocks = device.block_count().saturating_sub(block_offset);
+        let slots = cmp::min(blocks / (PAGE_SIZE / block_size)
+                            , MAX_AREA_SLOTS);
+        if slots == 0 { return None }
+        Some(SwapArea { device: device.clone()
+                      , block_offset: block_offset
+                      , first_slot: first_slot
+                      , counts: vec![0; slots as usize]
+                      })
+    }
+
+    #[inline]
+    fn is(&self, device: &Arc<BlockDevice>, block_offset: u64) -> bool {
+        Arc::ptr_eq(&self.device, device) && self.block_offset == block_offset
+    }
+
+    /// Returns the index of `slot` in this area, if it's one of its slots.
+    #[inline]
+    fn index_of(&self, slot: u64) -> Option<usize> {
+        if slot >= self.first_slot
+            && slot - self.first_slot < self.counts.len() as u64 {
+            Some((slot - self.first_slot) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+lazy_static! {
+    /// Every swap area that's been used, in slot order.
+    static ref AREAS: Mutex<Vec<SwapArea>> = Mutex::new(Vec::new());
+}
+
+/// Returns the contents of `frame`, through the physical memory map.
+#[inline]
+unsafe fn frame_bytes<'a>(frame: PhysicalPage) -> &'a mut [u8] {
+    slice::from_raw_parts_mut( phys_to_virt(frame.base_addr()).as_mut_ptr()
+                             , PAGE_SIZE as usize )
+}
+
+/// Read the page starting at block `offset` on `device` into `frame`.
+///
+/// # Panics
+/// + If the device's blocks don't fit evenly in a page.
+pub fn read_page(device: &BlockDevice, offset: u64, frame: PhysicalPage)
+                -> Result<(), BlockError> {
+    let block_size = device.block_size();
+    assert_eq!( PAGE_SIZE as usize % block_size, 0
+              , "swap device's blocks don't fit evenly in a page");
+    let page = unsafe { frame_bytes(frame) };
+    for (i, block) in page.chunks_mut(block_size).enumerate() {
+        device.read_block(offset + i as u64, block)?;
+    }
+    Ok(())
+}
+
+/// Write `frame` to the page starting at block `offset` on `device`.
+///
+/// # Panics
+/// + If the device's blocks don't fit evenly in a page.
+pub fn write_page(device: &BlockDevice, offset: u64, frame: PhysicalPage)
+                 -> Result<(), BlockError> {
+    let block_size = device.block_size();
+    assert_eq!( PAGE_SIZE as usize % block_size, 0
+              , "swap device's blocks don't fit evenly in a page");
+    let page = unsafe { frame_bytes(frame) };
+    for (i, block) in page.chunks(block_size).enumerate() {
+        device.write_block(offset + i as u64, block)?;
+    }
+    Ok(())
+}
+
+/// Returns the block on `device` where `slot` starts, if it's a slot in
+/// the swap area at `block_offset`.
+pub fn slot_offset(device: &Arc<BlockDevice>, block_offset: u64, slot: u64)
+                  -> Option<u64> {
+    let areas = AREAS.lock();
+    let area = areas.iter().find(|area| area.is(device, block_offset))?;
+    let index = area.index_of(slot)? as u64;
+    let blocks_per_page = PAGE_SIZE / device.block_size() as u64;
+    Some(block_offset + index * blocks_per_page)
+}
+
+/// Take a free slot in the swap area at `block_offset` on `device`, with
+/// one reference to it.
+///
+/// Returns `None` if the area is full, or can't hold any pages at all.
+pub fn alloc_slot(device: &Arc<BlockDevice>, block_offset: u64)
+                 -> Option<u64> {
+    let mut areas = AREAS.lock();
+    let i = match areas.iter().position(|area| area.is(device, block_offset))
+    {
+        Some(i) => i
+      , None => {
+            let first_slot = areas.last().map_or(0, |area| {
+                area.first_slot + area.counts.len() as u64
+            });
+            areas.push(SwapArea::new(device, block_offset, first_slot)?);
+            areas.len() - 1
+        }
+    };
+    let area = &mut areas[i];
+    let index = area.counts.iter().position(|&count| count == 0)?;
+    area.counts[index] = 1;
+    Some(area.first_slot + index as u64)
+}
+
+/// Count another reference to `slot`.
+pub fn dup_slot(slot: u64) {
+    let mut areas = AREAS.lock();
+    match areas.iter_mut()
+               .filter_map(|area| area.index_of(slot).map(|i| (area, i)))
+               .next() {
+        Some((area, i)) => area.counts[i] += 1
+      , None => warn!("swap: duplicating unknown slot {}", slot)
+    }
+}
+
+/// Drop a reference to `slot`, freeing it once there are none left.
+pub fn free_slot(slot: u64) {
+    let mut areas = AREAS.lock();
+    match areas.iter_mut()
+               .filter_map(|area| area.index_of(slot).map(|i| (area, i)))
+               .next() {
+        Some((area, i)) => {
+            debug_assert!(area.counts[i] > 0, "freeing a free swap slot!");
+            area.counts[i] = area.counts[i].saturating_sub(1)
+        }
+      , None => warn!("swap: freeing unknown slot {}", slot)
+    }
+}
+
+/// Returns `page`'s entry in the address space whose PML4 is in `pml4`, if
+/// there's a page table for it.
+unsafe fn entry_in(pml4: PhysicalPage, page: VirtualPage)
+                  -> Option<&'static mut Entry> {
+    let pml4 = table_at::<PML4Level>(pml4);
+    let pdpt = table_at::<PDPTLevel>(pml4[page].get_frame()?);
+    if pdpt[page].is_huge() { return None }
+    let pd = table_at::<PDLevel>(pdpt[page].get_frame()?);
+    if pd[page].is_huge() { return None }
+    let pt = table_at::<PTLevel>(pd[page].get_frame()?);
+    Some(&mut pt[page])
+}
+
+/// A page chosen to be swapped out.
+struct Victim { page: VirtualPage
+              , frame: PhysicalPage
+              , device: Arc<BlockDevice>
+              , block_offset: u64
+              }
+
+/// Returns up to `wanted` of `task`'s swap-backed pages that haven't been
+/// touched since the last pass, clearing the accessed bit of the rest.
+fn choose_victims(task: &mut Task, wanted: usize) -> Vec<Victim> {
+    let mut victims = Vec::new();
+    let mut aged = false;
+    for region in task.vm.iter() {
+        let (device, block_offset) = match region.backing {
+            VmBacking::Swap { ref device, block_offset } =>
+                (device, block_offset)
+          , VmBacking::Anonymous | VmBacking::File { .. } => continue
+        };
+        let first = VirtualPage::containing(region.start);
+        let pages = region.len() / PAGE_SIZE as usize;
+        for number in first.number .. first.number + pages {
+            if victims.len() >= wanted { break }
+            let page = VirtualPage { number: number };
+            let entry = match unsafe { entry_in(task.page_table, page) } {
+                Some(entry) => entry
+              , None => continue
+            };
+            let frame = match entry.get_frame() {
+                Some(frame) => frame
+              , None => continue
+            };
+            let flags = entry.flags();
+            if flags.contains(ACCESSED) {
+                entry.set(frame, flags - ACCESSED);
+                aged = true;
+            } else if frame::frame_refcount(frame) == 1 {
+                victims.push(Victim { page: page
+                                    , frame: frame
+                                    , device: device.clone()
+                                    , block_offset: block_offset
+                                    });
+            }
+        }
+    }
+    // the task isn't running, so this is enough for it to see the changes.
+    if aged { pcid::release(task) }
+    victims
+}
+
+/// Swap out up to `wanted` of the pages of task `pid`, returning how many
+/// were.
+fn reclaim_from(pid: Pid, wanted: usize) -> usize {
+    let victims = match task::with_task_mut(pid, |task| {
+        if task.state == TaskState::Blocked {
+            choose_victims(task, wanted)
+        } else {
+            Vec::new()
+        }
+    }) {
+        Some(victims) => victims
+      , None => return 0
+    };
+    let mut freed = 0;
+    for victim in victims {
+        // the task table isn't locked while we wait for the disk.
+        let slot = match alloc_slot(&victim.device, victim.block_offset) {
+            Some(slot) => slot
+          , None => break
+        };
+        let written = slot_offset(&victim.device, victim.block_offset, slot)
+            .map_or(false, |offset| {
+                write_page(&*victim.device, offset, victim.frame).is_ok()
+            });
+        // the task may have run, or exited, while the page was written.
+        let swapped = written && task::with_task_mut(pid, |task| {
+            if task.state != TaskState::Blocked { return false }
+            let entry = match unsafe { entry_in(task.page_table, victim.page) }
+            {
+                Some(entry) => entry
+              , None => return false
+            };
+            if entry.get_frame() != Some(victim.frame)
+                || entry.flags().contains(ACCESSED) {
+                return false
+            }
+            entry.set_swapped(slot);
+            pcid::release(task);
+            true
+        }).unwrap_or(false);
+        if swapped {
+            unsafe { frame::allocator().deallocate(victim.frame) }
+            freed += 1;
+        } else {
+            free_slot(slot);
+        }
+    }
+    freed
+}
+
+/// Swap out up to `wanted` pages from blocked tasks, returning how many
+/// were.
+pub fn reclaim(wanted: usize) -> usize {
+    let mut pids = Vec::new();
+    task::for_each(|task| {
+        let swap_backed = task.vm.iter().any(|region| match region.backing {
+            VmBacking::Swap { .. } => true
+          , VmBacking::Anonymous | VmBacking::File { .. } => false
+        });
+        if swap_backed && task.state == TaskState::Blocked {
+            pids.push(task.pid)
+        }
+    });
+    let mut freed = 0;
+    for pid in pids {
+        if freed >= wanted { break }
+        freed += reclaim_from(pid, wanted - freed);
+    }
+    freed
+}
+
+/// Swap pages out whenever free frames are short.
+///
+/// How short depends on the swappiness: at the highest, `kswapd` starts
+/// when less than a quarter of memory is free, and at 0 it never does.
+/// Before swapping, it compacts the kernel heap, which gives back any
+/// frames free at its top.
+extern "C" fn kswapd() -> ! {
+    loop {
+        sleep_until(now_ns() + KSWAPD_INTERVAL_NS);
+        let stats = frame::stats();
+        let low = stats.total * swappiness() / (SWAPPINESS_MAX * 4);
+        if stats.free() >= low { continue }
+        let compaction = heap::compact();
+        if compaction.released > 0 {
+            debug!( "kswapd: released {} bytes of kernel heap"
+                  , compaction.released);
+        }
+        // try for some headroom, so we aren't back here straight away.
+        let freed = reclaim(cmp::min(low * 2, stats.total) - stats.free());
+        debug!("kswapd: swapped out {} page(s)", freed);
+    }
+}
+
+/// Start `kswapd`.
+pub fn init() -> Pid { task::spawn_kernel("kswapd", kswapd) }
@@ -0,0 +1,187 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Access to user memory.
+//!
+//! Pointers passed in by user code can't be trusted: they may point at the
+//! kernel, at unmapped memory, or at memory the task isn't allowed to write.
+//! The kernel must never dereference a user pointer directly: every user
+//! buffer is copied in or out with [`copy_from_user`](fn.copy_from_user.html)
+//! or [`copy_to_user`](fn.copy_to_user.html), or one of the helpers built
+//! on them.
+//!
+//! Those check the buffer against the task's address space first, with
+//! [`validate_user_read`](fn.validate_user_read.html) or
+//! [`validate_user_write`](fn.validate_user_write.html), and survive the
+//! user memory being unmapped out from under them: a page fault during the
+//! copy makes them return an error rather than bringing down the kernel.
+//! With SMAP enabled, they're also the only kernel code that can touch user
+//! pages at all.
+use alloc::vec::Vec;
+use core::{cmp, mem};
+
+use memory::{PAGE_SIZE, Page, VAddr, VirtualPage};
+use paging::arch::ActivePML4;
+use paging::arch::table::{COPY_ON_WRITE, USER_ACCESSIBLE, WRITABLE};
+
+use arch::extable;
+use super::is_user_range;
+use super::vm::{VM_READ, VM_WRITE};
+use task;
+
+/// How many bytes of a string [`copy_user_cstr`](fn.copy_user_cstr.html)
+/// copies in at a time.
+const CSTR_CHUNK: usize = 256;
+
+/// Error returned when a user pointer is invalid.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Efault;
+
+/// Errors returned by [`copy_user_cstr`](fn.copy_user_cstr.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CStrError {
+    /// The string was not entirely in readable user memory.
+    Fault
+  , /// No NUL terminator was found within the length limit.
+    TooLong
+}
+
+impl From<Efault> for CStrError {
+    #[inline] fn from(_: Efault) -> Self { CStrError::Fault }
+}
+
+/// Check that the current task may access the `len` bytes at `addr`.
+///
+/// The range must lie in user space and be covered by the task's virtual
+/// memory regions with the right permissions. Pages that are already
+/// present must be mapped user-accessible (and, if `write` is true,
+/// writable or copy-on-write); pages that aren't are faulted in when
+/// they're touched.
+fn validate_user_range(addr: VAddr, len: usize, write: bool)
+                      -> Result<(), Efault> {
+    if !is_user_range(addr, len) { return Err(Efault) }
+
+    let end = VAddr::from(addr.as_usize() + len);
+    let vm_flags = if write { VM_WRITE } else { VM_READ };
+
+    let task = unsafe { task::current() };
+    if !task.vm.covers(addr, end, vm_flags) { return Err(Efault) }
+
+    let pml4 = unsafe { ActivePML4::new() };
+    let first = VirtualPage::containing(addr);
+    let last = VirtualPage::containing(VAddr::from(end.as_usize() - 1));
+    for number in first.number .. last.number + 1 {
+        if let Some(flags) = pml4.flags_of(VirtualPage { number: number }) {
+            let writable = flags.intersects(WRITABLE | COPY_ON_WRITE);
+            if !flags.contains(USER_ACCESSIBLE) || (write && !writable) {
+                return Err(Efault)
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that the current task may read the `len` bytes at `addr`.
+///
+/// This doesn't make the memory safe to dereference: it can still be
+/// unmapped before it's touched, and with SMAP enabled, the kernel can't
+/// touch it at all except through [`copy_from_user`](fn.copy_from_user.html).
+pub fn validate_user_read(addr: VAddr, len: usize) -> Result<(), Efault> {
+    if len == 0 { return Ok(()) }
+    validate_user_range(addr, len, false)
+}
+
+/// Check that the current task may write the `len` bytes at `addr`.
+///
+/// As with [`validate_user_read`](fn.validate_user_read.html), the memory
+/// must still only be written through [`copy_to_user`](fn.copy_to_user.html).
+pub fn validate_user_write(addr: VAddr, len: usize) -> Result<(), Efault> {
+    if len == 0 { return Ok(()) }
+    validate_user_range(addr, len, true)
+}
+
+/// Copy `len` bytes from user memory at `src` to `dst`.
+///
+/// # Safety
+/// + `dst` must be valid for `len` bytes of writes.
+/// + `dst` must not be in user memory: with SMAP enabled, user pages are
+///   only accessible for the duration of the copy, which reads them with
+///   `stac` set.
+pub unsafe fn copy_from_user(dst: *mut u8, src: VAddr, len: usize)
+                            -> Result<(), Efault> {
+    if len == 0 { return Ok(()) }
+    validate_user_read(src, len)?;
+    match extable::copy_user(dst, src.as_ptr(), len) {
+        0 => Ok(())
+      , _ => Err(Efault)
+    }
+}
+
+/// Copy `len` bytes from `src` to user memory at `dst`.
+///
+/// # Safety
+/// + `src` must be valid for `len` bytes of reads.
+/// + `src` must not be in user memory: with SMAP enabled, user pages are
+///   only accessible for the duration of the copy, which writes them with
+///   `stac` set.
+pub unsafe fn copy_to_user(dst: VAddr, src: *const u8, len: usize)
+                          -> Result<(), Efault> {
+    if len == 0 { return Ok(()) }
+    validate_user_write(dst, len)?;
+    match extable::copy_user(dst.as_mut_ptr(), src, len) {
+        0 => Ok(())
+      , _ => Err(Efault)
+    }
+}
+
+/// Read a `u64` from user memory.
+pub fn read_user_u64(addr: VAddr) -> Result<u64, Efault> {
+    let mut value = 0u64;
+    unsafe {
+        copy_from_user( &mut value as *mut u64 as *mut u8, addr
+                      , mem::size_of::<u64>())?;
+    }
+    Ok(value)
+}
+
+/// Write an `i32` to user memory.
+pub fn write_user_i32(addr: VAddr, value: i32) -> Result<(), Efault> {
+    unsafe {
+        copy_to_user( addr, &value as *const i32 as *const u8
+                    , mem::size_of::<i32>())
+    }
+}
+
+/// Copy the NUL-terminated string at `addr` into the kernel.
+///
+/// At most `max` bytes (including the NUL) are examined. The returned
+/// string does not include the terminator.
+pub fn copy_user_cstr(addr: VAddr, max: usize) -> Result<Vec<u8>, CStrError> {
+    let page_size = PAGE_SIZE as usize;
+    let mut string = Vec::new();
+    let mut buf = [0u8; CSTR_CHUNK];
+    let mut pos = addr.as_usize();
+    while string.len() < max {
+        // copy up to the end of the current page at a time, as the string
+        // may end right before an unmapped page
+        let page_end = (pos & !(page_size - 1)) + page_size;
+        let len = cmp::min( cmp::min(page_end - pos, max - string.len())
+                          , CSTR_CHUNK);
+        unsafe { copy_from_user(buf.as_mut_ptr(), VAddr::from(pos), len)? };
+        let chunk = &buf[..len];
+        match chunk.iter().position(|&b| b == 0) {
+            Some(nul) => {
+                string.extend_from_slice(&chunk[..nul]);
+                return Ok(string)
+            }
+          , None => string.extend_from_slice(chunk)
+        }
+        pos += len;
+    }
+    Err(CStrError::TooLong)
+}
@@ -0,0 +1,252 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Virtual memory regions.
+//!
+//! A task's [`VmMap`](struct.VmMap.html) records which parts of its user
+//! address space are valid, and with what permissions. The page tables are
+//! a cache of this information, not the source of truth: a page may be
+//! inside a region without being mapped yet, or after being swapped out.
+use alloc::arc::Arc;
+use alloc::btree_map::{self, BTreeMap};
+use alloc::vec::Vec;
+use core::fmt;
+use memory::{PAGE_SIZE, VAddr};
+
+use dev::block::BlockDevice;
+use fs::Inode;
+
+use super::USER_SPACE_END;
+
+/// The lowest address `find_free_region` will hand out, leaving plenty of
+/// room below it for program images and their heaps.
+pub const MMAP_BASE: usize = 0x0000_1000_0000_0000;
+
+bitflags! {
+    /// Permissions and properties of a `VmRegion`.
+    pub flags VmFlags: u32 {
+        const VM_READ =      1 << 0,
+        const VM_WRITE =     1 << 1,
+        const VM_EXEC =      1 << 2,
+        /// The region is a stack, and may grow downwards.
+        const VM_GROWSDOWN = 1 << 3
+    }
+}
+
+/// Where a region's pages come from, and where they go when their frames
+/// are wanted for something else.
+#[derive(Clone)]
+pub enum VmBacking { /// Zero-filled memory, which is only ever in RAM
+                     Anonymous
+                   , /// Zero-filled memory, which may be swapped out to
+                     /// the swap area starting at block `block_offset`
+                     /// of `device`
+                     Swap { device: Arc<BlockDevice>, block_offset: u64 }
+                   , /// A private copy of `inode`, from byte `offset` on,
+                     /// read in a page at a time as it's touched. Writes
+                     /// aren't written back.
+                     File { inode: Arc<Inode>, offset: u64 }
+                   }
+
+impl VmBacking {
+    /// Returns the backing of the part of a region that starts `by` bytes
+    /// into it.
+    fn advanced(&self, by: usize) -> Self {
+        match *self {
+            VmBacking::File { ref inode, offset } =>
+                VmBacking::File { inode: inode.clone()
+                                , offset: offset + by as u64 }
+          , ref other => other.clone()
+        }
+    }
+}
+
+impl fmt::Debug for VmBacking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VmBacking::Anonymous => f.write_str("Anonymous")
+          , VmBacking::Swap { block_offset, .. } =>
+                write!(f, "Swap {{ block_offset: {} }}", block_offset)
+          , VmBacking::File { offset, .. } =>
+                write!(f, "File {{ offset: {:#x} }}", offset)
+        }
+    }
+}
+
+/// A contiguous, page-aligned range of user virtual memory.
+#[derive(Clone)]
+pub struct VmRegion { /// The first address in the region
+                      pub start: VAddr
+                    , /// One past the last address in the region
+                      pub end: VAddr
+                    , pub flags: VmFlags
+                    , pub backing: VmBacking
+                    }
+
+impl VmRegion {
+    /// Returns an anonymous region from `start` up to `end`.
+    #[inline]
+    pub fn new(start: VAddr, end: VAddr, flags: VmFlags) -> Self {
+        VmRegion { start: start
+                 , end: end
+                 , flags: flags
+                 , backing: VmBacking::Anonymous
+                 }
+    }
+
+    /// Returns this region with its pages backed by `backing`.
+    #[inline]
+    pub fn with_backing(self, backing: VmBacking) -> Self {
+        VmRegion { backing: backing, ..self }
+    }
+
+    /// Returns the length of this region, in bytes.
+    #[inline] pub fn len(&self) -> usize {
+        self.end.as_usize() - self.start.as_usize()
+    }
+
+    /// Returns true if `addr` lies within this region.
+    #[inline] pub fn contains(&self, addr: VAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Returns true if this region overlaps the range `[start, end)`.
+    #[inline] pub fn overlaps(&self, start: VAddr, end: VAddr) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+impl fmt::Debug for VmRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VmRegion {{ {:#x}-{:#x} {:?} {:?} }}"
+              , self.start.as_usize(), self.end.as_usize(), self.flags
+              , self.backing)
+    }
+}
+
+/// Errors returned by `VmMap` operations.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VmError {
+    /// The requested range overlaps an existing region.
+    Overlap
+}
+
+/// The set of valid regions in a user address space.
+#[derive(Clone, Debug, Default)]
+pub struct VmMap {
+    /// Regions, keyed by their start address.
+    regions: BTreeMap<usize, VmRegion>
+}
+
+impl VmMap {
+    #[inline] pub fn new() -> Self { Self::default() }
+
+    /// Returns the region containing `addr`, if there is one.
+    pub fn find(&self, addr: VAddr) -> Option<&VmRegion> {
+        self.regions.range(..addr.as_usize() + 1)
+            .next_back()
+            .map(|(_, region)| region)
+            .and_then(|region|
+                if region.contains(addr) { Some(region) } else { None })
+    }
+
+    /// Add a new region.
+    ///
+    /// Fails if the region overlaps any existing region.
+    pub fn insert(&mut self, region: VmRegion) -> Result<(), VmError> {
+        if self.iter().any(|r| r.overlaps(region.start, region.end)) {
+            return Err(VmError::Overlap)
+        }
+        self.regions.insert(region.start.as_usize(), region);
+        Ok(())
+    }
+
+    /// Returns an iterator over all regions, in address order.
+    #[inline]
+    pub fn iter(&self) -> btree_map::Values<usize, VmRegion> {
+        self.regions.values()
+    }
+
+    /// Returns the total size of all regions, in bytes.
+    pub fn total_mapped_bytes(&self) -> usize {
+        self.iter().map(VmRegion::len).sum()
+    }
+
+    /// Returns true if every byte of `[start, end)` is covered by a region
+    /// whose flags contain `required`.
+    pub fn covers(&self, start: VAddr, end: VAddr, required: VmFlags)
+                 -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(region) if region.flags.contains(required) =>
+                    addr = region.end
+              , _ => return false
+            }
+        }
+        true
+    }
+
+    /// Find a gap of at least `len` bytes (rounded up to a whole number of
+    /// pages) between `MMAP_BASE` and the top of user space.
+    ///
+    /// Returns the start address of the gap, or `None` if there's no room.
+    pub fn find_free_region(&self, len: usize) -> Option<VAddr> {
+        let page_size = PAGE_SIZE as usize;
+        let len = len.checked_add(page_size - 1)? & !(page_size - 1);
+        let mut candidate = MMAP_BASE;
+        for region in self.iter() {
+            let (start, end) = (region.start.as_usize(), region.end.as_usize());
+            if end <= candidate { continue }
+            if start >= candidate && start - candidate >= len {
+                break
+            }
+            candidate = end;
+        }
+        match candidate.checked_add(len) {
+            Some(end) if end <= USER_SPACE_END => Some(VAddr::from(candidate))
+          , _ => None
+        }
+    }
+
+    /// Remove `[start, end)` from the map, splitting any regions that
+    /// straddle its boundaries.
+    ///
+    /// Returns the ranges that were actually removed (i.e. the parts of
+    /// `[start, end)` that were covered by a region).
+    pub fn remove_range(&mut self, start: VAddr, end: VAddr)
+                       -> Vec<(VAddr, VAddr)> {
+        let overlapping: Vec<usize>
+            = self.iter()
+                  .filter(|r| r.overlaps(start, end))
+                  .map(|r| r.start.as_usize())
+                  .collect();
+        let mut removed = Vec::with_capacity(overlapping.len());
+        for key in overlapping {
+            let region = self.regions.remove(&key)
+                             .expect("region vanished while unmapping");
+            if region.start < start {
+                // keep the part below the removed range
+                let below = VmRegion { end: start, ..region.clone() };
+                self.regions.insert(below.start.as_usize(), below);
+            }
+            if region.end > end {
+                // keep the part above the removed range
+                let skipped = end.as_usize() - region.start.as_usize();
+                let above = VmRegion { start: end
+                                     , backing: region.backing.advanced(skipped)
+                                     , ..region.clone() };
+                self.regions.insert(above.start.as_usize(), above);
+            }
+            let lo = if region.start > start { region.start } else { start };
+            let hi = if region.end < end { region.end } else { end };
+            removed.push((lo, hi));
+        }
+        removed
+    }
+}
@@ -0,0 +1,197 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The kernel symbol tables: the one modules are linked against, and,
+//! with the `kallsyms` feature, one for the whole kernel image.
+//!
+//! The table modules are linked against starts out with just the functions
+//! modules are allowed to call, all of which use the C calling convention.
+//! Each module adds its global symbols while it's loaded, so that modules
+//! loaded after it can call into it.
+//!
+//! The kernel image's table, [`SYMBOLS`](static.SYMBOLS.html), is made by
+//! `build.rs` from what `nm` says about the image the last build linked,
+//! and is only right once `make kernel-kallsyms` has linked the kernel
+//! enough times for its addresses to settle. As in Linux, the names are
+//! compressed: each is a string of byte tokens, where a token may stand
+//! for several bytes, and they're only expanded as they're compared or
+//! written out.
+use alloc::btree_map::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "kallsyms")] use core::fmt::{self, Write};
+#[cfg(feature = "kallsyms")] use memory::VAddr;
+use spin::Mutex;
+
+use sos_alloc::buddy::system::{ __rust_allocate, __rust_deallocate
+                              , __rust_reallocate };
+use super::{printk, ModuleId};
+
+/// An entry in the symbol table.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Symbol { /// The symbol's address
+                    pub addr: usize
+                  , /// The module that defines the symbol, or `None` if it's
+                    /// part of the kernel
+                    pub owner: Option<ModuleId>
+                  }
+
+lazy_static! {
+    /// Every symbol modules can link against, by name.
+    static ref EXPORTS: Mutex<BTreeMap<String, Symbol>> = {
+        let exports: [(&'static str, usize); 4]
+            = [ ("printk", printk as usize)
+              , ("__rust_allocate", __rust_allocate as usize)
+              , ("__rust_deallocate", __rust_deallocate as usize)
+              , ("__rust_reallocate", __rust_reallocate as usize)
+              ];
+        let mut symbols = BTreeMap::new();
+        for &(name, addr) in exports.iter() {
+            symbols.insert( String::from(name)
+                          , Symbol { addr: addr, owner: None });
+        }
+        Mutex::new(symbols)
+    };
+}
+
+/// Returns the symbol called `name`, if there is one.
+pub fn lookup(name: &str) -> Option<Symbol> {
+    EXPORTS.lock().get(name).cloned()
+}
+
+/// Add a symbol called `name` at `addr`, defined by the module `owner`.
+///
+/// Returns false, without adding it, if there's already a symbol with that
+/// name.
+pub fn add(name: &str, addr: usize, owner: ModuleId) -> bool {
+    let mut symbols = EXPORTS.lock();
+    if symbols.contains_key(name) { return false }
+    symbols.insert( String::from(name)
+                  , Symbol { addr: addr, owner: Some(owner) });
+    true
+}
+
+/// Remove every symbol defined by the module `owner`.
+pub fn remove_owned_by(owner: ModuleId) {
+    let mut symbols = EXPORTS.lock();
+    let owned: Vec<String>
+        = symbols.iter()
+                 .filter(|&(_, symbol)| symbol.owner == Some(owner))
+                 .map(|(name, _)| name.clone())
+                 .collect();
+    for name in owned {
+        symbols.remove(&name);
+    }
+}
+
+/// Where `linker.ld` puts the kernel. The image's symbols are stored as
+/// 32-bit offsets from here.
+#[cfg(feature = "kallsyms")]
+pub const KERNEL_BASE: usize = 0x10_0000;
+
+// `SYMBOLS`, and the `NAMES`, `TOKENS` and `TOKEN_INDEX` its names are
+// kept in.
+#[cfg(feature = "kallsyms")]
+include!(concat!(env!("OUT_DIR"), "/kallsyms.rs"));
+
+/// A symbol in the kernel image.
+#[cfg(feature = "kallsyms")]
+#[derive(Copy, Clone)]
+pub struct SymbolName { /// The symbol's address
+                        pub addr: VAddr
+                      , /// The compressed name, as token numbers
+                        tokens: &'static [u8]
+                      }
+
+#[cfg(feature = "kallsyms")]
+impl SymbolName {
+    fn from_entry(&(offset, name): &(u32, u32)) -> Self {
+        // the length is one byte, or two if the first has its top bit set.
+        let name = name as usize;
+        let (len, start) = if NAMES[name] & 0x80 == 0 {
+            (NAMES[name] as usize, name + 1)
+        } else {
+            ((NAMES[name] & 0x7f) as usize | (NAMES[name + 1] as usize) << 7
+            , name + 2)
+        };
+        SymbolName { addr: VAddr::from(KERNEL_BASE + offset as usize)
+                   , tokens: &NAMES[start .. start + len]
+                   }
+    }
+
+    /// Returns true if this symbol is called `name`.
+    pub fn is(&self, name: &str) -> bool {
+        let mut rest = name.as_bytes();
+        for &token in self.tokens {
+            let bytes = token_bytes(token);
+            if !rest.starts_with(bytes) { return false }
+            rest = &rest[bytes.len()..];
+        }
+        rest.is_empty()
+    }
+}
+
+#[cfg(feature = "kallsyms")]
+impl fmt::Display for SymbolName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &token in self.tokens {
+            for &byte in token_bytes(token) {
+                f.write_char(byte as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kallsyms")]
+impl fmt::Debug for SymbolName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({:?})", self, self.addr)
+    }
+}
+
+/// Returns the bytes `token` stands for.
+#[cfg(feature = "kallsyms")]
+#[inline]
+fn token_bytes(token: u8) -> &'static [u8] {
+    let token = token as usize;
+    &TOKENS[TOKEN_INDEX[token] as usize .. TOKEN_INDEX[token + 1] as usize]
+}
+
+/// Returns the symbol in the kernel image that `addr` is in, which is the
+/// last one that starts at or below it.
+///
+/// The name comes back compressed, as a
+/// [`SymbolName`](struct.SymbolName.html), which expands it as it's
+/// formatted, so this doesn't allocate and is safe to call from a panic.
+#[cfg(feature = "kallsyms")]
+pub fn name_of(addr: VAddr) -> Option<SymbolName> {
+    let addr = addr.as_usize();
+    if addr < KERNEL_BASE
+        || addr - KERNEL_BASE > u32::max_value() as usize {
+        return None
+    }
+    let offset = (addr - KERNEL_BASE) as u32;
+    match SYMBOLS.binary_search_by_key(&offset, |&(offset, _)| offset) {
+        Ok(i) => Some(SymbolName::from_entry(&SYMBOLS[i]))
+      , Err(0) => None
+      , Err(i) => Some(SymbolName::from_entry(&SYMBOLS[i - 1]))
+    }
+}
+
+/// Returns the address of the symbol in the kernel image called `name`,
+/// if there is one.
+///
+/// The names aren't indexed, so this looks at each of them in turn.
+#[cfg(feature = "kallsyms")]
+pub fn addr_of(name: &str) -> Option<VAddr> {
+    SYMBOLS.iter()
+           .map(SymbolName::from_entry)
+           .find(|symbol| symbol.is(name))
+           .map(|symbol| symbol.addr)
+}
@@ -0,0 +1,462 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Loadable kernel modules.
+//!
+//! A module is an `x86_64` ELF relocatable object (`ET_REL`). [`load`]
+//! copies its allocated sections into the module region, which lies above
+//! the kernel image and within 2 GiB of it, so that 32-bit PC-relative
+//! relocations can reach the kernel. Undefined symbols are looked up in
+//! the [kernel symbol table](kallsyms/index.html), and the `R_X86_64_64`,
+//! `R_X86_64_PC32`, and `R_X86_64_PLT32` relocations against them are
+//! applied. There's no PLT: calls go straight to the symbol.
+//!
+//! The module's `.modinfo` section must hold a [`ModInfo`], giving the
+//! addresses of its `module_init` function, which is called once it's
+//! loaded, and its `module_exit` function, if it has one, which
+//! [`unload`] calls before freeing it.
+//!
+//! Module memory is always writable and executable, and unloading only
+//! flushes the current CPU's TLB.
+//!
+//! [`load`]: fn.load.html
+//! [`unload`]: fn.unload.html
+//! [`ModInfo`]: struct.ModInfo.html
+use alloc::btree_map::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{cmp, mem, ptr, slice, str};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use elf::FileHeader;
+use elf::file::{self, Class, DataEncoding, Header, Machine};
+use memory::{PAGE_SIZE, Page, VAddr, VirtualPage};
+use paging::Mapper;
+use paging::arch::ActivePageTable;
+use paging::arch::table::WRITABLE;
+use sos_alloc::FrameAllocator;
+use spin::Mutex;
+
+use arch::tlb_shootdown;
+use mm::frame;
+use task::elf64::{ read_at, Rela64, SectionHeader64, Symbol64
+                 , R_X86_64_64, R_X86_64_NONE, SHN_ABS, SHN_UNDEF, SHT_RELA
+                 , STB_WEAK };
+
+pub mod kallsyms;
+
+/// The first address in the module region.
+pub const MODULE_BASE: usize = 0x4000_0000;
+/// One past the last address in the module region.
+pub const MODULE_END: usize = 0x8000_0000;
+
+/// `SHT_SYMTAB`: the section header type of a symbol table.
+const SHT_SYMTAB: u32 = 2;
+/// `SHT_NOBITS`: the section header type of a section that takes up no
+/// space in the file, such as `.bss`.
+const SHT_NOBITS: u32 = 8;
+/// `SHF_ALLOC`: the section is in memory while the module runs.
+const SHF_ALLOC: u64 = 0x2;
+/// `SHN_COMMON`: the section index of a common symbol.
+const SHN_COMMON: u16 = 0xfff2;
+/// `STB_GLOBAL`: the binding of a global symbol.
+const STB_GLOBAL: u8 = 1;
+
+/// `R_X86_64_PC32`: the symbol's value plus the addend, relative to the
+/// place being relocated, in 32 bits.
+const R_X86_64_PC32: u32 = 2;
+/// `R_X86_64_PLT32`: the symbol's PLT entry, relative to the place being
+/// relocated. Without a PLT, this is the same as `R_X86_64_PC32`.
+const R_X86_64_PLT32: u32 = 4;
+
+/// A module's `.modinfo` section.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ModInfo { /// The address of `extern "C" fn module_init()`
+                     pub init: u64
+                   , /// The address of `extern "C" fn module_exit()`, or 0
+                     pub exit: u64
+                   }
+
+/// Identifies a loaded module.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ModuleId(pub u32);
+
+/// Errors returned while loading or unloading a module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ModuleError {
+    /// The file isn't a module we know how to load.
+    Invalid(&'static str)
+  , /// The module needs a relocation of a type we don't support.
+    UnknownRelocation(u32)
+  , /// A relocated value doesn't fit in its place.
+    RelocationOverflow
+  , /// The module uses a symbol that isn't in the kernel symbol table.
+    UndefinedSymbol(String)
+  , /// The module defines a symbol that's already in the kernel symbol
+    /// table.
+    DuplicateSymbol(String)
+  , /// There's no room in the module region, or no memory to back it.
+    NoMemory
+  , /// There's no module with that ID.
+    NoSuchModule
+  , /// Another module uses this module's symbols.
+    InUse
+}
+
+/// The memory a module is loaded into, which is unmapped when this is
+/// dropped.
+struct ModuleMemory { start: usize
+                    , /// The number of pages in the range
+                      pages: usize
+                    , /// The number of pages mapped so far
+                      mapped: usize
+                    }
+
+/// A loaded module.
+struct Module { name: String
+              , memory: ModuleMemory
+              , exit: Option<extern "C" fn()>
+              , /// The modules whose symbols this module uses
+                deps: Vec<ModuleId>
+              }
+
+lazy_static! {
+    /// The ranges of the module region in use, as start to end.
+    static ref RANGES: Mutex<BTreeMap<usize, usize>>
+        = Mutex::new(BTreeMap::new());
+    /// Every loaded module.
+    static ref MODULES: Mutex<BTreeMap<ModuleId, Module>>
+        = Mutex::new(BTreeMap::new());
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Log the `len` bytes of UTF-8 text at `msg`.
+///
+/// This is exported to modules, which have no other way to write to the
+/// console.
+pub extern "C" fn printk(msg: *const u8, len: usize) {
+    let bytes = unsafe { slice::from_raw_parts(msg, len) };
+    match str::from_utf8(bytes) {
+        Ok(text) => info!(target: "module", "{}", text)
+      , Err(_) => warn!(target: "module", "printk: text isn't UTF-8")
+    }
+}
+
+impl ModuleMemory {
+    /// Reserve `len` bytes of the module region, and map them to zeroed
+    /// frames.
+    fn new(len: usize) -> Result<ModuleMemory, ModuleError> {
+        let page_size = PAGE_SIZE as usize;
+        let len = cmp::max(len, 1)
+                      .checked_add(page_size - 1)
+                      .ok_or(ModuleError::NoMemory)? & !(page_size - 1);
+        let start = {
+            let mut ranges = RANGES.lock();
+            let mut candidate = MODULE_BASE;
+            for (&start, &end) in ranges.iter() {
+                if start >= candidate && start - candidate >= len { break }
+                candidate = cmp::max(candidate, end);
+            }
+            if MODULE_END - candidate < len {
+                return Err(ModuleError::NoMemory)
+            }
+            ranges.insert(candidate, candidate + len);
+            candidate
+        };
+
+        let mut memory = ModuleMemory { start: start
+                                      , pages: len / page_size
+                                      , mapped: 0
+                                      };
+        let mut table = unsafe { ActivePageTable::new() };
+        let mut frames = frame::allocator();
+        let first = VirtualPage::containing(VAddr::from(start));
+        while memory.mapped < memory.pages {
+            let page = VirtualPage { number: first.number + memory.mapped };
+            // dropping `memory` unmaps what's been mapped so far.
+            table.map_to_any(page, WRITABLE, &mut frames)
+                 .map_err(|_| ModuleError::NoMemory)?;
+            memory.mapped += 1;
+        }
+        unsafe { ptr::write_bytes(start as *mut u8, 0, len); }
+        Ok(memory)
+    }
+
+    /// Returns true if `addr` is in this memory.
+    #[inline]
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.start
+            && addr - self.start < self.pages * PAGE_SIZE as usize
+    }
+}
+
+impl Drop for ModuleMemory {
+    fn drop(&mut self) {
+        let mut table = unsafe { ActivePageTable::new() };
+        let mut frames = frame::allocator();
+        let first = VirtualPage::containing(VAddr::from(self.start));
+        for i in 0 .. self.mapped {
+            let page = VirtualPage { number: first.number + i };
+            // other CPUs may still have the page cached, so the frame can
+            // only be reused once they've all flushed it.
+            if let Ok(frame) = table.unmap_no_free(page) {
+                tlb_shootdown::broadcast(page.base());
+                unsafe { frames.deallocate(frame) };
+            }
+        }
+        RANGES.lock().remove(&self.start);
+    }
+}
+
+/// Returns the NUL-terminated string at `offset` in the string table
+/// `strtab`.
+fn name_at<'a>(elf: &'a [u8], strtab: &SectionHeader64, offset: u32)
+              -> Result<&'a str, ModuleError> {
+    let start = strtab.offset as usize + offset as usize;
+    let end = cmp::min( elf.len()
+                      , strtab.offset.saturating_add(strtab.size) as usize);
+    if offset as u64 >= strtab.size || start >= end {
+        return Err(ModuleError::Invalid("name out of bounds"))
+    }
+    let bytes = &elf[start .. end];
+    let len = bytes.iter().position(|&b| b == 0)
+                   .ok_or(ModuleError::Invalid("unterminated name"))?;
+    str::from_utf8(&bytes[..len])
+        .map_err(|_| ModuleError::Invalid("name isn't UTF-8"))
+}
+
+/// Load the module `name` from the ELF relocatable object `elf`, and call
+/// its `module_init` function.
+pub fn load(elf: &[u8], name: &str) -> Result<ModuleId, ModuleError> {
+    let header = <FileHeader<u64> as Header>::from_slice(elf)
+        .map_err(ModuleError::Invalid)?;
+    let ident = header.ident();
+    if !ident.is_valid() || ident.class != Class::Elf64 {
+        return Err(ModuleError::Invalid("not a 64-bit ELF file"))
+    }
+    if ident.encoding != DataEncoding::LittleEndian {
+        return Err(ModuleError::Invalid("not a little-endian ELF file"))
+    }
+    if header.get_type() != file::Type::Relocatable {
+        return Err(ModuleError::Invalid("not a relocatable object"))
+    }
+    if header.machine() != Machine::X86_64 {
+        return Err(ModuleError::Invalid("not an x86_64 object"))
+    }
+    if header.sh_entry_size() != mem::size_of::<SectionHeader64>() {
+        return Err(ModuleError::Invalid("bad section header size"))
+    }
+    let mut sections = Vec::with_capacity(header.sh_count());
+    for idx in 0 .. header.sh_count() {
+        let section: SectionHeader64
+            = read_at(elf, header.section_index(idx).start)
+                .ok_or(ModuleError::Invalid("section headers out of bounds"))?;
+        if section.ty != SHT_NOBITS
+            && section.offset.checked_add(section.size)
+                             .map_or(true, |end| end > elf.len() as u64) {
+            return Err(ModuleError::Invalid("section out of bounds"))
+        }
+        sections.push(section);
+    }
+
+    // -- lay out the allocated sections, and copy them in ------------------
+    let mut placed: Vec<Option<usize>> = vec![None; sections.len()];
+    let mut size = 0;
+    for (idx, section) in sections.iter().enumerate() {
+        if section.flags & SHF_ALLOC == 0 || section.size == 0 { continue }
+        let align = cmp::max(section.addralign as usize, 1);
+        if !align.is_power_of_two() || align > PAGE_SIZE as usize {
+            return Err(ModuleError::Invalid("bad section alignment"))
+        }
+        size = (size + align - 1) & !(align - 1);
+        placed[idx] = Some(size);
+        size += section.size as usize;
+        if size > MODULE_END - MODULE_BASE {
+            return Err(ModuleError::NoMemory)
+        }
+    }
+    let memory = ModuleMemory::new(size)?;
+    for (section, place) in sections.iter().zip(placed.iter_mut()) {
+        if let Some(ref mut offset) = *place {
+            *offset += memory.start;
+            if section.ty == SHT_NOBITS { continue }
+            let start = section.offset as usize;
+            let bytes = &elf[start .. start + section.size as usize];
+            unsafe {
+                ptr::copy_nonoverlapping( bytes.as_ptr(), *offset as *mut u8
+                                        , bytes.len());
+            }
+        }
+    }
+
+    // -- resolve the symbols -----------------------------------------------
+    let id = ModuleId(NEXT_ID.fetch_add(1, Ordering::Relaxed) as u32);
+    let symtab_idx = sections.iter().position(|s| s.ty == SHT_SYMTAB);
+    let mut symbols: Vec<Option<u64>> = Vec::new();
+    let mut exports = Vec::new();
+    let mut deps = Vec::new();
+    if let Some(symtab_idx) = symtab_idx {
+        let symtab = sections[symtab_idx];
+        let strtab = *sections.get(symtab.link as usize)
+            .ok_or(ModuleError::Invalid("string table out of bounds"))?;
+        let count = symtab.size as usize / mem::size_of::<Symbol64>();
+        for i in 0 .. count {
+            let offset = symtab.offset as usize
+                       + i * mem::size_of::<Symbol64>();
+            let sym: Symbol64 = read_at(elf, offset)
+                .ok_or(ModuleError::Invalid("symbol out of bounds"))?;
+            let value = match sym.shndx {
+                _ if i == 0 => Some(0)
+              , SHN_UNDEF => {
+                    let name = name_at(elf, &strtab, sym.name)?;
+                    match kallsyms::lookup(name) {
+                        Some(symbol) => {
+                            if let Some(owner) = symbol.owner {
+                                if !deps.contains(&owner) { deps.push(owner) }
+                            }
+                            Some(symbol.addr as u64)
+                        }
+                      , None if sym.info >> 4 == STB_WEAK => Some(0)
+                      , None => return Err(ModuleError::UndefinedSymbol(
+                                    String::from(name)))
+                    }
+                }
+              , SHN_ABS => Some(sym.value)
+              , SHN_COMMON => return Err(ModuleError::Invalid(
+                    "common symbols aren't supported"))
+              , shndx => placed.get(shndx as usize)
+                               .and_then(|place| *place)
+                               .map(|base| base as u64 + sym.value)
+            };
+            if let Some(addr) = value {
+                if sym.info >> 4 == STB_GLOBAL && sym.shndx != SHN_UNDEF {
+                    exports.push((name_at(elf, &strtab, sym.name)?, addr));
+                }
+            }
+            symbols.push(value);
+        }
+    }
+
+    // -- apply the relocations ---------------------------------------------
+    for rela in sections.iter().filter(|s| s.ty == SHT_RELA) {
+        let target = rela.info as usize;
+        let target_base = match placed.get(target) {
+            Some(&Some(base)) => base
+          , Some(&None) => continue
+          , None => return Err(ModuleError::Invalid("bad relocation target"))
+        };
+        if Some(rela.link as usize) != symtab_idx {
+            return Err(ModuleError::Invalid("bad relocation symbol table"))
+        }
+        if rela.entsize as usize != mem::size_of::<Rela64>() {
+            return Err(ModuleError::Invalid("bad relocation entry size"))
+        }
+        let target_size = sections[target].size;
+        let count = rela.size as usize / mem::size_of::<Rela64>();
+        for i in 0 .. count {
+            let offset = rela.offset as usize + i * mem::size_of::<Rela64>();
+            let entry: Rela64 = read_at(elf, offset)
+                .ok_or(ModuleError::Invalid("relocations out of bounds"))?;
+            let r_type = entry.info as u32;
+            if r_type == R_X86_64_NONE { continue }
+            let width = match r_type {
+                R_X86_64_64 => 8
+              , R_X86_64_PC32 | R_X86_64_PLT32 => 4
+              , other => return Err(ModuleError::UnknownRelocation(other))
+            };
+            if entry.offset.checked_add(width)
+                           .map_or(true, |end| end > target_size) {
+                return Err(ModuleError::Invalid("relocation out of bounds"))
+            }
+            let sym = symbols.get((entry.info >> 32) as usize)
+                .ok_or(ModuleError::Invalid("symbol index out of bounds"))?
+                .ok_or(ModuleError::Invalid("symbol in an unloaded section"))?;
+            let place = target_base + entry.offset as usize;
+            let value = sym.wrapping_add(entry.addend as u64);
+            unsafe {
+                if r_type == R_X86_64_64 {
+                    ptr::write_unaligned(place as *mut u64, value);
+                } else {
+                    let relative = value.wrapping_sub(place as u64) as i64;
+                    if relative < i32::min_value() as i64
+                        || relative > i32::max_value() as i64 {
+                        return Err(ModuleError::RelocationOverflow)
+                    }
+                    ptr::write_unaligned(place as *mut i32, relative as i32);
+                }
+            }
+        }
+    }
+
+    // -- find the entry points ---------------------------------------------
+    let shstrtab = *sections.get(header.sh_str_idx())
+        .ok_or(ModuleError::Invalid("section names out of bounds"))?;
+    let mut modinfo = None;
+    for (section, place) in sections.iter().zip(placed.iter()) {
+        if name_at(elf, &shstrtab, section.name)? != ".modinfo" { continue }
+        match *place {
+            Some(addr) if section.size as usize
+                          >= mem::size_of::<ModInfo>() =>
+                modinfo = Some(unsafe {
+                    ptr::read_unaligned(addr as *const ModInfo)
+                })
+          , _ => return Err(ModuleError::Invalid("bad .modinfo section"))
+        }
+    }
+    let modinfo = modinfo.ok_or(ModuleError::Invalid("no .modinfo section"))?;
+    if !memory.contains(modinfo.init as usize)
+        || (modinfo.exit != 0 && !memory.contains(modinfo.exit as usize)) {
+        return Err(ModuleError::Invalid("entry point outside the module"))
+    }
+    let (init, exit) = unsafe {
+        ( mem::transmute::<usize, extern "C" fn()>(modinfo.init as usize)
+        , if modinfo.exit == 0 { None } else {
+            Some(mem::transmute::<usize, extern "C" fn()>(
+                modinfo.exit as usize))
+          })
+    };
+
+    // -- export the module's symbols, and start it -------------------------
+    for &(name, addr) in &exports {
+        if !kallsyms::add(name, addr as usize, id) {
+            kallsyms::remove_owned_by(id);
+            return Err(ModuleError::DuplicateSymbol(String::from(name)))
+        }
+    }
+    info!("loading module {} ({} bytes at {:#x})", name, size, memory.start);
+    init();
+    MODULES.lock().insert(id, Module { name: String::from(name)
+                                     , memory: memory
+                                     , exit: exit
+                                     , deps: deps
+                                     });
+    Ok(id)
+}
+
+/// Call the module `id`'s `module_exit` function, remove its symbols, and
+/// free its memory.
+///
+/// Fails if another module uses its symbols.
+pub fn unload(id: ModuleId) -> Result<(), ModuleError> {
+    let module = {
+        let mut modules = MODULES.lock();
+        if !modules.contains_key(&id) {
+            return Err(ModuleError::NoSuchModule)
+        }
+        if modules.values().any(|module| module.deps.contains(&id)) {
+            return Err(ModuleError::InUse)
+        }
+        modules.remove(&id).expect("module vanished while unloading")
+    };
+    info!("unloading module {}", module.name);
+    if let Some(exit) = module.exit { exit() }
+    kallsyms::remove_owned_by(id);
+    // dropping the module frees its memory.
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The Address Resolution Protocol, for finding the MAC address of an IPv4
+//! address on the local network.
+//!
+//! Only Ethernet and IPv4 are supported, so every packet looks like this:
+//!
+//! ```text
+//! | htype (2) | ptype (2) | hlen (1) | plen (1) | operation (2)
+//! | sender MAC (6) | sender IP (4) | target MAC (6) | target IP (4) |
+//! ```
+//!
+//! Whatever we hear from is remembered in a small table, the oldest entry
+//! making way for a new one when it's full.
+use spin::Mutex;
+
+use super::{eth, get_u16, put_u16, Ipv4Addr, MacAddr, NetError};
+use super::{config, local_mac, BROADCAST_MAC};
+
+/// The length of an ARP packet for IPv4 over Ethernet.
+pub const PACKET_LEN: usize = 28;
+/// The number of entries in the ARP table.
+pub const TABLE_SIZE: usize = 16;
+
+/// Hardware type: Ethernet.
+const HTYPE_ETHERNET: u16 = 1;
+/// Operations.
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+
+/// The ARP table, and the slot the next new entry goes in.
+struct Table { entries: [Option<(Ipv4Addr, MacAddr)>; TABLE_SIZE]
+             , next: usize
+             }
+
+static TABLE: Mutex<Table>
+    = Mutex::new(Table { entries: [None; TABLE_SIZE], next: 0 });
+
+impl Table {
+    /// Remember that `ip` is at `mac`.
+    fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr) {
+        for entry in self.entries.iter_mut() {
+            match *entry {
+                Some((known, ref mut old)) if known == ip => {
+                    *old = mac;
+                    return
+                }
+              , _ => {}
+            }
+        }
+        self.entries[self.next] = Some((ip, mac));
+        self.next = (self.next + 1) % TABLE_SIZE;
+    }
+
+    fn get(&self, ip: Ipv4Addr) -> Option<MacAddr> {
+        self.entries.iter()
+            .filter_map(|&entry| entry)
+            .find(|&(known, _)| known == ip)
+            .map(|(_, mac)| mac)
+    }
+}
+
+/// Returns the MAC address of `ip`, if it's in the table.
+pub fn lookup(ip: Ipv4Addr) -> Option<MacAddr> {
+    TABLE.lock().get(ip)
+}
+
+/// Returns a copy of the ARP table.
+pub fn table() -> [Option<(Ipv4Addr, MacAddr)>; TABLE_SIZE] {
+    TABLE.lock().entries
+}
+
+/// Build an ARP packet.
+fn packet(op: u16, sender: (MacAddr, Ipv4Addr), target: (MacAddr, Ipv4Addr))
+         -> [u8; PACKET_LEN] {
+    let mut buf = [0u8; PACKET_LEN];
+    put_u16(&mut buf, 0, HTYPE_ETHERNET);
+    put_u16(&mut buf, 2, eth::ETHERTYPE_IPV4);
+    buf[4] = 6;
+    buf[5] = 4;
+    put_u16(&mut buf, 6, op);
+    buf[8..14].copy_from_slice(&(sender.0).0);
+    buf[14..18].copy_from_slice(&(sender.1).0);
+    buf[18..24].copy_from_slice(&(target.0).0);
+    buf[24..28].copy_from_slice(&(target.1).0);
+    buf
+}
+
+/// Ask the local network who has `ip`.
+pub fn request(ip: Ipv4Addr) -> Result<(), NetError> {
+    let mac = local_mac().ok_or(NetError::NoDevice)?;
+    let local_ip = config().ok_or(NetError::NotConfigured)?.ip;
+    let buf = packet( OP_REQUEST, (mac, local_ip)
+                    , (MacAddr::default(), ip));
+    eth::send(BROADCAST_MAC, eth::ETHERTYPE_ARP, &buf)
+}
+
+/// Returns the MAC address of `ip`, or sends a request for it and returns
+/// `NetError::Unresolved` if it isn't known yet.
+pub fn resolve(ip: Ipv4Addr) -> Result<MacAddr, NetError> {
+    match lookup(ip) {
+        Some(mac) => Ok(mac)
+      , None => {
+            request(ip)?;
+            Err(NetError::Unresolved)
+        }
+    }
+}
+
+/// Handle a received ARP packet: remember the sender, and answer it if it
+/// asks for our address.
+pub fn recv(buf: &[u8]) {
+    if buf.len() < PACKET_LEN
+        || get_u16(buf, 0) != HTYPE_ETHERNET
+        || get_u16(buf, 2) != eth::ETHERTYPE_IPV4
+        || buf[4] != 6 || buf[5] != 4 {
+        return
+    }
+    let mut sender_mac = MacAddr::default();
+    sender_mac.0.copy_from_slice(&buf[8..14]);
+    let mut sender_ip = Ipv4Addr::default();
+    sender_ip.0.copy_from_slice(&buf[14..18]);
+    let mut target_ip = Ipv4Addr::default();
+    target_ip.0.copy_from_slice(&buf[24..28]);
+
+    TABLE.lock().insert(sender_ip, sender_mac);
+    let local_ip = match config() {
+        Some(config) => config.ip
+      , None => return
+    };
+    if get_u16(buf, 6) == OP_REQUEST && target_ip == local_ip {
+        if let Some(mac) = local_mac() {
+            let reply = packet( OP_REPLY, (mac, local_ip)
+                              , (sender_mac, sender_ip));
+            let _ = eth::send(sender_mac, eth::ETHERTYPE_ARP, &reply);
+        }
+    }
+}
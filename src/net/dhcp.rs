@@ -0,0 +1,281 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A DHCP client, for getting an address at boot.
+//!
+//! ```text
+//! client                              server
+//!   DISCOVER  0.0.0.0:68 ──▶ 255.255.255.255:67
+//!                        ◀── OFFER    the address, and who's offering it
+//!   REQUEST   0.0.0.0:68 ──▶ 255.255.255.255:67
+//!                        ◀── ACK      (or NAK)
+//! ```
+//!
+//! This runs before `netd` has started and before there's an address, so
+//! rather than going through the rest of the stack, it builds whole frames
+//! itself and polls the card for the replies. Anything else received in
+//! the meantime is dropped.
+//!
+//! Leases aren't renewed: the lease time is recorded in the `NetConfig`,
+//! and nothing looks at it yet.
+use core::cmp;
+use arch::cpu;
+use dev::virtio::net::{VirtioNet, MAX_FRAME_SIZE};
+use task::timer;
+use super::{eth, get_u16, ipv4, udp, Ipv4Addr, MacAddr, NetConfig, NetError
+           , BROADCAST_IP, BROADCAST_MAC};
+
+/// The port servers listen on.
+pub const SERVER_PORT: u16 = 67;
+/// The port clients listen on.
+pub const CLIENT_PORT: u16 = 68;
+
+/// How long to wait for each reply.
+const REPLY_TIMEOUT_NS: u64 = 5_000_000_000;
+/// How often to check the card for replies.
+const POLL_NS: u64 = 10_000_000;
+/// How many times to start again after the first attempt fails.
+const RETRIES: usize = 3;
+/// How long to wait before the first retry. This doubles each time.
+const FIRST_BACKOFF_NS: u64 = 1_000_000_000;
+
+/// The offset of the options, after the fixed BOOTP fields and the magic
+/// cookie.
+const OPTIONS: usize = 240;
+/// The magic cookie that marks the start of the DHCP options.
+const MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+/// Some servers ignore BOOTP messages shorter than this.
+const MIN_MESSAGE: usize = 300;
+/// The longest message we send.
+const MAX_MESSAGE: usize = 548;
+
+/// BOOTP `op`: a message from a client.
+const BOOTREQUEST: u8 = 1;
+/// BOOTP `op`: a message from a server.
+const BOOTREPLY: u8 = 2;
+/// BOOTP `flags`: ask the server to broadcast its replies, since we can't
+/// receive unicast packets without an address.
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMS: u8 = 55;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// Errors returned by [`discover`](fn.discover.html).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DhcpError { /// A message couldn't be sent
+                     Send(NetError)
+                   , /// No server replied in time
+                     Timeout
+                   , /// The server turned down our request
+                     Nak
+                   , /// The server's offer didn't say who it was from
+                     NoServerId
+                   }
+
+impl From<NetError> for DhcpError {
+    fn from(err: NetError) -> Self { DhcpError::Send(err) }
+}
+
+/// What a server's reply says.
+struct Reply { message_type: u8
+             , server: Option<Ipv4Addr>
+             , config: NetConfig
+             }
+
+/// Ask for an address for `net`, retrying with backoff if no server
+/// answers.
+///
+/// This sleeps while it waits, so `net` shouldn't be locked by anything
+/// else the interrupt handler needs.
+pub fn discover(net: &mut VirtioNet) -> Result<NetConfig, DhcpError> {
+    let mut backoff = FIRST_BACKOFF_NS;
+    let mut attempt = 0;
+    loop {
+        // a fresh transaction ID each time, so stale replies are ignored.
+        let xid = unsafe { cpu::rdtsc() } as u32;
+        match exchange(net, xid) {
+            Ok(config) => return Ok(config)
+          , Err(why) if attempt == RETRIES => return Err(why)
+          , Err(why) => warn!( "dhcp: attempt {} failed: {:?}"
+                             , attempt + 1, why)
+        }
+        timer::sleep_until(timer::now_ns() + backoff);
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Run through one DISCOVER, OFFER, REQUEST, ACK exchange.
+fn exchange(net: &mut VirtioNet, xid: u32) -> Result<NetConfig, DhcpError> {
+    let mac = MacAddr(net.mac());
+    let mut message = [0u8; MAX_MESSAGE];
+
+    let len = write_message(&mut message, xid, mac, DHCPDISCOVER, None);
+    send(net, &message[..len])?;
+    let offer = wait_for(net, xid, mac, DHCPOFFER)?;
+    let server = offer.server.ok_or(DhcpError::NoServerId)?;
+
+    let len = write_message( &mut message, xid, mac, DHCPREQUEST
+                           , Some((offer.config.ip, server)));
+    send(net, &message[..len])?;
+    let ack = wait_for(net, xid, mac, DHCPACK)?;
+    Ok(ack.config)
+}
+
+/// Write a message of type `message_type` to `buf`, and return its length.
+/// `request` is the offered address and the server that offered it, for a
+/// DHCPREQUEST.
+fn write_message( buf: &mut [u8], xid: u32, mac: MacAddr, message_type: u8
+                , request: Option<(Ipv4Addr, Ipv4Addr)>) -> usize {
+    for byte in buf.iter_mut() { *byte = 0 }
+    buf[0] = BOOTREQUEST;
+    buf[1] = 1; // Ethernet
+    buf[2] = 6; // the length of its addresses
+    buf[4] = (xid >> 24) as u8;
+    buf[5] = (xid >> 16) as u8;
+    buf[6] = (xid >> 8) as u8;
+    buf[7] = xid as u8;
+    buf[10] = (FLAG_BROADCAST >> 8) as u8;
+    buf[28..34].copy_from_slice(&mac.0);
+    buf[236..OPTIONS].copy_from_slice(&MAGIC_COOKIE);
+
+    let mut at = OPTIONS;
+    {
+        let mut option = |code: u8, value: &[u8]| {
+            buf[at] = code;
+            buf[at + 1] = value.len() as u8;
+            buf[at + 2 .. at + 2 + value.len()].copy_from_slice(value);
+            at += 2 + value.len();
+        };
+        option(OPT_MESSAGE_TYPE, &[message_type]);
+        if let Some((ip, server)) = request {
+            option(OPT_REQUESTED_IP, &ip.0);
+            option(OPT_SERVER_ID, &server.0);
+        }
+        option( OPT_PARAMS
+              , &[OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS, OPT_LEASE_TIME]);
+    }
+    buf[at] = OPT_END;
+    cmp::max(at + 1, MIN_MESSAGE)
+}
+
+/// Broadcast `message` from `0.0.0.0`.
+fn send(net: &mut VirtioNet, message: &[u8]) -> Result<(), NetError> {
+    const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    eth::write_header( &mut frame, BROADCAST_MAC, MacAddr(net.mac())
+                     , eth::ETHERTYPE_IPV4);
+    let udp_len = {
+        let datagram = &mut frame[eth::HEADER_LEN + ipv4::HEADER_LEN ..];
+        udp::write_datagram( datagram, UNSPECIFIED, CLIENT_PORT
+                           , BROADCAST_IP, SERVER_PORT, message)
+    };
+    ipv4::write_header( &mut frame[eth::HEADER_LEN..], UNSPECIFIED
+                      , BROADCAST_IP, ipv4::PROTO_UDP, udp_len);
+    let len = eth::HEADER_LEN + ipv4::HEADER_LEN + udp_len;
+    net.send(&frame[..len])?;
+    Ok(())
+}
+
+/// Wait for a reply of type `message_type` to transaction `xid`.
+fn wait_for(net: &mut VirtioNet, xid: u32, mac: MacAddr, message_type: u8)
+           -> Result<Reply, DhcpError> {
+    let deadline = timer::now_ns() + REPLY_TIMEOUT_NS;
+    loop {
+        while let Some(packet) = net.receive() {
+            let reply = match parse_frame(packet.as_bytes(), xid, mac) {
+                Some(reply) => reply
+              , None => continue
+            };
+            if reply.message_type == DHCPNAK { return Err(DhcpError::Nak) }
+            if reply.message_type == message_type { return Ok(reply) }
+        }
+        let now = timer::now_ns();
+        if now >= deadline { return Err(DhcpError::Timeout) }
+        timer::sleep_until(cmp::min(now + POLL_NS, deadline));
+    }
+}
+
+/// Returns the reply to `xid` in the frame `buf`, if it holds one.
+fn parse_frame(buf: &[u8], xid: u32, mac: MacAddr) -> Option<Reply> {
+    if buf.len() < eth::HEADER_LEN
+        || get_u16(buf, 12) != eth::ETHERTYPE_IPV4 {
+        return None
+    }
+    let (header, data) = ipv4::parse(&buf[eth::HEADER_LEN..])?;
+    if header.protocol != ipv4::PROTO_UDP { return None }
+    let (_, dst_port, message) = udp::parse(header.src, header.dst, data)?;
+    if dst_port != CLIENT_PORT { return None }
+    parse_message(message, xid, mac)
+}
+
+/// Returns the reply to `xid` in the BOOTP message `buf`, if it is one.
+fn parse_message(buf: &[u8], xid: u32, mac: MacAddr) -> Option<Reply> {
+    if buf.len() < OPTIONS || buf[0] != BOOTREPLY
+        || get_u32(buf, 4) != xid || buf[28..34] != mac.0
+        || buf[236..OPTIONS] != MAGIC_COOKIE {
+        return None
+    }
+    let mut message_type = None;
+    let mut server = None;
+    // if the server doesn't give a subnet mask, nothing else is treated as
+    // being on the local network.
+    let mut config = NetConfig { ip: addr(&buf[16..20])?
+                               , netmask: Ipv4Addr([0xff; 4])
+                               , router: None
+                               , dns: None
+                               , lease_secs: 0
+                               };
+    let mut at = OPTIONS;
+    while at < buf.len() {
+        let code = buf[at];
+        if code == OPT_PAD { at += 1; continue }
+        if code == OPT_END || at + 1 >= buf.len() { break }
+        let len = buf[at + 1] as usize;
+        let value = buf.get(at + 2 .. at + 2 + len)?;
+        match code {
+            OPT_SUBNET_MASK => config.netmask = addr(value)?
+          , OPT_ROUTER => config.router = addr(value)
+          , OPT_DNS => config.dns = addr(value)
+          , OPT_LEASE_TIME if len == 4 => config.lease_secs = get_u32(value, 0)
+          , OPT_MESSAGE_TYPE if len == 1 => message_type = Some(value[0])
+          , OPT_SERVER_ID => server = addr(value)
+          , _ => {}
+        }
+        at += 2 + len;
+    }
+    Some(Reply { message_type: message_type?
+               , server: server
+               , config: config
+               })
+}
+
+/// Returns the first address in the option value `value`.
+fn addr(value: &[u8]) -> Option<Ipv4Addr> {
+    if value.len() < 4 { return None }
+    Some(Ipv4Addr([value[0], value[1], value[2], value[3]]))
+}
+
+/// Returns the big-endian `u32` at `at` in `buf`.
+fn get_u32(buf: &[u8], at: usize) -> u32 {
+    (get_u16(buf, at) as u32) << 16 | get_u16(buf, at + 2) as u32
+}
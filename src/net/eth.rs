@@ -0,0 +1,65 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Ethernet II framing.
+//!
+//! ```text
+//! | destination MAC (6) | source MAC (6) | EtherType (2) | payload ...
+//! ```
+use core::cmp;
+use dev::virtio::net::{MAX_FRAME_SIZE, NET};
+use super::{arp, get_u16, ipv4, put_u16, MacAddr, NetError};
+
+/// The length of the Ethernet header.
+pub const HEADER_LEN: usize = 14;
+/// The most payload a frame can carry.
+pub const MTU: usize = MAX_FRAME_SIZE - HEADER_LEN;
+/// Shorter frames are padded out to this length (less the checksum, which
+/// the card adds).
+const MIN_FRAME_SIZE: usize = 60;
+
+/// EtherType: an IPv4 packet.
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+/// EtherType: an ARP packet.
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Handle the received frame `buf`.
+///
+/// Frames that aren't IPv4 or ARP are dropped.
+pub fn recv(buf: &[u8]) {
+    if buf.len() < HEADER_LEN { return }
+    let payload = &buf[HEADER_LEN..];
+    match get_u16(buf, 12) {
+        ETHERTYPE_IPV4 => ipv4::recv(payload)
+      , ETHERTYPE_ARP => arp::recv(payload)
+      , _ => {}
+    }
+}
+
+/// Write the header of a frame from `src` to `dst`, of type `ethertype`,
+/// to the start of `buf`.
+pub fn write_header( buf: &mut [u8], dst: MacAddr, src: MacAddr
+                   , ethertype: u16) {
+    buf[0..6].copy_from_slice(&dst.0);
+    buf[6..12].copy_from_slice(&src.0);
+    put_u16(buf, 12, ethertype);
+}
+
+/// Send `payload` to `dst` in a frame of type `ethertype`.
+pub fn send(dst: MacAddr, ethertype: u16, payload: &[u8])
+           -> Result<(), NetError> {
+    if payload.len() > MTU { return Err(NetError::TooLarge) }
+    let mut net = NET.lock();
+    let net = net.as_mut().ok_or(NetError::NoDevice)?;
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    write_header(&mut frame, dst, MacAddr(net.mac()), ethertype);
+    frame[HEADER_LEN .. HEADER_LEN + payload.len()].copy_from_slice(payload);
+    let len = cmp::max(HEADER_LEN + payload.len(), MIN_FRAME_SIZE);
+    net.send(&frame[..len])?;
+    Ok(())
+}
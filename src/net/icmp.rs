@@ -0,0 +1,40 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! ICMP. We only answer pings.
+//!
+//! ```text
+//! | type (1) | code (1) | checksum (2) | identifier (2) | sequence (2)
+//! | data ...
+//! ```
+use super::{checksum, ipv4, put_u16, Ipv4Addr};
+
+/// The length of an echo header.
+pub const HEADER_LEN: usize = 8;
+
+/// Types.
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+
+/// Handle a received ICMP message from `src`: if it's an echo request,
+/// send back the same identifier, sequence number and data.
+pub fn recv(src: Ipv4Addr, buf: &[u8]) {
+    if buf.len() < HEADER_LEN || checksum(buf) != 0 { return }
+    if buf[0] != ECHO_REQUEST || buf[1] != 0 { return }
+    if buf.len() > ipv4::MAX_PAYLOAD { return }
+
+    let mut reply = [0u8; ipv4::MAX_PAYLOAD];
+    let reply = &mut reply[..buf.len()];
+    reply.copy_from_slice(buf);
+    reply[0] = ECHO_REPLY;
+    put_u16(reply, 2, 0);
+    let sum = checksum(reply);
+    put_u16(reply, 2, sum);
+    // if we can't reply now, the pinger will try again.
+    let _ = ipv4::send(src, ipv4::PROTO_ICMP, reply);
+}
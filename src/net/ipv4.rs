@@ -0,0 +1,149 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! IPv4.
+//!
+//! ```text
+//! | version, IHL (1) | DSCP, ECN (1) | total length (2)
+//! | identification (2) | flags, fragment offset (2)
+//! | TTL (1) | protocol (1) | header checksum (2)
+//! | source address (4) | destination address (4) | options ... | data ...
+//! ```
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use super::{arp, config, eth, get_u16, icmp, put_u16, udp, checksum};
+use super::{Ipv4Addr, NetConfig, NetError, BROADCAST_IP, BROADCAST_MAC};
+
+/// The length of a header without options, which is all we send.
+pub const HEADER_LEN: usize = 20;
+/// The most data a packet we send can carry.
+pub const MAX_PAYLOAD: usize = eth::MTU - HEADER_LEN;
+
+/// Protocol: ICMP.
+pub const PROTO_ICMP: u8 = 1;
+/// Protocol: UDP.
+pub const PROTO_UDP: u8 = 17;
+
+/// The TTL of packets we send.
+const DEFAULT_TTL: u8 = 64;
+/// Flags: don't fragment.
+const FLAG_DF: u16 = 0x4000;
+/// Flags: more fragments.
+const FLAG_MF: u16 = 0x2000;
+/// The fragment offset bits of the flags field.
+const FRAGMENT_OFFSET: u16 = 0x1fff;
+
+/// The identification of the next packet we send.
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns the address at `at` in `buf`.
+#[inline]
+fn addr_at(buf: &[u8], at: usize) -> Ipv4Addr {
+    let mut addr = Ipv4Addr::default();
+    addr.0.copy_from_slice(&buf[at .. at + 4]);
+    addr
+}
+
+/// Returns true if `addr` is on the network `config` describes.
+fn is_local(config: &NetConfig, addr: Ipv4Addr) -> bool {
+    let (ip, mask) = (config.ip.0, config.netmask.0);
+    (0..4).all(|i| addr.0[i] & mask[i] == ip[i] & mask[i])
+}
+
+/// The interesting parts of a received packet's header.
+#[derive(Copy, Clone, Debug)]
+pub struct Header { pub src: Ipv4Addr
+                  , pub dst: Ipv4Addr
+                  , pub protocol: u8
+                  }
+
+/// Check the IPv4 packet `buf`, and return its header and data.
+///
+/// Returns `None` if the header is bad, the packet has run out of TTL, or
+/// it's a fragment.
+pub fn parse(buf: &[u8]) -> Option<(Header, &[u8])> {
+    if buf.len() < HEADER_LEN || buf[0] >> 4 != 4 { return None }
+    let header_len = (buf[0] & 0xf) as usize * 4;
+    let total_len = get_u16(buf, 2) as usize;
+    if header_len < HEADER_LEN || total_len < header_len
+        || total_len > buf.len() {
+        return None
+    }
+    let header = &buf[..header_len];
+    // a header with the right checksum sums to zero.
+    if checksum(header) != 0 { return None }
+    if header[8] == 0 { return None }
+    let flags = get_u16(header, 6);
+    if flags & FLAG_MF != 0 || flags & FRAGMENT_OFFSET != 0 { return None }
+    Some(( Header { src: addr_at(header, 12)
+                  , dst: addr_at(header, 16)
+                  , protocol: header[9]
+                  }
+         , &buf[header_len .. total_len] ))
+}
+
+/// Handle a received IPv4 packet.
+///
+/// Packets that `parse` rejects, or that aren't for us, are dropped.
+pub fn recv(buf: &[u8]) {
+    let (header, data) = match (parse(buf), config()) {
+        (Some(packet), Some(config)) => {
+            let dst = packet.0.dst;
+            if dst != config.ip && dst != BROADCAST_IP { return }
+            packet
+        }
+      , _ => return
+    };
+    match header.protocol {
+        PROTO_ICMP => icmp::recv(header.src, data)
+      , PROTO_UDP => udp::recv(header.src, header.dst, data)
+      , _ => {}
+    }
+}
+
+/// Write the header of a packet from `src` to `dst`, of protocol
+/// `protocol`, carrying `payload_len` bytes, to the start of `buf`.
+pub fn write_header( buf: &mut [u8], src: Ipv4Addr, dst: Ipv4Addr
+                   , protocol: u8, payload_len: usize) {
+    let header = &mut buf[..HEADER_LEN];
+    for byte in header.iter_mut() { *byte = 0 }
+    header[0] = 0x45;
+    put_u16(header, 2, (HEADER_LEN + payload_len) as u16);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed) as u16;
+    put_u16(header, 4, id);
+    put_u16(header, 6, FLAG_DF);
+    header[8] = DEFAULT_TTL;
+    header[9] = protocol;
+    header[12..16].copy_from_slice(&src.0);
+    header[16..20].copy_from_slice(&dst.0);
+    let sum = checksum(header);
+    put_u16(header, 10, sum);
+}
+
+/// Send `payload` to `dst` in a packet of protocol `protocol`.
+///
+/// Packets for the local network go straight to `dst`, and anything else
+/// to the router. If the next hop's MAC address isn't known, this asks
+/// for it and fails with `NetError::Unresolved`.
+pub fn send(dst: Ipv4Addr, protocol: u8, payload: &[u8])
+           -> Result<(), NetError> {
+    if payload.len() > MAX_PAYLOAD { return Err(NetError::TooLarge) }
+    let config = config().ok_or(NetError::NotConfigured)?;
+    let mac = if dst == BROADCAST_IP { BROADCAST_MAC }
+              else if is_local(config, dst) { arp::resolve(dst)? }
+              else {
+                  let router = config.router.ok_or(NetError::NoRoute)?;
+                  arp::resolve(router)?
+              };
+
+    let mut packet = [0u8; eth::MTU];
+    let total_len = HEADER_LEN + payload.len();
+    write_header(&mut packet, config.ip, dst, protocol, payload.len());
+    packet[HEADER_LEN .. total_len].copy_from_slice(payload);
+    eth::send(mac, eth::ETHERTYPE_IPV4, &packet[..total_len])
+}
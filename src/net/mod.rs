@@ -0,0 +1,202 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A minimal IPv4 network stack, over the virtio network card.
+//!
+//! ```text
+//! eth ─┬─ arp
+//!      └─ ipv4 ─┬─ icmp    echo requests are answered
+//!               └─ udp     datagrams go to whatever's bound to the port
+//! ```
+//!
+//! The `netd` kernel thread takes each frame the card receives and passes
+//! it up the stack, which answers it (ARP requests, pings) or hands it on
+//! (UDP datagrams) on that thread. Sending builds each layer's headers in
+//! a buffer on the stack, and the frame is copied to the card.
+//!
+//! At boot, [`init`] asks for an address over DHCP, falling back to
+//! `STATIC_CONFIG`. Fragmented IP packets and IP options are not
+//! supported.
+//!
+//! [`init`]: fn.init.html
+use core::fmt;
+use spin::Once;
+
+use dev::virtio::net::{SendError, NET, RECEIVED};
+use task::{self, Pid};
+
+pub mod arp;
+pub mod dhcp;
+pub mod eth;
+pub mod icmp;
+pub mod ipv4;
+pub mod udp;
+
+/// An IPv4 address.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+/// An Ethernet MAC address.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MacAddr(pub [u8; 6]);
+
+/// The limited broadcast address, `255.255.255.255`.
+pub const BROADCAST_IP: Ipv4Addr = Ipv4Addr([0xff; 4]);
+/// The Ethernet broadcast address, `ff:ff:ff:ff:ff:ff`.
+pub const BROADCAST_MAC: MacAddr = MacAddr([0xff; 6]);
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let a = self.0;
+        write!(f, "{}.{}.{}.{}", a[0], a[1], a[2], a[3])
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let a = self.0;
+        write!( f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}"
+              , a[0], a[1], a[2], a[3], a[4], a[5])
+    }
+}
+
+/// How we're connected to the network.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NetConfig { /// Our address
+                       pub ip: Ipv4Addr
+                     , /// The mask of the local network
+                       pub netmask: Ipv4Addr
+                     , /// Where packets for anywhere else go
+                       pub router: Option<Ipv4Addr>
+                     , /// The DNS server
+                       pub dns: Option<Ipv4Addr>
+                     , /// How long we may use `ip` for, in seconds, or 0
+                       /// if there's no limit
+                       pub lease_secs: u32
+                     }
+
+/// The configuration used if DHCP fails, or `None` to do without a network
+/// then. This is what QEMU's user networking hands out.
+pub const STATIC_CONFIG: Option<NetConfig>
+    = Some(NetConfig { ip: Ipv4Addr([10, 0, 2, 15])
+                     , netmask: Ipv4Addr([255, 255, 255, 0])
+                     , router: Some(Ipv4Addr([10, 0, 2, 2]))
+                     , dns: Some(Ipv4Addr([10, 0, 2, 3]))
+                     , lease_secs: 0
+                     });
+
+/// The configuration in use, once there is one.
+pub static GLOBAL_NET_CONFIG: Once<NetConfig> = Once::new();
+
+/// Returns the configuration in use, if the network is up.
+#[inline]
+pub fn config() -> Option<&'static NetConfig> { GLOBAL_NET_CONFIG.try() }
+
+/// Errors returned when sending.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NetError { /// There's no network card
+                    NoDevice
+                  , /// The packet doesn't fit in a frame
+                    TooLarge
+                  , /// Every transmit buffer is in flight
+                    QueueFull
+                  , /// The next hop's MAC address isn't known yet. An ARP
+                    /// request has been sent, so try again shortly.
+                    Unresolved
+                  , /// We don't have an address yet
+                    NotConfigured
+                  , /// The destination isn't local, and there's no router
+                    NoRoute
+                  }
+
+impl From<SendError> for NetError {
+    fn from(err: SendError) -> Self {
+        match err {
+            SendError::TooLarge => NetError::TooLarge
+          , SendError::QueueFull => NetError::QueueFull
+        }
+    }
+}
+
+/// Returns the network card's MAC address, if there is a card.
+pub fn local_mac() -> Option<MacAddr> {
+    NET.lock().as_ref().map(|net| MacAddr(net.mac()))
+}
+
+/// Returns the big-endian `u16` at `at` in `buf`.
+#[inline]
+pub fn get_u16(buf: &[u8], at: usize) -> u16 {
+    (buf[at] as u16) << 8 | buf[at + 1] as u16
+}
+
+/// Write `value` to `buf` at `at`, big-endian.
+#[inline]
+pub fn put_u16(buf: &mut [u8], at: usize, value: u16) {
+    buf[at] = (value >> 8) as u8;
+    buf[at + 1] = value as u8;
+}
+
+/// Add `data` to the running ones' complement sum `sum`, as big-endian
+/// 16-bit words. An odd byte at the end is padded with a zero.
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    for word in data.chunks(2) {
+        let hi = (word[0] as u32) << 8;
+        let lo = if word.len() > 1 { word[1] as u32 } else { 0 };
+        sum += hi | lo;
+    }
+    sum
+}
+
+/// Finish the running sum `sum`: fold the carries back in and complement
+/// it, giving the Internet checksum.
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns the Internet checksum of `data`. A packet whose checksum field
+/// is filled in sums to zero.
+#[inline]
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+/// Pass each frame the card receives up the stack, forever.
+extern "C" fn netd() -> ! {
+    loop {
+        // don't hold the card while handling the frame: replies need it.
+        let packet = NET.lock().as_mut().and_then(|net| net.receive());
+        match packet {
+            Some(packet) => eth::recv(packet.as_bytes())
+          , None => RECEIVED.down()
+        }
+    }
+}
+
+/// Configure the network card, over DHCP or from `STATIC_CONFIG`, and
+/// start the thread that handles received frames.
+///
+/// Returns `None` if there's no card, or no configuration for it.
+pub fn init() -> Option<Pid> {
+    // the card is taken out of `NET` while DHCP waits for replies, so the
+    // lock isn't held while this sleeps.
+    let mut card = NET.lock().take()?;
+    let result = dhcp::discover(&mut card);
+    *NET.lock() = Some(card);
+    let config = match result {
+        Ok(config) => config
+      , Err(why) => {
+            warn!("net: DHCP failed: {:?}", why);
+            STATIC_CONFIG?
+        }
+    };
+    GLOBAL_NET_CONFIG.call_once(|| config);
+    Some(task::spawn_kernel("netd", netd))
+}
@@ -0,0 +1,155 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! UDP.
+//!
+//! ```text
+//! | source port (2) | destination port (2) | length (2) | checksum (2)
+//! | data ...
+//! ```
+//!
+//! A handler can be [`bind`]ed to a port, and is called on the `netd`
+//! thread with each datagram that arrives for it. Datagrams for ports
+//! nothing is bound to are dropped.
+//!
+//! [`bind`]: fn.bind.html
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use super::{checksum_add, checksum_finish, get_u16, ipv4, put_u16};
+use super::{config, Ipv4Addr, NetError};
+
+/// The length of a UDP header.
+pub const HEADER_LEN: usize = 8;
+/// The most data a datagram we send can carry.
+pub const MAX_PAYLOAD: usize = ipv4::MAX_PAYLOAD - HEADER_LEN;
+/// The most ports that can be bound at once.
+pub const MAX_BINDINGS: usize = 8;
+
+/// A function called with each datagram for a bound port: the sender's
+/// address and port, and the data.
+pub type UdpHandler = fn(Ipv4Addr, u16, &[u8]);
+
+/// A slot that's being bound. This is never a port number.
+const RESERVED: usize = !0;
+
+/// Each binding's port, 0 if the slot is free, or `RESERVED`.
+static PORTS: [AtomicUsize; MAX_BINDINGS]
+    = [ ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT ];
+/// Each binding's handler, as a `usize`.
+static HANDLERS: [AtomicUsize; MAX_BINDINGS]
+    = [ ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT
+      , ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT ];
+
+/// Call `handler` with every datagram that arrives for `port`.
+///
+/// Returns false if `port` is 0 or already bound, or every slot is taken.
+pub fn bind(port: u16, handler: UdpHandler) -> bool {
+    if port == 0 || slot_of(port).is_some() { return false }
+    for (slot, p) in PORTS.iter().enumerate() {
+        // the handler goes in before the port, so that a datagram can't
+        // find the port without it.
+        if p.compare_and_swap(0, RESERVED, Ordering::SeqCst) == 0 {
+            HANDLERS[slot].store(handler as usize, Ordering::SeqCst);
+            p.store(port as usize, Ordering::SeqCst);
+            return true
+        }
+    }
+    false
+}
+
+/// Stop handling datagrams for `port`.
+pub fn unbind(port: u16) {
+    for p in PORTS.iter() {
+        p.compare_and_swap(port as usize, 0, Ordering::SeqCst);
+    }
+}
+
+/// Returns the slot `port` is bound in, if it is.
+fn slot_of(port: u16) -> Option<usize> {
+    PORTS.iter().position(|p| p.load(Ordering::SeqCst) == port as usize)
+}
+
+/// Returns the checksum of a datagram from `src` to `dst`, including the
+/// IPv4 pseudo-header.
+fn udp_checksum(src: Ipv4Addr, dst: Ipv4Addr, datagram: &[u8]) -> u16 {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&src.0);
+    pseudo[4..8].copy_from_slice(&dst.0);
+    pseudo[9] = ipv4::PROTO_UDP;
+    put_u16(&mut pseudo, 10, datagram.len() as u16);
+    checksum_finish(checksum_add(checksum_add(0, &pseudo), datagram))
+}
+
+/// Check the datagram `buf` from `src` to `dst`, and return its source
+/// port, destination port, and data.
+pub fn parse(src: Ipv4Addr, dst: Ipv4Addr, buf: &[u8])
+            -> Option<(u16, u16, &[u8])> {
+    if buf.len() < HEADER_LEN { return None }
+    let len = get_u16(buf, 4) as usize;
+    if len < HEADER_LEN || len > buf.len() { return None }
+    let datagram = &buf[..len];
+    // a checksum of zero means the sender didn't compute one.
+    if get_u16(datagram, 6) != 0 && udp_checksum(src, dst, datagram) != 0 {
+        return None
+    }
+    Some(( get_u16(datagram, 0), get_u16(datagram, 2)
+         , &datagram[HEADER_LEN..] ))
+}
+
+/// Handle a datagram from `src` to `dst`.
+pub fn recv(src: Ipv4Addr, dst: Ipv4Addr, buf: &[u8]) {
+    let (src_port, dst_port, data) = match parse(src, dst, buf) {
+        Some(datagram) => datagram
+      , None => return
+    };
+    if let Some(slot) = slot_of(dst_port) {
+        let handler: UdpHandler = unsafe {
+            mem::transmute(HANDLERS[slot].load(Ordering::SeqCst))
+        };
+        handler(src, src_port, data);
+    }
+}
+
+/// Write a datagram from `src_port` at `src` to `dst_port` at `dst`,
+/// carrying `payload`, to the start of `buf`. Returns its length.
+pub fn write_datagram( buf: &mut [u8], src: Ipv4Addr, src_port: u16
+                     , dst: Ipv4Addr, dst_port: u16, payload: &[u8])
+                     -> usize {
+    let len = HEADER_LEN + payload.len();
+    put_u16(buf, 0, src_port);
+    put_u16(buf, 2, dst_port);
+    put_u16(buf, 4, len as u16);
+    put_u16(buf, 6, 0);
+    buf[HEADER_LEN .. len].copy_from_slice(payload);
+    let sum = match udp_checksum(src, dst, &buf[..len]) {
+        // zero means no checksum, so send all ones instead.
+        0 => 0xffff
+      , sum => sum
+    };
+    put_u16(buf, 6, sum);
+    len
+}
+
+/// Send `payload` from our port `src_port` to port `dst_port` at `dst_ip`.
+///
+/// If the next hop's MAC address isn't known yet, this asks for it and
+/// fails with `NetError::Unresolved`; try again shortly.
+pub fn send(src_port: u16, dst_ip: [u8; 4], dst_port: u16, payload: &[u8])
+           -> Result<(), NetError> {
+    if payload.len() > MAX_PAYLOAD { return Err(NetError::TooLarge) }
+    let src = config().ok_or(NetError::NotConfigured)?.ip;
+    let dst = Ipv4Addr(dst_ip);
+    let mut datagram = [0u8; ipv4::MAX_PAYLOAD];
+    let len = write_datagram( &mut datagram, src, src_port, dst, dst_port
+                            , payload);
+    ipv4::send(dst, ipv4::PROTO_UDP, &datagram[..len])
+}
@@ -0,0 +1,205 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The general-purpose performance monitoring counters.
+//!
+//! A [`PmcCounter`] counts one hardware event, starting `overflow_count`
+//! below zero, so that it overflows and raises a performance monitoring
+//! interrupt (PMI) after that many events. The interrupt handler calls
+//! [`reload_overflowed`] to start the count again.
+//!
+//! This needs version 2 or later of Intel's architectural performance
+//! monitoring, which has the global control and status MSRs. AMD's
+//! counters are different, and aren't supported.
+//!
+//! [`PmcCounter`]: struct.PmcCounter.html
+//! [`reload_overflowed`]: fn.reload_overflowed.html
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use cpu::cpuid::{self, cpuid};
+use cpu::msr;
+
+use arch::apic;
+use arch::interrupts::PMI_VECTOR;
+
+/// The most counters we'll use, whatever the CPU has.
+pub const MAX_COUNTERS: usize = 8;
+
+/// `IA32_PERFEVTSELx`: count while the CPU is in ring 0.
+const EVTSEL_OS: u64 = 1 << 17;
+/// `IA32_PERFEVTSELx`: raise a PMI when the counter overflows.
+const EVTSEL_INT: u64 = 1 << 20;
+/// `IA32_PERFEVTSELx`: the counter is enabled.
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// A hardware event that a counter can count.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PmcEvent { /// Core cycles while the CPU isn't halted
+                    Cycles
+                  , /// Last level cache misses
+                    LlcMisses
+                  , /// Mispredicted branches that were retired
+                    BranchMisses
+                  }
+
+impl PmcEvent {
+    /// Returns the event's number and unit mask.
+    fn select(&self) -> (u8, u8) {
+        match *self {
+            PmcEvent::Cycles => (0x3c, 0x00)
+          , PmcEvent::LlcMisses => (0x2e, 0x41)
+          , PmcEvent::BranchMisses => (0xc5, 0x00)
+        }
+    }
+
+    /// Returns the bit of `CPUID.0AH:EBX` that's set if the CPU can't
+    /// count this event.
+    fn unavailable_bit(&self) -> u32 {
+        match *self {
+            PmcEvent::Cycles => 1 << 0
+          , PmcEvent::LlcMisses => 1 << 4
+          , PmcEvent::BranchMisses => 1 << 6
+        }
+    }
+
+    /// Returns the event's name.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PmcEvent::Cycles => "cycles"
+          , PmcEvent::LlcMisses => "llc-misses"
+          , PmcEvent::BranchMisses => "branch-misses"
+        }
+    }
+}
+
+/// What `CPUID` leaf `0xA` says about the performance monitoring unit.
+#[derive(Copy, Clone, Debug)]
+pub struct Pmu { /// The architectural performance monitoring version
+                 pub version: u8
+               , /// The number of general-purpose counters
+                 pub counters: u8
+               , /// The width of each counter, in bits
+                 pub width: u8
+               , /// Events the CPU can't count, as in `CPUID.0AH:EBX`
+                 unavailable: u32
+               }
+
+impl Pmu {
+    /// Returns true if the CPU can count `event`.
+    pub fn supports(&self, event: PmcEvent) -> bool {
+        self.unavailable & event.unavailable_bit() == 0
+    }
+}
+
+/// Returns the CPU's performance monitoring unit, or `None` if it doesn't
+/// have one we can use.
+pub fn detect() -> Option<Pmu> {
+    if cpuid(0, 0).eax < cpuid::LEAF_PERFMON { return None }
+    let leaf = cpuid(cpuid::LEAF_PERFMON, 0);
+    let pmu = Pmu { version: leaf.eax as u8
+                  , counters: (leaf.eax >> 8) as u8
+                  , width: (leaf.eax >> 16) as u8
+                  , unavailable: leaf.ebx
+                  };
+    if pmu.version < 2 || pmu.counters == 0 { None } else { Some(pmu) }
+}
+
+/// Bit `i` is set while counter `i` is taken.
+static IN_USE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// What each counter is set back to when it overflows.
+static RELOAD: [AtomicU64; MAX_COUNTERS]
+    = [ AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)
+      , AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)
+      , AtomicU64::new(0), AtomicU64::new(0) ];
+
+/// A running performance counter.
+///
+/// The counter's MSRs belong to the CPU that started it, so it only counts
+/// events on that CPU, and must be stopped there.
+#[derive(Debug)]
+pub struct PmcCounter { index: usize
+                      , event: PmcEvent
+                      }
+
+impl PmcCounter {
+    /// Start counting `event` on this CPU, raising a PMI after every
+    /// `overflow_count` events.
+    ///
+    /// `IA32_PMCx` only takes the low 32 bits of a write, sign-extended, so
+    /// `overflow_count` must be below 2^31.
+    pub fn start(event: PmcEvent, overflow_count: u64)
+                -> Result<Self, &'static str> {
+        let pmu = detect().ok_or("no performance monitoring unit")?;
+        if !pmu.supports(event) {
+            return Err("the CPU can't count that event")
+        }
+        if overflow_count == 0 || overflow_count >= 1 << 31 {
+            return Err("overflow count must be between 1 and 2^31")
+        }
+        let counters = ::core::cmp::min(pmu.counters as usize, MAX_COUNTERS);
+        let index = claim(counters).ok_or("no free counters")?;
+        let reload = 0u64.wrapping_sub(overflow_count);
+        RELOAD[index].store(reload, Ordering::SeqCst);
+        let (number, umask) = event.select();
+        unsafe {
+            apic::set_perf_vector(PMI_VECTOR);
+            msr::write(msr::IA32_PERFEVTSEL0 + index as u32, 0);
+            msr::write(msr::IA32_PMC0 + index as u32, reload);
+            msr::write( msr::IA32_PERFEVTSEL0 + index as u32
+                      , number as u64 | (umask as u64) << 8
+                      | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN);
+            let enabled = msr::read(msr::IA32_PERF_GLOBAL_CTRL);
+            msr::write(msr::IA32_PERF_GLOBAL_CTRL, enabled | 1 << index);
+        }
+        Ok(PmcCounter { index: index, event: event })
+    }
+
+    /// Returns the event this counts.
+    #[inline]
+    pub fn event(&self) -> PmcEvent { self.event }
+
+    /// Stop counting, and free the counter.
+    pub fn stop(self) {
+        unsafe {
+            let enabled = msr::read(msr::IA32_PERF_GLOBAL_CTRL);
+            msr::write( msr::IA32_PERF_GLOBAL_CTRL
+                      , enabled & !(1 << self.index));
+            msr::write(msr::IA32_PERFEVTSEL0 + self.index as u32, 0);
+            msr::write(msr::IA32_PERF_GLOBAL_OVF_CTRL, 1 << self.index);
+        }
+        IN_USE.fetch_and(!(1 << self.index), Ordering::SeqCst);
+    }
+}
+
+/// Take the first free counter of the first `counters`.
+fn claim(counters: usize) -> Option<usize> {
+    let mut in_use = IN_USE.load(Ordering::SeqCst);
+    loop {
+        let index = (0 .. counters).find(|&i| in_use & (1 << i) == 0)?;
+        let prev = IN_USE.compare_and_swap( in_use, in_use | 1 << index
+                                          , Ordering::SeqCst);
+        if prev == in_use { return Some(index) }
+        in_use = prev;
+    }
+}
+
+/// Set each counter that has overflowed back to its reload value, and clear
+/// its overflow bit. Returns which counters had overflowed.
+///
+/// # Safety
+/// + This is for the PMI handler.
+pub unsafe fn reload_overflowed() -> u64 {
+    let status = msr::read(msr::IA32_PERF_GLOBAL_STATUS);
+    let overflowed = status & ((1 << MAX_COUNTERS) - 1);
+    for index in (0 .. MAX_COUNTERS).filter(|i| overflowed & (1 << i) != 0) {
+        msr::write( msr::IA32_PMC0 + index as u32
+                  , RELOAD[index].load(Ordering::SeqCst));
+    }
+    msr::write(msr::IA32_PERF_GLOBAL_OVF_CTRL, overflowed);
+    overflowed
+}
@@ -0,0 +1,100 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Software event counters, kept for each task.
+//!
+//! Unlike the PMCs in [`counter`](../counter/index.html), these count
+//! things the kernel does on a task's behalf: the code that does them calls
+//! [`increment`](fn.increment.html), which bumps the current task's
+//! counter for the event. They're reported by `getrusage` and in
+//! `/proc/<pid>/stat`.
+//!
+//! Events are only counted when the kernel is built with the `task-perf`
+//! feature; otherwise `increment` does nothing, and every count stays 0.
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "task-perf")] use arch::percpu;
+
+/// The number of kinds of event.
+pub const NUM_EVENTS: usize = 5;
+
+/// A kind of event that's counted for each task.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PerfEvent { /// Times the task gave up the CPU
+                     ContextSwitches
+                   , /// Page faults on the task's memory, including ones
+                     /// that killed it
+                     PageFaults
+                   , /// System calls the task made
+                     Syscalls
+                   , /// Page faults that needed I/O to resolve, by reading
+                     /// the page back in from swap
+                     MajorFaults
+                   , /// Page faults resolved without any I/O
+                     MinorFaults
+                   }
+
+/// A count of one kind of event.
+#[derive(Debug)]
+pub struct PerfCounter { pub event: PerfEvent
+                       , pub count: AtomicU64
+                       }
+
+impl PerfCounter {
+    const fn new(event: PerfEvent) -> Self {
+        PerfCounter { event: event, count: AtomicU64::new(0) }
+    }
+}
+
+/// A task's counters, one for each kind of event.
+#[derive(Debug)]
+pub struct PerfCounters([PerfCounter; NUM_EVENTS]);
+
+impl PerfCounters {
+    /// Returns a set of counters that are all 0.
+    pub fn new() -> Self {
+        PerfCounters([ PerfCounter::new(PerfEvent::ContextSwitches)
+                     , PerfCounter::new(PerfEvent::PageFaults)
+                     , PerfCounter::new(PerfEvent::Syscalls)
+                     , PerfCounter::new(PerfEvent::MajorFaults)
+                     , PerfCounter::new(PerfEvent::MinorFaults)
+                     ])
+    }
+
+    /// Returns the count of `event`.
+    #[inline]
+    pub fn get(&self, event: PerfEvent) -> u64 {
+        self.0[event as usize].count.load(Ordering::Relaxed)
+    }
+
+    /// Count one `event`.
+    #[inline]
+    pub fn increment(&self, event: PerfEvent) {
+        self.0[event as usize].count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns an iterator over the counters.
+    #[inline]
+    pub fn iter(&self) -> slice::Iter<PerfCounter> { self.0.iter() }
+}
+
+/// Count one `event` against the task running on this CPU, if there is
+/// one.
+#[cfg(feature = "task-perf")]
+pub fn increment(event: PerfEvent) {
+    let task = unsafe { percpu::current().current_task };
+    if let Some(task) = unsafe { task.as_ref() } {
+        task.perf.increment(event)
+    }
+}
+
+/// Counting is disabled without the `task-perf` feature.
+#[cfg(not(feature = "task-perf"))]
+#[inline]
+pub fn increment(_event: PerfEvent) {}
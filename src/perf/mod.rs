@@ -0,0 +1,136 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A sampling profiler, driven by the performance monitoring counters.
+//!
+//! Start a [`PmcCounter`] for the event to profile. Each time it
+//! overflows, the PMI handler calls [`sample`] with the interrupted `%rip`,
+//! which bumps a bucket of [`PROFILE_HIST`]: one per 8 bytes of kernel
+//! text, wrapping around after `HIST_SIZE` of them. [`dump_top_n`] prints
+//! the busiest buckets.
+//!
+//! The PMI is an ordinary interrupt rather than an NMI, so code that runs
+//! with interrupts disabled is blamed on wherever they were enabled again.
+//!
+//! [`PmcCounter`]: counter/struct.PmcCounter.html
+//! [`sample`]: fn.sample.html
+//! [`PROFILE_HIST`]: static.PROFILE_HIST.html
+//! [`dump_top_n`]: fn.dump_top_n.html
+//!
+//! Software events, like context switches and page faults, are counted for
+//! each task by [`events`](events/index.html).
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use params::InitParams;
+
+pub mod counter;
+pub mod events;
+
+pub use self::counter::{PmcCounter, PmcEvent};
+pub use self::events::{increment, PerfCounter, PerfCounters, PerfEvent};
+
+/// The number of buckets in the histogram.
+pub const HIST_SIZE: usize = 65536;
+/// The number of bytes of kernel text each bucket covers.
+pub const BUCKET_SIZE: usize = 8;
+/// The most buckets `dump_top_n` prints.
+pub const MAX_TOP: usize = 32;
+
+/// Sample counts for each bucket of kernel text.
+///
+/// `[AtomicU64; N]` can't be written as a repeat expression, so this is
+/// plain `u64`s that are only ever accessed through [`bucket`], as
+/// atomics.
+///
+/// [`bucket`]: fn.bucket.html
+pub static mut PROFILE_HIST: [u64; HIST_SIZE] = [0; HIST_SIZE];
+
+/// Samples that landed outside the kernel image.
+static MISSED: AtomicU64 = AtomicU64::new(0);
+/// The start and end of the kernel image, or 0 before `init`.
+static KERNEL_BASE: AtomicUsize = ATOMIC_USIZE_INIT;
+static KERNEL_TOP: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns the count for bucket `index`.
+#[inline]
+fn bucket(index: usize) -> &'static AtomicU64 {
+    // `AtomicU64` has the same layout as `u64`.
+    unsafe { &*(&PROFILE_HIST[index] as *const u64 as *const AtomicU64) }
+}
+
+/// Returns the bucket `rip` falls in, or `None` if it's outside the
+/// kernel image.
+#[inline]
+pub fn bucket_of(rip: usize) -> Option<usize> {
+    let base = KERNEL_BASE.load(Ordering::Relaxed);
+    let top = KERNEL_TOP.load(Ordering::Relaxed);
+    if rip < base || rip >= top { return None }
+    Some((rip - base) / BUCKET_SIZE % HIST_SIZE)
+}
+
+/// Returns the lowest address that falls in bucket `index`.
+#[inline]
+pub fn bucket_start(index: usize) -> usize {
+    KERNEL_BASE.load(Ordering::Relaxed) + index * BUCKET_SIZE
+}
+
+/// Tell the profiler where the kernel image is.
+pub fn init(params: &InitParams) {
+    KERNEL_TOP.store(*params.kernel_top as usize, Ordering::Relaxed);
+    KERNEL_BASE.store(*params.kernel_base as usize, Ordering::Relaxed);
+}
+
+/// Record a sample at `rip`.
+///
+/// This is called by the PMI handler, after the counters have been
+/// reloaded.
+#[inline]
+pub fn sample(rip: usize) {
+    match bucket_of(rip) {
+        Some(index) => bucket(index).fetch_add(1, Ordering::Relaxed)
+      , None => MISSED.fetch_add(1, Ordering::Relaxed)
+    };
+}
+
+/// Zero the histogram.
+pub fn reset() {
+    for index in 0 .. HIST_SIZE {
+        bucket(index).store(0, Ordering::Relaxed);
+    }
+    MISSED.store(0, Ordering::Relaxed);
+}
+
+/// Print the `n` buckets with the most samples, busiest first.
+///
+/// There's no symbol table in the kernel, so buckets are printed as
+/// address ranges, to be looked up with `addr2line` or the like. If the
+/// kernel image is bigger than `HIST_SIZE` buckets, each bucket also
+/// counts samples from the addresses that alias it, which aren't printed.
+pub fn dump_top_n(n: usize) {
+    let n = ::core::cmp::min(n, MAX_TOP);
+    // (count, bucket), sorted by count, busiest first.
+    let mut top = [(0u64, 0usize); MAX_TOP];
+    let mut total = 0;
+    for index in 0 .. HIST_SIZE {
+        let count = bucket(index).load(Ordering::Relaxed);
+        total += count;
+        if n == 0 || count <= top[n - 1].0 { continue }
+        let mut i = n - 1;
+        while i > 0 && top[i - 1].0 < count {
+            top[i] = top[i - 1];
+            i -= 1;
+        }
+        top[i] = (count, index);
+    }
+    println!( "{} sample(s) in the kernel, {} outside it."
+            , total, MISSED.load(Ordering::Relaxed));
+    for &(count, index) in top[..n].iter().take_while(|&&(c, _)| c > 0) {
+        let start = bucket_start(index);
+        println!( "{:>10} {:>3}%  {:#x}..{:#x}"
+                , count, count * 100 / total, start, start + BUCKET_SIZE);
+    }
+}
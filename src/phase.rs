@@ -0,0 +1,88 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! How far the kernel has got through initialization.
+//!
+//! Code that needs some subsystem to have been set up calls
+//! [`require_phase`] first, so that calling it too early during boot fails
+//! with a message saying so, rather than with a null pointer or an empty
+//! `Option` somewhere further down.
+//!
+//! Each subsystem's init function advances the phase once it's done.
+//!
+//! [`require_phase`]: fn.require_phase.html
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// A stage of kernel initialization, in the order they happen.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum KernelPhase { /// Nothing is set up yet
+                       EarlyBoot = 0
+                     , /// Frames can be allocated
+                       MemoryInit
+                     , /// The IDT is loaded and interrupts are enabled
+                       InterruptInit
+                     , /// Tasks can block and be scheduled
+                       SchedulerInit
+                     , /// Initialization is finished
+                       FullyOperational
+                     }
+
+impl KernelPhase {
+    fn from_u32(n: u32) -> Self {
+        match n {
+            0 => KernelPhase::EarlyBoot
+          , 1 => KernelPhase::MemoryInit
+          , 2 => KernelPhase::InterruptInit
+          , 3 => KernelPhase::SchedulerInit
+          , _ => KernelPhase::FullyOperational
+        }
+    }
+}
+
+/// The current phase, as a `KernelPhase`.
+static KERNEL_PHASE: AtomicU32 = AtomicU32::new(KernelPhase::EarlyBoot as u32);
+
+/// Returns the phase the kernel has reached.
+#[inline]
+pub fn current() -> KernelPhase {
+    KernelPhase::from_u32(KERNEL_PHASE.load(Ordering::Acquire))
+}
+
+/// Returns true if the kernel has reached `phase`.
+#[inline]
+pub fn reached(phase: KernelPhase) -> bool { current() >= phase }
+
+/// Panic if the kernel hasn't reached `phase` yet.
+#[inline]
+pub fn require_phase(phase: KernelPhase) {
+    let current = current();
+    if current < phase {
+        panic!( "called too early: this needs {:?}, but the kernel is only \
+                 at {:?}", phase, current)
+    }
+}
+
+/// Record that the kernel has reached `phase`.
+///
+/// The phase never goes backwards: advancing to a phase that has already
+/// been passed does nothing.
+pub fn advance_phase(phase: KernelPhase) {
+    let mut current = KERNEL_PHASE.load(Ordering::Relaxed);
+    while current < phase as u32 {
+        match KERNEL_PHASE.compare_exchange_weak( current, phase as u32
+                                                , Ordering::Release
+                                                , Ordering::Relaxed) {
+            Ok(_) => {
+                debug!("kernel phase is now {:?}", phase);
+                return
+            }
+          , Err(actual) => current = actual
+        }
+    }
+}
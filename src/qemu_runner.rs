@@ -0,0 +1,1434 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Kernel self-tests, run under QEMU.
+//!
+//! Building the kernel with the `qemu-test` feature makes it run these tests
+//! at the end of `kernel_init`, instead of going on to `kernel_main`. Results
+//! are written to `COM1`, and then QEMU is told to exit through its
+//! `isa-debug-exit` device, which makes it exit with `(code << 1) | 1`: 33
+//! if every test passed, and 35 if one failed. `make test-qemu` does all of
+//! this.
+//!
+//! There's no unwinding, so a failing test can't be caught and skipped: its
+//! panic is reported by a panic hook, which exits QEMU straight away. The
+//! tests are listed in `TESTS` by hand, since this toolchain has no way to
+//! plug a custom runner into `#[test]`.
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Arguments, Write};
+use core::{mem, ptr, slice};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use cpu::PrivilegeLevel;
+use cpu::cpuid::CpuidResult;
+use cpu::ports::QEMU_DEBUG_EXIT;
+use cpu::segment::{self, Selector, TableIndicator};
+use memory::{MemRange, PAGE_SIZE, PAddr, Page, PhysicalPage, VAddr
+            , VirtualPage};
+use sos_alloc::FrameAllocator;
+use vga;
+
+use arch::acpi::aml::{self, AmlValue};
+use arch::bda;
+use arch::cpu::{self as insn, cli, hlt, without_interrupts};
+use arch::crc32c::{self, Crc32cHasher};
+use arch::kaslr::{self, SLIDE_ALIGN, SLIDE_MAX};
+use arch::mtrr::{MemType, MtrrMap, NUM_FIXED};
+use arch::numa;
+use arch::{extable, percpu, rng, smp, tlb_shootdown};
+use arch::topology::{self, CpuTopology, Levels};
+use arch::boot_args::EfiTableHeader;
+use arch::uefi::{ self, BootServices, ConfigurationTable, EfiStatus
+                , SystemTable};
+use arch::drivers::serial::SerialPort;
+use dev::block::{BlockDevice, Ramdisk};
+use dev::iosched::{self, IoRequest, IoScheduler};
+use dev::virtio::queue::{Virtqueue, QUEUE_SIZE, WRITE};
+use dev::virtio::rng::{self as virtio_rng, RngBuffer, RNG_BUF};
+use fs::{mode, Inode, IoError};
+use fs::devfs::Devfs;
+use fs::ext2::Ext2;
+use fs::fat32::{self, Fat32};
+use fs::tmpfs::{Tmpfs, TMPFS_FILE_MAX};
+use heap;
+use kdump::lz4;
+use mm::boot::{BootAllocator, BootFrames};
+use mm::{frame, map_user_page, swap, unmap_user_pages};
+use mm::memblock::Memblock;
+use mm::vm::{VmBacking, VmMap, VmRegion, MMAP_BASE, VM_READ, VM_WRITE};
+use module::{self, kallsyms, ModuleError};
+use net;
+use paging::arch::space::{self, phys_to_virt, table_at};
+use paging::arch::tlb;
+use perf;
+use phase::{self, KernelPhase};
+use syscall::seccomp::SyscallFilter;
+use syscall::time::{Timeval, NSEC_PER_SEC};
+use sysctl::{self, Sysctl};
+use syslog;
+use task::{self, KernelStack, Pid, KERNEL_STACK_SIZE};
+use task::channel::{self, TryRecvError, TrySendError};
+use task::timer::{TimerWheel, SLOT_NS, SLOTS};
+use task::workqueue::{WorkQueue, QUEUE_SIZE};
+
+/// Written to the debug exit port if every test passed.
+pub const EXIT_SUCCESS: u32 = 0x10;
+/// Written to the debug exit port if a test failed.
+pub const EXIT_FAILURE: u32 = 0x11;
+
+/// A kernel self-test.
+struct Test { name: &'static str
+            , run: fn()
+            }
+
+static TESTS: &'static [Test]
+    = &[ Test { name: "crc32c::check_value", run: crc32c_check_value }
+       , Test { name: "crc32c::hasher", run: crc32c_hasher }
+       , Test { name: "aml::s5_package", run: aml_s5_package }
+       , Test { name: "aml::pic_method", run: aml_pic_method }
+       , Test { name: "aml::unknown_opcode", run: aml_unknown_opcode }
+       , Test { name: "aml::self_reference", run: aml_self_reference }
+       , Test { name: "heap::stats", run: heap_stats }
+       , Test { name: "heap::grow_and_shrink", run: heap_grow_and_shrink }
+       , Test { name: "timer::wheel", run: timer_wheel }
+       , Test { name: "timer::overflow", run: timer_overflow }
+       , Test { name: "time::timeval", run: time_timeval }
+       , Test { name: "iosched::credit", run: iosched_credit }
+       , Test { name: "numa::parse_srat", run: numa_parse_srat }
+       , Test { name: "memblock::alloc", run: memblock_alloc }
+       , Test { name: "boot::memblock_frames", run: boot_memblock_frames }
+       , Test { name: "boot::frames", run: boot_frames }
+       , Test { name: "segment::selectors", run: segment_selectors }
+       , Test { name: "cpu::rdtsc", run: cpu_rdtsc }
+       , Test { name: "cpu::cpuid", run: cpu_cpuid }
+       , Test { name: "kdump::lz4", run: kdump_lz4 }
+       , Test { name: "perf::buckets", run: perf_buckets }
+       , Test { name: "net::checksum", run: net_checksum }
+       , Test { name: "ext2::mount", run: ext2_mount }
+       , Test { name: "fat32::mount", run: fat32_mount }
+       , Test { name: "channel::try_ops", run: channel_try_ops }
+       , Test { name: "seccomp::filter", run: seccomp_filter }
+       , Test { name: "sysctl::tree", run: sysctl_tree }
+       , Test { name: "swap::slots", run: swap_slots }
+       , Test { name: "task::stack_canary", run: task_stack_canary }
+       , Test { name: "kaslr::slide", run: kaslr_slide }
+       , Test { name: "kaslr::relocated", run: kaslr_relocated }
+       , Test { name: "syslog::kmsg", run: syslog_kmsg }
+       , Test { name: "rng::fill_bytes", run: rng_fill_bytes }
+       , Test { name: "virtio::rng", run: virtio_rng_protocol }
+       , Test { name: "uefi::system_table", run: uefi_system_table }
+       , Test { name: "mtrr::lookup", run: mtrr_lookup }
+       , Test { name: "module::load", run: module_load }
+       , Test { name: "kallsyms::image", run: kallsyms_image }
+       , Test { name: "memfd::file_backing", run: memfd_file_backing }
+       , Test { name: "tmpfs::file_max", run: tmpfs_file_max }
+       , Test { name: "workqueue::bounded", run: workqueue_bounded }
+       , Test { name: "topology::levels", run: topology_levels }
+       , Test { name: "tlb_shootdown::broadcast", run: tlb_shootdown_broadcast }
+       , Test { name: "smap::user_access", run: smap_user_access }
+       , Test { name: "frame::cow_refcount", run: frame_cow_refcount }
+       ];
+
+/// The index into `TESTS` of the test that's running.
+static CURRENT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Open `COM1` for writing results to, if there is one.
+fn serial() -> Option<SerialPort> {
+    bda::ports::com1().map(SerialPort::new)
+}
+
+/// Tell QEMU to exit, reporting `code`.
+pub fn exit_qemu(code: u32) -> ! {
+    QEMU_DEBUG_EXIT.write(code);
+    // not running under QEMU, or it wasn't given the device.
+    loop { unsafe { cli(); hlt() } }
+}
+
+/// Report the panic of the current test, and exit with `EXIT_FAILURE`.
+fn on_panic(args: Arguments, file: &'static str, line: usize) {
+    if let Some(mut serial) = serial() {
+        let test = &TESTS[CURRENT.load(Ordering::SeqCst)];
+        let _ = write!( serial, "FAILED\r\n\r\n---- {} ----\r\n\
+                                 panicked at '{}', {}:{}\r\n\r\n\
+                                 test result: FAILED\r\n"
+                      , test.name, args, file, line);
+    }
+    exit_qemu(EXIT_FAILURE)
+}
+
+/// Run every test, and exit QEMU with the result.
+pub fn run() -> ! {
+    vga::panic::set_hook(on_panic);
+    let mut serial = serial();
+    if let Some(ref mut serial) = serial {
+        let _ = write!(serial, "\r\nrunning {} tests\r\n", TESTS.len());
+    }
+    for (i, test) in TESTS.iter().enumerate() {
+        CURRENT.store(i, Ordering::SeqCst);
+        if let Some(ref mut serial) = serial {
+            let _ = write!(serial, "test {} ... ", test.name);
+        }
+        (test.run)();
+        if let Some(ref mut serial) = serial {
+            let _ = write!(serial, "ok\r\n");
+        }
+    }
+    if let Some(ref mut serial) = serial {
+        let _ = write!( serial, "\r\ntest result: ok. {} passed\r\n"
+                      , TESTS.len());
+    }
+    exit_qemu(EXIT_SUCCESS)
+}
+
+//==--------------------------------------------------------------------------==
+// The tests
+
+fn crc32c_check_value() {
+    assert_eq!(crc32c::crc32c(b""), 0);
+    assert_eq!(crc32c::crc32c(b"123456789"), 0xe306_9283);
+    // every length of tail, in both the byte and the word loops.
+    let zeros = [0u8; 32];
+    assert_eq!(crc32c::crc32c(&zeros), 0x8a91_36aa);
+}
+
+fn crc32c_hasher() {
+    use core::hash::Hasher;
+    let data = b"The quick brown fox jumps over the lazy dog";
+    let mut hasher = Crc32cHasher::new();
+    for chunk in data.chunks(7) {
+        hasher.write(chunk);
+    }
+    assert_eq!(hasher.finish(), crc32c::crc32c(data) as u64);
+    assert_eq!(hasher.finish(), 0x2262_0404);
+}
+
+/// `Name (PICM, Zero)`, `Method (_PIC, 1) { Store (Arg0, PICM) }`, and
+/// `Name (\_S5_, Package (4) { 5, 5, Zero, Zero })`.
+const TEST_AML: &'static [u8]
+    = &[ 0x08, b'P', b'I', b'C', b'M', 0x00
+       , 0x14, 0x0c, b'_', b'P', b'I', b'C', 0x01
+       ,     0x70, 0x68, b'P', b'I', b'C', b'M'
+       , 0x08, b'\\', b'_', b'S', b'5', b'_'
+       ,     0x12, 0x08, 0x04, 0x0a, 0x05, 0x0a, 0x05, 0x00, 0x00
+       ];
+
+fn aml_s5_package() {
+    let s5 = aml::find_package(TEST_AML, "\\_S5_").expect("no \\_S5_");
+    assert_eq!(s5, vec![ AmlValue::Integer(5), AmlValue::Integer(5)
+                       , AmlValue::Integer(0), AmlValue::Integer(0) ]);
+    // short names are padded with underscores.
+    assert!(aml::find_package(TEST_AML, "\\_S5").is_some());
+    assert_eq!(aml::find_integer(TEST_AML, "PICM"), Some(0));
+}
+
+fn aml_pic_method() {
+    let mut interpreter = aml::Interpreter::new(TEST_AML);
+    let result = interpreter.invoke("\\_PIC", &[AmlValue::Integer(1)]);
+    assert_eq!(result, Some(AmlValue::Integer(0)));
+    assert_eq!(interpreter.evaluate("PICM"), Some(AmlValue::Integer(1)));
+    // the wrong number of arguments is refused.
+    assert_eq!(interpreter.invoke("\\_PIC", &[]), None);
+}
+
+fn aml_unknown_opcode() {
+    // `Method (_PIC, 1) { If (Arg0) { } }`: `If` isn't understood.
+    let aml = [ 0x14, 0x09, b'_', b'P', b'I', b'C', 0x01
+              ,     0xa0, 0x02, 0x68 ];
+    assert_eq!(aml::Interpreter::new(&aml).invoke( "\\_PIC"
+                                                 , &[AmlValue::Integer(1)])
+              , None);
+    assert_eq!(aml::find_package(&aml, "\\_S5_"), None);
+}
+
+fn aml_self_reference() {
+    // `Name (LOOP, Package (1) { LOOP })` is given up on, rather than
+    // overflowing the stack.
+    let aml = [ 0x08, b'L', b'O', b'O', b'P'
+              ,     0x12, 0x06, 0x01, b'L', b'O', b'O', b'P' ];
+    assert_eq!(aml::find_package(&aml, "LOOP"), None);
+}
+
+fn heap_stats() {
+    let before = heap::stats();
+    let boxed = Box::new([0u8; 100]);
+    let during = heap::stats();
+    assert_eq!(during.alloc_calls, before.alloc_calls + 1);
+    assert!(during.allocated_bytes >= before.allocated_bytes + 100);
+    assert!(during.peak_allocated >= during.allocated_bytes);
+    drop(boxed);
+    let after = heap::stats();
+    assert_eq!(after.free_calls, during.free_calls + 1);
+}
+
+fn heap_grow_and_shrink() {
+    // twice as much as the heap starts out with, so it has to grow.
+    let chunks: Vec<Vec<u8>>
+        = (0..8).map(|_| Vec::with_capacity(1 << 20)).collect();
+    drop(chunks);
+    assert!(heap::shrink() > 0);
+    // everything that could go went the first time.
+    assert_eq!(heap::shrink(), 0);
+}
+
+fn timer_wheel() {
+    let mut wheel = TimerWheel::new(0);
+    let mut expired = Vec::new();
+    wheel.insert(Pid(1), 3 * SLOT_NS);
+    wheel.insert(Pid(2), 3 * SLOT_NS + 1);
+    wheel.insert(Pid(3), 10 * SLOT_NS);
+    assert_eq!(wheel.len(), 3);
+
+    wheel.advance(3 * SLOT_NS, &mut expired);
+    assert_eq!(expired, vec![Pid(1)]);
+    assert!(wheel.remove(Pid(3), 10 * SLOT_NS));
+    assert!(!wheel.remove(Pid(3), 10 * SLOT_NS));
+
+    expired.clear();
+    wheel.advance(4 * SLOT_NS, &mut expired);
+    assert_eq!(expired, vec![Pid(2)]);
+    assert_eq!(wheel.len(), 0);
+}
+
+fn timer_overflow() {
+    let mut wheel = TimerWheel::new(0);
+    let mut expired = Vec::new();
+    let far = (SLOTS as u64 + 5) * SLOT_NS;
+    wheel.insert(Pid(1), far);
+    wheel.advance(far - SLOT_NS, &mut expired);
+    assert!(expired.is_empty());
+    wheel.advance(far, &mut expired);
+    assert_eq!(expired, vec![Pid(1)]);
+    assert_eq!(wheel.len(), 0);
+}
+
+fn time_timeval() {
+    assert_eq!(Timeval::from_ns(0), Timeval { tv_sec: 0, tv_usec: 0 });
+    // partial microseconds are rounded down.
+    assert_eq!( Timeval::from_ns(3 * NSEC_PER_SEC + 250_999)
+              , Timeval { tv_sec: 3, tv_usec: 250 });
+}
+
+fn iosched_credit() {
+    fn done() {}
+    let request = |len| IoRequest { lba: 0, len: len, write: false
+                                  , completion: done };
+    let (big, small) = (512 * 1024, 4096);
+    let mut sched = IoScheduler::new(0);
+    for _ in 0..3 { sched.submit(request(big), Pid(1)); }
+    sched.submit(request(small), Pid(2));
+    assert_eq!(sched.len(), 4);
+
+    // both start with full credit, so the tie goes to the lower PID, and
+    // then task 2 has more credit left.
+    let order: Vec<Pid> = (0..4).filter_map(|_| sched.dispatch(0))
+                                .map(|(pid, _)| pid)
+                                .collect();
+    assert_eq!(order, vec![Pid(1), Pid(2), Pid(1), Pid(1)]);
+    assert!(sched.is_empty() && sched.dispatch(0).is_none());
+    let spent = 3 * big as i32;
+    assert_eq!(sched.credit(Pid(1)), Some(iosched::MAX_CREDIT - spent));
+
+    // a quantum later, task 1 has earned some back, and task 2 is back to
+    // full credit, so it's forgotten.
+    assert!(sched.dispatch(iosched::QUANTUM_NS).is_none());
+    assert_eq!( sched.credit(Pid(1))
+              , Some(iosched::MAX_CREDIT - spent + iosched::CREDIT_RATE));
+    assert_eq!(sched.credit(Pid(2)), None);
+
+    // however big the request, credit stops at the floor.
+    sched.submit(request(usize::max_value()), Pid(3));
+    assert!(sched.dispatch(iosched::QUANTUM_NS).is_some());
+    assert_eq!(sched.credit(Pid(3)), Some(iosched::MIN_CREDIT));
+}
+
+fn numa_parse_srat() {
+    // a 48-byte header, then: CPU 1 in domain 1, 64 MiB at 16 MiB in domain
+    // 1, a disabled CPU, and CPU 0 in domain 0.
+    let mut srat = vec![0u8; 48];
+    srat.extend_from_slice(&[0, 16, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut memory = [0u8; 40];
+    memory[0] = 1;
+    memory[1] = 40;
+    memory[2] = 1;
+    memory[11] = 0x01;
+    memory[19] = 0x04;
+    memory[28] = 1;
+    srat.extend_from_slice(&memory);
+    srat.extend_from_slice(&[0, 16, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    srat.extend_from_slice(&[0, 16, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    let topology = numa::parse_srat(&srat);
+    assert_eq!(topology.nodes.len(), 2);
+    assert_eq!(topology.nodes[0].domain, 0);
+    assert_eq!(topology.nodes[0].apic_ids, vec![0]);
+    let node = topology.node(1).expect("no node 1");
+    assert_eq!(node.apic_ids, vec![1]);
+    let (start, end) = (PAddr::from(0x100_0000), PAddr::from(0x500_0000));
+    assert_eq!(node.memory_ranges, vec![(start, end)]);
+    assert!(node.contains(start, PAddr::from(0x100_2000)));
+    assert!(!node.contains(PAddr::from(0x4ff_f000), PAddr::from(0x500_1000)));
+    assert_eq!(topology.domain_of_apic(1), Some(1));
+    assert_eq!(topology.domain_of_apic(2), None);
+}
+
+fn memblock_alloc() {
+    let mut buf = [0u64; 8];
+    let base = buf.as_mut_ptr() as usize;
+    let mut memblock = unsafe { Memblock::new(buf.as_mut_ptr() as *mut u8
+                                             , 64) };
+    assert_eq!(memblock.alloc(3, 1) as usize, base);
+    // the next allocation skips ahead to its alignment.
+    assert_eq!(memblock.alloc(8, 8) as usize, base + 8);
+    assert_eq!(memblock.remaining(), 48);
+    assert!(memblock.alloc(49, 1).is_null());
+    assert_eq!(memblock.alloc(48, 1) as usize, base + 16);
+    assert_eq!(memblock.remaining(), 0);
+}
+
+fn boot_memblock_frames() {
+    let mut buf = vec![0u8; 4 * PAGE_SIZE as usize];
+    let mut memblock = unsafe { Memblock::new(buf.as_mut_ptr(), buf.len()) };
+    let frame = unsafe { memblock.alloc_frames(1) }.expect("no frame");
+    assert_eq!(*frame % PAGE_SIZE, 0);
+    assert!(unsafe { memblock.alloc_frames(4) }.is_none());
+}
+
+/// Hands out made-up frames, and checks that each is freed exactly once.
+struct MockAllocator { next: u64
+                     , live: Vec<(PAddr, usize)>
+                     }
+
+impl BootAllocator for MockAllocator {
+    unsafe fn alloc_frames(&mut self, count: usize) -> Option<PAddr> {
+        let base = PAddr::from(self.next);
+        self.next += count as u64 * PAGE_SIZE;
+        self.live.push((base, count));
+        Some(base)
+    }
+
+    unsafe fn free_frames(&mut self, base: PAddr, count: usize) {
+        let i = self.live.iter().position(|&live| live == (base, count))
+            .expect("freed frames that weren't allocated");
+        self.live.remove(i);
+    }
+}
+
+fn boot_frames() {
+    let mut mock = MockAllocator { next: 0x10_0000, live: Vec::new() };
+    unsafe {
+        let (range, frame) = {
+            let mut frames = BootFrames(&mut mock);
+            let range = frames.allocate_range(3).expect("no range");
+            let frame = frames.allocate().expect("no frame");
+            (range, frame)
+        };
+        assert_eq!(range.length(), 3);
+        assert_eq!(frame.base_addr(), PAddr::from(0x10_3000));
+        assert_eq!(mock.live.len(), 2);
+        let mut frames = BootFrames(&mut mock);
+        frames.deallocate_range(range);
+        frames.deallocate(frame);
+    }
+    assert!(mock.live.is_empty());
+}
+
+fn segment_selectors() {
+    // these have to match the layout of `gdt64` in `boot.asm`.
+    assert_eq!(segment::KERNEL_CODE_SELECTOR.bits(), 0x08);
+    assert_eq!(segment::KERNEL_DATA_SELECTOR.bits(), 0x10);
+    assert_eq!(segment::USER_DATA_SELECTOR.bits(), 0x18 | 3);
+    assert_eq!(segment::USER_CODE_SELECTOR.bits(), 0x20 | 3);
+    assert_eq!(Selector::from_cs(), segment::KERNEL_CODE_SELECTOR);
+
+    let mut selector = Selector::new(7, PrivilegeLevel::Ring2
+                                    , TableIndicator::Ldt);
+    assert_eq!(selector.index(), 7);
+    assert_eq!(selector.rpl(), PrivilegeLevel::Ring2);
+    assert!(selector.is_ldt());
+    selector.set_global().set_rpl(PrivilegeLevel::UserMode);
+    assert_eq!(selector.index(), 7);
+    assert_eq!(selector.rpl(), PrivilegeLevel::UserMode);
+    assert!(!selector.is_ldt());
+}
+
+fn cpu_rdtsc() {
+    unsafe {
+        let first = insn::rdtsc();
+        insn::pause();
+        insn::mfence();
+        let second = insn::rdtsc();
+        assert!(first != 0, "the TSC isn't running");
+        assert!(second > first, "the TSC went backwards");
+    }
+}
+
+fn cpu_cpuid() {
+    let max_leaf = unsafe { insn::cpuid(0, 0) }.eax;
+    assert!(max_leaf >= 1, "CPUID leaf 1 isn't supported");
+    // leaf 1 is the same whichever way it's read.
+    assert_eq!( unsafe { insn::cpuid(1, 0) }.eax
+              , ::cpu::cpuid::cpuid(1, 0).eax);
+}
+
+fn kdump_lz4() {
+    let mut compressor = Box::new(lz4::Compressor::new());
+    let mut page = vec![0u8; PAGE_SIZE as usize];
+    let mut packed = vec![0u8; PAGE_SIZE as usize * 2];
+    let mut unpacked = vec![0u8; PAGE_SIZE as usize];
+
+    // a page of zeroes is one literal and one long match.
+    let len = compressor.compress(&page, &mut packed).expect("didn't fit");
+    assert!(len < 32, "a zero page compressed to {} bytes", len);
+    assert_eq!(lz4::decompress(&packed[..len], &mut unpacked), Some(4096));
+    assert!(unpacked.iter().all(|&b| b == 0));
+
+    for (i, byte) in page.iter_mut().enumerate() {
+        *byte = (i * 7 % 13) as u8 ^ if i > 2000 { i as u8 } else { 0 };
+    }
+    let len = compressor.compress(&page, &mut packed).expect("didn't fit");
+    assert_eq!(lz4::decompress(&packed[..len], &mut unpacked), Some(4096));
+    assert_eq!(page, unpacked);
+
+    // incompressible data doesn't fit in a page's worth of output.
+    let mut seed = 1u32;
+    for byte in page.iter_mut() {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        *byte = (seed >> 16) as u8;
+    }
+    let (small, _) = packed.split_at_mut(PAGE_SIZE as usize);
+    assert!(compressor.compress(&page, small).is_none());
+}
+
+fn perf_buckets() {
+    let rip = perf_buckets as usize;
+    let index = perf::bucket_of(rip).expect("kernel text isn't in the kernel");
+    let span = perf::HIST_SIZE * perf::BUCKET_SIZE;
+    assert!((rip - perf::bucket_start(index)) % span < perf::BUCKET_SIZE);
+    assert_eq!(perf::bucket_of(0), None);
+
+    let before = unsafe { perf::PROFILE_HIST[index] };
+    perf::sample(rip);
+    assert_eq!(unsafe { perf::PROFILE_HIST[index] }, before + 1);
+}
+
+fn net_checksum() {
+    // an IPv4 header, with its checksum (0xb861) zeroed.
+    let mut header = [ 0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00
+                     , 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01
+                     , 0xc0, 0xa8, 0x00, 0xc7 ];
+    assert_eq!(net::checksum(&header), 0xb861);
+    net::put_u16(&mut header, 10, 0xb861);
+    assert_eq!(net::checksum(&header), 0);
+    // an odd byte at the end is padded with a zero.
+    assert_eq!(net::checksum(&[0x12, 0x34, 0x56]), !(0x1234u16 + 0x5600));
+}
+
+/// The ext2 image for `ext2_mount`: 64 1K blocks.
+static mut EXT2_IMAGE: [u8; 64 * 1024] = [0; 64 * 1024];
+
+/// The length of `hello.txt` in `EXT2_IMAGE`: long enough to need its
+/// singly-indirect block.
+const EXT2_FILE_LEN: usize = 14 * 1024 - 100;
+
+fn put_le16(buf: &mut [u8], at: usize, value: u16) {
+    buf[at] = value as u8;
+    buf[at + 1] = (value >> 8) as u8;
+}
+
+fn put_le32(buf: &mut [u8], at: usize, value: u32) {
+    put_le16(buf, at, value as u16);
+    put_le16(buf, at + 2, (value >> 16) as u16);
+}
+
+fn put_le64(buf: &mut [u8], at: usize, value: u64) {
+    put_le32(buf, at, value as u32);
+    put_le32(buf, at + 4, (value >> 32) as u32);
+}
+
+/// Lay out an ext2 file system with one block group, as `mke2fs` would:
+/// the superblock in block 1, the group descriptors in block 2, the inode
+/// table in blocks 5 and 6, and the root directory in block 7. The root
+/// holds `hello.txt`, inode 11, whose data is in blocks 8 to 21, with its
+/// indirect block in block 22.
+fn ext2_image(image: &mut [u8]) {
+    const BLOCK: usize = 1024;
+    let sb = BLOCK;
+    put_le32(image, sb, 16);            // s_inodes_count
+    put_le32(image, sb + 4, 64);        // s_blocks_count
+    put_le32(image, sb + 20, 1);        // s_first_data_block
+    put_le32(image, sb + 32, 8192);     // s_blocks_per_group
+    put_le32(image, sb + 40, 16);       // s_inodes_per_group
+    put_le16(image, sb + 56, 0xef53);   // s_magic
+    put_le32(image, sb + 76, 1);        // s_rev_level
+    put_le32(image, sb + 84, 11);       // s_first_ino
+    put_le16(image, sb + 88, 128);      // s_inode_size
+    put_le32(image, sb + 96, 0x2);      // s_feature_incompat: filetype
+
+    let gd = 2 * BLOCK;
+    put_le32(image, gd, 3);             // bg_block_bitmap
+    put_le32(image, gd + 4, 4);         // bg_inode_bitmap
+    put_le32(image, gd + 8, 5);         // bg_inode_table
+
+    let inode = |ino: usize| 5 * BLOCK + (ino - 1) * 128;
+    let root = inode(2);
+    put_le16(image, root, 0o40755);
+    put_le32(image, root + 4, BLOCK as u32);
+    put_le32(image, root + 40, 7);
+    let file = inode(11);
+    put_le16(image, file, 0o100644);
+    put_le32(image, file + 4, EXT2_FILE_LEN as u32);
+    for i in 0..12 {
+        put_le32(image, file + 40 + i * 4, 8 + i as u32);
+    }
+    put_le32(image, file + 40 + 12 * 4, 22);
+    put_le32(image, 22 * BLOCK, 20);
+    put_le32(image, 22 * BLOCK + 4, 21);
+    for i in 0 .. EXT2_FILE_LEN {
+        image[8 * BLOCK + i] = (i % 251) as u8;
+    }
+
+    let dir = 7 * BLOCK;
+    let entries: [(u32, &[u8], u8, usize); 3]
+        = [ (2, b".", 2, 12), (2, b"..", 2, 12)
+          , (11, b"hello.txt", 1, BLOCK - 24) ];
+    let mut at = dir;
+    for &(ino, name, kind, rec_len) in entries.iter() {
+        put_le32(image, at, ino);
+        put_le16(image, at + 4, rec_len as u16);
+        image[at + 6] = name.len() as u8;
+        image[at + 7] = kind;
+        image[at + 8 .. at + 8 + name.len()].copy_from_slice(name);
+        at += rec_len;
+    }
+}
+
+fn ext2_mount() {
+    let image = unsafe { &mut EXT2_IMAGE };
+    ext2_image(image);
+    let fs = Ext2::mount(Arc::new(Ramdisk::from_static_slice(image, 512)))
+        .expect("mount failed");
+    assert_eq!(fs.block_size(), 1024);
+    let root = Ext2::root(&fs).expect("no root directory");
+    assert!(root.stat().is_dir());
+
+    let entry = root.readdir(0).unwrap().expect("root directory is empty");
+    assert_eq!(entry.name.as_bytes(), b"hello.txt");
+    assert_eq!(entry.kind, mode::S_IFREG);
+    assert!(root.readdir(1).unwrap().is_none());
+
+    let file = root.lookup(b"hello.txt").expect("lookup failed");
+    assert_eq!(file.stat().size, EXT2_FILE_LEN as u64);
+    // read across the end of the direct blocks, into the indirect ones.
+    let mut buf = [0u8; 300];
+    let offset = 12 * 1024 - 100;
+    assert_eq!(file.read_at(offset as u64, &mut buf), Ok(300));
+    for (i, &byte) in buf.iter().enumerate() {
+        assert_eq!(byte, ((offset + i) % 251) as u8);
+    }
+    // reads stop at the end of the file.
+    let end = EXT2_FILE_LEN as u64 - 10;
+    assert_eq!(file.read_at(end, &mut buf), Ok(10));
+    assert!(file.write_at(0, b"no").is_err());
+    assert!(root.lookup(b"missing").is_err());
+}
+
+/// The FAT32 image for `fat32_mount`: 64 512-byte sectors.
+static mut FAT32_IMAGE: [u8; 64 * 512] = [0; 64 * 512];
+
+/// The long name of the first file in `FAT32_IMAGE`.
+const FAT32_LONG_NAME: &'static [u8] = b"A long file name.txt";
+/// The length of that file: it spans two clusters.
+const FAT32_FILE_LEN: usize = 700;
+
+/// Lay out a FAT32 file system with one-sector clusters: the BPB in
+/// sector 0, the FAT in sector 1, and the root directory in cluster 2
+/// (sector 2). The root holds a file with a long name in clusters 3 and 5,
+/// and `README.TXT`, with its 8.3 name marked as lower case, in cluster 6.
+fn fat32_image(image: &mut [u8]) {
+    const SECTOR: usize = 512;
+    put_le16(image, 11, SECTOR as u16);   // bytes per sector
+    image[13] = 1;                        // sectors per cluster
+    put_le16(image, 14, 1);               // reserved sectors
+    image[16] = 1;                        // FATs
+    put_le32(image, 32, 64);              // total sectors
+    put_le32(image, 36, 1);               // sectors per FAT
+    put_le32(image, 44, 2);               // root cluster
+    image[82..90].copy_from_slice(b"FAT32   ");
+    put_le16(image, 510, 0xaa55);
+
+    let fat = SECTOR;
+    let chain = [0x0fff_fff8, 0x0fff_ffff, 0x0fff_ffff, 5, 0, 0x0fff_ffff
+                , 0x0fff_ffff];
+    for (i, &next) in chain.iter().enumerate() {
+        put_le32(image, fat + i * 4, next);
+    }
+
+    // the data region starts at sector 2, so cluster `n` is sector `n`.
+    let cluster = |n: usize| n * SECTOR;
+    let short = b"ALONGF~1TXT";
+    let checksum = fat32::lfn_checksum(short);
+    let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+    let root = cluster(2);
+    // long name entries come last part first.
+    for (n, seq) in [2, 1].iter().enumerate() {
+        let entry = root + n * 32;
+        image[entry] = *seq | if *seq == 2 { 0x40 } else { 0 };
+        image[entry + 11] = 0x0f;
+        image[entry + 13] = checksum;
+        for (i, &at) in offsets.iter().enumerate() {
+            let index = (*seq as usize - 1) * 13 + i;
+            let c = if index < FAT32_LONG_NAME.len() {
+                        FAT32_LONG_NAME[index] as u16
+                    } else if index == FAT32_LONG_NAME.len() { 0 }
+                    else { 0xffff };
+            put_le16(image, entry + at, c);
+        }
+    }
+    let entry = root + 2 * 32;
+    image[entry .. entry + 11].copy_from_slice(short);
+    put_le16(image, entry + 26, 3);
+    put_le32(image, entry + 28, FAT32_FILE_LEN as u32);
+    let entry = root + 3 * 32;
+    image[entry .. entry + 11].copy_from_slice(b"README  TXT");
+    image[entry + 12] = 0x18;
+    put_le16(image, entry + 26, 6);
+    put_le32(image, entry + 28, 5);
+
+    for i in 0 .. FAT32_FILE_LEN {
+        let at = if i < SECTOR { cluster(3) + i }
+                 else { cluster(5) + i - SECTOR };
+        image[at] = (i % 251) as u8;
+    }
+    image[cluster(6) .. cluster(6) + 5].copy_from_slice(b"hello");
+}
+
+fn fat32_mount() {
+    let image = unsafe { &mut FAT32_IMAGE };
+    fat32_image(image);
+    let fs = Fat32::mount(Arc::new(Ramdisk::from_static_slice(image, 512)))
+        .expect("mount failed");
+    assert_eq!(fs.cluster_size(), 512);
+    let chain = fs.follow_chain(3).collect::<Result<Vec<_>, _>>();
+    assert_eq!(chain, Ok(vec![3, 5]));
+
+    let root = Fat32::root(&fs);
+    let entry = root.readdir(0).unwrap().expect("root directory is empty");
+    assert_eq!(entry.name.as_bytes(), FAT32_LONG_NAME);
+    assert_eq!(entry.kind, mode::S_IFREG);
+    let entry = root.readdir(1).unwrap().expect("README.TXT is missing");
+    assert_eq!(entry.name.as_bytes(), b"readme.txt");
+    assert!(root.readdir(2).unwrap().is_none());
+
+    // read across the end of the first cluster, into the second.
+    let file = root.lookup(b"a LONG file name.txt").expect("lookup failed");
+    assert_eq!(file.stat().size, FAT32_FILE_LEN as u64);
+    let mut buf = [0u8; 100];
+    assert_eq!(file.read_at(450, &mut buf), Ok(100));
+    for (i, &byte) in buf.iter().enumerate() {
+        assert_eq!(byte, ((450 + i) % 251) as u8);
+    }
+    assert_eq!(file.read_at(650, &mut buf), Ok(50));
+
+    let readme = root.lookup(b"README.TXT").expect("lookup failed");
+    assert_eq!(readme.read_at(0, &mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"hello");
+    assert!(readme.write_at(0, b"no").is_err());
+}
+
+fn channel_try_ops() {
+    let (tx, rx) = channel::channel::<usize>(2);
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(tx.try_send(1), Ok(()));
+    assert_eq!(tx.try_send(2), Ok(()));
+    assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+    assert_eq!(rx.try_recv(), Ok(1));
+    assert_eq!(tx.try_send(3), Ok(()));
+    // values sent before the sender was dropped are still received.
+    drop(tx);
+    assert_eq!(rx.try_recv(), Ok(2));
+    assert_eq!(rx.try_recv(), Ok(3));
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+
+    let (tx, rx) = channel::channel(1);
+    drop(rx);
+    assert_eq!(tx.try_send(1), Err(TrySendError::Disconnected(1)));
+    assert_eq!(tx.send(1), Err(channel::SendError(1)));
+}
+
+fn seccomp_filter() {
+    // read, write, and exit.
+    let mut bits = [0u64; 8];
+    bits[0] = 1 << 0 | 1 << 1 | 1 << 60;
+    let filter = SyscallFilter(bits);
+    assert!(filter.allows(0) && filter.allows(1) && filter.allows(60));
+    assert!(!filter.allows(2) && !filter.allows(57));
+    assert!(!filter.allows(511) && !filter.allows(512));
+
+    // a second filter can only take system calls away.
+    let mut bits = [!0u64; 8];
+    bits[0] &= !(1 << 1);
+    let narrowed = filter.intersect(&SyscallFilter(bits));
+    assert!(narrowed.allows(0) && narrowed.allows(60));
+    assert!(!narrowed.allows(1) && !narrowed.allows(2));
+}
+
+fn sysctl_tree() {
+    assert!(sysctl::valid_path("kernel/pid_max"));
+    assert!(!sysctl::valid_path(""));
+    assert!(!sysctl::valid_path("/kernel/pid_max"));
+    assert!(!sysctl::valid_path("kernel/"));
+    assert!(!sysctl::valid_path("kernel//pid_max"));
+
+    match sysctl::lookup("kernel") {
+        Some(Sysctl::Dir) => {}
+      , _ => panic!("kernel isn't a directory!")
+    }
+    assert!(sysctl::lookup("kernel/no_such_thing").is_none());
+    match sysctl::lookup("vm/swappiness") {
+        Some(Sysctl::Param(get, Some(set))) => {
+            let old = get();
+            set(10);
+            assert_eq!(get(), 10);
+            // out of range values are clamped.
+            set(1000);
+            assert_eq!(get(), 100);
+            set(old);
+        }
+      , _ => panic!("vm/swappiness isn't a writable parameter!")
+    }
+
+    let mut names = Vec::new();
+    while let Some((name, is_dir)) = sysctl::read_dir("", names.len()) {
+        assert!(is_dir, "{} isn't a directory!", name);
+        names.push(name);
+    }
+    assert!(names.contains(&"kernel") && names.contains(&"vm"));
+}
+
+/// The number of pages in the swap area for `swap_slots`.
+const SWAP_SLOTS: usize = 4;
+
+/// The swap device for `swap_slots`: two 512-byte blocks, and then the
+/// swap area.
+static mut SWAP_IMAGE: [u8; 1024 + SWAP_SLOTS * 4096]
+    = [0; 1024 + SWAP_SLOTS * 4096];
+
+fn swap_slots() {
+    let device: Arc<BlockDevice>
+        = Arc::new(Ramdisk::from_static_slice(unsafe { &mut SWAP_IMAGE }, 512));
+    let slots: Vec<u64>
+        = (0..SWAP_SLOTS).map(|_| swap::alloc_slot(&device, 2)
+                                       .expect("swap area is full"))
+                         .collect();
+    assert!(swap::alloc_slot(&device, 2).is_none());
+    // each slot starts a page's worth of blocks after the one before.
+    assert_eq!(swap::slot_offset(&device, 2, slots[0]), Some(2));
+    assert_eq!(swap::slot_offset(&device, 2, slots[1]), Some(2 + 8));
+    assert!(swap::slot_offset(&device, 0, slots[0]).is_none());
+
+    // a shared slot is only freed once both references are gone.
+    swap::dup_slot(slots[1]);
+    swap::free_slot(slots[1]);
+    assert!(swap::alloc_slot(&device, 2).is_none());
+    swap::free_slot(slots[1]);
+    assert_eq!(swap::alloc_slot(&device, 2), Some(slots[1]));
+
+    let mut frames = frame::allocator();
+    let (out, back) = unsafe {
+        (frames.allocate().unwrap(), frames.allocate().unwrap())
+    };
+    let page = |frame: PhysicalPage| unsafe {
+        slice::from_raw_parts_mut(
+            phys_to_virt(frame.base_addr()).as_mut_ptr::<u8>()
+          , PAGE_SIZE as usize)
+    };
+    for (i, byte) in page(out).iter_mut().enumerate() { *byte = i as u8 }
+    let offset = swap::slot_offset(&device, 2, slots[3]).unwrap();
+    swap::write_page(&*device, offset, out).expect("write failed");
+    swap::read_page(&*device, offset, back).expect("read failed");
+    assert!(page(back).iter().enumerate().all(|(i, &b)| b == i as u8));
+    unsafe {
+        frames.deallocate(out);
+        frames.deallocate(back);
+    }
+    for slot in slots { swap::free_slot(slot) }
+}
+
+fn task_stack_canary() {
+    let (a, b) = (KernelStack::new(), KernelStack::new());
+    for stack in &[&a, &b] {
+        let addr = stack.canary_addr() as usize;
+        assert!(addr >= stack.top() - KERNEL_STACK_SIZE);
+        assert_eq!(addr % 16, 0);
+        assert_eq!(unsafe { *stack.canary_addr() }, stack.canary());
+    }
+    // the TSC moved on between the two.
+    assert!(a.canary() != b.canary());
+}
+
+fn kaslr_slide() {
+    assert_eq!(kaslr::compute_slide(0), 0);
+    assert_eq!(kaslr::compute_slide(!0), SLIDE_MAX - SLIDE_ALIGN);
+    assert_eq!(kaslr::compute_slide(SLIDE_ALIGN * 3 + 12345), SLIDE_ALIGN * 3);
+    let slide = kaslr::compute_slide(0x1234_5678_9abc_def0);
+    assert!(slide < SLIDE_MAX && slide % SLIDE_ALIGN == 0);
+
+    // two symbols keep the same distance apart, and each comes back to
+    // where it was linked.
+    let (a, b) = (kaslr_slide as u64, task_stack_canary as u64);
+    let (slid_a, slid_b)
+        = (kaslr::apply_slide(a, slide), kaslr::apply_slide(b, slide));
+    assert_eq!(slid_a.wrapping_sub(slid_b), a.wrapping_sub(b));
+    assert_eq!(kaslr::remove_slide(slid_a, slide), a);
+    assert_eq!(kaslr::remove_slide(slid_b, slide), b);
+}
+
+fn kaslr_relocated() {
+    let slide = kaslr::slide();
+    assert!(slide < SLIDE_MAX && slide % SLIDE_ALIGN == 0);
+    // an address the linker wrote into the image, which the boot stub had
+    // to move, agrees with the one the CPU works out from where the code
+    // really is; in a kernel that wasn't moved, trivially.
+    let (absolute, relative): (u64, u64);
+    unsafe {
+        asm!("movabsq $$KASLR_SLIDE, $0
+              leaq KASLR_SLIDE(%rip), $1"
+            : "=r"(absolute), "=r"(relative));
+    }
+    assert_eq!(absolute, relative);
+    assert_eq!(&kaslr::KASLR_SLIDE as *const _ as u64, relative);
+    assert!(kaslr::remove_slide(relative, slide) >= 0x10_0000);
+}
+
+fn syslog_kmsg() {
+    syslog::log(3, format_args!("syslog test {}", 1));
+    let (oldest, newest) = without_interrupts(|| {
+        let ring = syslog::RING.lock();
+        (ring.get(0).unwrap().seq, ring.get(ring.len() - 1).unwrap().seq)
+    });
+    let entry = syslog::entry_from(newest).unwrap();
+    assert_eq!(entry.message(), b"syslog test 1");
+    assert_eq!(entry.level, 3);
+    assert!(syslog::entry_from(newest + 1).is_none());
+    // anything older than the ring comes back as the oldest entry left.
+    assert_eq!(syslog::entry_from(0).unwrap().seq, oldest);
+
+    let kmsg = Devfs::kmsg();
+    assert_eq!(kmsg.write_at(0, b"<2>hello\n"), Ok(9));
+    let mut buf = [0u8; 256];
+    let len = loop {
+        let n = kmsg.read_at(0, &mut buf).expect("read failed");
+        if buf[..n].ends_with(b"] hello\n") { break n }
+    };
+    assert!(buf[..len].starts_with(b"2,"));
+    assert_eq!(buf[..len].iter().filter(|&&b| b == b',').count(), 2);
+    // a buffer too small for the next entry is an error, as on Linux.
+    assert_eq!( Devfs::kmsg().read_at(0, &mut buf[..4])
+              , Err(IoError::InvalidArgument));
+}
+
+fn rng_fill_bytes() {
+    let mut buf = [0u8; 61];
+    rng::fill_bytes(&mut buf);
+    assert!(buf.iter().any(|&b| b != 0));
+    assert!(rng::next_u64() != rng::next_u64());
+    // the fallback too, whatever this CPU has.
+    let (a, b) = (rng::lfsr(), rng::lfsr());
+    assert!(a != 0 && b != 0 && a != b);
+
+    let slide = kaslr::random_slide();
+    assert!(slide < SLIDE_MAX && slide % SLIDE_ALIGN == 0);
+}
+
+fn virtio_rng_protocol() {
+    let mut queue = Virtqueue::new(0).unwrap();
+    let mut buf = unsafe { RngBuffer::zeroed() }.unwrap();
+    let mut out = [0u8; 16];
+    let mut next_avail = 0;
+
+    // nothing back before the device has answered.
+    let head = virtio_rng::submit(&mut queue, &buf, out.len()).unwrap();
+    assert_eq!(virtio_rng::collect(&mut queue, &buf, &mut out), None);
+
+    // the device sees one writable buffer, of the length asked for.
+    assert_eq!(queue.device_pop_avail(&mut next_avail), Some(head));
+    assert_eq!(queue.device_pop_avail(&mut next_avail), None);
+    let desc = queue.descriptor(head);
+    assert_eq!(desc.addr, *buf.paddr());
+    assert_eq!(desc.len, 16);
+    assert_eq!(desc.flags, WRITE.bits());
+
+    // it fills fewer bytes than asked for, which is all that's copied.
+    for (i, byte) in buf.iter_mut().enumerate() { *byte = i as u8 + 1 }
+    queue.device_push_used(head, 10);
+    assert_eq!(virtio_rng::collect(&mut queue, &buf, &mut out), Some(10));
+    assert_eq!(&out[..10], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+    assert_eq!(&out[10..], &[0; 6]);
+    assert_eq!(virtio_rng::collect(&mut queue, &buf, &mut out), None);
+
+    // requests are capped at the buffer, and so is what's collected.
+    let mut big = [0u8; RNG_BUF * 2];
+    let head = virtio_rng::submit(&mut queue, &buf, big.len()).unwrap();
+    assert_eq!(queue.descriptor(head).len, RNG_BUF as u32);
+    assert_eq!(queue.device_pop_avail(&mut next_avail), Some(head));
+    queue.device_push_used(head, RNG_BUF as u32 * 2);
+    assert_eq!( virtio_rng::collect(&mut queue, &buf, &mut big)
+              , Some(RNG_BUF));
+    assert_eq!(big[RNG_BUF - 1], RNG_BUF as u8);
+    assert_eq!(queue.num_free(), QUEUE_SIZE);
+}
+
+/// The size of each descriptor `fake_get_memory_map` returns, which is
+/// padded, as real firmware's are.
+const UEFI_DESC_SIZE: usize = 48;
+
+static mut UEFI_MAP: [u8; 4 * UEFI_DESC_SIZE] = [0; 4 * UEFI_DESC_SIZE];
+
+/// `GetMemoryMap` for the fake boot services: 64 KiB of usable memory at
+/// 1 MiB, and a page of reserved memory at 2 MiB.
+extern "win64" fn fake_get_memory_map( size: *mut usize, map: *mut u8
+                                     , key: *mut usize
+                                     , descriptor_size: *mut usize
+                                     , version: *mut u32) -> EfiStatus {
+    unsafe {
+        let needed = 2 * UEFI_DESC_SIZE;
+        *descriptor_size = UEFI_DESC_SIZE;
+        *version = 1;
+        if *size < needed {
+            *size = needed;
+            return EfiStatus::buffer_too_small()
+        }
+        *size = needed;
+        *key = 0x1234;
+        // type, physical start and number of pages
+        for &(i, ty, start, pages) in [ (0, 7u32, 0x10_0000u64, 16u64)
+                                      , (1, 0, 0x20_0000, 1)].iter() {
+            let desc = map.offset(i * UEFI_DESC_SIZE as isize);
+            ptr::write_unaligned(desc as *mut u32, ty);
+            ptr::write_unaligned(desc.offset(8) as *mut u64, start);
+            ptr::write_unaligned(desc.offset(24) as *mut u64, pages);
+        }
+        EfiStatus::success()
+    }
+}
+
+/// Give `hdr` the signature `signature`, and the CRC32 of the
+/// `size`-byte table it starts.
+unsafe fn seal_efi_table( hdr: &mut EfiTableHeader, signature: u64
+                        , size: usize) {
+    hdr.signature = signature;
+    hdr.header_size = size as u32;
+    hdr.crc32 = uefi::table_crc32(hdr as *const _ as *const u8, size);
+}
+
+fn uefi_system_table() {
+    // the standard CRC32 check value.
+    assert_eq!(unsafe { uefi::table_crc32(b"123456789".as_ptr(), 9) }
+              , 0xcbf4_3926);
+
+    let empty_hdr = EfiTableHeader { signature: 0, revision: 0
+                                   , header_size: 0, crc32: 0, reserved: 0 };
+    let mut boot = BootServices { hdr: empty_hdr
+                                , raise_tpl: 0, restore_tpl: 0
+                                , allocate_pages: 0, free_pages: 0
+                                , get_memory_map: fake_get_memory_map
+                                };
+    let configs = [ ConfigurationTable { vendor_guid: uefi::EFI_ACPI_TABLE_GUID
+                                       , vendor_table: 0xe_0000 as *const u8 }
+                  , ConfigurationTable {
+                        vendor_guid: uefi::EFI_ACPI_20_TABLE_GUID
+                      , vendor_table: 0xf_0000 as *const u8 }
+                  ];
+    let mut table = SystemTable { hdr: empty_hdr
+                                , firmware_vendor: ptr::null()
+                                , firmware_revision: 0
+                                , console_in_handle: ptr::null()
+                                , con_in: ptr::null()
+                                , console_out_handle: ptr::null()
+                                , con_out: ptr::null()
+                                , standard_error_handle: ptr::null()
+                                , std_err: ptr::null()
+                                , runtime_services: ptr::null()
+                                , boot_services: &boot
+                                , number_of_table_entries: configs.len()
+                                , configuration_table: configs.as_ptr()
+                                };
+    let table_ptr = &table as *const SystemTable as *const _;
+    unsafe {
+        seal_efi_table( &mut boot.hdr, uefi::BOOT_SERVICES_SIGNATURE
+                      , mem::size_of::<BootServices>());
+        seal_efi_table( &mut table.hdr, uefi::SYSTEM_TABLE_SIGNATURE
+                      , mem::size_of::<SystemTable>());
+    }
+    let system = unsafe { SystemTable::from_ptr(table_ptr) }.unwrap();
+    assert!(system.runtime_services().is_none());
+
+    // ACPI 2.0's RSDP is preferred, wherever it is in the array.
+    assert_eq!(system.configuration_tables().len(), 2);
+    assert_eq!(system.acpi_rsdp(), Some(PAddr::from(0xf_0000)));
+
+    let boot_services = system.boot_services().unwrap();
+    let too_small = unsafe { &mut UEFI_MAP[..UEFI_DESC_SIZE] };
+    assert_eq!( boot_services.get_memory_map(too_small).err()
+              , Some(EfiStatus::buffer_too_small()));
+    let map = boot_services.get_memory_map(unsafe { &mut UEFI_MAP })
+        .unwrap();
+    assert_eq!(map.map_key, 0x1234);
+    let areas = map.iter().collect::<Vec<_>>();
+    assert_eq!(areas.len(), 2);
+    assert_eq!(areas[0].start_addr, PAddr::from(0x10_0000));
+    assert_eq!(areas[0].end_addr, PAddr::from(0x10_ffff));
+    assert!(areas[0].is_usable);
+    assert_eq!(areas[1].start_addr, PAddr::from(0x20_0000));
+    assert!(!areas[1].is_usable);
+
+    // any change to a table spoils its CRC32, and a bad signature is
+    // caught even with a good CRC32.
+    table.firmware_revision = 1;
+    assert!(unsafe { SystemTable::from_ptr(table_ptr) }.is_none());
+    unsafe {
+        seal_efi_table(&mut table.hdr, 0, mem::size_of::<SystemTable>());
+    }
+    assert!(unsafe { SystemTable::from_ptr(table_ptr) }.is_none());
+    assert!(unsafe { SystemTable::from_ptr(ptr::null()) }.is_none());
+}
+
+fn mtrr_lookup() {
+    const VALID: u64 = 1 << 11;
+    // 8 variable-range MTRRs and fixed ranges; enabled, default WB.
+    let (cap, def_type) = (0x108, 0xc06);
+    let mut fixed = [0; NUM_FIXED];
+    fixed[0] = 0x0606_0606_0606_0606;
+    fixed[1] = 0x0606_0606_0606_0606;
+    fixed[2] = 0x0101_0101_0101_0101;
+    let variable = [ // UC from 3 GiB to 4 GiB, and WC over part of it
+                     (0xc000_0000, 0xf_c000_0000 | VALID)
+                   , (0xe000_0001, 0xf_f000_0000 | VALID)
+                     // WT and WB over the same 4 GiB
+                   , (0x1_0000_0004, 0xf_0000_0000 | VALID)
+                   , (0x1_0000_0006, 0xf_0000_0000 | VALID)
+                     // not valid, and not a type
+                   , (0x2_0000_0000, 0xf_f000_0000)
+                   , (0x3_0000_0002, 0xf_f000_0000 | VALID)
+                   ];
+    let map = MtrrMap::from_msrs(cap, def_type, fixed, &variable);
+    let lookup = |addr: u64| map.lookup(PAddr::from(addr));
+
+    assert_eq!(lookup(0x1000), MemType::WriteBack);
+    assert_eq!(lookup(0xb_8000), MemType::WriteCombining);
+    assert_eq!(lookup(0xf_0000), MemType::Uncacheable);
+    assert_eq!(lookup(0x20_0000), MemType::WriteBack);
+    assert_eq!(lookup(0xfee0_0000), MemType::Uncacheable);
+    // overlaps: UC beats anything, and WT beats WB.
+    assert_eq!(lookup(0xe000_0000), MemType::Uncacheable);
+    assert_eq!(lookup(0x1_0000_1000), MemType::WriteThrough);
+    assert_eq!(lookup(0x2_0000_0000), MemType::WriteBack);
+    assert_eq!(lookup(0x3_0000_0000), MemType::WriteBack);
+
+    assert_eq!( map.lookup_range(PAddr::from(0xfed0_0000), 0x400)
+              , Some(MemType::Uncacheable));
+    assert_eq!(map.lookup_range(PAddr::from(0xbfff_f000), 0x2000), None);
+
+    // disabled MTRRs make everything UC.
+    let disabled = MtrrMap::from_msrs(cap, 0x406, fixed, &variable);
+    assert_eq!( disabled.lookup(PAddr::from(0x1000))
+              , MemType::Uncacheable);
+}
+
+/// `module_init` for the test module: `movl $42, counter(%rip); ret`.
+const MODULE_TEXT: [u8; 11]
+    = [0xc7, 0x05, 0, 0, 0, 0, 0x2a, 0, 0, 0, 0xc3];
+
+/// Where the section headers start in the test module.
+const MODULE_SHOFF: usize = 0x140;
+
+/// Write section header `idx` of the test module.
+fn put_section( image: &mut [u8], idx: usize, name: u32, ty: u32, flags: u64
+              , offset: u64, size: u64, link: u32, info: u32, align: u64) {
+    let at = MODULE_SHOFF + idx * 64;
+    put_le32(image, at, name);
+    put_le32(image, at + 4, ty);
+    put_le64(image, at + 8, flags);
+    put_le64(image, at + 24, offset);
+    put_le64(image, at + 32, size);
+    put_le32(image, at + 40, link);
+    put_le32(image, at + 44, info);
+    put_le64(image, at + 48, align);
+    // relocation and symbol table entries are both 24 bytes.
+    if ty == 2 || ty == 4 { put_le64(image, at + 56, 24); }
+}
+
+/// Build a module, as `as` would assemble it, whose `.text` holds
+/// `MODULE_TEXT` and whose `.bss` holds a global `counter`. `.modinfo`
+/// points at the start of `.text`, and there's no `module_exit`.
+fn module_image() -> Vec<u8> {
+    let mut image = vec![0u8; MODULE_SHOFF + 9 * 64];
+    image[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+    put_le16(&mut image, 16, 1);                // ET_REL
+    put_le16(&mut image, 18, 0x3e);             // EM_X86_64
+    put_le32(&mut image, 20, 1);                // e_version
+    put_le64(&mut image, 40, MODULE_SHOFF as u64);
+    put_le16(&mut image, 52, 64);               // e_ehsize
+    put_le16(&mut image, 58, 64);               // e_shentsize
+    put_le16(&mut image, 60, 9);                // e_shnum
+    put_le16(&mut image, 62, 8);                // e_shstrndx
+
+    image[0x40..0x40 + MODULE_TEXT.len()].copy_from_slice(&MODULE_TEXT);
+    // .rela.text: R_X86_64_PC32 against `counter`, for the `movl`.
+    put_le64(&mut image, 0x60, 2);
+    put_le64(&mut image, 0x68, 2 << 32 | 2);
+    put_le64(&mut image, 0x70, -8i64 as u64);
+    // .rela.modinfo: R_X86_64_64 against `.text`, for `init`.
+    put_le64(&mut image, 0x80, 1 << 32 | 1);
+    // .symtab: the null symbol, `.text`, and `counter`.
+    image[0xa8 + 4] = 0x03;                     // STB_LOCAL, STT_SECTION
+    put_le16(&mut image, 0xa8 + 6, 1);
+    put_le32(&mut image, 0xc0, 1);
+    image[0xc0 + 4] = 0x11;                     // STB_GLOBAL, STT_OBJECT
+    put_le16(&mut image, 0xc0 + 6, 3);
+    put_le64(&mut image, 0xc0 + 16, 4);
+    image[0xd8..0xd8 + 9].copy_from_slice(b"\0counter\0");
+    let names = b"\0.text\0.modinfo\0.bss\0.rela.text\0.rela.modinfo\0\
+                  .symtab\0.strtab\0.shstrtab\0";
+    image[0xe8..0xe8 + names.len()].copy_from_slice(names);
+
+    put_section(&mut image, 1, 1, 1, 0x6, 0x40, 11, 0, 0, 16);
+    put_section(&mut image, 2, 7, 1, 0x3, 0x50, 16, 0, 0, 8);
+    put_section(&mut image, 3, 16, 8, 0x3, 0x60, 4, 0, 0, 4);
+    put_section(&mut image, 4, 21, 4, 0, 0x60, 24, 6, 1, 8);
+    put_section(&mut image, 5, 32, 4, 0, 0x78, 24, 6, 2, 8);
+    put_section(&mut image, 6, 46, 2, 0, 0x90, 72, 7, 2, 8);
+    put_section(&mut image, 7, 54, 3, 0, 0xd8, 9, 0, 0, 1);
+    put_section(&mut image, 8, 62, 3, 0, 0xe8, names.len() as u64, 0, 0, 1);
+    image
+}
+
+fn module_load() {
+    let image = module_image();
+    let id = module::load(&image, "test").expect("loading the module failed");
+    let counter = kallsyms::lookup("counter").expect("counter wasn't exported");
+    assert_eq!(counter.owner, Some(id));
+    assert!( counter.addr >= module::MODULE_BASE
+          && counter.addr < module::MODULE_END);
+    // `module_init` ran, with its relocations applied.
+    assert_eq!(unsafe { *(counter.addr as *const u32) }, 42);
+
+    // loading it again would define `counter` twice.
+    assert_eq!( module::load(&image, "again")
+              , Err(ModuleError::DuplicateSymbol(String::from("counter"))));
+    assert_eq!(module::unload(id), Ok(()));
+    assert!(kallsyms::lookup("counter").is_none());
+    assert_eq!(module::unload(id), Err(ModuleError::NoSuchModule));
+    assert!(module::load(b"not an ELF file", "bogus").is_err());
+}
+
+fn kallsyms_image() {
+    #[cfg(feature = "kallsyms")] {
+        // an image built without `make kernel-kallsyms` has no table.
+        if kallsyms::SYMBOLS.is_empty() { return }
+        let addr = VAddr::from(kallsyms_image as usize);
+        let symbol = kallsyms::name_of(addr).expect("no symbol for a test");
+        assert_eq!(symbol.addr, addr);
+        assert_eq!(kallsyms::name_of(addr + 1).map(|s| s.addr), Some(addr));
+        let mut name = String::new();
+        write!(name, "{}", symbol).unwrap();
+        assert!(name.contains("kallsyms_image"));
+        assert!(symbol.is(&name));
+        assert_eq!(kallsyms::addr_of(&name), Some(addr));
+        assert_eq!( kaslr::sym_lookup_addr(addr + 1).map(|s| s.addr)
+                  , Some(addr));
+        assert!(kallsyms::addr_of("no such symbol").is_none());
+        assert!(kallsyms::name_of(VAddr::from(0)).is_none());
+    }
+}
+
+fn workqueue_bounded() {
+    fn work(_: *mut ()) {}
+    // nothing runs this queue, so its items stay queued until it's dropped.
+    let queue = WorkQueue::new();
+    for _ in 0 .. QUEUE_SIZE {
+        assert!(queue.submit(work, ptr::null_mut()));
+    }
+    assert!(!queue.submit(work, ptr::null_mut()));
+}
+
+fn memfd_file_backing() {
+    let page = PAGE_SIZE as usize;
+    let file = Tmpfs::unlinked_file();
+    assert_eq!(file.stat().size, 0);
+    assert_eq!(file.truncate(3 * PAGE_SIZE), Ok(()));
+    assert_eq!(file.stat().size, 3 * PAGE_SIZE);
+    assert_eq!(file.write_at(2 * PAGE_SIZE, b"memfd"), Ok(5));
+    let mut buf = [0xffu8; 8];
+    assert_eq!(file.read_at(2 * PAGE_SIZE - 3, &mut buf), Ok(8));
+    assert_eq!(&buf, b"\0\0\0memfd");
+
+    // unmapping the middle of a file mapping leaves the part above it
+    // mapping the file from further on.
+    let start = VAddr::from(0x1000_0000_0000usize);
+    let end = VAddr::from(start.as_usize() + 4 * page);
+    let backing = VmBacking::File { inode: file.clone(), offset: PAGE_SIZE };
+    let mut vm = VmMap::new();
+    vm.insert(VmRegion::new(start, end, VM_READ | VM_WRITE)
+                       .with_backing(backing)).unwrap();
+    let hole = VAddr::from(start.as_usize() + page);
+    let above = VAddr::from(start.as_usize() + 2 * page);
+    vm.remove_range(hole, above);
+    match vm.find(start).map(|region| &region.backing) {
+        Some(&VmBacking::File { offset, .. }) => assert_eq!(offset, PAGE_SIZE)
+      , other => panic!("below the hole is {:?}!", other)
+    }
+    match vm.find(above).map(|region| &region.backing) {
+        Some(&VmBacking::File { offset, .. }) =>
+            assert_eq!(offset, 3 * PAGE_SIZE)
+      , other => panic!("above the hole is {:?}!", other)
+    }
+    assert!(vm.find(hole).is_none());
+}
+
+fn tmpfs_file_max() {
+    let file = Tmpfs::unlinked_file();
+    assert_eq!(file.truncate(TMPFS_FILE_MAX + 1), Err(IoError::FileTooBig));
+    assert_eq!(file.truncate(u64::max_value()), Err(IoError::FileTooBig));
+    assert_eq!( file.write_at(TMPFS_FILE_MAX, b"x")
+              , Err(IoError::FileTooBig));
+    // a failed resize leaves the file as it was.
+    assert_eq!(file.stat().size, 0);
+    assert_eq!(file.write_at(0, b"tmpfs"), Ok(5));
+    assert_eq!(file.stat().size, 5);
+}
+
+fn topology_levels() {
+    // two threads per core (1 bit), eight logical processors (4 cores) per
+    // package (3 bits).
+    let smt = CpuidResult { eax: 1, ebx: 2, ecx: 0x100, edx: 0 };
+    let core = CpuidResult { eax: 3, ebx: 8, ecx: 0x201, edx: 0 };
+    let levels = Levels::from_leaves(smt, core).expect("levels not decoded");
+    assert_eq!(levels.core_id(5), 2);
+    assert_eq!(levels.package_id(9), 1);
+    assert!(Levels::from_leaves(core, smt).is_none());
+
+    // CPUs 0 and 2 are threads of core 0, CPU 1 is alone on core 1, and
+    // CPU 3 is in the second package.
+    let apic_ids = [Some(0), Some(2), Some(1), Some(8), None];
+    assert_eq!( levels.topology(&apic_ids)
+              , CpuTopology { smt_per_core: 2
+                            , cores_per_package: 4
+                            , num_packages: 2 });
+    let siblings = levels.siblings(&apic_ids);
+    assert_eq!(siblings[0], [2]);
+    assert!(siblings[1].is_empty());
+    assert_eq!(siblings[2], [0]);
+    assert!(siblings[3].is_empty() && siblings[4].is_empty());
+
+    // leaf 1 with HTT and 6 logical processors: 3 bits of core ID.
+    let leaf_1 = CpuidResult { eax: 0, ebx: 6 << 16, ecx: 0, edx: 1 << 28 };
+    let levels = Levels::from_leaf_1(leaf_1);
+    assert_eq!((levels.smt_per_core, levels.core_shift), (1, 3));
+
+    let cpus = topology::topology().expect("topology not worked out");
+    assert!(cpus.smt_per_core >= 1 && cpus.num_packages >= 1);
+    assert!(!topology::sibling_cpus(0).contains(&0));
+    assert_eq!(topology::build_from_cpuid().smt_per_core, cpus.smt_per_core);
+}
+
+fn tlb_shootdown_broadcast() {
+    // the heap doesn't grow this far, so this flushes nothing that matters,
+    // but every CPU online still has to answer.
+    let addr = VAddr::from(heap::GROWTH_END - PAGE_SIZE as usize);
+    for _ in 0 .. smp::cpus_online() * 4 {
+        tlb_shootdown::broadcast(addr);
+    }
+    let cpu = unsafe { percpu::current() };
+    assert!(!cpu.in_shootdown);
+}
+
+// `smap_probe(addr)` reads the byte at `addr` without `stac`, returning 0
+// if it could and 1 if that faulted.
+global_asm!("
+    .intel_syntax noprefix
+    .global smap_probe
+smap_probe:
+    xor     eax, eax
+smap_probe_insn:
+    mov     cl, [rdi]
+smap_probe_insn_end:
+    ret
+smap_probe_fixup:
+    mov     eax, 1
+    ret
+
+    .pushsection __ex_table, \"a\"
+    .balign 8
+    .quad   smap_probe_insn, smap_probe_insn_end
+    .quad   smap_probe_fixup
+    .popsection
+    .att_syntax
+");
+
+extern { fn smap_probe(addr: *const u8) -> usize; }
+
+fn smap_user_access() {
+    use cpu::control_regs::cr4;
+    // `make test-qemu` gives QEMU a CPU with SMAP.
+    assert!(cr4::read().contains(cr4::SMAP), "SMAP isn't enabled!");
+    // without an IDT, the fault below would triple-fault rather than reach
+    // its fixup.
+    assert!( phase::reached(KernelPhase::InterruptInit)
+           , "no IDT is loaded to take the page fault!");
+    let addr = VAddr::from(MMAP_BASE);
+    let page = VirtualPage::containing(addr);
+    map_user_page(page, VM_READ | VM_WRITE).expect("couldn't map page");
+    unsafe {
+        // touching a mapped user page faults without `stac`...
+        assert_eq!(smap_probe(addr.as_ptr()), 1);
+        // ...and doesn't with it.
+        let mut byte = 0xffu8;
+        assert_eq!(extable::copy_user(&mut byte, addr.as_ptr(), 1), 0);
+        assert_eq!(byte, 0);
+    }
+    unmap_user_pages(addr, VAddr::from(MMAP_BASE + PAGE_SIZE as usize));
+}
+
+fn frame_cow_refcount() {
+    let addr = VAddr::from(MMAP_BASE);
+    let page = VirtualPage::containing(addr);
+    let frame = map_user_page(page, VM_READ | VM_WRITE)
+        .expect("couldn't map page");
+    assert_eq!(frame::frame_refcount(frame), 1);
+    let before = frame::stats().used;
+
+    // share the page copy-on-write with a new address space, as fork does.
+    let mut frames = frame::allocator();
+    let clone = space::new_address_space(&mut frames)
+        .expect("couldn't create address space");
+    unsafe {
+        let current = task::current().page_table;
+        space::clone_user_address_space(
+            table_at(current), table_at(clone.frame())
+          , &mut frames, &mut frame::frame_inc_ref, &mut swap::dup_slot)
+            .expect("couldn't clone address space");
+        tlb::flush_all();
+    }
+    assert_eq!(frame::frame_refcount(frame), 2);
+    let used = frame::stats().used;
+
+    // the first drop leaves the frame to the clone...
+    unmap_user_pages(addr, VAddr::from(MMAP_BASE + PAGE_SIZE as usize));
+    assert_eq!(frame::frame_refcount(frame), 1);
+    assert_eq!(frame::stats().used, used);
+
+    // ...and the second frees it, along with the clone's tables.
+    unsafe {
+        space::free_address_space( clone.frame(), &mut frames
+                                 , &mut swap::free_slot);
+    }
+    assert_eq!(frame::stats().used, before - 1);
+}
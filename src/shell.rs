@@ -0,0 +1,214 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A debug shell on the serial port.
+//!
+//! The shell runs as a kernel thread, reading commands from `COM1` a line
+//! at a time. It only pokes at kernel data structures directly, so it works
+//! without a file system or any user space.
+//!
+//! Scheduling isn't preemptive yet, so the shell yields the CPU whenever
+//! it's waiting for input.
+use core::fmt::{self, Write};
+use core::str;
+use cpu::interrupts::idt::Idt;
+use memory::PAGE_SIZE;
+
+use arch::{acpi, bda, reset};
+use arch::cpu::{hlt, read_rbp};
+use arch::drivers::serial::SerialPort;
+use heap;
+use mm::frame;
+use task::{self, sched, TaskState};
+#[cfg(feature = "kernel-trace")] use trace;
+
+/// The longest line the shell will read.
+const LINE_MAX: usize = 128;
+
+/// The deepest a backtrace will go.
+const BACKTRACE_MAX: usize = 32;
+
+/// A shell command.
+struct Command { name: &'static str
+               , help: &'static str
+               , run: fn(&mut SerialPort) -> fmt::Result
+               }
+
+static COMMANDS: [Command; 7]
+    = [ Command { name: "help", help: "list commands", run: help }
+      , Command { name: "mem", help: "show physical memory and heap usage"
+                , run: mem }
+      , Command { name: "tasks", help: "list every task", run: tasks }
+      , Command { name: "reboot", help: "reset the machine", run: reboot }
+      , Command { name: "halt", help: "turn the machine off", run: halt }
+      , Command { name: "log-dump", help: "print the kernel event log"
+                , run: log_dump }
+      , Command { name: "backtrace", help: "walk the shell's stack frames"
+                , run: backtrace }
+      ];
+
+/// The serial port debug shell.
+pub struct Shell;
+
+impl Shell {
+    /// Read and run commands from `serial` forever.
+    pub fn run(serial: &mut SerialPort) -> ! {
+        let mut line = [0u8; LINE_MAX];
+        let _ = write!(serial, "\r\nSOS debug shell. Type `help` for a list \
+                                of commands.\r\n");
+        loop {
+            let _ = serial.write_str("sos> ");
+            let len = read_line(serial, &mut line);
+            let mut words = line[..len].split(|&b| b == b' ')
+                                       .filter(|word| !word.is_empty());
+            let name = match words.next() {
+                Some(name) => name
+              , None => continue
+            };
+            let result = match COMMANDS.iter()
+                                       .find(|c| c.name.as_bytes() == name) {
+                Some(command) => (command.run)(serial)
+              , None => write!( serial, "unknown command: {}\r\n"
+                              , str::from_utf8(name).unwrap_or("?"))
+            };
+            // the only thing that can fail is writing to the port, and
+            // there's nowhere else to report that.
+            let _ = result;
+        }
+    }
+}
+
+/// Start the shell as a kernel thread on `COM1`, if there is one.
+pub fn spawn() -> Option<task::Pid> {
+    bda::ports::com1().map(|_| task::spawn_kernel("shell", shell_thread))
+}
+
+extern "C" fn shell_thread() -> ! {
+    match bda::ports::com1() {
+        Some(port) => Shell::run(&mut SerialPort::new(port))
+      , None => task::exit(1)
+    }
+}
+
+/// Read a line from `serial` into `buf`, echoing it back and handling
+/// backspace. Returns the length of the line, without the line ending.
+///
+/// Characters past the end of `buf` are dropped.
+fn read_line(serial: &mut SerialPort, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        while !serial.has_byte() { sched::schedule() }
+        match serial.read_byte() {
+            b'\r' | b'\n' => {
+                let _ = serial.write_str("\r\n");
+                return len
+            }
+          , 0x08 | 0x7f => if len > 0 {
+                len -= 1;
+                let _ = serial.write_str("\x08 \x08");
+            }
+          , byte @ 0x20 ... 0x7e => if len < buf.len() {
+                buf[len] = byte;
+                len += 1;
+                serial.write_byte(byte);
+            }
+          , _ => {}
+        }
+    }
+}
+
+fn help(serial: &mut SerialPort) -> fmt::Result {
+    for command in COMMANDS.iter() {
+        write!(serial, "  {:<10} {}\r\n", command.name, command.help)?;
+    }
+    Ok(())
+}
+
+fn mem(serial: &mut SerialPort) -> fmt::Result {
+    let stats = frame::stats();
+    write!( serial, "{} of {} frames free ({} KiB of {} KiB)\r\n"
+          , stats.free(), stats.total
+          , stats.free() * PAGE_SIZE as usize / 1024
+          , stats.total * PAGE_SIZE as usize / 1024)?;
+    let heap = heap::stats();
+    write!( serial, "heap: {} KiB used, {} KiB free, {} KiB at peak\r\n"
+          , heap.allocated_bytes / 1024, heap.free_bytes / 1024
+          , heap.peak_allocated / 1024)?;
+    write!( serial, "heap: {} allocations, {} frees\r\n"
+          , heap.alloc_calls, heap.free_calls)
+}
+
+fn tasks(serial: &mut SerialPort) -> fmt::Result {
+    write!(serial, "  PID STATE NAME\r\n")?;
+    let mut result = Ok(());
+    task::for_each(|task| {
+        let state = match task.state {
+            TaskState::Runnable => 'R'
+          , TaskState::Blocked => 'S'
+          , TaskState::Zombie => 'Z'
+        };
+        result = result.and_then(|_|
+            write!( serial, "{:>5} {:>5} {}\r\n"
+                  , task.pid, state, task.name));
+    });
+    result
+}
+
+fn reboot(serial: &mut SerialPort) -> fmt::Result {
+    serial.write_str("rebooting...\r\n")?;
+    reset::keyboard_controller();
+    reset::triple_fault()
+}
+
+/// Turn the machine off, or if that doesn't work, stop the CPU.
+fn halt(serial: &mut SerialPort) -> fmt::Result {
+    serial.write_str("powering off...\r\n")?;
+    let why = acpi::shutdown();
+    write!(serial, "could not power off ({}), halting.\r\n", why)?;
+    unsafe { Idt::disable_interrupts() }
+    loop {
+        unsafe { hlt() }
+    }
+}
+
+#[cfg(feature = "kernel-trace")]
+fn log_dump(serial: &mut SerialPort) -> fmt::Result {
+    let mut count = 0;
+    trace::for_each_event(|event| {
+        count += 1;
+        write!(serial, "{}\r\n", event)
+    })?;
+    write!(serial, "{} event(s).\r\n", count)
+}
+
+#[cfg(not(feature = "kernel-trace"))]
+fn log_dump(serial: &mut SerialPort) -> fmt::Result {
+    serial.write_str("kernel tracing is off; build with `kernel-trace`.\r\n")
+}
+
+/// Print the return address of each frame on the stack, by following the
+/// chain of saved frame pointers.
+///
+/// The kernel is built with frame pointers, and a kernel thread's first
+/// frame has a null one, so the chain ends there.
+fn backtrace(serial: &mut SerialPort) -> fmt::Result {
+    let mut rbp = unsafe { read_rbp() };
+    for depth in 0 .. BACKTRACE_MAX {
+        if rbp == 0 || rbp % 8 != 0 { break }
+        let (next, ret) = unsafe {
+            (*(rbp as *const usize), *((rbp + 8) as *const usize))
+        };
+        if ret == 0 { break }
+        write!(serial, "  #{:<2} {:#018x}\r\n", depth, ret)?;
+        // frames further up the stack are at higher addresses, so anything
+        // else means the chain is broken.
+        if next <= rbp { break }
+        rbp = next;
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! File system calls.
+use core::{cmp, mem};
+use memory::VAddr;
+
+use fs::fd::{Fd, O_CLOEXEC, O_RDONLY, O_RDWR, O_WRONLY};
+use fs::pipe;
+use fs::tmpfs::Tmpfs;
+use mm::user::{ copy_from_user, copy_to_user, copy_user_cstr
+              , validate_user_write, CStrError };
+use task;
+
+use super::errno::{EBADF, EFAULT, EINVAL, EMFILE};
+use super::io_errno;
+
+/// Close the file on `execve`, as `O_CLOEXEC` does.
+pub const MFD_CLOEXEC: u64 = 0x1;
+/// Allow seals to be added to the file.
+pub const MFD_ALLOW_SEALING: u64 = 0x2;
+
+/// The longest name `memfd_create(2)` takes, not counting the NUL. Linux
+/// allows 255 bytes less the `"memfd:"` prefix it adds.
+pub const MFD_NAME_MAX: usize = 249;
+
+/// Convert a system call argument to a file descriptor.
+#[inline]
+fn fd_arg(fd: u64) -> Option<Fd> {
+    if fd <= u32::max_value() as u64 { Some(Fd(fd as u32)) } else { None }
+}
+
+/// The most `read(2)` or `write(2)` moves through the kernel at a time.
+///
+/// User buffers are copied through a kernel buffer of this size, as the
+/// file system can't touch user memory itself.
+const RW_CHUNK: usize = 64 * 1024;
+
+/// `write(2)`: write `count` bytes from `buf_addr` to the file `fd`.
+pub fn sys_write(fd: u64, buf_addr: u64, count: u64) -> i64 {
+    let task = unsafe { task::current() };
+    let file = match fd_arg(fd).and_then(|fd| task.files.get_mut(fd)) {
+        Some(file) if file.flags.is_writable() => file
+      , _ => return -EBADF
+    };
+    let count = count as usize;
+    let mut buf = vec![0u8; cmp::min(count, RW_CHUNK)];
+    let mut written = 0;
+    while written < count {
+        let len = cmp::min(count - written, buf.len());
+        let src = VAddr::from(buf_addr as usize + written);
+        if unsafe { copy_from_user(buf.as_mut_ptr(), src, len) }.is_err() {
+            return if written > 0 { written as i64 } else { -EFAULT }
+        }
+        match file.inode.write_at(file.position, &buf[..len]) {
+            Ok(n) => {
+                file.position += n as u64;
+                written += n;
+                if n < len { break }
+            }
+          , Err(_) if written > 0 => break
+          , Err(err) => return io_errno(err)
+        }
+    }
+    written as i64
+}
+
+/// `read(2)`: read up to `count` bytes from the file `fd` into `buf_addr`.
+///
+/// At most [`RW_CHUNK`](constant.RW_CHUNK.html) bytes are read at once.
+pub fn sys_read(fd: u64, buf_addr: u64, count: u64) -> i64 {
+    let task = unsafe { task::current() };
+    let file = match fd_arg(fd).and_then(|fd| task.files.get_mut(fd)) {
+        Some(file) if file.flags.is_readable() => file
+      , _ => return -EBADF
+    };
+    // check the buffer before reading, so that nothing is consumed from a
+    // pipe if it's bad.
+    let len = cmp::min(count as usize, RW_CHUNK);
+    let dst = VAddr::from(buf_addr as usize);
+    if validate_user_write(dst, len).is_err() {
+        return -EFAULT
+    }
+    let mut buf = vec![0u8; len];
+    let n = match file.inode.read_at(file.position, &mut buf) {
+        Ok(n) => n
+      , Err(err) => return io_errno(err)
+    };
+    if unsafe { copy_to_user(dst, buf.as_ptr(), n) }.is_err() {
+        return -EFAULT
+    }
+    file.position += n as u64;
+    n as i64
+}
+
+/// `ftruncate(2)`: set the size of the file `fd` to `length` bytes,
+/// zero-filling it if it grows.
+pub fn sys_ftruncate(fd: u64, length: u64) -> i64 {
+    let task = unsafe { task::current() };
+    let file = match fd_arg(fd).and_then(|fd| task.files.get(fd)) {
+        Some(file) => file
+      , None => return -EBADF
+    };
+    // like Linux, a file that isn't open for writing is an invalid
+    // argument rather than a bad descriptor.
+    if !file.flags.is_writable() || (length as i64) < 0 { return -EINVAL }
+    match file.inode.truncate(length) {
+        Ok(()) => 0
+      , Err(err) => io_errno(err)
+    }
+}
+
+/// `memfd_create(2)`: create an empty, anonymous in-memory file, and return
+/// a file descriptor for it.
+///
+/// The file can be grown with `ftruncate` and mapped with `mmap`. It has
+/// no path, so it lives only as long as something refers to it. Its name
+/// is only checked; we've nowhere to show it yet. Sealing isn't supported,
+/// so `MFD_ALLOW_SEALING` is accepted and ignored, as a file nobody seals.
+pub fn sys_memfd_create(name_ptr: VAddr, flags: u64) -> i64 {
+    if flags & !(MFD_CLOEXEC | MFD_ALLOW_SEALING) != 0 { return -EINVAL }
+    match copy_user_cstr(name_ptr, MFD_NAME_MAX + 1) {
+        Ok(_) => {}
+      , Err(CStrError::Fault) => return -EFAULT
+      , Err(CStrError::TooLong) => return -EINVAL
+    }
+    let mut open_flags = O_RDWR;
+    if flags & MFD_CLOEXEC != 0 { open_flags.insert(O_CLOEXEC) }
+    let files = unsafe { &mut task::current().files };
+    match files.open(Tmpfs::unlinked_file(), open_flags) {
+        Ok(fd) => fd.0 as i64
+      , Err(_) => -EMFILE
+    }
+}
+
+/// `pipe(2)`: create a pipe, storing the file descriptors for its read and
+/// write ends in the two `i32`s at `pipefd_addr`.
+pub fn sys_pipe(pipefd_addr: u64) -> i64 {
+    let addr = VAddr::from(pipefd_addr as usize);
+    let files = unsafe { &mut task::current().files };
+    let (reader, writer) = pipe::new();
+    let read_fd = match files.open(reader, O_RDONLY) {
+        Ok(fd) => fd
+      , Err(_) => return -EMFILE
+    };
+    let write_fd = match files.open(writer, O_WRONLY) {
+        Ok(fd) => fd
+      , Err(_) => {
+            let _ = files.close(read_fd);
+            return -EMFILE
+        }
+    };
+    let fds = [read_fd.0 as i32, write_fd.0 as i32];
+    let copied = unsafe {
+        copy_to_user( addr, fds.as_ptr() as *const u8
+                    , mem::size_of_val(&fds))
+    };
+    if copied.is_err() {
+        let _ = files.close(read_fd);
+        let _ = files.close(write_fd);
+        return -EFAULT
+    }
+    0
+}
@@ -0,0 +1,90 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The `futex` system call.
+use core::mem;
+use memory::{PAddr, VAddr};
+use paging::Mapper;
+use paging::arch::ActivePML4;
+
+use mm::user::copy_from_user;
+use task::futex::{self, FUTEX_BITSET_MATCH_ANY};
+use task::timer;
+
+use super::errno::{EAGAIN, EFAULT, EINVAL, ENOSYS, ETIMEDOUT};
+use super::time::read_timespec;
+
+pub const FUTEX_WAIT: u64 = 0;
+pub const FUTEX_WAKE: u64 = 1;
+pub const FUTEX_WAIT_BITSET: u64 = 9;
+pub const FUTEX_WAKE_BITSET: u64 = 10;
+
+/// Set on futexes that aren't shared between processes. Every futex is
+/// keyed by physical address, so it makes no difference here.
+pub const FUTEX_PRIVATE_FLAG: u64 = 128;
+/// Set if a `FUTEX_WAIT_BITSET` timeout is measured by the real time clock.
+pub const FUTEX_CLOCK_REALTIME: u64 = 256;
+
+/// Read the futex word at `uaddr`, and return its value and its physical
+/// address.
+fn read_futex(uaddr: VAddr) -> Result<(u32, PAddr), i64> {
+    if uaddr.as_usize() % mem::size_of::<u32>() != 0 { return Err(-EINVAL) }
+    let mut value = 0u32;
+    unsafe {
+        copy_from_user( &mut value as *mut u32 as *mut u8, uaddr
+                      , mem::size_of::<u32>())
+            .map_err(|_| -EFAULT)?;
+    }
+    // the read faulted the page in, if it wasn't already.
+    let key = unsafe { ActivePML4::new() }.translate(uaddr)
+                                          .ok_or(-EFAULT)?;
+    Ok((value, key))
+}
+
+/// `futex(2)`: wait on or wake up the futex at `uaddr`.
+///
+/// Only `FUTEX_WAIT`, `FUTEX_WAKE`, and their `_BITSET` variants are
+/// supported. A `FUTEX_WAIT` timeout is relative, and a `FUTEX_WAIT_BITSET`
+/// one is an absolute time since boot: there's no real time clock, so
+/// `FUTEX_CLOCK_REALTIME` makes no difference.
+pub fn sys_futex( uaddr: u64, op: u64, val: u64, timeout: u64
+                , _uaddr2: u64, val3: u64)
+                -> i64 {
+    let uaddr = VAddr::from(uaddr as usize);
+    let cmd = op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME);
+    let bitset = match cmd {
+        FUTEX_WAIT | FUTEX_WAKE => FUTEX_BITSET_MATCH_ANY
+      , FUTEX_WAIT_BITSET | FUTEX_WAKE_BITSET => val3 as u32
+      , _ => return -ENOSYS
+    };
+    if bitset == 0 { return -EINVAL }
+
+    match cmd {
+        FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+            let deadline = match timeout {
+                0 => None
+              , addr => match read_timespec(VAddr::from(addr as usize)) {
+                    Ok(ns) if cmd == FUTEX_WAIT =>
+                        Some(timer::now_ns().saturating_add(ns))
+                  , Ok(ns) => Some(ns)
+                  , Err(errno) => return errno
+                }
+            };
+            let (value, key) = match read_futex(uaddr) {
+                Ok(futex) => futex
+              , Err(errno) => return errno
+            };
+            if value != val as u32 { return -EAGAIN }
+            if futex::wait(key, bitset, deadline) { 0 } else { -ETIMEDOUT }
+        }
+      , _ => match read_futex(uaddr) {
+            Ok((_, key)) => futex::wake(key, val as u32 as usize, bitset) as i64
+          , Err(errno) => errno
+        }
+    }
+}
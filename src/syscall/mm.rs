@@ -0,0 +1,152 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Memory management system calls.
+use memory::{Addr, PAGE_SIZE, VAddr};
+
+use fs::fd::Fd;
+use mm::{is_user_range, unmap_user_pages};
+use mm::vm::{VmBacking, VmFlags, VmRegion, VM_EXEC, VM_READ, VM_WRITE};
+use task;
+
+use super::errno::{EACCES, EBADF, EINVAL, ENOMEM};
+
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Convert `mmap` protection bits to region flags.
+fn prot_to_flags(prot: u64) -> VmFlags {
+    let mut flags = VmFlags::empty();
+    if prot & PROT_READ != 0 { flags.insert(VM_READ); }
+    if prot & PROT_WRITE != 0 { flags.insert(VM_WRITE); }
+    if prot & PROT_EXEC != 0 { flags.insert(VM_EXEC); }
+    flags
+}
+
+/// Round `len` up to a whole number of pages, or `None` on overflow.
+#[inline]
+fn page_round_up(len: u64) -> Option<u64> {
+    len.checked_add(PAGE_SIZE - 1).map(|len| len & !(PAGE_SIZE - 1))
+}
+
+/// `mmap(2)`: map anonymous memory, or a private copy of the file `fd`
+/// from `offset` on, into the current task's address space.
+///
+/// Only private mappings are supported, so writes to a file mapping aren't
+/// seen by the file or anyone else mapping it. No frames are allocated
+/// here; the pages are faulted in on first access.
+pub fn sys_mmap( addr: u64, length: u64, prot: u64, flags: u64
+               , fd: u64, offset: u64)
+               -> i64 {
+    if length == 0 { return -EINVAL }
+    if flags & MAP_PRIVATE == 0 || flags & MAP_SHARED != 0 {
+        return -EINVAL
+    }
+    let length = match page_round_up(length) {
+        Some(length) => length as usize
+      , None => return -ENOMEM
+    };
+
+    let task = unsafe { task::current() };
+    let backing = if flags & MAP_ANONYMOUS != 0 {
+        VmBacking::Anonymous
+    } else {
+        if offset & (PAGE_SIZE - 1) != 0 { return -EINVAL }
+        if fd > u32::max_value() as u64 { return -EBADF }
+        let file = match task.files.get(Fd(fd as u32)) {
+            Some(file) => file
+          , None => return -EBADF
+        };
+        if !file.flags.is_readable() { return -EACCES }
+        VmBacking::File { inode: file.inode.clone(), offset: offset }
+    };
+    let start = if flags & MAP_FIXED != 0 {
+        let addr = VAddr::from(addr as usize);
+        if !addr.is_page_aligned() || !is_user_range(addr, length) {
+            return -EINVAL
+        }
+        // like Linux, a fixed mapping replaces whatever was there before.
+        let end = VAddr::from(addr.as_usize() + length);
+        for (lo, hi) in task.vm.remove_range(addr, end) {
+            unmap_user_pages(lo, hi);
+        }
+        addr
+    } else {
+        match task.vm.find_free_region(length) {
+            Some(addr) => addr
+          , None => return -ENOMEM
+        }
+    };
+
+    let end = VAddr::from(start.as_usize() + length);
+    let region = VmRegion::new(start, end, prot_to_flags(prot))
+        .with_backing(backing);
+    match task.vm.insert(region) {
+        Ok(()) => start.as_usize() as i64
+      , Err(_) => -ENOMEM
+    }
+}
+
+/// `munmap(2)`: remove `[addr, addr + length)` from the current task's
+/// address space, freeing any frames backing it.
+pub fn sys_munmap(addr: u64, length: u64) -> i64 {
+    let addr = VAddr::from(addr as usize);
+    if length == 0 || !addr.is_page_aligned() { return -EINVAL }
+    let length = match page_round_up(length) {
+        Some(length) => length as usize
+      , None => return -EINVAL
+    };
+    if !is_user_range(addr, length) { return -EINVAL }
+
+    let task = unsafe { task::current() };
+    let end = VAddr::from(addr.as_usize() + length);
+    for (lo, hi) in task.vm.remove_range(addr, end) {
+        unmap_user_pages(lo, hi);
+    }
+    0
+}
+
+/// `brk(2)`: set the end of the current task's heap to `addr`.
+///
+/// Like Linux, this returns the new break on success and the old break on
+/// failure, so `brk(0)` queries the current break. The heap is made of
+/// whole pages, so the break itself needn't be page-aligned. Growing the
+/// heap only extends the task's regions; frames are allocated when the new
+/// pages are first touched.
+pub fn sys_brk(addr: u64) -> i64 {
+    let task = unsafe { task::current() };
+    let old = task.brk;
+    let new = VAddr::from(addr as usize);
+    if new < task.brk_start { return old.as_usize() as i64 }
+
+    let (old_end, new_end) = match ( page_round_up(old.as_usize() as u64)
+                                   , page_round_up(addr) ) {
+        (Some(old_end), Some(new_end)) =>
+            (VAddr::from(old_end as usize), VAddr::from(new_end as usize))
+      , _ => return old.as_usize() as i64
+    };
+    if new_end > old_end {
+        let len = new_end.as_usize() - old_end.as_usize();
+        let heap = VmRegion::new(old_end, new_end, VM_READ | VM_WRITE);
+        if !is_user_range(old_end, len) || task.vm.insert(heap).is_err() {
+            return old.as_usize() as i64
+        }
+    } else if new_end < old_end {
+        for (lo, hi) in task.vm.remove_range(new_end, old_end) {
+            unmap_user_pages(lo, hi);
+        }
+    }
+    task.brk = new;
+    new.as_usize() as i64
+}
@@ -0,0 +1,148 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! System calls.
+//!
+//! System call numbers and error numbers are the same as Linux's on
+//! `x86_64`, so that simple statically-linked Linux programs have a chance
+//! of working. Handlers return a non-negative value on success, or a
+//! negated `errno` on failure.
+pub mod errno;
+pub mod fs;
+pub mod futex;
+pub mod mm;
+pub mod process;
+pub mod seccomp;
+pub mod signal;
+pub mod time;
+
+use ::fs::IoError;
+use arch::syscall::current_frame;
+use memory::VAddr;
+use perf::{self, PerfEvent};
+use task;
+use task::signal::deliver_pending;
+use trace::{self, trace_event};
+
+/// The number of entries in the system call table.
+pub const SYSCALL_MAX: usize = 512;
+
+/// A system call handler.
+pub type SyscallFn = fn(u64, u64, u64, u64, u64, u64) -> i64;
+
+/// System call numbers.
+pub mod nr {
+    pub const SYS_READ: usize = 0;
+    pub const SYS_WRITE: usize = 1;
+    pub const SYS_MMAP: usize = 9;
+    pub const SYS_MUNMAP: usize = 11;
+    pub const SYS_BRK: usize = 12;
+    pub const SYS_RT_SIGACTION: usize = 13;
+    pub const SYS_RT_SIGRETURN: usize = 15;
+    pub const SYS_PIPE: usize = 22;
+    pub const SYS_NANOSLEEP: usize = 35;
+    pub const SYS_FORK: usize = 57;
+    pub const SYS_EXECVE: usize = 59;
+    pub const SYS_EXIT: usize = 60;
+    pub const SYS_WAIT4: usize = 61;
+    pub const SYS_KILL: usize = 62;
+    pub const SYS_FTRUNCATE: usize = 77;
+    pub const SYS_GETRUSAGE: usize = 98;
+    pub const SYS_FUTEX: usize = 202;
+    pub const SYS_EXIT_GROUP: usize = 231;
+    pub const SYS_SECCOMP: usize = 317;
+    pub const SYS_MEMFD_CREATE: usize = 319;
+}
+
+/// Convert an `IoError` to a negated `errno`.
+pub fn io_errno(err: IoError) -> i64 {
+    use self::errno::*;
+    -match err {
+        IoError::NotFound => ENOENT
+      , IoError::NotADirectory => ENOTDIR
+      , IoError::IsADirectory => EISDIR
+      , IoError::AlreadyExists => EEXIST
+      , IoError::InvalidArgument => EINVAL
+      , IoError::NoSpace => ENOSPC
+      , IoError::Unsupported => EINVAL
+      , IoError::Device => EIO
+      , IoError::BrokenPipe => EPIPE
+      , IoError::ReadOnly => EROFS
+      , IoError::PermissionDenied => EACCES
+      , IoError::FileTooBig => EFBIG
+    }
+}
+
+lazy_static! {
+    /// The system call table, indexed by system call number.
+    static ref SYSCALL_TABLE: [Option<SyscallFn>; SYSCALL_MAX] = {
+        use self::nr::*;
+        let mut table: [Option<SyscallFn>; SYSCALL_MAX] = [None; SYSCALL_MAX];
+        table[SYS_READ] = Some(|a, b, c, _, _, _| fs::sys_read(a, b, c));
+        table[SYS_WRITE] = Some(|a, b, c, _, _, _| fs::sys_write(a, b, c));
+        table[SYS_MMAP] = Some(mm::sys_mmap);
+        table[SYS_MUNMAP] = Some(|a, b, _, _, _, _| mm::sys_munmap(a, b));
+        table[SYS_BRK] = Some(|a, _, _, _, _, _| mm::sys_brk(a));
+        table[SYS_RT_SIGACTION]
+            = Some(|a, b, c, d, _, _| signal::sys_rt_sigaction(a, b, c, d));
+        table[SYS_RT_SIGRETURN]
+            = Some(|_, _, _, _, _, _| signal::sys_rt_sigreturn());
+        table[SYS_PIPE] = Some(|a, _, _, _, _, _| fs::sys_pipe(a));
+        table[SYS_NANOSLEEP]
+            = Some(|a, b, _, _, _, _| time::sys_nanosleep(a, b));
+        table[SYS_FORK] = Some(|_, _, _, _, _, _| process::sys_fork());
+        table[SYS_EXECVE]
+            = Some(|a, b, c, _, _, _| process::sys_execve(a, b, c));
+        table[SYS_EXIT] = Some(|a, _, _, _, _, _| process::sys_exit(a));
+        // there's only ever one thread in a group
+        table[SYS_EXIT_GROUP] = Some(|a, _, _, _, _, _| process::sys_exit(a));
+        table[SYS_WAIT4]
+            = Some(|a, b, _, _, _, _| process::sys_wait(a as i64, b));
+        table[SYS_KILL]
+            = Some(|a, b, _, _, _, _| signal::sys_kill(a as i64, b));
+        table[SYS_FTRUNCATE]
+            = Some(|a, b, _, _, _, _| fs::sys_ftruncate(a, b));
+        table[SYS_GETRUSAGE] = Some(|a, b, _, _, _, _| {
+            process::sys_getrusage(a as i64, VAddr::from(b as usize))
+        });
+        table[SYS_FUTEX] = Some(futex::sys_futex);
+        table[SYS_SECCOMP] = Some(|a, b, _, _, _, _| {
+            seccomp::sys_seccomp(a as u32, VAddr::from(b as usize))
+        });
+        table[SYS_MEMFD_CREATE] = Some(|a, b, _, _, _, _| {
+            fs::sys_memfd_create(VAddr::from(a as usize), b)
+        });
+        table
+    };
+}
+
+/// Called by the architecture-specific system call entry point.
+#[no_mangle]
+pub extern "C" fn dispatch_syscall( nr: u64
+                                  , a: u64, b: u64, c: u64
+                                  , d: u64, e: u64, f: u64)
+                                  -> i64 {
+    trace_event(trace::SYSCALL_ENTER, nr, a, b);
+    perf::increment(PerfEvent::Syscalls);
+    let allowed = match unsafe { task::current() }.syscall_filter {
+        Some(ref filter) => filter.allows(nr as usize)
+      , None => true
+    };
+    let result = match SYSCALL_TABLE.get(nr as usize) {
+        _ if !allowed => -errno::EPERM
+      , Some(&Some(handler)) => handler(a, b, c, d, e, f)
+      , _ => {
+            debug!("unimplemented system call {}", nr);
+            -errno::ENOSYS
+        }
+    };
+    trace_event(trace::SYSCALL_EXIT, nr, result as u64, 0);
+    // this may change where we return to, but not the result.
+    deliver_pending(unsafe { current_frame() }, result);
+    result
+}
@@ -0,0 +1,233 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Process management system calls.
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::Ordering;
+use memory::VAddr;
+
+use arch::syscall::{current_frame, SyscallFrame};
+use fs::{self, PATH_MAX};
+use mm::user::{ copy_to_user, copy_user_cstr, read_user_u64, write_user_i32
+              , CStrError};
+use perf::PerfEvent;
+use task::{self, sched, ChildStatus, Pid};
+use task::elf64::ExecError;
+use task::exec::{self, ARG_MAX};
+
+use super::errno::{ E2BIG, EACCES, ECHILD, EFAULT, EINVAL, ENAMETOOLONG
+                  , ENOEXEC, ENOMEM};
+use super::io_errno;
+use super::time::Timeval;
+
+/// `getrusage` of the calling task.
+pub const RUSAGE_SELF: i64 = 0;
+/// `getrusage` of the calling task's children that have been reaped.
+pub const RUSAGE_CHILDREN: i64 = -1;
+/// `getrusage` of the calling thread, which is the same as the task.
+pub const RUSAGE_THREAD: i64 = 1;
+
+/// A `struct rusage`.
+///
+/// The fields we don't keep track of are always 0.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Rusage { /// User CPU time used
+                    pub ru_utime: Timeval
+                  , /// System CPU time used
+                    pub ru_stime: Timeval
+                  , /// Maximum resident set size, in kilobytes
+                    pub ru_maxrss: u64
+                  , pub ru_ixrss: u64
+                  , pub ru_idrss: u64
+                  , pub ru_isrss: u64
+                  , /// Page faults resolved without I/O
+                    pub ru_minflt: u64
+                  , /// Page faults that needed I/O
+                    pub ru_majflt: u64
+                  , pub ru_nswap: u64
+                  , pub ru_inblock: u64
+                  , pub ru_oublock: u64
+                  , pub ru_msgsnd: u64
+                  , pub ru_msgrcv: u64
+                  , pub ru_nsignals: u64
+                  , /// Voluntary context switches
+                    pub ru_nvcsw: u64
+                  , /// Involuntary context switches
+                    pub ru_nivcsw: u64
+                  }
+
+/// `fork(2)`: create a copy of the current task.
+///
+/// Returns the child's PID in the parent; the child returns 0.
+pub fn sys_fork() -> i64 {
+    let parent = unsafe { task::current() };
+    let frame = unsafe { current_frame() };
+    match parent.fork(task::alloc_pid(), frame) {
+        Ok(child) => task::spawn(child).0 as i64
+      , Err(_) => -ENOMEM
+    }
+}
+
+/// Copy a NULL-terminated array of string pointers (such as `argv`) from
+/// user space.
+fn copy_user_strings(mut addr: VAddr) -> Result<Vec<Vec<u8>>, i64> {
+    let mut strings = Vec::new();
+    if addr.as_usize() == 0 { return Ok(strings) }
+    let mut total = 0;
+    loop {
+        let ptr = read_user_u64(addr).map_err(|_| -EFAULT)?;
+        if ptr == 0 { return Ok(strings) }
+        let string = copy_user_cstr(VAddr::from(ptr as usize), ARG_MAX)
+            .map_err(|err| match err { CStrError::Fault => -EFAULT
+                                     , CStrError::TooLong => -E2BIG })?;
+        total += string.len() + 1;
+        if total > ARG_MAX { return Err(-E2BIG) }
+        strings.push(string);
+        addr = VAddr::from(addr.as_usize() + 8);
+    }
+}
+
+/// `execve(2)`: replace the current program with the executable at
+/// `path_ptr`.
+///
+/// On success, this "returns" to the new program's entry point.
+pub fn sys_execve(path_ptr: u64, argv_ptr: u64, envp_ptr: u64) -> i64 {
+    let path = match copy_user_cstr(VAddr::from(path_ptr as usize), PATH_MAX) {
+        Ok(path) => path
+      , Err(CStrError::Fault) => return -EFAULT
+      , Err(CStrError::TooLong) => return -ENAMETOOLONG
+    };
+    let argv = match copy_user_strings(VAddr::from(argv_ptr as usize)) {
+        Ok(argv) => argv
+      , Err(errno) => return errno
+    };
+    let envp = match copy_user_strings(VAddr::from(envp_ptr as usize)) {
+        Ok(envp) => envp
+      , Err(errno) => return errno
+    };
+    if exec::args_size(&argv, &envp) > ARG_MAX { return -E2BIG }
+
+    // read the whole executable into the kernel
+    let inode = match fs::lookup(&path) {
+        Ok(inode) => inode
+      , Err(err) => return io_errno(err)
+    };
+    let stat = inode.stat();
+    if !stat.is_file() { return -EACCES }
+    let mut image = vec![0u8; stat.size as usize];
+    let mut read = 0;
+    while read < image.len() {
+        match inode.read_at(read as u64, &mut image[read..]) {
+            Ok(0) => break
+          , Ok(n) => read += n
+          , Err(err) => return io_errno(err)
+        }
+    }
+    image.truncate(read);
+
+    let task = unsafe { task::current() };
+    let name = match path.iter().rposition(|&b| b == b'/') {
+        Some(slash) => &path[slash + 1..]
+      , None => &path[..]
+    };
+    match unsafe { exec::exec(task, name, &image, &argv, &envp) } {
+        Ok((entry, sp)) => {
+            let frame = unsafe { current_frame() };
+            *frame = SyscallFrame { rip: entry.as_usize() as u64
+                                  , rsp: sp.as_usize() as u64
+                                  // interrupts enabled
+                                  , rflags: 0x202
+                                  , ..Default::default()
+                                  };
+            0
+        }
+      , Err(ExecError::Invalid(why)) => {
+            debug!("execve: {}", why);
+            -ENOEXEC
+        }
+      , Err(ExecError::UnknownRelocation(r_type)) => {
+            debug!("execve: unsupported relocation type {}", r_type);
+            -ENOEXEC
+        }
+      , Err(ExecError::NoMemory) => -ENOMEM
+    }
+}
+
+/// `exit(2)`: terminate the current task.
+pub fn sys_exit(code: u64) -> i64 {
+    task::exit((code & 0xff) as u8)
+}
+
+/// `wait4(2)`: wait for a child to exit.
+///
+/// `pid` is either -1, to wait for any child, or the PID of a specific
+/// child. The exit status is stored at `status_ptr`, if it isn't null, in
+/// the same format as Linux. Options and resource usage aren't supported.
+pub fn sys_wait(pid: i64, status_ptr: u64) -> i64 {
+    let which = match pid {
+        -1 => None
+      , pid if pid > 0 && pid <= u32::max_value() as i64 =>
+            Some(Pid(pid as u32))
+      , _ => return -EINVAL
+    };
+    let me = unsafe { task::current() };
+    loop {
+        match task::find_child(me.pid, which) {
+            ChildStatus::NoChildren => return -ECHILD
+          , ChildStatus::Running => me.child_wq.sleep()
+          , ChildStatus::Exited(child, code) => {
+                if status_ptr != 0 {
+                    let addr = VAddr::from(status_ptr as usize);
+                    // the exit code goes in bits 8-15, as for `WEXITSTATUS`
+                    if write_user_i32(addr, (code as i32) << 8).is_err() {
+                        return -EFAULT
+                    }
+                }
+                task::reap(child);
+                return child.0 as i64
+            }
+        }
+    }
+}
+
+/// `getrusage(2)`: write the resources used by `who` to `usage_ptr`.
+///
+/// Time spent in the kernel isn't told apart from time spent in user mode
+/// yet, so all of it is reported as user time. Nor is the peak size of an
+/// address space recorded, so `ru_maxrss` is its current size, and zero
+/// for the children.
+///
+/// Page faults and context switches are only counted with the `task-perf`
+/// feature, and not for the children. There's no preemption, so every
+/// context switch is voluntary.
+pub fn sys_getrusage(who: i64, usage_ptr: VAddr) -> i64 {
+    let me = unsafe { task::current() };
+    let usage = match who {
+        RUSAGE_SELF | RUSAGE_THREAD =>
+            Rusage { ru_utime: Timeval::from_ns(sched::cpu_time_ns(me))
+                   , ru_maxrss: (me.vm.total_mapped_bytes() / 1024) as u64
+                   , ru_minflt: me.perf.get(PerfEvent::MinorFaults)
+                   , ru_majflt: me.perf.get(PerfEvent::MajorFaults)
+                   , ru_nvcsw: me.perf.get(PerfEvent::ContextSwitches)
+                   , ..Default::default()
+                   }
+      , RUSAGE_CHILDREN => {
+            let ns = me.children_cpu_time_ns.load(Ordering::Relaxed);
+            Rusage { ru_utime: Timeval::from_ns(ns), ..Default::default() }
+        }
+      , _ => return -EINVAL
+    };
+    let copied = unsafe {
+        copy_to_user( usage_ptr, &usage as *const Rusage as *const u8
+                    , mem::size_of::<Rusage>())
+    };
+    if copied.is_err() { return -EFAULT }
+    0
+}
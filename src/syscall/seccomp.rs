@@ -0,0 +1,77 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Per-task system call filters.
+//!
+//! A task can give up the right to make system calls by installing a
+//! [`SyscallFilter`]: a bitmap with a bit set for each system call it may
+//! still make. Anything else fails with `EPERM` without being dispatched.
+//! Filters can only be narrowed, never removed, and are inherited by
+//! children and kept across `execve`, so a sandboxed program can't get out
+//! by starting another one.
+//!
+//! This takes the place of Linux's `seccomp` BPF programs, which is why it
+//! has that system call's number but not its arguments.
+//!
+//! [`SyscallFilter`]: struct.SyscallFilter.html
+use core::mem;
+use memory::VAddr;
+
+use mm::user::copy_from_user;
+use task;
+
+use super::SYSCALL_MAX;
+use super::errno::{EFAULT, EINVAL};
+
+/// `seccomp` mode: install a filter.
+pub const SECCOMP_SET_MODE_FILTER: u32 = 1;
+
+/// The set of system calls a task may make, one bit per system call
+/// number.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SyscallFilter(pub [u64; SYSCALL_MAX / 64]);
+
+impl SyscallFilter {
+    /// Returns true if system call `nr` is allowed.
+    #[inline]
+    pub fn allows(&self, nr: usize) -> bool {
+        nr < SYSCALL_MAX && self.0[nr / 64] & (1 << (nr % 64)) != 0
+    }
+
+    /// Returns a filter that only allows what both `self` and `other` do.
+    pub fn intersect(&self, other: &SyscallFilter) -> SyscallFilter {
+        let mut bits = self.0;
+        for (bits, other) in bits.iter_mut().zip(other.0.iter()) {
+            *bits &= *other;
+        }
+        SyscallFilter(bits)
+    }
+}
+
+/// `seccomp(2)`, after a fashion: with `mode` `SECCOMP_SET_MODE_FILTER`,
+/// read a `SyscallFilter` from `bitmap_ptr` and apply it to the current
+/// task.
+///
+/// If the task already has a filter, the new one is combined with it, so
+/// this can only take system calls away.
+pub fn sys_seccomp(mode: u32, bitmap_ptr: VAddr) -> i64 {
+    if mode != SECCOMP_SET_MODE_FILTER { return -EINVAL }
+    let mut filter = SyscallFilter([0; SYSCALL_MAX / 64]);
+    let copied = unsafe {
+        copy_from_user( &mut filter as *mut SyscallFilter as *mut u8
+                      , bitmap_ptr
+                      , mem::size_of::<SyscallFilter>())
+    };
+    if copied.is_err() { return -EFAULT }
+    let task = unsafe { task::current() };
+    task.syscall_filter = Some(match task.syscall_filter {
+        Some(old) => old.intersect(&filter)
+      , None => filter
+    });
+    0
+}
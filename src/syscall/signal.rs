@@ -0,0 +1,115 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Signal system calls.
+use core::mem;
+use memory::VAddr;
+
+use arch::syscall::current_frame;
+use mm::is_user_range;
+use mm::user::{copy_from_user, copy_to_user};
+use task::{self, Pid};
+use task::signal::{self, SIGKILL, SIGSTOP, SIG_DFL, SIG_IGN};
+
+use super::errno::{EFAULT, EINVAL, ESRCH};
+
+/// `sa_flags`: `sa_restorer` is set.
+const SA_RESTORER: u64 = 0x0400_0000;
+
+/// The kernel's `struct sigaction`, as Linux has it on `x86_64`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct SigAction { handler: u64
+                 , flags: u64
+                 , restorer: u64
+                 , /// Signals blocked while the handler runs. There's no
+                   /// masking, so this is ignored.
+                   mask: u64
+                 }
+
+/// `rt_sigaction(2)`: set the handler for signal `signum` to the one in
+/// the `struct sigaction` at `act`, if it isn't null, and store the old
+/// one at `oldact`, if that isn't null.
+///
+/// Handlers other than `SIG_DFL` and `SIG_IGN` must come with a restorer
+/// for them to return to, which the C library always provides.
+pub fn sys_rt_sigaction(signum: u64, act: u64, oldact: u64, sigsetsize: u64)
+                       -> i64 {
+    let signum = signum as usize;
+    if !signal::is_valid(signum) || sigsetsize != 8 { return -EINVAL }
+    let task = unsafe { task::current() };
+    let index = signum - 1;
+
+    let mut new = SigAction::default();
+    if act != 0 {
+        let copied = unsafe {
+            copy_from_user( &mut new as *mut SigAction as *mut u8
+                          , VAddr::from(act as usize)
+                          , mem::size_of::<SigAction>())
+        };
+        if copied.is_err() { return -EFAULT }
+        // SIGKILL and SIGSTOP can't be caught or ignored.
+        if signum == SIGKILL || signum == SIGSTOP { return -EINVAL }
+        let handler = new.handler as usize;
+        if handler != SIG_DFL && handler != SIG_IGN
+            && ( new.flags & SA_RESTORER == 0
+              || !is_user_range(VAddr::from(handler), 1)
+              || !is_user_range(VAddr::from(new.restorer as usize), 1)) {
+            return -EINVAL
+        }
+    }
+
+    if oldact != 0 {
+        let handler = task.signal_handlers[index].as_usize() as u64;
+        let restorer = task.signal_restorer.as_usize() as u64;
+        let old = SigAction { handler: handler
+                            , flags: if restorer != 0 { SA_RESTORER }
+                                     else { 0 }
+                            , restorer: restorer
+                            , mask: 0
+                            };
+        let copied = unsafe {
+            copy_to_user( VAddr::from(oldact as usize)
+                        , &old as *const SigAction as *const u8
+                        , mem::size_of::<SigAction>())
+        };
+        if copied.is_err() { return -EFAULT }
+    }
+
+    if act != 0 {
+        task.signal_handlers[index] = VAddr::from(new.handler as usize);
+        if new.flags & SA_RESTORER != 0 {
+            task.signal_restorer = VAddr::from(new.restorer as usize);
+        }
+    }
+    0
+}
+
+/// `rt_sigreturn(2)`: return from a signal handler to wherever the signal
+/// interrupted.
+pub fn sys_rt_sigreturn() -> i64 {
+    signal::sigreturn(unsafe { current_frame() })
+}
+
+/// `kill(2)`: send signal `signum` to the task `pid`.
+///
+/// Only single tasks can be signalled, not process groups. A signal number
+/// of 0 just checks that the task exists.
+pub fn sys_kill(pid: i64, signum: u64) -> i64 {
+    let signum = signum as usize;
+    if signum != 0 && !signal::is_valid(signum) { return -EINVAL }
+    if pid <= 0 || pid > u32::max_value() as i64 { return -EINVAL }
+    let target = match task::get(Pid(pid as u32)) {
+        Some(target) => target
+      , None => return -ESRCH
+    };
+    if signum != 0 {
+        signal::task_send_signal(unsafe { &*target }, signum);
+    }
+    0
+}
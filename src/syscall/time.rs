@@ -0,0 +1,59 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Time system calls.
+use memory::VAddr;
+
+use mm::user::read_user_u64;
+use task::timer;
+
+use super::errno::{EFAULT, EINVAL};
+
+/// The number of nanoseconds in a second.
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+/// The number of nanoseconds in a microsecond.
+pub const NSEC_PER_USEC: u64 = 1_000;
+
+/// A `struct timeval`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Timeval { pub tv_sec: i64
+                   , pub tv_usec: i64
+                   }
+
+impl Timeval {
+    /// Returns `ns` nanoseconds as a `Timeval`, rounded down to the
+    /// microsecond.
+    pub fn from_ns(ns: u64) -> Self {
+        Timeval { tv_sec: (ns / NSEC_PER_SEC) as i64
+                , tv_usec: ((ns % NSEC_PER_SEC) / NSEC_PER_USEC) as i64
+                }
+    }
+}
+
+/// Read a `struct timespec` from user memory at `addr`, and return it in
+/// nanoseconds.
+pub fn read_timespec(addr: VAddr) -> Result<u64, i64> {
+    let secs = read_user_u64(addr).map_err(|_| -EFAULT)?;
+    let nsecs = read_user_u64(VAddr::from(addr.as_usize() + 8))
+        .map_err(|_| -EFAULT)?;
+    if (secs as i64) < 0 || nsecs >= NSEC_PER_SEC { return Err(-EINVAL) }
+    Ok(secs.saturating_mul(NSEC_PER_SEC).saturating_add(nsecs))
+}
+
+/// `nanosleep(2)`: sleep for the time given by the `timespec` at `req`.
+///
+/// Sleeps are never interrupted, so the remaining time isn't written back.
+pub fn sys_nanosleep(req: u64, _rem: u64) -> i64 {
+    let duration = match read_timespec(VAddr::from(req as usize)) {
+        Ok(duration) => duration
+      , Err(errno) => return errno
+    };
+    timer::sleep_until(timer::now_ns().saturating_add(duration));
+    0
+}
@@ -0,0 +1,207 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Kernel parameters that can be read and changed at runtime.
+//!
+//! Each parameter is a number, with a path like `kernel/pid_max`, and a
+//! pair of functions to get and (optionally) set it, which are
+//! [`register`](fn.register.html)ed by whatever owns it. They show up as
+//! files under `/proc/sys`.
+//!
+//! The parameters are kept in a tree with a node for each path component;
+//! each node's children are on an intrusive list. Nothing is ever
+//! unregistered, so nodes are never freed.
+use alloc::boxed::Box;
+use spin::Mutex;
+use sos_intrusive::{List, NonNullOwned, RawLink};
+use sos_intrusive::list::Node;
+
+#[cfg(feature = "logging")] use logger;
+use mm;
+use task;
+
+/// Returns the current value of a parameter.
+pub type GetFn = fn() -> u64;
+/// Changes the value of a parameter.
+///
+/// Out of range values should be clamped, rather than ignored.
+pub type SetFn = fn(u64);
+
+/// What's at a path in the tree.
+#[derive(Copy, Clone)]
+pub enum Sysctl { /// A directory of parameters
+                  Dir
+                , /// A parameter, and whether it can be set
+                  Param(GetFn, Option<SetFn>)
+                }
+
+/// A node in the tree: a parameter, or a directory of them.
+struct SysctlNode { name: &'static str
+                  , /// The node's functions, if it is a parameter
+                    param: Option<(GetFn, Option<SetFn>)>
+                  , children: List<NonNullOwned<SysctlNode>, SysctlNode>
+                  , next: RawLink<SysctlNode>
+                  , prev: RawLink<SysctlNode>
+                  }
+
+impl SysctlNode {
+    fn new(name: &'static str) -> Self {
+        SysctlNode { name: name
+                   , param: None
+                   , children: List::new()
+                   , next: RawLink::none()
+                   , prev: RawLink::none()
+                   }
+    }
+
+    /// Returns the `index`th child of this node.
+    fn nth_child(&self, index: usize) -> Option<&SysctlNode> {
+        let mut child = self.children.front();
+        for _ in 0..index {
+            child = child.and_then(|node| unsafe { node.next.resolve() });
+        }
+        child
+    }
+
+    /// Returns this node's child called `name`.
+    fn child(&self, name: &str) -> Option<&SysctlNode> {
+        let mut child = self.children.front();
+        while let Some(node) = child {
+            if node.name == name { return Some(node) }
+            child = unsafe { node.next.resolve() };
+        }
+        None
+    }
+
+    /// Returns this node's child called `name`, adding it if there isn't
+    /// one.
+    fn child_or_insert(&mut self, name: &'static str) -> &mut SysctlNode {
+        let found = self.child(name).map(|node| node as *const SysctlNode);
+        let node = match found {
+            Some(node) => node as *mut SysctlNode
+          , None => {
+                let node = Box::into_raw(Box::new(SysctlNode::new(name)));
+                self.children.push_back(unsafe {
+                    NonNullOwned::from_raw(node)
+                });
+                node
+            }
+        };
+        // the child is owned by `self.children`, so it lives as long as
+        // `self` is borrowed.
+        unsafe { &mut *node }
+    }
+}
+
+impl Node for SysctlNode {
+    #[inline] fn prev(&self) -> &RawLink<SysctlNode> {
+        &self.prev
+    }
+    #[inline] fn next(&self) -> &RawLink<SysctlNode> {
+        &self.next
+    }
+    #[inline] fn prev_mut(&mut self) -> &mut RawLink<SysctlNode> {
+        &mut self.prev
+    }
+    #[inline] fn next_mut(&mut self) -> &mut RawLink<SysctlNode> {
+        &mut self.next
+    }
+}
+
+/// The tree of every registered parameter.
+pub struct SysctlTree { root: SysctlNode }
+
+// the tree owns all of its nodes, and is only reached through a lock.
+unsafe impl Send for SysctlTree {}
+
+impl SysctlTree {
+    pub fn new() -> Self { SysctlTree { root: SysctlNode::new("") } }
+
+    /// Returns the node at `path`, or the root if `path` is empty.
+    fn find(&self, path: &str) -> Option<&SysctlNode> {
+        if path.is_empty() { return Some(&self.root) }
+        path.split('/')
+            .fold(Some(&self.root), |node, name| {
+                node.and_then(|node| node.child(name))
+            })
+    }
+
+    /// Add the parameter at `path`.
+    ///
+    /// Returns false if there's already something at `path`, or if one of
+    /// the directories on the way to it is a parameter.
+    fn insert(&mut self, path: &'static str, get: GetFn, set: Option<SetFn>)
+             -> bool {
+        let mut node = &mut self.root;
+        for name in path.split('/') {
+            if node.param.is_some() { return false }
+            node = node.child_or_insert(name);
+        }
+        if node.param.is_some() || !node.children.is_empty() {
+            return false
+        }
+        node.param = Some((get, set));
+        true
+    }
+}
+
+lazy_static! {
+    /// Every registered parameter.
+    static ref TREE: Mutex<SysctlTree> = Mutex::new(SysctlTree::new());
+}
+
+/// Returns true if `path` is a relative, slash-separated path with no
+/// empty components.
+pub fn valid_path(path: &str) -> bool {
+    !path.is_empty() && path.split('/').all(|name| !name.is_empty())
+}
+
+/// Make the parameter at `path` readable with `get`, and writable with
+/// `set` if there is one.
+///
+/// # Panics
+/// + If `path` isn't a valid path, like `kernel/pid_max`.
+/// + In debug builds, if something is already registered at `path`, or at
+///   one of the directories above it. In release builds, the first
+///   registration is kept.
+pub fn register(path: &'static str, get: GetFn, set: Option<SetFn>) {
+    assert!(valid_path(path), "invalid sysctl path {:?}!", path);
+    if !TREE.lock().insert(path, get, set) {
+        debug_assert!(false, "sysctl {:?} registered twice!", path);
+        warn!("sysctl {:?} registered twice; ignoring it", path);
+    }
+}
+
+/// Returns what's at `path`, or the root directory if `path` is empty.
+pub fn lookup(path: &str) -> Option<Sysctl> {
+    TREE.lock().find(path).map(|node| match node.param {
+        Some((get, set)) => Sysctl::Param(get, set)
+      , None => Sysctl::Dir
+    })
+}
+
+/// Returns the name of the `index`th entry in the directory at `path`, and
+/// whether it's a directory itself.
+pub fn read_dir(path: &str, index: usize) -> Option<(&'static str, bool)> {
+    TREE.lock().find(path)
+        .and_then(|dir| dir.nth_child(index))
+        .map(|node| (node.name, node.param.is_none()))
+}
+
+fn get_pid_max() -> u64 { task::pid_max() as u64 }
+fn set_pid_max(value: u64) { task::set_pid_max(value as usize) }
+fn get_swappiness() -> u64 { mm::swappiness() as u64 }
+fn set_swappiness(value: u64) { mm::set_swappiness(value as usize) }
+
+/// Register the kernel's own parameters.
+pub fn init() {
+    register("kernel/pid_max", get_pid_max, Some(set_pid_max));
+    register("vm/swappiness", get_swappiness, Some(set_swappiness));
+    #[cfg(feature = "logging")]
+    register("kernel/log_level", logger::level, Some(logger::set_level));
+}
@@ -0,0 +1,145 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The kernel log buffer, which `/dev/kmsg` reads from.
+//!
+//! Every message the kernel logs goes into a ring of the most recent
+//! [`RING_SIZE`](constant.RING_SIZE.html) entries, as well as out of the
+//! serial port. Each entry gets the next sequence number, so a reader can
+//! tell where it left off, and whether it fell so far behind that entries
+//! were overwritten before it got to them.
+//!
+//! Levels are syslog priorities, from 0 (emergency) to 7 (debug).
+use core::fmt::{self, Write};
+use spin::Mutex;
+use util::bufwriter::BufWriter;
+use util::ring::RingBuffer;
+
+use arch::cpu::without_interrupts;
+use task::timer;
+use task::wait::WaitQueue;
+
+/// The number of entries kept.
+pub const RING_SIZE: usize = 1024;
+
+/// The longest message an entry can hold. Longer ones are truncated.
+pub const MESSAGE_MAX: usize = 128;
+
+/// The level user messages without a `<level>` prefix are logged at.
+pub const DEFAULT_LEVEL: u8 = 6;
+
+/// A logged message.
+#[derive(Copy, Clone)]
+pub struct SyslogEntry { pub level: u8
+                       , /// One more than the entry before's
+                         pub seq: u64
+                       , /// When the message was logged, in nanoseconds
+                         /// since boot
+                         pub timestamp_ns: u64
+                       , /// The length of `message`
+                         len: u8
+                       , message: [u8; MESSAGE_MAX]
+                       }
+
+impl SyslogEntry {
+    /// Returns the message.
+    #[inline]
+    pub fn message(&self) -> &[u8] { &self.message[..self.len as usize] }
+}
+
+/// An empty entry, so that the ring's storage can go in `.bss`.
+const NO_ENTRY: SyslogEntry = SyslogEntry { level: 0
+                                          , seq: 0
+                                          , timestamp_ns: 0
+                                          , len: 0
+                                          , message: [0; MESSAGE_MAX]
+                                          };
+
+/// The ring of recent entries, oldest first.
+///
+/// Messages are logged from interrupt handlers too, so this must only be
+/// locked with interrupts disabled.
+pub static RING: Mutex<RingBuffer<[SyslogEntry; RING_SIZE]>>
+    = Mutex::new(RingBuffer::with_storage([NO_ENTRY; RING_SIZE]));
+
+lazy_static! {
+    /// Readers waiting for new entries.
+    ///
+    /// The kernel logs with all sorts of locks held, including the ones
+    /// waking a task takes, so kernel messages don't wake anyone: see
+    /// [`wait_for`](fn.wait_for.html).
+    static ref READERS: WaitQueue = WaitQueue::new();
+}
+
+/// How long a reader waits for new entries before looking again, in
+/// nanoseconds.
+const POLL_NS: u64 = 50_000_000;
+
+/// Add a message at `level` to the ring, dropping the oldest entry if it's
+/// full.
+pub fn log(level: u8, args: fmt::Arguments) {
+    let mut entry = SyslogEntry { level: level
+                                , timestamp_ns: timer::now_ns()
+                                , ..NO_ENTRY
+                                };
+    entry.len = {
+        let mut w = BufWriter::new(&mut entry.message);
+        // `BufWriter` truncates rather than failing
+        let _ = w.write_fmt(args);
+        w.len() as u8
+    };
+    without_interrupts(|| {
+        let mut ring = RING.lock();
+        entry.seq = match ring.len() {
+            0 => 0
+          , len => ring.get(len - 1).map_or(0, |last| last.seq + 1)
+        };
+        if ring.is_full() { ring.pop(); }
+        let _ = ring.push(entry);
+    })
+}
+
+/// Add a message to the ring, like [`log`](fn.log.html), and wake any
+/// waiting readers.
+///
+/// This must only be called where the current task could block.
+pub fn log_and_wake(level: u8, args: fmt::Arguments) {
+    log(level, args);
+    READERS.wake_all();
+}
+
+/// Returns the first entry numbered `seq` or later, if there is one.
+///
+/// If the entries from `seq` on have been overwritten, this is the oldest
+/// one left.
+pub fn entry_from(seq: u64) -> Option<SyslogEntry> {
+    without_interrupts(|| {
+        let ring = RING.lock();
+        let oldest = match ring.get(0) {
+            Some(entry) => entry.seq
+          , None => return None
+        };
+        let index = seq.saturating_sub(oldest) as usize;
+        ring.get(index).cloned()
+    })
+}
+
+/// Block the current task until there's an entry numbered `seq` or later,
+/// and return it.
+///
+/// Messages logged by the kernel don't wake readers, so this looks again
+/// every `POLL_NS` regardless.
+pub fn wait_for(seq: u64) -> SyslogEntry {
+    loop {
+        if let Some(entry) = entry_from(seq) { return entry }
+        let deadline = timer::now_ns() + POLL_NS;
+        timer::arm(deadline);
+        READERS.sleep();
+        timer::cancel(deadline);
+    }
+}
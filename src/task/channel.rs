@@ -0,0 +1,196 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Bounded channels, for passing values between kernel threads.
+//!
+//! [`channel`](fn.channel.html) returns the two halves of a queue that
+//! holds up to `capacity` values: a [`Sender`] and a [`Receiver`]. Sending
+//! blocks while the queue is full, and receiving blocks while it's empty.
+//! Once either half is dropped, the other end finds out: the receiver gets
+//! `Disconnected` once it has taken everything that was sent, and the
+//! sender gets its value back.
+//!
+//! The queue is only touched with interrupts disabled, and the receiver
+//! checks it and goes to sleep without letting them in, like
+//! `Semaphore::down`. This makes `try_send` safe to call from an interrupt
+//! handler, to hand work to a driver's thread.
+//!
+//! [`Sender`]: struct.Sender.html
+//! [`Receiver`]: struct.Receiver.html
+use alloc::arc::Arc;
+use alloc::vec_deque::VecDeque;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cpu::interrupts::idt::Idt;
+use spin::Mutex;
+
+use arch::cpu::without_interrupts;
+use super::wait::WaitQueue;
+
+/// Returned by [`Receiver::recv`] once the queue is empty and the sender
+/// has been dropped.
+///
+/// [`Receiver::recv`]: struct.Receiver.html#method.recv
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
+/// Returned by [`Sender::send`] if the receiver has been dropped, with the
+/// value that couldn't be sent.
+///
+/// [`Sender::send`]: struct.Sender.html#method.send
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Errors returned by [`Sender::try_send`], with the value that couldn't
+/// be sent.
+///
+/// [`Sender::try_send`]: struct.Sender.html#method.try_send
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrySendError<T> { /// The queue is full
+                           Full(T)
+                         , /// The receiver has been dropped
+                           Disconnected(T)
+                         }
+
+/// Errors returned by [`Receiver::try_recv`].
+///
+/// [`Receiver::try_recv`]: struct.Receiver.html#method.try_recv
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TryRecvError { /// The queue is empty
+                        Empty
+                      , /// The queue is empty, and the sender has been
+                        /// dropped
+                        Disconnected
+                      }
+
+/// The state shared by the two halves of a channel.
+struct Channel<T> { queue: Mutex<VecDeque<T>>
+                  , capacity: usize
+                  , /// Senders waiting for room in the queue
+                    not_full: WaitQueue
+                  , /// The receiver, waiting for something in the queue
+                    not_empty: WaitQueue
+                  , sender_dropped: AtomicBool
+                  , receiver_dropped: AtomicBool
+                  }
+
+/// The sending half of a channel.
+pub struct Sender<T: Send> { channel: Arc<Channel<T>> }
+
+/// The receiving half of a channel.
+pub struct Receiver<T: Send> { channel: Arc<Channel<T>> }
+
+/// Returns the two halves of a channel that holds up to `capacity` values.
+///
+/// # Panics
+/// + If `capacity` is zero.
+pub fn channel<T: Send>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let queue = VecDeque::with_capacity(capacity);
+    let channel = Arc::new(Channel { queue: Mutex::new(queue)
+                                   , capacity: capacity
+                                   , not_full: WaitQueue::new()
+                                   , not_empty: WaitQueue::new()
+                                   , sender_dropped: AtomicBool::new(false)
+                                   , receiver_dropped: AtomicBool::new(false)
+                                   });
+    (Sender { channel: channel.clone() }, Receiver { channel: channel })
+}
+
+impl<T: Send> Sender<T> {
+    /// Put `value` on the queue, if there's room, without blocking.
+    ///
+    /// This may be called from an interrupt handler.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let channel = &self.channel;
+        if channel.receiver_dropped.load(Ordering::SeqCst) {
+            return Err(TrySendError::Disconnected(value))
+        }
+        without_interrupts(|| {
+            let mut queue = channel.queue.lock();
+            if queue.len() >= channel.capacity {
+                return Err(TrySendError::Full(value))
+            }
+            queue.push_back(value);
+            Ok(())
+        })?;
+        channel.not_empty.wake_one();
+        Ok(())
+    }
+
+    /// Put `value` on the queue, blocking the current task until there's
+    /// room.
+    pub fn send(&self, mut value: T) -> Result<(), SendError<T>> {
+        loop {
+            // senders are never woken from interrupt context, so there's
+            // no wakeup to lose between trying and sleeping.
+            match self.try_send(value) {
+                Ok(()) => return Ok(())
+              , Err(TrySendError::Disconnected(v)) => return Err(SendError(v))
+              , Err(TrySendError::Full(v)) => value = v
+            }
+            self.channel.not_full.sleep();
+        }
+    }
+}
+
+impl<T: Send> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.sender_dropped.store(true, Ordering::SeqCst);
+        self.channel.not_empty.wake_all();
+    }
+}
+
+impl<T: Send> Receiver<T> {
+    /// Take the oldest value off the queue, without blocking.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let channel = &self.channel;
+        // check for the sender first, so a value sent just before it was
+        // dropped isn't missed.
+        let dropped = channel.sender_dropped.load(Ordering::SeqCst);
+        match without_interrupts(|| channel.queue.lock().pop_front()) {
+            Some(value) => {
+                channel.not_full.wake_one();
+                Ok(value)
+            }
+          , None if dropped => Err(TryRecvError::Disconnected)
+          , None => Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Take the oldest value off the queue, blocking the current task
+    /// until there is one.
+    ///
+    /// Returns `Err(Disconnected)` once the queue is empty and the sender
+    /// has been dropped. This must be called with interrupts enabled, and
+    /// leaves them enabled.
+    pub fn recv(&self) -> Result<T, Disconnected> {
+        loop {
+            // keep interrupts off between finding the queue empty and
+            // going to sleep, so a `try_send` from an interrupt handler
+            // can't slip in between and be lost.
+            unsafe { Idt::disable_interrupts(); }
+            let result = self.try_recv();
+            if let Err(TryRecvError::Empty) = result {
+                self.channel.not_empty.sleep();
+            }
+            unsafe { Idt::enable_interrupts(); }
+            match result {
+                Ok(value) => return Ok(value)
+              , Err(TryRecvError::Disconnected) => return Err(Disconnected)
+              , Err(TryRecvError::Empty) => {}
+            }
+        }
+    }
+}
+
+impl<T: Send> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receiver_dropped.store(true, Ordering::SeqCst);
+        self.channel.not_full.wake_all();
+    }
+}
@@ -0,0 +1,379 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Loading 64-bit ELF executables into user space.
+//!
+//! Loading happens in two steps, so that `execve` can fail cleanly: first
+//! the binary is [`parse`](fn.parse.html)d and checked, without touching
+//! the current address space, and only then is it [`load`](fn.load.html)ed.
+//!
+//! Position-independent executables (`ET_DYN`) are loaded at
+//! [`PIE_BASE`](constant.PIE_BASE.html), and their dynamic relocations are
+//! applied as they're loaded. There's no dynamic linker, so they must be
+//! statically linked; the only symbols they may refer to are their own.
+use alloc::vec::Vec;
+use core::{cmp, mem, ptr};
+
+use elf::FileHeader;
+use elf::file::{self, Class, DataEncoding, Header, Machine};
+use elf::program::{self, HeaderRepr64};
+use memory::{PAGE_SIZE, Page, VAddr, VirtualPage};
+use paging::Mapper;
+use paging::arch::ActivePageTable;
+use paging::arch::space::phys_to_virt;
+
+use mm::{is_user_range, map_user_page, USER_SPACE_START};
+use mm::vm::{VmFlags, VmMap, VmRegion, VM_EXEC, VM_READ, VM_WRITE};
+
+/// `PT_LOAD`: the program header type of a loadable segment.
+const PT_LOAD: u32 = 1;
+/// `SHT_RELA`: the section header type of a relocation table with addends.
+pub const SHT_RELA: u32 = 4;
+/// `SHN_UNDEF`: the section index of an undefined symbol.
+pub const SHN_UNDEF: u16 = 0;
+/// `SHN_ABS`: the section index of a symbol with an absolute value.
+pub const SHN_ABS: u16 = 0xfff1;
+/// `STB_WEAK`: the binding of a weak symbol.
+pub const STB_WEAK: u8 = 2;
+
+/// `R_X86_64_NONE`: no relocation.
+pub const R_X86_64_NONE: u32 = 0;
+/// `R_X86_64_64`: the symbol's value plus the addend.
+pub const R_X86_64_64: u32 = 1;
+/// `R_X86_64_GLOB_DAT`: the symbol's value, for a GOT entry.
+const R_X86_64_GLOB_DAT: u32 = 6;
+/// `R_X86_64_RELATIVE`: the load base plus the addend.
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// The address position-independent executables are loaded at.
+pub const PIE_BASE: usize = USER_SPACE_START;
+
+/// Errors returned while loading an executable.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExecError {
+    /// The file isn't an executable we know how to load.
+    Invalid(&'static str)
+  , /// The executable needs a relocation of a type we don't support.
+    UnknownRelocation(u32)
+  , /// We ran out of memory while loading the executable.
+    NoMemory
+}
+
+/// A loadable segment of an executable.
+#[derive(Copy, Clone, Debug)]
+struct Segment { vaddr: usize
+               , mem_size: usize
+               , offset: usize
+               , file_size: usize
+               , flags: VmFlags
+               }
+
+/// A relocation, ready to apply: the 64-bit word at `addr` is set to
+/// `value`.
+#[derive(Copy, Clone, Debug)]
+struct Relocation { addr: usize
+                  , value: u64
+                  }
+
+/// An ELF64 section header.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SectionHeader64 { pub name: u32
+                           , pub ty: u32
+                           , pub flags: u64
+                           , pub addr: u64
+                           , pub offset: u64
+                           , pub size: u64
+                           , pub link: u32
+                           , pub info: u32
+                           , pub addralign: u64
+                           , pub entsize: u64
+                           }
+
+/// An ELF64 relocation with an addend.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Rela64 { pub offset: u64
+                  , pub info: u64
+                  , pub addend: i64
+                  }
+
+/// An ELF64 symbol table entry.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Symbol64 { pub name: u32
+                    , pub info: u8
+                    , pub other: u8
+                    , pub shndx: u16
+                    , pub value: u64
+                    , pub size: u64
+                    }
+
+/// Read a `T` from `bytes` at `offset`, if it's in bounds.
+pub fn read_at<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    match offset.checked_add(mem::size_of::<T>()) {
+        Some(end) if end <= bytes.len() => Some(unsafe {
+            ptr::read_unaligned(bytes[offset..].as_ptr() as *const T)
+        })
+      , _ => None
+    }
+}
+
+/// A parsed and validated 64-bit ELF executable.
+pub struct Elf64<'a> { bytes: &'a [u8]
+                     , entry: VAddr
+                     , segments: Vec<Segment>
+                     , regions: VmMap
+                     , relocations: Vec<Relocation>
+                     }
+
+impl<'a> Elf64<'a> {
+    /// Returns the executable's entry point.
+    #[inline] pub fn entry(&self) -> VAddr { self.entry }
+
+    /// Returns the user memory regions the executable will occupy.
+    #[inline] pub fn regions(&self) -> &VmMap { &self.regions }
+
+    /// Returns the first page-aligned address above every segment, where
+    /// the program's heap starts.
+    pub fn image_end(&self) -> VAddr {
+        self.regions.iter()
+            .map(|region| region.end)
+            .max()
+            .expect("a parsed executable always has a segment")
+    }
+}
+
+/// Convert ELF segment flags to region flags.
+fn segment_flags(flags: program::Flags) -> VmFlags {
+    let mut vm_flags = VmFlags::empty();
+    if flags.contains(program::READABLE) { vm_flags.insert(VM_READ); }
+    if flags.contains(program::WRITABLE) { vm_flags.insert(VM_WRITE); }
+    if flags.contains(program::EXECUTABLE) { vm_flags.insert(VM_EXEC); }
+    vm_flags
+}
+
+/// Parse and validate the executable in `bytes`.
+///
+/// This checks that the file is a statically linked `x86_64` executable,
+/// and that all of its loadable segments lie within the file and within
+/// user space, without overlapping each other. A position-independent
+/// executable's relocations are checked and worked out here too, so that
+/// loading it can't fail because of them.
+pub fn parse(bytes: &[u8]) -> Result<Elf64, ExecError> {
+    let header = <FileHeader<u64> as Header>::from_slice(bytes)
+        .map_err(ExecError::Invalid)?;
+
+    let ident = header.ident();
+    if !ident.is_valid() || ident.class != Class::Elf64 {
+        return Err(ExecError::Invalid("not a 64-bit ELF file"))
+    }
+    if ident.encoding != DataEncoding::LittleEndian {
+        return Err(ExecError::Invalid("not a little-endian ELF file"))
+    }
+    let base = match header.get_type() {
+        file::Type::Executable => 0
+      , file::Type::SharedObject => PIE_BASE
+      , _ => return Err(ExecError::Invalid("not an executable"))
+    };
+    if header.machine() != Machine::X86_64 {
+        return Err(ExecError::Invalid("not an x86_64 executable"))
+    }
+    if header.ph_entry_size() != mem::size_of::<HeaderRepr64>() {
+        return Err(ExecError::Invalid("bad program header size"))
+    }
+    let ph_range = header.ph_range();
+    if ph_range.end > bytes.len() || ph_range.start > ph_range.end {
+        return Err(ExecError::Invalid("program headers out of bounds"))
+    }
+
+    let mut segments = Vec::new();
+    let mut regions = VmMap::new();
+    let page_size = PAGE_SIZE as usize;
+    for i in 0 .. header.ph_count() {
+        let offset = ph_range.start + i * mem::size_of::<HeaderRepr64>();
+        // the program header `Type` enum doesn't cover every type found in
+        // the wild, so check the raw type before reinterpreting the header.
+        let ty = unsafe {
+            ptr::read_unaligned(bytes[offset..].as_ptr() as *const u32)
+        };
+        if ty != PT_LOAD { continue }
+        let phdr = unsafe {
+            ptr::read_unaligned(bytes[offset..].as_ptr()
+                                as *const HeaderRepr64)
+        };
+
+        let segment = Segment { vaddr: base + phdr.vaddr as usize
+                              , mem_size: phdr.mem_size as usize
+                              , offset: phdr.offset as usize
+                              , file_size: phdr.file_size as usize
+                              , flags: segment_flags(phdr.flags)
+                              };
+        if segment.file_size > segment.mem_size {
+            return Err(ExecError::Invalid("segment larger than its image"))
+        }
+        match segment.offset.checked_add(segment.file_size) {
+            Some(end) if end <= bytes.len() => {}
+          , _ => return Err(ExecError::Invalid("segment out of bounds"))
+        }
+        if segment.mem_size == 0 { continue }
+        if !is_user_range(VAddr::from(segment.vaddr), segment.mem_size) {
+            return Err(ExecError::Invalid("segment outside user space"))
+        }
+        let start = segment.vaddr & !(page_size - 1);
+        let end = (segment.vaddr + segment.mem_size + page_size - 1)
+                & !(page_size - 1);
+        regions.insert(VmRegion::new( VAddr::from(start), VAddr::from(end)
+                                    , segment.flags))
+               .map_err(|_| ExecError::Invalid("overlapping segments"))?;
+        segments.push(segment);
+    }
+    if segments.is_empty() {
+        return Err(ExecError::Invalid("no loadable segments"))
+    }
+
+    let entry = VAddr::from(base + header.entry_point());
+    if !regions.covers( entry, VAddr::from(entry.as_usize() + 1)
+                      , VM_EXEC) {
+        return Err(ExecError::Invalid("entry point not executable"))
+    }
+
+    let relocations = if base == 0 {
+        Vec::new()
+    } else {
+        relocations(bytes, header, base, &regions)?
+    };
+
+    Ok(Elf64 { bytes: bytes
+             , entry: entry
+             , segments: segments
+             , regions: regions
+             , relocations: relocations
+             })
+}
+
+/// Work out the relocations in every `SHT_RELA` section of the
+/// position-independent executable in `bytes`, loaded at `base`.
+///
+/// Each relocation must patch an aligned word inside one of `regions`.
+fn relocations( bytes: &[u8], header: &FileHeader<u64>, base: usize
+              , regions: &VmMap)
+               -> Result<Vec<Relocation>, ExecError> {
+    if header.sh_count() == 0 { return Ok(Vec::new()) }
+    if header.sh_entry_size() != mem::size_of::<SectionHeader64>() {
+        return Err(ExecError::Invalid("bad section header size"))
+    }
+    let section = |idx: usize| -> Result<SectionHeader64, ExecError> {
+        if idx >= header.sh_count() {
+            return Err(ExecError::Invalid("section index out of bounds"))
+        }
+        read_at(bytes, header.section_index(idx).start)
+            .ok_or(ExecError::Invalid("section headers out of bounds"))
+    };
+
+    let mut relocations = Vec::new();
+    for idx in 0 .. header.sh_count() {
+        let rela = section(idx)?;
+        if rela.ty != SHT_RELA { continue }
+        if rela.entsize as usize != mem::size_of::<Rela64>() {
+            return Err(ExecError::Invalid("bad relocation entry size"))
+        }
+        let symtab = section(rela.link as usize)?;
+        let count = rela.size as usize / mem::size_of::<Rela64>();
+        for i in 0 .. count {
+            let offset = rela.offset as usize + i * mem::size_of::<Rela64>();
+            let entry: Rela64 = read_at(bytes, offset)
+                .ok_or(ExecError::Invalid("relocations out of bounds"))?;
+            let r_type = entry.info as u32;
+            let sym_value = || -> Result<u64, ExecError> {
+                let sym_idx = (entry.info >> 32) as usize;
+                if sym_idx == 0 { return Ok(0) }
+                let offset = symtab.offset as usize
+                           + sym_idx * mem::size_of::<Symbol64>();
+                let sym: Symbol64 = read_at(bytes, offset)
+                    .ok_or(ExecError::Invalid("symbol out of bounds"))?;
+                match sym.shndx {
+                    SHN_UNDEF if sym.info >> 4 == STB_WEAK => Ok(0)
+                  , SHN_UNDEF =>
+                        Err(ExecError::Invalid("undefined symbol"))
+                  , SHN_ABS => Ok(sym.value)
+                  , _ => Ok(base as u64 + sym.value)
+                }
+            };
+            let value = match r_type {
+                R_X86_64_NONE => continue
+              , R_X86_64_RELATIVE =>
+                    (base as u64).wrapping_add(entry.addend as u64)
+              , R_X86_64_64 =>
+                    sym_value()?.wrapping_add(entry.addend as u64)
+              , R_X86_64_GLOB_DAT => sym_value()?
+              , other => return Err(ExecError::UnknownRelocation(other))
+            };
+
+            let addr = base + entry.offset as usize;
+            if addr % mem::size_of::<u64>() != 0 {
+                return Err(ExecError::Invalid("misaligned relocation"))
+            }
+            let end = VAddr::from(addr + mem::size_of::<u64>());
+            if !regions.covers(VAddr::from(addr), end, VmFlags::empty()) {
+                return Err(ExecError::Invalid("relocation out of bounds"))
+            }
+            relocations.push(Relocation { addr: addr, value: value });
+        }
+    }
+    Ok(relocations)
+}
+
+/// Map the segments of `elf` into the current address space, copy in their
+/// contents, and apply its relocations.
+///
+/// The caller is responsible for making sure that nothing is mapped where
+/// the segments go, and for adding `elf.regions()` to the task's `VmMap`.
+pub fn load(elf: &Elf64) -> Result<(), ExecError> {
+    let page_size = PAGE_SIZE as usize;
+    for segment in &elf.segments {
+        let file_start = segment.vaddr;
+        let file_end = segment.vaddr + segment.file_size;
+        let first = VirtualPage::containing(VAddr::from(segment.vaddr));
+        let last = VirtualPage::containing(
+            VAddr::from(segment.vaddr + segment.mem_size - 1));
+
+        for number in first.number .. last.number + 1 {
+            let page = VirtualPage { number: number };
+            let frame = map_user_page(page, segment.flags)
+                .map_err(|_| ExecError::NoMemory)?;
+
+            // copy the part of the file image that falls in this page; the
+            // rest of the page (e.g. `.bss`) stays zeroed.
+            let page_start = page.base().as_usize();
+            let lo = cmp::max(page_start, file_start);
+            let hi = cmp::min(page_start + page_size, file_end);
+            if lo < hi {
+                let src = &elf.bytes[segment.offset + (lo - file_start) ..
+                                     segment.offset + (hi - file_start)];
+                unsafe {
+                    let dst = phys_to_virt(frame.base_addr())
+                                .as_mut_ptr::<u8>()
+                                .offset((lo - page_start) as isize);
+                    ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len());
+                }
+            }
+        }
+    }
+
+    // relocations are written through the physical memory map as well, as
+    // they usually land in pages the program can't write to (`.got` and
+    // anything else in `PT_GNU_RELRO`).
+    let table = unsafe { ActivePageTable::new() };
+    for reloc in &elf.relocations {
+        let paddr = table.translate(VAddr::from(reloc.addr))
+            .expect("relocation in a segment that wasn't mapped");
+        unsafe { *phys_to_virt(paddr).as_mut_ptr::<u64>() = reloc.value; }
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Replacing a task's program image.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{mem, ptr};
+use memory::{PAGE_SIZE, PhysicalPage, VAddr, VirtualPage};
+use paging::arch::space;
+
+use arch::{extable, pcid};
+use arch::cpu::without_interrupts;
+use mm::{frame, map_user_page, swap, USER_SPACE_END};
+use mm::vm::{VmRegion, VM_GROWSDOWN, VM_READ, VM_WRITE};
+use super::{signal, with_task, Pid, Task};
+use super::elf64::{self, ExecError};
+
+/// The number of pages in a new user stack.
+pub const USER_STACK_PAGES: usize = 8;
+/// The size of a new user stack, in bytes.
+pub const USER_STACK_SIZE: usize = USER_STACK_PAGES * PAGE_SIZE as usize;
+/// One past the highest address of a new user stack. The page above it is
+/// left unmapped.
+pub const USER_STACK_TOP: usize = USER_SPACE_END - PAGE_SIZE as usize;
+/// The most space the arguments and environment may take up on the new
+/// stack, including their pointer arrays.
+pub const ARG_MAX: usize = USER_STACK_SIZE / 4;
+
+/// Returns the number of bytes `argv` and `envp` will take up on the stack.
+pub fn args_size(argv: &[Vec<u8>], envp: &[Vec<u8>]) -> usize {
+    let ptr_size = mem::size_of::<u64>();
+    argv.iter().chain(envp.iter())
+        .map(|arg| arg.len() + 1 + ptr_size)
+        .sum()
+}
+
+/// Replace `task`'s user address space with the executable in `image`,
+/// and rename it to `name`.
+///
+/// Returns the entry point and initial stack pointer of the new program.
+/// The new program is loaded into an address space of its own, and the old
+/// one is only thrown away once that has succeeded, so if the executable is
+/// invalid or we run out of memory, `task` is left untouched.
+///
+/// # Safety
+/// + `task` must be the current task, as the new program is loaded into
+///   the active address space.
+pub unsafe fn exec( task: &mut Task, name: &[u8], image: &[u8]
+                  , argv: &[Vec<u8>], envp: &[Vec<u8>])
+                  -> Result<(VAddr, VAddr), ExecError> {
+    let elf = elf64::parse(image)?;
+    let mut vm = elf.regions().clone();
+    let stack_bottom = VAddr::from(USER_STACK_TOP - USER_STACK_SIZE);
+    let stack_flags = VM_READ | VM_WRITE | VM_GROWSDOWN;
+    vm.insert(VmRegion::new( stack_bottom, VAddr::from(USER_STACK_TOP)
+                           , stack_flags))
+        .map_err(|_| ExecError::Invalid("stack overlaps program image"))?;
+
+    let new_table = space::new_address_space(&mut frame::allocator())
+        .map_err(|_| ExecError::NoMemory)?
+        .frame();
+    let old_table = task.page_table;
+    switch_page_table(task, new_table);
+    if let Err(err) = map_image(&elf, stack_bottom) {
+        switch_page_table(task, old_table);
+        space::free_address_space( new_table, &mut frame::allocator()
+                                 , &mut swap::free_slot );
+        return Err(err)
+    }
+
+    // point of no return: throw away the old program. a kernel thread
+    // exec-ing its first program had the kernel's page tables, which
+    // aren't its to free.
+    if with_task(Pid(0), |kernel| kernel.page_table) != Some(old_table) {
+        space::free_address_space( old_table, &mut frame::allocator()
+                                 , &mut swap::free_slot );
+    }
+    task.vm = vm;
+    task.files.close_on_exec();
+    // the handlers were in the old program; ignored signals stay ignored.
+    for handler in task.signal_handlers.iter_mut() {
+        if handler.as_usize() != signal::SIG_IGN {
+            *handler = VAddr::from(signal::SIG_DFL);
+        }
+    }
+    task.name = String::from_utf8_lossy(name).into_owned();
+    task.brk_start = elf.image_end();
+    task.brk = task.brk_start;
+
+    // the stack was just mapped, so it can be written directly.
+    let sp = extable::with_user_access(||
+        push_args(USER_STACK_TOP, argv, envp));
+    Ok((elf.entry(), VAddr::from(sp)))
+}
+
+/// Make `table` the address space of `task`, which must be the current
+/// task, and switch to it.
+///
+/// `task` is given a fresh PCID, so nothing cached from the address space
+/// it had before can be used.
+unsafe fn switch_page_table(task: &mut Task, table: PhysicalPage) {
+    without_interrupts(|| {
+        task.page_table = table;
+        pcid::release(task);
+        pcid::switch(task);
+    })
+}
+
+/// Load `elf` into the active address space, and map a new stack above
+/// `stack_bottom`.
+unsafe fn map_image(elf: &elf64::Elf64, stack_bottom: VAddr)
+                   -> Result<(), ExecError> {
+    elf64::load(elf)?;
+    let first = VirtualPage::containing(stack_bottom);
+    for number in first.number .. first.number + USER_STACK_PAGES {
+        map_user_page( VirtualPage { number: number }
+                     , VM_READ | VM_WRITE | VM_GROWSDOWN)
+            .map_err(|_| ExecError::NoMemory)?;
+    }
+    Ok(())
+}
+
+/// Copy a string onto the user stack below `sp`, NUL-terminated.
+unsafe fn push_str(sp: &mut usize, string: &[u8]) -> u64 {
+    *sp -= string.len() + 1;
+    ptr::copy_nonoverlapping(string.as_ptr(), *sp as *mut u8, string.len());
+    *((*sp + string.len()) as *mut u8) = 0;
+    *sp as u64
+}
+
+/// Lay out the initial stack of a new program, as expected by the System V
+/// ABI, below `top`.
+///
+/// The strings go at the very top, and below them (16-byte aligned) are
+/// `argc`, the `argv` array, the `envp` array and an empty auxiliary vector.
+/// Returns the new stack pointer, which points at `argc`.
+unsafe fn push_args(top: usize, argv: &[Vec<u8>], envp: &[Vec<u8>])
+                   -> usize {
+    let mut sp = top;
+    let env_ptrs: Vec<u64>
+        = envp.iter().map(|s| push_str(&mut sp, s)).collect();
+    let arg_ptrs: Vec<u64>
+        = argv.iter().map(|s| push_str(&mut sp, s)).collect();
+
+    // argc, argv, NULL, envp, NULL, AT_NULL, 0
+    let words = 1 + arg_ptrs.len() + 1 + env_ptrs.len() + 1 + 2;
+    sp = (sp - words * mem::size_of::<u64>()) & !0xf;
+
+    let mut slot = sp as *mut u64;
+    let mut push = |word: u64| { *slot = word; slot = slot.offset(1); };
+    push(arg_ptrs.len() as u64);
+    for &p in &arg_ptrs { push(p); }
+    push(0);
+    for &p in &env_ptrs { push(p); }
+    push(0);
+    push(0);
+    push(0);
+    sp
+}
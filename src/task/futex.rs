@@ -0,0 +1,112 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Futexes: wait queues keyed by a word of user memory.
+//!
+//! A task waits on a futex by giving the address of a 32-bit word, and the
+//! value it expects to find there; it only goes to sleep if the word still
+//! holds that value, so a wakeup sent after the task last looked at it
+//! can't be missed. Tasks are never preempted in the kernel, so nothing can
+//! happen between the check and going to sleep.
+//!
+//! Futexes are keyed by the *physical* address of the word, so that tasks
+//! sharing a page (after `fork`, until it's copied) share its futexes too.
+//! Waiters are kept in a fixed number of buckets, by hash of the key.
+//!
+//! Every waiter has a bitset, and a wakeup only wakes waiters whose bitset
+//! intersects the waker's. Plain waits and wakeups use every bit.
+use alloc::vec::Vec;
+use alloc::vec_deque::VecDeque;
+use memory::PAddr;
+use spin::Mutex;
+
+use super::{Pid, TaskState};
+use super::{sched, timer, wait};
+
+/// The number of buckets in the futex hash table.
+pub const FUTEX_HASH_BUCKETS: usize = 256;
+
+/// The bitset that matches every other bitset.
+pub const FUTEX_BITSET_MATCH_ANY: u32 = !0;
+
+/// A task waiting on a futex.
+#[derive(Copy, Clone, Debug)]
+struct FutexWaiter { pid: Pid
+                   , key: PAddr
+                   , bitset: u32
+                   }
+
+lazy_static! {
+    /// Tasks waiting on futexes, hashed by the futex's physical address.
+    static ref FUTEX_TABLE: Vec<Mutex<VecDeque<FutexWaiter>>>
+        = (0 .. FUTEX_HASH_BUCKETS).map(|_| Mutex::new(VecDeque::new()))
+                                   .collect();
+}
+
+/// Returns the bucket for the futex at `key`.
+#[inline]
+fn bucket(key: PAddr) -> &'static Mutex<VecDeque<FutexWaiter>> {
+    // futex words are 4-byte aligned, so the bottom two bits say nothing.
+    &FUTEX_TABLE[(*key >> 2) as usize % FUTEX_HASH_BUCKETS]
+}
+
+/// Block the current task on the futex at `key` until it's woken by a
+/// [`wake`](fn.wake.html) whose bitset intersects `bitset`, or until
+/// `deadline_ns`, if there is one.
+///
+/// The caller must have checked the futex word's value since it last gave
+/// up the CPU.
+///
+/// Returns false if the deadline passed first.
+pub fn wait(key: PAddr, bitset: u32, deadline_ns: Option<u64>) -> bool {
+    let task = unsafe { super::current() };
+    if let Some(deadline) = deadline_ns {
+        if timer::now_ns() >= deadline { return false }
+        timer::arm(deadline);
+    }
+    task.state = TaskState::Blocked;
+    let waiter = FutexWaiter { pid: task.pid, key: key, bitset: bitset };
+    bucket(key).lock().push_back(waiter);
+    sched::schedule();
+
+    // a wakeup takes us out of the bucket, so if we're still in it, it was
+    // the timer that woke us.
+    let mut waiters = bucket(key).lock();
+    match waiters.iter().position(|w| w.pid == waiter.pid && w.key == key) {
+        Some(i) => {
+            waiters.remove(i);
+            false
+        }
+      , None => {
+            if let Some(deadline) = deadline_ns { timer::cancel(deadline); }
+            true
+        }
+    }
+}
+
+/// Wake up to `count` tasks waiting on the futex at `key` with a bitset
+/// that intersects `bitset`, oldest first.
+///
+/// Returns the number of tasks woken.
+pub fn wake(key: PAddr, count: usize, bitset: u32) -> usize {
+    let mut woken = 0;
+    let mut waiters = bucket(key).lock();
+    let mut i = 0;
+    while woken < count && i < waiters.len() {
+        let waiter = waiters[i];
+        if waiter.key == key && waiter.bitset & bitset != 0 {
+            waiters.remove(i);
+            // a task that has exited since it started waiting doesn't
+            // count.
+            if wait::wake(waiter.pid) { woken += 1 }
+        } else {
+            i += 1;
+        }
+    }
+    woken
+}
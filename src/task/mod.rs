@@ -0,0 +1,599 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Tasks.
+//!
+//! A task is a single thread of execution, together with the resources it
+//! owns: its open files and its user address space.
+use core::{cmp, fmt, ptr};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use alloc::boxed::Box;
+use alloc::btree_map::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use memory::{PhysicalPage, VAddr};
+use paging::MapResult;
+use paging::arch::cr3;
+use paging::arch::space::{self, table_at};
+use paging::arch::tlb;
+use spin::Mutex;
+
+use arch::{self, pcid, percpu};
+use arch::context::Context;
+use arch::cpu::without_interrupts;
+use arch::fpu::{self, XsaveArea};
+use arch::rng;
+use arch::syscall::SyscallFrame;
+use arch::tls::KernelTls;
+use fs::fd::FdTable;
+use mm::{frame, swap, unmap_user_pages};
+use mm::vm::VmMap;
+use perf::PerfCounters;
+use phase::{advance_phase, KernelPhase};
+use syscall::seccomp::SyscallFilter;
+
+pub mod channel;
+pub mod elf64;
+pub mod exec;
+pub mod futex;
+pub mod sched;
+pub mod signal;
+pub mod timer;
+pub mod wait;
+pub mod workqueue;
+
+use self::wait::WaitQueue;
+
+/// The size of each task's kernel stack.
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+/// A process identifier.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Pid(pub u32);
+
+impl fmt::Display for Pid {
+    #[inline] fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The scheduling state of a task.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TaskState {
+    /// The task is running or waiting to be scheduled.
+    Runnable
+  , /// The task is waiting for an event.
+    Blocked
+  , /// The task has exited, but its parent hasn't collected its exit status
+    /// yet.
+    Zombie
+}
+
+/// Returns a new stack canary, from the hardware random number generator
+/// if there is one.
+#[inline]
+fn new_canary() -> u64 { rng::next_u64() }
+
+/// Write a new canary at `addr`, returning its value.
+///
+/// # Safety
+/// + `addr` must be the bottom of a kernel stack that nothing is using the
+///   bottom 8 bytes of.
+unsafe fn place_canary(addr: *mut u64) -> u64 {
+    let canary = new_canary();
+    ptr::write_volatile(addr, canary);
+    canary
+}
+
+/// A task's kernel stack.
+///
+/// There's no guard page below it, so the bottom 8 bytes hold a canary
+/// instead, which is checked on every system call. If a task has run off
+/// the end of its stack, the canary won't match.
+pub struct KernelStack { stack: Box<[u8]>
+                       , canary: u64
+                       }
+
+impl KernelStack {
+    /// Allocate a new kernel stack on the heap.
+    pub fn new() -> Self {
+        let mut stack = KernelStack {
+            stack: vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice()
+          , canary: 0
+        };
+        stack.canary = unsafe { place_canary(stack.canary_addr()) };
+        stack
+    }
+
+    /// Returns the (16-byte aligned) address of the top of the stack.
+    #[inline]
+    pub fn top(&self) -> usize {
+        (self.stack.as_ptr() as usize + self.stack.len()) & !0xf
+    }
+
+    /// Returns the address of the stack's canary.
+    ///
+    /// The boxed slice isn't aligned, so this is the lowest 16-byte aligned
+    /// address that's sure to be in the stack, going by its top.
+    #[inline]
+    pub fn canary_addr(&self) -> *mut u64 {
+        (self.top() - KERNEL_STACK_SIZE + 16) as *mut u64
+    }
+
+    /// Returns the value the stack's canary should have.
+    #[inline]
+    pub fn canary(&self) -> u64 { self.canary }
+}
+
+/// A task.
+pub struct Task { pub pid: Pid
+                , /// The task's name (the file name of its program)
+                  pub name: String
+                , /// The task that created this task, if any
+                  pub parent: Option<Pid>
+                , pub state: TaskState
+                , /// Saved kernel context, while the task isn't running
+                  pub context: Context
+                , /// Saved FPU state, or `None` if the task has never used
+                  /// the FPU
+                  pub fpu_state: Option<Box<XsaveArea>>
+                , /// The task's kernel stack, or `None` for the boot task,
+                  /// which runs on the boot stack
+                  pub kernel_stack: Option<KernelStack>
+                , /// The frame containing the task's PML4 table
+                  pub page_table: PhysicalPage
+                , /// The task's PCID, if it has been given one
+                  pub pcid: Option<u16>
+                , /// The task's open files
+                  pub files: FdTable
+                , /// The valid regions of the task's user address space
+                  pub vm: VmMap
+                , /// The lowest address the program break may be set to
+                  pub brk_start: VAddr
+                , /// The current program break (the end of the heap)
+                  pub brk: VAddr
+                , /// The task's exit status, once it has exited
+                  pub exit_code: u8
+                , /// Woken whenever one of the task's children exits
+                  pub child_wq: WaitQueue
+                , /// The handler for each signal, or `SIG_DFL` or
+                  /// `SIG_IGN`. Signal `n` is at index `n - 1`.
+                  pub signal_handlers: [VAddr; signal::NSIG]
+                , /// Where signal handlers return to
+                  pub signal_restorer: VAddr
+                , /// Signals sent to the task but not yet delivered;
+                  /// signal `n` is bit `n - 1`
+                  pub pending_signals: AtomicU64
+                , /// The system calls the task may make, or `None` if
+                  /// it may make any of them
+                  pub syscall_filter: Option<SyscallFilter>
+                , /// Added to the task's badness when the OOM killer picks
+                  /// a victim, from `OOM_SCORE_ADJ_MIN` (never kill it) to
+                  /// `OOM_SCORE_ADJ_MAX`
+                  pub oom_score_adj: i32
+                , /// CPU time the task has used, in nanoseconds, up to
+                  /// the last time it was switched away from
+                  pub cpu_time_ns: AtomicU64
+                , /// CPU time used by the task's children that have been
+                  /// reaped, in nanoseconds
+                  pub children_cpu_time_ns: AtomicU64
+                , /// When the task was last switched to, in nanoseconds
+                  /// since boot
+                  pub last_scheduled_ns: u64
+                , /// The task's thread-local storage, if it's a kernel
+                  /// thread
+                  pub tls: Option<Box<KernelTls>>
+                , /// Counts of software events, which are only kept with
+                  /// the `task-perf` feature
+                  pub perf: PerfCounters
+                , /// The value the canary at the bottom of the task's
+                  /// kernel stack should have
+                  pub stack_canary: u64
+                }
+
+impl Task {
+    /// Returns a new task with no open files and an empty address space.
+    pub fn new(pid: Pid, name: &str, page_table: PhysicalPage) -> Self {
+        Task { pid: pid
+             , name: String::from(name)
+             , parent: None
+             , state: TaskState::Runnable
+             , context: Context::default()
+             , fpu_state: None
+             , kernel_stack: None
+             , page_table: page_table
+             , pcid: None
+             , files: FdTable::new()
+             , vm: VmMap::new()
+             , brk_start: VAddr::from(0)
+             , brk: VAddr::from(0)
+             , exit_code: 0
+             , child_wq: WaitQueue::new()
+             , signal_handlers: [VAddr::from(signal::SIG_DFL); signal::NSIG]
+             , signal_restorer: VAddr::from(0)
+             , pending_signals: AtomicU64::new(0)
+             , syscall_filter: None
+             , oom_score_adj: 0
+             , cpu_time_ns: AtomicU64::new(0)
+             , children_cpu_time_ns: AtomicU64::new(0)
+             , last_scheduled_ns: 0
+             , tls: None
+             , perf: PerfCounters::new()
+             , stack_canary: 0
+             }
+    }
+
+    /// Returns the address loaded into `%rsp` when this task enters the
+    /// kernel from user mode.
+    pub fn kernel_stack_top(&self) -> u64 {
+        match self.kernel_stack {
+            Some(ref stack) => stack.top() as u64
+          , None => unsafe { arch::STACK_TOP as u64 }
+        }
+    }
+
+    /// Returns the address of the canary at the bottom of this task's
+    /// kernel stack, which should hold `stack_canary`.
+    pub fn stack_canary_addr(&self) -> u64 {
+        match self.kernel_stack {
+            Some(ref stack) => stack.canary_addr() as u64
+          , None => unsafe { arch::STACK_BASE as u64 }
+        }
+    }
+
+    /// Create a copy of this task with the PID `pid`.
+    ///
+    /// The child gets a copy of the parent's user address space, open files
+    /// and FPU state, and a new kernel stack. When it is first scheduled, it
+    /// returns from the current system call (as described by `frame`) with
+    /// 0.
+    ///
+    /// The address space is shared copy-on-write, so no pages are copied
+    /// until one side or the other writes to them. Pages that are swapped
+    /// out stay in the same swap slots, which both sides then refer to.
+    ///
+    /// This must be called by the task being forked, since its FPU state is
+    /// copied out of the registers, and its TLB entries are flushed.
+    pub fn fork(&self, pid: Pid, frame: &SyscallFrame) -> MapResult<Task> {
+        let mut frames = frame::allocator();
+        let table = space::new_address_space(&mut frames)?;
+        unsafe {
+            let result = space::clone_user_address_space(
+                table_at(self.page_table), table_at(table.frame())
+              , &mut frames, &mut frame::frame_inc_ref, &mut swap::dup_slot);
+            // our writable pages are read-only now, even if the clone failed
+            // halfway through.
+            tlb::flush_all();
+            result?;
+        }
+
+        let stack = KernelStack::new();
+        let canary = stack.canary();
+        let context = unsafe { Context::fork_child(stack.top(), frame) };
+        Ok(Task { pid: pid
+                , name: self.name.clone()
+                , parent: Some(self.pid)
+                , state: TaskState::Runnable
+                , context: context
+                , fpu_state: fpu::fork_state(self)
+                , kernel_stack: Some(stack)
+                , page_table: table.frame()
+                , pcid: None
+                , files: self.files.clone()
+                , vm: self.vm.clone()
+                , brk_start: self.brk_start
+                , brk: self.brk
+                , exit_code: 0
+                , child_wq: WaitQueue::new()
+                , signal_handlers: self.signal_handlers
+                , signal_restorer: self.signal_restorer
+                , pending_signals: AtomicU64::new(0)
+                , syscall_filter: self.syscall_filter
+                , oom_score_adj: self.oom_score_adj
+                , cpu_time_ns: AtomicU64::new(0)
+                , children_cpu_time_ns: AtomicU64::new(0)
+                , last_scheduled_ns: 0
+                , tls: None
+                , perf: PerfCounters::new()
+                , stack_canary: canary
+                })
+    }
+
+    /// Returns a new kernel thread with the PID `pid`, which runs `entry`
+    /// on its own kernel stack when it is first scheduled.
+    ///
+    /// Kernel threads have no user address space; they run on the kernel's
+    /// page tables, which they share with task 0. Each has its own
+    /// [`KernelTls`](../arch/tls/struct.KernelTls.html).
+    pub fn kernel_thread(pid: Pid, name: &str, entry: extern "C" fn() -> !)
+                        -> Task {
+        let page_table = with_task(Pid(0), |task| task.page_table)
+            .expect("kernel threads can't be created before task 0!");
+        let stack = KernelStack::new();
+        let mut task = Task::new(pid, name, page_table);
+        task.context = unsafe { Context::kernel_thread(stack.top(), entry) };
+        task.stack_canary = stack.canary();
+        task.kernel_stack = Some(stack);
+        task.tls = Some(Box::new(KernelTls::new()));
+        task
+    }
+}
+
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Task")
+         .field("pid", &self.pid)
+         .field("name", &self.name)
+         .field("parent", &self.parent)
+         .field("state", &self.state)
+         .finish()
+    }
+}
+
+lazy_static! {
+    /// Every task in the system, by PID.
+    ///
+    /// Tasks are boxed so that they don't move when the map is modified, as
+    /// the per-CPU data holds raw pointers to them.
+    ///
+    /// Interrupt handlers look tasks up to wake them, so this must only be
+    /// locked with interrupts disabled.
+    static ref TASKS: Mutex<BTreeMap<Pid, Box<Task>>>
+        = Mutex::new(BTreeMap::new());
+}
+
+/// The default for [`pid_max`](fn.pid_max.html), as on Linux.
+pub const PID_MAX_DEFAULT: usize = 32_768;
+/// The highest `pid_max` may be set to, as on Linux.
+pub const PID_MAX_LIMIT: usize = 4_194_304;
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+static PID_MAX: AtomicUsize = AtomicUsize::new(PID_MAX_DEFAULT);
+
+/// Returns one more than the highest PID that will be handed out.
+#[inline]
+pub fn pid_max() -> usize { PID_MAX.load(Ordering::Relaxed) }
+
+/// Set one more than the highest PID that will be handed out.
+///
+/// This is clamped between `PID_MAX_LIMIT` and a few PIDs more than task 0
+/// and the kernel's first threads need. Tasks that already have higher
+/// PIDs keep them.
+pub fn set_pid_max(max: usize) {
+    let max = cmp::max(cmp::min(max, PID_MAX_LIMIT), 32);
+    PID_MAX.store(max, Ordering::Relaxed)
+}
+
+/// Returns an unused PID.
+///
+/// PIDs are handed out in order, wrapping back around to 1 at
+/// [`pid_max`](fn.pid_max.html) and skipping those that are still in use.
+///
+/// # Panics
+/// + If every PID is in use.
+pub fn alloc_pid() -> Pid {
+    without_interrupts(|| {
+        let tasks = TASKS.lock();
+        let max = pid_max();
+        for _ in 1..max {
+            let mut pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+            if pid >= max {
+                // PID 0 is the boot task, which never exits.
+                NEXT_PID.store(2, Ordering::Relaxed);
+                pid = 1;
+            }
+            let pid = Pid(pid as u32);
+            if !tasks.contains_key(&pid) { return pid }
+        }
+        panic!("out of PIDs!")
+    })
+}
+
+/// Add `task` to the task table, without putting it on the run queue.
+///
+/// Returns a pointer to the task, which remains valid until it is removed
+/// from the task table.
+pub fn insert(task: Task) -> *mut Task {
+    let mut task = Box::new(task);
+    let ptr = &mut *task as *mut Task;
+    without_interrupts(|| TASKS.lock().insert(task.pid, task));
+    ptr
+}
+
+/// Add `task` to the task table and make it runnable.
+pub fn spawn(task: Task) -> Pid {
+    let pid = task.pid;
+    insert(task);
+    sched::enqueue(pid);
+    pid
+}
+
+/// Start a kernel thread called `name`, running `entry`.
+pub fn spawn_kernel(name: &str, entry: extern "C" fn() -> !) -> Pid {
+    spawn(Task::kernel_thread(alloc_pid(), name, entry))
+}
+
+/// Returns a pointer to the task with the given PID, if it exists.
+///
+/// The pointer remains valid until the task is removed from the task table.
+pub fn get(pid: Pid) -> Option<*mut Task> {
+    without_interrupts(|| {
+        TASKS.lock()
+             .get_mut(&pid)
+             .map(|task| &mut **task as *mut Task)
+    })
+}
+
+/// Call `f` with the task `pid`, if it exists.
+///
+/// The task table is locked, and interrupts are disabled, while `f` runs.
+pub fn with_task<F, R>(pid: Pid, f: F) -> Option<R>
+where F: FnOnce(&Task) -> R {
+    without_interrupts(|| TASKS.lock().get(&pid).map(|task| f(task)))
+}
+
+/// Call `f` with the task `pid`, if it exists, and let it change the task.
+///
+/// The task table is locked, and interrupts are disabled, while `f` runs.
+pub fn with_task_mut<F, R>(pid: Pid, f: F) -> Option<R>
+where F: FnOnce(&mut Task) -> R {
+    without_interrupts(|| TASKS.lock().get_mut(&pid).map(|task| f(task)))
+}
+
+/// Call `f` with every task, in PID order.
+///
+/// The task table is locked, and interrupts are disabled, while `f` runs.
+pub fn for_each<F>(mut f: F)
+where F: FnMut(&Task) {
+    without_interrupts(|| {
+        for task in TASKS.lock().values() {
+            f(task)
+        }
+    })
+}
+
+/// Call `f` with every task, in PID order, unless the task table is
+/// already locked.
+///
+/// Returns false, without calling `f`, if the table is locked. This is for
+/// code that may run while the table's holder is stuck, such as the OOM
+/// killer.
+pub fn try_for_each<F>(mut f: F) -> bool
+where F: FnMut(&Task) {
+    match TASKS.try_lock() {
+        Some(tasks) => {
+            for task in tasks.values() {
+                f(task)
+            }
+            true
+        }
+      , None => false
+    }
+}
+
+/// Turn the boot thread into task 0, make it the current task, and give
+/// the bootstrap processor its idle task.
+///
+/// # Safety
+/// + This must be called once, after the heap and per-CPU data have been
+///   initialized.
+pub unsafe fn init() {
+    let mut task = Task::new(Pid(0), "kernel", cr3::current_pagetable_frame());
+    // the boot stack is in the kernel's `.bss`, and nothing else is there.
+    task.stack_canary = place_canary(arch::STACK_BASE as *mut u64);
+    let cpu = percpu::current();
+    cpu.stack_canary_addr = task.stack_canary_addr();
+    cpu.stack_canary = task.stack_canary;
+    cpu.current_task = insert(task);
+    sched::init_idle();
+    advance_phase(KernelPhase::SchedulerInit);
+}
+
+/// Returns the task currently running on this CPU.
+///
+/// # Panics
+/// + If no task is running on this CPU.
+///
+/// # Safety
+/// + The caller must not hold the returned reference across a point where
+///   the task may be destroyed, or create aliasing mutable references.
+pub unsafe fn current() -> &'static mut Task {
+    let task = percpu::current().current_task;
+    assert!(!task.is_null(), "no task is running on this CPU!");
+    &mut *task
+}
+
+/// Terminate the current task with the status `code`.
+///
+/// The task's address space and open files are released immediately, but
+/// it stays in the task table as a zombie until its parent collects its
+/// exit status with [`reap`](fn.reap.html).
+pub fn exit(code: u8) -> ! {
+    let task = unsafe { current() };
+    debug!("task {} exiting with status {}", task.pid, code);
+    let regions: Vec<(VAddr, VAddr)>
+        = task.vm.iter().map(|r| (r.start, r.end)).collect();
+    for (start, end) in regions {
+        unmap_user_pages(start, end);
+    }
+    task.vm = VmMap::new();
+    task.files = FdTable::new();
+    task.exit_code = code;
+    task.state = TaskState::Zombie;
+
+    // TODO: reparent this task's children, or their exit statuses will
+    //       never be collected.
+    if let Some(parent) = task.parent.and_then(get) {
+        unsafe { (*parent).child_wq.wake_all(); }
+    }
+    sched::schedule();
+    unreachable!("zombie task {} was scheduled!", task.pid)
+}
+
+/// The result of [`find_child`](fn.find_child.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ChildStatus {
+    /// No child matched.
+    NoChildren
+  , /// At least one child matched, but none of them have exited.
+    Running
+  , /// A matching child has exited with the given status.
+    Exited(Pid, u8)
+}
+
+/// Look for a child of `parent` that has exited.
+///
+/// If `which` is `Some`, only that child is considered; otherwise, any
+/// child matches.
+pub fn find_child(parent: Pid, which: Option<Pid>) -> ChildStatus {
+    without_interrupts(|| {
+        let tasks = TASKS.lock();
+        let mut status = ChildStatus::NoChildren;
+        let children = tasks.values()
+                            .filter(|task| task.parent == Some(parent))
+                            .filter(|task| which.map(|pid| task.pid == pid)
+                                                .unwrap_or(true));
+        for child in children {
+            if child.state == TaskState::Zombie {
+                return ChildStatus::Exited(child.pid, child.exit_code)
+            }
+            status = ChildStatus::Running;
+        }
+        status
+    })
+}
+
+/// Remove the zombie task `pid` from the task table, and free everything
+/// it still owns.
+///
+/// The CPU time it and its own children used is added to its parent's
+/// children's CPU time.
+///
+/// # Panics
+/// + If `pid` is not a zombie.
+pub fn reap(pid: Pid) {
+    let mut task = without_interrupts(|| TASKS.lock().remove(&pid))
+                       .expect("tried to reap a task that doesn't exist!");
+    assert_eq!(task.state, TaskState::Zombie, "tried to reap a live task!");
+    pcid::release(&mut task);
+    let cpu_time = task.cpu_time_ns.load(Ordering::Relaxed)
+                 + task.children_cpu_time_ns.load(Ordering::Relaxed);
+    if let Some(parent) = task.parent.and_then(get) {
+        unsafe {
+            (*parent).children_cpu_time_ns
+                     .fetch_add(cpu_time, Ordering::Relaxed);
+        }
+    }
+    unsafe {
+        // the task has exited, so nothing can be using its address space
+        space::free_address_space( task.page_table, &mut frame::allocator()
+                                 , &mut swap::free_slot );
+    }
+    // dropping the task frees its kernel stack
+}
@@ -0,0 +1,249 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A round-robin scheduler.
+//!
+//! Runnable tasks wait their turn in a single FIFO run queue. There is no
+//! preemption (yet): tasks only give up the CPU by calling
+//! [`schedule`](fn.schedule.html), typically because they have blocked, so
+//! that's also when sleeping tasks are woken. When nothing is runnable, each
+//! CPU runs its idle task, which halts until an interrupt arrives.
+//!
+//! Each task's CPU time is added up as it's switched away from.
+//!
+//! Hardware threads of the same core (SMT siblings) can see each other's
+//! cache and execution unit use, so a CPU won't pick a task of another
+//! process than the one a sibling is running, and prefers one of the
+//! same process. Such tasks are left on the queue for a CPU whose siblings
+//! are idle.
+use alloc::vec_deque::VecDeque;
+use core::sync::atomic::Ordering;
+use cpu::interrupts::idt::Idt;
+use cpu::tsc;
+use spin::Mutex;
+
+use arch::{fpu, pcid, percpu, tls, topology};
+use arch::cpu::{sti_hlt, without_interrupts};
+use arch::smp::MAX_CPUS;
+use perf::{self, PerfEvent};
+use watchdog;
+use super::{Pid, Task, TaskState};
+use super::timer;
+use trace::{self, trace_event};
+
+lazy_static! {
+    /// PIDs of tasks that are waiting to run.
+    static ref RUN_QUEUE: Mutex<VecDeque<Pid>> = Mutex::new(VecDeque::new());
+}
+
+/// The process each CPU is running, by CPU number, or `None` while it's
+/// idle.
+static RUNNING: Mutex<[Option<Pid>; MAX_CPUS]>
+    = Mutex::new([None; MAX_CPUS]);
+
+/// Add the task `pid` to the back of the run queue.
+///
+/// This may be called from an interrupt handler.
+pub fn enqueue(pid: Pid) {
+    without_interrupts(|| RUN_QUEUE.lock().push_back(pid))
+}
+
+/// Give up the CPU, and switch to the next runnable task.
+///
+/// If the current task is still runnable, it goes to the back of the run
+/// queue; otherwise, it won't run again until something wakes it up. If
+/// there is nothing else to run, this returns immediately.
+///
+/// Tasks whose timers have gone off are woken first. If nothing at all is
+/// runnable, this CPU switches to its idle task until something is.
+pub fn schedule() {
+    let prev = unsafe { super::current() };
+    let idle = unsafe { percpu::current().idle_task };
+    let prev_is_idle = prev as *mut Task == idle;
+    if prev.state == TaskState::Runnable && !prev_is_idle {
+        enqueue(prev.pid)
+    }
+    watchdog::pet();
+    timer::tick();
+    let next = match next_runnable() {
+        Some(task) => task
+      , None if idle.is_null() => panic!("no runnable tasks!")
+      , None => idle
+    };
+    if unsafe { (*next).pid } == prev.pid { return }
+    unsafe { switch_to(prev, &mut *next) }
+}
+
+/// Returns the CPU time `task` has used, in nanoseconds.
+///
+/// If `task` is running on this CPU, that includes the time since it was
+/// switched to.
+pub fn cpu_time_ns(task: &Task) -> u64 {
+    let total = task.cpu_time_ns.load(Ordering::Relaxed);
+    let current = unsafe { percpu::current().current_task };
+    if current as *const Task == task as *const Task {
+        total + tsc::current_ns().saturating_sub(task.last_scheduled_ns)
+    } else {
+        total
+    }
+}
+
+/// Returns the process `task` is part of.
+///
+/// There are no threads yet, so every task is a process of its own.
+#[inline]
+fn process_of(task: &Task) -> Pid { task.pid }
+
+/// How well `task` would run beside the processes `running` on this CPU's
+/// SMT siblings: `Some(0)` if a sibling runs the same process, `Some(1)` if
+/// the siblings are idle, and `None` if a sibling runs another process.
+fn core_affinity(task: &Task, running: &[Option<Pid>]) -> Option<u8> {
+    let process = process_of(task);
+    running.iter().fold(Some(1), |best, sibling| match *sibling {
+        None => best
+      , Some(other) if other == process => best.map(|_| 0)
+      , Some(_) => None
+    })
+}
+
+/// Take the next runnable task this CPU may run off the run queue.
+///
+/// This is the first task in the queue that shares a process with one of
+/// this CPU's SMT siblings are running, or else the first that any of its
+/// siblings are idle for. Tasks that would have to run beside another
+/// process stay where they are.
+fn next_runnable() -> Option<*mut Task> {
+    let cpu = unsafe { percpu::current().cpu_id } as u8;
+    let running: [Option<Pid>; MAX_CPUS] = {
+        let all = RUNNING.lock();
+        let mut running = [None; MAX_CPUS];
+        for (slot, &sibling) in running.iter_mut()
+                                       .zip(topology::sibling_cpus(cpu)) {
+            *slot = all.get(sibling as usize).and_then(|&pid| pid);
+        }
+        running
+    };
+    without_interrupts(|| {
+        let mut queue = RUN_QUEUE.lock();
+        let mut best: Option<(usize, u8)> = None;
+        let mut i = 0;
+        while i < queue.len() {
+            match super::get(queue[i]) {
+                Some(task) if unsafe { (*task).state }
+                           == TaskState::Runnable => {
+                    match core_affinity(unsafe { &*task }, &running) {
+                        Some(rank) if best.map_or(true, |(_, b)| rank < b) =>
+                            best = Some((i, rank))
+                      , _ => {}
+                    }
+                    if best.map_or(false, |(_, rank)| rank == 0) { break }
+                    i += 1;
+                }
+                // skip over any tasks that have exited or blocked since
+                // they were queued
+              , _ => { queue.remove(i); }
+            }
+        }
+        best.and_then(|(i, _)| queue.remove(i))
+            .and_then(super::get)
+    })
+}
+
+/// Returns true if the run queue isn't empty.
+///
+/// The tasks on it may have blocked or exited since they were queued, so
+/// this is only a hint that `schedule` will find something to switch to.
+fn has_runnable() -> bool {
+    !without_interrupts(|| RUN_QUEUE.lock().is_empty())
+}
+
+/// Create this CPU's idle task.
+///
+/// # Safety
+/// + This must be called once on each CPU that schedules tasks, after task
+///   0 has been created.
+pub unsafe fn init_idle() {
+    let task = Task::kernel_thread(super::alloc_pid(), "idle", idle_task);
+    percpu::current().idle_task = super::insert(task);
+}
+
+/// The idle task, which runs when there's nothing else to.
+///
+/// It never goes on the run queue: `schedule` switches to it when the
+/// queue is empty, and it halts until an interrupt arrives, then looks at
+/// the queue again. `sti; hlt` enables interrupts and halts atomically, so
+/// an interrupt that makes a task runnable after the queue was checked
+/// still wakes the CPU, rather than being taken before the `hlt`.
+extern "C" fn idle_task() -> ! {
+    loop {
+        unsafe { Idt::disable_interrupts() }
+        // a timer may have gone off, or a `wake` arrived, since we last
+        // looked.
+        timer::tick();
+        if has_runnable() {
+            schedule();
+        } else {
+            watchdog::pet();
+            unsafe { sti_hlt() }
+        }
+    }
+}
+
+/// Idle forever, with interrupts enabled.
+///
+/// This is where the application processors end up once they have started:
+/// only the bootstrap processor runs tasks for now, so there's nothing else
+/// for them to do but answer IPIs, like TLB shootdowns.
+pub fn idle() -> ! {
+    loop {
+        unsafe { sti_hlt() }
+    }
+}
+
+/// Switch from `prev` to `next`.
+///
+/// If either is a kernel thread, `%fs` is pointed at `next`'s
+/// thread-local storage, or cleared for a task without any.
+///
+/// # Safety
+/// + `prev` must be the current task, and interrupts must be disabled.
+unsafe fn switch_to(prev: &mut Task, next: &mut Task) {
+    trace_event( trace::SCHED_SWITCH, prev.pid.0 as u64, next.pid.0 as u64
+               , 0);
+    perf::increment(PerfEvent::ContextSwitches);
+    let cpu = percpu::current();
+    let now = tsc::current_ns();
+    cpu.switch_time = now;
+    prev.cpu_time_ns.fetch_add( now.saturating_sub(prev.last_scheduled_ns)
+                              , Ordering::Relaxed);
+    next.last_scheduled_ns = now;
+    cpu.current_task = next as *mut Task;
+    if let Some(slot) = RUNNING.lock().get_mut(cpu.cpu_id as usize) {
+        let next_is_idle = next as *mut Task == cpu.idle_task;
+        *slot = if next_is_idle { None } else { Some(process_of(next)) };
+    }
+    if prev.tls.is_some() || next.tls.is_some() {
+        let task = next as *mut Task;
+        if let Some(ref mut tls) = next.tls {
+            tls.current = task;
+            tls.cpu_id = cpu.cpu_id as u8;
+        }
+        tls::install(next.tls.as_mut().map(|tls| &mut **tls));
+    }
+    // `syscall` finds the kernel stack in `kernel_rsp`; interrupts and
+    // exceptions from user mode find it in the TSS.
+    cpu.kernel_rsp = next.kernel_stack_top();
+    cpu.tables.set_rsp0(cpu.kernel_rsp);
+    cpu.stack_canary_addr = next.stack_canary_addr();
+    cpu.stack_canary = next.stack_canary;
+    if prev.page_table != next.page_table {
+        pcid::switch(next);
+    }
+    fpu::switch(prev, next);
+    prev.context.switch_to(&next.context);
+}
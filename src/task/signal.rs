@@ -0,0 +1,225 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Signals.
+//!
+//! Each task has a handler address for each of the 64 signals, and a mask
+//! of the signals sent to it that haven't been delivered yet. Signals are
+//! delivered on the way back to user mode from a system call: the lowest
+//! pending signal is taken, and if it has a handler, a [`SigFrame`] is
+//! pushed on the user stack and the task "returns" into the handler.
+//!
+//! ```text
+//!   original %rsp ─▶ ...
+//!                    (128-byte red zone)
+//!                    SigFrame          the interrupted registers
+//!   handler %rsp ─▶  restorer          the handler's return address
+//! ```
+//!
+//! The handler returns to the restorer given to `rt_sigaction`, which
+//! calls `rt_sigreturn`, which puts the registers back from the frame.
+//! This is what the C library does on Linux, so its `signal()` works.
+//!
+//! Signals aren't masked while their handler runs, blocked system calls
+//! aren't interrupted, and a task that never makes a system call never
+//! sees its signals.
+//!
+//! [`SigFrame`]: struct.SigFrame.html
+use core::mem;
+use core::sync::atomic::Ordering;
+use memory::VAddr;
+
+use arch::syscall::SyscallFrame;
+use mm::is_user_range;
+use mm::user::{copy_from_user, copy_to_user};
+use super::{wait, Pid, Task};
+
+/// The number of signals.
+pub const NSIG: usize = 64;
+
+pub const SIGKILL: usize = 9;
+pub const SIGSEGV: usize = 11;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+/// The handler address that means "take the default action".
+pub const SIG_DFL: usize = 0;
+/// The handler address that means "ignore the signal".
+pub const SIG_IGN: usize = 1;
+
+/// The System V ABI lets functions use the 128 bytes below `%rsp`, so the
+/// frame has to go below that.
+const RED_ZONE: usize = 128;
+
+/// The `%rflags` bits that user code can change through `rt_sigreturn`:
+/// the arithmetic flags and the direction flag.
+const USER_RFLAGS: u64 = 0xcd5;
+/// `%rflags`: interrupts are enabled. This is always set in user mode.
+const RFLAGS_IF: u64 = 1 << 9;
+/// `%rflags`: bit 1 is reserved, and always set.
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+/// What's pushed on the user stack when a signal is delivered.
+///
+/// As well as where to return to, this keeps the system call's result and
+/// the argument registers, which the handler is free to clobber.
+/// Callee-saved registers are preserved by the handler itself.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SigFrame { pub saved_rip: u64
+                    , pub saved_rflags: u64
+                    , pub saved_rsp: u64
+                    , pub signum: u64
+                    , /// The result of the interrupted system call
+                      pub saved_rax: u64
+                    , pub saved_rdi: u64
+                    , pub saved_rsi: u64
+                    , pub saved_rdx: u64
+                    , pub saved_r10: u64
+                    , pub saved_r8: u64
+                    , pub saved_r9: u64
+                    }
+
+/// Returns true if `signum` is a signal number.
+#[inline]
+pub fn is_valid(signum: usize) -> bool { signum >= 1 && signum <= NSIG }
+
+/// Returns true if a signal `signum` with no handler is ignored, rather
+/// than terminating the task.
+#[inline]
+fn ignored_by_default(signum: usize) -> bool {
+    match signum {
+        SIGCHLD | SIGCONT | SIGURG | SIGWINCH => true
+      , _ => false
+    }
+}
+
+/// Send signal `signum` to `task`.
+///
+/// The signal is delivered the next time the task returns from a system
+/// call. If it's blocked, it's woken up, so that it can see a `SIGKILL`
+/// once whatever it's waiting for has happened.
+///
+/// # Panics
+/// + If `signum` isn't a signal number.
+pub fn task_send_signal(task: &Task, signum: usize) {
+    assert!(is_valid(signum), "no such signal {}", signum);
+    task.pending_signals.fetch_or(1 << (signum - 1), Ordering::SeqCst);
+    wait::wake(task.pid);
+}
+
+/// Send `SIGKILL` to the task `pid`.
+///
+/// Returns false if there's no such task.
+pub fn task_kill(pid: Pid) -> bool {
+    match super::get(pid) {
+        Some(task) => {
+            task_send_signal(unsafe { &*task }, SIGKILL);
+            true
+        }
+      , None => false
+    }
+}
+
+/// Terminate the current task for signal `signum`, with the status a
+/// shell would report.
+fn die(signum: usize) -> ! {
+    super::exit(128 + signum as u8)
+}
+
+/// Deliver the current task's lowest pending signal, if it has one.
+///
+/// `frame` holds the registers the task will return to user mode with, and
+/// `result` is the return value of the system call it's returning from.
+/// If the signal has a handler, `frame` is changed to enter it.
+pub fn deliver_pending(frame: &mut SyscallFrame, result: i64) {
+    let task = unsafe { super::current() };
+    loop {
+        let pending = task.pending_signals.load(Ordering::SeqCst);
+        if pending == 0 { return }
+        let bit = pending.trailing_zeros() as usize;
+        task.pending_signals.fetch_and(!(1 << bit), Ordering::SeqCst);
+        let signum = bit + 1;
+        if signum == SIGKILL { die(signum) }
+
+        let handler = task.signal_handlers[bit].as_usize();
+        match handler {
+            SIG_IGN => continue
+          , SIG_DFL if ignored_by_default(signum) => continue
+          , SIG_DFL => die(signum)
+          , _ => {}
+        }
+        let sig_frame = SigFrame { saved_rip: frame.rip
+                                 , saved_rflags: frame.rflags
+                                 , saved_rsp: frame.rsp
+                                 , signum: signum as u64
+                                 , saved_rax: result as u64
+                                 , saved_rdi: frame.rdi
+                                 , saved_rsi: frame.rsi
+                                 , saved_rdx: frame.rdx
+                                 , saved_r10: frame.r10
+                                 , saved_r8: frame.r8
+                                 , saved_r9: frame.r9
+                                 };
+        // the frame is 16-byte aligned, so that the handler's stack is
+        // aligned as a call would leave it once the return address is
+        // pushed.
+        let frame_addr = (frame.rsp as usize)
+            .wrapping_sub(RED_ZONE + mem::size_of::<SigFrame>()) & !0xf;
+        let ret_addr = frame_addr.wrapping_sub(mem::size_of::<u64>());
+        let restorer = task.signal_restorer.as_usize() as u64;
+        let pushed = unsafe {
+            copy_to_user( VAddr::from(frame_addr)
+                        , &sig_frame as *const SigFrame as *const u8
+                        , mem::size_of::<SigFrame>())
+                .and_then(|_| copy_to_user( VAddr::from(ret_addr)
+                                          , &restorer as *const u64
+                                                      as *const u8
+                                          , mem::size_of::<u64>()))
+        };
+        // like Linux, a task whose stack can't take the frame gets a
+        // SIGSEGV it can't catch.
+        if pushed.is_err() { die(SIGSEGV) }
+
+        frame.rip = handler as u64;
+        frame.rsp = ret_addr as u64;
+        frame.rdi = signum as u64;
+        return
+    }
+}
+
+/// Put back the registers saved in the `SigFrame` at the top of the user
+/// stack in `frame`, returning the interrupted system call's result.
+pub fn sigreturn(frame: &mut SyscallFrame) -> i64 {
+    let mut sig_frame = SigFrame::default();
+    let copied = unsafe {
+        copy_from_user( &mut sig_frame as *mut SigFrame as *mut u8
+                      , VAddr::from(frame.rsp as usize)
+                      , mem::size_of::<SigFrame>())
+    };
+    // `sysret` to a non-canonical address faults in the kernel, so the
+    // saved `%rip` has to be checked.
+    if copied.is_err()
+        || !is_user_range(VAddr::from(sig_frame.saved_rip as usize), 1) {
+        die(SIGSEGV)
+    }
+    frame.rip = sig_frame.saved_rip;
+    frame.rflags = (sig_frame.saved_rflags & USER_RFLAGS)
+                 | RFLAGS_IF | RFLAGS_RESERVED;
+    frame.rsp = sig_frame.saved_rsp;
+    frame.rdi = sig_frame.saved_rdi;
+    frame.rsi = sig_frame.saved_rsi;
+    frame.rdx = sig_frame.saved_rdx;
+    frame.r10 = sig_frame.saved_r10;
+    frame.r8 = sig_frame.saved_r8;
+    frame.r9 = sig_frame.saved_r9;
+    sig_frame.saved_rax as i64
+}
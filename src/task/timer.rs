@@ -0,0 +1,197 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Sleeping until a deadline.
+//!
+//! Sleeping tasks are kept in a [`TimerWheel`](struct.TimerWheel.html): a
+//! ring of `SLOTS` slots, each covering `SLOT_NS` nanoseconds, which a
+//! cursor goes round as time passes. A task with the deadline `d` goes in
+//! slot `(d / SLOT_NS) % SLOTS`, so adding a timer takes constant time, and
+//! expiring them only looks at the slots the cursor has passed. Deadlines
+//! more than one revolution away wait in an overflow list, and are moved
+//! into the wheel once the cursor comes round far enough.
+//!
+//! The wheel is advanced by [`tick`](fn.tick.html), which the scheduler
+//! calls whenever it picks a task to run. Time is measured by the TSC,
+//! in nanoseconds since boot.
+use alloc::vec::Vec;
+use core::{cmp, mem};
+use cpu::tsc;
+use spin::Mutex;
+
+use super::{Pid, TaskState};
+use super::{sched, wait};
+
+/// The number of slots in the timer wheel.
+pub const SLOTS: usize = 1024;
+/// The number of nanoseconds each slot covers.
+pub const SLOT_NS: u64 = 1_000_000;
+
+/// A task waiting for a deadline.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Timer { pid: Pid
+             , deadline_ns: u64
+             }
+
+/// A hashed timer wheel.
+pub struct TimerWheel { slots: Vec<Vec<Timer>>
+                      , /// Timers too far away to go in a slot yet
+                        overflow: Vec<Timer>
+                      , /// The number of the slot the cursor is on,
+                        /// counting from boot rather than modulo `SLOTS`
+                        cursor: u64
+                      , /// The number of timers in the wheel
+                        len: usize
+                      }
+
+impl TimerWheel {
+    /// Returns an empty timer wheel, with its cursor at `now_ns`.
+    pub fn new(now_ns: u64) -> Self {
+        TimerWheel { slots: (0 .. SLOTS).map(|_| Vec::new()).collect()
+                   , overflow: Vec::new()
+                   , cursor: now_ns / SLOT_NS
+                   , len: 0
+                   }
+    }
+
+    /// Returns the number of timers in the wheel.
+    #[inline] pub fn len(&self) -> usize { self.len }
+
+    /// Put `timer` in its slot, or in the overflow list if it's more than
+    /// one revolution away.
+    fn place(&mut self, timer: Timer) {
+        // a deadline that's already passed goes off on the next tick.
+        let slot = cmp::max(timer.deadline_ns / SLOT_NS, self.cursor);
+        if slot - self.cursor < SLOTS as u64 {
+            self.slots[slot as usize % SLOTS].push(timer)
+        } else {
+            self.overflow.push(timer)
+        }
+    }
+
+    /// Wake the task `pid` once the time is `deadline_ns` or later.
+    pub fn insert(&mut self, pid: Pid, deadline_ns: u64) {
+        self.place(Timer { pid: pid, deadline_ns: deadline_ns });
+        self.len += 1;
+    }
+
+    /// Remove `pid`'s timer for `deadline_ns`, if it hasn't gone off yet.
+    ///
+    /// Returns true if the timer was removed.
+    pub fn remove(&mut self, pid: Pid, deadline_ns: u64) -> bool {
+        let timer = Timer { pid: pid, deadline_ns: deadline_ns };
+        let slot = cmp::max(deadline_ns / SLOT_NS, self.cursor) as usize;
+        // a timer may still be in the overflow list after the cursor has
+        // come close enough for it to have a slot.
+        let removed = remove_timer(&mut self.slots[slot % SLOTS], timer)
+                   || remove_timer(&mut self.overflow, timer);
+        if removed { self.len -= 1 }
+        removed
+    }
+
+    /// Move the cursor up to `now_ns`, and push the PID of every task
+    /// whose deadline has passed onto `expired`.
+    pub fn advance(&mut self, now_ns: u64, expired: &mut Vec<Pid>) {
+        let target = now_ns / SLOT_NS;
+        if target < self.cursor { return }
+        let already_expired = expired.len();
+        // every slot behind the target is due in full; if the cursor is a
+        // whole revolution or more behind, that's every slot.
+        let behind = cmp::min(target - self.cursor, SLOTS as u64);
+        let mut revolved = target - self.cursor >= SLOTS as u64;
+        for i in 0 .. behind {
+            let slot = (self.cursor + i) as usize % SLOTS;
+            expired.extend(self.slots[slot].drain(..).map(|t| t.pid));
+            if slot == SLOTS - 1 { revolved = true }
+        }
+        self.cursor = target;
+
+        let slot = &mut self.slots[target as usize % SLOTS];
+        let mut i = 0;
+        while i < slot.len() {
+            if slot[i].deadline_ns <= now_ns {
+                expired.push(slot.swap_remove(i).pid);
+            } else {
+                i += 1;
+            }
+        }
+
+        if revolved {
+            let overflow = mem::replace(&mut self.overflow, Vec::new());
+            for timer in overflow {
+                if timer.deadline_ns <= now_ns {
+                    expired.push(timer.pid)
+                } else {
+                    self.place(timer)
+                }
+            }
+        }
+        self.len -= expired.len() - already_expired;
+    }
+}
+
+/// Remove `timer` from `list`, returning true if it was there.
+#[inline]
+fn remove_timer(list: &mut Vec<Timer>, timer: Timer) -> bool {
+    match list.iter().position(|t| *t == timer) {
+        Some(i) => { list.swap_remove(i); true }
+      , None => false
+    }
+}
+
+lazy_static! {
+    static ref TIMERS: Mutex<TimerWheel>
+        = Mutex::new(TimerWheel::new(tsc::current_ns()));
+}
+
+/// Returns the current time, in nanoseconds since boot.
+#[inline]
+pub fn now_ns() -> u64 { tsc::current_ns() }
+
+/// Returns true if any task is waiting for a timer.
+pub fn pending() -> bool { TIMERS.lock().len() > 0 }
+
+/// Wake every task whose deadline has passed.
+pub fn tick() {
+    let mut expired = Vec::new();
+    TIMERS.lock().advance(now_ns(), &mut expired);
+    for pid in expired {
+        wait::wake(pid);
+    }
+}
+
+/// Arm a timer that wakes the current task at `deadline_ns`, without
+/// blocking it.
+///
+/// This is for tasks that are about to block on something else as well,
+/// and want to give up waiting at the deadline. The timer must be
+/// [`cancel`](fn.cancel.html)led if the task is woken by something else.
+pub fn arm(deadline_ns: u64) {
+    let pid = unsafe { super::current() }.pid;
+    TIMERS.lock().insert(pid, deadline_ns);
+}
+
+/// Cancel the current task's timer for `deadline_ns`.
+///
+/// Returns false if it had already gone off.
+pub fn cancel(deadline_ns: u64) -> bool {
+    let pid = unsafe { super::current() }.pid;
+    TIMERS.lock().remove(pid, deadline_ns)
+}
+
+/// Block the current task until `deadline_ns`.
+pub fn sleep_until(deadline_ns: u64) {
+    while now_ns() < deadline_ns {
+        let task = unsafe { super::current() };
+        task.state = TaskState::Blocked;
+        arm(deadline_ns);
+        sched::schedule();
+        // if something else woke us, the timer is still in the wheel.
+        cancel(deadline_ns);
+    }
+}
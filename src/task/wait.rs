@@ -0,0 +1,153 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Wait queues and semaphores.
+//!
+//! A [`WaitQueue`](struct.WaitQueue.html) is a list of tasks blocked until
+//! some event happens. Whoever causes the event wakes them up again.
+//!
+//! Tasks are never preempted in the kernel, so there's no window between
+//! checking a condition and going to sleep on it in which a wakeup can be
+//! lost. Interrupt handlers can still run in that window, though, so events
+//! signalled from interrupt context should go through a
+//! [`Semaphore`](struct.Semaphore.html) instead.
+use alloc::vec_deque::VecDeque;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cpu::interrupts::idt::Idt;
+use spin::Mutex;
+
+use arch::cpu::without_interrupts;
+use phase::{require_phase, KernelPhase};
+
+use super::{Pid, TaskState};
+use super::sched;
+
+/// A queue of tasks waiting for an event.
+#[derive(Debug)]
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<Pid>>
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        WaitQueue { waiters: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Block the current task until it is woken up.
+    pub fn sleep(&self) {
+        require_phase(KernelPhase::SchedulerInit);
+        let task = unsafe { super::current() };
+        task.state = TaskState::Blocked;
+        without_interrupts(|| self.waiters.lock().push_back(task.pid));
+        sched::schedule();
+    }
+
+    /// Block the current task until `condition` returns true.
+    pub fn sleep_until<F>(&self, mut condition: F)
+    where F: FnMut() -> bool {
+        while !condition() {
+            self.sleep();
+        }
+    }
+
+    /// Wake up the task that has been waiting longest, if any.
+    ///
+    /// Returns true if a task was woken. This may be called from an
+    /// interrupt handler.
+    pub fn wake_one(&self) -> bool {
+        // a task that has since exited or been woken by something else
+        // doesn't count
+        loop {
+            let next = without_interrupts(|| self.waiters.lock().pop_front());
+            match next {
+                Some(pid) => if wake(pid) { return true }
+              , None => return false
+            }
+        }
+    }
+
+    /// Wake up every waiting task.
+    pub fn wake_all(&self) {
+        while self.wake_one() {}
+    }
+}
+
+/// A counting semaphore.
+///
+/// `up` may be called from an interrupt handler, which makes a semaphore
+/// the way for a task to wait for an interrupt.
+#[derive(Debug)]
+pub struct Semaphore { count: AtomicUsize
+                     , waiters: WaitQueue
+                     }
+
+impl Semaphore {
+    /// Returns a new semaphore with `count` units available.
+    pub fn new(count: usize) -> Self {
+        Semaphore { count: AtomicUsize::new(count), waiters: WaitQueue::new() }
+    }
+
+    /// Take a unit if one is available, without blocking.
+    ///
+    /// Returns true if a unit was taken.
+    pub fn try_down(&self) -> bool {
+        let mut count = self.count.load(Ordering::SeqCst);
+        while count > 0 {
+            match self.count.compare_exchange( count, count - 1
+                                             , Ordering::SeqCst
+                                             , Ordering::SeqCst) {
+                Ok(_) => return true
+              , Err(actual) => count = actual
+            }
+        }
+        false
+    }
+
+    /// Take a unit, blocking the current task until one is available.
+    ///
+    /// This must be called with interrupts enabled, and leaves them
+    /// enabled.
+    pub fn down(&self) {
+        loop {
+            // keep interrupts off between checking the count and going to
+            // sleep, so an `up` from an interrupt handler can't slip in
+            // between and be lost.
+            unsafe { Idt::disable_interrupts(); }
+            let taken = self.try_down();
+            if !taken { self.waiters.sleep(); }
+            unsafe { Idt::enable_interrupts(); }
+            if taken { return }
+        }
+    }
+
+    /// Release a unit, waking up a task waiting for one.
+    pub fn up(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+}
+
+/// Make the task `pid` runnable, if it is blocked.
+///
+/// Returns true if the task was woken. This may be called from an interrupt
+/// handler, so the task table and run queue are only ever locked with
+/// interrupts disabled.
+pub fn wake(pid: Pid) -> bool {
+    match super::get(pid) {
+        Some(task) => unsafe {
+            if (*task).state == TaskState::Blocked {
+                (*task).state = TaskState::Runnable;
+                sched::enqueue(pid);
+                true
+            } else {
+                false
+            }
+        }
+      , None => false
+    }
+}
@@ -0,0 +1,211 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Work queues, for deferring work out of interrupt handlers.
+//!
+//! An interrupt handler should do as little as it can. Anything that takes
+//! longer, or needs to block, can be [`submit`](fn.submit.html)ted as a
+//! work item instead: a function and a pointer to pass it, which a kernel
+//! thread runs later, in task context.
+//!
+//! Work items are kept on an intrusive list, and the worker thread waits on
+//! a [`Semaphore`](../wait/struct.Semaphore.html), which unlike a bare wait
+//! queue can be signalled from an interrupt handler.
+//!
+//! The heap's lock doesn't disable interrupts, so an interrupt handler that
+//! allocated could deadlock against the code it interrupted. Instead, each
+//! queue allocates `QUEUE_SIZE` items up front, and submitting takes one
+//! from its free list.
+use alloc::boxed::Box;
+use core::ptr;
+use spin::Mutex;
+use sos_intrusive::{List, NonNullOwned, RawLink};
+use sos_intrusive::list::Node;
+
+use arch::cpu::without_interrupts;
+use super::Pid;
+use super::wait::Semaphore;
+
+/// A function run by a work queue, with the pointer it was submitted with.
+pub type WorkFn = fn(*mut ());
+
+/// The number of work items a queue can hold at once.
+pub const QUEUE_SIZE: usize = 256;
+
+/// Define a [`WorkFn`](type.WorkFn.html) that takes its data as a `&mut T`.
+///
+/// ```ignore
+/// work_queue_item! {
+///     fn complete(request: &mut Request) { request.finish() }
+/// }
+/// workqueue::submit(complete, request as *mut Request as *mut ());
+/// ```
+#[macro_export]
+macro_rules! work_queue_item {
+    ( $(#[$attr:meta])*
+      fn $name:ident($arg:ident: &mut $ty:ty) $body:block ) => {
+        $(#[$attr])*
+        fn $name(data: *mut ()) {
+            let $arg: &mut $ty = unsafe { &mut *(data as *mut $ty) };
+            $body
+        }
+    }
+}
+
+/// A unit of deferred work.
+pub struct WorkItem { func: WorkFn
+                    , data: *mut ()
+                    , next: RawLink<WorkItem>
+                    , prev: RawLink<WorkItem>
+                    }
+
+// whoever submits a work item promises that its data may be used from the
+// worker thread.
+unsafe impl Send for WorkItem {}
+
+/// The function in a free work item.
+fn nothing(_: *mut ()) {}
+
+impl Node for WorkItem {
+    #[inline] fn prev(&self) -> &RawLink<WorkItem> {
+        &self.prev
+    }
+    #[inline] fn next(&self) -> &RawLink<WorkItem> {
+        &self.next
+    }
+    #[inline] fn prev_mut(&mut self) -> &mut RawLink<WorkItem> {
+        &mut self.prev
+    }
+    #[inline] fn next_mut(&mut self) -> &mut RawLink<WorkItem> {
+        &mut self.next
+    }
+}
+
+type ItemList = List<NonNullOwned<WorkItem>, WorkItem>;
+
+/// A queue's work items: the ones waiting to run, and the free ones.
+struct Items { queued: ItemList
+             , free: ItemList
+             }
+
+/// A queue of work items, run in order by a worker thread.
+pub struct WorkQueue { items: Mutex<Items>
+                     , /// One unit for every item submitted
+                       pending: Semaphore
+                     }
+
+impl WorkQueue {
+    /// Returns a new queue, with its `QUEUE_SIZE` items allocated.
+    ///
+    /// This allocates, so it mustn't be called from an interrupt handler.
+    pub fn new() -> Self {
+        let mut free = List::new();
+        for _ in 0 .. QUEUE_SIZE {
+            let item = Box::new(WorkItem { func: nothing
+                                         , data: ptr::null_mut()
+                                         , next: RawLink::none()
+                                         , prev: RawLink::none()
+                                         });
+            free.push_back(unsafe {
+                NonNullOwned::from_raw(Box::into_raw(item))
+            });
+        }
+        WorkQueue { items: Mutex::new(Items { queued: List::new()
+                                            , free: free
+                                            })
+                  , pending: Semaphore::new(0)
+                  }
+    }
+
+    /// Call `func(data)` from the worker thread.
+    ///
+    /// This may be called from an interrupt handler. Returns false, and
+    /// doesn't queue the work, if `QUEUE_SIZE` items are already waiting.
+    pub fn submit(&self, func: WorkFn, data: *mut ()) -> bool {
+        let queued = without_interrupts(|| {
+            let mut items = self.items.lock();
+            match items.free.pop_front() {
+                Some(mut item) => {
+                    {
+                        let item = item.get_mut();
+                        item.func = func;
+                        item.data = data;
+                    }
+                    items.queued.push_back(item);
+                    true
+                }
+              , None => false
+            }
+        });
+        if queued { self.pending.up() }
+        queued
+    }
+
+    /// Take the oldest item off the queue, returning its function and data,
+    /// and put the item back on the free list.
+    fn dequeue(&self) -> Option<(WorkFn, *mut ())> {
+        without_interrupts(|| {
+            let mut items = self.items.lock();
+            items.queued.pop_front().map(|item| {
+                let work = (item.get().func, item.get().data);
+                items.free.push_back(item);
+                work
+            })
+        })
+    }
+
+    /// Run items as they are submitted, forever.
+    ///
+    /// This is the body of the queue's worker thread.
+    pub fn run(&self) -> ! {
+        loop {
+            self.pending.down();
+            // a burst of submissions may all be run on one wakeup, leaving
+            // the semaphore with units for items that are already gone.
+            while let Some((func, data)) = self.dequeue() {
+                func(data);
+            }
+        }
+    }
+}
+
+impl Drop for WorkQueue {
+    fn drop(&mut self) {
+        let mut items = self.items.lock();
+        while let Some(item) = items.free.pop_front() {
+            drop(unsafe { Box::from_raw(item.as_ptr()) });
+        }
+        while let Some(item) = items.queued.pop_front() {
+            drop(unsafe { Box::from_raw(item.as_ptr()) });
+        }
+    }
+}
+
+lazy_static! {
+    /// The kernel's shared work queue.
+    static ref SYSTEM: WorkQueue = WorkQueue::new();
+}
+
+/// Call `func(data)` from the kernel's worker thread.
+///
+/// This may be called from an interrupt handler, once the worker thread has
+/// been started. Returns false if the work couldn't be queued.
+#[inline]
+pub fn submit(func: WorkFn, data: *mut ()) -> bool {
+    SYSTEM.submit(func, data)
+}
+
+extern "C" fn worker() -> ! { SYSTEM.run() }
+
+/// Start the kernel's worker thread.
+pub fn init() -> Pid {
+    // the first use of `SYSTEM` allocates its items, so it mustn't be in
+    // an interrupt handler.
+    ::lazy_static::initialize(&SYSTEM);
+    super::spawn_kernel("kworker", worker)
+}
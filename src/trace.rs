@@ -0,0 +1,165 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Kernel event tracing.
+//!
+//! With the `kernel-trace` feature, interesting kernel events (context
+//! switches, page faults, system calls, interrupts, frame allocations, and
+//! OOM kills) are recorded in [`RING`], which keeps the most recent
+//! `RING_SIZE` of them. The debug shell's `log-dump` command prints them.
+//!
+//! Without the feature, [`trace_event`] does nothing, so calls to it can
+//! be left in hot paths.
+//!
+//! [`RING`]: struct.RING.html
+//! [`trace_event`]: fn.trace_event.html
+use core::fmt;
+
+/// A context switch. `data`: the old task's PID, the new task's PID.
+pub const SCHED_SWITCH: u16 = 1;
+/// A page fault. `data`: the faulting address, the error code, `%rip`.
+pub const PAGE_FAULT: u16 = 2;
+/// Entry to a system call. `data`: the call number, the first two
+/// arguments.
+pub const SYSCALL_ENTER: u16 = 3;
+/// Return from a system call. `data`: the call number, the result.
+pub const SYSCALL_EXIT: u16 = 4;
+/// A hardware interrupt. `data`: the IRQ number.
+pub const IRQ: u16 = 5;
+/// Frames were allocated. `data`: the first frame's address, the count.
+pub const ALLOC_FRAME: u16 = 6;
+/// Frames were freed. `data`: the first frame's address, the count.
+pub const FREE_FRAME: u16 = 7;
+/// The OOM killer killed a task. `data`: its PID, the bytes it had mapped,
+/// its `oom_score_adj`.
+pub const OOM_KILL: u16 = 8;
+
+/// The number of events the ring holds.
+pub const RING_SIZE: usize = 65536;
+
+/// A recorded kernel event.
+#[derive(Copy, Clone, Debug)]
+pub struct TraceEvent { /// When it happened, in nanoseconds since boot
+                        pub ts_ns: u64
+                      , /// What happened
+                        pub kind: u16
+                      , /// The CPU it happened on
+                        pub cpu: u8
+                      , /// The task that was running
+                        pub pid: u16
+                      , /// Depends on `kind`
+                        pub data: [u64; 3]
+                      }
+
+/// Returns the name of the event kind `kind`.
+pub fn kind_name(kind: u16) -> &'static str {
+    match kind {
+        SCHED_SWITCH => "sched_switch"
+      , PAGE_FAULT => "page_fault"
+      , SYSCALL_ENTER => "syscall_enter"
+      , SYSCALL_EXIT => "syscall_exit"
+      , IRQ => "irq"
+      , ALLOC_FRAME => "alloc_frame"
+      , FREE_FRAME => "free_frame"
+      , OOM_KILL => "oom_kill"
+      , _ => "unknown"
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "[{:>16}] cpu {} pid {:<5} {:<14} {:#x} {:#x} {:#x}"
+              , self.ts_ns, self.cpu, self.pid, kind_name(self.kind)
+              , self.data[0], self.data[1], self.data[2])
+    }
+}
+
+#[cfg(feature = "kernel-trace")]
+pub use self::imp::*;
+
+#[cfg(feature = "kernel-trace")]
+mod imp {
+    use core::fmt;
+    use spin::Mutex;
+    use util::ring::RingBuffer;
+
+    use arch::cpu::without_interrupts;
+    use arch::percpu;
+    use task::timer;
+    use super::{RING_SIZE, TraceEvent};
+
+    /// An all-zero event, so that the ring's storage can go in `.bss`.
+    const NO_EVENT: TraceEvent
+        = TraceEvent { ts_ns: 0, kind: 0, cpu: 0, pid: 0, data: [0; 3] };
+
+    /// The ring of recent events.
+    ///
+    /// Interrupt handlers record events too, so this must only be locked
+    /// with interrupts disabled.
+    pub static RING: Mutex<RingBuffer<[TraceEvent; RING_SIZE]>>
+        = Mutex::new(RingBuffer::with_storage([NO_EVENT; RING_SIZE]));
+
+    /// Record an event of kind `kind`, dropping the oldest event if the
+    /// ring is full.
+    pub fn trace_event(kind: u16, d0: u64, d1: u64, d2: u64) {
+        let (cpu, pid) = unsafe {
+            let cpu = percpu::current();
+            let pid = cpu.current_task.as_ref().map_or(0, |task| task.pid.0);
+            (cpu.cpu_id as u8, pid as u16)
+        };
+        let event = TraceEvent { ts_ns: timer::now_ns()
+                               , kind: kind
+                               , cpu: cpu
+                               , pid: pid
+                               , data: [d0, d1, d2]
+                               };
+        without_interrupts(|| {
+            let mut ring = RING.lock();
+            if ring.is_full() { ring.pop(); }
+            let _ = ring.push(event);
+        })
+    }
+
+    /// Call `f` on each event in the ring, oldest first.
+    ///
+    /// The ring is copied out a chunk at a time, so that `f` can take as
+    /// long as it likes without holding it up. Events recorded meanwhile
+    /// push older ones off the front, so a few may be skipped or repeated,
+    /// but this stops after as many events as were in the ring to begin
+    /// with.
+    pub fn for_each_event<F>(mut f: F) -> fmt::Result
+    where F: FnMut(&TraceEvent) -> fmt::Result {
+        const CHUNK: usize = 64;
+        let mut chunk = [NO_EVENT; CHUNK];
+        let total = without_interrupts(|| RING.lock().len());
+        let mut next = 0;
+        while next < total {
+            let n = without_interrupts(|| {
+                let ring = RING.lock();
+                let mut n = 0;
+                while n < CHUNK && next + n < total {
+                    match ring.get(next + n) {
+                        Some(event) => chunk[n] = *event
+                      , None => break
+                    }
+                    n += 1;
+                }
+                n
+            });
+            if n == 0 { break }
+            for event in &chunk[..n] { f(event)? }
+            next += n;
+        }
+        Ok(())
+    }
+}
+
+/// Record an event. Tracing is compiled out, so this does nothing.
+#[cfg(not(feature = "kernel-trace"))]
+#[inline(always)]
+pub fn trace_event(_kind: u16, _d0: u64, _d1: u64, _d2: u64) { }
@@ -0,0 +1,76 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A software watchdog, for noticing when the kernel has stopped
+//! scheduling.
+//!
+//! The scheduler [`pet`](fn.pet.html)s the watchdog every time it looks for
+//! a task to run. Every `CHECK_INTERVAL_NS`, the HPET interrupts whatever
+//! is running, and if the watchdog hasn't been petted for `TIMEOUT_NS`,
+//! something is spinning without ever giving up the CPU, so we say where
+//! it was and panic.
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::u64;
+use cpu::context::InterruptFrame;
+use cpu::tsc;
+
+use arch::{hpet, percpu};
+
+/// How often the HPET checks on the watchdog.
+pub const CHECK_INTERVAL_NS: u64 = 5_000_000_000;
+/// How long the watchdog may go without being petted.
+pub const TIMEOUT_NS: u64 = 10_000_000_000;
+
+/// When the watchdog was last petted, in nanoseconds since boot, or
+/// `u64::MAX` if it's disabled.
+static LAST_PET_NS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Start the watchdog.
+pub fn init() -> Result<(), &'static str> {
+    pet();
+    hpet::every(CHECK_INTERVAL_NS, check)
+}
+
+/// Tell the watchdog the kernel is still making progress.
+#[inline]
+pub fn pet() {
+    LAST_PET_NS.store(tsc::current_ns(), Ordering::Relaxed);
+}
+
+/// Stop the watchdog until it's next petted, before doing something that
+/// legitimately takes a long time without scheduling.
+#[inline]
+pub fn disable() {
+    LAST_PET_NS.store(u64::MAX, Ordering::Relaxed);
+}
+
+/// Called from the HPET interrupt: bark if the watchdog is stale.
+fn check(frame: &InterruptFrame) {
+    let last = LAST_PET_NS.load(Ordering::Relaxed);
+    if last == u64::MAX { return }
+    if tsc::current_ns().saturating_sub(last) > TIMEOUT_NS {
+        watchdog_bark(frame)
+    }
+}
+
+/// Report where the CPU was stuck, and panic.
+fn watchdog_bark(frame: &InterruptFrame) -> ! {
+    // don't bark again while we're panicking.
+    disable();
+    let cpu = unsafe { percpu::current() };
+    error!( "watchdog: CPU {} hasn't scheduled for over {} ms"
+          , cpu.cpu_id, TIMEOUT_NS / 1_000_000);
+    error!("watchdog: stuck at {:?}", frame);
+    if cpu.current_task.is_null() {
+        error!("watchdog: no task was running");
+    } else {
+        let task = unsafe { &*cpu.current_task };
+        error!("watchdog: the current task is {} ({})", task.pid, task.name);
+    }
+    panic!("watchdog timeout")
+}
@@ -0,0 +1,72 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Formatting into fixed-size buffers.
+//!
+//! This is for code paths that need to format text but can't (or shouldn't)
+//! touch the heap, such as the logger or interrupt handlers.
+use core::{fmt, str};
+
+/// A `fmt::Write` implementation that writes into a borrowed byte slice.
+///
+/// Output that doesn't fit in the buffer is silently truncated, rather than
+/// returning an error, so that a long message still produces *something*.
+pub struct BufWriter<'a> { buf: &'a mut [u8]
+                         , pos: usize
+                         }
+
+impl<'a> BufWriter<'a> {
+    /// Construct a new `BufWriter` writing into `buf`.
+    #[inline]
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        BufWriter { buf: buf, pos: 0 }
+    }
+
+    /// Returns the bytes written so far.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.pos]
+    }
+
+    /// Returns the text written so far.
+    ///
+    /// If truncation split a multi-byte character, the partial character
+    /// is dropped.
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        let bytes = self.as_bytes();
+        match str::from_utf8(bytes) {
+            Ok(s) => s
+          , Err(e) => unsafe {
+                str::from_utf8_unchecked(&bytes[..e.valid_up_to()])
+            }
+        }
+    }
+
+    /// Returns the number of bytes written so far.
+    #[inline] pub fn len(&self) -> usize { self.pos }
+
+    /// Returns true if nothing has been written yet.
+    #[inline] pub fn is_empty(&self) -> bool { self.pos == 0 }
+
+    /// Returns true if output has been truncated because the buffer is full.
+    #[inline] pub fn is_full(&self) -> bool { self.pos == self.buf.len() }
+
+    /// Discard everything written so far.
+    #[inline] pub fn clear(&mut self) { self.pos = 0 }
+}
+
+impl<'a> fmt::Write for BufWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let remaining = self.buf.len() - self.pos;
+        let n = if s.len() < remaining { s.len() } else { remaining };
+        self.buf[self.pos..self.pos + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.pos += n;
+        Ok(())
+    }
+}
@@ -11,6 +11,8 @@
 #![no_std]
 
 #![feature(step_trait)]
+#![feature(associated_consts)]
+#![feature(const_fn)]
 // #[cfg(not(test))] extern crate vga;
 
 use core::{fmt, ops};
@@ -19,6 +21,8 @@ use core::iter::Step;
 // use core::num::One;
 
 pub mod io;
+pub mod bufwriter;
+pub mod ring;
 
 #[macro_use] pub mod macros;
 
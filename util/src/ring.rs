@@ -0,0 +1,169 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A fixed-capacity FIFO ring buffer.
+//!
+//! The buffer's storage is an array stored inline, so a `RingBuffer` never
+//! touches the heap. Since Rust can't (yet) be generic over array lengths,
+//! the capacity is given by the array type, as in `RingBuffer<[u8; 4096]>`.
+use core::{cmp, fmt, mem};
+
+/// Arrays that may be used as `RingBuffer` storage.
+///
+/// This is implemented for arrays of power-of-two lengths up to 65536.
+pub unsafe trait Array {
+    type Item;
+    /// The number of elements in the array.
+    const CAPACITY: usize;
+    fn as_slice(&self) -> &[Self::Item];
+    fn as_mut_slice(&mut self) -> &mut [Self::Item];
+}
+
+macro_rules! impl_array {
+    ($($n:expr),+) => {$(
+        unsafe impl<T> Array for [T; $n] {
+            type Item = T;
+            const CAPACITY: usize = $n;
+            #[inline] fn as_slice(&self) -> &[T] { self }
+            #[inline] fn as_mut_slice(&mut self) -> &mut [T] { self }
+        }
+    )+}
+}
+
+impl_array! { 1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096
+            , 8192, 16384, 32768, 65536 }
+
+/// A fixed-capacity FIFO queue of `Copy` elements.
+pub struct RingBuffer<A>
+where A: Array
+    , A::Item: Copy { /// Backing storage. Only the `len` elements
+                      /// starting at `head` (wrapping around) are
+                      /// initialized.
+                      buf: A
+                    , /// Index of the oldest element
+                      head: usize
+                    , /// Number of elements in the buffer
+                      len: usize
+                    }
+
+impl<A> RingBuffer<A>
+where A: Array
+    , A::Item: Copy {
+
+    /// Returns a new, empty `RingBuffer`.
+    #[inline]
+    pub fn new() -> Self {
+        // the elements are `Copy`, so they don't need dropping, and none
+        // of them are read before they've been written.
+        RingBuffer { buf: unsafe { mem::uninitialized() }, head: 0, len: 0 }
+    }
+
+    /// Returns a new, empty `RingBuffer` that stores its elements in `buf`.
+    ///
+    /// Unlike `new`, this is a `const fn`, so it can initialize a `static`.
+    #[inline]
+    pub const fn with_storage(buf: A) -> Self {
+        RingBuffer { buf: buf, head: 0, len: 0 }
+    }
+
+    /// Returns the maximum number of elements the buffer can hold.
+    #[inline] pub fn capacity(&self) -> usize { A::CAPACITY }
+
+    /// Returns the number of elements in the buffer.
+    #[inline] pub fn len(&self) -> usize { self.len }
+
+    /// Returns true if the buffer is empty.
+    #[inline] pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Returns true if the buffer is full.
+    #[inline] pub fn is_full(&self) -> bool { self.len == A::CAPACITY }
+
+    /// Returns the number of elements that can be added before the buffer
+    /// is full.
+    #[inline] pub fn remaining(&self) -> usize { A::CAPACITY - self.len }
+
+    /// Remove every element from the buffer.
+    #[inline] pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Add `item` to the back of the buffer.
+    ///
+    /// If the buffer is full, `item` is handed back.
+    pub fn push(&mut self, item: A::Item) -> Result<(), A::Item> {
+        if self.is_full() { return Err(item) }
+        let tail = (self.head + self.len) % A::CAPACITY;
+        self.buf.as_mut_slice()[tail] = item;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Returns the element `index` places from the front of the buffer.
+    pub fn get(&self, index: usize) -> Option<&A::Item> {
+        if index >= self.len { return None }
+        Some(&self.buf.as_slice()[(self.head + index) % A::CAPACITY])
+    }
+
+    /// Remove the element at the front of the buffer.
+    pub fn pop(&mut self) -> Option<A::Item> {
+        if self.is_empty() { return None }
+        let item = self.buf.as_slice()[self.head];
+        self.head = (self.head + 1) % A::CAPACITY;
+        self.len -= 1;
+        Some(item)
+    }
+
+    /// Add as many elements from `items` as will fit to the back of the
+    /// buffer, returning how many were added.
+    pub fn write(&mut self, items: &[A::Item]) -> usize {
+        let n = cmp::min(items.len(), self.remaining());
+        let tail = (self.head + self.len) % A::CAPACITY;
+        // the free space may wrap around the end of the storage array
+        let first = cmp::min(n, A::CAPACITY - tail);
+        {
+            let buf = self.buf.as_mut_slice();
+            buf[tail .. tail + first].copy_from_slice(&items[..first]);
+            buf[.. n - first].copy_from_slice(&items[first .. n]);
+        }
+        self.len += n;
+        n
+    }
+
+    /// Remove elements from the front of the buffer into `items`, until
+    /// either is exhausted, returning how many were removed.
+    pub fn read(&mut self, items: &mut [A::Item]) -> usize {
+        let n = cmp::min(items.len(), self.len);
+        let first = cmp::min(n, A::CAPACITY - self.head);
+        {
+            let (buf, head) = (self.buf.as_slice(), self.head);
+            items[..first].copy_from_slice(&buf[head .. head + first]);
+            items[first .. n].copy_from_slice(&buf[.. n - first]);
+        }
+        self.head = (self.head + n) % A::CAPACITY;
+        self.len -= n;
+        n
+    }
+}
+
+impl<A> Default for RingBuffer<A>
+where A: Array
+    , A::Item: Copy {
+    #[inline] fn default() -> Self { Self::new() }
+}
+
+impl<A> fmt::Debug for RingBuffer<A>
+where A: Array
+    , A::Item: Copy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RingBuffer")
+         .field("len", &self.len)
+         .field("capacity", &A::CAPACITY)
+         .finish()
+    }
+}
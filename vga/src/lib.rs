@@ -218,6 +218,19 @@ impl Terminal {
         self
     }
 
+    /// Returns the color palette currently used for writing characters.
+    #[inline]
+    pub fn palette(&self) -> Palette {
+        self.colors
+    }
+
+    /// Replace the color palette used for writing subsequent characters.
+    #[inline]
+    pub fn set_palette(&mut self, colors: Palette) -> &mut Self {
+        self.colors = colors;
+        self
+    }
+
     /// Scrolls the terminal one row.
     fn scroll(&mut self) {
         // // construct an iterator over the whole buffer
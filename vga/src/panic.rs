@@ -12,8 +12,42 @@
 //! panics at runtime.
 
 use core::fmt::{Arguments, Write};
+use core::mem;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use super::{Color, CONSOLE};
 
+/// A function to call when the kernel panics, with the panic message and
+/// where it came from.
+pub type PanicHook = fn(Arguments, &'static str, usize);
+
+/// A function to call when the kernel panics, before anything is printed.
+pub type StopHook = fn();
+
+/// The hook set by [`set_hook`](fn.set_hook.html), as a `usize`, or 0.
+static HOOK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The hook set by [`set_stop_hook`](fn.set_stop_hook.html), as a `usize`,
+/// or 0.
+static STOP_HOOK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Call `hook` on every panic, after the message has been printed and
+/// before the kernel hangs. Replaces any hook set before.
+///
+/// There's no unwinding, so the hook can't recover from the panic, but it
+/// can report it somewhere else, or never return.
+pub fn set_hook(hook: PanicHook) {
+    HOOK.store(hook as usize, Ordering::SeqCst);
+}
+
+/// Call `hook` at the start of every panic, before the message is printed.
+/// Replaces any hook set before.
+///
+/// This is for stopping anything else that might print over the message,
+/// such as the other CPUs.
+pub fn set_stop_hook(hook: StopHook) {
+    STOP_HOOK.store(hook as usize, Ordering::SeqCst);
+}
+
 /// Called to handle a panic.
 ///
 /// Since kernel panics are non-recoverable, this function prints out
@@ -27,6 +61,11 @@ pub extern "C" fn rust_begin_unwind( args: Arguments
                                    , file: &'static str
                                    , line: usize )
                                    -> ! {
+    let stop = STOP_HOOK.load(Ordering::SeqCst);
+    if stop != 0 {
+        let stop: StopHook = unsafe { mem::transmute(stop) };
+        stop();
+    }
     let _ = write!( CONSOLE.lock()
                         .set_colors(Color::White, Color::Red)
                   , "Something has gone horribly wrong in {} at line {}. \
@@ -35,5 +74,10 @@ pub extern "C" fn rust_begin_unwind( args: Arguments
                   , file, line, args
                   );
     error!(target: file, "{}", args);
+    let hook = HOOK.load(Ordering::SeqCst);
+    if hook != 0 {
+        let hook: PanicHook = unsafe { mem::transmute(hook) };
+        hook(args, file, line);
+    }
     loop { }
 }